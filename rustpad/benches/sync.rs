@@ -0,0 +1,106 @@
+//! Benchmarks of the sync hot path: diffing a full-document update, rebasing concurrent edits,
+//! applying a delta and serializing the snapshot a joining client loads. Run with
+//! `cargo bench --bench sync`; `cargo run --bin loadgen` measures the same path end to end.
+//! `engine_apply` measures the whole of what the server does for an edit, minus the socket.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rustpad::editor::diff_engine::{DiffEngine, DiffOperation};
+use rustpad::engine::DocumentEngine;
+use rustpad::validation::Username;
+
+/// Document sizes in bytes, as in the load generator's snapshot scenario
+const SIZES: [usize; 3] = [100 * 1024, 1024 * 1024, 10 * 1024 * 1024];
+
+/// A document of about `size` bytes of code-like lines
+fn document(size: usize) -> String {
+    let line = "let value = compute(42); // filler\n";
+    line.repeat(size / line.len() + 1)[..size].to_string()
+}
+
+fn label(size: usize) -> String {
+    match size {
+        size if size >= 1024 * 1024 => format!("{}MB", size / (1024 * 1024)),
+        size => format!("{}KB", size / 1024),
+    }
+}
+
+/// A full-document update differing from the pad by one line in the middle, as the server diffs
+/// `{"content": ...}` updates
+fn diff_full_update(c: &mut Criterion) {
+    let mut group = c.benchmark_group("diff_full_update");
+    group.sample_size(10);
+    for size in SIZES {
+        let old = document(size);
+        let mut new = old.clone();
+        new.insert_str(size / 2 - (size / 2) % 35, "let inserted = true;\n");
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(label(size)), &(old, new), |b, (old, new)| {
+            b.iter(|| DiffEngine::diff(old, new))
+        });
+    }
+    group.finish();
+}
+
+/// Rebasing an edit over a backlog of concurrent edits, as `Pad::apply` does for a client that
+/// is behind
+fn transform_backlog(c: &mut Criterion) {
+    let mut group = c.benchmark_group("transform_backlog");
+    for behind in [1, 16, 256] {
+        let edit = vec![DiffOperation::Insert(500, "typed".to_string())];
+        let backlog: Vec<Vec<DiffOperation>> = (0..behind).map(|n| vec![DiffOperation::Insert(n * 7, "x;".to_string())]).collect();
+        group.bench_with_input(BenchmarkId::from_parameter(behind), &backlog, |b, backlog| {
+            b.iter(|| backlog.iter().fold(edit.clone(), |edit, concurrent| DiffEngine::transform(&edit, concurrent, false).0))
+        });
+    }
+    group.finish();
+}
+
+/// Applying a one-character delta to the document
+fn apply_delta(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply_delta");
+    for size in SIZES {
+        let text = document(size);
+        let delta = vec![DiffOperation::Insert(size / 2 - (size / 2) % 35, "x".to_string())];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(label(size)), &text, |b, text| b.iter(|| DiffEngine::apply(text, &delta)));
+    }
+    group.finish();
+}
+
+/// Applying one-character deltas through `DocumentEngine` on one thread: rebasing, applying,
+/// keeping undo and attribution, and announcing each to a subscriber
+fn engine_apply(c: &mut Criterion) {
+    let mut group = c.benchmark_group("engine_apply");
+    let user = Username::try_from("bench".to_string()).unwrap();
+    for size in SIZES {
+        let mut engine = DocumentEngine::default();
+        engine.replace(&document(size), user.clone()).unwrap();
+        let mut events = engine.subscribe();
+        let at = size / 2 - (size / 2) % 35;
+        group.throughput(Throughput::Elements(1));
+        group.bench_function(BenchmarkId::from_parameter(label(size)), |b| {
+            b.iter(|| {
+                engine.apply(engine.revision(), vec![DiffOperation::Insert(at, "x".to_string())], user.clone()).unwrap();
+                let _ = events.try_recv();
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Serializing the load frame a joining client gets, the work behind `rustpad_snapshot_seconds`
+fn snapshot_frame(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot_frame");
+    group.sample_size(10);
+    for size in SIZES {
+        let content = document(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(label(size)), &content, |b, content| {
+            b.iter(|| serde_json::json!({ "type": "load", "revision": 42, "content": content, "resume_token": "token" }).to_string())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, diff_full_update, transform_backlog, apply_delta, engine_apply, snapshot_frame);
+criterion_main!(benches);
@@ -0,0 +1,122 @@
+use rust_embed::RustEmbed;
+use std::path::PathBuf;
+use warp::filters::BoxedFilter;
+use warp::http::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+/// Frontend files compiled into the binary, so the server runs without a `static` directory.
+#[derive(RustEmbed)]
+#[folder = "static/"]
+struct Embedded;
+
+/// Cache policy for files whose name carries a content hash; they never change under the same name.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Cache policy for everything else: browsers must revalidate before reusing it.
+const REVALIDATE: &str = "no-cache";
+
+/// Serves the frontend. Files in `static_dir`, when given, win over the embedded copies
+/// so the frontend can be edited without rebuilding the server.
+pub fn routes(static_dir: Option<PathBuf>) -> BoxedFilter<(Response,)> {
+    let embedded = warp::get()
+        .and(warp::path::tail())
+        .and_then(|tail: warp::path::Tail| async move { serve_embedded(tail.as_str()).ok_or_else(warp::reject::not_found) });
+
+    match static_dir {
+        Some(dir) => warp::fs::dir(dir)
+            .map(|file: warp::fs::File| {
+                let name = file.path().to_string_lossy().into_owned();
+                with_cache_control(file.into_response(), &name)
+            })
+            .or(embedded)
+            .unify()
+            .boxed(),
+        None => embedded.boxed(),
+    }
+}
+
+/// Looks up `path` among the embedded files; directories resolve to their `index.html`.
+fn serve_embedded(path: &str) -> Option<Response> {
+    let path = if path.is_empty() || path.ends_with('/') { format!("{}index.html", path) } else { path.to_string() };
+    let file = Embedded::get(&path)?;
+
+    let mut response = Response::new(file.data.into_owned().into());
+    if let Ok(content_type) = HeaderValue::from_str(file.metadata.mimetype()) {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    Some(with_cache_control(response, &path))
+}
+
+fn with_cache_control(mut response: Response, path: &str) -> Response {
+    response.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static(cache_control(path)));
+    response
+}
+
+/// Cache-Control value for the file at `path`.
+pub fn cache_control(path: &str) -> &'static str {
+    if is_hashed(path) {
+        IMMUTABLE
+    } else {
+        REVALIDATE
+    }
+}
+
+/// Whether the file name carries a content hash, like `app.3f2a9c1b.js` or `app-3f2a9c1b.js`.
+fn is_hashed(path: &str) -> bool {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    stem.split(['.', '-'])
+        .skip(1)
+        .any(|part| part.len() >= 8 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("rustpad-assets-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_embedded_assets_served_without_static_dir() {
+        let routes = routes(Some(temp_dir())); // Doesn't exist
+        let response = warp::test::request().path("/").reply(&routes).await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html");
+        assert_eq!(response.body().as_ref(), include_bytes!("../static/index.html"));
+
+        let response = warp::test::request().path("/styles.css").reply(&routes).await;
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/css");
+        assert_eq!(warp::test::request().path("/missing.js").reply(&routes).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_static_dir_overrides_embedded_assets() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "<p>dev build</p>").unwrap();
+
+        let routes = routes(Some(dir.clone()));
+        let response = warp::test::request().path("/").reply(&routes).await;
+        assert_eq!(response.body().as_ref(), b"<p>dev build</p>");
+        assert_eq!(response.headers()[CACHE_CONTROL], REVALIDATE);
+
+        // Files missing from the dev dir still come from the binary
+        let response = warp::test::request().path("/styles.css").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_policy() {
+        assert_eq!(cache_control("index.html"), REVALIDATE);
+        assert_eq!(cache_control("styles.css"), REVALIDATE);
+        assert_eq!(cache_control("codemirror/lib/codemirror.js"), REVALIDATE);
+        assert_eq!(cache_control("app.3f2a9c1b.js"), IMMUTABLE);
+        assert_eq!(cache_control("assets/app-3f2a9c1be0.css"), IMMUTABLE);
+        assert_eq!(cache_control("deadbeef00.js"), REVALIDATE); // A hex name alone isn't a hash suffix
+    }
+}
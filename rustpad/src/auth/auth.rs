@@ -1,13 +1,20 @@
+use warp::http::StatusCode;
 use warp::{Filter, Rejection, Reply};
-use jsonwebtoken::{encode, decode, Header, Validation, EncodingKey, DecodingKey, TokenData};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::hmac;
 use serde::{Deserialize, Serialize};
 use chrono::{Utc, Duration};
 use std::env;
+use crate::tokens;
+
+/// Header of every token issued here: HMAC-SHA256 signed JWTs
+const HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String, // Subject (typically the user ID or email)
-    exp: usize,  // Expiration time (in seconds since epoch)
+pub struct Claims {
+    pub sub: String, // Subject (typically the user ID or email)
+    pub exp: usize,  // Expiration time (in seconds since epoch)
 }
 
 /// Secret key for signing tokens, loaded from an environment variable for security
@@ -15,8 +22,12 @@ fn get_secret_key() -> String {
     env::var("JWT_SECRET").unwrap_or_else(|_| "your_secret_key".to_string())  // Default key, replace with a secure one
 }
 
+fn signing_key() -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, get_secret_key().as_bytes())
+}
+
 /// Generates a JWT token for the given user ID
-pub fn generate_jwt(user_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
+pub fn generate_jwt(user_id: &str) -> Result<String, String> {
     let expiration = Utc::now()
         .checked_add_signed(Duration::hours(24))  // Token valid for 24 hours
         .expect("valid timestamp")
@@ -27,24 +38,79 @@ pub fn generate_jwt(user_id: &str) -> Result<String, jsonwebtoken::errors::Error
         exp: expiration as usize,
     };
 
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(get_secret_key().as_ref()))
+    let payload = serde_json::to_vec(&claims).map_err(|e| e.to_string())?;
+    let message = format!("{}.{}", URL_SAFE_NO_PAD.encode(HEADER), URL_SAFE_NO_PAD.encode(payload));
+    let signature = hmac::sign(&signing_key(), message.as_bytes());
+    Ok(format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature.as_ref())))
 }
 
 /// Validates the given JWT token and returns the claims if valid
-pub fn validate_jwt(token: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(get_secret_key().as_ref()),
-        &Validation::default(),
-    )
+pub fn validate_jwt(token: &str) -> Result<Claims, String> {
+    let (message, signature) = token.rsplit_once('.').ok_or("Malformed token")?;
+    let (header, payload) = message.split_once('.').ok_or("Malformed token")?;
+    if URL_SAFE_NO_PAD.decode(header).map_err(|e| e.to_string())? != HEADER.as_bytes() {
+        return Err("Unsupported token header".to_string());
+    }
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|e| e.to_string())?;
+    hmac::verify(&signing_key(), message.as_bytes(), &signature).map_err(|_| "Invalid signature".to_string())?;
+
+    let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|e| e.to_string())?;
+    let claims: Claims = serde_json::from_slice(&payload).map_err(|e| e.to_string())?;
+    if claims.exp < Utc::now().timestamp() as usize {
+        return Err("Token expired".to_string());
+    }
+    Ok(claims)
+}
+
+/// The signed-in user making a request: the subject of the JWT it bears as
+/// `Authorization: Bearer <token>`, or `None` without a valid one. Routes acting for a user take
+/// them from here, never from the request's own say-so.
+pub fn authenticated_user() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").map(|authorization: Option<String>| {
+        let token = tokens::bearer(authorization.as_deref())?;
+        validate_jwt(token).ok().map(|claims| claims.sub)
+    })
+}
+
+/// `authenticated_user` for routes only a signed-in user may use, rejecting with `SignedOut`
+/// requests nobody signed in to
+pub fn signed_in_user() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    authenticated_user().and_then(|user: Option<String>| async move { user.ok_or_else(|| warp::reject::custom(SignedOut)) })
+}
+
+/// Rejection of requests `signed_in_user` found nobody signed in to
+#[derive(Debug)]
+pub struct SignedOut;
+
+impl warp::reject::Reject for SignedOut {}
+
+/// Recovers `SignedOut` rejections as `sign_in_required` replies
+pub async fn recover_signed_out(rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+    if rejection.find::<SignedOut>().is_some() {
+        Ok(sign_in_required())
+    } else {
+        Err(rejection)
+    }
+}
+
+/// Reply of routes acting for a user to requests `authenticated_user` found nobody signed in to
+pub fn sign_in_required() -> warp::reply::Response {
+    let body = serde_json::json!({ "type": "error", "message": "Sign in first" });
+    warp::reply::with_status(warp::reply::json(&body), StatusCode::UNAUTHORIZED).into_response()
+}
+
+/// `Authorization` header signing `user` in, for route tests
+#[cfg(test)]
+pub(crate) fn bearer_for(user: &str) -> String {
+    format!("Bearer {}", generate_jwt(user).unwrap())
 }
 
 /// Filter for requiring JWT authentication in routes
 pub fn with_auth() -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
     warp::header::<String>("authorization")
         .and_then(|token: String| async move {
-            match validate_jwt(&token) {
-                Ok(token_data) => Ok(token_data.claims),
+            match validate_jwt(token.trim_start_matches("Bearer ")) {
+                Ok(claims) => Ok(claims),
                 Err(_) => Err(warp::reject::custom(AuthError::InvalidToken)),
             }
         })
@@ -52,24 +118,16 @@ pub fn with_auth() -> impl Filter<Extract = (Claims,), Error = Rejection> + Clon
 
 /// Custom error type for handling auth errors
 #[derive(Debug)]
-struct AuthError {
-    message: String,
+enum AuthError {
+    InvalidToken,
 }
 
 impl warp::reject::Reject for AuthError {}
 
-impl AuthError {
-    fn invalid_token() -> Self {
-        AuthError {
-            message: "Invalid token".to_string(),
-        }
-    }
-}
-
 pub async fn login_handler(user_id: String) -> Result<impl Reply, Rejection> {
     match generate_jwt(&user_id) {
         Ok(token) => Ok(warp::reply::json(&token)),
-        Err(_) => Err(warp::reject::custom(AuthError::invalid_token())),
+        Err(_) => Err(warp::reject::custom(AuthError::InvalidToken)),
     }
 }
 
@@ -79,3 +137,30 @@ pub fn protected_route() -> impl Filter<Extract = (String,), Error = Rejection>
         .and(with_auth())  // Require JWT authentication
         .map(|claims: Claims| format!("Welcome, user {}!", claims.sub))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issued_token_validates_until_tampered_with() {
+        let token = generate_jwt("ana").unwrap();
+        assert_eq!(validate_jwt(&token).unwrap().sub, "ana");
+
+        // Another subject under the same signature
+        let parts: Vec<&str> = token.split('.').collect();
+        let payload = String::from_utf8(URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap().replace("ana", "bob");
+        let forged = format!("{}.{}.{}", parts[0], URL_SAFE_NO_PAD.encode(payload), parts[2]);
+        assert!(validate_jwt(&forged).is_err());
+        assert!(validate_jwt("not a token").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_user_comes_from_a_valid_bearer_token() {
+        let filter = authenticated_user();
+        let user = |authorization: &str| warp::test::request().header("authorization", authorization).filter(&filter);
+        assert_eq!(user(&bearer_for("ana")).await.unwrap(), Some("ana".to_string()));
+        assert_eq!(user("Bearer forged").await.unwrap(), None);
+        assert_eq!(warp::test::request().filter(&filter).await.unwrap(), None);
+    }
+}
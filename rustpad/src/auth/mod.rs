@@ -1 +1,5 @@
 pub mod session;
+#[allow(clippy::module_inception)]
+pub mod auth;
+pub mod user_store;
+pub mod oauth;
@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use ring::digest;
+use uuid::Uuid;
+use warp::http::header::{ACCEPT, LOCATION, SET_COOKIE, USER_AGENT};
+use warp::http::{Response, StatusCode};
+use warp::hyper::Body;
+use warp::{Filter, Rejection};
+
+use crate::auth::auth::generate_jwt;
+use crate::auth::session::{Sessions, UserSession};
+use crate::auth::user_store::UserStore;
+use crate::config::{OAuthProviderConfig, ServerConfig};
+use crate::validation::Username;
+
+/// How long a login attempt may take before its state parameter expires
+pub const STATE_TTL: Duration = Duration::from_secs(600);
+
+/// Page users are redirected to when a login fails
+pub const ERROR_PAGE: &str = "/auth/error";
+
+/// Errors raised during the OAuth2 authorization-code flow
+#[derive(Debug, PartialEq)]
+pub enum OAuthError {
+    UnknownProvider(String),
+    InvalidState,
+    ProviderError(String),
+    TokenExchange(String),
+    Profile(String),
+    Linking(String),
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OAuthError::UnknownProvider(name) => write!(f, "Unknown login provider '{}'", name),
+            OAuthError::InvalidState => write!(f, "The login request expired or was already used, please try again"),
+            OAuthError::ProviderError(message) => write!(f, "The provider refused the login: {}", message),
+            OAuthError::TokenExchange(message) => write!(f, "Could not complete the login: {}", message),
+            OAuthError::Profile(message) => write!(f, "Could not read your profile: {}", message),
+            OAuthError::Linking(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// A login that was started but not yet completed
+#[derive(Debug, Clone)]
+pub struct PendingLogin {
+    pub provider: String,
+    pub code_verifier: String,       // PKCE verifier sent with the token exchange
+    pub link_user_id: Option<String>, // Set when a logged-in user is linking a provider
+    created_at: Instant,
+}
+
+/// Single-use state parameters for logins in progress
+#[derive(Clone)]
+pub struct OAuthStateStore {
+    states: Arc<Mutex<HashMap<String, PendingLogin>>>,
+}
+
+impl OAuthStateStore {
+    /// Creates an empty state store
+    pub fn new() -> Self {
+        OAuthStateStore {
+            states: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts a login for `provider`, returning the state parameter and the PKCE verifier
+    pub fn issue(&self, provider: &str, link_user_id: Option<String>) -> (String, String) {
+        let state = Uuid::new_v4().simple().to_string();
+        let code_verifier = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+        let mut states = self.states.lock().unwrap();
+        states.retain(|_, pending| pending.created_at.elapsed() < STATE_TTL);
+        states.insert(
+            state.clone(),
+            PendingLogin {
+                provider: provider.to_string(),
+                code_verifier: code_verifier.clone(),
+                link_user_id,
+                created_at: Instant::now(),
+            },
+        );
+        (state, code_verifier)
+    }
+
+    /// Consumes a state parameter. Unknown, expired, reused, or cross-provider states are rejected.
+    pub fn take(&self, state: &str, provider: &str) -> Result<PendingLogin, OAuthError> {
+        let pending = self.states.lock().unwrap().remove(state).ok_or(OAuthError::InvalidState)?;
+        if pending.provider != provider || pending.created_at.elapsed() >= STATE_TTL {
+            return Err(OAuthError::InvalidState);
+        }
+        Ok(pending)
+    }
+}
+
+impl Default for OAuthStateStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Everything the OAuth routes need to complete a login
+#[derive(Clone)]
+pub struct OAuthContext {
+    pub config: Arc<ServerConfig>,
+    pub states: OAuthStateStore,
+    pub users: UserStore,
+    pub sessions: Sessions,
+}
+
+/// Computes the S256 PKCE challenge for a verifier
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    base64_url(digest::digest(&digest::SHA256, code_verifier.as_bytes()).as_ref())
+}
+
+/// Base64url encoding without padding, as required by PKCE
+fn base64_url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Percent-encodes a query parameter value
+fn url_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Builds the provider's authorization URL for a login attempt
+pub fn authorize_url(provider: &OAuthProviderConfig, state: &str, code_verifier: &str) -> String {
+    format!(
+        "{}?response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&code_challenge={}&code_challenge_method=S256",
+        provider.auth_url,
+        url_encode(&provider.client_id),
+        url_encode(&provider.redirect_url),
+        url_encode(&provider.scopes.join(" ")),
+        url_encode(state),
+        pkce_challenge(code_verifier),
+    )
+}
+
+/// Builds a redirect response
+fn redirect(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Redirects to the error page with a readable message
+fn error_redirect(error: &OAuthError) -> Response<Body> {
+    redirect(&format!("{}?message={}", ERROR_PAGE, url_encode(&error.to_string())))
+}
+
+/// Returns the id of the logged-in user for a session cookie, if any
+fn logged_in_user(context: &OAuthContext, session_id: Option<String>) -> Option<String> {
+    let session = context.sessions.lock().unwrap().get(&session_id?).cloned()?;
+    if session.is_authenticated && context.users.get(session.user_id.as_str()).is_some() {
+        Some(session.user_id.to_string())
+    } else {
+        None
+    }
+}
+
+/// `GET /api/auth/:provider/login` - redirects to the provider with a fresh state and PKCE challenge
+pub async fn login_handler(provider_name: String, session_id: Option<String>, context: OAuthContext) -> Result<Response<Body>, Rejection> {
+    let provider = match context.config.oauth_provider(&provider_name) {
+        Some(provider) => provider,
+        None => return Ok(error_redirect(&OAuthError::UnknownProvider(provider_name))),
+    };
+
+    let link_user_id = logged_in_user(&context, session_id);
+    let (state, code_verifier) = context.states.issue(&provider_name, link_user_id);
+    Ok(redirect(&authorize_url(provider, &state, &code_verifier)))
+}
+
+/// `GET /api/auth/:provider/callback` - completes the login and issues a session and JWT
+pub async fn callback_handler(provider_name: String, query: HashMap<String, String>, context: OAuthContext) -> Result<Response<Body>, Rejection> {
+    match complete_login(&provider_name, &query, &context).await {
+        Ok((session_id, token)) => {
+            let mut response = redirect(&format!("/#token={}", token));
+            let cookie = format!("session_id={}; Path=/; HttpOnly", session_id);
+            response.headers_mut().insert(SET_COOKIE, cookie.parse().unwrap());
+            Ok(response)
+        }
+        Err(e) => Ok(error_redirect(&e)),
+    }
+}
+
+/// Validates the callback, exchanges the code, and creates or links the local user.
+/// Returns the new session id and JWT.
+async fn complete_login(provider_name: &str, query: &HashMap<String, String>, context: &OAuthContext) -> Result<(String, String), OAuthError> {
+    let provider = context
+        .config
+        .oauth_provider(provider_name)
+        .ok_or_else(|| OAuthError::UnknownProvider(provider_name.to_string()))?;
+
+    // The state is consumed even when the provider reports an error
+    let pending = context.states.take(query.get("state").map_or("", |s| s.as_str()), provider_name)?;
+    if let Some(error) = query.get("error") {
+        let description = query.get("error_description").unwrap_or(error);
+        return Err(OAuthError::ProviderError(description.clone()));
+    }
+    let code = query.get("code").ok_or_else(|| OAuthError::ProviderError("missing authorization code".to_string()))?;
+
+    let access_token = exchange_code(provider, code, &pending.code_verifier).await?;
+    let (subject, display_name) = fetch_profile(provider, &access_token).await?;
+
+    let user = match (&pending.link_user_id, context.users.find_by_identity(provider_name, &subject)) {
+        (Some(user_id), _) => context.users.link_identity(user_id, provider_name, &subject).map_err(OAuthError::Linking)?,
+        (None, Some(user)) => user,
+        (None, None) => {
+            let user = context.users.create_user(&display_name);
+            context.users.link_identity(&user.id, provider_name, &subject).map_err(OAuthError::Linking)?
+        }
+    };
+
+    let token = generate_jwt(&user.id).map_err(|e| OAuthError::TokenExchange(e.to_string()))?;
+    let session_id = Uuid::new_v4().to_string();
+    let user_id = Username::try_from(user.id.as_str()).map_err(|e| OAuthError::Linking(e.to_string()))?;
+    context.sessions.lock().unwrap().insert(session_id.clone(), UserSession::new(user_id));
+    Ok((session_id, token))
+}
+
+/// Exchanges an authorization code for an access token
+async fn exchange_code(provider: &OAuthProviderConfig, code: &str, code_verifier: &str) -> Result<String, OAuthError> {
+    let params = [
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", provider.redirect_url.as_str()),
+        ("client_id", provider.client_id.as_str()),
+        ("client_secret", provider.client_secret.as_str()),
+        ("code_verifier", code_verifier),
+    ];
+
+    let response = reqwest::Client::new()
+        .post(&provider.token_url)
+        .header(ACCEPT, "application/json")
+        .form(&params)
+        .send()
+        .await
+        .map_err(|e| OAuthError::TokenExchange(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(OAuthError::TokenExchange(format!("token endpoint returned {}", response.status())));
+    }
+
+    let body: serde_json::Value = response.json().await.map_err(|e| OAuthError::TokenExchange(e.to_string()))?;
+    if let Some(error) = body.get("error").and_then(|e| e.as_str()) {
+        return Err(OAuthError::TokenExchange(error.to_string()));
+    }
+    body.get("access_token")
+        .and_then(|token| token.as_str())
+        .map(|token| token.to_string())
+        .ok_or_else(|| OAuthError::TokenExchange("no access token in response".to_string()))
+}
+
+/// Fetches the provider's user profile, returning the stable subject id and a display name
+async fn fetch_profile(provider: &OAuthProviderConfig, access_token: &str) -> Result<(String, String), OAuthError> {
+    let response = reqwest::Client::new()
+        .get(&provider.userinfo_url)
+        .bearer_auth(access_token)
+        .header(USER_AGENT, "rustpad")
+        .header(ACCEPT, "application/json")
+        .send()
+        .await
+        .map_err(|e| OAuthError::Profile(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(OAuthError::Profile(format!("userinfo endpoint returned {}", response.status())));
+    }
+
+    let profile: serde_json::Value = response.json().await.map_err(|e| OAuthError::Profile(e.to_string()))?;
+
+    // OpenID providers use `sub`; GitHub uses a numeric `id`
+    let subject = match (profile.get("sub"), profile.get("id")) {
+        (Some(sub), _) => sub.as_str().map(|s| s.to_string()),
+        (None, Some(id)) => Some(id.to_string().trim_matches('"').to_string()),
+        (None, None) => None,
+    }
+    .ok_or_else(|| OAuthError::Profile("profile has no user id".to_string()))?;
+
+    let display_name = ["name", "login", "email"]
+        .iter()
+        .find_map(|field| profile.get(*field).and_then(|v| v.as_str()))
+        .unwrap_or(&subject)
+        .to_string();
+    Ok((subject, display_name))
+}
+
+/// Helper function to pass the OAuth context to the routes
+fn with_context(context: OAuthContext) -> impl Filter<Extract = (OAuthContext,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || context.clone())
+}
+
+/// Routes for `GET /api/auth/:provider/login` and `GET /api/auth/:provider/callback`
+pub fn oauth_routes(context: OAuthContext) -> impl Filter<Extract = (Response<Body>,), Error = Rejection> + Clone {
+    let login = warp::path!("api" / "auth" / String / "login")
+        .and(warp::get())
+        .and(warp::cookie::optional("session_id"))
+        .and(with_context(context.clone()))
+        .and_then(login_handler);
+
+    let callback = warp::path!("api" / "auth" / String / "callback")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_context(context))
+        .and_then(callback_handler);
+
+    login.or(callback).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    /// Starts a stub provider. The token endpoint fails for the code "bad".
+    async fn mock_provider() -> SocketAddr {
+        let token = warp::path("token")
+            .and(warp::post())
+            .and(warp::body::form::<HashMap<String, String>>())
+            .map(|form: HashMap<String, String>| {
+                let ok = form.get("code").map(|c| c.as_str()) != Some("bad") && form.contains_key("code_verifier");
+                let status = if ok { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                warp::reply::with_status(warp::reply::json(&serde_json::json!({ "access_token": "mock-token" })), status)
+            });
+        let userinfo = warp::path("userinfo")
+            .and(warp::header::<String>("authorization"))
+            .map(|_auth: String| warp::reply::json(&serde_json::json!({ "sub": "mock-user-1", "name": "Mock User" })));
+
+        let (addr, server) = warp::serve(token.or(userinfo)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    async fn context() -> OAuthContext {
+        let addr = mock_provider().await;
+        let provider = OAuthProviderConfig {
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            auth_url: format!("http://{}/authorize", addr),
+            token_url: format!("http://{}/token", addr),
+            userinfo_url: format!("http://{}/userinfo", addr),
+            redirect_url: "http://localhost/api/auth/mock/callback".to_string(),
+            scopes: vec!["profile".to_string()],
+        };
+        OAuthContext {
+            config: Arc::new(ServerConfig::new().with_oauth_provider("mock", provider)),
+            states: OAuthStateStore::new(),
+            users: UserStore::new(),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Starts a login and returns the state parameter from the redirect
+    async fn start_login(context: &OAuthContext, cookie: Option<&str>) -> String {
+        let mut request = warp::test::request().path("/api/auth/mock/login");
+        if let Some(cookie) = cookie {
+            request = request.header("cookie", cookie);
+        }
+        let response = request.reply(&oauth_routes(context.clone())).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+
+        let location = response.headers()[LOCATION].to_str().unwrap().to_string();
+        assert!(location.contains("code_challenge_method=S256"));
+        location.split("state=").nth(1).unwrap().split('&').next().unwrap().to_string()
+    }
+
+    async fn callback(context: &OAuthContext, code: &str, state: &str) -> String {
+        let response = warp::test::request()
+            .path(&format!("/api/auth/mock/callback?code={}&state={}", code, state))
+            .reply(&oauth_routes(context.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        response.headers()[LOCATION].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        // Example from RFC 7636, appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login_creates_user_and_session() {
+        let context = context().await;
+        let state = start_login(&context, None).await;
+
+        let location = callback(&context, "good", &state).await;
+        assert!(location.starts_with("/#token="));
+        assert_eq!(context.users.user_count(), 1);
+        let user = context.users.find_by_identity("mock", "mock-user-1").unwrap();
+        assert_eq!(user.display_name, "Mock User");
+        assert_eq!(context.sessions.lock().unwrap().values().next().unwrap().user_id.as_str(), user.id);
+
+        // Logging in again reuses the same account
+        let state = start_login(&context, None).await;
+        callback(&context, "good", &state).await;
+        assert_eq!(context.users.user_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_state_mismatch_and_reuse_rejected() {
+        let context = context().await;
+        let state = start_login(&context, None).await;
+
+        assert!(callback(&context, "good", "forged").await.starts_with(ERROR_PAGE));
+        assert!(callback(&context, "good", &state).await.starts_with("/#token="));
+        assert!(callback(&context, "good", &state).await.starts_with(ERROR_PAGE)); // Single use
+        assert_eq!(context.users.user_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_token_exchange_failure_redirects_to_error_page() {
+        let context = context().await;
+        let state = start_login(&context, None).await;
+
+        let location = callback(&context, "bad", &state).await;
+        assert!(location.starts_with(ERROR_PAGE));
+        assert!(location.contains("message="));
+        assert_eq!(context.users.user_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_logged_in_user_links_provider() {
+        let context = context().await;
+        let existing = context.users.create_user("Existing");
+        context.sessions.lock().unwrap().insert("existing-session".to_string(), UserSession::new(Username::try_from(existing.id.as_str()).unwrap()));
+
+        let state = start_login(&context, Some("session_id=existing-session")).await;
+        callback(&context, "good", &state).await;
+
+        assert_eq!(context.users.user_count(), 1);
+        assert_eq!(context.users.find_by_identity("mock", "mock-user-1").unwrap().id, existing.id);
+    }
+}
@@ -3,21 +3,21 @@ use std::sync::{Arc, Mutex};
 use serde::{Deserialize, Serialize};
 use warp::{Filter, Rejection, Reply, http::header::SET_COOKIE};
 use uuid::Uuid;
-use warp::http::HeaderValue;
-use warp::reply::Response;
+use crate::validation::Username;
+use warp::http::{HeaderValue, StatusCode};
 
 pub type Sessions = Arc<Mutex<HashMap<String, UserSession>>>;
 
 /// Struct representing a user session.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserSession {
-    pub user_id: String,
+    pub user_id: Username,
     pub is_authenticated: bool,
 }
 
 impl UserSession {
     /// Creates a new user session.
-    pub fn new(user_id: String) -> Self {
+    pub fn new(user_id: Username) -> Self {
         UserSession {
             user_id,
             is_authenticated: true,
@@ -51,7 +51,7 @@ pub fn with_session(
                 // Retrieve existing session or create a new one.
                 let session = sessions
                     .entry(session_id.clone())
-                    .or_insert_with(|| UserSession::new("guest".to_string()))
+                    .or_insert_with(|| UserSession::new(Username::guest()))
                     .clone();
 
                 Ok::<_, Rejection>(session)
@@ -66,6 +66,15 @@ pub async fn create_session(
 ) -> Result<impl Reply, Rejection> {
     let session_id = generate_session_id();
 
+    // Reject user IDs that fail validation before they reach the session store.
+    let user_id = match Username::try_from(user_id) {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            let reply = warp::reply::json(&serde_json::json!({ "type": "error", "message": e.to_string() }));
+            return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
+        }
+    };
+
     // Create a new session with the provided user ID.
     let new_session = UserSession::new(user_id);
 
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A local user account, optionally linked to external identities.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UserRecord {
+    pub id: String,
+    pub display_name: String,
+    pub identities: Vec<ExternalIdentity>,
+}
+
+/// An account at an external identity provider.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ExternalIdentity {
+    pub provider: String,
+    pub subject: String, // The provider's stable user id
+}
+
+/// Stores local users and the external identities linked to them.
+#[derive(Clone)]
+pub struct UserStore {
+    users: Arc<Mutex<HashMap<String, UserRecord>>>,              // Keyed by local user id
+    identities: Arc<Mutex<HashMap<(String, String), String>>>,   // (provider, subject) -> local user id
+}
+
+impl UserStore {
+    /// Creates an empty user store.
+    pub fn new() -> Self {
+        UserStore {
+            users: Arc::new(Mutex::new(HashMap::new())),
+            identities: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Creates a new local user and returns it.
+    pub fn create_user(&self, display_name: &str) -> UserRecord {
+        let user = UserRecord {
+            id: Uuid::new_v4().to_string(),
+            display_name: display_name.to_string(),
+            identities: Vec::new(),
+        };
+        self.users.lock().unwrap().insert(user.id.clone(), user.clone());
+        user
+    }
+
+    /// Retrieves a user by local id.
+    pub fn get(&self, user_id: &str) -> Option<UserRecord> {
+        self.users.lock().unwrap().get(user_id).cloned()
+    }
+
+    /// Finds the local user linked to a provider account.
+    pub fn find_by_identity(&self, provider: &str, subject: &str) -> Option<UserRecord> {
+        let user_id = self
+            .identities
+            .lock()
+            .unwrap()
+            .get(&(provider.to_string(), subject.to_string()))
+            .cloned()?;
+        self.get(&user_id)
+    }
+
+    /// Links a provider account to an existing local user.
+    pub fn link_identity(&self, user_id: &str, provider: &str, subject: &str) -> Result<UserRecord, String> {
+        let mut users = self.users.lock().unwrap();
+        let user = users.get_mut(user_id).ok_or_else(|| format!("Unknown user {}", user_id))?;
+
+        let mut identities = self.identities.lock().unwrap();
+        let key = (provider.to_string(), subject.to_string());
+        match identities.get(&key) {
+            Some(owner) if owner != user_id => {
+                return Err(format!("This {} account is already linked to another user", provider));
+            }
+            Some(_) => {}
+            None => {
+                identities.insert(key, user_id.to_string());
+                user.identities.push(ExternalIdentity {
+                    provider: provider.to_string(),
+                    subject: subject.to_string(),
+                });
+            }
+        }
+        Ok(user.clone())
+    }
+
+    /// Number of local users.
+    pub fn user_count(&self) -> usize {
+        self.users.lock().unwrap().len()
+    }
+}
+
+impl Default for UserStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
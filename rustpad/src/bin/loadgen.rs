@@ -0,0 +1,560 @@
+//! Load generator for the sync hot path. It starts the `rustpad` server next to it (or loads the
+//! one at `--url`), connects simulated clients, runs the scenarios and prints what it measured as
+//! JSON on stdout, so runs can be compared. With `--assert` it exits with failure when a
+//! measurement exceeds its threshold.
+//!
+//!     cargo build --release --bins && cargo run --release --bin loadgen -- --assert
+
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use rustpad::editor::diff_engine::{DiffEngine, DiffOperation};
+use rustpad::metrics::quantile;
+use rustpad::version::PROTOCOL_VERSION;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+const USAGE: &str = "\
+Usage: loadgen [options]
+  --url <ws://host:port>      Server to load; by default the `rustpad` binary next to this one is started
+  --scenarios <list>          Comma-separated: fanout,convergence,idle,snapshot (default: all)
+  --clients <n>               Clients connected in the fan-out and convergence scenarios (default 50)
+  --writers <n>               Clients among them sending edits, at most the pad's editors (default 10)
+  --rate <n>                  Edits per second across the writers in the fan-out scenario (default 100)
+  --seconds <n>               Length of the fan-out scenario (default 10)
+  --burst <n>                 Edits sent at once in the convergence scenario (default 200)
+  --idle-clients <n>          Connections opened to measure the memory of idle clients (default 200)
+  --snapshot-sizes <list>     Document sizes in bytes for the snapshot scenario (default 102400,1048576,10485760)
+  --assert                    Exit with failure when a measurement exceeds its threshold
+  --max-fanout-p99-ms <ms>    Threshold on the fan-out latency p99 (default 250)
+  --max-convergence-ms <ms>   Threshold on the time to converge after the burst (default 5000)
+  --max-snapshot-ms <ms>      Threshold on loading the largest snapshot (default 2000)
+";
+
+/// Scenarios run when `--scenarios` isn't given, in the order they run. The snapshot scenario
+/// leaves a large document behind, so it comes last.
+const SCENARIOS: [&str; 4] = ["fanout", "convergence", "idle", "snapshot"];
+
+/// How long a scenario waits for every client to see every edit before giving up
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a started server gets to begin accepting connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections made to time each snapshot size, of which the median counts
+const SNAPSHOT_LOADS: usize = 3;
+
+struct Options {
+    url: Option<String>,
+    scenarios: Vec<String>,
+    clients: usize,
+    writers: usize,
+    rate: f64,
+    seconds: u64,
+    burst: usize,
+    idle_clients: usize,
+    snapshot_sizes: Vec<usize>,
+    assert: bool,
+    max_fanout_p99_ms: f64,
+    max_convergence_ms: f64,
+    max_snapshot_ms: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            url: None,
+            scenarios: SCENARIOS.iter().map(|scenario| scenario.to_string()).collect(),
+            clients: 50,
+            writers: 10,
+            rate: 100.0,
+            seconds: 10,
+            burst: 200,
+            idle_clients: 200,
+            snapshot_sizes: vec![100 * 1024, 1024 * 1024, 10 * 1024 * 1024],
+            assert: false,
+            max_fanout_p99_ms: 250.0,
+            max_convergence_ms: 5000.0,
+            max_snapshot_ms: 2000.0,
+        }
+    }
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        while let Some(arg) = args.next() {
+            if arg == "--assert" {
+                options.assert = true;
+                continue;
+            }
+            let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+            let number = |value: &str| value.parse::<f64>().map_err(|_| format!("{} needs a number, not {:?}", arg, value));
+            let list = |value: &str| value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect::<Vec<_>>();
+            match arg.as_str() {
+                "--url" => options.url = Some(value.trim_end_matches('/').to_string()),
+                "--scenarios" => {
+                    options.scenarios = list(&value);
+                    if let Some(unknown) = options.scenarios.iter().find(|scenario| !SCENARIOS.contains(&scenario.as_str())) {
+                        return Err(format!("Unknown scenario {:?}", unknown));
+                    }
+                }
+                "--clients" => options.clients = number(&value)? as usize,
+                "--writers" => options.writers = number(&value)? as usize,
+                "--rate" => options.rate = number(&value)?,
+                "--seconds" => options.seconds = number(&value)? as u64,
+                "--burst" => options.burst = number(&value)? as usize,
+                "--idle-clients" => options.idle_clients = number(&value)? as usize,
+                "--snapshot-sizes" => {
+                    options.snapshot_sizes = list(&value).iter().map(|size| number(size).map(|size| size as usize)).collect::<Result<_, _>>()?
+                }
+                "--max-fanout-p99-ms" => options.max_fanout_p99_ms = number(&value)?,
+                "--max-convergence-ms" => options.max_convergence_ms = number(&value)?,
+                "--max-snapshot-ms" => options.max_snapshot_ms = number(&value)?,
+                _ => return Err(format!("Unknown option {}", arg)),
+            }
+        }
+        if options.writers == 0 || options.writers > options.clients || options.rate <= 0.0 {
+            return Err("Between 1 and --clients writers and a positive --rate are needed".to_string());
+        }
+        Ok(options)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if std::env::args().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", USAGE);
+        return;
+    }
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    match run(&options).await {
+        Ok(report) => {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            if options.assert && report["passed"] == false {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("loadgen: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Runs the chosen scenarios against the server and reports their measurements, the server's
+/// own timings and, when asserting, how they compare to the thresholds
+async fn run(options: &Options) -> Result<Value, String> {
+    let server = match &options.url {
+        Some(url) => Server::existing(url)?,
+        None => Server::start().await?,
+    };
+
+    let mut report = json!({ "server": server.address, "started_server": server.child.is_some() });
+    for scenario in &options.scenarios {
+        eprintln!("Running the {} scenario", scenario);
+        report[scenario.as_str()] = match scenario.as_str() {
+            "fanout" => fanout(&server, options).await?,
+            "convergence" => convergence(&server, options).await?,
+            "idle" => idle(&server, options).await?,
+            _ => snapshot(&server, options).await?,
+        };
+    }
+    report["server_metrics"] = server.metrics().await?;
+
+    let mut checks = Vec::new();
+    let mut check = |name: &str, value: &Value, limit: f64| {
+        if let Some(value) = value.as_f64() {
+            checks.push(json!({ "check": name, "value_ms": value, "limit_ms": limit, "ok": value <= limit }));
+        }
+    };
+    check("fanout latency p99", &report["fanout"]["p99_ms"], options.max_fanout_p99_ms);
+    check("convergence after burst", &report["convergence"]["converged_ms"], options.max_convergence_ms);
+    check("largest snapshot load", &report["snapshot"].as_array().and_then(|sizes| sizes.last()).map_or(Value::Null, |size| size["load_ms"].clone()), options.max_snapshot_ms);
+    let settled = ["fanout", "convergence"].iter().all(|scenario| report[scenario].get("settled") != Some(&Value::Bool(false)));
+    report["passed"] = json!(settled && checks.iter().all(|check| check["ok"] == true));
+    report["checks"] = json!(checks);
+    Ok(report)
+}
+
+/// The server under load: one this run started, which is stopped when it ends, or one already
+/// running at `--url`
+struct Server {
+    address: String, // `host:port`
+    child: Option<Child>,
+}
+
+impl Server {
+    fn existing(url: &str) -> Result<Self, String> {
+        let address = url.strip_prefix("ws://").ok_or_else(|| format!("{} isn't a ws:// URL", url))?;
+        Ok(Self { address: address.to_string(), child: None })
+    }
+
+    /// Starts the server binary built alongside this one on a free port
+    async fn start() -> Result<Self, String> {
+        let binary = std::env::current_exe()
+            .map_err(|e| e.to_string())?
+            .with_file_name(format!("rustpad{}", std::env::consts::EXE_SUFFIX));
+        if !binary.exists() {
+            return Err(format!("No server at {}; build it with `cargo build --release --bins` or pass --url", binary.display()));
+        }
+        let port = std::net::TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).map_err(|e| e.to_string())?.port();
+        let child = Command::new(&binary)
+            .env("RUSTPAD_PORT", port.to_string())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Starting {}: {}", binary.display(), e))?;
+        let server = Self { address: format!("127.0.0.1:{}", port), child: Some(child) };
+
+        let started = Instant::now();
+        while TcpStream::connect(&server.address).await.is_err() {
+            if started.elapsed() > STARTUP_TIMEOUT {
+                return Err(format!("The server didn't start listening on {}", server.address));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        Ok(server)
+    }
+
+    fn ws_url(&self) -> String {
+        format!("ws://{}/ws?protocol={}", self.address, PROTOCOL_VERSION)
+    }
+
+    /// The server's timings from `GET /metrics`, by metric name and labels
+    async fn metrics(&self) -> Result<Value, String> {
+        let mut stream = TcpStream::connect(&self.address).await.map_err(|e| e.to_string())?;
+        let request = format!("GET /metrics HTTP/1.0\r\nHost: {}\r\n\r\n", self.address);
+        stream.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.map_err(|e| e.to_string())?;
+        let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+        let metrics = body
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.rsplit_once(' '))
+            .filter_map(|(name, value)| Some((name.to_string(), json!(value.parse::<f64>().ok()?))))
+            .collect::<serde_json::Map<_, _>>();
+        Ok(Value::Object(metrics))
+    }
+
+    /// The server's resident memory in bytes, when this run started it on Linux
+    fn resident_bytes(&self) -> Option<u64> {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.child.as_ref()?.id())).ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+        let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+        Some(kilobytes * 1024)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// When each edit was sent, by the text it inserts, which is unique to it
+type Sent = Arc<Mutex<HashMap<String, Instant>>>;
+
+/// How long edits took to reach each client, sender included
+type Latencies = Arc<Mutex<Vec<Duration>>>;
+
+/// What a simulated client has seen of the pad
+#[derive(Default)]
+struct View {
+    revision: u64,
+    content: String,
+    errors: usize,  // Error frames, each refusing one of the client's edits
+    resyncs: usize, // Times the server sent the whole document again
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A simulated client, keeping its view of the pad up to date as frames arrive
+struct Client {
+    user: String,
+    sink: SplitSink<Socket, Message>,
+    view: Arc<Mutex<View>>,
+    edits: usize, // Edits sent
+    sent: Sent,
+    reader: JoinHandle<()>,
+}
+
+impl Client {
+    /// Connects as `user`, returning once the document is loaded
+    async fn connect(server: &Server, user: &str, sent: &Sent, latencies: &Latencies) -> Result<Self, String> {
+        let (socket, _) = connect_async(server.ws_url()).await.map_err(|e| format!("Connecting to {}: {}", server.address, e))?;
+        let (sink, stream) = socket.split();
+        let view = Arc::new(Mutex::new(View::default()));
+        let (loaded, on_load) = oneshot::channel();
+        let reader = tokio::spawn(read_frames(stream, view.clone(), latencies.clone(), sent.clone(), loaded));
+        on_load.await.map_err(|_| format!("{} was disconnected before the document loaded", user))?;
+        Ok(Self { user: user.to_string(), sink, view, edits: 0, sent: sent.clone(), reader })
+    }
+
+    /// Inserts `text` at the start of the document, against the latest revision seen
+    async fn insert(&mut self, text: &str) -> Result<(), String> {
+        let base = self.view.lock().unwrap().revision;
+        let frame = json!({ "base": base, "operations": [DiffOperation::Insert(0, text.to_string())], "user": self.user });
+        self.sent.lock().unwrap().insert(text.to_string(), Instant::now());
+        self.edits += 1;
+        self.sink.send(Message::Text(frame.to_string())).await.map_err(|e| e.to_string())
+    }
+
+    /// Replaces the whole document with `content`
+    async fn replace(&mut self, content: &str) -> Result<(), String> {
+        let frame = json!({ "content": content, "user": self.user });
+        self.edits += 1;
+        self.sink.send(Message::Text(frame.to_string())).await.map_err(|e| e.to_string())
+    }
+
+    /// Disconnects, waiting briefly for the server to drop the connection so the next scenario
+    /// finds the editor slot free
+    async fn close(mut self) {
+        let _ = self.sink.close().await;
+        let _ = tokio::time::timeout(Duration::from_secs(1), &mut self.reader).await;
+        self.reader.abort();
+    }
+}
+
+/// Applies the frames a client receives to its view, timing the edits it recognizes
+async fn read_frames(mut stream: SplitStream<Socket>, view: Arc<Mutex<View>>, latencies: Latencies, sent: Sent, loaded: oneshot::Sender<()>) {
+    let mut loaded = Some(loaded);
+    while let Some(Ok(message)) = stream.next().await {
+        let received = Instant::now();
+        let Message::Text(text) = message else { continue };
+        let Ok(frame) = serde_json::from_str::<Value>(&text) else { continue };
+        let mut view = view.lock().unwrap();
+        match frame["type"].as_str() {
+            Some(kind @ ("load" | "resync")) => {
+                view.revision = frame["revision"].as_u64().unwrap_or(0);
+                view.content = frame["content"].as_str().unwrap_or("").to_string();
+                if kind == "resync" {
+                    view.resyncs += 1;
+                }
+                if let Some(loaded) = loaded.take() {
+                    let _ = loaded.send(());
+                }
+            }
+            Some("error") => view.errors += 1,
+            Some(_) => (), // Room notices
+            None => {
+                let Ok(operations) = serde_json::from_value::<Vec<DiffOperation>>(frame["operations"].clone()) else { continue };
+                view.content = DiffEngine::apply(&view.content, &operations);
+                view.revision = frame["base"].as_u64().unwrap_or(0) + 1;
+                let sent = sent.lock().unwrap();
+                for operation in &operations {
+                    if let Some(at) = match operation {
+                        DiffOperation::Insert(_, text) => sent.get(text),
+                        _ => None,
+                    } {
+                        latencies.lock().unwrap().push(received - *at);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Connects `count` clients named after the scenario, one after another so the first ones
+/// get the editor slots
+async fn connect_all(server: &Server, scenario: &str, count: usize, sent: &Sent, latencies: &Latencies) -> Result<Vec<Client>, String> {
+    let mut clients = Vec::with_capacity(count);
+    for n in 0..count {
+        clients.push(Client::connect(server, &format!("{}-{}", scenario, n), sent, latencies).await?);
+    }
+    Ok(clients)
+}
+
+/// Waits until every client has every edit the clients sent, since they loaded revision `start`,
+/// that the server didn't refuse, returning whether that happened within `SETTLE_TIMEOUT`
+async fn settle(clients: &[Client], start: u64) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < SETTLE_TIMEOUT {
+        let accepted = clients.iter().map(|client| client.edits).sum::<usize>() - total(clients, |view| view.errors);
+        if clients.iter().all(|client| client.view.lock().unwrap().revision >= start + accepted as u64) {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    false
+}
+
+/// The revision the clients loaded
+fn revision(clients: &[Client]) -> u64 {
+    clients.first().map_or(0, |client| client.view.lock().unwrap().revision)
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Sums a count over the clients' views
+fn total(clients: &[Client], count: impl Fn(&View) -> usize) -> usize {
+    clients.iter().map(|client| count(&client.view.lock().unwrap())).sum()
+}
+
+/// Writers edit at a steady rate while everyone receives the deltas; measures how long each
+/// delta takes to reach each client
+async fn fanout(server: &Server, options: &Options) -> Result<Value, String> {
+    let (sent, latencies) = (Sent::default(), Latencies::default());
+    let mut readers = connect_all(server, "fanout", options.clients, &sent, &latencies).await?;
+    let writers: Vec<Client> = readers.drain(..options.writers).collect();
+    let start = revision(&writers);
+
+    let period = Duration::from_secs_f64(options.writers as f64 / options.rate);
+    let deadline = Instant::now() + Duration::from_secs(options.seconds);
+    let tasks: Vec<JoinHandle<Result<Client, String>>> = writers
+        .into_iter()
+        .enumerate()
+        .map(|(writer, mut client)| {
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(period);
+                let mut edit = 0;
+                while Instant::now() < deadline {
+                    ticks.tick().await;
+                    client.insert(&format!("{}.{};", writer, edit)).await?;
+                    edit += 1;
+                }
+                Ok(client)
+            })
+        })
+        .collect();
+    let mut clients = Vec::new();
+    for task in tasks {
+        clients.push(task.await.map_err(|e| e.to_string())??);
+    }
+    clients.append(&mut readers);
+    let settled = settle(&clients, start).await;
+
+    let latencies = latencies.lock().unwrap().clone();
+    let at = |q: f64| quantile(&latencies, q).map(milliseconds);
+    let report = json!({
+        "clients": options.clients,
+        "writers": options.writers,
+        "rate": options.rate,
+        "seconds": options.seconds,
+        "edits": clients.iter().map(|client| client.edits).sum::<usize>(),
+        "rejected": total(&clients, |view| view.errors),
+        "deliveries": latencies.len(),
+        "resyncs": total(&clients, |view| view.resyncs),
+        "p50_ms": at(0.5),
+        "p99_ms": at(0.99),
+        "max_ms": at(1.0),
+        "settled": settled,
+    });
+    for client in clients {
+        client.close().await;
+    }
+    Ok(report)
+}
+
+/// Every writer sends its share of a burst of edits at once; measures how long until all
+/// clients have every edit, and checks that they hold the same document then
+async fn convergence(server: &Server, options: &Options) -> Result<Value, String> {
+    let (sent, latencies) = (Sent::default(), Latencies::default());
+    let mut readers = connect_all(server, "convergence", options.clients, &sent, &latencies).await?;
+    let writers: Vec<Client> = readers.drain(..options.writers).collect();
+    let start = revision(&writers);
+
+    let started = Instant::now();
+    let per_writer = options.burst.div_ceil(options.writers);
+    let tasks: Vec<JoinHandle<Result<Client, String>>> = writers
+        .into_iter()
+        .enumerate()
+        .map(|(writer, mut client)| {
+            tokio::spawn(async move {
+                for edit in 0..per_writer {
+                    client.insert(&format!("{}:{};", writer, edit)).await?;
+                }
+                Ok(client)
+            })
+        })
+        .collect();
+    let mut clients = Vec::new();
+    for task in tasks {
+        clients.push(task.await.map_err(|e| e.to_string())??);
+    }
+    clients.append(&mut readers);
+    let settled = settle(&clients, start).await;
+    let converged = started.elapsed();
+
+    let contents: Vec<String> = clients.iter().map(|client| client.view.lock().unwrap().content.clone()).collect();
+    let report = json!({
+        "clients": options.clients,
+        "writers": options.writers,
+        "burst": per_writer * options.writers,
+        "rejected": total(&clients, |view| view.errors),
+        "resyncs": total(&clients, |view| view.resyncs),
+        "converged_ms": settled.then(|| milliseconds(converged)),
+        "consistent": contents.windows(2).all(|pair| pair[0] == pair[1]),
+        "settled": settled,
+    });
+    for client in clients {
+        client.close().await;
+    }
+    Ok(report)
+}
+
+/// Opens idle connections and measures how much the server's memory grows per connection.
+/// The server hosts a single pad, so this is the cost of a connection rather than a room.
+async fn idle(server: &Server, options: &Options) -> Result<Value, String> {
+    let before = server.resident_bytes();
+    let (sent, latencies) = (Sent::default(), Latencies::default());
+    let clients = connect_all(server, "idle", options.idle_clients, &sent, &latencies).await?;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let after = server.resident_bytes();
+    let report = json!({
+        "connections": options.idle_clients,
+        "resident_bytes": after,
+        "bytes_per_connection": before.zip(after).map(|(before, after)| after.saturating_sub(before) / options.idle_clients.max(1) as u64),
+    });
+    for client in clients {
+        client.close().await;
+    }
+    Ok(report)
+}
+
+/// Fills the document to each size and times a new client loading it, from connecting to
+/// having the whole document
+async fn snapshot(server: &Server, options: &Options) -> Result<Value, String> {
+    let (sent, latencies) = (Sent::default(), Latencies::default());
+    let mut sizes = Vec::new();
+    for &size in &options.snapshot_sizes {
+        let line = "let value = compute(42); // filler\n";
+        let content: String = line.repeat(size / line.len() + 1)[..size].to_string();
+        let mut writer = Client::connect(server, "snapshot-writer", &sent, &latencies).await?;
+        let start = revision(std::slice::from_ref(&writer));
+        writer.replace(&content).await?;
+        if !settle(std::slice::from_ref(&writer), start).await || writer.view.lock().unwrap().errors > 0 {
+            return Err(format!("The server didn't take a {} byte document", size));
+        }
+        writer.close().await;
+
+        let mut loads = Vec::new();
+        for n in 0..SNAPSHOT_LOADS {
+            let started = Instant::now();
+            let reader = Client::connect(server, &format!("snapshot-{}", n), &sent, &latencies).await?;
+            loads.push(started.elapsed());
+            reader.close().await;
+        }
+        sizes.push(json!({ "bytes": size, "load_ms": quantile(&loads, 0.5).map(milliseconds) }));
+    }
+    Ok(json!(sizes))
+}
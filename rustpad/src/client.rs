@@ -1,7 +1,12 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use warp::ws::Message;
-use tokio::sync::mpsc;
+use futures_util::{Sink, SinkExt};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::{AbortHandle, JoinHandle};
+
+/// Messages queued for each client before it counts as too slow to keep up and is dropped
+pub const CLIENT_QUEUE_SIZE: usize = 256;
 
 /// Type alias for the shared state containing the list of connected clients.
 /// The `Clients` is an `Arc<Mutex<HashMap<String, Client>>>` to allow safe shared access.
@@ -13,25 +18,50 @@ pub type Clients = Arc<Mutex<HashMap<String, Client>>>;
 pub struct Client {
     pub id: String,
     pub username: String,  // Additional field to store the client's username for identification
-    pub sender: Option<mpsc::UnboundedSender<Message>>, // Unbounded sender for WebSocket messages
+    pub sender: Option<mpsc::Sender<Message>>, // Bounded queue of messages for the WebSocket
+    pub writer: Option<AbortHandle>, // Task draining the queue, stopped when the client is dropped
 }
 
 impl Client {
     /// Creates a new client with the given ID, username, and WebSocket sender.
-    pub fn new(id: &str, username: &str, sender: mpsc::UnboundedSender<Message>) -> Self {
+    pub fn new(id: &str, username: &str, sender: mpsc::Sender<Message>) -> Self {
         Client {
             id: id.to_string(),
             username: username.to_string(),
             sender: Some(sender),
+            writer: None,
         }
     }
 
+    /// Stops `writer` when the client is dropped for falling behind, which ends its connection
+    pub fn with_writer(self, writer: AbortHandle) -> Self {
+        Self { writer: Some(writer), ..self }
+    }
+
     /// Disconnects the client by setting its sender to `None`.
     pub fn disconnect(&mut self) {
         self.sender = None;
     }
 }
 
+/// Queues up to `CLIENT_QUEUE_SIZE` messages for `sink` and writes them from a task of its own,
+/// so broadcasting never waits on a slow client. The task ends when a write fails or every
+/// sender is dropped.
+pub fn spawn_writer<S>(mut sink: S) -> (mpsc::Sender<Message>, JoinHandle<()>)
+where
+    S: Sink<Message> + Unpin + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::channel(CLIENT_QUEUE_SIZE);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            if sink.send(message).await.is_err() {
+                break; // Client disconnected
+            }
+        }
+    });
+    (sender, writer)
+}
+
 /// Adds a client to the list of connected clients.
 pub fn add_client(clients: Clients, id: String, client: Client) {
     clients.lock().unwrap().insert(id, client);
@@ -42,33 +72,50 @@ pub fn remove_client(clients: Clients, id: &str) {
     clients.lock().unwrap().remove(id);
 }
 
+/// Snapshots the senders of all connected clients, so messages are sent without holding the lock.
+pub fn senders(clients: &Clients) -> Vec<(String, mpsc::Sender<Message>)> {
+    clients.lock().unwrap().iter().filter_map(|(id, client)| Some((id.clone(), client.sender.clone()?))).collect()
+}
+
+/// Queues `message` for each of `senders` without waiting. Clients whose queue is full have
+/// fallen too far behind to catch up, so they are removed and their writers stopped.
+fn send_all(clients: &Clients, senders: Vec<(String, mpsc::Sender<Message>)>, message: &str) {
+    let mut lagging = Vec::new();
+    for (id, sender) in senders {
+        match sender.try_send(Message::text(message.to_string())) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => lagging.push(id),
+            Err(e) => eprintln!("Failed to send message to client: {}", e),
+        }
+    }
+    if lagging.is_empty() {
+        return;
+    }
+    let mut clients = clients.lock().unwrap();
+    for id in lagging {
+        if let Some(writer) = clients.remove(&id).and_then(|client| client.writer) {
+            writer.abort();
+        }
+        eprintln!("Client {} fell {} messages behind; dropping it", id, CLIENT_QUEUE_SIZE);
+    }
+}
+
 /// Broadcasts a message to all connected clients.
 /// This function serializes the message and sends it to all clients.
 pub fn broadcast_message(clients: Clients, message: &str) {
-    let clients_guard = clients.lock().unwrap();
+    send_all(&clients, senders(&clients), message);
+}
 
-    // Send the message to each connected client
-    for (_id, client) in clients_guard.iter() {
-        if let Some(sender) = &client.sender {
-            if let Err(e) = sender.send(Message::text(message.to_string())) {
-                eprintln!("Failed to send message to client: {}", e);
-            }
-        }
-    }
+/// Broadcasts a message to all connected clients except `excluded_id`, usually the one it came from.
+pub fn broadcast_message_except(clients: Clients, message: &str, excluded_id: &str) {
+    let senders = senders(&clients).into_iter().filter(|(id, _)| id.as_str() != excluded_id).collect();
+    send_all(&clients, senders, message);
 }
 
 /// Broadcasts a personalized message to all connected clients, identifying the sender.
 pub fn broadcast_personalized_message(clients: Clients, message: &str, sender_username: &str) {
-    let clients_guard = clients.lock().unwrap();
-
-    for (_id, client) in clients_guard.iter() {
-        if let Some(sender) = &client.sender {
-            let personalized_message = format!("{} says: {}", sender_username, message);
-            if let Err(e) = sender.send(Message::text(personalized_message.clone())) {
-                eprintln!("Failed to send message to client: {}", e);
-            }
-        }
-    }
+    let personalized_message = format!("{} says: {}", sender_username, message);
+    send_all(&clients, senders(&clients), &personalized_message);
 }
 
 /// Returns the number of connected clients.
@@ -78,10 +125,91 @@ pub fn get_client_count(clients: Clients) -> usize {
 
 /// Lists all connected clients' IDs and usernames.
 pub fn list_clients(clients: Clients) -> Vec<(String, String)> {
-    clients.lock().unwrap().iter().map(|(_id, client)| (client.id.clone(), client.username.clone())).collect()
+    clients.lock().unwrap().values().map(|client| (client.id.clone(), client.username.clone())).collect()
 }
 
 /// Retrieves a specific client by ID.
 pub fn get_client_by_id(clients: Clients, id: &str) -> Option<Client> {
     clients.lock().unwrap().get(id).cloned()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_connections_and_broadcasts_never_block() {
+        const CONNECTIONS: usize = 100;
+        const BROADCASTS: usize = 20;
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+
+        // Every connection joins, broadcasts rapidly while the others do the same, then reads
+        // until it has seen every broadcast
+        let tasks: Vec<_> = (0..CONNECTIONS)
+            .map(|i| {
+                let clients = clients.clone();
+                tokio::spawn(async move {
+                    let (sender, mut receiver) = mpsc::channel(CONNECTIONS * BROADCASTS); // Room for every broadcast
+                    add_client(clients.clone(), i.to_string(), Client::new(&i.to_string(), "ana", sender));
+                    tokio::task::yield_now().await;
+                    for n in 0..BROADCASTS {
+                        broadcast_message(clients.clone(), &format!("{}:{}", i, n));
+                        tokio::task::yield_now().await;
+                    }
+                    let mut received = 0;
+                    while received < BROADCASTS {
+                        let message = receiver.recv().await.unwrap();
+                        if message.to_str().unwrap().starts_with(&format!("{}:", i)) {
+                            received += 1; // Its own broadcasts arrive after joining, in order
+                        }
+                    }
+                    remove_client(clients, &i.to_string());
+                })
+            })
+            .collect();
+
+        let all = futures::future::join_all(tasks);
+        let results = tokio::time::timeout(Duration::from_secs(10), all).await.expect("a task blocked past the deadline");
+        assert!(results.into_iter().all(|result| result.is_ok()));
+        assert_eq!(get_client_count(clients), 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_is_dropped_without_holding_up_the_others() {
+        const BROADCASTS: usize = CLIENT_QUEUE_SIZE * 4;
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+
+        // Fast clients write straight into a channel; the slow one takes a second per message
+        let mut fast = Vec::new();
+        for id in ["ana", "bo"] {
+            let (sink, receiver) = futures::channel::mpsc::unbounded::<Message>();
+            let (sender, writer) = spawn_writer(sink);
+            add_client(clients.clone(), id.to_string(), Client::new(id, id, sender).with_writer(writer.abort_handle()));
+            fast.push(receiver);
+        }
+        let slow_sink = Box::pin(futures::sink::unfold((), |(), _: Message| async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok::<_, std::convert::Infallible>(())
+        }));
+        let (sender, slow_writer) = spawn_writer(slow_sink);
+        add_client(clients.clone(), "slow".to_string(), Client::new("slow", "slow", sender).with_writer(slow_writer.abort_handle()));
+
+        // Broadcasting never waits, and the fast clients get everything promptly
+        for n in 0..BROADCASTS {
+            broadcast_message(clients.clone(), &n.to_string());
+            tokio::task::yield_now().await; // As between edits arriving, letting the writers run
+        }
+        for receiver in &mut fast {
+            let received = tokio::time::timeout(Duration::from_secs(1), receiver.take(BROADCASTS).collect::<Vec<_>>()).await.expect("a fast client was held up");
+            assert_eq!(received.last().unwrap().to_str().unwrap(), (BROADCASTS - 1).to_string());
+        }
+
+        // The slow client overflowed its queue, so it was dropped and its writer stopped
+        let mut remaining: Vec<_> = list_clients(clients.clone()).into_iter().map(|(id, _)| id).collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["ana", "bo"]);
+        assert!(slow_writer.await.unwrap_err().is_cancelled());
+    }
+}
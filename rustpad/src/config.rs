@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Settings for an OAuth2 identity provider using the authorization-code flow.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OAuthProviderConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub auth_url: String,     // Where the user is sent to authorize
+    pub token_url: String,    // Where the authorization code is exchanged
+    pub userinfo_url: String, // Where the user profile is fetched
+    pub redirect_url: String, // Our callback URL registered with the provider
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl OAuthProviderConfig {
+    /// GitHub provider settings.
+    pub fn github(client_id: &str, client_secret: &str, redirect_url: &str) -> Self {
+        OAuthProviderConfig {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            auth_url: "https://github.com/login/oauth/authorize".to_string(),
+            token_url: "https://github.com/login/oauth/access_token".to_string(),
+            userinfo_url: "https://api.github.com/user".to_string(),
+            redirect_url: redirect_url.to_string(),
+            scopes: vec!["read:user".to_string()],
+        }
+    }
+
+    /// Google provider settings.
+    pub fn google(client_id: &str, client_secret: &str, redirect_url: &str) -> Self {
+        OAuthProviderConfig {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            auth_url: "https://accounts.google.com/o/oauth2/v2/auth".to_string(),
+            token_url: "https://oauth2.googleapis.com/token".to_string(),
+            userinfo_url: "https://openidconnect.googleapis.com/v1/userinfo".to_string(),
+            redirect_url: redirect_url.to_string(),
+            scopes: vec!["openid".to_string(), "profile".to_string(), "email".to_string()],
+        }
+    }
+}
+
+/// Port the server listens on, by default.
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Updates the broadcast channel holds for clients that haven't caught up yet, by default.
+pub const DEFAULT_BROADCAST_CAPACITY: usize = 100;
+
+/// Server-wide configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub oauth_providers: HashMap<String, OAuthProviderConfig>, // Keyed by provider name used in routes
+    #[serde(default)]
+    pub static_dir: Option<PathBuf>, // Serves the frontend from disk over the embedded copy, for development
+    #[serde(default)]
+    pub max_total_editors: Option<usize>, // Editors across all rooms; `None` leaves only the per-room caps
+    #[serde(default)]
+    pub broadcast_capacity: Option<usize>, // Clients further behind than this are resynced; `None` for the default
+    #[serde(default)]
+    pub admin_key: Option<String>, // Bearer token for managing API tokens and the admin view of presence
+    #[serde(default)]
+    pub max_subscribers: Option<usize>, // Read-only token connections per room; `None` for the default
+    #[serde(default)]
+    pub port: Option<u16>, // `None` for the default
+}
+
+impl ServerConfig {
+    /// Creates a configuration with default settings.
+    pub fn new() -> Self {
+        ServerConfig::default()
+    }
+
+    /// Registers an OAuth2 provider under `name`.
+    pub fn with_oauth_provider(mut self, name: &str, provider: OAuthProviderConfig) -> Self {
+        self.oauth_providers.insert(name.to_string(), provider);
+        self
+    }
+
+    /// Serves frontend files from `dir` before falling back to the ones embedded in the binary.
+    pub fn with_static_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.static_dir = Some(dir.into());
+        self
+    }
+
+    /// Caps the number of editors across all rooms.
+    pub fn with_max_total_editors(mut self, max_total_editors: usize) -> Self {
+        self.max_total_editors = Some(max_total_editors);
+        self
+    }
+
+    /// Sets how many updates a client may fall behind before it gets the whole document instead.
+    pub fn with_broadcast_capacity(mut self, broadcast_capacity: usize) -> Self {
+        self.broadcast_capacity = Some(broadcast_capacity);
+        self
+    }
+
+    /// Lets requests bearing `admin_key` manage API tokens and see hidden subscribers.
+    pub fn with_admin_key(mut self, admin_key: &str) -> Self {
+        self.admin_key = Some(admin_key.to_string());
+        self
+    }
+
+    /// Caps the read-only API token connections of each room.
+    pub fn with_max_subscribers(mut self, max_subscribers: usize) -> Self {
+        self.max_subscribers = Some(max_subscribers);
+        self
+    }
+
+    /// Listens on `port` instead of the default.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// The port the server listens on.
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+
+    /// Capacity of the broadcast channel, at least 1.
+    pub fn broadcast_capacity(&self) -> usize {
+        self.broadcast_capacity.unwrap_or(DEFAULT_BROADCAST_CAPACITY).max(1)
+    }
+
+    /// Looks up the OAuth2 provider registered under `name`.
+    pub fn oauth_provider(&self, name: &str) -> Option<&OAuthProviderConfig> {
+        self.oauth_providers.get(name)
+    }
+}
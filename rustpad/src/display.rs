@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Colors remote cursors are drawn in. Every palette has the same number of colors, so a
+/// collaborator's color index picks a color in whichever palette the viewer chose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Palette {
+    #[default]
+    Default,
+    Deuteranopia,
+    Protanopia,
+    Tritanopia,
+    HighContrast,
+}
+
+/// Colors in each palette
+pub const PALETTE_SIZE: usize = 8;
+
+impl Palette {
+    /// The colors of the palette, as `#rrggbb`
+    pub fn colors(&self) -> &'static [&'static str; PALETTE_SIZE] {
+        match self {
+            Palette::Default => &["#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#9a6324"],
+            // Okabe-Ito, told apart without red-green vision
+            Palette::Deuteranopia => &["#e69f00", "#56b4e9", "#009e73", "#f0e442", "#0072b2", "#d55e00", "#cc79a7", "#000000"],
+            // Paul Tol's bright scheme, avoiding the dark reds protanopes see as black
+            Palette::Protanopia => &["#4477aa", "#66ccee", "#228833", "#ccbb44", "#ee6677", "#aa3377", "#bbbbbb", "#332288"],
+            // Reds and blue-greens, without the blue-yellow pairs tritanopes confuse
+            Palette::Tritanopia => &["#dc3220", "#005ab5", "#1a1a1a", "#e66100", "#5d3a9b", "#40b0a6", "#994f00", "#d35fb7"],
+            Palette::HighContrast => &["#ff0000", "#0000ff", "#008000", "#ff00ff", "#00ffff", "#ffff00", "#000000", "#ffffff"],
+        }
+    }
+
+    /// The color for color index `index`
+    pub fn color(&self, index: usize) -> &'static str {
+        self.colors()[index % PALETTE_SIZE]
+    }
+}
+
+/// When the names of remote users are shown next to their cursors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelMode {
+    #[default]
+    Always,
+    OnHover,
+    Never,
+}
+
+/// How a user sees the other users in a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct DisplayPreferences {
+    #[serde(default)]
+    pub palette: Palette, // Colors of remote cursors, as sent to this user
+    #[serde(default)]
+    pub labels: LabelMode, // When names are shown next to remote cursors
+    #[serde(default)]
+    pub dim_away_users: bool, // Draw the cursors of users who are away faded
+}
+
+/// Lowest contrast ratio for text, WCAG 2 level AA
+pub const MIN_TEXT_CONTRAST: f64 = 4.5;
+/// Lowest contrast ratio for other things drawn against the background, like the caret
+pub const MIN_UI_CONTRAST: f64 = 3.0;
+
+/// Theme colors that have to stand out against each other, with the lowest ratio each needs
+const CONTRAST_PAIRS: &[(&str, &str, f64)] = &[
+    ("text", "background", MIN_TEXT_CONTRAST),
+    ("text", "selection", MIN_TEXT_CONTRAST),
+    ("text", "line_highlight", MIN_TEXT_CONTRAST),
+    ("caret", "background", MIN_UI_CONTRAST),
+];
+
+/// Two theme colors with too little contrast between them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContrastWarning {
+    pub foreground: String, // Color names, as keyed in the theme
+    pub background: String,
+    pub ratio: f64, // Rounded to two decimals
+    pub minimum: f64,
+}
+
+/// Relative luminance of a `#rgb` or `#rrggbb` color, `None` when it is neither
+fn luminance(color: &str) -> Option<f64> {
+    let digits = color.trim().strip_prefix('#')?;
+    let digits: String = match digits.len() {
+        3 => digits.chars().flat_map(|c| [c, c]).collect(),
+        6 => digits.to_string(),
+        _ => return None,
+    };
+    let channel = |i: usize| -> Option<f64> {
+        let value = f64::from(u8::from_str_radix(digits.get(i..i + 2)?, 16).ok()?) / 255.0;
+        Some(if value <= 0.03928 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) })
+    };
+    Some(0.2126 * channel(0)? + 0.7152 * channel(2)? + 0.0722 * channel(4)?)
+}
+
+/// The WCAG contrast ratio between two colors, from 1 to 21; `None` unless both are hex colors.
+pub fn contrast_ratio(first: &str, second: &str) -> Option<f64> {
+    let (first, second) = (luminance(first)?, luminance(second)?);
+    Some((first.max(second) + 0.05) / (first.min(second) + 0.05))
+}
+
+/// Checks the colors of a theme, keyed like `ui::theme` colors, against the WCAG contrast
+/// minimums. Pairs the theme doesn't set are skipped.
+pub fn contrast_warnings(colors: &HashMap<String, String>) -> Vec<ContrastWarning> {
+    CONTRAST_PAIRS
+        .iter()
+        .filter_map(|&(foreground, background, minimum)| {
+            let ratio = contrast_ratio(colors.get(foreground)?, colors.get(background)?)?;
+            (ratio < minimum).then(|| ContrastWarning {
+                foreground: foreground.to_string(),
+                background: background.to_string(),
+                ratio: (ratio * 100.0).round() / 100.0,
+                minimum,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sessions::{self, Sessions, UserSettings};
+
+    #[test]
+    fn test_contrast_warnings() {
+        assert_eq!(contrast_ratio("#000", "#ffffff"), Some(21.0));
+        assert_eq!(contrast_ratio("#777777", "#777777"), Some(1.0));
+        assert_eq!(contrast_ratio("#777777", "grey"), None);
+
+        let colors = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(name, color)| (name.to_string(), color.to_string())).collect()
+        };
+        assert!(contrast_warnings(&colors(&[("background", "#ffffff"), ("text", "#000000"), ("caret", "#333333")])).is_empty());
+
+        // Grey on white is below AA; the caret passes the lower minimum for non-text
+        let warnings = contrast_warnings(&colors(&[("background", "#ffffff"), ("text", "#999999"), ("caret", "#888888")]));
+        assert_eq!(
+            warnings,
+            vec![ContrastWarning { foreground: "text".to_string(), background: "background".to_string(), ratio: 2.85, minimum: MIN_TEXT_CONTRAST }]
+        );
+    }
+
+    #[test]
+    fn test_palettes_share_color_indexes() {
+        for palette in [Palette::Deuteranopia, Palette::Protanopia, Palette::Tritanopia, Palette::HighContrast] {
+            assert_ne!(palette.color(0), Palette::Default.color(0));
+            assert_eq!(palette.color(PALETTE_SIZE + 3), palette.color(3));
+        }
+    }
+
+    #[test]
+    fn test_display_preferences_round_trip_and_persist() {
+        let display = DisplayPreferences { palette: Palette::Tritanopia, labels: LabelMode::OnHover, dim_away_users: true };
+        let json = serde_json::to_value(display).unwrap();
+        assert_eq!(json, serde_json::json!({ "palette": "tritanopia", "labels": "on_hover", "dim_away_users": true }));
+        assert_eq!(serde_json::from_value::<DisplayPreferences>(json).unwrap(), display);
+        assert_eq!(serde_json::from_str::<DisplayPreferences>("{}").unwrap(), DisplayPreferences::default());
+        assert!(serde_json::from_str::<DisplayPreferences>(r#"{"labels":"sometimes"}"#).is_err());
+
+        // Kept with the rest of the session's settings
+        let sessions = Sessions::default();
+        sessions::save_settings(&sessions, "session", UserSettings { display, ..UserSettings::default() }).unwrap();
+        assert_eq!(sessions::load_settings(&sessions, "session").display, display);
+        assert_eq!(sessions::load_settings(&sessions, "other").display, DisplayPreferences::default());
+    }
+}
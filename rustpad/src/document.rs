@@ -1,25 +1,23 @@
 use serde::{Deserialize, Serialize};
+use crate::rooms::DEFAULT_MAX_EDITORS;
+use crate::validation::Username;
 
 /// Represents an update to the document. This struct is shared between
 /// the server and clients to communicate document changes.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DocumentUpdate {
     pub content: String,
-    pub user: String,
-    pub timestamp: String,  // Adding a timestamp to track when the update occurred
+    pub user: Username,
+    pub timestamp: String,  // When the update occurred, in RFC 3339 UTC
 }
 
 impl DocumentUpdate {
     /// Creates a new `DocumentUpdate` with the given content, user, and timestamp.
-    pub fn new(content: &str, user: &str) -> Self {
+    pub fn new(content: &str, user: Username) -> Self {
         DocumentUpdate {
             content: content.to_string(),
-            user: user.to_string(),
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-                .to_string(),
+            user,
+            timestamp: crate::i18n::timestamp(chrono::Utc::now()),
         }
     }
 }
@@ -29,6 +27,18 @@ impl DocumentUpdate {
 pub struct Document {
     pub content: String,
     pub history: Vec<DocumentUpdate>, // History of updates for undo/redo functionality
+    #[serde(default = "default_max_editors")]
+    pub max_editors: usize, // Concurrent editors allowed; later arrivals wait as viewers
+}
+
+fn default_max_editors() -> usize {
+    DEFAULT_MAX_EDITORS
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Document {
@@ -37,6 +47,7 @@ impl Document {
         Document {
             content: String::new(),
             history: Vec::new(),
+            max_editors: DEFAULT_MAX_EDITORS,
         }
     }
 
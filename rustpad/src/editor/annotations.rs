@@ -1,8 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use warp::ws::{Message, WebSocket};
-use futures_util::{StreamExt, SinkExt};
+use warp::Filter;
+use futures_util::StreamExt;
+use uuid::Uuid;
+use crate::client::{self, Client, Clients};
+use crate::rate_limit::RateLimiter;
+
+/// Longest annotation accepted, in characters
+pub const MAX_ANNOTATION_LEN: usize = 2000;
+/// Annotations kept across all lines; the oldest are dropped beyond this
+pub const MAX_ANNOTATIONS: usize = 10_000;
+/// Annotations each user may add per `ANNOTATION_RATE_WINDOW`
+pub const ANNOTATION_RATE_LIMIT: usize = 20;
+pub const ANNOTATION_RATE_WINDOW: Duration = Duration::from_secs(10);
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Annotation {
@@ -13,12 +26,15 @@ pub struct Annotation {
 }
 
 type Annotations = Arc<Mutex<HashMap<usize, Vec<Annotation>>>>; // Keyed by line number
-type AnnotationClients = Arc<Mutex<Vec<warp::ws::WebSocket>>>;
 
 /// Manages the inline annotations and provides real-time updates to collaborators
+#[derive(Clone)]
 pub struct AnnotationManager {
     annotations: Annotations,
-    clients: AnnotationClients,
+    order: Arc<Mutex<VecDeque<usize>>>, // Line of each annotation, oldest first
+    clients: Clients, // Each client's messages are forwarded to its socket by a task of its own
+    rate_limiter: RateLimiter,
+    max_annotations: usize,
 }
 
 impl AnnotationManager {
@@ -26,60 +42,90 @@ impl AnnotationManager {
     pub fn new() -> Self {
         Self {
             annotations: Arc::new(Mutex::new(HashMap::new())),
-            clients: Arc::new(Mutex::new(Vec::new())),
+            order: Arc::new(Mutex::new(VecDeque::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            rate_limiter: RateLimiter::new(ANNOTATION_RATE_LIMIT, ANNOTATION_RATE_WINDOW),
+            max_annotations: MAX_ANNOTATIONS,
         }
     }
 
-    /// Registers a new WebSocket client for receiving annotation updates
-    pub async fn register_client(&self, socket: WebSocket) {
-        let (mut ws_tx, mut ws_rx) = socket.split();
+    /// Keeps at most `max_annotations` instead of `MAX_ANNOTATIONS`
+    pub fn with_max_annotations(self, max_annotations: usize) -> Self {
+        Self { max_annotations, ..self }
+    }
 
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.push(ws_tx.clone());
-        }
+    /// Throttles each user with `rate_limiter` instead of the default limit
+    pub fn with_rate_limiter(self, rate_limiter: RateLimiter) -> Self {
+        Self { rate_limiter, ..self }
+    }
 
-        // Send existing annotations to the new client
+    /// Registers a new WebSocket client for receiving annotation updates
+    pub async fn register_client(self, socket: WebSocket) {
+        let (ws_tx, ws_rx) = socket.split();
+        let (sender, send_task) = client::spawn_writer(ws_tx);
+        let writer = send_task.abort_handle();
+
+        // Send existing annotations to the new client before it joins the broadcasts
         let annotations = self.annotations.lock().unwrap().clone();
-        let serialized_annotations = serde_json::to_string(&annotations).unwrap();
-        if ws_tx.send(Message::text(serialized_annotations)).await.is_err() {
-            println!("Failed to send annotations to client");
-        }
+        let _ = sender.send(Message::text(serde_json::to_string(&annotations).unwrap())).await;
+        let client_id = Uuid::new_v4().to_string();
+        client::add_client(self.clients.clone(), client_id.clone(), Client::new(&client_id, "", sender.clone()).with_writer(writer.clone()));
+
+        let mut ws_rx = ws_rx.take_until(send_task);
 
         // Listen for incoming annotation messages
         while let Some(result) = ws_rx.next().await {
             if let Ok(message) = result {
                 if message.is_text() {
-                    let annotation: Annotation = serde_json::from_str(message.to_str().unwrap()).unwrap();
-                    self.add_annotation(annotation.clone()).await;
-                    self.broadcast_annotation(annotation).await;
+                    let added = serde_json::from_str::<Annotation>(message.to_str().unwrap())
+                        .map_err(|e| format!("Invalid annotation: {}", e))
+                        .and_then(|annotation| self.add_annotation(annotation.clone(), Instant::now()).map(|_| annotation));
+                    match added {
+                        Ok(annotation) => self.broadcast_annotation(annotation),
+                        Err(e) => {
+                            let error = serde_json::json!({ "type": "error", "message": e }).to_string();
+                            let _ = sender.send(Message::text(error)).await;
+                        }
+                    }
                 }
             }
         }
 
         // Remove the WebSocket client when it disconnects
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.retain(|client| !client.is_closed());
-        }
+        client::remove_client(self.clients.clone(), &client_id);
+        writer.abort();
     }
 
-    /// Adds a new annotation to the map and associates it with a line number
-    pub async fn add_annotation(&self, annotation: Annotation) {
+    /// Adds a new annotation to the map and associates it with a line number. Annotations over
+    /// `MAX_ANNOTATION_LEN` or from a user over the rate limit are refused; once the map holds
+    /// `max_annotations`, the oldest annotation is dropped to make room.
+    pub fn add_annotation(&self, annotation: Annotation, now: Instant) -> Result<(), String> {
+        let length = annotation.content.chars().count();
+        if length > MAX_ANNOTATION_LEN {
+            return Err(format!("Annotations are limited to {} characters; this one has {}", MAX_ANNOTATION_LEN, length));
+        }
+        self.rate_limiter.check(&annotation.user, now)?;
+
         let mut annotations = self.annotations.lock().unwrap();
-        annotations.entry(annotation.line_number).or_insert_with(Vec::new).push(annotation);
+        let mut order = self.order.lock().unwrap();
+        order.push_back(annotation.line_number);
+        annotations.entry(annotation.line_number).or_default().push(annotation);
+
+        while order.len() > self.max_annotations {
+            let line_number = order.pop_front().unwrap();
+            if let Some(line) = annotations.get_mut(&line_number) {
+                line.remove(0); // Each line's annotations are in the order they were added
+                if line.is_empty() {
+                    annotations.remove(&line_number);
+                }
+            }
+        }
+        Ok(())
     }
 
     /// Broadcasts a new annotation to all connected clients
-    pub async fn broadcast_annotation(&self, annotation: Annotation) {
-        let message = serde_json::to_string(&annotation).unwrap();
-        let clients = self.clients.lock().unwrap();
-
-        for client in clients.iter() {
-            if client.send(Message::text(message.clone())).await.is_err() {
-                println!("Failed to send annotation to client");
-            }
-        }
+    pub fn broadcast_annotation(&self, annotation: Annotation) {
+        client::broadcast_message(self.clients.clone(), &serde_json::to_string(&annotation).unwrap());
     }
 
     /// Retrieves annotations for a specific line number
@@ -89,9 +135,15 @@ impl AnnotationManager {
     }
 }
 
+impl Default for AnnotationManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// WebSocket handler for annotations
-pub async fn annotation_ws_handler(ws: warp::ws::Ws, manager: AnnotationManager) -> impl warp::Reply {
-    ws.on_upgrade(move |socket| manager.register_client(socket))
+pub async fn annotation_ws_handler(ws: warp::ws::Ws, manager: AnnotationManager) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(ws.on_upgrade(move |socket| manager.register_client(socket)))
 }
 
 /// Route for WebSocket annotations
@@ -107,15 +159,38 @@ fn with_manager(manager: AnnotationManager) -> impl warp::Filter<Extract = (Anno
     warp::any().map(move || manager.clone())
 }
 
-/// Example of how to set up the server with WebSocket routes for annotations
-#[tokio::main]
-async fn main() {
-    let annotation_manager = AnnotationManager::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // WebSocket route for annotations
-    let annotation_ws_route = annotation_route(annotation_manager.clone());
+    fn annotation(user: &str, line_number: usize, content: &str) -> Annotation {
+        Annotation { user: user.to_string(), content: content.to_string(), line_number, timestamp: String::new() }
+    }
 
-    // Start the server
-    println!("Annotation server running on ws://localhost:3030/annotation_ws");
-    warp::serve(annotation_ws_route).run(([127, 0, 0, 1], 3030)).await;
+    #[test]
+    fn test_flood_is_throttled() {
+        let manager = AnnotationManager::new();
+        let now = Instant::now();
+        for _ in 0..ANNOTATION_RATE_LIMIT {
+            manager.add_annotation(annotation("spammer", 1, "spam"), now).unwrap();
+        }
+        assert!(manager.add_annotation(annotation("spammer", 1, "spam"), now).is_err());
+        assert!(manager.add_annotation(annotation("alice", 1, "fine"), now).is_ok());
+        assert_eq!(manager.get_annotations_for_line(1).len(), ANNOTATION_RATE_LIMIT + 1);
+
+        let too_long = "x".repeat(MAX_ANNOTATION_LEN + 1);
+        assert!(manager.add_annotation(annotation("bob", 2, &too_long), now).is_err());
+    }
+
+    #[test]
+    fn test_oldest_annotations_are_evicted() {
+        let manager = AnnotationManager::new().with_max_annotations(3);
+        let now = Instant::now();
+        for (user, line) in [("a", 1), ("b", 2), ("c", 1), ("d", 3)] {
+            manager.add_annotation(annotation(user, line, user), now).unwrap();
+        }
+        let users = |line| manager.get_annotations_for_line(line).into_iter().map(|a| a.user).collect::<Vec<_>>();
+        assert_eq!(users(1), vec!["c"]);
+        assert_eq!((users(2), users(3)), (vec!["b".to_string()], vec!["d".to_string()]));
+    }
 }
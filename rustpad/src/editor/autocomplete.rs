@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::editor::extensions::Extension;
+use crate::editor::snippets::{list_snippets, SnippetStore};
+use crate::editor::state::EditorState;
+
+/// Suggestions returned per completion request
+pub const MAX_COMPLETIONS: usize = 20;
+
+/// How well a candidate matches the typed prefix; better matches sort first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Quality {
+    Prefix,           // Starts with the prefix as typed
+    PrefixIgnoreCase, // Starts with it in another case
+    WordStarts,       // The prefix's letters start words of it, as `gft` in `generate_file_tree`
+    Scattered,        // The prefix's letters appear in it in order
+}
+
+/// The built-in `autocomplete` extension: suggests identifiers used elsewhere in the document,
+/// and snippet names, for the word being typed at the cursor
+#[derive(Clone)]
+pub struct Autocomplete {
+    snippets: SnippetStore,
+}
+
+impl Autocomplete {
+    /// Creates an autocomplete suggesting identifiers only
+    pub fn new() -> Self {
+        Self { snippets: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Also suggests the names of the snippets in `snippets`
+    pub fn with_snippets(self, snippets: SnippetStore) -> Self {
+        Self { snippets }
+    }
+
+    /// Completions of `prefix` from the identifiers of the document in `state` and the snippet
+    /// names, up to `MAX_COMPLETIONS`. Better matches come first, then identifiers used nearest
+    /// the cursor, which are usually the ones written last; snippets come after identifiers
+    /// matching as well.
+    pub fn complete(&self, prefix: &str, state: &EditorState) -> Vec<String> {
+        if prefix.is_empty() {
+            return Vec::new();
+        }
+        let text = state.get_text();
+        let cursor = state.get_cursor_position();
+
+        // Each identifier once, at its occurrence nearest the cursor, leaving out the word
+        // being typed
+        let mut distances: HashMap<&str, usize> = HashMap::new();
+        for (start, identifier) in identifiers(&text) {
+            let end = start + identifier.len();
+            if (start..=end).contains(&cursor) || identifier == prefix {
+                continue;
+            }
+            let distance = if end < cursor { cursor - end } else { start - cursor };
+            let nearest = distances.entry(identifier).or_insert(distance);
+            *nearest = (*nearest).min(distance);
+        }
+
+        let mut candidates: Vec<(Quality, usize, String)> = distances
+            .into_iter()
+            .filter_map(|(identifier, distance)| quality(prefix, identifier).map(|quality| (quality, distance, identifier.to_string())))
+            .collect();
+        for snippet in list_snippets(self.snippets.clone()) {
+            if let Some(quality) = quality(prefix, &snippet.name) {
+                candidates.push((quality, usize::MAX, snippet.name));
+            }
+        }
+        candidates.sort();
+        candidates.dedup_by(|a, b| a.2 == b.2);
+        candidates.into_iter().take(MAX_COMPLETIONS).map(|(_, _, name)| name).collect()
+    }
+
+    /// The part of the identifier before the cursor, which completions replace
+    pub fn prefix_at_cursor(state: &EditorState) -> String {
+        let text = state.get_text();
+        let before = &text[..state.get_cursor_position()];
+        let start = before.char_indices().rev().take_while(|(_, c)| is_identifier_char(*c)).last().map_or(before.len(), |(at, _)| at);
+        before[start..].to_string()
+    }
+}
+
+impl Default for Autocomplete {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Extension for Autocomplete {
+    fn id(&self) -> String {
+        "autocomplete".to_string()
+    }
+
+    fn description(&self) -> String {
+        "Completes identifiers used elsewhere in the document, and snippet names.".to_string()
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Identifiers in `text` with their byte offsets: runs of letters, digits and underscores not
+/// starting with a digit
+fn identifiers(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut rest = text.char_indices().peekable();
+    std::iter::from_fn(move || loop {
+        let (start, first) = rest.next()?;
+        if !is_identifier_char(first) {
+            continue;
+        }
+        let mut end = start + first.len_utf8();
+        while let Some((at, c)) = rest.next_if(|(_, c)| is_identifier_char(*c)) {
+            end = at + c.len_utf8();
+        }
+        if !first.is_ascii_digit() {
+            return Some((start, &text[start..end]));
+        }
+    })
+}
+
+/// How `candidate` matches `prefix`, if it does
+fn quality(prefix: &str, candidate: &str) -> Option<Quality> {
+    if candidate.len() <= prefix.len() && candidate.to_lowercase() == prefix.to_lowercase() {
+        return None; // Nothing left to complete
+    }
+    if candidate.starts_with(prefix) {
+        return Some(Quality::Prefix);
+    }
+    if candidate.to_lowercase().starts_with(&prefix.to_lowercase()) {
+        return Some(Quality::PrefixIgnoreCase);
+    }
+
+    let word_starts: String = candidate
+        .char_indices()
+        .filter(|(at, c)| {
+            let previous = candidate[..*at].chars().next_back();
+            match previous {
+                None => true,
+                Some(previous) => (!is_identifier_char(previous) || previous == '_' || previous.is_lowercase() && c.is_uppercase()) && *c != '_',
+            }
+        })
+        .map(|(_, c)| c)
+        .collect();
+    if is_subsequence(prefix, &word_starts) {
+        return Some(Quality::WordStarts);
+    }
+    is_subsequence(prefix, candidate).then_some(Quality::Scattered)
+}
+
+/// Whether the letters of `needle` appear in `haystack` in order, ignoring case
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars().flat_map(char::to_lowercase);
+    needle.chars().flat_map(char::to_lowercase).all(|c| haystack.any(|h| h == c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::snippets::{add_snippet, Snippet};
+
+    fn state(text: &str, cursor: usize) -> EditorState {
+        let mut state = EditorState::new();
+        state.replace_text(text.to_string());
+        state.move_cursor(cursor);
+        state
+    }
+
+    #[test]
+    fn test_prefix_completes_identifiers_from_document() {
+        let text = "fn generate_file_tree() {}\nlet file_tree = generate_file_tree();\ngen";
+        let state = state(text, text.len());
+        let prefix = Autocomplete::prefix_at_cursor(&state);
+        assert_eq!(prefix, "gen");
+        let completions = Autocomplete::new().complete(&prefix, &state);
+        assert_eq!(completions, vec!["generate_file_tree".to_string()]);
+
+        // Fuzzy matches count too
+        let completions = Autocomplete::new().complete("gft", &state);
+        assert_eq!(completions, vec!["generate_file_tree".to_string()]);
+    }
+
+    #[test]
+    fn test_ranking_by_quality_then_nearness() {
+        let text = "let user_name = 1;\nlet username = 2;\nlet UserCount = 3;\nlet under_use = 4;\n\nlet total = us";
+        let state = state(text, text.len());
+        let snippets: SnippetStore = Arc::new(Mutex::new(HashMap::new()));
+        add_snippet(snippets.clone(), Snippet::new("use-statement", "", "use crate::;")).unwrap();
+
+        let completions = Autocomplete::new().with_snippets(snippets).complete("us", &state);
+        // Case-sensitive prefixes, the nearest first, then snippets; other cases; then fuzzy
+        assert_eq!(completions, vec!["username", "user_name", "use-statement", "UserCount", "under_use"]);
+        assert!(Autocomplete::new().complete("", &state).is_empty());
+    }
+}
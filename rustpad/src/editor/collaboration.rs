@@ -1,9 +1,23 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use futures_util::{StreamExt, SinkExt};
 use warp::ws::{Message, WebSocket};
-use tokio::sync::broadcast;
+use warp::Filter;
+use tokio::sync::{broadcast, mpsc, Notify};
 use chrono::Utc;
+use crate::storage::activity::{lines_changed, ActivityFeeds};
+use crate::storage::notifications::EditWatcher;
+
+/// Edits kept in the log by default; older ones are dropped
+pub const DEFAULT_EDIT_LOG_LIMIT: usize = 1000;
+
+/// Edits buffered for each client by default; clients further behind are resynced
+pub const DEFAULT_BROADCAST_CAPACITY: usize = 100;
+
+/// How long a client may stay silent before it is taken for gone
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// Represents a collaborative edit from a user
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -14,70 +28,432 @@ pub struct Edit {
     pub timestamp: String,
 }
 
+/// An edit as the log keeps it: the bytes it replaced in the document before it, and their
+/// replacement
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoggedEdit {
+    pub user: String,
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+    pub timestamp: String,
+}
+
+/// The most recent edits, and the document as it was before the oldest of them
+#[derive(Default)]
+struct EditLog {
+    base: String,
+    edits: Vec<LoggedEdit>,
+}
+
+impl EditLog {
+    /// Adds `edit`, folding the oldest edits beyond `max_edits` into the base
+    fn push(&mut self, edit: LoggedEdit, max_edits: usize) {
+        self.edits.push(edit);
+        let excess = self.edits.len().saturating_sub(max_edits);
+        for dropped in self.edits.drain(..excess) {
+            self.base.replace_range(dropped.start..dropped.end, &dropped.text);
+        }
+    }
+
+    /// The document the edits lead to
+    fn replay(&self) -> String {
+        let mut document = self.base.clone();
+        for edit in &self.edits {
+            document.replace_range(edit.start..edit.end, &edit.text);
+        }
+        document
+    }
+}
+
+/// An exclusive lock on a byte range of the document; only its user may edit inside it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegionLock {
+    pub user: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl RegionLock {
+    /// Whether an edit replacing `start..end` of the document reaches into the locked range.
+    /// Inserting right at either edge doesn't.
+    fn covers(&self, start: usize, end: usize) -> bool {
+        if start == end {
+            self.start < start && start < self.end
+        } else {
+            start < self.end && self.start < end
+        }
+    }
+
+    /// Moves the lock along with `editor` replacing `start..old_end` by text ending at `new_end`.
+    /// Its own user's edits inside or at the edges of the region grow or shrink it.
+    fn follow_edit(&mut self, editor: &str, start: usize, old_end: usize, new_end: usize) {
+        let own = editor == self.user;
+        let touches = if start == old_end { self.start <= start && start <= self.end } else { self.covers(start, old_end) };
+        if own && touches {
+            self.start = self.start.min(start);
+            self.end = if self.end >= old_end { self.end + new_end - old_end } else { new_end };
+        } else if self.start >= old_end {
+            self.start = self.start + new_end - old_end;
+            self.end = self.end + new_end - old_end;
+        }
+    }
+}
+
+/// Lock requests a client sends besides edits
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LockRequest {
+    Lock(RegionLock),
+    Unlock(RegionLock),
+}
+
+/// Session requests a client sends besides edits and locks. Every message counts as a
+/// heartbeat; a client with nothing else to send sends `Heartbeat`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionRequest {
+    Join { user: String },
+    Heartbeat,
+}
+
+/// Participants coming and going, sent to every client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ParticipantEvent {
+    Joined { client_id: String, user: String },
+    Left { client_id: String, user: Option<String>, timed_out: bool },
+}
+
+/// A connected client
+struct Participant {
+    user: Option<String>, // Set once the client joins
+    last_seen: Instant,
+    kick: Arc<Notify>, // Ends the connection when the client times out
+}
+
+/// Messages a client sends
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    Lock(LockRequest),
+    Session(SessionRequest),
+    Edit(Edit),
+}
+
 /// Manages collaborative editing and broadcasting updates to users
 pub struct CollaborationManager {
     document: Arc<Mutex<String>>,                 // Shared document content
-    edits: Arc<Mutex<Vec<Edit>>>,                 // Log of edits
+    locks: Arc<Mutex<Vec<RegionLock>>>,           // Locked regions, which never overlap
+    edits: Arc<Mutex<EditLog>>,                   // Log of the most recent edits
+    max_edits: usize,                             // Edits the log keeps before dropping the oldest
     broadcaster: broadcast::Sender<Edit>,         // Broadcast channel for updates
+    activity: Option<(String, ActivityFeeds)>,    // Document id and feeds that edits are summarized into
+    watcher: Option<(String, EditWatcher)>,       // Document id and watcher notifying authors of edited lines
+    participants: Arc<Mutex<HashMap<String, Participant>>>, // Connected clients, by client id
+    heartbeat_timeout: Duration,                  // Silence after which a client is reaped
+    presence: broadcast::Sender<ParticipantEvent>, // Arrivals and departures, for every client
 }
 
 impl CollaborationManager {
     /// Creates a new CollaborationManager with an empty document and edit log
     pub fn new() -> Self {
-        let (broadcaster, _) = broadcast::channel(100); // Create a broadcast channel with capacity
+        let (broadcaster, _) = broadcast::channel(DEFAULT_BROADCAST_CAPACITY);
+        let (presence, _) = broadcast::channel(100);
         Self {
             document: Arc::new(Mutex::new(String::new())),
-            edits: Arc::new(Mutex::new(Vec::new())),
+            locks: Arc::new(Mutex::new(Vec::new())),
+            edits: Arc::new(Mutex::new(EditLog::default())),
+            max_edits: DEFAULT_EDIT_LOG_LIMIT,
             broadcaster,
+            activity: None,
+            watcher: None,
+            participants: Arc::new(Mutex::new(HashMap::new())),
+            heartbeat_timeout: HEARTBEAT_TIMEOUT,
+            presence,
         }
     }
 
-    /// Registers a new WebSocket client for collaborative editing
-    pub async fn register_client(&self, socket: WebSocket) {
+    /// Reaps clients silent for longer than `heartbeat_timeout` instead of `HEARTBEAT_TIMEOUT`
+    pub fn with_heartbeat_timeout(self, heartbeat_timeout: Duration) -> Self {
+        Self { heartbeat_timeout, ..self }
+    }
+
+    /// Buffers `capacity` edits for each client instead of `DEFAULT_BROADCAST_CAPACITY`. Clients
+    /// that fall further behind miss the oldest and are sent the whole document instead.
+    pub fn with_broadcast_capacity(self, capacity: usize) -> Self {
+        Self { broadcaster: broadcast::channel(capacity.max(1)).0, ..self }
+    }
+
+    /// Keeps at most `max_edits` edits in the log
+    pub fn with_edit_limit(self, max_edits: usize) -> Self {
+        Self { max_edits, ..self }
+    }
+
+    /// Records edits to `doc_id` in its activity feed
+    pub fn with_activity(self, doc_id: &str, feeds: ActivityFeeds) -> Self {
+        Self { activity: Some((doc_id.to_string(), feeds)), ..self }
+    }
+
+    /// Notifies authors of `doc_id` who are away when their lines are edited
+    pub fn with_watcher(self, doc_id: &str, watcher: EditWatcher) -> Self {
+        Self { watcher: Some((doc_id.to_string(), watcher)), ..self }
+    }
+
+    /// Registers a new WebSocket client for collaborative editing. Locks taken through the
+    /// connection are released when it closes.
+    pub async fn register_client(self: Arc<Self>, socket: WebSocket) {
         let (mut ws_tx, mut ws_rx) = socket.split();
         let mut rx = self.broadcaster.subscribe();
+        let mut presence = self.presence.subscribe();
+        let sender = self.clone();
+        let client_id = uuid::Uuid::new_v4().to_string();
+        let kick = self.touch(&client_id, Instant::now());
+        let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<serde_json::Value>(); // Answers to this client alone
 
-        // Task to send document updates to the client
-        let send_task = tokio::spawn(async move {
-            while let Ok(edit) = rx.recv().await {
-                let msg = serde_json::to_string(&edit).unwrap();
+        // Task to send document updates and replies to the client
+        let mut send_task = tokio::spawn(async move {
+            loop {
+                let msg = tokio::select! {
+                    frame = sender.next_edit_frame(&mut rx) => match frame {
+                        Some(frame) => frame,
+                        None => break,
+                    },
+                    received = presence.recv() => match received {
+                        Ok(event) => serde_json::to_string(&event).unwrap(),
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    Some(reply) = reply_rx.recv() => reply.to_string(),
+                };
                 if ws_tx.send(Message::text(msg)).await.is_err() {
                     break; // Client disconnected
                 }
             }
         });
 
-        // Task to receive edits from the client
-        let recv_task = tokio::spawn(async move {
+        // Task to receive edits and lock requests from the client
+        let lockers = Arc::new(Mutex::new(HashSet::new())); // Users who took locks through this connection
+        let manager = self.clone();
+        let connection_lockers = lockers.clone();
+        let connection_id = client_id.clone();
+        let mut recv_task = tokio::spawn(async move {
             while let Some(result) = ws_rx.next().await {
                 if let Ok(msg) = result {
+                    manager.heartbeat(&connection_id, Instant::now());
                     if msg.is_text() {
-                        let edit: Edit = serde_json::from_str(msg.to_str().unwrap()).unwrap();
-                        self.apply_edit(edit.clone()).await;
-                        let _ = self.broadcaster.send(edit);  // Broadcast the edit to all clients
+                        // Malformed messages are dropped rather than taking the connection down
+                        let Ok(incoming) = serde_json::from_str::<Incoming>(msg.to_str().unwrap_or_default()) else {
+                            continue;
+                        };
+                        let reply = match incoming {
+                            Incoming::Session(SessionRequest::Join { user }) => {
+                                manager.join(&connection_id, &user, Instant::now());
+                                continue;
+                            }
+                            Incoming::Session(SessionRequest::Heartbeat) => continue,
+                            Incoming::Edit(edit) => match manager.apply_edit(edit.clone()).await {
+                                Ok(()) => {
+                                    let _ = manager.broadcaster.send(edit); // Broadcast the edit to all clients
+                                    continue;
+                                }
+                                Err(reason) => serde_json::json!({ "type": "edit_rejected", "reason": reason }),
+                            },
+                            Incoming::Lock(LockRequest::Lock(lock)) => {
+                                connection_lockers.lock().unwrap().insert(lock.user.clone());
+                                match manager.acquire_lock(&lock.user, lock.start, lock.end) {
+                                    Ok(lock) => serde_json::json!({ "type": "locked", "lock": lock }),
+                                    Err(e) => serde_json::json!({ "type": "error", "message": e }),
+                                }
+                            }
+                            Incoming::Lock(LockRequest::Unlock(lock)) => match manager.release_lock(&lock.user, lock.start, lock.end) {
+                                Ok(()) => serde_json::json!({ "type": "unlocked", "lock": lock }),
+                                Err(e) => serde_json::json!({ "type": "error", "message": e }),
+                            },
+                        };
+                        let _ = reply_tx.send(reply);
                     }
                 }
             }
         });
 
         tokio::select! {
-            _ = send_task => (),
-            _ = recv_task => (),
+            _ = &mut send_task => (),
+            _ = &mut recv_task => (),
+            _ = kick.notified() => (), // Silent for too long; its socket may be half dead
+        }
+        // Dropping both halves of the socket closes it
+        send_task.abort();
+        recv_task.abort();
+        self.leave(&client_id);
+        let lockers: Vec<String> = lockers.lock().unwrap().drain().collect();
+        for user in lockers {
+            self.release_locks(&user);
+        }
+    }
+
+    /// Number of clients currently receiving edits
+    pub fn subscriber_count(&self) -> usize {
+        self.broadcaster.receiver_count()
+    }
+
+    /// The next frame for a client receiving edits through `edits`: the next edit or, when the
+    /// client fell so far behind that edits were dropped, a `resync` frame with the whole
+    /// document. The edits it missed are skipped rather than sent with gaps in between.
+    async fn next_edit_frame(&self, edits: &mut broadcast::Receiver<Edit>) -> Option<String> {
+        match edits.recv().await {
+            Ok(edit) => Some(serde_json::to_string(&edit).unwrap()),
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                // Resubscribing first means every edit received afterwards is newer than the document sent
+                *edits = edits.resubscribe();
+                let content = self.get_document();
+                Some(serde_json::json!({ "type": "resync", "content": content, "missed": missed }).to_string())
+            }
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    }
+
+    /// Records that `client_id` was heard from at `now`
+    pub fn heartbeat(&self, client_id: &str, now: Instant) {
+        self.touch(client_id, now);
+    }
+
+    /// Registers `client_id` as `user` and tells every client they joined
+    pub fn join(&self, client_id: &str, user: &str, now: Instant) {
+        self.touch(client_id, now);
+        if let Some(participant) = self.participants.lock().unwrap().get_mut(client_id) {
+            participant.user = Some(user.to_string());
+        }
+        let _ = self.presence.send(ParticipantEvent::Joined { client_id: client_id.to_string(), user: user.to_string() });
+    }
+
+    /// Removes `client_id`, whose connection closed, and tells every client they left
+    pub fn leave(&self, client_id: &str) {
+        let Some(participant) = self.participants.lock().unwrap().remove(client_id) else { return };
+        let _ = self.presence.send(ParticipantEvent::Left { client_id: client_id.to_string(), user: participant.user, timed_out: false });
+    }
+
+    /// Removes the clients not heard from within the heartbeat timeout before `now`, ending
+    /// their connections and telling every client they left. Returns their client ids.
+    pub fn reap(&self, now: Instant) -> Vec<String> {
+        let stale: Vec<(String, Participant)> = {
+            let mut participants = self.participants.lock().unwrap();
+            let ids: Vec<String> = participants
+                .iter()
+                .filter(|(_, participant)| now.saturating_duration_since(participant.last_seen) > self.heartbeat_timeout)
+                .map(|(client_id, _)| client_id.clone())
+                .collect();
+            ids.into_iter().filter_map(|client_id| participants.remove(&client_id).map(|participant| (client_id, participant))).collect()
+        };
+        let mut reaped = Vec::new();
+        for (client_id, participant) in stale {
+            participant.kick.notify_one();
+            let _ = self.presence.send(ParticipantEvent::Left { client_id: client_id.clone(), user: participant.user, timed_out: true });
+            reaped.push(client_id);
+        }
+        reaped.sort();
+        reaped
+    }
+
+    /// Number of connected clients, joined or not
+    pub fn participant_count(&self) -> usize {
+        self.participants.lock().unwrap().len()
+    }
+
+    /// Arrivals and departures of participants from now on
+    pub fn subscribe_participants(&self) -> broadcast::Receiver<ParticipantEvent> {
+        self.presence.subscribe()
+    }
+
+    /// Registers `client_id` if it is new and records that it was heard from at `now`.
+    /// Returns what ends its connection.
+    fn touch(&self, client_id: &str, now: Instant) -> Arc<Notify> {
+        let mut participants = self.participants.lock().unwrap();
+        let participant = participants
+            .entry(client_id.to_string())
+            .or_insert_with(|| Participant { user: None, last_seen: now, kick: Arc::new(Notify::new()) });
+        participant.last_seen = participant.last_seen.max(now);
+        participant.kick.clone()
+    }
+
+    /// Locks `start..end` (byte offsets) for `user`, unless it overlaps a region already locked
+    pub fn acquire_lock(&self, user: &str, start: usize, end: usize) -> Result<RegionLock, String> {
+        if start >= end || end > self.document.lock().unwrap().len() {
+            return Err(format!("Invalid region {}..{}", start, end));
+        }
+        let mut locks = self.locks.lock().unwrap();
+        if let Some(held) = locks.iter().find(|held| start < held.end && held.start < end) {
+            return Err(format!("{}..{} overlaps {}..{}, locked by {}", start, end, held.start, held.end, held.user));
         }
+        let lock = RegionLock { user: user.to_string(), start, end };
+        locks.push(lock.clone());
+        Ok(lock)
     }
 
-    /// Applies an edit to the shared document
-    pub async fn apply_edit(&self, edit: Edit) {
-        let mut document = self.document.lock().unwrap();
-        let mut edits = self.edits.lock().unwrap();
+    /// Releases the lock `user` holds on exactly `start..end`
+    pub fn release_lock(&self, user: &str, start: usize, end: usize) -> Result<(), String> {
+        let mut locks = self.locks.lock().unwrap();
+        let index = locks
+            .iter()
+            .position(|lock| lock.user == user && lock.start == start && lock.end == end)
+            .ok_or_else(|| format!("{} holds no lock on {}..{}", user, start, end))?;
+        locks.remove(index);
+        Ok(())
+    }
+
+    /// Releases every lock `user` holds
+    pub fn release_locks(&self, user: &str) {
+        self.locks.lock().unwrap().retain(|lock| lock.user != user);
+    }
+
+    /// The locked regions, in the order they were locked
+    pub fn locks(&self) -> Vec<RegionLock> {
+        self.locks.lock().unwrap().clone()
+    }
 
-        // Add the edit to the log
-        edits.push(edit.clone());
+    /// Applies an edit to the shared document, unless it changes a region another user has
+    /// locked. Locked regions move with the text around them. Each lock is held only for its own
+    /// update, so activity and notification bookkeeping never runs under them.
+    pub async fn apply_edit(&self, edit: Edit) -> Result<(), String> {
+        // Merge the edit into the document, checking and moving the locks in the same step
+        let previous = {
+            let mut document = self.document.lock().unwrap();
+            if let Some((start, old_end, new_end)) = changed_range(&document, &edit.content) {
+                let mut locks = self.locks.lock().unwrap();
+                if let Some(held) = locks.iter().find(|lock| lock.user != edit.user && lock.covers(start, old_end)) {
+                    let reason = format!("{}..{} is locked by {}", held.start, held.end, held.user);
+                    println!("Rejected edit by {}: {}", edit.user, reason);
+                    return Err(reason);
+                }
+                for lock in locks.iter_mut() {
+                    lock.follow_edit(&edit.user, start, old_end, new_end);
+                }
+                drop(locks);
 
-        // Merge the edit into the document (simple append for now, can be more complex)
-        *document = edit.content.clone();
+                // Log what changed, in the order edits reach the document
+                let logged = LoggedEdit {
+                    user: edit.user.clone(),
+                    start,
+                    end: old_end,
+                    text: edit.content[start..new_end].to_string(),
+                    timestamp: edit.timestamp.clone(),
+                };
+                self.edits.lock().unwrap().push(logged, self.max_edits);
+            }
+            std::mem::replace(&mut *document, edit.content.clone())
+        };
+
+        if let Some((doc_id, feeds)) = &self.activity {
+            let changed = lines_changed(&previous, &edit.content);
+            feeds.with_feed(doc_id, |feed| feed.record_edit(&edit.user, changed, Utc::now()));
+        }
 
-        println!("Document updated by {}: {}", edit.user, document);
+        if let Some((doc_id, watcher)) = &self.watcher {
+            watcher.on_edit(doc_id, &edit.user, &previous, &edit.content, Utc::now());
+        }
+
+        println!("Document updated by {}: {}", edit.user, edit.content);
+        Ok(())
     }
 
     /// Retrieves the current document content
@@ -85,11 +461,60 @@ impl CollaborationManager {
         let document = self.document.lock().unwrap();
         document.clone()
     }
+
+    /// Reconstructs the document by replaying the edit log onto the document as it was before
+    /// the oldest logged edit. Matches `get_document` unless the log was lost.
+    pub fn rebuild_from_log(&self) -> String {
+        self.edits.lock().unwrap().replay()
+    }
+
+    /// The logged edits, oldest first, for catching up a client on recent history
+    pub fn edit_log(&self) -> Vec<LoggedEdit> {
+        self.edits.lock().unwrap().edits.clone()
+    }
+}
+
+impl Default for CollaborationManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The bytes an edit from `old` to `new` replaced, as `(start, old_end, new_end)`, after trimming
+/// what the two share at both ends. The range never splits a character.
+fn changed_range(old: &str, new: &str) -> Option<(usize, usize, usize)> {
+    if old == new {
+        return None;
+    }
+    let mut prefix = old.bytes().zip(new.bytes()).take_while(|(a, b)| a == b).count();
+    while !old.is_char_boundary(prefix) || !new.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let mut suffix = old.bytes().rev().zip(new.bytes().rev()).take(max_suffix).take_while(|(a, b)| a == b).count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+    Some((prefix, old.len() - suffix, new.len() - suffix))
+}
+
+/// Reaps silent clients of `manager` twice per heartbeat timeout
+pub fn spawn_reaper(manager: Arc<CollaborationManager>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(manager.heartbeat_timeout / 2);
+        loop {
+            interval.tick().await;
+            let reaped = manager.reap(Instant::now());
+            if !reaped.is_empty() {
+                println!("Reaped {} silent clients", reaped.len());
+            }
+        }
+    })
 }
 
 /// WebSocket handler for collaborative editing
-pub async fn collaboration_ws_handler(ws: warp::ws::Ws, manager: Arc<CollaborationManager>) -> impl warp::Reply {
-    ws.on_upgrade(move |socket| manager.register_client(socket))
+pub async fn collaboration_ws_handler(ws: warp::ws::Ws, manager: Arc<CollaborationManager>) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(ws.on_upgrade(move |socket| manager.register_client(socket)))
 }
 
 /// Route for WebSocket collaborative editing
@@ -105,15 +530,218 @@ fn with_manager(manager: Arc<CollaborationManager>) -> impl warp::Filter<Extract
     warp::any().map(move || manager.clone())
 }
 
-/// Example main function for setting up the collaboration server
-#[tokio::main]
-async fn main() {
-    let manager = Arc::new(CollaborationManager::new());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
 
-    // WebSocket route for collaborative editing
-    let collaborate_route = collaboration_route(manager.clone());
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_connections_never_block() {
+        const CONNECTIONS: usize = 100;
+        const EDITS: usize = 5;
+        let manager = Arc::new(CollaborationManager::new());
+        let route = collaboration_route(manager.clone());
+
+        let stress = async {
+            let mut clients = Vec::new();
+            for _ in 0..CONNECTIONS {
+                clients.push(warp::test::ws().path("/collaborate").handshake(route.clone()).await.unwrap());
+            }
+
+            // Every connection edits rapidly while all of them receive the broadcasts
+            let tasks = clients.into_iter().enumerate().map(|(i, mut client)| {
+                tokio::spawn(async move {
+                    for n in 0..EDITS {
+                        let edit = Edit { user: format!("user{}", i), content: format!("{}:{}", i, n), cursor_position: 0, timestamp: String::new() };
+                        client.send_text(serde_json::to_string(&edit).unwrap()).await;
+                    }
+                    assert!(client.recv().await.unwrap().is_text());
+                    client
+                })
+            });
+            let clients = futures::future::join_all(tasks).await;
+            while manager.edits.lock().unwrap().edits.len() < CONNECTIONS * EDITS {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            clients
+        };
+
+        let clients = tokio::time::timeout(Duration::from_secs(10), stress).await.expect("a task blocked past the deadline");
+        assert!(clients.iter().all(|client| client.is_ok()));
+    }
+
+    fn edit(user: &str, content: &str) -> Edit {
+        Edit { user: user.to_string(), content: content.to_string(), cursor_position: 0, timestamp: String::new() }
+    }
 
-    // Start the server
-    println!("Collaboration server running on ws://localhost:3030/collaborate");
-    warp::serve(collaborate_route).run(([127, 0, 0, 1], 3030)).await;
+    #[tokio::test]
+    async fn test_overlapping_locks_are_refused() {
+        let manager = CollaborationManager::new();
+        manager.apply_edit(edit("alice", "fn a() {}\nfn b() {}\n")).await.unwrap();
+
+        manager.acquire_lock("alice", 0, 10).unwrap();
+        let error = manager.acquire_lock("bob", 5, 15).unwrap_err();
+        assert!(error.contains("locked by alice"), "{}", error);
+        assert!(manager.acquire_lock("alice", 9, 12).is_err()); // Not even alice may overlap her own lock
+        manager.acquire_lock("bob", 10, 20).unwrap(); // Adjacent regions don't overlap
+        assert!(manager.acquire_lock("bob", 3, 3).is_err());
+
+        assert!(manager.release_lock("bob", 0, 10).is_err());
+        manager.release_lock("alice", 0, 10).unwrap();
+        manager.acquire_lock("bob", 0, 5).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_edits_inside_another_users_lock_are_rejected() {
+        let manager = CollaborationManager::new();
+        manager.apply_edit(edit("alice", "fn a() {}\nfn b() {}\n")).await.unwrap();
+        manager.acquire_lock("alice", 0, 10).unwrap();
+
+        let error = manager.apply_edit(edit("bob", "fn x() {}\nfn b() {}\n")).await.unwrap_err();
+        assert!(error.contains("locked by alice"), "{}", error);
+        assert_eq!(manager.get_document(), "fn a() {}\nfn b() {}\n");
+
+        // alice refactors inside her lock, which grows with the function
+        manager.apply_edit(edit("alice", "fn alpha() {}\nfn b() {}\n")).await.unwrap();
+        assert_eq!((manager.locks()[0].start, manager.locks()[0].end), (0, 14));
+
+        // bob edits outside it, and text he inserts before it moves it along
+        manager.apply_edit(edit("bob", "fn alpha() {}\nfn beta() {}\n")).await.unwrap();
+        manager.apply_edit(edit("bob", "// math\nfn alpha() {}\nfn beta() {}\n")).await.unwrap();
+        assert_eq!((manager.locks()[0].start, manager.locks()[0].end), (8, 22));
+
+        manager.release_locks("alice");
+        manager.apply_edit(edit("bob", "// math\nfn a() {}\nfn beta() {}\n")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejections_are_reported_and_locks_released_on_disconnect() {
+        let manager = Arc::new(CollaborationManager::new());
+        manager.apply_edit(edit("alice", "fn a() {}\n")).await.unwrap();
+        let route = collaboration_route(manager.clone());
+        let mut alice = warp::test::ws().path("/collaborate").handshake(route.clone()).await.unwrap();
+        let mut bob = warp::test::ws().path("/collaborate").handshake(route).await.unwrap();
+
+        let request = LockRequest::Lock(RegionLock { user: "alice".to_string(), start: 0, end: 10 });
+        alice.send_text(serde_json::to_string(&request).unwrap()).await;
+        let reply: serde_json::Value = serde_json::from_str(alice.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(reply["type"], "locked");
+
+        bob.send_text(serde_json::to_string(&edit("bob", "fn b() {}\n")).unwrap()).await;
+        let reply: serde_json::Value = serde_json::from_str(bob.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(reply["type"], "edit_rejected");
+        assert_eq!(reply["reason"], "0..10 is locked by alice");
+
+        drop(alice);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !manager.locks().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the lock outlived its connection");
+    }
+
+    #[test]
+    fn test_only_silent_participants_are_reaped() {
+        let manager = CollaborationManager::new();
+        let mut events = manager.subscribe_participants();
+        let start = Instant::now();
+        manager.join("c1", "alice", start);
+        manager.join("c2", "bob", start);
+        manager.heartbeat("c2", start + Duration::from_secs(20));
+        assert_eq!(manager.participant_count(), 2);
+
+        assert!(manager.reap(start + HEARTBEAT_TIMEOUT).is_empty());
+        assert_eq!(manager.reap(start + HEARTBEAT_TIMEOUT + Duration::from_secs(1)), vec!["c1".to_string()]);
+        assert_eq!(manager.participant_count(), 1);
+        manager.leave("c2");
+        manager.leave("c2"); // Already gone; nobody is told twice
+
+        let received: Vec<ParticipantEvent> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert_eq!(received[2..], [
+            ParticipantEvent::Left { client_id: "c1".to_string(), user: Some("alice".to_string()), timed_out: true },
+            ParticipantEvent::Left { client_id: "c2".to_string(), user: Some("bob".to_string()), timed_out: false },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_disconnected_and_its_departure_broadcast() {
+        let manager = Arc::new(CollaborationManager::new().with_heartbeat_timeout(Duration::from_millis(200)));
+        let route = collaboration_route(manager.clone());
+        let reaper = spawn_reaper(manager.clone());
+        let mut alice = warp::test::ws().path("/collaborate").handshake(route.clone()).await.unwrap();
+        let mut bob = warp::test::ws().path("/collaborate").handshake(route).await.unwrap();
+        alice.send_text(serde_json::to_string(&SessionRequest::Join { user: "alice".to_string() }).unwrap()).await;
+        bob.send_text(serde_json::to_string(&SessionRequest::Join { user: "bob".to_string() }).unwrap()).await;
+
+        // alice falls silent while bob keeps sending heartbeats, until he hears she left
+        let departure = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                tokio::select! {
+                    frame = bob.recv() => {
+                        let event = serde_json::from_str::<ParticipantEvent>(frame.unwrap().to_str().unwrap()).unwrap();
+                        if matches!(event, ParticipantEvent::Left { .. }) {
+                            return event;
+                        }
+                    }
+                    _ = tokio::time::sleep(Duration::from_millis(50)) => {
+                        bob.send_text(serde_json::to_string(&SessionRequest::Heartbeat).unwrap()).await;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("alice was never reaped");
+        assert!(matches!(departure, ParticipantEvent::Left { user: Some(user), timed_out: true, .. } if user == "alice"));
+        assert_eq!(manager.participant_count(), 1);
+        // Her connection was closed after what was already sent to her
+        let closed = tokio::time::timeout(Duration::from_secs(5), async { while alice.recv().await.is_ok() {} }).await;
+        assert!(closed.is_ok(), "alice's connection stayed open");
+        reaper.abort();
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_is_resynced_with_the_whole_document() {
+        let manager = CollaborationManager::new().with_broadcast_capacity(2);
+        let mut slow = manager.broadcaster.subscribe();
+        assert_eq!(manager.subscriber_count(), 1);
+
+        // Five edits while the subscriber reads none: the first three are dropped for it
+        for n in 1..=5 {
+            let edit = edit("alice", &format!("v{}", n));
+            manager.apply_edit(edit.clone()).await.unwrap();
+            manager.broadcaster.send(edit).unwrap();
+        }
+        let frame: serde_json::Value = serde_json::from_str(&manager.next_edit_frame(&mut slow).await.unwrap()).unwrap();
+        assert_eq!(frame, serde_json::json!({ "type": "resync", "content": "v5", "missed": 3 }));
+
+        // What it had buffered is older than the document it was sent, so only later edits follow
+        let edit = edit("bob", "v6");
+        manager.apply_edit(edit.clone()).await.unwrap();
+        manager.broadcaster.send(edit).unwrap();
+        let frame: Edit = serde_json::from_str(&manager.next_edit_frame(&mut slow).await.unwrap()).unwrap();
+        assert_eq!(frame.content, "v6");
+        assert_eq!(manager.subscriber_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rebuilding_from_the_log_reproduces_the_document() {
+        for max_edits in [DEFAULT_EDIT_LOG_LIMIT, 2] {
+            let manager = CollaborationManager::new().with_edit_limit(max_edits);
+            for content in ["fn main() {}\n", "fn main() {\n    go();\n}\n", "// café\nfn main() {\n    go();\n}\n", "// caffè\nfn main() {\n}\n", ""] {
+                manager.apply_edit(edit("alice", content)).await.unwrap();
+                assert_eq!(manager.rebuild_from_log(), manager.get_document(), "keeping {} edits", max_edits);
+            }
+            manager.apply_edit(edit("bob", "done\n")).await.unwrap();
+            assert_eq!(manager.rebuild_from_log(), "done\n");
+            assert_eq!(manager.edit_log().len(), max_edits.min(6));
+        }
+
+        // Edits that change nothing aren't logged
+        let manager = CollaborationManager::new();
+        manager.apply_edit(edit("alice", "same")).await.unwrap();
+        manager.apply_edit(edit("bob", "same")).await.unwrap();
+        assert_eq!(manager.edit_log(), vec![LoggedEdit { user: "alice".to_string(), start: 0, end: 0, text: "same".to_string(), timestamp: String::new() }]);
+    }
 }
@@ -0,0 +1,21 @@
+/// Comment syntax of a language, for the comment toggling commands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommentTokens {
+    pub line: Option<&'static str>,                   // Starts a comment running to the end of the line
+    pub block: Option<(&'static str, &'static str)>, // Opens and closes a comment spanning any text
+}
+
+/// Comment tokens for a language, given by name or file extension (e.g. "rust" or "rs").
+/// Unknown languages get C-style comments.
+pub fn comment_tokens(language: &str) -> CommentTokens {
+    let c_style = CommentTokens { line: Some("//"), block: Some(("/*", "*/")) };
+    match language.to_lowercase().as_str() {
+        "python" | "py" | "ruby" | "rb" | "shell" | "sh" | "bash" | "toml" | "yaml" | "yml" => {
+            CommentTokens { line: Some("#"), block: None }
+        }
+        "html" | "htm" | "xml" | "svg" | "markdown" | "md" => CommentTokens { line: None, block: Some(("<!--", "-->")) },
+        "css" => CommentTokens { line: None, block: Some(("/*", "*/")) },
+        "sql" => CommentTokens { line: Some("--"), block: Some(("/*", "*/")) },
+        _ => c_style,
+    }
+}
@@ -0,0 +1,129 @@
+use serde::{Deserialize, Serialize};
+
+use crate::storage::encoding::LineEnding;
+use crate::ui::keymap::KeymapMode;
+
+/// Per-user editor preferences.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EditorConfig {
+    #[serde(default)]
+    pub keymap: KeymapMode, // Keybinding scheme, switchable while editing
+    #[serde(default)]
+    pub indent_style: IndentStyle, // What Tab inserts
+    #[serde(default)]
+    pub cleanup_on_save: bool, // Whether saving runs the whitespace cleanup first
+    #[serde(default)]
+    pub cleanup: CleanupOptions, // What the whitespace cleanup does
+    #[serde(default)]
+    pub typing_rules: TypingRuleOptions, // Which on-type formatting rules run
+    #[serde(default)]
+    pub line_ending: Option<LineEnding>, // What lines end with on save; `None` keeps each file's own
+}
+
+impl EditorConfig {
+    /// Creates a configuration with the default keymap.
+    pub fn new() -> Self {
+        Self {
+            keymap: KeymapMode::Default,
+            indent_style: IndentStyle::Tabs,
+            cleanup_on_save: false,
+            cleanup: CleanupOptions::default(),
+            typing_rules: TypingRuleOptions::default(),
+            line_ending: None,
+        }
+    }
+
+    /// Changes the keybinding scheme.
+    pub fn set_keymap(&mut self, keymap: KeymapMode) {
+        self.keymap = keymap;
+    }
+}
+
+impl Default for EditorConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What one level of indentation is made of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IndentStyle {
+    #[default]
+    Tabs,
+    Spaces(usize), // Spaces per level, also the distance between tab stops
+}
+
+impl IndentStyle {
+    /// Columns between tab stops; tabs are displayed four columns wide.
+    pub fn width(&self) -> usize {
+        match self {
+            IndentStyle::Tabs => 4,
+            IndentStyle::Spaces(width) => (*width).max(1),
+        }
+    }
+
+    /// Text that indents from `column` to the next tab stop.
+    pub fn to_next_stop(&self, column: usize) -> String {
+        match self {
+            IndentStyle::Tabs => "\t".to_string(),
+            IndentStyle::Spaces(_) => " ".repeat(self.width() - column % self.width()),
+        }
+    }
+}
+
+/// What the whitespace cleanup does; both steps are on unless turned off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CleanupOptions {
+    #[serde(default = "enabled")]
+    pub trim_trailing_whitespace: bool, // Strip spaces and tabs at the end of every line
+    #[serde(default = "enabled")]
+    pub ensure_final_newline: bool, // End a non-empty document with exactly one newline
+}
+
+impl Default for CleanupOptions {
+    fn default() -> Self {
+        Self { trim_trailing_whitespace: true, ensure_final_newline: true }
+    }
+}
+
+/// Which on-type formatting rules run; all are on unless turned off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypingRuleOptions {
+    #[serde(default = "enabled")]
+    pub auto_dedent: bool, // Dedent `}`, `else` and lines after a Python block ends
+    #[serde(default = "enabled")]
+    pub list_continuation: bool, // Continue Markdown lists on Enter
+    #[serde(default = "enabled")]
+    pub trim_trailing_whitespace: bool, // Strip whitespace from the line Enter leaves
+}
+
+impl Default for TypingRuleOptions {
+    fn default() -> Self {
+        Self { auto_dedent: true, list_continuation: true, trim_trailing_whitespace: true }
+    }
+}
+
+/// A user's settings for one document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentPreferences {
+    #[serde(default = "enabled")]
+    pub notify_on_edits: bool, // Digest of edits others make to lines this user wrote
+}
+
+impl DocumentPreferences {
+    /// Creates preferences with notifications enabled.
+    pub fn new() -> Self {
+        Self { notify_on_edits: true }
+    }
+}
+
+impl Default for DocumentPreferences {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
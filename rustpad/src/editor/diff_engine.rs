@@ -1,11 +1,92 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
 /// Represents the type of change detected between document states.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum DiffOperation {
     Insert(usize, String),  // Insert text at position (pos, "text")
     Delete(usize, usize),   // Delete text from start to end (start, end)
     Replace(usize, usize, String), // Replace text from start to end with new text (start, end, "new_text")
 }
 
+/// A region where both sides of a three-way merge changed the same lines.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MergeConflict {
+    pub start: usize,   // Start of the conflict block (including markers) in the merged text
+    pub end: usize,     // End of the conflict block in the merged text
+    pub ours: String,   // The lines as edited by our side
+    pub theirs: String, // The lines as edited by their side
+}
+
+/// Outcome of a three-way merge: the merged text plus any conflicting regions,
+/// which are written into `merged` surrounded by conflict markers.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MergeResult {
+    pub merged: String,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl MergeResult {
+    /// Returns `true` if the merge completed without any conflicts.
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+/// Why `DiffEngine::apply_unified` couldn't apply a patch. Lines are counted from 1.
+#[derive(Debug, PartialEq, Clone)]
+pub enum PatchError {
+    Malformed { line: usize, reason: String },   // A line of the patch that isn't unified diff
+    ContextMismatch { hunk: usize, line: usize }, // A hunk's context or removed lines differ from the text's at `line`
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Malformed { line, reason } => write!(f, "Malformed patch at line {}: {}", line, reason),
+            PatchError::ContextMismatch { hunk, line } => write!(f, "Hunk {} doesn't match the text at line {}", hunk, line),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// A parsed unified diff hunk: where it starts in the old text, as an index of its lines, and
+/// its lines with their markers. Lines keep their `\n` unless the patch marks them as the last.
+#[derive(Debug)]
+struct Hunk {
+    old_start: usize,
+    lines: Vec<(char, String)>,
+}
+
+/// Unchanged characters needed between two changes for `DiffEngine::diff` to keep them as
+/// separate operations rather than one replacement covering both
+const MIN_UNCHANGED_RUN: usize = 4;
+
+/// Largest product of changed line counts `DiffEngine::to_unified` compares line by line;
+/// beyond it the changed lines are shown as one block
+const MAX_LINE_DIFF_CELLS: usize = 4_000_000;
+
+pub const CONFLICT_MARKER_OURS: &str = "<<<<<<< ours";
+pub const CONFLICT_MARKER_SEPARATOR: &str = "=======";
+pub const CONFLICT_MARKER_THEIRS: &str = ">>>>>>> theirs";
+
+/// A single edit against the merge base, tagged with the side it came from.
+#[derive(Debug, Clone)]
+struct SideEdit {
+    start: usize,
+    end: usize,
+    text: String,
+    ours: bool,
+}
+
+/// Lines changed together, as half-open ranges of line indexes in the old and the new text
+#[derive(Debug, Clone, Copy)]
+struct LineRegion {
+    old: (usize, usize),
+    new: (usize, usize),
+}
+
 /// The `DiffEngine` struct calculates differences between two versions of a document.
 /// These differences can be used for synchronization, version control, and collaborative editing.
 pub struct DiffEngine;
@@ -34,6 +115,9 @@ impl DiffEngine {
         } else if !old_middle.is_empty() && new_middle.is_empty() {
             // Deletion detected
             operations.push(DiffOperation::Delete(common_prefix, common_prefix + old_middle.len()));
+        } else if old_middle.len() == new_middle.len() && old_middle.chars().count() == new_middle.chars().count() {
+            // Same-length replacement: only the characters that changed are replaced
+            operations = DiffEngine::diff_aligned(old_middle, new_middle, common_prefix);
         } else if !old_middle.is_empty() && !new_middle.is_empty() && old_middle != new_middle {
             // Replacement detected
             operations.push(DiffOperation::Replace(common_prefix, common_prefix + old_middle.len(), new_middle.to_string()));
@@ -42,29 +126,690 @@ impl DiffEngine {
         operations
     }
 
-    /// Finds the length of the common prefix between two strings.
+    /// Diffs two texts with the same number of characters character by character, replacing
+    /// each run of changed characters. Runs separated by fewer than `MIN_UNCHANGED_RUN`
+    /// characters are joined. The replacements come last to first, so each one's positions
+    /// are still those of `old_text` (offset by `offset`) when it is applied.
+    fn diff_aligned(old_text: &str, new_text: &str, offset: usize) -> Vec<DiffOperation> {
+        let mut runs: Vec<(usize, usize, usize, usize)> = Vec::new(); // Old start and end, new start and end
+        let mut unchanged = 0;
+        let pairs = old_text.char_indices().zip(new_text.char_indices());
+        for ((old_at, old_char), (new_at, new_char)) in pairs {
+            if old_char == new_char {
+                unchanged += 1;
+                continue;
+            }
+            let (old_end, new_end) = (old_at + old_char.len_utf8(), new_at + new_char.len_utf8());
+            match runs.last_mut() {
+                Some(run) if unchanged < MIN_UNCHANGED_RUN => {
+                    run.1 = old_end;
+                    run.3 = new_end;
+                }
+                _ => runs.push((old_at, old_end, new_at, new_end)),
+            }
+            unchanged = 0;
+        }
+        runs.into_iter()
+            .rev()
+            .map(|(old_start, old_end, new_start, new_end)| {
+                DiffOperation::Replace(offset + old_start, offset + old_end, new_text[new_start..new_end].to_string())
+            })
+            .collect()
+    }
+
+    /// Finds the length of the common prefix between two strings, ending on a character boundary.
     fn find_common_prefix(old_text: &str, new_text: &str) -> usize {
         let min_len = old_text.len().min(new_text.len());
-        for i in 0..min_len {
-            if old_text.as_bytes()[i] != new_text.as_bytes()[i] {
-                return i;
-            }
+        let mut prefix = (0..min_len)
+            .find(|&i| old_text.as_bytes()[i] != new_text.as_bytes()[i])
+            .unwrap_or(min_len);
+        while !old_text.is_char_boundary(prefix) || !new_text.is_char_boundary(prefix) {
+            prefix -= 1;
         }
-        min_len
+        prefix
     }
 
     /// Finds the length of the common suffix between two strings, considering the common prefix.
+    /// The suffix starts on a character boundary in both strings.
     fn find_common_suffix(old_text: &str, new_text: &str, common_prefix: usize) -> usize {
         let old_len = old_text.len();
         let new_len = new_text.len();
         let min_len = old_len.min(new_len) - common_prefix;
-        
-        for i in 0..min_len {
-            if old_text.as_bytes()[old_len - 1 - i] != new_text.as_bytes()[new_len - 1 - i] {
-                return i;
+
+        let mut suffix = (0..min_len)
+            .find(|&i| old_text.as_bytes()[old_len - 1 - i] != new_text.as_bytes()[new_len - 1 - i])
+            .unwrap_or(min_len);
+        while !old_text.is_char_boundary(old_len - suffix) || !new_text.is_char_boundary(new_len - suffix) {
+            suffix -= 1;
+        }
+        suffix
+    }
+
+    /// Renders the changes from `old_text` to `new_text` as unified diff hunks, `@@ -a,b +c,d @@`
+    /// followed by the lines, with up to `context_lines` unchanged lines around each change.
+    /// Lines are compared whole, and those not in the longest sequence both texts share are
+    /// shown as removed or added. Equal texts give an empty string.
+    pub fn to_unified(old_text: &str, new_text: &str, context_lines: usize) -> String {
+        let old_lines: Vec<&str> = old_text.split_inclusive('\n').collect();
+        let new_lines: Vec<&str> = new_text.split_inclusive('\n').collect();
+        let regions = DiffEngine::changed_lines(&old_lines, &new_lines);
+
+        let mut unified = String::new();
+        let mut index = 0;
+        while index < regions.len() {
+            // Changes whose context would meet share a hunk
+            let mut last = index;
+            while last + 1 < regions.len() && regions[last + 1].old.0 - regions[last].old.1 <= 2 * context_lines {
+                last += 1;
+            }
+            let leading = context_lines.min(regions[index].old.0);
+            let trailing = context_lines.min(old_lines.len() - regions[last].old.1);
+            let old_start = regions[index].old.0 - leading;
+            let new_start = regions[index].new.0 - leading;
+            let old_count = regions[last].old.1 + trailing - old_start;
+            let new_count = regions[last].new.1 + trailing - new_start;
+            unified.push_str(&format!("@@ -{} +{} @@\n", hunk_range(old_start, old_count), hunk_range(new_start, new_count)));
+
+            let mut line = old_start;
+            for region in &regions[index..=last] {
+                for context in &old_lines[line..region.old.0] {
+                    push_line(&mut unified, ' ', context);
+                }
+                for removed in &old_lines[region.old.0..region.old.1] {
+                    push_line(&mut unified, '-', removed);
+                }
+                for added in &new_lines[region.new.0..region.new.1] {
+                    push_line(&mut unified, '+', added);
+                }
+                line = region.old.1;
+            }
+            for context in &old_lines[line..line + trailing] {
+                push_line(&mut unified, ' ', context);
+            }
+            index = last + 1;
+        }
+        unified
+    }
+
+    /// The lines that differ between two texts, as ranges of line indexes on both sides, in
+    /// order and apart from each other. Lines outside the ranges are the longest sequence the
+    /// texts have in common, so moving or inserting a line doesn't mark what lies between.
+    fn changed_lines(old_lines: &[&str], new_lines: &[&str]) -> Vec<LineRegion> {
+        let prefix = old_lines.iter().zip(new_lines).take_while(|(old, new)| old == new).count();
+        let suffix = old_lines[prefix..].iter().rev().zip(new_lines[prefix..].iter().rev()).take_while(|(old, new)| old == new).count();
+        let old_middle = &old_lines[prefix..old_lines.len() - suffix];
+        let new_middle = &new_lines[prefix..new_lines.len() - suffix];
+        if old_middle.is_empty() && new_middle.is_empty() {
+            return Vec::new();
+        }
+        if old_middle.len().saturating_mul(new_middle.len()) > MAX_LINE_DIFF_CELLS {
+            // Too big to compare line by line; everything between the common ends changed
+            return vec![LineRegion { old: (prefix, prefix + old_middle.len()), new: (prefix, prefix + new_middle.len()) }];
+        }
+
+        // common[i][j]: the length of the longest common sequence of the lines from i and j on
+        let width = new_middle.len() + 1;
+        let mut common = vec![0usize; (old_middle.len() + 1) * width];
+        for i in (0..old_middle.len()).rev() {
+            for j in (0..new_middle.len()).rev() {
+                common[i * width + j] = if old_middle[i] == new_middle[j] {
+                    common[(i + 1) * width + j + 1] + 1
+                } else {
+                    common[(i + 1) * width + j].max(common[i * width + j + 1])
+                };
+            }
+        }
+
+        let mut regions: Vec<LineRegion> = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < old_middle.len() || j < new_middle.len() {
+            if i < old_middle.len() && j < new_middle.len() && old_middle[i] == new_middle[j] {
+                i += 1;
+                j += 1;
+                continue;
+            }
+            // Removals before additions, so a changed line reads as `-` then `+`
+            let removed = j == new_middle.len() || (i < old_middle.len() && common[(i + 1) * width + j] >= common[i * width + j + 1]);
+            let (old_line, new_line) = (prefix + i, prefix + j);
+            if removed {
+                i += 1;
+            } else {
+                j += 1;
+            }
+            match regions.last_mut() {
+                Some(previous) if previous.old.1 == old_line && previous.new.1 == new_line => {
+                    previous.old.1 = prefix + i;
+                    previous.new.1 = prefix + j;
+                }
+                _ => regions.push(LineRegion { old: (old_line, prefix + i), new: (new_line, prefix + j) }),
+            }
+        }
+        regions
+    }
+
+    /// Applies a unified diff, as `to_unified` renders it, to `text`. File headers (`---`,
+    /// `+++`) before the hunks are skipped. Each hunk's context and removed lines must match the
+    /// text exactly where its header places them; hunks must come in order and not overlap.
+    pub fn apply_unified(text: &str, patch: &str) -> Result<String, PatchError> {
+        let old_lines: Vec<&str> = text.split_inclusive('\n').collect();
+        let mut result = String::with_capacity(text.len());
+        let mut line = 0; // Lines of `text` copied or replaced so far
+        for (index, hunk) in parse_hunks(patch)?.into_iter().enumerate() {
+            let mismatch = |at: usize| PatchError::ContextMismatch { hunk: index + 1, line: at + 1 };
+            if hunk.old_start < line || hunk.old_start > old_lines.len() {
+                return Err(mismatch(hunk.old_start));
+            }
+            result.extend(old_lines[line..hunk.old_start].iter().copied());
+            line = hunk.old_start;
+            for (marker, content) in &hunk.lines {
+                if *marker == '+' {
+                    result.push_str(content);
+                    continue;
+                }
+                if old_lines.get(line) != Some(&content.as_str()) {
+                    return Err(mismatch(line));
+                }
+                if *marker == ' ' {
+                    result.push_str(content);
+                }
+                line += 1;
+            }
+        }
+        result.extend(old_lines[line..].iter().copied());
+        Ok(result)
+    }
+
+    /// Merges two versions of a document that were both derived from a common `base`.
+    ///
+    /// Each side is diffed against the base and changes touching different lines are
+    /// combined. When both sides change the same lines differently, the region is
+    /// written into the result between conflict markers and reported in `conflicts`.
+    ///
+    /// # Arguments
+    /// * `base` - The common ancestor of both versions.
+    /// * `ours` - The local version of the document.
+    /// * `theirs` - The incoming version of the document.
+    pub fn merge3(base: &str, ours: &str, theirs: &str) -> MergeResult {
+        let mut edits: Vec<SideEdit> = DiffEngine::diff(base, ours)
+            .into_iter()
+            .map(|op| DiffEngine::to_side_edit(op, true))
+            .chain(DiffEngine::diff(base, theirs).into_iter().map(|op| DiffEngine::to_side_edit(op, false)))
+            .collect();
+        edits.sort_by_key(|edit| (DiffEngine::line_start(base, edit.start), edit.start));
+
+        let mut merged = String::new();
+        let mut conflicts = Vec::new();
+        let mut position = 0;
+        let mut index = 0;
+
+        while index < edits.len() {
+            // Grow a group of edits whose touched lines overlap
+            let group_start = DiffEngine::line_start(base, edits[index].start);
+            let mut group_end = DiffEngine::line_end(base, &edits[index]);
+            let mut group = vec![edits[index].clone()];
+            index += 1;
+            while index < edits.len() {
+                let line_start = DiffEngine::line_start(base, edits[index].start);
+                // Empty trailing lines have zero width, so edits there join on the start offset
+                if line_start >= group_end && line_start != group_start {
+                    break;
+                }
+                group_end = group_end.max(DiffEngine::line_end(base, &edits[index]));
+                group.push(edits[index].clone());
+                index += 1;
+            }
+
+            merged.push_str(&base[position..group_start]);
+            position = group_end;
+
+            let ours_text = DiffEngine::apply_side(base, group_start, group_end, &group, true);
+            let theirs_text = DiffEngine::apply_side(base, group_start, group_end, &group, false);
+            let both_sides = group.iter().any(|edit| edit.ours) && group.iter().any(|edit| !edit.ours);
+
+            if !both_sides || ours_text == theirs_text {
+                // Only one side touched these lines, or both made the same change
+                let side_is_ours = group.iter().any(|edit| edit.ours);
+                merged.push_str(if side_is_ours { &ours_text } else { &theirs_text });
+                continue;
+            }
+
+            let start = merged.len();
+            for (marker, text) in [(CONFLICT_MARKER_OURS, &ours_text), (CONFLICT_MARKER_SEPARATOR, &theirs_text)] {
+                merged.push_str(marker);
+                merged.push('\n');
+                merged.push_str(text);
+                if !text.is_empty() && !text.ends_with('\n') {
+                    merged.push('\n');
+                }
+            }
+            merged.push_str(CONFLICT_MARKER_THEIRS);
+            merged.push('\n');
+
+            conflicts.push(MergeConflict {
+                start,
+                end: merged.len(),
+                ours: ours_text,
+                theirs: theirs_text,
+            });
+        }
+
+        merged.push_str(&base[position..]);
+
+        MergeResult { merged, conflicts }
+    }
+
+    /// Applies a list of diff operations to `text`, in order, and returns the result.
+    /// Each operation is relative to the text produced by the operations before it.
+    pub fn apply(text: &str, operations: &[DiffOperation]) -> String {
+        let mut result = text.to_string();
+        for operation in operations {
+            let (start, end, new_text) = DiffEngine::to_range(operation.clone());
+            let end = end.min(result.len());
+            result.replace_range(start.min(end)..end, &new_text);
+        }
+        result
+    }
+
+    /// Transforms `operations` so they can be applied after `against`, where both lists were
+    /// produced from the same text. Returns the transformed `operations` together with
+    /// `against` transformed to apply after `operations`, so that both orders converge.
+    ///
+    /// # Arguments
+    /// * `operations` - The operations to rebase.
+    /// * `against` - The concurrent operations that were applied first.
+    /// * `operations_first` - Whether `operations` wins ties (e.g. inserts at the same position).
+    pub fn transform(
+        operations: &[DiffOperation],
+        against: &[DiffOperation],
+        operations_first: bool,
+    ) -> (Vec<DiffOperation>, Vec<DiffOperation>) {
+        let mut rebased: Vec<(usize, usize, String)> = operations.iter().cloned().map(DiffEngine::to_range).collect();
+        let mut transformed_against = Vec::new();
+
+        for other in against.iter().cloned().map(DiffEngine::to_range) {
+            let mut other = Some(other);
+            let mut next = Vec::new();
+            for operation in rebased {
+                match other.take() {
+                    Some(current) => {
+                        next.extend(DiffEngine::transform_range(&operation, &current, operations_first));
+                        other = DiffEngine::transform_range(&current, &operation, !operations_first);
+                    }
+                    None => next.push(operation),
+                }
+            }
+            rebased = next;
+            transformed_against.extend(other);
+        }
+
+        (
+            rebased.into_iter().filter_map(DiffEngine::from_range).collect(),
+            transformed_against.into_iter().filter_map(DiffEngine::from_range).collect(),
+        )
+    }
+
+    /// Transforms a single ranged edit `a` to apply after the concurrent edit `b`.
+    /// Returns `None` when `a` is swallowed by `b` (e.g. an insertion inside text `b` removed).
+    /// A range ending before it starts isn't an edit: `a` is then kept as is, for whoever
+    /// applies it to refuse.
+    fn transform_range(
+        a: &(usize, usize, String),
+        b: &(usize, usize, String),
+        a_first: bool,
+    ) -> Option<(usize, usize, String)> {
+        let (a_start, a_end, a_text) = a;
+        let (b_start, b_end, b_text) = b;
+        let (a_start, a_end, b_start, b_end) = (*a_start, *a_end, *b_start, *b_end);
+        if a_start > a_end || b_start > b_end {
+            return Some(a.clone());
+        }
+        let shift = |position: usize| position + b_text.len() - (b_end - b_start);
+
+        if a_start == a_end && b_start == b_end {
+            // Two insertions: the tie at the same position is broken by priority
+            if b_start < a_start || (b_start == a_start && !a_first) {
+                return Some((shift(a_start), shift(a_end), a_text.clone()));
+            }
+            return Some((a_start, a_end, a_text.clone()));
+        }
+        if a_end <= b_start && !(b_start == b_end && a_start == b_start) {
+            return Some((a_start, a_end, a_text.clone())); // Entirely before `b`
+        }
+        if a_start >= b_end {
+            return Some((shift(a_start), shift(a_end), a_text.clone())); // Entirely after `b`
+        }
+        if b_start == b_end {
+            // `b` inserted at the start of, or strictly inside, the range `a` replaces
+            if b_start == a_start {
+                return Some((shift(a_start), shift(a_end), a_text.clone()));
             }
+            return Some((a_start, shift(a_end), a_text.clone()));
+        }
+        if a_start == b_start && a_end == b_end {
+            // Both replaced the same range: the prioritized side replaces the other's text
+            return if a_first { Some((b_start, b_start + b_text.len(), a_text.clone())) } else { None };
+        }
+        if a_start <= b_start && b_end <= a_end {
+            return Some((a_start, shift(a_end), a_text.clone())); // `a` contains `b`
+        }
+        if b_start <= a_start && a_end <= b_end {
+            return None; // `b` contains `a`
         }
-        min_len
+        if a_start < b_start {
+            return Some((a_start, b_start, a_text.clone())); // Overlap at the end of `a`
+        }
+        Some((b_start + b_text.len(), shift(a_end), a_text.clone())) // Overlap at the start of `a`
+    }
+
+    /// Converts a diff operation into a `(start, end, text)` range replacement.
+    pub(crate) fn to_range(operation: DiffOperation) -> (usize, usize, String) {
+        match operation {
+            DiffOperation::Insert(pos, text) => (pos, pos, text),
+            DiffOperation::Delete(start, end) => (start, end, String::new()),
+            DiffOperation::Replace(start, end, text) => (start, end, text),
+        }
+    }
+
+    /// Converts a range replacement back into a diff operation, dropping no-ops.
+    fn from_range((start, end, text): (usize, usize, String)) -> Option<DiffOperation> {
+        match (start == end, text.is_empty()) {
+            (true, true) => None,
+            (true, false) => Some(DiffOperation::Insert(start, text)),
+            (false, true) => Some(DiffOperation::Delete(start, end)),
+            (false, false) => Some(DiffOperation::Replace(start, end, text)),
+        }
+    }
+
+    /// Converts a diff operation into an edit against the base text.
+    fn to_side_edit(operation: DiffOperation, ours: bool) -> SideEdit {
+        let (start, end, text) = DiffEngine::to_range(operation);
+        SideEdit { start, end, text, ours }
+    }
+
+    /// Applies one side's edits from a group to the base text between `start` and `end`.
+    fn apply_side(base: &str, start: usize, end: usize, group: &[SideEdit], ours: bool) -> String {
+        let mut result = String::new();
+        let mut position = start;
+        for edit in group.iter().filter(|edit| edit.ours == ours) {
+            result.push_str(&base[position..edit.start]);
+            result.push_str(&edit.text);
+            position = edit.end;
+        }
+        result.push_str(&base[position..end]);
+        result
+    }
+
+    /// Returns the offset of the start of the line containing `position`.
+    fn line_start(text: &str, position: usize) -> usize {
+        text[..position].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    /// Returns the offset just past the end (including the newline) of the last line touched by `edit`.
+    fn line_end(text: &str, edit: &SideEdit) -> usize {
+        // The last character the edit removes, or its start for an insertion
+        let last = if edit.end > edit.start {
+            text[..edit.end].char_indices().next_back().map_or(edit.start, |(i, _)| i)
+        } else {
+            edit.start
+        };
+        text[last.min(text.len())..].find('\n').map_or(text.len(), |i| last + i + 1)
     }
 }
 
+/// A hunk header range, `start,count` with the first line counted from 1; a single line is
+/// just its number, and no lines the number of the line before them
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+/// Adds a unified diff line, marking a last line without a newline the way `diff` does
+fn push_line(unified: &mut String, marker: char, line: &str) {
+    unified.push(marker);
+    unified.push_str(line);
+    if !line.ends_with('\n') {
+        unified.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+/// Parses the hunks of a unified diff, checking each has the lines its header counts
+fn parse_hunks(patch: &str) -> Result<Vec<Hunk>, PatchError> {
+    let malformed = |line: usize, reason: &str| PatchError::Malformed { line: line + 1, reason: reason.to_string() };
+    let patch_lines: Vec<&str> = patch.lines().collect();
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let mut index = 0;
+    while index < patch_lines.len() {
+        let header = patch_lines[index];
+        if !header.starts_with("@@") {
+            if hunks.is_empty() {
+                index += 1; // File headers and anything else before the first hunk
+                continue;
+            }
+            return Err(malformed(index, "expected a hunk header"));
+        }
+        let ranges = header.strip_prefix("@@ -").and_then(|rest| rest.split_once(" @@")).and_then(|(ranges, _)| ranges.split_once(" +"));
+        let (old, new) = ranges.ok_or_else(|| malformed(index, "expected `@@ -a,b +c,d @@`"))?;
+        let ((old_start, mut old_count), (_, mut new_count)) = match (parse_range(old), parse_range(new)) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return Err(malformed(index, "expected `@@ -a,b +c,d @@`")),
+        };
+        index += 1;
+
+        // An empty range starts after its line rather than at it
+        let old_start = if old_count == 0 { old_start } else { old_start.checked_sub(1).ok_or_else(|| malformed(index - 1, "lines are counted from 1"))? };
+        let mut hunk = Hunk { old_start, lines: Vec::new() };
+        while old_count > 0 || new_count > 0 {
+            let Some(&body) = patch_lines.get(index) else {
+                return Err(malformed(index, "the hunk ends before its lines do"));
+            };
+            // Some tools drop the space of blank context lines
+            let (marker, content) = match body.chars().next() {
+                Some(marker @ (' ' | '-' | '+')) => (marker, &body[1..]),
+                None => (' ', ""),
+                Some(_) => return Err(malformed(index, "expected a line starting with ' ', '-' or '+'")),
+            };
+            let count = if marker == '+' { &mut new_count } else { &mut old_count };
+            *count = count.checked_sub(1).ok_or_else(|| malformed(index, "more lines than the hunk header counts"))?;
+            if marker == ' ' {
+                new_count = new_count.checked_sub(1).ok_or_else(|| malformed(index, "more lines than the hunk header counts"))?;
+            }
+            hunk.lines.push((marker, format!("{}\n", content)));
+            index += 1;
+            if patch_lines.get(index).is_some_and(|next| next.starts_with('\\')) {
+                hunk.lines.last_mut().unwrap().1.pop(); // "\ No newline at end of file"
+                index += 1;
+            }
+        }
+        if hunks.last().is_some_and(|previous| previous.old_start > hunk.old_start) {
+            return Err(malformed(index, "hunks out of order"));
+        }
+        hunks.push(hunk);
+    }
+    Ok(hunks)
+}
+
+/// Parses a hunk header range, `start,count` or just `start` for a single line
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    match range.split_once(',') {
+        Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config::config;
+    use proptest::prelude::*;
+
+    /// Picks a valid edit of `text` from arbitrary numbers: a range on character boundaries and
+    /// the text to put there.
+    fn edit_of(text: &str, start: usize, len: usize, insert: &str) -> Vec<DiffOperation> {
+        let boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).chain(std::iter::once(text.len())).collect();
+        let start_index = start % boundaries.len();
+        let end_index = (start_index + len).min(boundaries.len() - 1);
+        DiffEngine::from_range((boundaries[start_index], boundaries[end_index], insert.to_string()))
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn test_merge3_edit_ending_in_multibyte_character() {
+        // Found by prop_merge3_non_overlapping_is_clean: finding the end of the line of an edit
+        // that ends after "é" sliced the base text inside the character
+        let result = DiffEngine::merge3("é\n\n", "\n\n", "é\n\nz");
+        assert!(result.is_clean());
+        assert_eq!(result.merged, "\n\nz");
+    }
+
+    #[test]
+    fn test_unified_diff_of_separate_changes() {
+        let old: String = (1..=12).map(|n| format!("line {}\n", n)).collect();
+        let new = old.replace("line 2\n", "line b\n").replace("line 11\n", "line 1b\n");
+        assert_eq!(
+            DiffEngine::to_unified(&old, &new, 1),
+            "@@ -1,3 +1,3 @@\n line 1\n-line 2\n+line b\n line 3\n@@ -10,3 +10,3 @@\n line 10\n-line 11\n+line 1b\n line 12\n"
+        );
+        // Close enough for their context to meet, the changes share a hunk
+        assert!(DiffEngine::to_unified(&old, &new, 4).starts_with("@@ -1,12 +1,12 @@\n"));
+
+        assert_eq!(DiffEngine::to_unified("a\nb", "a\nc", 3), "@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+c\n\\ No newline at end of file\n");
+        assert_eq!(DiffEngine::to_unified("", "new\n", 3), "@@ -0,0 +1 @@\n+new\n");
+        assert_eq!(DiffEngine::to_unified("same\n", "same\n", 3), "");
+    }
+
+    #[test]
+    fn test_unified_diff_of_insertions_deletions_and_changes() {
+        let old = "one\ntwo\nthree\nfour\nfive\nsix\n";
+        assert_eq!(
+            DiffEngine::to_unified(old, "one\ntwo\nthree\nthree and a half\nfour\nfive\nsix\n", 2),
+            "@@ -2,4 +2,5 @@\n two\n three\n+three and a half\n four\n five\n"
+        );
+        assert_eq!(
+            DiffEngine::to_unified(old, "one\nthree\nfour\nfive\nsix\n", 1),
+            "@@ -1,3 +1,2 @@\n one\n-two\n three\n"
+        );
+        assert_eq!(
+            DiffEngine::to_unified(old, "one\ntwo\n3\nfour\nfive\nsix\n", 1),
+            "@@ -2,3 +2,3 @@\n two\n-three\n+3\n four\n"
+        );
+        // A line removed near the top and one added near the bottom stay two separate hunks
+        // rather than everything between them being replaced
+        assert_eq!(
+            DiffEngine::to_unified(old, "one\nthree\nfour\nfive\nfive and a half\nsix\n", 0),
+            "@@ -2 +1,0 @@\n-two\n@@ -5,0 +5 @@\n+five and a half\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_patch_applies_and_checks_context() {
+        let old: String = (1..=12).map(|n| format!("line {}\n", n)).collect();
+        let new = old.replace("line 2\n", "").replace("line 11\n", "line 11\nline 11b\n") + "tail";
+        let patch = format!("--- a/pad.txt\n+++ b/pad.txt\n{}", DiffEngine::to_unified(&old, &new, 2));
+        assert_eq!(DiffEngine::apply_unified(&old, &patch), Ok(new.clone()));
+
+        // The patch was made against other text
+        let edited = old.replace("line 11\n", "line eleven\n");
+        assert_eq!(DiffEngine::apply_unified(&edited, &patch), Err(PatchError::ContextMismatch { hunk: 2, line: 11 }));
+
+        assert_eq!(DiffEngine::apply_unified(&old, "@@ -1,2 +1,2 @@\n line 1\n"), Err(PatchError::Malformed { line: 3, reason: "the hunk ends before its lines do".to_string() }));
+        assert!(matches!(DiffEngine::apply_unified(&old, "@@ -1 +1 @@\n line 1\nstray\n"), Err(PatchError::Malformed { line: 3, .. })));
+        assert!(matches!(DiffEngine::apply_unified(&old, "@@ -x +1 @@\n"), Err(PatchError::Malformed { line: 1, .. })));
+        assert_eq!(DiffEngine::apply_unified(&old, ""), Ok(old));
+    }
+
+    #[test]
+    fn test_same_length_edit_replaces_only_changed_characters() {
+        let old = format!("let value = {}; // {}", "a".repeat(40), "b".repeat(40));
+        let one = old.replacen("aaaa;", "aaab;", 1);
+        assert_eq!(DiffEngine::diff(&old, &one), vec![DiffOperation::Replace(51, 52, "b".to_string())]);
+
+        // Two separate changes stay two small replacements, applied last to first
+        let two = one.replacen("value", "valve", 1).replacen("b;", "c;", 1);
+        let operations = DiffEngine::diff(&old, &two);
+        assert_eq!(operations, vec![DiffOperation::Replace(51, 52, "c".to_string()), DiffOperation::Replace(7, 8, "v".to_string())]);
+        assert_eq!(DiffEngine::apply(&old, &operations), two);
+
+        // Changes a few characters apart become one replacement
+        assert_eq!(DiffEngine::diff("abcdefgh", "aXcXefgh"), vec![DiffOperation::Replace(1, 4, "XcX".to_string())]);
+    }
+
+    #[test]
+    fn test_transform_keeps_reversed_ranges_for_the_caller() {
+        // Found by prop_ws_survives_arbitrary_frames: shifting a client's reversed range past a
+        // deletion underflowed, panicking with the pad locked
+        let reversed = vec![DiffOperation::Delete(6, 0)];
+        let deletion = vec![DiffOperation::Delete(0, 3)];
+        assert_eq!(DiffEngine::transform(&reversed, &deletion, false), (reversed.clone(), deletion.clone()));
+        assert_eq!(DiffEngine::transform(&deletion, &reversed, false).0, deletion);
+    }
+
+    proptest! {
+        #![proptest_config(config(256))]
+
+        #[test]
+        fn prop_apply_diff_yields_target(old in "[ab\né]{0,16}", new in "[ab\né]{0,16}") {
+            prop_assert_eq!(DiffEngine::apply(&old, &DiffEngine::diff(&old, &new)), new);
+        }
+
+        #[test]
+        fn prop_unified_patch_round_trips(old in "[ab\né]{0,24}", new in "[ab\né]{0,24}", context in 0usize..4) {
+            let patch = DiffEngine::to_unified(&old, &new, context);
+            prop_assert_eq!(DiffEngine::apply_unified(&old, &patch), Ok(new));
+        }
+
+        #[test]
+        fn prop_apply_diff_yields_target_any_text(old in any::<String>(), new in any::<String>()) {
+            prop_assert_eq!(DiffEngine::apply(&old, &DiffEngine::diff(&old, &new)), new);
+        }
+
+        /// Concurrent edits converge whichever order they are applied in.
+        #[test]
+        fn prop_transform_converges(
+            text in "[abé]{0,12}",
+            (a_start, a_len, a_text) in (0..16usize, 0..4usize, "[xß]{0,3}"),
+            (b_start, b_len, b_text) in (0..16usize, 0..4usize, "[yß]{0,3}"),
+            a_first in any::<bool>(),
+        ) {
+            let a = edit_of(&text, a_start, a_len, &a_text);
+            let b = edit_of(&text, b_start, b_len, &b_text);
+            let (a_after_b, b_after_a) = DiffEngine::transform(&a, &b, a_first);
+            let via_b = DiffEngine::apply(&DiffEngine::apply(&text, &b), &a_after_b);
+            let via_a = DiffEngine::apply(&DiffEngine::apply(&text, &a), &b_after_a);
+            prop_assert_eq!(via_a, via_b);
+        }
+
+        /// Edits to separate blocks of lines merge cleanly, keeping both sides' changes.
+        #[test]
+        fn prop_merge3_non_overlapping_is_clean(
+            lines in prop::collection::vec("[abé]{0,4}", 3..10),
+            split in 1..8usize,
+            ours_edit in prop::collection::vec("x[xé\n]{0,3}", 1..4),
+            theirs_edit in prop::collection::vec("y[yß\n]{0,3}", 1..4),
+        ) {
+            // Ours edits lines before `split`, theirs edits lines after it; line `split` is untouched.
+            // The x/y markers keep the sides apart: a bare inserted newline could be either side's.
+            let split = split.min(lines.len() - 2);
+            let replace = |edits: &[String], offset: usize| {
+                let mut edited = lines.clone();
+                for (i, edit) in edits.iter().enumerate() {
+                    if let Some(line) = edited.get_mut(offset + i) {
+                        *line = edit.clone();
+                    }
+                }
+                edited
+            };
+            let ours_lines = replace(&ours_edit[..ours_edit.len().min(split)], 0);
+            let theirs_lines = replace(&theirs_edit, split + 1);
+            let mut expected = ours_lines.clone();
+            expected[split + 1..].clone_from_slice(&theirs_lines[split + 1..]);
+
+            let result = DiffEngine::merge3(&lines.join("\n"), &ours_lines.join("\n"), &theirs_lines.join("\n"));
+            prop_assert!(result.is_clean(), "conflicts: {:?}", result.conflicts);
+            prop_assert_eq!(result.merged, expected.join("\n"));
+        }
+    }
+}
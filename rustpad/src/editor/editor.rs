@@ -1,7 +1,10 @@
+use crate::editor::config::EditorConfig;
 use crate::editor::state::EditorState;
-use crate::editor::events::{InputEvent, CursorMove};
+use crate::editor::typing_rules::TypingRules;
+use crate::editor::events::InputEvent;
 use crate::editor::version_control::VersionControl;
 use crate::networking::peer_sync::PeerSync;
+use std::borrow::Cow;
 
 /// `Editor` is the core structure that manages text input, cursor position,
 /// document state, and interactions with other modules like version control and peer sync.
@@ -24,24 +27,24 @@ impl Editor {
     /// Handles text insertion into the document. Updates the document state,
     /// cursor position, and synchronization with peers.
     pub fn insert_text(&mut self, text: &str) {
+        // Track the state before this change in version control
+        self.version_control.track_change(&self.state);
+
         // Update the document state by inserting the text
         self.state.insert_text(text);
 
-        // Track this change in version control
-        self.version_control.track_change(&self.state);
-
         // Sync the change with peers
         self.peer_sync.broadcast_change(&self.state);
     }
 
     /// Handles text deletion from the document.
     pub fn delete_text(&mut self, start: usize, end: usize) {
+        // Track the state before this change in version control
+        self.version_control.track_change(&self.state);
+
         // Update the document state by deleting the text
         self.state.delete_text(start, end);
 
-        // Track this change in version control
-        self.version_control.track_change(&self.state);
-
         // Sync the change with peers
         self.peer_sync.broadcast_change(&self.state);
     }
@@ -64,7 +67,8 @@ impl Editor {
                 self.delete_text(start, end);
             }
             InputEvent::MoveCursor(cursor_move) => {
-                self.move_cursor(cursor_move as usize);
+                cursor_move.apply(&mut self.state);
+                self.peer_sync.broadcast_cursor(&self.state);
             }
             InputEvent::Undo => {
                 self.undo();
@@ -95,8 +99,36 @@ impl Editor {
         }
     }
 
+    /// Returns the text to write when saving, running the whitespace cleanup first if `config`
+    /// asks for it. The cleanup is an ordinary change, tracked for undo and synced with peers.
+    pub fn save(&mut self, config: &EditorConfig) -> Cow<'_, str> {
+        if config.cleanup_on_save {
+            self.version_control.track_change(&self.state);
+            self.state.set_cleanup_options(config.cleanup);
+            self.state.cleanup();
+            self.peer_sync.broadcast_change(&self.state);
+        }
+        self.state.get_text()
+    }
+
+    /// Sets the document's language, turning on its on-type formatting rules as `config` allows.
+    pub fn set_language(&mut self, language: &str, config: &EditorConfig) {
+        self.state.set_typing_rules(Some(TypingRules::new(language, config.typing_rules)));
+    }
+
+    /// Applies what peers sent since the last call and sends a held back cursor position.
+    pub fn sync(&mut self) {
+        self.peer_sync.sync(&mut self.state);
+    }
+
     /// Gets the current state of the editor, useful for rendering and synchronization.
     pub fn get_state(&self) -> &EditorState {
         &self.state
     }
 }
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
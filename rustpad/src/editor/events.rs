@@ -36,6 +36,19 @@ pub enum CursorMove {
     ToPosition(usize),
 }
 
+impl CursorMove {
+    /// Moves the primary cursor of `state` accordingly.
+    pub fn apply(self, state: &mut crate::editor::state::EditorState) {
+        match self {
+            CursorMove::Up => state.move_cursor_up(),
+            CursorMove::Down => state.move_cursor_down(),
+            CursorMove::Left => state.move_cursor_left(),
+            CursorMove::Right => state.move_cursor_right(),
+            CursorMove::ToPosition(position) => state.move_cursor(position),
+        }
+    }
+}
+
 /// The `EventHandler` struct is responsible for handling input events and dispatching them
 /// to the appropriate methods in the editor.
 pub struct EventHandler;
@@ -56,26 +69,15 @@ impl EventHandler {
         Vec::new()
     }
 
-    /// Dispatches a given input event to the appropriate method in the editor.
-    /// This is where you handle different types of input events like text insertion,
-    /// cursor movement, undo/redo, etc.
+    /// Dispatches a given input event to the editor, which applies it to its state and
+    /// history and shares it with peers.
     pub fn handle_event(&self, event: InputEvent, editor: &mut crate::editor::Editor) {
-        match event {
-            InputEvent::InsertText(text) => {
-                editor.insert_text(&text);
-            }
-            InputEvent::DeleteText(start, end) => {
-                editor.delete_text(start, end);
-            }
-            InputEvent::MoveCursor(cursor_move) => {
-                editor.move_cursor(cursor_move);
-            }
-            InputEvent::Undo => {
-                editor.undo();
-            }
-            InputEvent::Redo => {
-                editor.redo();
-            }
-        }
+        editor.handle_event(event);
+    }
+}
+
+impl Default for EventHandler {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -1,6 +1,8 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use serde::{Deserialize, Serialize};
+use crate::editor::autocomplete::Autocomplete;
 
 /// Trait that defines the basic functionality of an extension
 pub trait Extension: Send + Sync {
@@ -40,11 +42,8 @@ pub type ExtensionStore = Arc<Mutex<HashMap<String, Arc<dyn Extension>>>>;
 pub fn initialize_extensions() -> ExtensionStore {
     let mut extensions: HashMap<String, Arc<dyn Extension>> = HashMap::new();
 
-    // Example of a built-in extension
-    let autocomplete_extension: Arc<dyn Extension> = Arc::new(CustomExtension {
-        id: "autocomplete".to_string(),
-        description: "Provides autocompletion for common programming languages.".to_string(),
-    });
+    // Built-in extensions
+    let autocomplete_extension: Arc<dyn Extension> = Arc::new(Autocomplete::new());
 
     // Insert the built-in extension into the store
     extensions.insert(autocomplete_extension.id(), autocomplete_extension);
@@ -57,11 +56,12 @@ pub fn initialize_extensions() -> ExtensionStore {
 pub fn add_extension(extension_store: ExtensionStore, extension: Arc<dyn Extension>) -> Result<(), String> {
     let mut store = extension_store.lock().unwrap();
 
-    if store.contains_key(&extension.id()) {
-        Err(format!("Extension with ID '{}' already exists.", extension.id()))
-    } else {
-        store.insert(extension.id(), extension);
-        Ok(())
+    match store.entry(extension.id()) {
+        Entry::Occupied(entry) => Err(format!("Extension with ID '{}' already exists.", entry.key())),
+        Entry::Vacant(entry) => {
+            entry.insert(extension);
+            Ok(())
+        }
     }
 }
 
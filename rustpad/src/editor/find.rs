@@ -0,0 +1,100 @@
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+/// Longest pattern find accepts
+pub const MAX_PATTERN_LEN: usize = 256;
+
+/// Memory a compiled pattern may take; larger ones, like deeply repeated classes, are refused
+const MAX_COMPILED_BYTES: usize = 1024 * 1024;
+
+/// Deepest nesting of groups and repetitions accepted
+const MAX_NESTING: u32 = 32;
+
+/// What to find: `pattern` as literal text, or as a regular expression when `regex` is set
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FindQuery {
+    pub pattern: String,
+    #[serde(default)]
+    pub regex: bool,
+    #[serde(default)]
+    pub case_sensitive: bool,
+}
+
+impl FindQuery {
+    /// Finds `pattern` as literal text, ignoring case
+    pub fn literal(pattern: &str) -> Self {
+        Self { pattern: pattern.to_string(), regex: false, case_sensitive: false }
+    }
+
+    /// Finds `pattern` as a regular expression, ignoring case
+    pub fn regex(pattern: &str) -> Self {
+        Self { pattern: pattern.to_string(), regex: true, case_sensitive: false }
+    }
+
+    /// Matches case exactly instead
+    pub fn with_case_sensitive(self, case_sensitive: bool) -> Self {
+        Self { case_sensitive, ..self }
+    }
+}
+
+/// A `FindQuery` checked and compiled, the guard every find goes through. Patterns are bounded
+/// in length, nesting and compiled size, and matching takes time linear in the text searched,
+/// so no pattern can stall a find however it is written.
+#[derive(Debug, Clone)]
+pub struct Finder {
+    regex: Regex,
+}
+
+impl Finder {
+    /// Compiles `query`, refusing empty, oversized and invalid patterns
+    pub fn new(query: &FindQuery) -> Result<Self, String> {
+        if query.pattern.is_empty() {
+            return Err("Nothing to find".to_string());
+        }
+        if query.pattern.chars().count() > MAX_PATTERN_LEN {
+            return Err(format!("Patterns are limited to {} characters", MAX_PATTERN_LEN));
+        }
+        let pattern = if query.regex { query.pattern.clone() } else { regex::escape(&query.pattern) };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(!query.case_sensitive)
+            .multi_line(true)
+            .size_limit(MAX_COMPILED_BYTES)
+            .dfa_size_limit(MAX_COMPILED_BYTES)
+            .nest_limit(MAX_NESTING)
+            .build()
+            .map_err(|e| format!("Invalid pattern: {}", e))?;
+        Ok(Self { regex })
+    }
+
+    /// The byte ranges of the matches in `text`, in order. Empty matches, like those of `^`,
+    /// are left out.
+    pub fn find_iter<'a>(&'a self, text: &'a str) -> impl Iterator<Item = (usize, usize)> + 'a {
+        self.regex.find_iter(text).filter(|found| !found.is_empty()).map(|found| (found.start(), found.end()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literal_and_regex_queries() {
+        let text = "let a = (1 + 2);\nLet b = a.max(3);";
+        let found = |query: &FindQuery| Finder::new(query).unwrap().find_iter(text).collect::<Vec<_>>();
+        assert_eq!(found(&FindQuery::literal("(1 + 2)")), vec![(8, 15)]);
+        assert_eq!(found(&FindQuery::literal("let")), vec![(0, 3), (17, 20)]);
+        assert_eq!(found(&FindQuery::literal("let").with_case_sensitive(true)), vec![(0, 3)]);
+        assert_eq!(found(&FindQuery::regex(r"^let \w")), vec![(0, 5), (17, 22)]);
+        assert_eq!(found(&FindQuery::regex(r"\d")), vec![(9, 10), (13, 14), (31, 32)]);
+        assert!(found(&FindQuery::regex("^")).is_empty());
+    }
+
+    #[test]
+    fn test_patterns_are_bounded() {
+        assert!(Finder::new(&FindQuery::literal("")).is_err());
+        assert!(Finder::new(&FindQuery::regex("(unclosed")).unwrap_err().starts_with("Invalid pattern"));
+        assert!(Finder::new(&FindQuery::literal(&"a".repeat(MAX_PATTERN_LEN + 1))).is_err());
+        assert!(Finder::new(&FindQuery::regex(&format!("{}a{}", "(".repeat(40), ")".repeat(40)))).is_err()); // Too deeply nested
+        assert!(Finder::new(&FindQuery::regex(r"(\w{1000}){1000}")).is_err()); // Compiles too large
+    }
+}
@@ -1,10 +1,11 @@
-use std::process::{Command, Output};
+use std::io::Write;
+use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 /// Supported languages for code formatting
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Language {
     Rust,
     JavaScript,
@@ -27,7 +28,7 @@ pub struct RustFormatter;
 
 impl Formatter for RustFormatter {
     fn format_code(&self, code: &str) -> Result<String, FormatterError> {
-        run_formatter_command("rustfmt", code)
+        run_formatter_command("rustfmt", &["--emit", "stdout"], code)
     }
 }
 
@@ -36,7 +37,7 @@ pub struct JavaScriptFormatter;
 
 impl Formatter for JavaScriptFormatter {
     fn format_code(&self, code: &str) -> Result<String, FormatterError> {
-        run_formatter_command("prettier", code)
+        run_formatter_command("prettier", &["--stdin-filepath", "pad.js"], code)
     }
 }
 
@@ -45,19 +46,27 @@ pub struct PythonFormatter;
 
 impl Formatter for PythonFormatter {
     fn format_code(&self, code: &str) -> Result<String, FormatterError> {
-        run_formatter_command("black", code)
+        run_formatter_command("black", &["--quiet", "-"], code)
     }
 }
 
-/// Runs a formatter command and returns the formatted code or an error
-fn run_formatter_command(command: &str, code: &str) -> Result<String, FormatterError> {
+/// Runs a formatter command on `code`, given on its stdin, and returns the formatted code or an
+/// error
+fn run_formatter_command(command: &str, args: &[&str], code: &str) -> Result<String, FormatterError> {
+    let failed = |e: std::io::Error| FormatterError {
+        message: format!("Failed to run formatter: {}", e),
+    };
     // Run the formatter command as an external process
-    let output: Output = Command::new(command)
-        .arg("--stdin")
-        .output()
-        .map_err(|e| FormatterError {
-            message: format!("Failed to run formatter: {}", e),
-        })?;
+    let mut child = Command::new(command)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(failed)?;
+    // Dropping stdin once written closes it, so the formatter sees the end of the code
+    child.stdin.take().unwrap().write_all(code.as_bytes()).map_err(failed)?;
+    let output = child.wait_with_output().map_err(failed)?;
 
     if output.status.success() {
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
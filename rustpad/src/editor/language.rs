@@ -0,0 +1,40 @@
+use std::path::Path;
+
+/// Longest language name a document can be set to
+pub const MAX_LANGUAGE_LEN: usize = 32;
+
+/// The language of a document backed by `path`: its lowercased extension. Files without one
+/// are plain text.
+pub fn language_for_path(path: &Path) -> Option<String> {
+    path.extension().map(|extension| extension.to_string_lossy().to_lowercase())
+}
+
+/// Checks a language sent by a client, given by name or file extension (e.g. "rust" or "rs"),
+/// and lowercases it.
+pub fn validate_language(language: &str) -> Result<String, String> {
+    let language = language.trim().to_lowercase();
+    if language.is_empty() || language.len() > MAX_LANGUAGE_LEN {
+        return Err(format!("Languages are 1 to {} characters long", MAX_LANGUAGE_LEN));
+    }
+    if !language.chars().all(|c| c.is_ascii_alphanumeric() || "+#-_.".contains(c)) {
+        return Err(format!("Invalid language {:?}", language));
+    }
+    Ok(language)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_languages_from_paths_and_clients() {
+        assert_eq!(language_for_path(Path::new("src/main.RS")).as_deref(), Some("rs"));
+        assert_eq!(language_for_path(Path::new("Makefile")), None);
+
+        assert_eq!(validate_language(" Python ").unwrap(), "python");
+        assert_eq!(validate_language("c++").unwrap(), "c++");
+        assert!(validate_language("").is_err());
+        assert!(validate_language("<script>").is_err());
+        assert!(validate_language(&"x".repeat(MAX_LANGUAGE_LEN + 1)).is_err());
+    }
+}
@@ -3,7 +3,7 @@ use std::sync::{Arc, Mutex};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LintError {
     pub line: usize,
     pub column: usize,
@@ -22,7 +22,7 @@ pub trait Linter {
 pub struct RustLinter;
 
 impl Linter for RustLinter {
-    fn lint_code(&self, code: &str) -> Vec<LintError> {
+    fn lint_code(&self, _code: &str) -> Vec<LintError> {
         let mut errors = Vec::new();
 
         // Write code to a temporary file and run `cargo check` or another Rust linter tool.
@@ -48,7 +48,7 @@ impl Linter for RustLinter {
 pub struct JavaScriptLinter;
 
 impl Linter for JavaScriptLinter {
-    fn lint_code(&self, code: &str) -> Vec<LintError> {
+    fn lint_code(&self, _code: &str) -> Vec<LintError> {
         let mut errors = Vec::new();
 
         // Run ESLint as an external command
@@ -74,7 +74,7 @@ impl Linter for JavaScriptLinter {
 pub struct PythonLinter;
 
 impl Linter for PythonLinter {
-    fn lint_code(&self, code: &str) -> Vec<LintError> {
+    fn lint_code(&self, _code: &str) -> Vec<LintError> {
         let mut errors = Vec::new();
 
         // Run Pylint as an external command
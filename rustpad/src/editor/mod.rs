@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod editor;
 pub mod syntax_highlighting;
 pub mod version_control;
@@ -5,6 +6,23 @@ pub mod events;
 pub mod state;
 pub mod diff_engine;
 pub mod extensions;
+pub mod autocomplete;
+pub mod config;
+pub mod comments;
+pub mod language;
+pub mod tasks;
+pub mod linter;
+pub mod formatter;
+pub mod structured;
+pub mod typing_rules;
+pub mod snippets;
+pub mod spellcheck;
+pub mod save_hooks;
+pub mod session;
+pub mod find;
+pub mod annotations;
+pub mod collaboration;
+pub mod theme;
 
 
 use crate::editor::state::EditorState;
@@ -54,7 +72,7 @@ impl Editor {
             self.syntax_highlighter.highlight(&mut self.state);
 
             // Sync the editor state with peers in real-time
-            self.peer_sync.sync(&self.state);
+            self.peer_sync.sync(&mut self.state);
 
             // Render the updated state to the UI
             self.renderer.render(&self.state);
@@ -62,20 +80,20 @@ impl Editor {
     }
 
     /// Handles different types of input events by calling appropriate methods.
-    fn handle_event(&mut self, event: InputEvent) {
+    pub fn handle_event(&mut self, event: InputEvent) {
         match event {
             InputEvent::InsertText(text) => {
-                self.state.insert_text(&text);
                 self.version_control.track_change(&self.state);
+                self.state.insert_text(&text);
                 self.peer_sync.broadcast_change(&self.state);
             }
             InputEvent::DeleteText(start, end) => {
-                self.state.delete_text(start, end);
                 self.version_control.track_change(&self.state);
+                self.state.delete_text(start, end);
                 self.peer_sync.broadcast_change(&self.state);
             }
             InputEvent::MoveCursor(cursor_move) => {
-                self.state.move_cursor(cursor_move);
+                cursor_move.apply(&mut self.state);
                 // Optionally sync cursor position with peers
                 self.peer_sync.broadcast_cursor(&self.state);
             }
@@ -95,3 +113,9 @@ impl Editor {
     }
 }
 
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::editor::formatter::{Formatter, JavaScriptFormatter, PythonFormatter, RustFormatter};
+use crate::editor::linter::{JavaScriptLinter, LintError, Linter, PythonLinter, RustLinter};
+
+/// Metadata key holding a document's save hooks, as JSON; documents without one use their
+/// workspace's
+pub const SAVE_HOOKS_KEY: &str = "save_hooks";
+
+/// Who the edits of save hooks are attributed to
+pub const SAVE_HOOKS_ACTOR: &str = "save-hooks";
+
+/// Time a hook gets unless configured otherwise
+pub const DEFAULT_HOOK_TIMEOUT_MS: u64 = 5_000;
+
+/// Longest timeout a hook can be given
+pub const MAX_HOOK_TIMEOUT_MS: u64 = 60_000;
+
+/// A built-in action run on a document's content when it is saved
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "hook", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SaveHook {
+    TrimTrailingWhitespace,
+    FinalNewline,
+    Format, // With the formatter of the document's language, if it has one
+    LintGate {
+        #[serde(default = "default_block_on")]
+        block_on: String, // Lowest severity that blocks the save: "error" or "warning"
+    },
+}
+
+fn default_block_on() -> String {
+    "error".to_string()
+}
+
+impl SaveHook {
+    /// The name the hook is configured by
+    pub fn name(&self) -> &'static str {
+        match self {
+            SaveHook::TrimTrailingWhitespace => "trim_trailing_whitespace",
+            SaveHook::FinalNewline => "final_newline",
+            SaveHook::Format => "format",
+            SaveHook::LintGate { .. } => "lint_gate",
+        }
+    }
+}
+
+/// The save hooks of a document, or the defaults of a workspace
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SaveHooksConfig {
+    #[serde(default)]
+    pub hooks: Vec<SaveHook>, // Run in this order, each on what the previous one left
+    #[serde(default)]
+    pub on_autosave: bool, // Autosaves skip the hooks unless set
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64, // Per hook; a hook taking longer is skipped with a warning
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_HOOK_TIMEOUT_MS
+}
+
+impl Default for SaveHooksConfig {
+    fn default() -> Self {
+        Self { hooks: Vec::new(), on_autosave: false, timeout_ms: DEFAULT_HOOK_TIMEOUT_MS }
+    }
+}
+
+impl SaveHooksConfig {
+    /// Creates a configuration running no hooks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `hooks` in order on every save but autosaves
+    pub fn with_hooks(self, hooks: Vec<SaveHook>) -> Self {
+        Self { hooks, ..self }
+    }
+
+    /// Runs the hooks on autosaves too
+    pub fn with_autosave(self) -> Self {
+        Self { on_autosave: true, ..self }
+    }
+
+    /// Gives each hook `timeout_ms` to finish
+    pub fn with_timeout_ms(self, timeout_ms: u64) -> Self {
+        Self { timeout_ms, ..self }
+    }
+
+    /// Parses and validates a configuration sent by a client
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let value: serde_json::Value = serde_json::from_str(json).map_err(|e| format!("Invalid save hooks: {}", e))?;
+        // Serde lets unknown options of option-less hooks through, so check them first
+        let hooks = value.get("hooks").and_then(serde_json::Value::as_array).into_iter().flatten();
+        for hook in hooks.filter_map(serde_json::Value::as_object) {
+            let name = hook.get("hook").and_then(serde_json::Value::as_str).unwrap_or_default();
+            let options: &[&str] = if name == "lint_gate" { &["block_on"] } else { &[] };
+            if let Some(option) = hook.keys().find(|key| *key != "hook" && !options.contains(&key.as_str())) {
+                return Err(format!("Invalid save hooks: {} has no option {}", name, option));
+            }
+        }
+        let config: Self = serde_json::from_value(value).map_err(|e| format!("Invalid save hooks: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the options: each hook at most once, known lint severities and a timeout within
+    /// 1 to `MAX_HOOK_TIMEOUT_MS`
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout_ms == 0 || self.timeout_ms > MAX_HOOK_TIMEOUT_MS {
+            return Err(format!("Hook timeouts are 1 to {} ms", MAX_HOOK_TIMEOUT_MS));
+        }
+        for (index, hook) in self.hooks.iter().enumerate() {
+            if self.hooks[..index].iter().any(|earlier| earlier.name() == hook.name()) {
+                return Err(format!("Hook {} is listed twice", hook.name()));
+            }
+            if let SaveHook::LintGate { block_on } = hook {
+                if severity_rank(block_on).is_none() {
+                    return Err(format!("lint_gate can block on \"error\" or \"warning\", not {:?}", block_on));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a document is being saved
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SaveKind {
+    Manual,
+    Checkpoint, // Saved and kept as a checkpoint to revert to
+    Autosave,
+}
+
+/// What a hook made of the content
+#[derive(Debug, Clone)]
+pub enum HookOutcome {
+    Modified(String),
+    Pass,
+    Veto(Vec<LintError>), // The diagnostics blocking the save
+}
+
+/// Content that passed the hooks, as they left it
+#[derive(Debug, Clone, PartialEq)]
+pub struct HookReport {
+    pub content: String,
+    pub warnings: Vec<String>, // Hooks skipped for timing out or failing
+}
+
+/// A save blocked by a hook
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SaveVeto {
+    pub hook: String,
+    pub diagnostics: Vec<LintError>,
+}
+
+/// Runs save hooks, with the formatters and linters they use by language name and extension
+#[derive(Clone)]
+pub struct SaveHooks {
+    formatters: HashMap<String, Arc<dyn Formatter + Send + Sync>>,
+    linters: HashMap<String, Arc<dyn Linter + Send + Sync>>,
+}
+
+impl SaveHooks {
+    /// Creates a runner with the built-in formatters and linters for Rust, JavaScript and Python
+    pub fn new() -> Self {
+        let mut hooks = Self { formatters: HashMap::new(), linters: HashMap::new() };
+        for language in ["rust", "rs"] {
+            hooks = hooks.with_formatter(language, Arc::new(RustFormatter)).with_linter(language, Arc::new(RustLinter));
+        }
+        for language in ["javascript", "js"] {
+            hooks = hooks.with_formatter(language, Arc::new(JavaScriptFormatter)).with_linter(language, Arc::new(JavaScriptLinter));
+        }
+        for language in ["python", "py"] {
+            hooks = hooks.with_formatter(language, Arc::new(PythonFormatter)).with_linter(language, Arc::new(PythonLinter));
+        }
+        hooks
+    }
+
+    /// Formats documents in `language` with `formatter`
+    pub fn with_formatter(mut self, language: &str, formatter: Arc<dyn Formatter + Send + Sync>) -> Self {
+        self.formatters.insert(language.to_string(), formatter);
+        self
+    }
+
+    /// Lints documents in `language` with `linter`
+    pub fn with_linter(mut self, language: &str, linter: Arc<dyn Linter + Send + Sync>) -> Self {
+        self.linters.insert(language.to_string(), linter);
+        self
+    }
+
+    /// Runs the hooks of `config` on `content`, in order. Autosaves skip them unless the config
+    /// says otherwise. A hook that fails or runs past the timeout is skipped with a warning.
+    pub fn run(&self, config: &SaveHooksConfig, kind: SaveKind, language: Option<&str>, content: &str) -> Result<HookReport, SaveVeto> {
+        let mut report = HookReport { content: content.to_string(), warnings: Vec::new() };
+        if kind == SaveKind::Autosave && !config.on_autosave {
+            return Ok(report);
+        }
+        let timeout = Duration::from_millis(config.timeout_ms);
+        for hook in &config.hooks {
+            match self.run_hook(hook, language, &report.content, timeout) {
+                Ok(HookOutcome::Modified(content)) => report.content = content,
+                Ok(HookOutcome::Pass) => {}
+                Ok(HookOutcome::Veto(diagnostics)) => return Err(SaveVeto { hook: hook.name().to_string(), diagnostics }),
+                Err(warning) => report.warnings.push(warning),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Runs one hook on its own thread, giving up on it after `timeout`. The thread of a hook
+    /// that timed out is left to finish on its own.
+    fn run_hook(&self, hook: &SaveHook, language: Option<&str>, content: &str, timeout: Duration) -> Result<HookOutcome, String> {
+        let language = language.map(str::to_lowercase);
+        let formatter = language.as_ref().and_then(|language| self.formatters.get(language)).cloned();
+        let linter = language.as_ref().and_then(|language| self.linters.get(language)).cloned();
+        let (hook, content) = (hook.clone(), content.to_string());
+        let name = hook.name();
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(apply(&hook, formatter, linter, &content));
+        });
+        match receiver.recv_timeout(timeout) {
+            Ok(outcome) => outcome.map_err(|e| format!("Skipped {}: {}", name, e)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(format!("Skipped {}: took longer than {} ms", name, timeout.as_millis())),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(format!("Skipped {}: it crashed", name)),
+        }
+    }
+}
+
+impl Default for SaveHooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn apply(hook: &SaveHook, formatter: Option<Arc<dyn Formatter + Send + Sync>>, linter: Option<Arc<dyn Linter + Send + Sync>>, content: &str) -> Result<HookOutcome, String> {
+    let modified = |new: String| if new == content { HookOutcome::Pass } else { HookOutcome::Modified(new) };
+    match hook {
+        SaveHook::TrimTrailingWhitespace => {
+            // Keeps the `\r` of CRLF line endings
+            let lines: Vec<String> = content
+                .split('\n')
+                .map(|line| match line.strip_suffix('\r') {
+                    Some(line) => format!("{}\r", line.trim_end_matches([' ', '\t'])),
+                    None => line.trim_end_matches([' ', '\t']).to_string(),
+                })
+                .collect();
+            Ok(modified(lines.join("\n")))
+        }
+        SaveHook::FinalNewline => match content.is_empty() || content.ends_with('\n') {
+            true => Ok(HookOutcome::Pass),
+            false => Ok(HookOutcome::Modified(format!("{}\n", content))),
+        },
+        SaveHook::Format => match formatter {
+            Some(formatter) => formatter.format_code(content).map(modified).map_err(|e| e.message),
+            None => Ok(HookOutcome::Pass),
+        },
+        SaveHook::LintGate { block_on } => {
+            let Some(linter) = linter else { return Ok(HookOutcome::Pass) };
+            let threshold = severity_rank(block_on).unwrap_or(2);
+            let blocking: Vec<LintError> = linter
+                .lint_code(content)
+                .into_iter()
+                .filter(|diagnostic| severity_rank(&diagnostic.severity).is_some_and(|rank| rank >= threshold))
+                .collect();
+            Ok(if blocking.is_empty() { HookOutcome::Pass } else { HookOutcome::Veto(blocking) })
+        }
+    }
+}
+
+fn severity_rank(severity: &str) -> Option<u8> {
+    match severity {
+        "warning" => Some(1),
+        "error" => Some(2),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::formatter::FormatterError;
+
+    /// Uppercases code, leaving trailing spaces on every line
+    struct ShoutingFormatter;
+
+    impl Formatter for ShoutingFormatter {
+        fn format_code(&self, code: &str) -> Result<String, FormatterError> {
+            Ok(code.lines().map(|line| format!("{}  \n", line.to_uppercase())).collect())
+        }
+    }
+
+    struct SlowFormatter;
+
+    impl Formatter for SlowFormatter {
+        fn format_code(&self, code: &str) -> Result<String, FormatterError> {
+            thread::sleep(Duration::from_millis(500));
+            Ok(code.to_uppercase())
+        }
+    }
+
+    /// Reports a warning and an error wherever it finds "todo" and "panic"
+    struct StrictLinter;
+
+    impl Linter for StrictLinter {
+        fn lint_code(&self, code: &str) -> Vec<LintError> {
+            let found = |word: &str, severity: &str| {
+                code.contains(word).then(|| LintError { line: 1, column: 1, message: format!("Found {}", word), severity: severity.to_string() })
+            };
+            found("todo", "warning").into_iter().chain(found("panic", "error")).collect()
+        }
+    }
+
+    fn hooks() -> SaveHooks {
+        SaveHooks::new().with_formatter("txt", Arc::new(ShoutingFormatter)).with_formatter("slow", Arc::new(SlowFormatter)).with_linter("txt", Arc::new(StrictLinter))
+    }
+
+    fn run(config: &SaveHooksConfig, language: &str, content: &str) -> Result<HookReport, SaveVeto> {
+        hooks().run(config, SaveKind::Manual, Some(language), content)
+    }
+
+    #[test]
+    fn test_hooks_run_in_order() {
+        let trim_then_format = SaveHooksConfig::new().with_hooks(vec![SaveHook::TrimTrailingWhitespace, SaveHook::Format]);
+        assert_eq!(run(&trim_then_format, "txt", "a \nb").unwrap().content, "A  \nB  \n");
+        let format_then_trim = SaveHooksConfig::new().with_hooks(vec![SaveHook::Format, SaveHook::TrimTrailingWhitespace]);
+        assert_eq!(run(&format_then_trim, "txt", "a \nb").unwrap().content, "A\nB\n");
+
+        // Languages without a formatter are left alone; CRLF endings survive trimming
+        let config = SaveHooksConfig::new().with_hooks(vec![SaveHook::Format, SaveHook::TrimTrailingWhitespace, SaveHook::FinalNewline]);
+        let report = run(&config, "md", "# Notes \t\r\nfine").unwrap();
+        assert_eq!(report, HookReport { content: "# Notes\r\nfine\n".to_string(), warnings: Vec::new() });
+    }
+
+    #[test]
+    fn test_lint_gate_vetoes_with_diagnostics() {
+        let gate = |block_on: &str| SaveHooksConfig::new().with_hooks(vec![SaveHook::FinalNewline, SaveHook::LintGate { block_on: block_on.to_string() }]);
+        assert_eq!(run(&gate("error"), "txt", "todo").unwrap().content, "todo\n");
+
+        let veto = run(&gate("error"), "txt", "todo: panic").unwrap_err();
+        assert_eq!(veto.hook, "lint_gate");
+        assert_eq!(veto.diagnostics.iter().map(|d| d.message.as_str()).collect::<Vec<_>>(), vec!["Found panic"]);
+        assert_eq!(run(&gate("warning"), "txt", "todo: panic").unwrap_err().diagnostics.len(), 2);
+
+        // Nothing to lint with, nothing to block
+        assert!(run(&gate("warning"), "md", "todo: panic").is_ok());
+    }
+
+    #[test]
+    fn test_slow_hook_is_skipped_with_warning() {
+        let config = SaveHooksConfig::new().with_hooks(vec![SaveHook::Format, SaveHook::FinalNewline]).with_timeout_ms(50);
+        let report = run(&config, "slow", "fn main() {}").unwrap();
+        assert_eq!(report.content, "fn main() {}\n");
+        assert_eq!(report.warnings, vec!["Skipped format: took longer than 50 ms".to_string()]);
+    }
+
+    #[test]
+    fn test_autosave_skips_hooks_unless_enabled() {
+        let config = SaveHooksConfig::new().with_hooks(vec![SaveHook::FinalNewline]);
+        assert_eq!(hooks().run(&config, SaveKind::Autosave, None, "draft").unwrap().content, "draft");
+        assert_eq!(hooks().run(&config, SaveKind::Checkpoint, None, "draft").unwrap().content, "draft\n");
+        assert_eq!(hooks().run(&config.with_autosave(), SaveKind::Autosave, None, "draft").unwrap().content, "draft\n");
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let config = SaveHooksConfig::parse(r#"{"hooks":[{"hook":"trim_trailing_whitespace"},{"hook":"lint_gate"}],"timeout_ms":2000}"#).unwrap();
+        assert_eq!(config.hooks, vec![SaveHook::TrimTrailingWhitespace, SaveHook::LintGate { block_on: "error".to_string() }]);
+        assert!(!config.on_autosave);
+
+        for invalid in [
+            r#"{"hooks":[{"hook":"delete_everything"}]}"#,
+            r#"{"hooks":[{"hook":"format","style":"tabs"}]}"#,
+            r#"{"hooks":[{"hook":"format"},{"hook":"format"}]}"#,
+            r#"{"hooks":[{"hook":"lint_gate","block_on":"info"}]}"#,
+            r#"{"hooks":[],"timeout_ms":0}"#,
+            r#"{"hooks":[],"timeout_ms":600000}"#,
+            r#"{"hooks":[],"retries":3}"#,
+        ] {
+            assert!(SaveHooksConfig::parse(invalid).is_err(), "accepted {}", invalid);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+use crate::editor::state::{Cursor, EditorState};
+use crate::editor::version_control::{HistorySnapshot, VersionControl};
+use crate::networking::optimistic::OfflineJournal;
+
+/// Format version written by `serialize_session`
+pub const SESSION_FORMAT_VERSION: u32 = 1;
+
+/// Marks the start of an encoded session
+const MAGIC: &[u8; 4] = b"RPSN";
+
+/// An editor session packed for another device: the format it was written in and the
+/// deflate-compressed session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionBlob {
+    pub version: u32,
+    pub payload: Vec<u8>, // Compressed JSON of the session
+}
+
+impl SessionBlob {
+    /// Encodes the blob for storage or transfer: magic, version (little-endian), payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 4 + self.payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /// Decodes bytes written by `to_bytes`. Only the header is checked here; the payload is
+    /// read by `restore_session`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < MAGIC.len() + 4 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err("Not a RustPad session".to_string());
+        }
+        let version = u32::from_le_bytes(bytes[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap());
+        Ok(SessionBlob { version, payload: bytes[MAGIC.len() + 4..].to_vec() })
+    }
+}
+
+/// Everything a handoff carries over
+#[derive(Serialize, Deserialize)]
+struct SessionData {
+    text: String,
+    cursor_position: usize,
+    selection: Option<(usize, usize)>,
+    secondary_cursors: Vec<Cursor>,
+    history: HistorySnapshot, // Undo and redo stacks of every branch, as diffs
+    journal: Option<OfflineJournal>, // Edits the server hasn't acknowledged yet
+}
+
+/// A session rebuilt by `restore_session`
+pub struct RestoredSession {
+    pub state: EditorState,
+    pub version_control: VersionControl,
+    pub journal: Option<OfflineJournal>, // Resume with `OptimisticBuffer::from_journal`
+}
+
+/// Packs the editor state, its undo history and any unacknowledged edits for another device.
+/// Highlighting isn't carried over; the receiving editor highlights the restored text afresh.
+pub fn serialize_session(
+    state: &EditorState,
+    version_control: &mut VersionControl,
+    journal: Option<OfflineJournal>,
+) -> Result<SessionBlob, String> {
+    let data = SessionData {
+        text: state.get_text().to_string(),
+        cursor_position: state.get_cursor_position(),
+        selection: state.get_selection_range(),
+        secondary_cursors: state.secondary_cursors().to_vec(),
+        history: version_control.snapshot(state),
+        journal,
+    };
+    let json = serde_json::to_vec(&data).map_err(|e| format!("Failed to serialize session: {}", e))?;
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&json)
+        .and_then(|_| encoder.finish())
+        .map(|payload| SessionBlob { version: SESSION_FORMAT_VERSION, payload })
+        .map_err(|e| format!("Failed to compress session: {}", e))
+}
+
+/// Rebuilds a session packed by `serialize_session`, upgrading older formats first.
+/// Sessions written by a newer version of RustPad are refused rather than guessed at.
+pub fn restore_session(blob: &SessionBlob) -> Result<RestoredSession, String> {
+    if blob.version > SESSION_FORMAT_VERSION {
+        return Err(format!(
+            "This session was saved by a newer version of RustPad (format {}, this version reads up to {}); update to restore it",
+            blob.version, SESSION_FORMAT_VERSION
+        ));
+    }
+
+    let mut json = Vec::new();
+    DeflateDecoder::new(blob.payload.as_slice())
+        .read_to_end(&mut json)
+        .map_err(|e| format!("Corrupt session: {}", e))?;
+    let value = serde_json::from_slice(&json).map_err(|e| format!("Corrupt session: {}", e))?;
+    let data = migrate(blob.version, value)?;
+
+    let mut state = EditorState::new();
+    state.replace_text(data.text);
+    state.move_cursor(data.cursor_position);
+    if let Some((start, end)) = data.selection {
+        state.set_selection(start, end);
+    }
+    state.set_secondary_cursors(data.secondary_cursors);
+
+    Ok(RestoredSession {
+        state,
+        version_control: VersionControl::from_snapshot(data.history),
+        journal: data.journal,
+    })
+}
+
+/// Reads a session saved in format `version` as the current format. When the format changes,
+/// the previous version gets an arm here that rewrites its JSON into the next version's shape.
+fn migrate(version: u32, value: serde_json::Value) -> Result<SessionData, String> {
+    match version {
+        SESSION_FORMAT_VERSION => serde_json::from_value(value).map_err(|e| format!("Corrupt session: {}", e)),
+        _ => Err(format!("Unknown session format {}", version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::diff_engine::DiffOperation;
+    use crate::networking::optimistic::OptimisticBuffer;
+    use crate::networking::protocol::DeltaMessage;
+    use crate::networking::revision_log::{Receipt, RevisionLog};
+
+    fn edit(version_control: &mut VersionControl, state: &mut EditorState, text: &str) {
+        version_control.track_change(state);
+        state.insert_text(text);
+    }
+
+    fn hand_off(blob: &SessionBlob) -> RestoredSession {
+        restore_session(&SessionBlob::from_bytes(&blob.to_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_keeps_text_cursors_and_history() {
+        let mut version_control = VersionControl::new();
+        let mut state = EditorState::new();
+        edit(&mut version_control, &mut state, "one");
+        edit(&mut version_control, &mut state, " two");
+        edit(&mut version_control, &mut state, " three");
+        state = version_control.undo(&state).unwrap();
+        version_control.create_branch("draft", &state).unwrap();
+        state.set_selection(0, 3);
+
+        let blob = serialize_session(&state, &mut version_control, None).unwrap();
+        let restored = hand_off(&blob);
+        let mut version_control = restored.version_control;
+        let state = restored.state;
+        assert_eq!(state.get_text(), "one two");
+        assert_eq!(state.get_selection_range(), Some((0, 3)));
+        assert_eq!(version_control.list_branches(), vec!["draft", "main"]);
+        assert!(restored.journal.is_none());
+
+        let redone = version_control.redo(&state).unwrap();
+        assert_eq!(redone.get_text(), "one two three");
+        let undone = version_control.undo(&redone).unwrap();
+        let undone = version_control.undo(&undone).unwrap();
+        assert_eq!(undone.get_text(), "one");
+
+        let draft = version_control.switch_branch("draft", &undone).unwrap();
+        assert_eq!(draft.get_text(), "one two");
+    }
+
+    #[test]
+    fn test_handoff_resumes_offline_edits() {
+        let mut server = RevisionLog::new("hello");
+        let mut desktop = OptimisticBuffer::new("hello", 0).with_client_id("desktop");
+        desktop.local_edit("hello world").unwrap();
+        let first = desktop.take_outgoing().unwrap();
+        server.receive(&first).unwrap(); // Applied, but the desktop went offline before the ack
+        desktop.local_edit("hello world!").unwrap();
+
+        let other = DeltaMessage {
+            client_id: "other".to_string(),
+            seq: 0,
+            base_revision: 1,
+            paste: false,
+            bulk: None,
+            operations: vec![DiffOperation::Insert(0, "Oh, ".to_string())],
+        };
+        let Ok(Receipt::Applied(_, remote)) = server.receive(&other) else { panic!("Edit not applied") };
+
+        let mut state = EditorState::new();
+        state.insert_text("hello world!");
+        let blob = serialize_session(&state, &mut VersionControl::new(), Some(desktop.journal())).unwrap();
+
+        let restored = hand_off(&blob);
+        let mut web = OptimisticBuffer::from_journal(restored.journal.unwrap());
+        assert_eq!(web.local_text(), restored.state.get_text());
+
+        let Ok(Receipt::Duplicate(ack)) = server.receive(&web.take_outgoing().unwrap()) else { panic!("Retry applied twice") };
+        web.handle_ack(&ack).unwrap();
+        web.handle_remote(&remote);
+        let Ok(Receipt::Applied(ack, _)) = server.receive(&web.take_outgoing().unwrap()) else { panic!("Edit not applied") };
+        web.handle_ack(&ack).unwrap();
+
+        assert_eq!(server.text(), "Oh, hello world!");
+        assert_eq!(web.local_text(), server.text());
+        assert_eq!(web.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_unknown_formats_are_refused() {
+        let mut state = EditorState::new();
+        state.insert_text("text");
+        let mut blob = serialize_session(&state, &mut VersionControl::new(), None).unwrap();
+
+        blob.version = SESSION_FORMAT_VERSION + 1;
+        let error = restore_session(&blob).err().unwrap();
+        assert!(error.contains("newer version of RustPad"), "{}", error);
+
+        blob.version = 0;
+        assert_eq!(restore_session(&blob).err().unwrap(), "Unknown session format 0");
+        assert!(SessionBlob::from_bytes(b"{\"text\":\"\"}").is_err());
+    }
+}
@@ -20,7 +20,7 @@ impl Snippet {
 }
 
 // Store for predefined and user-defined snippets.
-type SnippetStore = Arc<Mutex<HashMap<String, Snippet>>>;
+pub type SnippetStore = Arc<Mutex<HashMap<String, Snippet>>>;
 
 /// Adds a new snippet to the store.
 pub fn add_snippet(store: SnippetStore, snippet: Snippet) -> Result<(), String> {
@@ -94,3 +94,68 @@ pub fn initialize_snippets(store: SnippetStore) {
         snippets.insert(snippet.name.clone(), snippet);
     }
 }
+
+/// Starter content for new files, keyed by extension. Defaults cover Rust, Python and HTML;
+/// users can register their own or replace the defaults.
+#[derive(Clone)]
+pub struct FileTemplateStore {
+    templates: Arc<Mutex<HashMap<String, String>>>, // Keyed by lowercase extension, without the dot
+}
+
+impl FileTemplateStore {
+    /// Creates a store holding the default templates.
+    pub fn new() -> Self {
+        let store = Self { templates: Arc::new(Mutex::new(HashMap::new())) };
+        store.register("rs", "//!\n\n#[cfg(test)]\nmod tests {\n    use super::*;\n}\n");
+        store.register(
+            "py",
+            "#!/usr/bin/env python3\n\n\ndef main():\n    pass\n\n\nif __name__ == \"__main__\":\n    main()\n",
+        );
+        let html = "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n    <meta charset=\"utf-8\">\n    <title></title>\n</head>\n<body>\n</body>\n</html>\n";
+        store.register("html", html);
+        store.register("htm", html);
+        store
+    }
+
+    /// Registers `content` as the template for files ending in `extension`, replacing any existing one.
+    pub fn register(&self, extension: &str, content: &str) {
+        self.templates.lock().unwrap().insert(normalize_extension(extension), content.to_string());
+    }
+
+    /// Removes the template for `extension`, so new files of that kind start empty.
+    pub fn unregister(&self, extension: &str) {
+        self.templates.lock().unwrap().remove(&normalize_extension(extension));
+    }
+
+    /// Content for a new file ending in `extension`: its template, or nothing.
+    pub fn new_file_content(&self, extension: &str) -> String {
+        self.templates.lock().unwrap().get(&normalize_extension(extension)).cloned().unwrap_or_default()
+    }
+}
+
+impl Default for FileTemplateStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn normalize_extension(extension: &str) -> String {
+    extension.trim_start_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_templates_by_extension() {
+        let templates = FileTemplateStore::new();
+        assert!(templates.new_file_content("py").starts_with("#!/usr/bin/env python3\n"));
+        assert_eq!(templates.new_file_content(".HTML"), templates.new_file_content("htm"));
+        assert_eq!(templates.new_file_content("toml"), "");
+
+        templates.register(".toml", "[package]\n");
+        templates.unregister("py");
+        assert_eq!((templates.new_file_content("toml").as_str(), templates.new_file_content("py").as_str()), ("[package]\n", ""));
+    }
+}
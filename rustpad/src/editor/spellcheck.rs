@@ -0,0 +1,247 @@
+use std::collections::HashSet;
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+use crate::editor::comments::{comment_tokens, CommentTokens};
+
+/// Suggestions offered per misspelled word
+pub const MAX_SUGGESTIONS: usize = 5;
+
+/// Suggestions are words at most this many single-letter edits away
+const MAX_EDIT_DISTANCE: usize = 2;
+
+/// Finds misspelled words in prose, and in the comments and strings of code. Words are checked
+/// against a word list, plus the document's own custom dictionary of accepted words.
+#[derive(Debug, Clone)]
+pub struct SpellChecker {
+    words: HashSet<String>,       // Known words, lowercased
+    custom: HashSet<String>,      // Words accepted for this document, lowercased
+    tokens: Option<CommentTokens>, // Comment syntax of the document's language; `None` for prose
+}
+
+impl SpellChecker {
+    /// Creates a checker for prose knowing the words of `word_list`, one per line
+    pub fn new(word_list: &str) -> Self {
+        let words = word_list.lines().map(str::trim).filter(|word| !word.is_empty()).map(str::to_lowercase).collect();
+        Self { words, custom: HashSet::new(), tokens: None }
+    }
+
+    /// Creates a checker from a hunspell `.dic` file: a word count, then one word per line with
+    /// its affix flags after a `/`. Affixes aren't expanded, so inflected forms the dictionary
+    /// only has as flags are reported.
+    pub fn from_hunspell(dic: &str) -> Self {
+        let words: Vec<&str> = dic
+            .lines()
+            .skip(1)
+            .map(|line| line.split('/').next().unwrap_or("").trim())
+            .collect();
+        Self::new(&words.join("\n"))
+    }
+
+    /// Checks documents in `language`, by name or file extension: only comments and strings of
+    /// code, everything in markdown and plain text
+    pub fn with_language(self, language: &str) -> Self {
+        let tokens = match language.to_lowercase().as_str() {
+            "" | "txt" | "text" | "plain" | "markdown" | "md" => None,
+            _ => Some(comment_tokens(language)),
+        };
+        Self { tokens, ..self }
+    }
+
+    /// Accepts the words of the document's custom dictionary
+    pub fn with_custom_words<'a>(mut self, words: impl IntoIterator<Item = &'a str>) -> Self {
+        for word in words {
+            self.add_word(word);
+        }
+        self
+    }
+
+    /// Accepts `word` for this document from now on
+    pub fn add_word(&mut self, word: &str) {
+        self.custom.insert(word.to_lowercase());
+    }
+
+    /// The document's custom dictionary, sorted, to save with it
+    pub fn custom_words(&self) -> Vec<String> {
+        let mut words: Vec<String> = self.custom.iter().cloned().collect();
+        words.sort();
+        words
+    }
+
+    /// Misspelled words in `text`, as byte ranges with up to `MAX_SUGGESTIONS` corrections each.
+    /// Numbers, acronyms and identifiers such as `snake_case` or `camelCase` are skipped.
+    pub fn check(&self, text: &str) -> Vec<(Range<usize>, Vec<String>)> {
+        let regions = match self.tokens {
+            Some(tokens) => comment_and_string_ranges(text, tokens),
+            None => std::iter::once(0..text.len()).collect(),
+        };
+        let mut misspelled = Vec::new();
+        for region in regions {
+            for (offset, word) in text[region.clone()].split_word_bound_indices() {
+                if !is_checked_word(word) || self.is_known(word) {
+                    continue;
+                }
+                let start = region.start + offset;
+                misspelled.push((start..start + word.len(), self.suggestions(word)));
+            }
+        }
+        misspelled
+    }
+
+    /// Known words closest to `word`, nearest first, capitalized like it
+    pub fn suggestions(&self, word: &str) -> Vec<String> {
+        let lower = word.to_lowercase();
+        let mut candidates: Vec<(usize, &String)> = self
+            .words
+            .iter()
+            .chain(&self.custom)
+            .filter(|candidate| candidate.chars().count().abs_diff(lower.chars().count()) <= MAX_EDIT_DISTANCE)
+            .map(|candidate| (edit_distance(&lower, candidate), candidate))
+            .filter(|(distance, _)| *distance <= MAX_EDIT_DISTANCE)
+            .collect();
+        candidates.sort();
+        candidates.dedup_by(|a, b| a.1 == b.1);
+
+        let capitalized = word.chars().next().is_some_and(char::is_uppercase);
+        candidates
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(_, candidate)| match capitalized {
+                true => capitalize(candidate),
+                false => candidate.clone(),
+            })
+            .collect()
+    }
+
+    fn is_known(&self, word: &str) -> bool {
+        let lower = word.to_lowercase();
+        // "writer's" is fine when "writer" is
+        let stem = lower.split(['\'', '’']).next().unwrap_or("");
+        [lower.as_str(), stem].iter().any(|word| self.words.contains(*word) || self.custom.contains(*word))
+    }
+}
+
+/// Whether `word` is prose to check, rather than punctuation, a number, an acronym or an
+/// identifier
+fn is_checked_word(word: &str) -> bool {
+    let mut chars = word.chars();
+    let Some(first) = chars.next() else { return false };
+    let letters = word.chars().filter(|c| c.is_alphabetic()).count();
+    let apostrophes = word.chars().filter(|c| *c == '\'' || *c == '’').count();
+    let all_caps = word.chars().filter(|c| c.is_alphabetic()).all(char::is_uppercase);
+    let inner_capital = chars.any(char::is_uppercase);
+    first.is_alphabetic() && letters + apostrophes == word.chars().count() && letters > 1 && !all_caps && !inner_capital
+}
+
+/// Byte ranges of the comments and double-quoted strings of code, delimiters excluded. Block
+/// comments and strings may span lines; a backslash escapes the next character of a string.
+fn comment_and_string_ranges(text: &str, tokens: CommentTokens) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut at = 0;
+    while at < text.len() {
+        let rest = &text[at..];
+        if let Some(token) = tokens.line.filter(|token| rest.starts_with(*token)) {
+            let start = at + token.len();
+            at = text[start..].find('\n').map_or(text.len(), |end| start + end);
+            ranges.push(start..at);
+        } else if let Some((open, close)) = tokens.block.filter(|(open, _)| rest.starts_with(*open)) {
+            let start = at + open.len();
+            let end = text[start..].find(close).map_or(text.len(), |end| start + end);
+            ranges.push(start..end);
+            at = (end + close.len()).min(text.len());
+        } else if rest.starts_with('"') {
+            let start = at + 1;
+            let mut end = text.len();
+            let mut escaped = false;
+            for (offset, c) in text[start..].char_indices() {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => {
+                        end = start + offset;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            ranges.push(start..end);
+            at = (end + 1).min(text.len());
+        } else {
+            at += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    ranges
+}
+
+/// Levenshtein distance between `a` and `b`, in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars.next().map(|first| first.to_uppercase().chain(chars).collect()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORDS: &str = "the\nquick\nbrown\nfox\njumps\nover\nlazy\ndog\nreturns\nanswer\nwriter\nhello\nworld";
+
+    fn flagged<'a>(text: &'a str, checker: &SpellChecker) -> Vec<&'a str> {
+        checker.check(text).into_iter().map(|(range, _)| &text[range]).collect()
+    }
+
+    #[test]
+    fn test_misspellings_flagged_with_suggestions() {
+        let checker = SpellChecker::new(WORDS);
+        let text = "The quikc brown fox jumps ovr the lazy dog's writer's HTML 42x";
+        let misspelled = checker.check(text);
+        assert_eq!(flagged(text, &checker), vec!["quikc", "ovr"]);
+        assert_eq!(misspelled[0].1, vec!["quick".to_string()]);
+        assert_eq!(misspelled[1].1[0], "over");
+        assert_eq!(checker.suggestions("Wrold"), vec!["World".to_string()]);
+    }
+
+    #[test]
+    fn test_code_checks_only_comments_and_strings() {
+        let checker = SpellChecker::new(WORDS).with_language("rs");
+        let text = "// Returns the ansewr\nfn qwerty(lazyDog: u32) -> &str {\n    /* helo\n       wrold */ \"helo \\\"wrold\\\"\"\n}";
+        assert_eq!(flagged(text, &checker), vec!["ansewr", "helo", "wrold", "helo", "wrold"]);
+
+        let checker = SpellChecker::new(WORDS).with_language("py");
+        assert_eq!(flagged("qwerty = 1  # the ansewr", &checker), vec!["ansewr"]);
+    }
+
+    #[test]
+    fn test_custom_dictionary_suppresses_accepted_words() {
+        let mut checker = SpellChecker::new(WORDS);
+        assert_eq!(flagged("hello Rustpad", &checker), vec!["Rustpad"]);
+
+        checker.add_word("Rustpad");
+        assert!(checker.check("hello Rustpad, hello rustpad").is_empty());
+        assert_eq!(checker.custom_words(), vec!["rustpad".to_string()]);
+        assert_eq!(checker.suggestions("rustpd"), vec!["rustpad".to_string()]);
+
+        // Another document's checker doesn't know it, until it is loaded from that document
+        let other = SpellChecker::new(WORDS);
+        assert!(!other.check("rustpad").is_empty());
+        assert!(other.with_custom_words(["rustpad"]).check("rustpad").is_empty());
+    }
+
+    #[test]
+    fn test_hunspell_dictionary() {
+        let checker = SpellChecker::from_hunspell("3\nhello\nworld/MS\ncolour/S\n");
+        assert_eq!(flagged("Hello world, what colour", &checker), vec!["what"]);
+    }
+}
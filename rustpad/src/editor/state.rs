@@ -1,56 +1,704 @@
+use crate::editor::comments::comment_tokens;
+use crate::editor::config::{CleanupOptions, IndentStyle};
+use crate::editor::diff_engine::DiffOperation;
+use crate::editor::find::{FindQuery, Finder};
+use crate::editor::syntax_highlighting::HighlightedRegion;
+use crate::editor::typing_rules::TypingRules;
+use crate::sessions::UserSettings;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Most cursors an editor keeps at once, including the primary one.
+pub const MAX_CURSORS: usize = 1000;
+
+/// A cursor and its optional selection, used for the secondary cursors of multi-cursor editing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub position: usize,
+    pub selection: Option<(usize, usize)>,
+}
+
+/// Shape of the selection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelectionMode {
+    Linear,
+    /// A rectangle of lines and columns (in graphemes), kept as one cursor per line it reaches.
+    Block { start_line: usize, start_column: usize, end_line: usize, end_column: usize },
+}
+
 #[derive(Clone)]
 pub struct EditorState {
-    text: String,            // The content of the document
+    text: Rope,              // The content of the document; clones share it until one of them changes
     cursor_position: usize,   // The current cursor position (character index)
     selection_start: Option<usize>, // Optional start of text selection
     selection_end: Option<usize>,   // Optional end of text selection
+    secondary_cursors: Vec<Cursor>, // Extra cursors, sorted by position, never on the primary cursor
+    last_edit: Vec<DiffOperation>,  // Operations of the most recent local edit, for collaborators
+    clipboard: String,              // Text of the last copy or cut
+    clipboard_linewise: bool,       // Whether the clipboard holds whole lines, copied without a selection
+    goal_column: Option<usize>,     // Column the primary cursor returns to on vertical moves, in graphemes
+    indent_style: IndentStyle,      // What Tab inserts
+    folds: Vec<(usize, usize)>,     // Collapsed fold regions, sorted
+    selection_mode: SelectionMode,  // Whether the cursors form a block selection
+    non_code_ranges: Vec<(usize, usize)>, // Strings and comments found by the last highlight, sorted
+    highlighted_lines: Vec<Vec<HighlightedRegion>>, // Styled regions of each line from the last highlight
+    cleanup_options: CleanupOptions, // What `cleanup` does
+    typing_rules: Option<TypingRules>, // On-type formatting for the document's language
 }
 
 impl EditorState {
     /// Creates a new instance of `EditorState` with an empty document.
     pub fn new() -> Self {
         Self {
-            text: String::new(),
+            text: Rope::new(),
             cursor_position: 0,
             selection_start: None,
             selection_end: None,
+            secondary_cursors: Vec::new(),
+            last_edit: Vec::new(),
+            clipboard: String::new(),
+            clipboard_linewise: false,
+            goal_column: None,
+            indent_style: IndentStyle::Tabs,
+            folds: Vec::new(),
+            selection_mode: SelectionMode::Linear,
+            non_code_ranges: Vec::new(),
+            highlighted_lines: Vec::new(),
+            cleanup_options: CleanupOptions::default(),
+            typing_rules: None,
         }
     }
 
-    /// Returns the entire document text.
-    pub fn get_text(&self) -> &str {
-        &self.text
+    /// Returns the entire document text. Only a document small enough for one rope chunk is
+    /// borrowed; anything larger is copied, so hot paths should prefer `line` and `lines`.
+    pub fn get_text(&self) -> Cow<'_, str> {
+        self.text.slice(..).into()
+    }
+
+    /// Length of the document in bytes.
+    pub fn text_len(&self) -> usize {
+        self.text.len_bytes()
+    }
+
+    /// Number of lines, counting the empty one after a final newline.
+    pub fn line_count(&self) -> usize {
+        self.text.len_lines()
+    }
+
+    /// Text of line `index`, without its line ending.
+    pub fn line(&self, index: usize) -> Cow<'_, str> {
+        without_line_ending(self.text.line(index))
+    }
+
+    /// Text of every line without its line ending, like `str::lines`: a final newline doesn't
+    /// start another line.
+    pub fn lines(&self) -> impl Iterator<Item = Cow<'_, str>> + '_ {
+        let ends_with_newline = self.text.len_bytes() == 0 || self.text.byte(self.text.len_bytes() - 1) == b'\n';
+        self.text.lines().take(self.text.len_lines() - ends_with_newline as usize).map(without_line_ending)
+    }
+
+    /// Byte ranges of every match of `query`, for find to highlight and step through.
+    /// Invalid and oversized patterns are refused.
+    pub fn find_all(&self, query: &FindQuery) -> Result<Vec<(usize, usize)>, String> {
+        let finder = Finder::new(query)?;
+        let text = self.get_text();
+        Ok(finder.find_iter(&text).collect())
+    }
+
+    /// Line a byte offset is on.
+    pub fn byte_to_line(&self, position: usize) -> usize {
+        self.text.byte_to_line(position)
+    }
+
+    /// Byte offset where line `index` starts.
+    pub fn line_to_byte(&self, index: usize) -> usize {
+        self.text.line_to_byte(index)
+    }
+
+    /// Character index of a byte offset.
+    pub fn byte_to_char(&self, position: usize) -> usize {
+        self.text.byte_to_char(position)
+    }
+
+    /// Byte offset of a character index.
+    pub fn char_to_byte(&self, index: usize) -> usize {
+        self.text.char_to_byte(index)
     }
 
-    /// Inserts text at the current cursor position, updating the cursor position accordingly.
+    /// Inserts text at every cursor, moving each cursor past its inserted text.
+    /// A lone tab indents according to the indentation style instead. In a block selection,
+    /// single-line text goes in at the block's left column on every line and the block moves along.
+    /// Typing rules may reindent the line as part of the same edit, except in strings and comments.
     pub fn insert_text(&mut self, text: &str) {
-        self.text.insert_str(self.cursor_position, text);
-        self.cursor_position += text.len();  // Move the cursor forward by the length of the inserted text
+        if let SelectionMode::Block { start_line, start_column, end_line, end_column } = self.selection_mode {
+            if !text.contains('\n') {
+                self.edit_at_cursors(|_, cursor| {
+                    let at = cursor.selection.map_or(cursor.position, |(start, end)| start.min(end));
+                    Some((at, at, text.to_string()))
+                });
+                let width = text.graphemes(true).count();
+                self.set_block_selection(start_line, start_column + width, end_line, end_column + width);
+                return;
+            }
+        }
+        if text == "\t" {
+            self.indent();
+            return;
+        }
+        let (rules, indent_style) = (self.typing_rules, self.indent_style);
+        let non_code = std::mem::take(&mut self.non_code_ranges);
+        // Typing rules look around the cursor in a flat string; without them the rope is left alone
+        let document = rules.map(|_| self.get_text().into_owned());
+        self.edit_at_cursors(|_, cursor| {
+            let rule = rules
+                .zip(document.as_deref())
+                .filter(|_| !in_ranges(&non_code, cursor.position))
+                .and_then(|(rules, document)| rules.on_type(document, cursor.position, text, indent_style));
+            Some(rule.unwrap_or_else(|| (cursor.position, cursor.position, text.to_string())))
+        });
+        self.non_code_ranges = non_code;
+    }
+
+    /// Types `ch`, closing brackets and quotes automatically: an opening one is inserted with its
+    /// partner and the cursors between them, and typing a closing one that is already next to
+    /// every cursor just moves past it. With selections, each selection is wrapped in the pair
+    /// instead. Nothing is paired inside strings or comments reported by the highlighter.
+    pub fn insert_with_autopair(&mut self, ch: char) {
+        let cursors = self.cursors();
+        let partner = closing_partner(ch);
+        if let Some(close) = partner.filter(|_| cursors.iter().any(has_selection)) {
+            self.wrap_selections(ch, close);
+            return;
+        }
+
+        let is_closing = matches!(ch, ')' | ']' | '}') || partner == Some(ch);
+        if is_closing && cursors.iter().all(|cursor| char_at(&self.text, cursor.position) == Some(ch)) {
+            self.last_edit = Vec::new();
+            self.move_cursors(|_, position| position + ch.len_utf8());
+            return;
+        }
+
+        let pairs = match partner {
+            Some(_) if matches!(self.selection_mode, SelectionMode::Block { .. }) => false,
+            Some(close) if close == ch => cursors.iter().all(|cursor| self.pairs_quote_at(cursor.position)),
+            Some(_) => cursors.iter().all(|cursor| !self.in_non_code(cursor.position)),
+            None => false,
+        };
+        match partner {
+            Some(close) if pairs => {
+                self.edit_at_cursors(|_, cursor| Some((cursor.position, cursor.position, format!("{}{}", ch, close))));
+                self.move_cursors(|_, position| position - close.len_utf8());
+            }
+            _ => self.insert_text(&ch.to_string()),
+        }
+    }
+
+    /// Sets the byte ranges of string literals and comments, for `insert_with_autopair`. The
+    /// highlighter reports them after each change; a line comment's range includes its newline.
+    pub fn set_non_code_ranges(&mut self, mut ranges: Vec<(usize, usize)>) {
+        ranges.sort();
+        self.non_code_ranges = ranges;
+    }
+
+    /// Drops the styled regions of the last highlight, before highlighting again.
+    pub fn clear_highlight(&mut self) {
+        self.highlighted_lines.clear();
+    }
+
+    /// Sets the styled regions of line `line_index`, by byte offsets within the line.
+    pub fn add_highlighted_line(&mut self, line_index: usize, regions: Vec<HighlightedRegion>) {
+        if self.highlighted_lines.len() <= line_index {
+            self.highlighted_lines.resize(line_index + 1, Vec::new());
+        }
+        self.highlighted_lines[line_index] = regions;
+    }
+
+    /// Styled regions of line `line_index` from the last highlight, sorted; none for lines it
+    /// didn't reach.
+    pub fn get_highlighted_regions_for_line(&self, line_index: usize) -> Vec<HighlightedRegion> {
+        self.highlighted_lines.get(line_index).cloned().unwrap_or_default()
     }
 
     /// Deletes text between the given start and end positions. Updates the cursor position.
+    /// Secondary cursors after the range move back with the text.
     pub fn delete_text(&mut self, start: usize, end: usize) {
-        if start < end && end <= self.text.len() {
-            self.text.replace_range(start..end, "");  // Remove text between start and end
+        if start < end && end <= self.text.len_bytes() {
+            self.splice(start, end, "");  // Remove text between start and end
             self.cursor_position = start;  // Set the cursor to the start of the deleted range
+            self.last_edit = vec![DiffOperation::Delete(start, end)];
+            self.goal_column = None;
+            self.map_secondary_cursors(&[(start, end, 0)]);
+        }
+    }
+
+    /// Deletes the selected text of every cursor and clears the selections.
+    /// A block selection loses its rectangle and stays as a zero-width block at its left column.
+    pub fn delete_selected_text(&mut self) {
+        let mode = self.selection_mode;
+        self.edit_at_cursors(|_, cursor| cursor.selection.map(|(start, end)| (start.min(end), start.max(end), String::new())));
+        self.clear_all_selections();
+        if let SelectionMode::Block { start_line, start_column, end_line, end_column } = mode {
+            let left = start_column.min(end_column);
+            self.set_block_selection(start_line, left, end_line, left);
+        }
+    }
+
+    /// Starts a new line at every cursor, indented like the line the cursor was on. Typing rules
+    /// may adjust the indentation, continue a list or trim the line left behind, as part of the
+    /// same edit, except in strings and comments.
+    pub fn insert_newline(&mut self) {
+        let (rules, indent_style) = (self.typing_rules, self.indent_style);
+        let non_code = std::mem::take(&mut self.non_code_ranges);
+        let document = rules.map(|_| self.get_text().into_owned());
+        self.edit_at_cursors(|text, cursor| {
+            if let Some((rules, document)) = rules.zip(document.as_deref()).filter(|_| !in_ranges(&non_code, cursor.position)) {
+                return Some(rules.on_newline(document, cursor.position, indent_style));
+            }
+            let line_start = start_of_line(text, cursor.position);
+            let indent: String = text.byte_slice(line_start..cursor.position).chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+            Some((cursor.position, cursor.position, format!("\n{}", indent)))
+        });
+        self.non_code_ranges = non_code;
+    }
+
+    /// Backspace: deletes each cursor's selection, or the grapheme before it. At the start of a
+    /// line this joins it with the previous line.
+    pub fn delete_character_before_cursor(&mut self) {
+        self.edit_at_cursors(|text, cursor| match cursor.selection {
+            Some((start, end)) if start != end => Some((start.min(end), start.max(end), String::new())),
+            _ => previous_boundary(text, cursor.position).map(|start| (start, cursor.position, String::new())),
+        });
+        self.clear_all_selections();
+    }
+
+    /// Delete: deletes each cursor's selection, or the grapheme after it.
+    pub fn delete_character_at_cursor(&mut self) {
+        self.edit_at_cursors(|text, cursor| match cursor.selection {
+            Some((start, end)) if start != end => Some((start.min(end), start.max(end), String::new())),
+            _ => next_boundary(text, cursor.position).map(|end| (cursor.position, end, String::new())),
+        });
+        self.clear_all_selections();
+    }
+
+    /// Moves every cursor one grapheme to the left.
+    pub fn move_cursor_left(&mut self) {
+        self.move_cursors(|text, position| previous_boundary(text, position).unwrap_or(position));
+    }
+
+    /// Moves every cursor one grapheme to the right.
+    pub fn move_cursor_right(&mut self) {
+        self.move_cursors(|text, position| next_boundary(text, position).unwrap_or(position));
+    }
+
+    /// Moves every cursor to the same column on the previous line, or to the start of the document.
+    /// The primary cursor aims for its goal column, so passing a short line doesn't lose it.
+    pub fn move_cursor_up(&mut self) {
+        self.move_vertically(|text, line, column| if line == 0 { 0 } else { offset_at(text, line - 1, column) });
+    }
+
+    /// Moves every cursor to the same column on the next line, or to the end of the document.
+    /// The primary cursor aims for its goal column, so passing a short line doesn't lose it.
+    pub fn move_cursor_down(&mut self) {
+        self.move_vertically(|text, line, column| {
+            if line + 1 == text.len_lines() { text.len_bytes() } else { offset_at(text, line + 1, column) }
+        });
+    }
+
+    /// Copies the selected text of every cursor, joined by newlines, to the clipboard and returns it.
+    /// Without any selection, the whole lines the cursors are on are copied instead.
+    pub fn copy_selected_text(&mut self) -> String {
+        let linewise = self.cursors().iter().all(|cursor| !has_selection(cursor));
+        let mut ranges: Vec<(usize, usize)> = self
+            .cursors()
+            .iter()
+            .filter_map(|cursor| clipboard_range(&self.text, cursor, linewise))
+            .collect();
+        ranges.sort();
+        ranges.dedup();
+
+        let mut copied: Vec<String> = ranges.iter().map(|(start, end)| self.slice(*start, *end).into_owned()).collect();
+        if linewise {
+            for line in &mut copied {
+                if !line.ends_with('\n') {
+                    line.push('\n');
+                }
+            }
+        }
+        self.clipboard = copied.join(if linewise { "" } else { "\n" });
+        self.clipboard_linewise = linewise;
+        self.clipboard.clone()
+    }
+
+    /// Copies like `copy_selected_text`, then deletes what was copied.
+    pub fn cut_selected_text(&mut self) -> String {
+        let copied = self.copy_selected_text();
+        let linewise = self.clipboard_linewise;
+        self.edit_at_cursors(|text, cursor| {
+            let (start, end) = clipboard_range(text, cursor, linewise)?;
+            // Cutting the last line takes the newline before it, so no empty line is left behind
+            let ends_with_newline = end > start && text.byte(end - 1) == b'\n';
+            let start = if linewise && !ends_with_newline { start.saturating_sub(1) } else { start };
+            Some((start, end, String::new()))
+        });
+        self.clear_all_selections();
+        copied
+    }
+
+    /// Pastes `text` at every cursor, replacing any selection. Whole lines from the clipboard
+    /// are pasted above the cursor's line instead.
+    pub fn paste_text(&mut self, text: &str) {
+        let linewise = self.clipboard_linewise && text == self.clipboard;
+        self.edit_at_cursors(|document, cursor| match cursor.selection {
+            Some((start, end)) if start != end => Some((start.min(end), start.max(end), text.to_string())),
+            _ if linewise => {
+                let line_start = start_of_line(document, cursor.position);
+                Some((line_start, line_start, text.to_string()))
+            }
+            _ => Some((cursor.position, cursor.position, text.to_string())),
+        });
+        self.clear_all_selections();
+    }
+
+    /// Pastes the clipboard at every cursor.
+    pub fn paste(&mut self) {
+        let text = self.clipboard.clone();
+        self.paste_text(&text);
+    }
+
+    /// Returns the text of the last copy or cut.
+    pub fn clipboard(&self) -> &str {
+        &self.clipboard
+    }
+
+    /// Duplicates the current line, or every line the selection touches, below itself and moves
+    /// the cursor and selection onto the copy. Secondary cursors are dropped.
+    pub fn duplicate_lines(&mut self) {
+        let (start, end) = self.line_block();
+        let copy = format!("\n{}", self.slice(start, end));
+        let shift = copy.len() as isize;
+        self.apply_line_command(vec![(end, end, copy)], shift);
+    }
+
+    /// Swaps the lines the cursor and selection touch with the line above them, keeping the
+    /// cursor and selection on the moved text. Does nothing on the first line.
+    pub fn move_lines_up(&mut self) {
+        let (start, end) = self.line_block();
+        if start == 0 {
+            return;
+        }
+        let above_start = start_of_line(&self.text, start - 1);
+        let above = self.slice(above_start, start - 1);
+        let moved = format!("{}\n{}", self.slice(start, end), above);
+        let shift = -(above.len() as isize + 1);
+        self.apply_line_command(vec![(above_start, end, moved)], shift);
+    }
+
+    /// Swaps the lines the cursor and selection touch with the line below them, keeping the
+    /// cursor and selection on the moved text. Does nothing on the last line.
+    pub fn move_lines_down(&mut self) {
+        let (start, end) = self.line_block();
+        if end == self.text.len_bytes() {
+            return;
+        }
+        let below_end = end_of_line(&self.text, end + 1);
+        let below = self.slice(end + 1, below_end);
+        let moved = format!("{}\n{}", below, self.slice(start, end));
+        let shift = below.len() as isize + 1;
+        self.apply_line_command(vec![(start, below_end, moved)], shift);
+    }
+
+    /// Comments out the lines the cursor and selection touch, or uncomments them when every
+    /// non-blank one is already commented. The token goes after the lines' common indentation.
+    /// Languages without line comments get a block comment instead.
+    pub fn toggle_line_comment(&mut self, language: &str) {
+        let Some(token) = comment_tokens(language).line else {
+            self.toggle_block_comment(language);
+            return;
+        };
+        let (start, end) = self.line_block();
+        let mut lines = Vec::new(); // (line start, indentation length) of each non-blank line
+        let mut line_start = start;
+        for line in self.slice(start, end).split('\n') {
+            let indent = line.len() - line.trim_start().len();
+            if indent < line.len() {
+                lines.push((line_start, indent));
+            }
+            line_start += line.len() + 1;
         }
+
+        let commented = |&(line_start, indent): &(usize, usize)| self.has_at(line_start + indent, token);
+        let edits = if lines.iter().all(commented) {
+            lines
+                .iter()
+                .map(|&(line_start, indent)| {
+                    let token_start = line_start + indent;
+                    let spaced = self.has_at(token_start + token.len(), " ");
+                    (token_start, token_start + token.len() + spaced as usize, String::new())
+                })
+                .collect()
+        } else {
+            let indent = lines.iter().map(|(_, indent)| *indent).min().unwrap_or(0);
+            lines.iter().map(|&(line_start, _)| (line_start + indent, line_start + indent, format!("{} ", token))).collect()
+        };
+        self.apply_line_command(edits, 0);
+    }
+
+    /// Wraps the selection, or the current lines without their indentation, in a block comment,
+    /// or unwraps it when it already is one. Languages without block comments get line comments instead.
+    pub fn toggle_block_comment(&mut self, language: &str) {
+        let Some((open, close)) = comment_tokens(language).block else {
+            self.toggle_line_comment(language);
+            return;
+        };
+        let (start, end) = match self.get_selection_range() {
+            Some((start, end)) if start != end => (start.min(end), start.max(end)),
+            _ => self.line_block(),
+        };
+        let selected = self.slice(start, end);
+        let start = start + selected.len() - selected.trim_start().len();
+        let end = (end - (selected.len() - selected.trim_end().len())).max(start);
+        let inner = self.slice(start, end);
+
+        let edits = if inner.len() >= open.len() + close.len() && inner.starts_with(open) && inner.ends_with(close) {
+            let open_end = start + open.len() + inner[open.len()..].starts_with(' ') as usize;
+            let close_start = end - close.len() - inner[..inner.len() - close.len()].ends_with(' ') as usize;
+            vec![(start, open_end, String::new()), (close_start.max(open_end), end, String::new())]
+        } else {
+            vec![(start, start, format!("{} ", open)), (end, end, format!(" {}", close))]
+        };
+        self.apply_line_command(edits, 0);
+    }
+
+    /// Sets what Tab inserts.
+    pub fn set_indent_style(&mut self, indent_style: IndentStyle) {
+        self.indent_style = indent_style;
+    }
+
+    /// Applies the indentation of the settings the server sent for this user's session.
+    pub fn apply_settings(&mut self, settings: &UserSettings) {
+        self.indent_style = if settings.indent_with_spaces { IndentStyle::Spaces(settings.tab_width) } else { IndentStyle::Tabs };
+    }
+
+    /// Returns what Tab inserts.
+    pub fn indent_style(&self) -> IndentStyle {
+        self.indent_style
+    }
+
+    /// Changes what `cleanup` does.
+    pub fn set_cleanup_options(&mut self, cleanup_options: CleanupOptions) {
+        self.cleanup_options = cleanup_options;
+    }
+
+    /// Returns what `cleanup` does.
+    pub fn cleanup_options(&self) -> CleanupOptions {
+        self.cleanup_options
+    }
+
+    /// Sets the on-type formatting rules applied when typing and pressing Enter; `None` turns them off.
+    pub fn set_typing_rules(&mut self, typing_rules: Option<TypingRules>) {
+        self.typing_rules = typing_rules;
+    }
+
+    /// Strips trailing spaces and tabs from every line and ends the document with exactly one
+    /// newline, as the cleanup options allow, in one undoable edit. Cursors and selections
+    /// follow the text, so they stay valid.
+    pub fn cleanup(&mut self) {
+        let CleanupOptions { trim_trailing_whitespace, ensure_final_newline } = self.cleanup_options;
+        let text = self.get_text();
+        let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+        let body_end = if trim_trailing_whitespace {
+            text.trim_end_matches([' ', '\t', '\r', '\n']).len()
+        } else {
+            text.trim_end_matches(['\r', '\n']).len()
+        };
+        let tail_start = if ensure_final_newline { body_end } else { text.len() };
+
+        let mut edits = Vec::new();
+        if trim_trailing_whitespace {
+            let mut line_start = 0;
+            for line in text.split('\n') {
+                let content = line.strip_suffix('\r').unwrap_or(line);
+                let (start, end) = (line_start + content.trim_end_matches([' ', '\t']).len(), line_start + content.len());
+                if start < end && end <= tail_start {
+                    edits.push((start, end, String::new()));
+                }
+                line_start += line.len() + 1;
+            }
+        }
+        if ensure_final_newline && body_end > 0 && &text[body_end..] != newline {
+            edits.push((body_end, text.len(), newline.to_string()));
+        }
+        self.apply_line_command(edits, 0);
+    }
+
+    /// Tab: indents every cursor to the next tab stop, replacing its selection. When a selection
+    /// spans lines, the non-blank lines of every cursor are indented by a full level instead.
+    pub fn indent(&mut self) {
+        let style = self.indent_style;
+        let multi_line = self.cursors().iter().any(|cursor| {
+            cursor.selection.is_some_and(|(start, end)| self.slice(start.min(end), start.max(end)).contains('\n'))
+        });
+        if multi_line {
+            let edits = self
+                .cursor_line_starts()
+                .into_iter()
+                .filter(|&line_start| !matches!(char_at(&self.text, line_start), None | Some('\n')))
+                .map(|line_start| (line_start, line_start, style.to_next_stop(0)))
+                .collect();
+            self.apply_line_command(edits, 0);
+            return;
+        }
+
+        self.edit_at_cursors(|text, cursor| {
+            let (start, end) = cursor.selection.map_or((cursor.position, cursor.position), |(a, b)| (a.min(b), a.max(b)));
+            let line_start = start_of_line(text, start);
+            let column = Cow::from(text.byte_slice(line_start..start)).graphemes(true).fold(0, |column, grapheme| {
+                if grapheme == "\t" { (column / style.width() + 1) * style.width() } else { column + 1 }
+            });
+            Some((start, end, style.to_next_stop(column)))
+        });
+        self.clear_all_selections();
+    }
+
+    /// Shift+Tab: removes one level of indentation from the lines of every cursor: a leading tab,
+    /// or the spaces back to the previous tab stop.
+    pub fn dedent(&mut self) {
+        let width = self.indent_style.width();
+        let edits = self
+            .cursor_line_starts()
+            .into_iter()
+            .filter_map(|line_start| {
+                let line = self.slice(line_start, end_of_line(&self.text, line_start));
+                if line.starts_with('\t') {
+                    return Some((line_start, line_start + 1, String::new()));
+                }
+                let spaces = line.len() - line.trim_start_matches(' ').len();
+                let remove = match spaces % width {
+                    0 => width.min(spaces),
+                    partial => partial, // Back to the previous tab stop
+                };
+                (remove > 0).then(|| (line_start, line_start + remove, String::new()))
+            })
+            .collect();
+        self.apply_line_command(edits, 0);
+    }
+
+    /// Regions that can be folded, as 0-based (header line, last line) pairs sorted by header.
+    /// The header stays visible when folded. Regions come from brackets spanning lines, or for
+    /// headers without a bracket, from the more indented lines that follow them.
+    pub fn fold_regions(&self) -> Vec<(usize, usize)> {
+        let text = self.get_text();
+        let mut regions = bracket_regions(&text);
+        let lines: Vec<&str> = text.split('\n').collect();
+        let indent = |line: &str| (!line.trim().is_empty()).then(|| line.len() - line.trim_start().len());
+
+        for (header, line) in lines.iter().enumerate() {
+            let Some(header_indent) = indent(line) else { continue };
+            if regions.iter().any(|(start, _)| *start == header) {
+                continue;
+            }
+            let last = lines[header + 1..]
+                .iter()
+                .map(|line| indent(line))
+                .take_while(|line_indent| line_indent.is_none_or(|line_indent| line_indent > header_indent))
+                .enumerate()
+                .filter(|(_, line_indent)| line_indent.is_some())
+                .last()
+                .map(|(offset, _)| header + 1 + offset);
+            if let Some(last) = last {
+                regions.push((header, last));
+            }
+        }
+        regions.sort();
+        regions
+    }
+
+    /// Collapses `region`, which must be one of `fold_regions`.
+    pub fn fold(&mut self, region: (usize, usize)) -> Result<(), String> {
+        if !self.fold_regions().contains(&region) {
+            return Err(format!("Lines {}-{} are not a foldable region", region.0, region.1));
+        }
+        if let Err(index) = self.folds.binary_search(&region) {
+            self.folds.insert(index, region);
+        }
+        Ok(())
+    }
+
+    /// Expands `region` if it is folded.
+    pub fn unfold(&mut self, region: (usize, usize)) {
+        self.folds.retain(|fold| *fold != region);
+    }
+
+    /// Collapsed regions that still match the document's structure. Edits that change which
+    /// lines a region covers unfold it rather than hide the wrong lines.
+    pub fn folded_regions(&self) -> Vec<(usize, usize)> {
+        let regions = self.fold_regions();
+        self.folds.iter().filter(|fold| regions.contains(fold)).copied().collect()
+    }
+
+    /// Whether `line` is hidden inside a folded region, for the renderer to skip.
+    pub fn is_line_hidden(&self, line: usize) -> bool {
+        self.folded_regions().iter().any(|(header, last)| *header < line && line <= *last)
     }
 
     /// Moves the cursor based on input command or direct position.
     pub fn move_cursor(&mut self, position: usize) {
-        self.cursor_position = position.min(self.text.len());
+        self.cursor_position = position.min(self.text.len_bytes());
+        self.goal_column = None;
+        self.selection_mode = SelectionMode::Linear;
+        self.merge_cursors();
     }
 
     /// Selects text between the start and end positions.
     pub fn set_selection(&mut self, start: usize, end: usize) {
-        self.selection_start = Some(start.min(self.text.len()));
-        self.selection_end = Some(end.min(self.text.len()));
+        self.selection_start = Some(start.min(self.text.len_bytes()));
+        self.selection_end = Some(end.min(self.text.len_bytes()));
+        self.selection_mode = SelectionMode::Linear;
     }
 
     /// Clears the current text selection.
     pub fn clear_selection(&mut self) {
         self.selection_start = None;
         self.selection_end = None;
+        self.selection_mode = SelectionMode::Linear;
+    }
+
+    /// Selects the rectangle from `start_line`/`start_column` to `end_line`/`end_column`, columns in
+    /// graphemes. Every line that reaches the left column gets a cursor selecting its part of the
+    /// rectangle; the cursor on the end line is the primary one.
+    pub fn set_block_selection(&mut self, start_line: usize, start_column: usize, end_line: usize, end_column: usize) {
+        let last_line = self.text.len_lines() - 1;
+        let (start_line, end_line) = (start_line.min(last_line), end_line.min(last_line));
+        let left = start_column.min(end_column);
+
+        let mut cursors: Vec<(usize, Cursor)> = (start_line.min(end_line)..=start_line.max(end_line))
+            .filter(|line| line_and_column(&self.text, offset_at(&self.text, *line, left)).1 == left)
+            .map(|line| {
+                let start = offset_at(&self.text, line, start_column);
+                let end = offset_at(&self.text, line, end_column);
+                let selection = if start == end { None } else { Some((start, end)) };
+                (line, Cursor { position: end, selection })
+            })
+            .take(MAX_CURSORS)
+            .collect();
+        if cursors.is_empty() {
+            cursors.push((end_line, Cursor { position: offset_at(&self.text, end_line, end_column), selection: None }));
+        }
+
+        let primary_index = cursors.iter().position(|(line, _)| *line == end_line).unwrap_or(cursors.len() - 1);
+        let (_, primary) = cursors.remove(primary_index);
+        self.set_primary(primary);
+        self.secondary_cursors = cursors.into_iter().map(|(_, cursor)| cursor).collect();
+        self.merge_cursors();
+        self.goal_column = None;
+        self.selection_mode = SelectionMode::Block { start_line, start_column, end_line, end_column };
+    }
+
+    /// Returns whether the selection is linear or a block, and the block's rectangle.
+    pub fn selection_mode(&self) -> SelectionMode {
+        self.selection_mode
     }
 
     /// Returns the current cursor position.
@@ -69,16 +717,1120 @@ impl EditorState {
 
     /// Replaces the entire document text with new content.
     pub fn replace_text(&mut self, new_text: String) {
-        self.text = new_text;
-        self.cursor_position = self.text.len();  // Set the cursor at the end of the new text
+        self.text = Rope::from(new_text);
+        self.cursor_position = self.text.len_bytes();  // Set the cursor at the end of the new text
         self.clear_selection();  // Clear selection since the document has changed
+        self.secondary_cursors.clear();
+        self.goal_column = None;
     }
 
     /// Applies a synchronization update by replacing a section of the text.
     /// This is used for real-time collaboration to update the editor's state with incoming changes.
     pub fn apply_sync(&mut self, start: usize, end: usize, new_text: &str) {
-        self.text.replace_range(start..end, new_text);
+        self.splice(start, end, new_text);
         self.cursor_position = start + new_text.len();  // Adjust the cursor after the synced change
+        self.map_secondary_cursors(&[(start, end, new_text.len())]);
+    }
+
+    /// Applies an edit a collaborator made, replacing `start..end` with `new_text`. Unlike
+    /// `apply_sync`, every cursor and selection stays on the text it was on: edits before a
+    /// cursor shift it, and an insertion right at a cursor goes in after it.
+    pub fn apply_remote(&mut self, start: usize, end: usize, new_text: &str) {
+        let end = end.min(self.text.len_bytes());
+        let start = start.min(end);
+        self.splice(start, end, new_text);
+        let shifts = [(start, end, new_text.len())];
+        self.cursor_position = map_position(self.cursor_position, &shifts);
+        self.selection_start = self.selection_start.map(|position| map_position(position, &shifts));
+        self.selection_end = self.selection_end.map(|position| map_position(position, &shifts));
+        self.map_secondary_cursors(&shifts);
+    }
+
+    /// Operations of the most recent local edit across all cursors, in the order they apply.
+    /// Sent to collaborators as one batched delta.
+    pub fn last_edit(&self) -> &[DiffOperation] {
+        &self.last_edit
+    }
+
+    /// Returns the primary cursor followed by the secondary cursors.
+    pub fn cursors(&self) -> Vec<Cursor> {
+        let primary = Cursor {
+            position: self.cursor_position,
+            selection: self.get_selection_range(),
+        };
+        std::iter::once(primary).chain(self.secondary_cursors.iter().cloned()).collect()
+    }
+
+    /// Returns the secondary cursors, sorted by position.
+    pub fn secondary_cursors(&self) -> &[Cursor] {
+        &self.secondary_cursors
+    }
+
+    /// Replaces the secondary cursors, e.g. when restoring a saved state.
+    pub fn set_secondary_cursors(&mut self, cursors: Vec<Cursor>) {
+        let len = self.text.len_bytes();
+        self.secondary_cursors = cursors
+            .into_iter()
+            .map(|cursor| Cursor {
+                position: cursor.position.min(len),
+                selection: cursor.selection.map(|(start, end)| (start.min(len), end.min(len))),
+            })
+            .collect();
+        self.merge_cursors();
+    }
+
+    /// Adds a secondary cursor at `position`. Does nothing if a cursor is already there.
+    pub fn add_cursor_at(&mut self, position: usize) {
+        self.secondary_cursors.push(Cursor {
+            position: position.min(self.text.len_bytes()),
+            selection: None,
+        });
+        self.merge_cursors();
+    }
+
+    /// Selects every occurrence of `query` with its own cursor, up to `MAX_CURSORS`. The first
+    /// occurrence becomes the primary cursor. Returns the number of cursors placed.
+    pub fn add_cursors_for_matches(&mut self, query: &str) -> usize {
+        if query.is_empty() {
+            return 0;
+        }
+        let text = self.get_text();
+        let mut matches: Vec<Cursor> = text
+            .match_indices(query)
+            .take(MAX_CURSORS)
+            .map(|(start, found)| Cursor {
+                position: start + found.len(),
+                selection: Some((start, start + found.len())),
+            })
+            .collect();
+        if matches.is_empty() {
+            return 0;
+        }
+
+        let first = matches.remove(0);
+        self.set_primary(first);
+        self.secondary_cursors = matches;
+        self.merge_cursors();
+        self.secondary_cursors.len() + 1
+    }
+
+    /// Places one cursor on every line between `anchor` and `head`, each selecting the same
+    /// columns on its line. Short lines are clamped to their end. The cursor on the head's line
+    /// becomes the primary cursor.
+    pub fn column_select(&mut self, anchor: usize, head: usize) {
+        let (anchor_line, anchor_column) = line_and_column(&self.text, anchor.min(self.text.len_bytes()));
+        let (head_line, head_column) = line_and_column(&self.text, head.min(self.text.len_bytes()));
+
+        let mut cursors: Vec<Cursor> = (anchor_line.min(head_line)..=anchor_line.max(head_line))
+            .map(|line| {
+                let start = offset_at(&self.text, line, anchor_column);
+                let end = offset_at(&self.text, line, head_column);
+                Cursor {
+                    position: end,
+                    selection: if start == end { None } else { Some((start, end)) },
+                }
+            })
+            .take(MAX_CURSORS)
+            .collect();
+
+        let primary_index = if head_line >= anchor_line { cursors.len() - 1 } else { 0 };
+        let primary = cursors.remove(primary_index);
+        self.set_primary(primary);
+        self.secondary_cursors = cursors;
+        self.merge_cursors();
+    }
+
+    /// Removes every cursor except the primary one.
+    pub fn clear_secondary_cursors(&mut self) {
+        self.secondary_cursors.clear();
+    }
+
+    /// Replaces a range at each cursor, given by `edit` as (start, end, replacement), or leaves
+    /// the cursor's text alone when `edit` returns `None`. Ranges are applied from the back of the
+    /// document so earlier edits don't shift later ones; overlapping ranges merge, as do cursors
+    /// that end up in the same place.
+    fn edit_at_cursors(&mut self, edit: impl Fn(&Rope, &Cursor) -> Option<(usize, usize, String)>) {
+        let len = self.text.len_bytes();
+        let mut ranges: Vec<(usize, usize, String, usize)> = self
+            .cursors()
+            .iter()
+            .enumerate()
+            .filter_map(|(index, cursor)| {
+                edit(&self.text, cursor).map(|(start, end, text)| {
+                    let end = end.min(len);
+                    (start.min(end), end, text, index)
+                })
+            })
+            .collect();
+        ranges.sort_by_key(|(start, end, _, _)| (*start, *end));
+
+        // (start, end, replacement, indices of the cursors placed after it)
+        let mut edits: Vec<(usize, usize, String, Vec<usize>)> = Vec::new();
+        for (start, end, text, index) in ranges {
+            match edits.last_mut() {
+                Some(last) if start < last.1 || (start, end) == (last.0, last.1) => {
+                    last.1 = last.1.max(end);
+                    last.3.push(index);
+                }
+                _ => edits.push((start, end, text, vec![index])),
+            }
+        }
+
+        self.last_edit = Vec::new();
+        self.goal_column = None;
+        for (start, end, text, _) in edits.iter().rev() {
+            self.splice(*start, *end, text);
+            match (start == end, text.is_empty()) {
+                (true, true) => {}
+                (true, false) => self.last_edit.push(DiffOperation::Insert(*start, text.clone())),
+                (false, true) => self.last_edit.push(DiffOperation::Delete(*start, *end)),
+                (false, false) => self.last_edit.push(DiffOperation::Replace(*start, *end, text.clone())),
+            }
+        }
+
+        // Move every cursor and selection through the edits, then put edited cursors after their text
+        let shifts: Vec<(usize, usize, usize)> = edits.iter().map(|(start, end, text, _)| (*start, *end, text.len())).collect();
+        let mut cursors: Vec<Cursor> = self
+            .cursors()
+            .into_iter()
+            .map(|cursor| Cursor {
+                position: map_position(cursor.position, &shifts),
+                selection: cursor.selection.map(|(start, end)| (map_position(start, &shifts), map_position(end, &shifts))),
+            })
+            .collect();
+        for (start, _, text, indices) in &edits {
+            let position = map_position(*start, &shifts) + text.len();
+            for index in indices {
+                cursors[*index].position = position;
+            }
+        }
+
+        let primary = cursors.remove(0);
+        self.set_primary(primary);
+        self.secondary_cursors = cursors;
+        self.merge_cursors();
+    }
+
+    /// Moves the secondary cursors through edits given as (start, end, inserted length).
+    fn map_secondary_cursors(&mut self, shifts: &[(usize, usize, usize)]) {
+        for cursor in &mut self.secondary_cursors {
+            cursor.position = map_position(cursor.position, shifts);
+            cursor.selection = cursor.selection.map(|(start, end)| (map_position(start, shifts), map_position(end, shifts)));
+        }
+        self.merge_cursors();
+    }
+
+    /// Makes `cursor` the primary cursor.
+    fn set_primary(&mut self, cursor: Cursor) {
+        self.cursor_position = cursor.position;
+        match cursor.selection {
+            Some((start, end)) => self.set_selection(start, end),
+            None => self.clear_selection(),
+        }
+    }
+
+    /// Sorts the secondary cursors and drops any that collide with another cursor.
+    fn merge_cursors(&mut self) {
+        let primary = self.cursor_position;
+        self.secondary_cursors.sort_by_key(|cursor| cursor.position);
+        self.secondary_cursors.dedup_by_key(|cursor| cursor.position);
+        self.secondary_cursors.retain(|cursor| cursor.position != primary);
+        self.secondary_cursors.truncate(MAX_CURSORS - 1);
+    }
+
+    /// Byte range of the whole lines the primary cursor and its selection touch, without the last newline.
+    fn line_block(&self) -> (usize, usize) {
+        line_block(&self.text, &self.cursors()[0])
+    }
+
+    /// Start offsets of the lines any cursor or selection touches, in order.
+    fn cursor_line_starts(&self) -> Vec<usize> {
+        let mut starts = Vec::new();
+        for cursor in self.cursors() {
+            let (start, end) = line_block(&self.text, &cursor);
+            let mut line_start = start;
+            for line in self.slice(start, end).split('\n') {
+                starts.push(line_start);
+                line_start += line.len() + 1;
+            }
+        }
+        starts.sort();
+        starts.dedup();
+        starts
+    }
+
+    /// Applies the sorted, non-overlapping `edits` of a line command as one local edit. The cursors
+    /// and selections follow the text; with a non-zero `shift` the primary one moves by `shift`
+    /// instead and secondary cursors are dropped.
+    fn apply_line_command(&mut self, edits: Vec<(usize, usize, String)>, shift: isize) {
+        if edits.is_empty() {
+            return;
+        }
+        let shifts: Vec<(usize, usize, usize)> = edits.iter().map(|(start, end, text)| (*start, *end, text.len())).collect();
+        let position = |offset: usize| {
+            if shift == 0 { map_position(offset, &shifts) } else { (offset as isize + shift) as usize }
+        };
+
+        self.cursor_position = position(self.cursor_position);
+        if let Some((start, end)) = self.get_selection_range() {
+            self.selection_start = Some(position(start));
+            self.selection_end = Some(position(end));
+        }
+        self.last_edit = Vec::new();
+        for (start, end, text) in edits.into_iter().rev() {
+            self.splice(start, end, &text);
+            self.last_edit.push(match (start == end, text.is_empty()) {
+                (true, _) => DiffOperation::Insert(start, text),
+                (false, true) => DiffOperation::Delete(start, end),
+                (false, false) => DiffOperation::Replace(start, end, text),
+            });
+        }
+        if shift == 0 {
+            self.map_secondary_cursors(&shifts);
+        } else {
+            self.secondary_cursors.clear();
+        }
+        self.goal_column = None;
+    }
+
+    /// Surrounds every non-empty selection with `open` and `close`, keeping the original text selected.
+    fn wrap_selections(&mut self, open: char, close: char) {
+        let mut edits = Vec::new();
+        for cursor in self.cursors().iter().filter(|cursor| has_selection(cursor)) {
+            let (start, end) = cursor.selection.map(|(start, end)| (start.min(end), start.max(end))).unwrap();
+            edits.push((start, start, open.to_string()));
+            edits.push((end, end, close.to_string()));
+        }
+        edits.sort_by_key(|(start, _, _)| *start);
+        self.apply_line_command(edits, 0);
+
+        // Selection starts stay in front of the inserted opening character; move them inside
+        let inside = |mut cursor: Cursor| {
+            if let Some((anchor, head)) = cursor.selection.filter(|(start, end)| start != end) {
+                let start = anchor.min(head);
+                if cursor.position == start {
+                    cursor.position += open.len_utf8();
+                }
+                cursor.selection = Some(if anchor == start { (anchor + open.len_utf8(), head) } else { (anchor, head + open.len_utf8()) });
+            }
+            cursor
+        };
+        let mut cursors: Vec<Cursor> = self.cursors().into_iter().map(inside).collect();
+        let primary = cursors.remove(0);
+        self.set_primary(primary);
+        self.secondary_cursors = cursors;
+    }
+
+    /// Whether `position` lies inside a string or comment, as of the last highlight.
+    fn in_non_code(&self, position: usize) -> bool {
+        in_ranges(&self.non_code_ranges, position)
+    }
+
+    /// Whether typing a quote at `position` should insert a pair: not in a string or comment, and
+    /// not right after a word character, where it is more likely an apostrophe or a closing quote.
+    fn pairs_quote_at(&self, position: usize) -> bool {
+        let after_word = char_before(&self.text, position).is_some_and(|c| c.is_alphanumeric() || c == '_');
+        !after_word && !self.in_non_code(position)
+    }
+
+    /// Moves the primary and secondary cursors with `step`, dropping their selections and the goal column.
+    fn move_cursors(&mut self, step: impl Fn(&Rope, usize) -> usize) {
+        self.goal_column = None;
+        self.cursor_position = step(&self.text, self.cursor_position);
+        for cursor in &mut self.secondary_cursors {
+            cursor.position = step(&self.text, cursor.position);
+        }
+        self.clear_all_selections();
+        self.merge_cursors();
+    }
+
+    /// Moves every cursor with `step(text, line, column)`. The primary cursor passes its goal
+    /// column instead of its current one, and remembers it for the next vertical move.
+    fn move_vertically(&mut self, step: impl Fn(&Rope, usize, usize) -> usize) {
+        let primary = self.cursor_position;
+        let goal = self.goal_column.unwrap_or_else(|| line_and_column(&self.text, primary).1);
+        self.move_cursors(|text, position| {
+            let (line, column) = line_and_column(text, position);
+            step(text, line, if position == primary { goal } else { column })
+        });
+        self.goal_column = Some(goal);
+    }
+
+    /// Replaces the bytes from `start` to `end` with `replacement`, in time logarithmic in the
+    /// document's length rather than linear as with a `String`.
+    fn splice(&mut self, start: usize, end: usize, replacement: &str) {
+        let start_char = self.text.byte_to_char(start);
+        if start < end {
+            self.text.remove(start_char..self.text.byte_to_char(end));
+        }
+        if !replacement.is_empty() {
+            self.text.insert(start_char, replacement);
+        }
+    }
+
+    /// The text between two byte offsets, borrowed when it lies in one chunk of the rope.
+    fn slice(&self, start: usize, end: usize) -> Cow<'_, str> {
+        self.text.byte_slice(start..end).into()
+    }
+
+    /// Whether the text at `position` starts with `prefix`.
+    fn has_at(&self, position: usize, prefix: &str) -> bool {
+        self.text.bytes_at(position).take(prefix.len()).eq(prefix.bytes())
+    }
+
+    /// Clears the selection of every cursor.
+    fn clear_all_selections(&mut self) {
+        self.clear_selection();
+        for cursor in &mut self.secondary_cursors {
+            cursor.selection = None;
+        }
+    }
+}
+
+impl Default for EditorState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Line regions between brackets that open and close on different lines, ignoring brackets in
+/// string literals and `//` comments.
+fn bracket_regions(text: &str) -> Vec<(usize, usize)> {
+    let mut regions = Vec::new();
+    let mut open = Vec::new(); // Line of each unclosed bracket
+    for (line_number, line) in text.split('\n').enumerate() {
+        let mut chars = line.chars().peekable();
+        let mut in_string = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if in_string => {
+                    chars.next();
+                }
+                '"' => in_string = !in_string,
+                _ if in_string => {}
+                '/' if chars.peek() == Some(&'/') => break,
+                '{' | '[' | '(' => open.push(line_number),
+                '}' | ']' | ')' => {
+                    if let Some(start) = open.pop().filter(|start| *start < line_number) {
+                        if !regions.iter().any(|(region_start, _)| *region_start == start) {
+                            regions.push((start, line_number));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    regions
+}
+
+/// Byte range of the whole lines `cursor` and its selection touch, without the last newline.
+/// A selection ending at the start of a line doesn't count that line.
+fn line_block(text: &Rope, cursor: &Cursor) -> (usize, usize) {
+    let (from, to) = match cursor.selection {
+        Some((start, end)) if start != end => (start.min(end), start.max(end)),
+        _ => (cursor.position, cursor.position),
+    };
+    let to = if to > from && text.byte(to - 1) == b'\n' { to - 1 } else { to };
+    (start_of_line(text, from), end_of_line(text, to))
+}
+
+/// Byte offset of the start of the line `position` is on.
+fn start_of_line(text: &Rope, position: usize) -> usize {
+    text.line_to_byte(text.byte_to_line(position))
+}
+
+/// Byte offset of the end of the line `position` is on, before its newline.
+fn end_of_line(text: &Rope, position: usize) -> usize {
+    let next_line = text.byte_to_line(position) + 1;
+    if next_line < text.len_lines() { text.line_to_byte(next_line) - 1 } else { text.len_bytes() }
+}
+
+/// The character starting at byte offset `position`, if any.
+fn char_at(text: &Rope, position: usize) -> Option<char> {
+    text.get_char(text.byte_to_char(position))
+}
+
+/// The character ending at byte offset `position`, if any.
+fn char_before(text: &Rope, position: usize) -> Option<char> {
+    text.byte_to_char(position).checked_sub(1).map(|index| text.char(index))
+}
+
+/// A line of the rope without its `\n` or `\r\n`.
+fn without_line_ending(line: ropey::RopeSlice<'_>) -> Cow<'_, str> {
+    let mut end = line.len_bytes();
+    if end > 0 && line.byte(end - 1) == b'\n' {
+        end -= 1;
+        if end > 0 && line.byte(end - 1) == b'\r' {
+            end -= 1;
+        }
+    }
+    line.byte_slice(..end).into()
+}
+
+/// Whether `position` lies strictly inside one of `ranges`.
+fn in_ranges(ranges: &[(usize, usize)], position: usize) -> bool {
+    ranges.iter().any(|(start, end)| *start < position && position < *end)
+}
+
+/// The character auto-inserted after typing `ch`, for brackets and quotes.
+fn closing_partner(ch: char) -> Option<char> {
+    match ch {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        '"' | '\'' | '`' => Some(ch),
+        _ => None,
+    }
+}
+
+fn has_selection(cursor: &Cursor) -> bool {
+    cursor.selection.is_some_and(|(start, end)| start != end)
+}
+
+/// The range a copy takes from `cursor`: its selection, or its whole line including the newline.
+fn clipboard_range(text: &Rope, cursor: &Cursor, linewise: bool) -> Option<(usize, usize)> {
+    if linewise {
+        let line_end = end_of_line(text, cursor.position);
+        let line_end = if line_end < text.len_bytes() { line_end + 1 } else { line_end };
+        return Some((start_of_line(text, cursor.position), line_end));
+    }
+    cursor.selection.filter(|(start, end)| start != end).map(|(start, end)| (start.min(end), start.max(end)))
+}
+
+/// Returns the start of the grapheme before `position`, if any. Graphemes only span lines as
+/// `\r\n`, so segmenting from the start of the previous line is enough.
+fn previous_boundary(text: &Rope, position: usize) -> Option<usize> {
+    let from = start_of_line(text, position.saturating_sub(1));
+    let before = Cow::from(text.byte_slice(from..position));
+    before.grapheme_indices(true).next_back().map(|(offset, _)| from + offset)
+}
+
+/// Returns the end of the grapheme after `position`, if any.
+fn next_boundary(text: &Rope, position: usize) -> Option<usize> {
+    let to = (end_of_line(text, position) + 1).min(text.len_bytes());
+    let after = Cow::from(text.byte_slice(position..to));
+    after.graphemes(true).next().map(|grapheme| position + grapheme.len())
+}
+
+/// Returns the line index and column, in graphemes, of a byte offset.
+fn line_and_column(text: &Rope, position: usize) -> (usize, usize) {
+    let line = text.byte_to_line(position);
+    let before = Cow::from(text.byte_slice(text.line_to_byte(line)..position));
+    (line, before.graphemes(true).count())
+}
+
+/// Returns the byte offset of `column` (in graphemes) on `line`, clamped to the end of the line.
+fn offset_at(text: &Rope, line: usize, column: usize) -> usize {
+    let line_start = text.line_to_byte(line);
+    let line_text = Cow::from(text.byte_slice(line_start..end_of_line(text, line_start)));
+    line_start + line_text.grapheme_indices(true).nth(column).map_or(line_text.len(), |(offset, _)| offset)
+}
+
+/// Maps an offset through edits given as (start, end, inserted length), sorted by position.
+/// Offsets inside a replaced range move to the end of its replacement; offsets at its start stay put.
+fn map_position(position: usize, shifts: &[(usize, usize, usize)]) -> usize {
+    let mut shift: isize = 0;
+    for &(start, end, inserted) in shifts {
+        if position <= start {
+            break;
+        }
+        if position < end {
+            return (start as isize + shift) as usize + inserted;
+        }
+        shift += inserted as isize - (end - start) as isize;
     }
+    (position as isize + shift) as usize
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::diff_engine::DiffEngine;
+    use crate::editor::version_control::VersionControl;
+    use crate::editor::config::TypingRuleOptions;
+
+    fn state_with(text: &str) -> EditorState {
+        let mut state = EditorState::new();
+        state.insert_text(text);
+        state
+    }
+
+    fn positions(state: &EditorState) -> Vec<usize> {
+        state.cursors().iter().map(|cursor| cursor.position).collect()
+    }
+
+    #[test]
+    fn test_insert_at_three_cursors() {
+        let mut state = state_with("one\ntwo\nthree");
+        state.move_cursor(0);
+        state.add_cursor_at(4);
+        state.add_cursor_at(8);
+        state.add_cursor_at(8); // Already there
+
+        state.insert_text("- ");
+        assert_eq!(state.get_text(), "- one\n- two\n- three");
+        assert_eq!(positions(&state), vec![2, 8, 14]);
+        assert_eq!(state.last_edit().len(), 3);
+    }
+
+    #[test]
+    fn test_overlapping_deletions_merge_cursors() {
+        let mut state = state_with("abcdefgh");
+        state.set_selection(1, 4);
+        state.move_cursor(4);
+        state.set_secondary_cursors(vec![
+            Cursor { position: 6, selection: Some((3, 6)) },
+            Cursor { position: 8, selection: Some((7, 8)) },
+        ]);
+
+        state.delete_selected_text();
+        assert_eq!(state.get_text(), "ag");
+        assert_eq!(positions(&state), vec![1, 2]);
+        assert_eq!(state.get_selection_range(), None);
+    }
+
+    #[test]
+    fn test_column_select_clamps_short_lines() {
+        let mut state = state_with("abcdef\nab\nabcdefgh");
+        state.column_select(1, 14); // Line 0 column 1 to line 2 column 4
+
+        let cursors = state.cursors();
+        assert_eq!(cursors.len(), 3);
+        assert_eq!(cursors[0], Cursor { position: 14, selection: Some((11, 14)) });
+        assert_eq!(state.secondary_cursors()[0], Cursor { position: 4, selection: Some((1, 4)) });
+        assert_eq!(state.secondary_cursors()[1], Cursor { position: 9, selection: Some((8, 9)) });
+
+        state.insert_text("|");
+        assert_eq!(state.get_text(), "abcd|ef\nab|\nabcd|efgh");
+
+        state.clear_secondary_cursors();
+        assert_eq!(state.cursors().len(), 1);
+    }
+
+    #[test]
+    fn test_matches_get_one_cursor_each() {
+        let mut state = state_with("let a = 1; let b = a; let c = a;");
+        assert_eq!(state.add_cursors_for_matches("let"), 3);
+        assert_eq!(state.get_selection_range(), Some((0, 3)));
+        assert_eq!(state.add_cursors_for_matches("missing"), 0);
+    }
+
+    #[test]
+    fn test_multi_cursor_edit_is_one_undo_step() {
+        let mut version_control = VersionControl::new();
+        let mut state = state_with("a\nb\nc");
+        state.move_cursor(0);
+        state.add_cursor_at(2);
+        state.add_cursor_at(4);
+
+        version_control.track_change(&state);
+        state.insert_text("> ");
+        assert_eq!(state.get_text(), "> a\n> b\n> c");
+
+        let state = version_control.undo(&state).unwrap();
+        assert_eq!(state.get_text(), "a\nb\nc");
+        assert_eq!(state.cursors().len(), 3);
+    }
+
+    #[test]
+    fn test_batched_delta_matches_local_edit() {
+        let mut state = state_with("fn a() {}\nfn b() {}\nfn c() {}");
+        state.add_cursors_for_matches("fn");
+
+        // Each multi-cursor edit is one delta that a collaborator can apply to their copy
+        let mut remote = state.get_text().to_string();
+        state.delete_selected_text();
+        remote = DiffEngine::apply(&remote, state.last_edit());
+        assert_eq!(remote, state.get_text());
+
+        state.insert_text("pub fn");
+        remote = DiffEngine::apply(&remote, state.last_edit());
+        assert_eq!(remote, "pub fn a() {}\npub fn b() {}\npub fn c() {}");
+        assert_eq!(remote, state.get_text());
+    }
+
+    #[test]
+    fn test_newline_copies_indentation() {
+        let mut state = state_with("fn main() {\n    let a = 1;");
+        state.insert_newline();
+        state.insert_text("let b = 2;");
+        assert_eq!(state.get_text(), "fn main() {\n    let a = 1;\n    let b = 2;");
+
+        // Only the indentation before the cursor is copied
+        state.move_cursor(14);
+        state.insert_newline();
+        assert_eq!(state.get_text(), "fn main() {\n  \n    let a = 1;\n    let b = 2;");
+        assert_eq!(state.get_cursor_position(), 17);
+    }
+
+    #[test]
+    fn test_backspace_at_line_start_joins_lines() {
+        let mut state = state_with("one\ntwo");
+        state.move_cursor(4);
+        state.delete_character_before_cursor();
+        assert_eq!(state.get_text(), "onetwo");
+        assert_eq!(state.get_cursor_position(), 3);
+
+        state.move_cursor(0);
+        state.delete_character_before_cursor();
+        assert_eq!(state.get_text(), "onetwo");
+    }
+
+    #[test]
+    fn test_deletion_is_grapheme_safe() {
+        let mut state = state_with("ae\u{301}👨‍👩‍👧b");
+        state.move_cursor_left();
+        state.delete_character_before_cursor();
+        assert_eq!(state.get_text(), "ae\u{301}b");
+
+        state.move_cursor(1);
+        state.delete_character_at_cursor();
+        assert_eq!(state.get_text(), "ab");
+
+        state.move_cursor_right();
+        assert_eq!(state.get_cursor_position(), 2);
+    }
+
+    #[test]
+    fn test_cursor_up_down_preserves_column() {
+        let mut state = state_with("abcdef\nxy\nabcdef");
+        state.move_cursor(4);
+        state.move_cursor_down();
+        assert_eq!(state.get_cursor_position(), 9); // Clamped to the end of "xy"
+        state.move_cursor_down();
+        assert_eq!(state.get_cursor_position(), 14); // Back at the goal column on the long line
+        state.move_cursor_up();
+        state.move_cursor_up();
+        assert_eq!(state.get_cursor_position(), 4);
+        state.move_cursor_up();
+        assert_eq!(state.get_cursor_position(), 0);
+    }
+
+    #[test]
+    fn test_horizontal_move_and_edit_reset_goal_column() {
+        let mut state = state_with("abcdef\nxy\nabcdef");
+        state.move_cursor(4);
+        state.move_cursor_down();
+        state.move_cursor_left();
+        state.move_cursor_down();
+        assert_eq!(state.get_cursor_position(), 11); // Column 1, where the cursor was after moving left
+
+        state.move_cursor(5);
+        state.move_cursor_down();
+        state.insert_text("z");
+        state.move_cursor_down();
+        assert_eq!(state.get_cursor_position(), 14); // Column 3 of the last line, after "xyz"
+    }
+
+    #[test]
+    fn test_copy_and_paste_round_trip() {
+        let mut state = state_with("hello world");
+        state.set_selection(0, 5);
+        assert_eq!(state.copy_selected_text(), "hello");
+        assert_eq!(state.get_text(), "hello world");
+
+        // Pasting replaces the selection
+        state.set_selection(6, 11);
+        state.paste();
+        assert_eq!(state.get_text(), "hello hello");
+        assert_eq!(state.get_cursor_position(), 11);
+        assert_eq!(state.get_selection_range(), None);
+    }
+
+    #[test]
+    fn test_cut_and_paste_round_trip() {
+        let mut state = state_with("one two three");
+        state.set_selection(4, 8);
+        assert_eq!(state.cut_selected_text(), "two ");
+        assert_eq!(state.get_text(), "one three");
+        assert_eq!(state.get_cursor_position(), 4);
+
+        state.move_cursor(9);
+        state.paste_text(" two");
+        assert_eq!(state.get_text(), "one three two");
+        assert_eq!(state.clipboard(), "two ");
+    }
+
+    #[test]
+    fn test_copy_without_selection_takes_the_line() {
+        let mut state = state_with("first\nsecond\nthird");
+        state.move_cursor(8);
+        assert_eq!(state.copy_selected_text(), "second\n");
+
+        // Whole lines paste above the current line
+        state.move_cursor(15);
+        state.paste();
+        assert_eq!(state.get_text(), "first\nsecond\nsecond\nthird");
+
+        // Cutting the last line leaves no empty line behind
+        state.move_cursor(state.get_text().len());
+        assert_eq!(state.cut_selected_text(), "third\n");
+        assert_eq!(state.get_text(), "first\nsecond\nsecond");
+    }
+
+    /// Runs `command` as one tracked edit and checks it undoes in one step and replays remotely.
+    fn run_line_command(state: &mut EditorState, command: impl Fn(&mut EditorState)) {
+        let mut version_control = VersionControl::new();
+        let before = state.get_text().to_string();
+        version_control.track_change(state);
+        command(state);
+        assert_eq!(DiffEngine::apply(&before, state.last_edit()), state.get_text());
+        assert_eq!(version_control.undo(state).unwrap().get_text(), before);
+    }
+
+    #[test]
+    fn test_move_lines_at_document_boundaries() {
+        let mut state = state_with("one\ntwo\nthree");
+        state.move_cursor(1);
+        state.move_lines_up();
+        assert_eq!(state.get_text(), "one\ntwo\nthree"); // Already the first line
+
+        run_line_command(&mut state, EditorState::move_lines_down);
+        assert_eq!(state.get_text(), "two\none\nthree");
+        assert_eq!(state.get_cursor_position(), 5); // Still after the "o" of "one"
+
+        state.move_lines_down();
+        state.move_lines_down();
+        assert_eq!(state.get_text(), "two\nthree\none"); // Stops at the last line
+        run_line_command(&mut state, EditorState::move_lines_up);
+        assert_eq!(state.get_text(), "two\none\nthree");
+    }
+
+    #[test]
+    fn test_duplicate_multi_line_selection() {
+        let mut state = state_with("a\nbc\nde\nf");
+        state.set_selection(3, 7); // From "c" into "de"
+        run_line_command(&mut state, EditorState::duplicate_lines);
+        assert_eq!(state.get_text(), "a\nbc\nde\nbc\nde\nf");
+        assert_eq!(state.get_selection_range(), Some((9, 13))); // Same text, on the copy
+
+        // A selection ending at a line start leaves that line alone
+        let mut state = state_with("a\nb\nc");
+        state.set_selection(0, 2);
+        state.duplicate_lines();
+        assert_eq!(state.get_text(), "a\na\nb\nc");
+    }
+
+    #[test]
+    fn test_toggle_line_comment() {
+        // Rust: the token goes after the common indentation, blank lines are skipped
+        let mut state = state_with("fn a() {\n    let x = 1;\n\n    let y = 2;\n}");
+        state.set_selection(9, 40);
+        run_line_command(&mut state, |state| state.toggle_line_comment("rust"));
+        assert_eq!(state.get_text(), "fn a() {\n    // let x = 1;\n\n    // let y = 2;\n}");
+        state.toggle_line_comment("rust");
+        assert_eq!(state.get_text(), "fn a() {\n    let x = 1;\n\n    let y = 2;\n}");
+
+        // Python: a mix of commented and uncommented lines gets commented throughout
+        let mut state = state_with("# a = 1\nb = 2");
+        state.set_selection(0, 13);
+        state.toggle_line_comment("py");
+        assert_eq!(state.get_text(), "# # a = 1\n# b = 2");
+        state.toggle_line_comment("py");
+        assert_eq!(state.get_text(), "# a = 1\nb = 2");
+
+        // HTML has no line comments and falls back to a block comment
+        let mut state = state_with("  <p>hi</p>");
+        state.toggle_line_comment("html");
+        assert_eq!(state.get_text(), "  <!-- <p>hi</p> -->");
+        state.toggle_line_comment("html");
+        assert_eq!(state.get_text(), "  <p>hi</p>");
+    }
+
+    #[test]
+    fn test_toggle_block_comment() {
+        let mut state = state_with("let x = a + b;");
+        state.set_selection(8, 13);
+        run_line_command(&mut state, |state| state.toggle_block_comment("rs"));
+        assert_eq!(state.get_text(), "let x = /* a + b */;");
+
+        state.set_selection(8, 19);
+        state.toggle_block_comment("rs");
+        assert_eq!(state.get_text(), "let x = a + b;");
+
+        // Python has no block comments and falls back to line comments
+        let mut state = state_with("x = 1");
+        state.toggle_block_comment("python");
+        assert_eq!(state.get_text(), "# x = 1");
+    }
+
+    #[test]
+    fn test_tab_aligns_to_next_space_stop() {
+        let mut state = state_with("ab");
+        state.set_indent_style(IndentStyle::Spaces(4));
+        state.insert_text("\t");
+        assert_eq!(state.get_text(), "ab  ");
+        state.indent();
+        assert_eq!(state.get_text(), "ab      ");
+
+        // Tabs keep inserting a tab character
+        let mut state = state_with("ab");
+        state.indent();
+        assert_eq!(state.get_text(), "ab\t");
+
+        // A selection across lines indents each non-blank line by a full level
+        let mut state = state_with("a\n\n  b");
+        state.set_indent_style(IndentStyle::Spaces(4));
+        state.set_selection(0, 6);
+        state.indent();
+        assert_eq!(state.get_text(), "    a\n\n      b");
+    }
+
+    #[test]
+    fn test_dedent_removes_one_level() {
+        let mut state = state_with("        a\n      b\n\tc\nd");
+        state.set_indent_style(IndentStyle::Spaces(4));
+        state.set_selection(0, 22);
+        state.dedent();
+        assert_eq!(state.get_text(), "    a\n    b\nc\nd");
+        state.dedent();
+        assert_eq!(state.get_text(), "a\nb\nc\nd");
+    }
+
+    #[test]
+    fn test_function_body_is_foldable() {
+        let state = state_with("fn main() {\n    let a = \"{\";\n    if a.is_empty() {\n        return;\n    }\n}\nfn other() {}");
+        assert_eq!(state.fold_regions(), vec![(0, 5), (2, 4)]);
+
+        // Without brackets, indentation decides
+        let state = state_with("def f():\n    a = 1\n\n    return a\nprint(f())");
+        assert_eq!(state.fold_regions(), vec![(0, 3)]);
+    }
+
+    #[test]
+    fn test_fold_and_unfold() {
+        let mut state = state_with("fn main() {\n    body();\n}\nfn other() {}");
+        assert!(state.fold((1, 2)).is_err());
+        state.fold((0, 2)).unwrap();
+        assert_eq!(state.folded_regions(), vec![(0, 2)]);
+        assert!(!state.is_line_hidden(0));
+        assert!(state.is_line_hidden(1) && state.is_line_hidden(2));
+        assert!(!state.is_line_hidden(3));
+
+        state.unfold((0, 2));
+        assert!(state.folded_regions().is_empty());
+        assert!(!state.is_line_hidden(1));
+    }
+
+    #[test]
+    fn test_block_selection_inserts_prefix_on_aligned_lines() {
+        let mut state = state_with("let a = 1;\nlet b = 2;\nlet c = 3;");
+        state.set_block_selection(0, 4, 2, 4);
+        assert_eq!(state.cursors().len(), 3);
+
+        state.insert_text("mut ");
+        assert_eq!(state.get_text(), "let mut a = 1;\nlet mut b = 2;\nlet mut c = 3;");
+        assert_eq!(state.selection_mode(), SelectionMode::Block { start_line: 0, start_column: 8, end_line: 2, end_column: 8 });
+
+        // Typing goes on at the moved block
+        state.insert_text("x");
+        assert_eq!(state.get_text(), "let mut xa = 1;\nlet mut xb = 2;\nlet mut xc = 3;");
+    }
+
+    #[test]
+    fn test_block_deletion_removes_rectangle() {
+        let mut state = state_with("abcdef\nab\nabcdef");
+        state.set_block_selection(0, 1, 2, 3);
+        assert_eq!(state.cursors().len(), 3);
+
+        state.delete_selected_text();
+        assert_eq!(state.get_text(), "adef\na\nadef");
+        assert_eq!(state.selection_mode(), SelectionMode::Block { start_line: 0, start_column: 1, end_line: 2, end_column: 1 });
+
+        // Lines that don't reach the block are left alone, and moving the cursor ends block mode
+        let mut state = state_with("abcdef\nab\nabcdef");
+        state.set_block_selection(0, 4, 2, 4);
+        state.insert_text("|");
+        assert_eq!(state.get_text(), "abcd|ef\nab\nabcd|ef");
+        state.move_cursor(0);
+        assert_eq!(state.selection_mode(), SelectionMode::Linear);
+    }
+
+    #[test]
+    fn test_autopair_closes_and_steps_over_brackets() {
+        let mut state = state_with("f");
+        state.insert_with_autopair('(');
+        assert_eq!((state.get_text().as_ref(), state.get_cursor_position()), ("f()", 2));
+        assert_eq!(state.last_edit(), &[DiffOperation::Insert(1, "()".to_string())]);
+
+        state.insert_with_autopair('x');
+        state.insert_with_autopair(')');
+        assert_eq!((state.get_text().as_ref(), state.get_cursor_position()), ("f(x)", 4));
+        state.insert_with_autopair(')'); // Nothing to step over any more
+        assert_eq!(state.get_text(), "f(x))");
+
+        // No pairing inside a string, or for an apostrophe after a word
+        let mut state = state_with("s = \"ab\"");
+        state.set_non_code_ranges(vec![(4, 8)]);
+        state.move_cursor(6);
+        state.insert_with_autopair('[');
+        assert_eq!(state.get_text(), "s = \"a[b\"");
+        let mut state = state_with("don");
+        state.insert_with_autopair('\'');
+        assert_eq!(state.get_text(), "don'");
+    }
+
+    #[test]
+    fn test_autopair_wraps_selections() {
+        let mut state = state_with("a + b\nc + d");
+        state.move_cursor(5);
+        state.set_selection(0, 5);
+        state.set_secondary_cursors(vec![Cursor { position: 11, selection: Some((11, 6)) }]);
+
+        state.insert_with_autopair('(');
+        assert_eq!(state.get_text(), "(a + b)\n(c + d)");
+        assert_eq!((state.get_selection_range(), state.get_cursor_position()), (Some((1, 6)), 6));
+        assert_eq!(state.secondary_cursors(), &[Cursor { position: 14, selection: Some((14, 9)) }]);
+
+        // One undo step for collaborators: the delta replays to the same text
+        let mut remote = "a + b\nc + d".to_string();
+        for operation in state.last_edit() {
+            if let DiffOperation::Insert(at, text) = operation {
+                remote.insert_str(*at, text);
+            }
+        }
+        assert_eq!(remote, state.get_text());
+    }
+
+    #[test]
+    fn test_cleanup_trims_trailing_whitespace() {
+        let mut state = state_with("fn main() {  \n\tlet x = 1;\t \n  \n}\n");
+        state.move_cursor(13); // At the end of the first line's trailing spaces
+        state.cleanup();
+        assert_eq!(state.get_text(), "fn main() {\n\tlet x = 1;\n\n}\n");
+        assert_eq!(state.get_cursor_position(), 11);
+        assert_eq!(state.last_edit().len(), 3); // One edit, undone in one step
+
+        // Windows line endings keep their carriage returns
+        let mut state = state_with("a \r\nb\t\r\n");
+        state.cleanup();
+        assert_eq!(state.get_text(), "a\r\nb\r\n");
+    }
+
+    #[test]
+    fn test_cleanup_ensures_one_final_newline() {
+        let mut state = state_with("first\nlast  ");
+        state.cleanup();
+        assert_eq!((state.get_text().as_ref(), state.get_cursor_position()), ("first\nlast\n", 11));
+
+        let mut state = state_with("text\n\n  \n\n");
+        state.set_cleanup_options(CleanupOptions { trim_trailing_whitespace: false, ..CleanupOptions::default() });
+        state.cleanup();
+        assert_eq!(state.get_text(), "text\n\n  \n"); // Whitespace-only lines are content now
+        assert!(state.get_cursor_position() <= state.get_text().len());
+
+        let mut state = state_with("a  \nb  ");
+        state.set_cleanup_options(CleanupOptions { ensure_final_newline: false, ..CleanupOptions::default() });
+        state.cleanup();
+        assert_eq!(state.get_text(), "a\nb");
+
+        let mut state = state_with("");
+        state.cleanup();
+        assert_eq!(state.get_text(), ""); // Empty documents stay empty
+    }
+
+    fn state_with_rules(language: &str, text: &str, options: TypingRuleOptions) -> EditorState {
+        let mut state = state_with(text);
+        state.set_indent_style(IndentStyle::Spaces(4));
+        state.set_typing_rules(Some(TypingRules::new(language, options)));
+        state
+    }
+
+    #[test]
+    fn test_typing_rules_apply_in_one_undo_step() {
+        let mut state = state_with_rules("rust", "fn main() {\n    if x {\n        a();   ", TypingRuleOptions::default());
+        run_line_command(&mut state, |state| state.insert_newline());
+        assert_eq!(state.get_text(), "fn main() {\n    if x {\n        a();\n        ");
+
+        run_line_command(&mut state, |state| state.insert_text("}"));
+        assert_eq!(state.get_text(), "fn main() {\n    if x {\n        a();\n    }");
+        assert_eq!(state.last_edit(), [DiffOperation::Replace(36, 44, "    }".to_string())]);
+        assert_eq!(state.get_cursor_position(), state.get_text().len());
+    }
+
+    #[test]
+    fn test_typing_rules_skip_strings_and_disabled_rules() {
+        // Inside a multi-line string, Enter neither trims nor dedents
+        let mut state = state_with_rules("python", "s = '''\n    return  ", TypingRuleOptions::default());
+        state.set_non_code_ranges(vec![(4, 25)]); // An unterminated string runs to the end
+        state.insert_newline();
+        assert_eq!(state.get_text(), "s = '''\n    return  \n    ");
+
+        let mut state = state_with_rules("python", "def f():\n    return 1", TypingRuleOptions::default());
+        state.insert_newline();
+        assert_eq!(state.get_text(), "def f():\n    return 1\n");
+
+        let options = TypingRuleOptions { auto_dedent: false, ..TypingRuleOptions::default() };
+        let mut state = state_with_rules("python", "def f():\n    return 1 ", options);
+        state.insert_newline();
+        assert_eq!(state.get_text(), "def f():\n    return 1\n    ");
+    }
+
+    #[test]
+    fn test_edits_across_rope_chunks_keep_graphemes_whole() {
+        // Big enough that the rope splits it into many chunks, some of them inside these graphemes
+        let line = "é\u{301}👍🏽 x\r\n";
+        let mut state = state_with(&line.repeat(5000));
+        let middle = line.len() * 2500;
+
+        state.move_cursor(middle);
+        state.delete_character_before_cursor(); // The whole "\r\n"
+        assert_eq!(state.get_cursor_position(), middle - 2);
+        state.delete_character_before_cursor();
+        state.delete_character_before_cursor();
+        state.delete_character_before_cursor(); // The thumbs up with its skin tone
+        assert_eq!(state.get_cursor_position(), middle - line.len() + "é\u{301}".len());
+        state.move_cursor_left();
+        assert_eq!(state.get_cursor_position(), middle - line.len());
+
+        let mut expected = line.repeat(5000);
+        expected.replace_range(middle - line.len() + "é\u{301}".len()..middle, "");
+        assert_eq!(state.get_text(), expected);
+        assert_eq!(state.line_count(), 5000);
+        assert_eq!(state.line(2499), "é\u{301}é\u{301}👍🏽 x");
+        assert_eq!(state.lines().count(), expected.lines().count());
+        assert!(state.lines().zip(expected.lines()).all(|(line, expected)| line == expected));
+    }
+
+    #[test]
+    fn test_lines_match_str_lines() {
+        for text in ["", "\n", "one", "one\n", "one\r\ntwo\n\n", "\nthree"] {
+            let state = state_with(text);
+            assert_eq!(state.lines().collect::<Vec<_>>(), text.lines().collect::<Vec<_>>(), "{:?}", text);
+        }
+    }
+
+    /// Compares the rope with the `String` it replaced on a 10MB document. Run with `--ignored`.
+    #[test]
+    #[ignore]
+    fn bench_rope_against_string() {
+        use std::time::Instant;
+        let text = "lorem ipsum dolor sit amet, consectetur adipiscing\n".repeat(200_000);
+        let middle = text.len() / 2;
+
+        let mut string = text.clone();
+        let started = Instant::now();
+        for _ in 0..1000 {
+            string.insert(middle, 'x');
+        }
+        let string_insert = started.elapsed();
+        let started = Instant::now();
+        let string_lines = (0..1000).map(|line| string.split('\n').nth(line * 100).unwrap().len()).sum::<usize>();
+        let string_lookup = started.elapsed();
+
+        let mut state = state_with(&text);
+        let started = Instant::now();
+        for _ in 0..1000 {
+            state.apply_sync(middle, middle, "x");
+        }
+        let rope_insert = started.elapsed();
+        let started = Instant::now();
+        let rope_lines = (0..1000).map(|line| state.line(line * 100).len()).sum::<usize>();
+        let rope_lookup = started.elapsed();
+
+        assert_eq!(string_lines, rope_lines);
+        assert!(rope_insert < string_insert, "Inserts: String {:?}, rope {:?}", string_insert, rope_insert);
+        assert!(rope_lookup < string_lookup, "Line lookups: String {:?}, rope {:?}", string_lookup, rope_lookup);
+    }
+}
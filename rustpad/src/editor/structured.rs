@@ -0,0 +1,292 @@
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use crate::editor::linter::LintError;
+
+/// Formats a document can be edited in structured mode, where it is parsed and validated as it changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StructuredFormat {
+    Json,
+    Yaml,
+}
+
+impl StructuredFormat {
+    /// The structured format of documents in `language`, if it has one
+    pub fn for_language(language: &str) -> Option<Self> {
+        match language.to_lowercase().as_str() {
+            "json" => Some(StructuredFormat::Json),
+            "yaml" | "yml" => Some(StructuredFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Parses `text`. A syntax error is reported at the line and column it was found.
+    pub fn parse(&self, text: &str) -> Result<Value, LintError> {
+        let (message, line, column) = match self {
+            StructuredFormat::Json => match serde_json::from_str(text) {
+                Ok(value) => return Ok(value),
+                Err(e) => (e.to_string(), e.line().max(1), e.column().max(1)),
+            },
+            StructuredFormat::Yaml => match serde_yaml::from_str(text) {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    let (line, column) = e.location().map_or((1, 1), |location| (location.line(), location.column()));
+                    (e.to_string(), line, column)
+                }
+            },
+        };
+        Err(LintError { line, column, message, severity: "error".to_string() })
+    }
+
+    /// Offset in `text` of the value at the JSON pointer `path`. When part of the path can't be
+    /// found, such as a YAML flow collection, the deepest value found is used instead.
+    pub fn locate(&self, text: &str, path: &[String]) -> usize {
+        match self {
+            StructuredFormat::Json => locate_json(text, path),
+            StructuredFormat::Yaml => locate_yaml(text, path),
+        }
+    }
+}
+
+/// Parses `text` and, when it parses and a `schema` is attached, validates it. Returns the
+/// parsed value and the diagnostics: a parse error, or one warning per schema violation
+/// pointing at the offending value.
+pub fn analyze(format: StructuredFormat, text: &str, schema: Option<&JSONSchema>) -> (Option<Value>, Vec<LintError>) {
+    let value = match format.parse(text) {
+        Ok(value) => value,
+        Err(error) => return (None, vec![error]),
+    };
+    let mut diagnostics = Vec::new();
+    if let Some(Err(errors)) = schema.map(|schema| schema.validate(&value)) {
+        for error in errors {
+            let pointer = error.instance_path.to_string();
+            let (line, column) = line_column(text, format.locate(text, &error.instance_path.clone().into_vec()));
+            diagnostics.push(LintError {
+                line,
+                column,
+                message: format!("{}: {}", if pointer.is_empty() { "/" } else { &pointer }, error),
+                severity: "warning".to_string(),
+            });
+        }
+    }
+    (Some(value), diagnostics)
+}
+
+/// 1-based line and column of byte `offset` in `text`
+pub fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+fn locate_json(text: &str, path: &[String]) -> usize {
+    let bytes = text.as_bytes();
+    let mut position = skip_whitespace(bytes, 0);
+    for segment in path {
+        let found = match bytes.get(position) {
+            Some(b'{') => json_member(text, position, segment),
+            Some(b'[') => segment.parse().ok().and_then(|index| json_element(bytes, position, index)),
+            _ => None,
+        };
+        match found {
+            Some(found) => position = found,
+            None => break,
+        }
+    }
+    position
+}
+
+/// Offset of the value of member `key` in the object starting at `start`
+fn json_member(text: &str, start: usize, key: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut at = skip_whitespace(bytes, start + 1);
+    while bytes.get(at) == Some(&b'"') {
+        let key_end = value_end(bytes, at);
+        let name: String = serde_json::from_str(&text[at..key_end]).ok()?;
+        at = skip_whitespace(bytes, key_end);
+        at = skip_whitespace(bytes, at + 1); // Past the colon
+        if name == key {
+            return Some(at);
+        }
+        at = skip_whitespace(bytes, value_end(bytes, at));
+        if bytes.get(at) != Some(&b',') {
+            return None;
+        }
+        at = skip_whitespace(bytes, at + 1);
+    }
+    None
+}
+
+/// Offset of element `index` of the array starting at `start`
+fn json_element(bytes: &[u8], start: usize, index: usize) -> Option<usize> {
+    let mut at = skip_whitespace(bytes, start + 1);
+    for _ in 0..index {
+        at = skip_whitespace(bytes, value_end(bytes, at));
+        if bytes.get(at) != Some(&b',') {
+            return None;
+        }
+        at = skip_whitespace(bytes, at + 1);
+    }
+    Some(at)
+}
+
+/// Offset just past the JSON value starting at `start`
+fn value_end(bytes: &[u8], start: usize) -> usize {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (offset, &byte) in bytes.iter().enumerate().skip(start) {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => {
+                    in_string = false;
+                    if depth == 0 {
+                        return offset + 1;
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    return offset + 1;
+                }
+            }
+            b',' | b'}' | b']' if depth == 0 => return offset,
+            _ if depth == 0 && byte.is_ascii_whitespace() => return offset,
+            _ => {}
+        }
+    }
+    bytes.len()
+}
+
+fn skip_whitespace(bytes: &[u8], mut at: usize) -> usize {
+    while bytes.get(at).is_some_and(|byte| byte.is_ascii_whitespace()) {
+        at += 1;
+    }
+    at
+}
+
+/// A block-style YAML line, split into the sequence items it opens and the key it starts with
+struct YamlEntry {
+    indent: usize,
+    offset: usize,
+    key: Option<String>, // `None` for a sequence item's dash or a plain value
+}
+
+fn yaml_entries(text: &str) -> Vec<YamlEntry> {
+    let mut entries = Vec::new();
+    let mut line_start = 0;
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_end();
+        let mut indent = content.len() - content.trim_start().len();
+        let mut rest = content.trim_start();
+        while !rest.is_empty() && !rest.starts_with('#') && !rest.starts_with("---") {
+            if rest == "-" || rest.starts_with("- ") {
+                entries.push(YamlEntry { indent, offset: line_start + indent, key: None });
+                let after = rest[1..].trim_start();
+                indent += rest.len() - after.len();
+                rest = after;
+                continue;
+            }
+            let key = rest
+                .split_once(": ")
+                .map(|(key, _)| key)
+                .or_else(|| rest.strip_suffix(':'))
+                .map(|key| key.trim().trim_matches(|c| c == '"' || c == '\'').to_string());
+            entries.push(YamlEntry { indent, offset: line_start + indent, key });
+            break;
+        }
+        line_start += line.len();
+    }
+    entries
+}
+
+fn locate_yaml(text: &str, path: &[String]) -> usize {
+    let entries = yaml_entries(text);
+    let mut position = entries.first().map_or(0, |entry| entry.offset);
+    let mut next = 0;
+    let mut parent: Option<&YamlEntry> = None; // The last entry matched
+    for segment in path {
+        // A key's sequence items may sit at its own indent; anything else must be nested deeper
+        let in_scope = |entry: &YamlEntry| match parent {
+            None => true,
+            Some(parent) => {
+                entry.indent > parent.indent || (entry.indent == parent.indent && entry.key.is_none() && parent.key.is_some())
+            }
+        };
+        let mut scope_indent = None;
+        let mut items = 0;
+        let mut found = None;
+        for (index, entry) in entries.iter().enumerate().skip(next) {
+            if !in_scope(entry) {
+                break;
+            }
+            if *scope_indent.get_or_insert(entry.indent) != entry.indent {
+                continue;
+            }
+            let matches = match &entry.key {
+                Some(key) => key == segment,
+                None => {
+                    items += 1;
+                    segment.parse() == Ok(items - 1)
+                }
+            };
+            if matches {
+                found = Some(index);
+                break;
+            }
+        }
+        match found {
+            Some(index) => {
+                position = entries[index].offset;
+                parent = Some(&entries[index]);
+                next = index + 1;
+            }
+            None => break,
+        }
+    }
+    position
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_errors_report_line_and_column() {
+        let error = StructuredFormat::Json.parse("{\n  \"name\": \"pad\",\n  \"port\": 80,\n}").unwrap_err();
+        assert_eq!((error.line, error.column, error.severity.as_str()), (4, 1, "error"));
+
+        let error = StructuredFormat::Yaml.parse("name: pad\nports:\n  - 80\n  bad: [1, 2\n").unwrap_err();
+        assert_eq!(error.line, 4);
+        assert!(error.column > 1);
+        assert_eq!(StructuredFormat::Yaml.parse("name: pad\n").unwrap()["name"], "pad");
+    }
+
+    #[test]
+    fn test_schema_violation_points_at_offending_line() {
+        let schema = JSONSchema::compile(&serde_json::json!({
+            "type": "object",
+            "properties": { "servers": { "type": "array", "items": { "properties": { "port": { "type": "integer" } } } } }
+        }))
+        .unwrap();
+
+        let json = "{\n  \"servers\": [\n    { \"port\": 80 },\n    { \"name\": \"b\",\n      \"port\": \"eighty\" }\n  ]\n}";
+        let (value, diagnostics) = analyze(StructuredFormat::Json, json, Some(&schema));
+        assert!(value.is_some());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column, diagnostics[0].severity.as_str()), (5, 15, "warning"));
+        assert!(diagnostics[0].message.starts_with("/servers/1/port: "));
+
+        let yaml = "servers:\n- port: 80\n- name: b\n  port: eighty\n";
+        let (_, diagnostics) = analyze(StructuredFormat::Yaml, yaml, Some(&schema));
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (4, 3));
+    }
+}
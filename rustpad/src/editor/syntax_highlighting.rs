@@ -1,8 +1,17 @@
-use syntect::highlighting::{ThemeSet, HighlightLines, Style, Color};
+use std::collections::HashMap;
+use syntect::highlighting::{ThemeSet, Style, Color, FontStyle};
 use syntect::parsing::{SyntaxSet, SyntaxReference};
-use syntect::easy::HighlightFile;
-use syntect::util::{LinesWithEndings};
+use syntect::easy::HighlightLines;
 use crate::editor::state::EditorState;
+use crate::ui::renderer::HighlightedStyle;
+
+/// A styled byte range of one line, as the last highlight left it for the renderer
+#[derive(Clone)]
+pub struct HighlightedRegion {
+    pub start: usize,
+    pub end: usize,
+    pub style: HighlightedStyle,
+}
 
 pub struct SyntaxHighlighter {
     syntax_set: SyntaxSet,
@@ -26,27 +35,37 @@ impl SyntaxHighlighter {
         }
     }
 
-    /// Sets the programming language syntax for the highlighter (e.g., Rust, Python).
-    pub fn set_language(&mut self, file_extension: &str) {
-        self.syntax = self.syntax_set.find_syntax_by_extension(file_extension);
+    /// Sets the programming language syntax for the highlighter, by name or file extension
+    /// (e.g. "rust" or "rs"), as documents carry it. Unknown languages are plain text.
+    pub fn set_language(&mut self, language: &str) {
+        self.syntax = self.syntax_set.find_syntax_by_token(language).cloned();
     }
 
     /// Highlights the given text based on the current programming language and theme.
     /// This method will apply syntax highlighting to the EditorState's text.
     pub fn highlight(&self, state: &mut EditorState) {
+        // Clear previous highlights, so plain text shows none
+        state.clear_highlight();
+
         if let Some(syntax) = &self.syntax {
             let theme = &self.theme_set.themes[&self.theme_name];
             let mut highlighter = HighlightLines::new(syntax, theme);
 
-            // Get the document text from the editor state
-            let lines = state.get_text().lines();
-
-            // Clear previous highlights
-            state.clear_highlight();
+            // Get the document's lines from the editor state, which is updated below
+            let lines: Vec<String> = state.lines().map(|line| line.into_owned()).collect();
 
             // Apply syntax highlighting to each line
-            for (line_number, line) in lines.enumerate() {
-                let regions = highlighter.highlight_line(line, &self.syntax_set).unwrap();
+            for (line_number, line) in lines.iter().enumerate() {
+                let mut start = 0;
+                let regions = highlighter
+                    .highlight_line(line, &self.syntax_set)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(style, text)| {
+                        start += text.len();
+                        HighlightedRegion { start: start - text.len(), end: start, style: highlighted_style(style) }
+                    })
+                    .collect();
 
                 // Store the highlighted styles in the editor state
                 state.add_highlighted_line(line_number, regions);
@@ -54,11 +73,110 @@ impl SyntaxHighlighter {
         }
     }
 
-    /// Allows switching the theme of the syntax highlighting.
-    pub fn set_theme(&mut self, theme_name: &str) {
-        if self.theme_set.themes.contains_key(theme_name) {
-            self.theme_name = theme_name.to_string();
+    /// Highlights `text` line by line with the current language and theme, without touching
+    /// any editor state. Plain text yields no lines.
+    pub fn highlight_text(&self, text: &str) -> Vec<Vec<(Style, String)>> {
+        let Some(syntax) = &self.syntax else {
+            return Vec::new();
+        };
+        let mut highlighter = HighlightLines::new(syntax, &self.theme_set.themes[&self.theme_name]);
+        text.lines()
+            .map(|line| {
+                let regions = highlighter.highlight_line(line, &self.syntax_set).unwrap();
+                regions.into_iter().map(|(style, text)| (style, text.to_string())).collect()
+            })
+            .collect()
+    }
+
+    /// Allows switching the theme of the syntax highlighting. Unknown themes are rejected
+    /// and the current one kept.
+    pub fn set_theme(&mut self, theme_name: &str) -> Result<(), String> {
+        if !self.theme_set.themes.contains_key(theme_name) {
+            return Err(format!("Theme '{}' not found.", theme_name));
         }
+        self.theme_name = theme_name.to_string();
+        Ok(())
+    }
+
+    /// Name of the current highlight theme
+    pub fn theme_name(&self) -> &str {
+        &self.theme_name
+    }
+
+    /// Names of the loaded highlight themes, sorted, for a theme picker
+    pub fn theme_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.theme_set.themes.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Editor colors of the current highlight theme, as hex codes keyed like `ui::theme` colors
+    pub fn ui_colors(&self) -> HashMap<String, String> {
+        let settings = &self.theme_set.themes[&self.theme_name].settings;
+        [
+            ("background", settings.background),
+            ("text", settings.foreground),
+            ("caret", settings.caret),
+            ("selection", settings.selection),
+            ("line_highlight", settings.line_highlight),
+        ]
+        .into_iter()
+        .filter_map(|(name, color)| color.map(|color| (name.to_string(), hex(color))))
+        .collect()
+    }
+}
+
+impl Default for SyntaxHighlighter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn highlighted_style(style: Style) -> HighlightedStyle {
+    HighlightedStyle {
+        color: hex(style.foreground),
+        bold: style.font_style.contains(FontStyle::BOLD),
+        italic: style.font_style.contains(FontStyle::ITALIC),
     }
 }
 
+fn hex(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_switching_theme_changes_highlight_colors() {
+        let mut highlighter = SyntaxHighlighter::new();
+        highlighter.set_language("rs");
+        let colors = |highlighter: &SyntaxHighlighter| -> Vec<Color> {
+            highlighter.highlight_text("fn main() { let s = \"pad\"; }")[0].iter().map(|(style, _)| style.foreground).collect()
+        };
+
+        let dark = colors(&highlighter);
+        highlighter.set_theme("InspiredGitHub").unwrap();
+        let light = colors(&highlighter);
+        assert_eq!(dark.len(), light.len());
+        assert_ne!(dark, light);
+        assert_ne!(highlighter.ui_colors()["background"], "#2b303b");
+
+        assert!(highlighter.set_theme("No Such Theme").is_err());
+        assert_eq!((highlighter.theme_name(), colors(&highlighter)), ("InspiredGitHub", light));
+    }
+
+    #[test]
+    fn test_language_by_name_or_extension() {
+        let mut highlighter = SyntaxHighlighter::new();
+        highlighter.set_language("rust");
+        let by_name = highlighter.highlight_text("fn main() {}");
+        highlighter.set_language("rs");
+        assert_eq!(highlighter.highlight_text("fn main() {}"), by_name);
+        assert!(by_name[0].len() > 1);
+
+        highlighter.set_language("no-such-language");
+        assert!(highlighter.highlight_text("fn main() {}").is_empty());
+    }
+}
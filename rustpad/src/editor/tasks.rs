@@ -0,0 +1,406 @@
+use serde::{Deserialize, Serialize};
+use crate::editor::comments::{comment_tokens, CommentTokens};
+use crate::editor::diff_engine::DiffOperation;
+
+/// What kind of item a task was written as.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskKind {
+    Todo,     // `TODO` in a code comment
+    Fixme,    // `FIXME` in a code comment
+    Checkbox, // A markdown task-list item, `- [ ]` or `- [x]`
+}
+
+/// A task found in a document.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Task {
+    pub id: String,   // Hash of kind and text, stable while other lines change
+    pub kind: TaskKind,
+    pub line: usize,  // Zero-based
+    pub text: String,
+    pub done: bool,   // Only checkboxes can be done
+    pub assignee: Option<String>, // From `TODO(name)` or the first `@name` mention
+}
+
+/// Lines replaced by an edit: `removed` lines from `start` became `inserted` lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineChange {
+    pub start: usize,
+    pub removed: usize,
+    pub inserted: usize,
+}
+
+impl LineChange {
+    /// The lines that differ between `old` and `new`, found from their common prefix and suffix.
+    pub fn between(old: &str, new: &str) -> Self {
+        let prefix = old.bytes().zip(new.bytes()).take_while(|(a, b)| a == b).count();
+        let max_suffix = old.len().min(new.len()) - prefix;
+        let suffix = old.bytes().rev().zip(new.bytes().rev()).take(max_suffix).take_while(|(a, b)| a == b).count();
+
+        let newlines = |text: &str, end: usize| text.as_bytes()[..end].iter().filter(|byte| **byte == b'\n').count();
+        let start = newlines(old, prefix);
+        Self {
+            start,
+            removed: newlines(old, old.len() - suffix) + 1 - start,
+            inserted: newlines(new, new.len() - suffix) + 1 - start,
+        }
+    }
+}
+
+/// Where a line starts. Code carries its comment syntax, since fenced blocks in markdown
+/// each have their own, and whether a comment or string is still open from an earlier line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scope {
+    Prose, // Markdown outside code fences
+    Code { tokens: CommentTokens, fenced: bool, open: Open },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Open {
+    Nothing,
+    BlockComment,
+    Str,
+}
+
+/// A task before ids are assigned.
+#[derive(Debug, Clone, PartialEq)]
+struct Found {
+    kind: TaskKind,
+    text: String,
+    done: bool,
+    assignee: Option<String>,
+    checkbox: Option<usize>, // Byte offset of a checkbox's `[` within its line
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+    scope: Scope, // Scope at the start of the line
+    tasks: Vec<Found>,
+}
+
+/// Keeps the tasks of a document, rescanning only lines an edit touched. Code files yield their
+/// `TODO` and `FIXME` comments; markdown yields task-list items and comments in fenced code.
+pub struct TaskExtractor {
+    initial: Scope,
+    lines: Vec<Line>,
+}
+
+impl TaskExtractor {
+    /// Creates an extractor for `text` written in `language`, given by name or file extension.
+    pub fn new(language: &str, text: &str) -> Self {
+        let initial = match language.to_lowercase().as_str() {
+            "markdown" | "md" => Scope::Prose,
+            _ => Scope::Code { tokens: comment_tokens(language), fenced: false, open: Open::Nothing },
+        };
+        let mut extractor = Self { initial, lines: Vec::new() };
+        extractor.update(text, LineChange { start: 0, removed: 0, inserted: text.split('\n').count() });
+        extractor
+    }
+
+    /// Rescans the lines `change` describes in the edited `text`, then the lines after them for
+    /// as long as the edit changed the scope they start in (e.g. by opening a block comment).
+    pub fn update(&mut self, text: &str, change: LineChange) {
+        let lines: Vec<&str> = text.split('\n').collect();
+        let start = change.start.min(self.lines.len());
+        let removed = change.removed.min(self.lines.len() - start);
+        let placeholder = Line { scope: self.initial, tasks: Vec::new() };
+        self.lines.splice(start..start + removed, std::iter::repeat_n(placeholder.clone(), change.inserted));
+        self.lines.resize(lines.len(), placeholder);
+
+        let mut scope = match start {
+            0 => self.initial,
+            _ => scan_line(lines[start - 1], self.lines[start - 1].scope).0,
+        };
+        for (index, line) in lines.iter().enumerate().skip(start) {
+            if index >= start + change.inserted && self.lines[index].scope == scope {
+                break; // Unchanged from here on
+            }
+            let (next, tasks) = scan_line(line, scope);
+            self.lines[index] = Line { scope, tasks };
+            scope = next;
+        }
+    }
+
+    /// All tasks in document order. Ids hash the kind and text, plus how many earlier tasks share
+    /// both, so editing or moving other lines leaves them alone.
+    pub fn tasks(&self) -> Vec<Task> {
+        let mut seen: Vec<(TaskKind, &str)> = Vec::new();
+        let mut tasks = Vec::new();
+        for (line, entry) in self.lines.iter().enumerate() {
+            for found in &entry.tasks {
+                let occurrence = seen.iter().filter(|(kind, text)| *kind == found.kind && *text == found.text).count();
+                seen.push((found.kind, &found.text));
+                tasks.push(Task {
+                    id: task_id(found.kind, &found.text, occurrence),
+                    kind: found.kind,
+                    line,
+                    text: found.text.clone(),
+                    done: found.done,
+                    assignee: found.assignee.clone(),
+                });
+            }
+        }
+        tasks
+    }
+
+    /// The edit that checks or unchecks checkbox task `id` in `text`, the document this
+    /// extractor was last updated with.
+    pub fn toggle(&self, text: &str, id: &str) -> Result<DiffOperation, String> {
+        let task = self.tasks().into_iter().find(|task| task.id == id).ok_or_else(|| format!("Unknown task {}", id))?;
+        let offset = match task.kind {
+            TaskKind::Checkbox => self.lines[task.line].tasks[0].checkbox.unwrap_or(0), // One checkbox per line
+            _ => return Err("Only checkbox tasks can be toggled".to_string()),
+        };
+
+        let line_start: usize = text.split('\n').take(task.line).map(|line| line.len() + 1).sum();
+        let start = line_start + offset;
+        Ok(DiffOperation::Replace(start, start + 3, if task.done { "[ ]" } else { "[x]" }.to_string()))
+    }
+}
+
+/// Scans one line starting in `scope`, returning the scope the next line starts in and the
+/// tasks found on it.
+fn scan_line(line: &str, scope: Scope) -> (Scope, Vec<Found>) {
+    let fence = line.trim_start().strip_prefix("```");
+    match (scope, fence) {
+        (Scope::Prose, Some(info)) => {
+            let tokens = comment_tokens(info.split_whitespace().next().unwrap_or(""));
+            (Scope::Code { tokens, fenced: true, open: Open::Nothing }, Vec::new())
+        }
+        (Scope::Prose, None) => (Scope::Prose, checkbox(line).into_iter().collect()),
+        (Scope::Code { fenced: true, .. }, Some(_)) => (Scope::Prose, Vec::new()),
+        (Scope::Code { tokens, fenced, open }, _) => {
+            let (open, tasks) = scan_code(line, tokens, open);
+            (Scope::Code { tokens, fenced, open }, tasks)
+        }
+    }
+}
+
+/// Splits a line of code into comments, strings and the rest, and looks for tasks in the
+/// comments only. Strings are double-quoted and may span lines.
+fn scan_code(line: &str, tokens: CommentTokens, mut open: Open) -> (Open, Vec<Found>) {
+    let mut tasks = Vec::new();
+    let mut rest = line;
+    loop {
+        match open {
+            Open::Nothing => {
+                let line_comment = tokens.line.and_then(|token| rest.find(token).map(|at| (at, at + token.len(), Open::Nothing)));
+                let block_comment = tokens.block.and_then(|(start, _)| rest.find(start).map(|at| (at, at + start.len(), Open::BlockComment)));
+                let quote = rest.find('"').map(|at| (at, at + 1, Open::Str));
+                match [line_comment, block_comment, quote].into_iter().flatten().min_by_key(|(at, _, _)| *at) {
+                    None => break,
+                    Some((_, after, Open::Nothing)) => {
+                        tasks.extend(comment_task(&rest[after..]));
+                        break;
+                    }
+                    Some((_, after, next)) => {
+                        open = next;
+                        rest = &rest[after..];
+                    }
+                }
+            }
+            Open::BlockComment => {
+                let end = tokens.block.and_then(|(_, end)| rest.find(end).map(|at| (at, at + end.len())));
+                tasks.extend(comment_task(&rest[..end.map_or(rest.len(), |(at, _)| at)]));
+                match end {
+                    Some((_, after)) => {
+                        open = Open::Nothing;
+                        rest = &rest[after..];
+                    }
+                    None => break,
+                }
+            }
+            Open::Str => {
+                let mut chars = rest.char_indices();
+                let mut end = None;
+                while let Some((at, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => {
+                            end = Some(at + 1);
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                match end {
+                    Some(after) => {
+                        open = Open::Nothing;
+                        rest = &rest[after..];
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+    (open, tasks)
+}
+
+/// The first `TODO` or `FIXME` in a comment's text, as in `TODO: text`, `TODO(name): text`
+/// or `FIXME text`.
+fn comment_task(comment: &str) -> Option<Found> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let (at, kind, marker) = [("TODO", TaskKind::Todo), ("FIXME", TaskKind::Fixme)]
+        .into_iter()
+        .flat_map(|(marker, kind)| comment.match_indices(marker).map(move |(at, _)| (at, kind, marker)))
+        .filter(|(at, _, marker)| {
+            let before = comment[..*at].chars().next_back();
+            let after = comment[at + marker.len()..].chars().next();
+            !before.is_some_and(is_word) && !after.is_some_and(is_word)
+        })
+        .min_by_key(|(at, _, _)| *at)?;
+
+    let mut rest = &comment[at + marker.len()..];
+    let mut assignee = None;
+    if let Some((name, after)) = rest.strip_prefix('(').and_then(|inner| inner.split_once(')')) {
+        assignee = Some(name.trim().trim_start_matches('@').to_string()).filter(|name| !name.is_empty());
+        rest = after;
+    }
+    let text = rest.trim_start_matches(':').trim().to_string();
+    let assignee = assignee.or_else(|| mention(&text));
+    Some(Found { kind, text, done: false, assignee, checkbox: None })
+}
+
+/// A markdown task-list item: a bullet or numbered item starting with `[ ]`, `[x]` or `[X]`.
+fn checkbox(line: &str) -> Option<Found> {
+    let item = line.trim_start();
+    let after_bullet = match item.strip_prefix(['-', '*', '+']) {
+        Some(rest) => rest,
+        None => {
+            let digits = item.len() - item.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            item[digits..].strip_prefix(['.', ')']).filter(|_| digits > 0)?
+        }
+    };
+    let boxed = after_bullet.strip_prefix(' ')?.trim_start();
+    let done = match boxed.get(..3)? {
+        "[ ]" => false,
+        "[x]" | "[X]" => true,
+        _ => return None,
+    };
+    let text = &boxed[3..];
+    if !(text.is_empty() || text.starts_with(' ')) {
+        return None;
+    }
+    let text = text.trim().to_string();
+    let assignee = mention(&text);
+    Some(Found { kind: TaskKind::Checkbox, text, done, assignee, checkbox: Some(line.len() - boxed.len()) })
+}
+
+/// The first `@name` mention in `text`, without the `@`.
+fn mention(text: &str) -> Option<String> {
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-')))
+        .find(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+/// FNV-1a over the kind, text and occurrence: deterministic across runs and platforms, unlike
+/// the standard library's hasher.
+fn task_id(kind: TaskKind, text: &str, occurrence: usize) -> String {
+    let key = format!("{:?}\0{}\0{}", kind, text, occurrence);
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::diff_engine::DiffEngine;
+
+    const NOTES: &str = "# Release\n\
+        - [ ] Write changelog @dana\n\
+        - [x] Tag the build\n\
+        Not a task: [ ] here\n\
+        ```rust\n\
+        // TODO: handle resize\n\
+        let s = \"TODO: not a task\"; /* FIXME(sam): leaks\n\
+        still a comment */ let t = \"// TODO nope\";\n\
+        ```\n\
+        1. [ ] Announce";
+
+    fn summary(extractor: &TaskExtractor) -> Vec<(TaskKind, usize, String, bool)> {
+        extractor.tasks().into_iter().map(|task| (task.kind, task.line, task.text, task.done)).collect()
+    }
+
+    /// Applies `operation` to `text` and updates `extractor` with the result.
+    fn edit(extractor: &mut TaskExtractor, text: &str, operation: DiffOperation) -> String {
+        let edited = DiffEngine::apply(text, &[operation]);
+        extractor.update(&edited, LineChange::between(text, &edited));
+        edited
+    }
+
+    #[test]
+    fn test_extracts_from_markdown_and_fenced_code() {
+        let extractor = TaskExtractor::new("md", NOTES);
+        let s = str::to_string;
+        assert_eq!(
+            summary(&extractor),
+            vec![
+                (TaskKind::Checkbox, 1, s("Write changelog @dana"), false),
+                (TaskKind::Checkbox, 2, s("Tag the build"), true),
+                (TaskKind::Todo, 5, s("handle resize"), false),
+                (TaskKind::Fixme, 6, s("leaks"), false),
+                (TaskKind::Checkbox, 9, s("Announce"), false),
+            ]
+        );
+
+        // In a code file the markdown lines are just text, and strings never hold tasks
+        let rust = "fn main() {\n    let s = \"TODO: no\"; // FIXME: yes\n    let t = \"\\\" // TODO: escaped\";\n}";
+        let extractor = TaskExtractor::new("rs", rust);
+        assert_eq!(summary(&extractor), vec![(TaskKind::Fixme, 1, s("yes"), false)]);
+    }
+
+    #[test]
+    fn test_ids_stable_when_other_lines_change() {
+        let mut extractor = TaskExtractor::new("md", NOTES);
+        let before: Vec<String> = extractor.tasks().into_iter().map(|task| task.id).collect();
+
+        // Insert lines above, edit an unrelated line, and open then close a comment in the fence
+        let mut text = edit(&mut extractor, NOTES, DiffOperation::Insert(0, "Intro\n\n".to_string()));
+        text = edit(&mut extractor, &text, DiffOperation::Replace(7, 16, "# Launch".to_string()));
+        let fence = text.find("```rust\n").unwrap() + 8;
+        text = edit(&mut extractor, &text, DiffOperation::Insert(fence, "/*\n".to_string()));
+        assert!(extractor.tasks().iter().any(|task| task.text.starts_with("not a task"))); // The string is commented out now
+        text = edit(&mut extractor, &text, DiffOperation::Delete(fence, fence + 3));
+
+        let after: Vec<String> = extractor.tasks().into_iter().map(|task| task.id).collect();
+        assert_eq!(after, before);
+        assert_eq!(extractor.tasks(), TaskExtractor::new("md", &text).tasks()); // Incremental matches a full scan
+
+        // Editing a task's own text gives it a new id; the others keep theirs
+        let at = text.find("Tag the build").unwrap();
+        edit(&mut extractor, &text, DiffOperation::Insert(at, "Re-".to_string()));
+        let edited: Vec<String> = extractor.tasks().into_iter().map(|task| task.id).collect();
+        assert_eq!(edited.iter().zip(&before).filter(|(a, b)| a != b).count(), 1);
+    }
+
+    #[test]
+    fn test_toggle_checkbox() {
+        let mut extractor = TaskExtractor::new("md", NOTES);
+        let task = extractor.tasks().remove(0);
+        let operation = extractor.toggle(NOTES, &task.id).unwrap();
+        assert_eq!(operation, DiffOperation::Replace(12, 15, "[x]".to_string()));
+
+        let text = edit(&mut extractor, NOTES, operation);
+        assert!(text.contains("- [x] Write changelog"));
+        let toggled = extractor.tasks().remove(0);
+        assert_eq!((toggled.id.as_str(), toggled.done), (task.id.as_str(), true));
+        assert_eq!(extractor.toggle(&text, &task.id), Ok(DiffOperation::Replace(12, 15, "[ ]".to_string())));
+
+        let todo = extractor.tasks().into_iter().find(|task| task.kind == TaskKind::Todo).unwrap();
+        assert!(extractor.toggle(&text, &todo.id).is_err());
+        assert!(extractor.toggle(&text, "0000000000000000").is_err());
+    }
+
+    #[test]
+    fn test_assignees() {
+        let text = "// TODO(alice): review\n// FIXME: ping @bob, then @carol\n// TODO: nobody\n// TODO(@dave) x";
+        let assignees: Vec<Option<String>> = TaskExtractor::new("rs", text).tasks().into_iter().map(|task| task.assignee).collect();
+        let name = |name: &str| Some(name.to_string());
+        assert_eq!(assignees, vec![name("alice"), name("bob"), None, name("dave")]);
+        assert_eq!(TaskExtractor::new("md", "* [ ] ship it @erin.").tasks()[0].assignee, name("erin"));
+    }
+}
@@ -0,0 +1,191 @@
+use crate::editor::config::{IndentStyle, TypingRuleOptions};
+
+/// Python statements after which the block ends, so the next line is dedented
+const BLOCK_ENDERS: [&str; 5] = ["return", "pass", "break", "continue", "raise"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RuleSet {
+    Braces, // Rust, JavaScript and other C-like languages
+    Python,
+    Markdown,
+    Plain,
+}
+
+/// Lightweight on-type formatting for a language. Each rule looks only at the cursor's line,
+/// or walks back to the enclosing block for `else`, so it is cheap enough for every keystroke.
+/// Rules return a replacement that covers the typed text, so the editor applies both as one edit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TypingRules {
+    rule_set: RuleSet,
+    options: TypingRuleOptions,
+}
+
+impl TypingRules {
+    /// Creates the rules for `language`, as enabled by `options`. Languages without rules
+    /// only get trailing whitespace trimmed.
+    pub fn new(language: &str, options: TypingRuleOptions) -> Self {
+        let rule_set = match language.to_lowercase().as_str() {
+            "rust" | "rs" | "javascript" | "js" | "jsx" | "typescript" | "ts" | "tsx" | "c" | "h" | "cpp" | "java" | "go" | "css" => RuleSet::Braces,
+            "python" | "py" => RuleSet::Python,
+            "markdown" | "md" => RuleSet::Markdown,
+            _ => RuleSet::Plain,
+        };
+        Self { rule_set, options }
+    }
+
+    /// Replacement for typing `typed` at `position`, as (start, end, text) with `typed` included,
+    /// or `None` to insert it as is. Dedents a `}` typed on a blank line, and `else` and its kin
+    /// to their `if`.
+    pub fn on_type(&self, text: &str, position: usize, typed: &str, indent_style: IndentStyle) -> Option<(usize, usize, String)> {
+        if !self.options.auto_dedent {
+            return None;
+        }
+        let line_start = text[..position].rfind('\n').map_or(0, |newline| newline + 1);
+        let before = &text[line_start..position];
+        let word = before.trim_start();
+        let indent = &before[..before.len() - word.len()];
+
+        let target = match (self.rule_set, typed) {
+            (RuleSet::Braces, "}") if word.is_empty() => dedent(indent, indent_style),
+            (RuleSet::Braces, " " | "{") if word == "else" => enclosing_indent(text, line_start, indent, indent_style)?,
+            (RuleSet::Python, ":") if matches!(word, "else" | "finally" | "except") || word.starts_with("elif ") || word.starts_with("except ") => {
+                enclosing_indent(text, line_start, indent, indent_style)?
+            }
+            _ => return None,
+        };
+        (target != indent).then(|| (line_start, position, format!("{}{}{}", target, word, typed)))
+    }
+
+    /// Replacement for Enter at `position`: a newline indented like the current line, one level
+    /// deeper after a Python `:` or shallower after `return` and the like, continuing a Markdown
+    /// list item. Trailing whitespace before the cursor is removed with it. Enter on an empty
+    /// list item removes its marker instead, ending the list.
+    pub fn on_newline(&self, text: &str, position: usize, indent_style: IndentStyle) -> (usize, usize, String) {
+        let line_start = text[..position].rfind('\n').map_or(0, |newline| newline + 1);
+        let before = &text[line_start..position];
+        let content = before.trim();
+        let indent = &before[..before.len() - before.trim_start().len()];
+        let mut start = position;
+        if self.options.trim_trailing_whitespace && self.rule_set != RuleSet::Markdown {
+            start = line_start + before.trim_end_matches([' ', '\t']).len(); // Markdown uses trailing spaces as line breaks
+        }
+
+        let mut next_indent = indent.to_string();
+        match self.rule_set {
+            RuleSet::Python if self.options.auto_dedent => {
+                let statement = content.split(|c: char| !c.is_alphanumeric() && c != '_').next().unwrap_or("");
+                if content.ends_with(':') {
+                    next_indent.push_str(&indent_style.to_next_stop(0));
+                } else if BLOCK_ENDERS.contains(&statement) {
+                    next_indent = dedent(indent, indent_style);
+                }
+            }
+            RuleSet::Markdown if self.options.list_continuation => {
+                if let Some((marker_len, continuation)) = list_marker(&before[indent.len()..]) {
+                    let rest_of_line = text[position..].split('\n').next().unwrap_or("");
+                    if before[indent.len() + marker_len..].trim().is_empty() && rest_of_line.trim().is_empty() {
+                        return (line_start, position, String::new());
+                    }
+                    next_indent.push_str(&continuation);
+                }
+            }
+            _ => {}
+        }
+        (start, position, format!("\n{}", next_indent))
+    }
+}
+
+/// `indent` less one level: a tab, or the spaces back to the previous tab stop
+fn dedent(indent: &str, indent_style: IndentStyle) -> String {
+    if let Some(rest) = indent.strip_suffix('\t') {
+        return rest.to_string();
+    }
+    let spaces = indent.len() - indent.trim_end_matches(' ').len();
+    let remove = match spaces % indent_style.width() {
+        0 => indent_style.width().min(spaces),
+        partial => partial,
+    };
+    indent[..indent.len() - remove].to_string()
+}
+
+/// Indentation of the nearest non-blank line above `line_start` that is indented less than
+/// `indent`, which is where an `else` belongs
+fn enclosing_indent(text: &str, line_start: usize, indent: &str, indent_style: IndentStyle) -> Option<String> {
+    let width = |indent: &str| indent.chars().map(|c| if c == '\t' { indent_style.width() } else { 1 }).sum::<usize>();
+    let current = width(indent);
+    text[..line_start].lines().rev().filter(|line| !line.trim().is_empty()).find_map(|line| {
+        let line_indent = &line[..line.len() - line.trim_start().len()];
+        (width(line_indent) < current).then(|| line_indent.to_string())
+    })
+}
+
+/// Length of the list marker `line` starts with, such as `- `, `1. ` or `- [x] `, and the
+/// marker that continues the list on the next line
+fn list_marker(line: &str) -> Option<(usize, String)> {
+    let (len, continuation) = if line.starts_with("- ") || line.starts_with("* ") || line.starts_with("+ ") {
+        (2, line[..2].to_string())
+    } else {
+        let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let punctuation = line[digits..].chars().next().filter(|c| *c == '.' || *c == ')')?;
+        if digits == 0 || digits > 9 || !line[digits + 1..].starts_with(' ') {
+            return None;
+        }
+        let number: u64 = line[..digits].parse().ok()?;
+        (digits + 2, format!("{}{} ", number + 1, punctuation))
+    };
+    match &line[len..] {
+        rest if rest.starts_with("[ ] ") || rest.starts_with("[x] ") || rest.starts_with("[X] ") => Some((len + 4, continuation + "[ ] ")),
+        _ => Some((len, continuation)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPACES: IndentStyle = IndentStyle::Spaces(4);
+
+    fn rules(language: &str) -> TypingRules {
+        TypingRules::new(language, TypingRuleOptions::default())
+    }
+
+    fn type_at_end(rules: TypingRules, text: &str, typed: &str) -> Option<(usize, usize, String)> {
+        rules.on_type(text, text.len(), typed, SPACES)
+    }
+
+    #[test]
+    fn test_brace_and_else_dedent() {
+        let js = rules("javascript");
+        assert_eq!(type_at_end(js, "if (a) {\n    b();\n    ", "}"), Some((18, 22, "}".to_string())));
+        assert_eq!(type_at_end(js, "if (a) {\n    b();\n  x", "}"), None); // Not on a blank line
+        assert_eq!(type_at_end(js, "if (a)\n    b();\n    else", " "), Some((16, 24, "else ".to_string())));
+        assert_eq!(type_at_end(js, "if (a) {\n}\nelse", "{"), None); // Already where it belongs
+        assert_eq!(rules("rs").on_type("{\n\t\t", 4, "}", IndentStyle::Tabs), Some((2, 4, "\t}".to_string())));
+    }
+
+    #[test]
+    fn test_python_block_dedent() {
+        let py = rules("python");
+        assert_eq!(py.on_newline("def f(x):", 9, SPACES), (9, 9, "\n    ".to_string()));
+        assert_eq!(py.on_newline("def f(x):\n    if x:\n        return 1", 36, SPACES), (36, 36, "\n    ".to_string()));
+        assert_eq!(py.on_newline("def f(x):\n    pass  ", 20, SPACES), (18, 20, "\n".to_string()));
+        assert_eq!(py.on_newline("def f(x):\n    returned = 1", 25, SPACES).2, "\n    ");
+        assert_eq!(type_at_end(py, "if x:\n    y()\n    else", ":"), Some((14, 22, "else:".to_string())));
+        assert_eq!(type_at_end(py, "if x:\n    y()\n    elif z", ":"), Some((14, 24, "elif z:".to_string())));
+    }
+
+    #[test]
+    fn test_markdown_list_continuation_and_termination() {
+        let md = rules("md");
+        assert_eq!(md.on_newline("- one", 5, SPACES), (5, 5, "\n- ".to_string()));
+        assert_eq!(md.on_newline("  9. nine", 9, SPACES), (9, 9, "\n  10. ".to_string()));
+        assert_eq!(md.on_newline("- [x] done", 10, SPACES), (10, 10, "\n- [ ] ".to_string()));
+        assert_eq!(md.on_newline("line  ", 6, SPACES), (6, 6, "\n".to_string())); // Trailing spaces are a line break
+        assert_eq!(md.on_newline("- one\n- ", 8, SPACES), (6, 8, String::new()));
+        assert_eq!(md.on_newline("- one\n- [ ] ", 12, SPACES), (6, 12, String::new()));
+        assert_eq!(md.on_newline("- one\n- two", 8, SPACES), (8, 8, "\n- ".to_string())); // Splits the item
+
+        let options = TypingRuleOptions { list_continuation: false, ..TypingRuleOptions::default() };
+        assert_eq!(TypingRules::new("md", options).on_newline("- one", 5, SPACES), (5, 5, "\n".to_string()));
+    }
+}
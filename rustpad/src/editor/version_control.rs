@@ -1,13 +1,99 @@
-use crate::editor::state::EditorState;
-use std::collections::VecDeque;
+use crate::editor::diff_engine::{DiffEngine, DiffOperation};
+use crate::editor::state::{Cursor, EditorState};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// Name of the branch every `VersionControl` starts on.
+pub const DEFAULT_BRANCH: &str = "main";
+
+/// A history entry: the diff that turns the neighbouring state back into the recorded one,
+/// plus the cursors and selections of the recorded state.
+#[derive(Clone, Serialize, Deserialize)]
+struct Change {
+    operations: Vec<DiffOperation>,
+    cursor_position: usize,
+    selection: Option<(usize, usize)>,
+    secondary_cursors: Vec<Cursor>,
+}
+
+impl Change {
+    /// Records how to get back to `target` from `from`.
+    fn between(from: &EditorState, target: &EditorState) -> Self {
+        Self {
+            operations: DiffEngine::diff(&from.get_text(), &target.get_text()),
+            cursor_position: target.get_cursor_position(),
+            selection: target.get_selection_range(),
+            secondary_cursors: target.secondary_cursors().to_vec(),
+        }
+    }
+
+    /// Rebuilds the recorded state from the neighbouring one.
+    fn restore(&self, from: &EditorState) -> EditorState {
+        let mut state = EditorState::new();
+        state.replace_text(DiffEngine::apply(&from.get_text(), &self.operations));
+        state.move_cursor(self.cursor_position);
+        if let Some((start, end)) = self.selection {
+            state.set_selection(start, end);
+        }
+        state.set_secondary_cursors(self.secondary_cursors.clone());
+        state
+    }
+
+    /// Approximate size of this entry in bytes.
+    fn size_in_bytes(&self) -> usize {
+        let payload: usize = self
+            .operations
+            .iter()
+            .map(|operation| match operation {
+                DiffOperation::Insert(_, text) | DiffOperation::Replace(_, _, text) => text.capacity(),
+                DiffOperation::Delete(_, _) => 0,
+            })
+            .sum();
+        std::mem::size_of::<Change>()
+            + self.operations.capacity() * std::mem::size_of::<DiffOperation>()
+            + self.secondary_cursors.capacity() * std::mem::size_of::<Cursor>()
+            + payload
+    }
+}
+
+/// The saved history of a branch that is not currently active.
+struct Branch {
+    undo_stack: VecDeque<Change>,
+    redo_stack: VecDeque<Change>,
+    head: EditorState, // The editor state when the branch was last active
+}
+
+/// The history of every branch, in a form that can be handed to another device.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistorySnapshot {
+    undo_stack: VecDeque<Change>,
+    redo_stack: VecDeque<Change>,
+    max_history: usize,
+    active_branch: String,
+    branches: Vec<BranchSnapshot>, // Inactive branches, sorted by name
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct BranchSnapshot {
+    name: String,
+    undo_stack: VecDeque<Change>,
+    redo_stack: VecDeque<Change>,
+    head: Change, // Rebuilds the branch head from an empty document
+}
 
 /// `VersionControl` is responsible for managing the undo/redo stack and tracking
 /// changes to the document's state. It allows users to revert to previous states
-/// and redo changes after undo operations.
+/// and redo changes after undo operations. Each named branch keeps its own history.
+///
+/// Only the diffs between neighbouring states are kept, so each entry costs roughly
+/// the size of its edit rather than the size of the document.
 pub struct VersionControl {
-    undo_stack: VecDeque<EditorState>,  // Stack to hold states for undo
-    redo_stack: VecDeque<EditorState>,  // Stack to hold states for redo
+    undo_stack: VecDeque<Change>,       // Diffs back to earlier states, newest last
+    redo_stack: VecDeque<Change>,       // Diffs forward to undone states, newest last
+    pending: Option<EditorState>,       // Last tracked state, until the edit after it is known
     max_history: usize,                 // Maximum number of states to store
+    active_branch: String,              // Branch the undo/redo stacks belong to
+    branches: HashMap<String, Branch>,  // Inactive branches keyed by name
 }
 
 impl VersionControl {
@@ -16,19 +102,19 @@ impl VersionControl {
         Self {
             undo_stack: VecDeque::new(),
             redo_stack: VecDeque::new(),
+            pending: None,
             max_history: 100,  // Default max history states
+            active_branch: DEFAULT_BRANCH.to_string(),
+            branches: HashMap::new(),
         }
     }
 
-    /// Tracks changes by storing the current state of the editor in the undo stack.
+    /// Tracks changes by recording the current state of the editor, before it is edited.
     /// Clears the redo stack since new changes invalidate the redo history.
     pub fn track_change(&mut self, state: &EditorState) {
-        if self.undo_stack.len() == self.max_history {
-            self.undo_stack.pop_front();  // Remove the oldest state to maintain history limit
-        }
-
-        // Push the current state onto the undo stack
-        self.undo_stack.push_back(state.clone());
+        // The state tracked last time can now be stored as a diff from this one
+        self.settle(state);
+        self.pending = Some(state.clone());
 
         // Clear the redo stack because a new change invalidates the redo history
         self.redo_stack.clear();
@@ -36,12 +122,22 @@ impl VersionControl {
 
     /// Undoes the last change by reverting to the previous state in the undo stack.
     /// Moves the current state to the redo stack to enable redoing the action.
+    /// The cursor is placed after the text the undo restores (at the edit point for an
+    /// undone insertion); the selection recorded with the state is kept. Multi-cursor states keep
+    /// their recorded cursors, since a single edit point doesn't describe them.
     pub fn undo(&mut self, current_state: &EditorState) -> Option<EditorState> {
-        if let Some(previous_state) = self.undo_stack.pop_back() {
+        self.settle(current_state);
+        if let Some(change) = self.undo_stack.pop_back() {
+            let mut previous_state = change.restore(current_state);
+
             // Move the current state to the redo stack
-            self.redo_stack.push_back(current_state.clone());
+            self.redo_stack.push_back(Change::between(&previous_state, current_state));
 
-            // Return the previous state for reverting
+            // Return the previous state for reverting, with the cursor at the edit
+            if previous_state.secondary_cursors().is_empty() {
+                let (_, edit_end) = edit_location(&current_state.get_text(), &previous_state.get_text(), edit_hint(&previous_state));
+                previous_state.move_cursor(edit_end);
+            }
             return Some(previous_state);
         }
         None
@@ -49,12 +145,19 @@ impl VersionControl {
 
     /// Redoes the last undone change by restoring the next state in the redo stack.
     /// Moves the current state back to the undo stack.
+    /// The cursor is placed after the re-applied edit; the selection is kept.
     pub fn redo(&mut self, current_state: &EditorState) -> Option<EditorState> {
-        if let Some(next_state) = self.redo_stack.pop_back() {
+        if let Some(change) = self.redo_stack.pop_back() {
+            let mut next_state = change.restore(current_state);
+
             // Move the current state back to the undo stack
-            self.undo_stack.push_back(current_state.clone());
+            self.push_undo(Change::between(&next_state, current_state));
 
-            // Return the next state for redoing
+            // Return the next state for redoing, with the cursor after the edit
+            if next_state.secondary_cursors().is_empty() {
+                let (_, edit_end) = edit_location(&current_state.get_text(), &next_state.get_text(), edit_hint(current_state));
+                next_state.move_cursor(edit_end);
+            }
             return Some(next_state);
         }
         None
@@ -65,10 +168,352 @@ impl VersionControl {
         self.max_history = max_history;
     }
 
-    /// Clears all stored history for undo and redo actions.
+    /// Clears all stored history for undo and redo actions on the active branch.
     pub fn clear_history(&mut self) {
         self.undo_stack.clear();
         self.redo_stack.clear();
+        self.pending = None;
+    }
+
+    /// Approximate memory held by the active branch's history, in bytes.
+    pub fn history_size_in_bytes(&self) -> usize {
+        let pending = self.pending.as_ref().map_or(0, |state| state.text_len());
+        self.undo_stack.iter().chain(self.redo_stack.iter()).map(Change::size_in_bytes).sum::<usize>() + pending
+    }
+
+    /// Creates a branch named `name` that forks from `current_state`, sharing the
+    /// active branch's history up to this point. The active branch is unchanged.
+    pub fn create_branch(&mut self, name: &str, current_state: &EditorState) -> Result<(), String> {
+        if name == self.active_branch || self.branches.contains_key(name) {
+            return Err(format!("Branch '{}' already exists", name));
+        }
+
+        self.settle(current_state);
+        self.branches.insert(
+            name.to_string(),
+            Branch {
+                undo_stack: self.undo_stack.clone(),
+                redo_stack: self.redo_stack.clone(),
+                head: current_state.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Makes `name` the active branch. `current_state` is saved as the head of the branch
+    /// being left, and the state to load into the editor is returned.
+    pub fn switch_branch(&mut self, name: &str, current_state: &EditorState) -> Result<EditorState, String> {
+        if name == self.active_branch {
+            return Ok(current_state.clone());
+        }
+        let target = self
+            .branches
+            .remove(name)
+            .ok_or_else(|| format!("Branch '{}' does not exist", name))?;
+
+        self.settle(current_state);
+        let previous = Branch {
+            undo_stack: std::mem::replace(&mut self.undo_stack, target.undo_stack),
+            redo_stack: std::mem::replace(&mut self.redo_stack, target.redo_stack),
+            head: current_state.clone(),
+        };
+        let previous_name = std::mem::replace(&mut self.active_branch, name.to_string());
+        self.branches.insert(previous_name, previous);
+
+        Ok(target.head)
+    }
+
+    /// Lists all branch names, sorted alphabetically.
+    pub fn list_branches(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.branches.keys().cloned().collect();
+        names.push(self.active_branch.clone());
+        names.sort();
+        names
+    }
+
+    /// Returns the name of the active branch.
+    pub fn current_branch(&self) -> &str {
+        &self.active_branch
+    }
+
+    /// Captures the history of every branch, for `from_snapshot` to rebuild elsewhere.
+    /// `current_state` is the editor's state, which the last tracked edit produced.
+    pub fn snapshot(&mut self, current_state: &EditorState) -> HistorySnapshot {
+        self.settle(current_state);
+        let empty = EditorState::new();
+        let mut branches: Vec<BranchSnapshot> = self
+            .branches
+            .iter()
+            .map(|(name, branch)| BranchSnapshot {
+                name: name.clone(),
+                undo_stack: branch.undo_stack.clone(),
+                redo_stack: branch.redo_stack.clone(),
+                head: Change::between(&empty, &branch.head),
+            })
+            .collect();
+        branches.sort_by(|a, b| a.name.cmp(&b.name));
+
+        HistorySnapshot {
+            undo_stack: self.undo_stack.clone(),
+            redo_stack: self.redo_stack.clone(),
+            max_history: self.max_history,
+            active_branch: self.active_branch.clone(),
+            branches,
+        }
+    }
+
+    /// Rebuilds the history captured by `snapshot`. Undo and redo then apply to the state the
+    /// snapshot was taken with.
+    pub fn from_snapshot(snapshot: HistorySnapshot) -> Self {
+        let empty = EditorState::new();
+        let branches = snapshot
+            .branches
+            .into_iter()
+            .map(|branch| {
+                let head = branch.head.restore(&empty);
+                (branch.name, Branch { undo_stack: branch.undo_stack, redo_stack: branch.redo_stack, head })
+            })
+            .collect();
+
+        Self {
+            undo_stack: snapshot.undo_stack,
+            redo_stack: snapshot.redo_stack,
+            pending: None,
+            max_history: snapshot.max_history,
+            active_branch: snapshot.active_branch,
+            branches,
+        }
+    }
+
+    /// Stores the pending tracked state as a diff from `current_state`, the state its edit produced.
+    fn settle(&mut self, current_state: &EditorState) {
+        if let Some(tracked) = self.pending.take() {
+            self.push_undo(Change::between(current_state, &tracked));
+        }
+    }
+
+    fn push_undo(&mut self, change: Change) {
+        if self.undo_stack.len() == self.max_history {
+            self.undo_stack.pop_front();  // Remove the oldest state to maintain history limit
+        }
+        self.undo_stack.push_back(change);
+    }
+}
+
+impl Default for VersionControl {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+/// Where an edit made from `state` most likely started: the selection it replaced, or the cursor.
+fn edit_hint(state: &EditorState) -> usize {
+    state
+        .get_selection_range()
+        .map_or(state.get_cursor_position(), |(start, end)| start.min(end))
+}
+
+/// Locates the edit that turns `from` into `to`, as the `(start, end)` byte range it covers in `to`.
+/// An edit next to repeated text can be placed at several offsets (deleting "two " from
+/// "one two three" looks the same as deleting "wo t"), so the offset closest to `hint` is used.
+fn edit_location(from: &str, to: &str, hint: usize) -> (usize, usize) {
+    let shortest = from.len().min(to.len());
+    let is_boundary = |at: usize| from.is_char_boundary(at) && to.is_char_boundary(at);
+    let common_prefix = |limit: usize| {
+        let mut len = from.bytes().zip(to.bytes()).take(limit).take_while(|(a, b)| a == b).count();
+        while !is_boundary(len) {
+            len -= 1;
+        }
+        len
+    };
+    let common_suffix = |limit: usize| {
+        let mut len = from.bytes().rev().zip(to.bytes().rev()).take(limit).take_while(|(a, b)| a == b).count();
+        while !from.is_char_boundary(from.len() - len) || !to.is_char_boundary(to.len() - len) {
+            len -= 1;
+        }
+        len
+    };
+
+    // The latest and earliest offsets the edit can start at
+    let latest = common_prefix(shortest);
+    let inserted = to.len() - latest - common_suffix(shortest - latest);
+    let earliest = common_prefix(shortest - common_suffix(shortest)).min(latest);
+
+    let mut start = hint.clamp(earliest, latest);
+    while start > earliest && !(is_boundary(start) && to.is_char_boundary(start + inserted)) {
+        start -= 1;
+    }
+    (start, start + inserted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(version_control: &mut VersionControl, state: &mut EditorState, text: &str) {
+        version_control.track_change(state);
+        state.insert_text(text);
+    }
+
+    #[test]
+    fn test_branches_keep_independent_history() {
+        let mut version_control = VersionControl::new();
+        let mut state = EditorState::new();
+        edit(&mut version_control, &mut state, "base");
+
+        version_control.create_branch("experiment", &state).unwrap();
+        edit(&mut version_control, &mut state, " main");
+        assert_eq!(state.get_text(), "base main");
+
+        let mut state = version_control.switch_branch("experiment", &state).unwrap();
+        assert_eq!(version_control.current_branch(), "experiment");
+        assert_eq!(state.get_text(), "base");
+        edit(&mut version_control, &mut state, " alternative");
+
+        let mut state = version_control.switch_branch(DEFAULT_BRANCH, &state).unwrap();
+        assert_eq!(state.get_text(), "base main");
+        state = version_control.undo(&state).unwrap();
+        assert_eq!(state.get_text(), "base");
+
+        let state = version_control.switch_branch("experiment", &state).unwrap();
+        assert_eq!(state.get_text(), "base alternative");
+        let state = version_control.undo(&state).unwrap();
+        assert_eq!(state.get_text(), "base");
+        let state = version_control.undo(&state).unwrap();
+        assert_eq!(state.get_text(), ""); // History before the fork is shared
+    }
+
+    #[test]
+    fn test_list_and_invalid_branches() {
+        let mut version_control = VersionControl::new();
+        let state = EditorState::new();
+        version_control.create_branch("b", &state).unwrap();
+        version_control.create_branch("a", &state).unwrap();
+
+        assert_eq!(version_control.list_branches(), vec!["a", "b", DEFAULT_BRANCH]);
+        assert!(version_control.create_branch("a", &state).is_err());
+        assert!(version_control.create_branch(DEFAULT_BRANCH, &state).is_err());
+        assert!(version_control.switch_branch("missing", &state).is_err());
+    }
+
+    #[test]
+    fn test_undo_insertion_places_cursor_at_insertion_point() {
+        let mut version_control = VersionControl::new();
+        let mut state = EditorState::new();
+        edit(&mut version_control, &mut state, "hello world");
+
+        state.move_cursor(5);
+        edit(&mut version_control, &mut state, ",");
+        state.move_cursor(0); // The cursor wandered off before undoing
+
+        let state = version_control.undo(&state).unwrap();
+        assert_eq!(state.get_text(), "hello world");
+        assert_eq!(state.get_cursor_position(), 5);
+
+        let state = version_control.redo(&state).unwrap();
+        assert_eq!(state.get_text(), "hello, world");
+        assert_eq!(state.get_cursor_position(), 6);
+    }
+
+    #[test]
+    fn test_undo_deletion_places_cursor_after_restored_text() {
+        let mut version_control = VersionControl::new();
+        let mut state = EditorState::new();
+        edit(&mut version_control, &mut state, "one two three");
+
+        state.set_selection(4, 8);
+        version_control.track_change(&state);
+        state.delete_text(4, 8);
+        state.move_cursor(state.text_len());
+
+        let state = version_control.undo(&state).unwrap();
+        assert_eq!(state.get_text(), "one two three");
+        assert_eq!(state.get_cursor_position(), 8);
+        assert_eq!(state.get_selection_range(), Some((4, 8))); // The selection that was deleted
+
+        let state = version_control.redo(&state).unwrap();
+        assert_eq!(state.get_text(), "one three");
+        assert_eq!(state.get_cursor_position(), 4);
+    }
+
+    #[test]
+    fn test_edit_location_uses_hint_and_char_boundaries() {
+        assert_eq!(edit_location("é", "è", 1), (0, 2));
+        assert_eq!(edit_location("aa", "aaa", 2), (2, 3));
+        assert_eq!(edit_location("aa", "aaa", 0), (0, 1));
+        assert_eq!(edit_location("abc", "abc", 1), (1, 1));
+    }
+
+    /// Deterministic pseudo-random numbers, so failures are reproducible.
+    fn next_random(seed: &mut u64) -> usize {
+        *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (*seed >> 33) as usize
+    }
+
+    #[test]
+    fn test_reconstructed_states_match_full_clones() {
+        let mut version_control = VersionControl::new();
+        let mut state = EditorState::new();
+        let mut clones: Vec<EditorState> = Vec::new(); // Reference history of full copies
+        let mut redo_clones: Vec<EditorState> = Vec::new();
+        let mut seed = 7;
+
+        for _ in 0..2000 {
+            match next_random(&mut seed) % 5 {
+                0 if !clones.is_empty() => {
+                    let expected = clones.pop().unwrap();
+                    redo_clones.push(state.clone());
+                    state = version_control.undo(&state).unwrap();
+                    assert_eq!(state.get_text(), expected.get_text());
+                    assert_eq!(state.get_selection_range(), expected.get_selection_range());
+                }
+                1 if !redo_clones.is_empty() => {
+                    let expected = redo_clones.pop().unwrap();
+                    clones.push(state.clone());
+                    state = version_control.redo(&state).unwrap();
+                    assert_eq!(state.get_text(), expected.get_text());
+                }
+                2 if !state.get_text().is_empty() => {
+                    let chars: Vec<(usize, char)> = state.get_text().char_indices().collect();
+                    let (start, c) = chars[next_random(&mut seed) % chars.len()];
+                    clones.push(state.clone());
+                    redo_clones.clear();
+                    version_control.track_change(&state);
+                    state.delete_text(start, start + c.len_utf8());
+                }
+                _ => {
+                    let text = ["a", "é", "\n", "word ", "ß"][next_random(&mut seed) % 5];
+                    let boundaries: Vec<usize> = (0..=state.text_len()).filter(|i| state.get_text().is_char_boundary(*i)).collect();
+                    state.move_cursor(boundaries[next_random(&mut seed) % boundaries.len()]);
+                    clones.push(state.clone());
+                    redo_clones.clear();
+                    version_control.track_change(&state);
+                    state.insert_text(text);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_many_small_edits_use_little_memory() {
+        let mut version_control = VersionControl::new();
+        version_control.set_max_history(1000);
+        let mut state = EditorState::new();
+        state.insert_text(&"lorem ipsum dolor sit amet\n".repeat(2000));
+
+        let mut full_clone_bytes = 0;
+        for _ in 0..1000 {
+            full_clone_bytes += state.get_text().len();
+            version_control.track_change(&state);
+            state.insert_text("x");
+        }
+
+        // One pending snapshot plus small diffs, instead of a document copy per change
+        assert!(version_control.history_size_in_bytes() * 20 < full_clone_bytes);
+
+        for _ in 0..1000 {
+            state = version_control.undo(&state).unwrap();
+        }
+        assert_eq!(state.get_text(), "lorem ipsum dolor sit amet\n".repeat(2000));
+    }
+}
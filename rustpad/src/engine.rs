@@ -0,0 +1,504 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::editor::diff_engine::{DiffEngine, DiffOperation};
+use crate::validation::Username;
+
+/// Deltas kept for rebasing edits made against older revisions, and for undoing them; clients
+/// further behind are resynced.
+pub const MAX_REBASE: usize = 256;
+
+/// Events kept for subscribers that fall behind, unless the engine is given another capacity.
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+
+/// An edit replacing the whole document, which the engine turns into a delta.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DocumentUpdate {
+    pub content: String,
+    pub user: Username, // Validated when the update is deserialized
+}
+
+/// An edit as a unified diff against the current document, for clients holding a patch rather
+/// than the whole document. It is refused when its context no longer matches.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PatchUpdate {
+    pub patch: String,
+    pub user: Username,
+}
+
+/// Operations on the document at revision `base`, each applying to the text the ones before it
+/// produce. Clients send them against the last revision they saw; the engine broadcasts them
+/// rebased onto the revision before the one they make, `base + 1`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeltaUpdate {
+    pub base: u64,
+    pub operations: Vec<DiffOperation>,
+    pub user: Username,
+}
+
+/// An edit as a client sends it.
+#[derive(Debug)]
+pub enum Edit {
+    Delta(DeltaUpdate),
+    Full(DocumentUpdate),
+    Patch(PatchUpdate),
+}
+
+impl Edit {
+    /// Parses an edit: updates with `operations` are deltas and those with a `patch` unified
+    /// diffs; others carry the whole document.
+    pub fn parse(text: &str) -> serde_json::Result<Edit> {
+        let value: serde_json::Value = serde_json::from_str(text)?;
+        if value.get("operations").is_some() {
+            serde_json::from_value(value).map(Edit::Delta)
+        } else if value.get("patch").is_some() {
+            serde_json::from_value(value).map(Edit::Patch)
+        } else {
+            serde_json::from_value(value).map(Edit::Full)
+        }
+    }
+}
+
+/// Why the whole document was asked for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotKind {
+    Load,   // A client joining
+    Resync, // A client that missed deltas
+}
+
+impl SnapshotKind {
+    /// The name of the kind, as the `type` of the frame carrying the snapshot.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SnapshotKind::Load => "load",
+            SnapshotKind::Resync => "resync",
+        }
+    }
+}
+
+/// The whole document at a revision.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub kind: SnapshotKind,
+    pub revision: u64,
+    pub content: String,
+}
+
+/// What a reconnecting client is sent first.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resume {
+    Deltas { from: u64, deltas: Vec<DeltaUpdate> }, // What it missed since `from`
+    Snapshot(Snapshot),                             // The whole document, when the deltas aren't kept
+}
+
+/// What happened to the document, for transports, persistence and metrics to pass on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DocEvent {
+    DeltaApplied(DeltaUpdate),
+    SnapshotRequested { kind: SnapshotKind, revision: u64 },
+    ConflictDetected { user: Username, base: u64, revision: u64, concurrent: Vec<Username> }, // An edit was rebased over others' edits
+}
+
+/// How much each user changed the document.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Contribution {
+    pub edits: u64,
+    pub inserted: usize, // Bytes
+    pub deleted: usize,  // Bytes
+    pub undone: u64,     // Edits of theirs they undid
+}
+
+/// An applied delta, with what undoing it takes.
+#[derive(Debug, Clone)]
+struct Applied {
+    delta: DeltaUpdate,
+    inverse: Vec<DiffOperation>, // Restores the document before the delta, applied right after it
+    undo_of: Option<u64>,        // The base of the delta this one undid; undos are never undone themselves
+    undone: bool,
+}
+
+/// The authoritative document: its content and revision, the recent deltas that edits made
+/// against older revisions are rebased over, who changed how much, and what undoing each
+/// recent delta takes. It does no I/O; every change is announced on `subscribe` as a
+/// `DocEvent`, for the transports to translate into their own frames.
+#[derive(Debug)]
+pub struct DocumentEngine {
+    content: String,
+    revision: u64,              // Deltas applied so far
+    recent: VecDeque<Applied>,  // The latest deltas as broadcast, oldest first
+    resume_token: String,       // Tells this document's revisions from those of one before a restart
+    contributions: BTreeMap<String, Contribution>, // By user
+    events: broadcast::Sender<DocEvent>,
+}
+
+impl Default for DocumentEngine {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_CAPACITY)
+    }
+}
+
+impl DocumentEngine {
+    /// Creates an empty document whose subscribers may fall `event_capacity` events behind
+    /// before they lag.
+    pub fn new(event_capacity: usize) -> Self {
+        Self {
+            content: String::new(),
+            revision: 0,
+            recent: VecDeque::new(),
+            resume_token: Uuid::new_v4().to_string(),
+            contributions: BTreeMap::new(),
+            events: broadcast::channel(event_capacity.max(1)).0,
+        }
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn resume_token(&self) -> &str {
+        &self.resume_token
+    }
+
+    /// The events from now on. Subscribing while holding the engine's lock means the deltas
+    /// received are exactly those made after the revision read under it.
+    pub fn subscribe(&self) -> broadcast::Receiver<DocEvent> {
+        self.events.subscribe()
+    }
+
+    /// How much each user changed the document, by user.
+    pub fn contributions(&self) -> &BTreeMap<String, Contribution> {
+        &self.contributions
+    }
+
+    /// Applies an edit from a client, returning the delta it made.
+    pub fn submit(&mut self, edit: Edit) -> Result<DeltaUpdate, String> {
+        match edit {
+            Edit::Delta(delta) => self.apply(delta.base, delta.operations, delta.user),
+            Edit::Full(update) => self.replace(&update.content, update.user),
+            Edit::Patch(update) => {
+                let content = DiffEngine::apply_unified(&self.content, &update.patch).map_err(|e| e.to_string())?;
+                self.replace(&content, update.user)
+            }
+        }
+    }
+
+    /// Applies `operations` made against revision `base`, rebased over the revisions since, and
+    /// returns them as broadcast. Concurrent edits already applied win ties.
+    pub fn apply(&mut self, base: u64, operations: Vec<DiffOperation>, user: Username) -> Result<DeltaUpdate, String> {
+        if base > self.revision {
+            return Err(format!("Revision {} is ahead of the pad's {}", base, self.revision));
+        }
+        let behind = (self.revision - base) as usize;
+        if behind > self.recent.len() {
+            return Err(format!("Revision {} is too old to rebase", base));
+        }
+        let concurrent: Vec<&Applied> = self.recent.iter().skip(self.recent.len() - behind).collect();
+        let operations = concurrent.iter().fold(operations, |operations, applied| DiffEngine::transform(&operations, &applied.delta.operations, false).0);
+        let mut others: Vec<Username> = concurrent.iter().map(|applied| applied.delta.user.clone()).filter(|other| *other != user).collect();
+        others.dedup();
+
+        let delta = self.commit(operations, user.clone(), None)?;
+        if !others.is_empty() {
+            let _ = self.events.send(DocEvent::ConflictDetected { user, base, revision: self.revision, concurrent: others });
+        }
+        let _ = self.events.send(DocEvent::DeltaApplied(delta.clone())); // Fails only when nobody is listening
+        Ok(delta)
+    }
+
+    /// Replaces the whole document, returning the delta that does it.
+    pub fn replace(&mut self, content: &str, user: Username) -> Result<DeltaUpdate, String> {
+        let operations = DiffEngine::diff(&self.content, content);
+        self.apply(self.revision, operations, user)
+    }
+
+    /// Undoes the latest delta of `user` not undone yet, rebased over the deltas made since,
+    /// returning the delta that does it. Only the last `MAX_REBASE` deltas can be undone.
+    pub fn undo(&mut self, user: Username) -> Result<DeltaUpdate, String> {
+        let index = self
+            .recent
+            .iter()
+            .rposition(|applied| applied.delta.user == user && applied.undo_of.is_none() && !applied.undone)
+            .ok_or_else(|| format!("{} has nothing to undo", user.as_str()))?;
+        // Later deltas that were undone later still cancel out with their undos, so neither counts
+        let base = self.recent[index].delta.base;
+        let inverse = self
+            .recent
+            .iter()
+            .skip(index + 1)
+            .filter(|later| !later.undone && later.undo_of.is_none_or(|undone| undone < base))
+            .fold(self.recent[index].inverse.clone(), |inverse, later| DiffEngine::transform(&inverse, &later.delta.operations, false).0);
+        // Marked first, as committing a full history drops its oldest delta and shifts the rest
+        self.recent[index].undone = true;
+        let delta = match self.commit(inverse, user.clone(), Some(base)) {
+            Ok(delta) => delta,
+            Err(e) => {
+                self.recent[index].undone = false; // Nothing was committed, so nothing moved
+                return Err(e);
+            }
+        };
+        self.contributions.entry(user.as_str().to_string()).or_default().undone += 1;
+        let _ = self.events.send(DocEvent::DeltaApplied(delta.clone()));
+        Ok(delta)
+    }
+
+    /// The deltas made since `revision`, or `None` when they are no longer kept or the revision
+    /// is unknown, and the whole document has to be sent instead.
+    pub fn since(&self, revision: u64) -> Option<Vec<DeltaUpdate>> {
+        let behind = self.revision.checked_sub(revision)? as usize;
+        (behind <= self.recent.len()).then(|| self.recent.iter().skip(self.recent.len() - behind).map(|applied| applied.delta.clone()).collect())
+    }
+
+    /// The whole document, for a client loading it or missing deltas.
+    pub fn snapshot(&self, kind: SnapshotKind) -> Snapshot {
+        let _ = self.events.send(DocEvent::SnapshotRequested { kind, revision: self.revision });
+        Snapshot { kind, revision: self.revision, content: self.content.clone() }
+    }
+
+    /// What a connecting client gets first: the deltas since `revision` when it is resuming
+    /// with this document's token and they are still kept, the whole document otherwise.
+    pub fn resume(&self, resume: Option<&(String, u64)>) -> Resume {
+        let missed = resume
+            .filter(|(token, _)| *token == self.resume_token)
+            .and_then(|&(_, revision)| Some((revision, self.since(revision)?)));
+        match missed {
+            Some((from, deltas)) => Resume::Deltas { from, deltas },
+            None => Resume::Snapshot(self.snapshot(SnapshotKind::Load)),
+        }
+    }
+
+    /// Applies `operations`, already rebased onto the current revision, and records the delta.
+    fn commit(&mut self, operations: Vec<DiffOperation>, user: Username, undo_of: Option<u64>) -> Result<DeltaUpdate, String> {
+        let (content, inverse) = apply_checked(&self.content, &operations)?;
+        let contribution = self.contributions.entry(user.as_str().to_string()).or_default();
+        contribution.edits += 1;
+        contribution.deleted += self.content.len() + operations.iter().map(inserted_bytes).sum::<usize>() - content.len();
+        contribution.inserted += operations.iter().map(inserted_bytes).sum::<usize>();
+        self.content = content;
+
+        let delta = DeltaUpdate { base: self.revision, operations, user };
+        self.recent.push_back(Applied { delta: delta.clone(), inverse, undo_of, undone: false });
+        if self.recent.len() > MAX_REBASE {
+            self.recent.pop_front();
+        }
+        self.revision += 1;
+        Ok(delta)
+    }
+}
+
+fn inserted_bytes(operation: &DiffOperation) -> usize {
+    match operation {
+        DiffOperation::Insert(_, text) | DiffOperation::Replace(_, _, text) => text.len(),
+        DiffOperation::Delete(..) => 0,
+    }
+}
+
+/// `DiffEngine::apply`, refusing operations outside the text or splitting a character. Also
+/// returns the operations restoring `text` from the result.
+pub(crate) fn apply_checked(text: &str, operations: &[DiffOperation]) -> Result<(String, Vec<DiffOperation>), String> {
+    let mut result = text.to_string();
+    let mut inverse = Vec::with_capacity(operations.len());
+    for operation in operations {
+        let (start, end) = match operation {
+            DiffOperation::Insert(position, _) => (*position, *position),
+            DiffOperation::Delete(start, end) | DiffOperation::Replace(start, end, _) => (*start, *end),
+        };
+        if start > end || end > result.len() || !result.is_char_boundary(start) || !result.is_char_boundary(end) {
+            return Err(format!("{:?} doesn't fit the document", operation));
+        }
+        let removed = result[start..end].to_string();
+        inverse.push(match (operation, removed.is_empty()) {
+            (DiffOperation::Insert(_, text), _) => DiffOperation::Delete(start, start + text.len()),
+            (DiffOperation::Delete(..), _) => DiffOperation::Insert(start, removed),
+            (DiffOperation::Replace(_, _, text), true) => DiffOperation::Delete(start, start + text.len()),
+            (DiffOperation::Replace(_, _, text), false) => DiffOperation::Replace(start, start + text.len(), removed),
+        });
+        result = DiffEngine::apply(&result, std::slice::from_ref(operation));
+    }
+    inverse.reverse();
+    Ok((result, inverse))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config::config;
+    use proptest::prelude::*;
+
+    fn user(name: &str) -> Username {
+        serde_json::from_value(serde_json::json!(name)).unwrap()
+    }
+
+    fn events_of(events: &mut broadcast::Receiver<DocEvent>) -> Vec<DocEvent> {
+        std::iter::from_fn(|| events.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn test_engine_is_send() {
+        fn send<T: Send>() {}
+        send::<DocumentEngine>();
+    }
+
+    #[test]
+    fn test_engine_applies_delta_sequence() {
+        let versions = ["", "fn main() {}", "fn main() { println!(\"héllo\"); }", "fn main() {\n    println!(\"héllo, wörld\");\n}", "// é\n"];
+        let mut engine = DocumentEngine::default();
+        let mut events = engine.subscribe();
+        let mut broadcast = Vec::new();
+        for pair in versions.windows(2) {
+            broadcast.push(engine.apply(engine.revision(), DiffEngine::diff(pair[0], pair[1]), user("alice")).unwrap());
+        }
+        assert_eq!((engine.content(), engine.revision()), (versions[4], 4));
+
+        // The deltas as broadcast take a client from the base to the same content
+        let replayed = broadcast.iter().fold(String::new(), |content, delta| DiffEngine::apply(&content, &delta.operations));
+        assert_eq!(replayed, versions[4]);
+        assert_eq!(broadcast.iter().map(|delta| delta.base).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        assert_eq!(events_of(&mut events), broadcast.into_iter().map(DocEvent::DeltaApplied).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_engine_rebases_and_rejects_deltas() {
+        let mut engine = DocumentEngine::default();
+        engine.replace("hello world", user("alice")).unwrap();
+        let mut events = engine.subscribe();
+
+        // Two edits against revision 1: the second is rebased over the first
+        engine.apply(1, vec![DiffOperation::Insert(0, "say ".to_string())], user("alice")).unwrap();
+        let rebased = engine.apply(1, vec![DiffOperation::Replace(6, 11, "wörld".to_string())], user("bob")).unwrap();
+        assert_eq!(rebased.operations, vec![DiffOperation::Replace(10, 15, "wörld".to_string())]);
+        assert_eq!((engine.content(), engine.revision()), ("say hello wörld", 3));
+        let conflict = DocEvent::ConflictDetected { user: user("bob"), base: 1, revision: 3, concurrent: vec![user("alice")] };
+        assert_eq!(events_of(&mut events)[1..], [conflict, DocEvent::DeltaApplied(rebased)]);
+
+        // Edits from the future, outside the text or splitting a character change nothing
+        assert!(engine.apply(4, vec![DiffOperation::Insert(0, "x".to_string())], user("bob")).is_err());
+        assert!(engine.apply(3, vec![DiffOperation::Delete(10, 99)], user("bob")).is_err());
+        assert!(engine.apply(3, vec![DiffOperation::Insert(12, "x".to_string())], user("bob")).is_err());
+        assert_eq!((engine.content(), engine.revision()), ("say hello wörld", 3));
+        assert!(events_of(&mut events).is_empty());
+
+        // Revisions older than the rebase window can't be rebased
+        for _ in 0..MAX_REBASE {
+            engine.apply(engine.revision(), vec![DiffOperation::Insert(0, "x".to_string())], user("alice")).unwrap();
+        }
+        assert!(engine.apply(2, Vec::new(), user("bob")).is_err());
+        assert!(engine.apply(3, Vec::new(), user("bob")).is_ok());
+    }
+
+    #[test]
+    fn test_undo_rebases_over_later_edits() {
+        let mut engine = DocumentEngine::default();
+        engine.replace("let x = 1;", user("alice")).unwrap();
+        engine.apply(1, vec![DiffOperation::Replace(4, 5, "count".to_string())], user("bob")).unwrap();
+        engine.apply(2, vec![DiffOperation::Insert(0, "// setup\n".to_string())], user("alice")).unwrap();
+
+        // Bob's rename is undone where it ended up, leaving Alice's later comment
+        let undo = engine.undo(user("bob")).unwrap();
+        assert_eq!(undo, DeltaUpdate { base: 3, operations: vec![DiffOperation::Replace(13, 18, "x".to_string())], user: user("bob") });
+        assert_eq!(engine.content(), "// setup\nlet x = 1;");
+        assert!(engine.undo(user("bob")).is_err());
+
+        // Undoing walks back through a user's own edits, newest first
+        engine.undo(user("alice")).unwrap();
+        assert_eq!(engine.content(), "let x = 1;");
+        engine.undo(user("alice")).unwrap();
+        assert_eq!((engine.content(), engine.revision()), ("", 6));
+        assert!(engine.undo(user("alice")).is_err());
+    }
+
+    #[test]
+    fn test_undo_with_a_full_history() {
+        let mut engine = DocumentEngine::default();
+        engine.replace("ab", user("alice")).unwrap();
+        engine.apply(1, vec![DiffOperation::Insert(2, "c".to_string())], user("alice")).unwrap();
+        for _ in 0..MAX_REBASE {
+            engine.apply(engine.revision(), vec![DiffOperation::Insert(0, "x".to_string())], user("bob")).unwrap();
+        }
+        for text in ["d", "e"] {
+            let end = engine.content().len();
+            engine.apply(engine.revision(), vec![DiffOperation::Insert(end, text.to_string())], user("alice")).unwrap();
+        }
+
+        // Each undo drops the oldest delta; the right ones are still marked undone
+        let bobs = "x".repeat(MAX_REBASE);
+        engine.undo(user("alice")).unwrap();
+        assert_eq!(engine.content(), format!("{}abcd", bobs));
+        engine.undo(user("alice")).unwrap();
+        assert_eq!(engine.content(), format!("{}abc", bobs));
+
+        // Alice's first edits are out of the window by now
+        assert!(engine.undo(user("alice")).is_err());
+        assert_eq!(engine.content(), format!("{}abc", bobs));
+    }
+
+    #[test]
+    fn test_contributions_attribute_edits() {
+        let mut engine = DocumentEngine::default();
+        engine.replace("hello", user("alice")).unwrap();
+        engine.apply(1, vec![DiffOperation::Replace(0, 5, "héllo wörld".to_string())], user("bob")).unwrap();
+        engine.apply(2, vec![DiffOperation::Delete(6, 13)], user("alice")).unwrap();
+        engine.undo(user("alice")).unwrap();
+
+        let contributions = engine.contributions();
+        assert_eq!(contributions["alice"], Contribution { edits: 3, inserted: 12, deleted: 7, undone: 1 });
+        assert_eq!(contributions["bob"], Contribution { edits: 1, inserted: 13, deleted: 5, undone: 0 });
+        assert_eq!(engine.content(), "héllo wörld");
+    }
+
+    #[test]
+    fn test_resume_sends_missed_deltas_or_snapshot() {
+        let mut engine = DocumentEngine::default();
+        let hello = engine.replace("hello", user("alice")).unwrap();
+        let bye = engine.replace("bye", user("alice")).unwrap();
+        let mut events = engine.subscribe();
+        let token = engine.resume_token().to_string();
+
+        assert_eq!(engine.resume(Some(&(token.clone(), 0))), Resume::Deltas { from: 0, deltas: vec![hello, bye] });
+        assert!(events_of(&mut events).is_empty());
+        let snapshot = Snapshot { kind: SnapshotKind::Load, revision: 2, content: "bye".to_string() };
+        assert_eq!(engine.resume(Some(&("before a restart".to_string(), 1))), Resume::Snapshot(snapshot.clone()));
+        assert_eq!(engine.resume(Some(&(token, 3))), Resume::Snapshot(snapshot));
+        assert_eq!(events_of(&mut events), vec![DocEvent::SnapshotRequested { kind: SnapshotKind::Load, revision: 2 }; 2]);
+    }
+
+    proptest! {
+        #![proptest_config(config(64))]
+
+        /// Deltas made from a sequence of versions take the document from the first to the last.
+        #[test]
+        fn prop_deltas_reach_final_content(versions in prop::collection::vec("[a-z é\n]{0,12}", 1..8)) {
+            let mut engine = DocumentEngine::default();
+            let mut previous = String::new();
+            for version in &versions {
+                engine.apply(engine.revision(), DiffEngine::diff(&previous, version), user("alice")).unwrap();
+                previous = version.clone();
+            }
+            prop_assert_eq!(engine.content(), versions.last().unwrap().as_str());
+            prop_assert_eq!(engine.revision(), versions.len() as u64);
+            let replayed = engine.since(0).unwrap().iter().fold(String::new(), |text, delta| DiffEngine::apply(&text, &delta.operations));
+            prop_assert_eq!(replayed.as_str(), engine.content());
+        }
+
+        /// Undoing every edit, newest first, goes back through the versions to the empty document.
+        #[test]
+        fn prop_undo_restores_previous_versions(versions in prop::collection::vec("[a-z é\n]{0,12}", 1..8)) {
+            let mut engine = DocumentEngine::default();
+            for version in &versions {
+                engine.replace(version, user("alice")).unwrap();
+            }
+            for expected in versions.iter().rev().skip(1) {
+                engine.undo(user("alice")).unwrap();
+                prop_assert_eq!(engine.content(), expected.as_str());
+            }
+            engine.undo(user("alice")).unwrap();
+            prop_assert_eq!(engine.content(), "");
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use warp::Filter;
+
+/// Query parameter of the WebSocket handshake naming the languages the client reads, most
+/// preferred first, in the form of an `Accept-Language` header (`/ws?protocol=1.0&locale=de`)
+pub const LOCALE_PARAM: &str = "locale";
+
+/// A language server messages are written in. Adding one takes a variant, its code and a
+/// catalog with every key of `EN`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    De,
+}
+
+impl Locale {
+    /// Every locale with a catalog
+    pub const ALL: [Locale; 2] = [Locale::En, Locale::De];
+
+    /// The language code, as clients name it
+    pub fn code(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::De => "de",
+        }
+    }
+
+    /// The locale of a language tag such as `de` or `de-AT`, going by its primary language
+    pub fn from_tag(tag: &str) -> Option<Locale> {
+        let language = tag.trim().split(['-', '_']).next()?;
+        Locale::ALL.into_iter().find(|locale| locale.code().eq_ignore_ascii_case(language))
+    }
+
+    /// The locale to write to a client in, from the languages it `requested` as in an
+    /// `Accept-Language` header (`de-CH, fr;q=0.8, en;q=0.5`): the most preferred one with a
+    /// catalog, or English
+    pub fn negotiate(requested: Option<&str>) -> Locale {
+        let mut ranges: Vec<(&str, f32)> = requested
+            .into_iter()
+            .flat_map(|requested| requested.split(','))
+            .map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next().unwrap_or_default().trim();
+                let quality = parts.find_map(|part| part.trim().strip_prefix("q=")?.parse().ok()).unwrap_or(1.0);
+                (tag, quality)
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1)); // Stable, so equal weights keep their order
+        ranges.into_iter().find_map(|(tag, _)| Locale::from_tag(tag)).unwrap_or_default()
+    }
+
+    /// The message catalog of the locale
+    fn catalog(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Locale::En => EN,
+            Locale::De => DE,
+        }
+    }
+}
+
+/// English messages by key; the fallback for keys another catalog is missing. `{name}` is
+/// replaced with the parameter `name`.
+const EN: &[(&str, &str)] = &[
+    ("error.admin_key_required", "The admin view of presence needs the admin key"),
+    ("error.api_token_required", "The event feed needs an API token"),
+    ("error.document_not_found", "Document {doc_id} does not exist"),
+    ("error.invalid_token", "Unknown or revoked API token"),
+    ("error.invalid_update", "Invalid update: {detail}"),
+    ("error.pad_full", "The pad is full; you can edit once an editor slot frees up"),
+    ("error.quota_exceeded.attachment_bytes", "Attachments are limited to {limit} bytes; this one has {requested}"),
+    ("error.quota_exceeded.documents", "You have {usage} of your {limit} documents; delete one to create another"),
+    ("error.quota_exceeded.storage_bytes", "This needs {requested} more bytes, and {usage} of your {limit} bytes are used"),
+    ("error.read_only_token", "This API token is read-only"),
+    ("error.request_failed", "{detail}"), // Errors of modules not moved to the catalog yet, as they wrote them
+    ("error.session_required", "Saving settings needs a session"),
+    ("notice.document_deleted", "{doc_id} was deleted; its owner can restore it until {restore_until}"),
+    ("notification.edit_digest.one", "{editor} edited 1 region you authored in {doc_id}"),
+    ("notification.edit_digest.other", "{editor} edited {regions} regions you authored in {doc_id}"),
+];
+
+const DE: &[(&str, &str)] = &[
+    ("error.admin_key_required", "Die Admin-Ansicht der Anwesenheit erfordert den Admin-Schlüssel"),
+    ("error.api_token_required", "Der Ereignis-Feed erfordert ein API-Token"),
+    ("error.document_not_found", "Dokument {doc_id} existiert nicht"),
+    ("error.invalid_token", "Unbekanntes oder widerrufenes API-Token"),
+    ("error.invalid_update", "Ungültige Änderung: {detail}"),
+    ("error.pad_full", "Das Pad ist voll; du kannst bearbeiten, sobald ein Platz zum Bearbeiten frei wird"),
+    ("error.quota_exceeded.attachment_bytes", "Anhänge sind auf {limit} Bytes begrenzt; dieser hat {requested}"),
+    ("error.quota_exceeded.documents", "Du hast {usage} von {limit} Dokumenten; lösche eines, um ein neues anzulegen"),
+    ("error.quota_exceeded.storage_bytes", "Dafür werden {requested} weitere Bytes gebraucht, und {usage} deiner {limit} Bytes sind belegt"),
+    ("error.read_only_token", "Dieses API-Token darf nur lesen"),
+    ("error.request_failed", "{detail}"),
+    ("error.session_required", "Zum Speichern der Einstellungen ist eine Sitzung nötig"),
+    ("notice.document_deleted", "{doc_id} wurde gelöscht; der Eigentümer kann es bis {restore_until} wiederherstellen"),
+    ("notification.edit_digest.one", "{editor} hat 1 Bereich bearbeitet, den du in {doc_id} verfasst hast"),
+    ("notification.edit_digest.other", "{editor} hat {regions} Bereiche bearbeitet, die du in {doc_id} verfasst hast"),
+];
+
+/// The message `key` in `locale` with its `params` filled in. Keys missing from the locale's
+/// catalog are written in English, and unknown keys as the key itself.
+pub fn translate(locale: Locale, key: &str, params: &BTreeMap<String, String>) -> String {
+    translate_from(locale.catalog(), key, params)
+}
+
+fn translate_from(catalog: &[(&str, &str)], key: &str, params: &BTreeMap<String, String>) -> String {
+    let lookup = |catalog: &[(&str, &str)]| catalog.iter().find(|(k, _)| *k == key).map(|(_, text)| text.to_string());
+    let template = lookup(catalog).or_else(|| lookup(EN)).unwrap_or_else(|| key.to_string());
+    params.iter().fold(template, |text, (name, value)| text.replace(&format!("{{{}}}", name), value))
+}
+
+/// A user-facing message as it goes out in a frame: the stable key and parameters, for clients
+/// with catalogs of their own, and the text already written in the connection's locale
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LocalizedMessage {
+    pub message_key: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, String>,
+    pub message: String,
+}
+
+impl LocalizedMessage {
+    /// The message `key` with `params`, written in `locale`
+    pub fn new(locale: Locale, key: &str, params: &[(&str, String)]) -> Self {
+        let params: BTreeMap<String, String> = params.iter().map(|(name, value)| (name.to_string(), value.clone())).collect();
+        LocalizedMessage { message: translate(locale, key, &params), message_key: key.to_string(), params }
+    }
+
+    /// An error of a module whose messages aren't in the catalog yet, passed on as written
+    pub fn unlocalized(locale: Locale, detail: &str) -> Self {
+        Self::new(locale, "error.request_failed", &[("detail", detail.to_string())])
+    }
+
+    /// The same message written in `locale`
+    pub fn to_locale(&self, locale: Locale) -> Self {
+        LocalizedMessage { message: translate(locale, &self.message_key, &self.params), ..self.clone() }
+    }
+
+    /// An error frame carrying the message: `{"type": "error", "message_key", "params", "message"}`
+    pub fn error_frame(&self) -> serde_json::Value {
+        let mut frame = serde_json::to_value(self).unwrap();
+        frame["type"] = "error".into();
+        frame
+    }
+}
+
+/// The locale an HTTP request asks for in its `Accept-Language` header
+pub fn accept_language() -> impl Filter<Extract = (Locale,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("accept-language").map(|header: Option<String>| Locale::negotiate(header.as_deref()))
+}
+
+/// A time as it goes out in frames and messages: RFC 3339 in UTC with a `Z`, as chrono
+/// serializes `DateTime<Utc>` fields. Clients convert it to local time for display.
+pub fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::DocumentUpdate;
+    use crate::rooms::LastSeen;
+    use crate::tokens::{Scope, TokenStore};
+    use crate::validation::Username;
+    use chrono::TimeZone;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_messages_fill_params_and_fall_back_to_english() {
+        let missing = LocalizedMessage::new(Locale::De, "error.document_not_found", &[("doc_id", "plan".to_string())]);
+        assert_eq!(missing.message, "Dokument plan existiert nicht");
+        assert_eq!(missing.to_locale(Locale::En).message, "Document plan does not exist");
+        assert_eq!(
+            missing.error_frame(),
+            serde_json::json!({ "type": "error", "message_key": "error.document_not_found", "params": { "doc_id": "plan" }, "message": "Dokument plan existiert nicht" })
+        );
+
+        // A key missing from a catalog is written in English; one nobody has, as the key
+        let params = BTreeMap::from([("detail".to_string(), "boom".to_string())]);
+        let partial: &[(&str, &str)] = &[("error.pad_full", "Voll")];
+        assert_eq!(translate_from(partial, "error.invalid_update", &params), "Invalid update: boom");
+        assert_eq!(translate_from(partial, "error.pad_full", &params), "Voll");
+        assert_eq!(translate(Locale::De, "error.unknown", &params), "error.unknown");
+        assert_eq!(LocalizedMessage::unlocalized(Locale::De, "Room full").message, "Room full");
+    }
+
+    #[test]
+    fn test_negotiate_locale() {
+        assert_eq!(Locale::negotiate(None), Locale::En);
+        assert_eq!(Locale::negotiate(Some("de")), Locale::De);
+        assert_eq!(Locale::negotiate(Some("de-AT")), Locale::De);
+        assert_eq!(Locale::negotiate(Some("fr-CH, fr;q=0.9, de;q=0.7, en;q=0.5")), Locale::De);
+        assert_eq!(Locale::negotiate(Some("en;q=0.5, DE_de")), Locale::De);
+        assert_eq!(Locale::negotiate(Some("de;q=0, en")), Locale::En);
+        assert_eq!(Locale::negotiate(Some("fr, *")), Locale::En);
+        assert_eq!(Locale::from_tag("pt-BR"), None);
+    }
+
+    #[test]
+    fn test_catalogs_have_the_same_keys() {
+        let keys = |locale: Locale| locale.catalog().iter().map(|(key, _)| *key).collect::<BTreeSet<_>>();
+        for locale in Locale::ALL {
+            assert_eq!(keys(locale), keys(Locale::En), "The {} catalog has other keys than English", locale.code());
+            assert_eq!(keys(locale).len(), locale.catalog().len(), "The {} catalog repeats a key", locale.code());
+            // Translations use the same parameters as the English text
+            for (key, text) in locale.catalog() {
+                let names = |text: &str| text.split('{').skip(1).filter_map(|rest| rest.split_once('}')).map(|(name, _)| name.to_string()).collect::<BTreeSet<_>>();
+                assert_eq!(names(text), names(&translate(Locale::En, key, &BTreeMap::new())), "{} in {}", key, locale.code());
+            }
+        }
+    }
+
+    /// Every time in `value`, by field name, is RFC 3339 in UTC with a `Z`
+    fn assert_timestamps(value: &serde_json::Value) {
+        match value {
+            serde_json::Value::Object(fields) => {
+                for (name, field) in fields {
+                    if name == "timestamp" || name.ends_with("_at") || name == "last_activity" {
+                        let text = field.as_str().unwrap_or_else(|| panic!("{} is not a string: {}", name, field));
+                        assert!(DateTime::parse_from_rfc3339(text).is_ok() && text.ends_with('Z'), "{} is {}", name, text);
+                    }
+                    assert_timestamps(field);
+                }
+            }
+            serde_json::Value::Array(items) => items.iter().for_each(assert_timestamps),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_frames_send_rfc3339_utc_timestamps() {
+        let at = Utc.with_ymd_and_hms(2026, 10, 17, 9, 30, 0).unwrap() + chrono::Duration::milliseconds(250);
+        assert_eq!(timestamp(at), "2026-10-17T09:30:00.250Z");
+        assert_eq!(serde_json::to_value(at).unwrap(), serde_json::json!(timestamp(at)));
+
+        let (_, token) = TokenStore::new().create("ci", vec![Scope::DocRead]).unwrap();
+        let frames = [
+            serde_json::to_value(DocumentUpdate::new("hello", Username::try_from("ana".to_string()).unwrap())).unwrap(),
+            serde_json::json!({ "type": "who", "users": [LastSeen { client: "ana".to_string(), last_activity: at, away: false }] }),
+            serde_json::json!({ "secret": "", "token": token }),
+        ];
+        for frame in &frames {
+            assert_timestamps(frame);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use chrono::{DateTime, Duration, Utc};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::integrations::webhooks::{Webhook, WebhookDispatcher, WebhookEvent, WebhookPayload, WebhookScope};
+use crate::storage::activity::{ActivityEvent, ActivityFeeds, ActivityKind};
+
+/// How much activity one digest covers
+pub fn digest_period() -> Duration {
+    Duration::days(1)
+}
+
+/// What happened in one document over a digest period
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DocumentDigest {
+    pub doc_id: String,
+    pub edits: usize,
+    pub lines_changed: usize,
+    pub saves: usize,
+    pub checkpoints: usize,
+    pub chat_messages: usize,
+    pub mentions: usize,
+    pub people: Vec<String>, // Everyone who did any of it, sorted
+}
+
+/// Sums up the entries of `doc_id` last updated in `from..to`, from after `from` up to and
+/// including `to`; `None` when nothing happened. Entries coalesced across the boundary are
+/// counted whole in the period they were last updated in.
+pub fn summarize(doc_id: &str, events: &[ActivityEvent], from: DateTime<Utc>, to: DateTime<Utc>) -> Option<DocumentDigest> {
+    let mut digest = DocumentDigest { doc_id: doc_id.to_string(), ..DocumentDigest::default() };
+    let mut people = BTreeSet::new();
+    for event in events.iter().filter(|event| from < event.updated_at && event.updated_at <= to) {
+        match &event.kind {
+            ActivityKind::Edited { lines_changed, edits } => {
+                digest.edits += edits;
+                digest.lines_changed += lines_changed;
+            }
+            ActivityKind::Saved => digest.saves += 1,
+            ActivityKind::CheckpointCreated { .. } | ActivityKind::Reverted { .. } => digest.checkpoints += 1,
+            ActivityKind::ChatBurst { messages } => digest.chat_messages += messages,
+            ActivityKind::Mentioned { users } => digest.mentions += users.len(),
+            ActivityKind::Joined | ActivityKind::Left | ActivityKind::Renamed { .. } => continue, // Not activity of note
+        }
+        people.insert(event.user.clone());
+    }
+    (!people.is_empty()).then(|| DocumentDigest { people: people.into_iter().collect(), ..digest })
+}
+
+/// Sends the webhooks subscribed to `daily_digest` a summary of their documents' activity once
+/// a period, starting a period after each was created. Quiet periods send nothing.
+#[derive(Clone)]
+pub struct DigestJob {
+    dispatcher: WebhookDispatcher,
+    feeds: ActivityFeeds,
+    period: Duration,
+}
+
+impl DigestJob {
+    /// Digests the activity in `feeds`, delivered through `dispatcher`, every `digest_period()`
+    pub fn new(dispatcher: WebhookDispatcher, feeds: ActivityFeeds) -> Self {
+        Self { dispatcher, feeds, period: digest_period() }
+    }
+
+    /// Sends a digest every `period` instead
+    pub fn with_period(self, period: Duration) -> Self {
+        Self { period, ..self }
+    }
+
+    /// The digests due at `now`, each with the webhook it goes to. Their periods count as sent,
+    /// so each period is digested once.
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<(Webhook, WebhookPayload)> {
+        let store = self.dispatcher.store();
+        let mut due = Vec::new();
+        for hook in store.all().into_iter().filter(|hook| hook.events.contains(&WebhookEvent::DailyDigest)) {
+            let from = hook.last_digest_at.unwrap_or(hook.created_at);
+            if now - from < self.period {
+                continue;
+            }
+            store.mark_digested(&hook.id, now);
+
+            let docs = match &hook.scope {
+                WebhookScope::Workspace(id) => match self.dispatcher.workspaces().get(id) {
+                    Some(workspace) => workspace.docs.into_iter().filter(|doc| !workspace.trashed.contains_key(doc)).collect(),
+                    None => continue,
+                },
+                WebhookScope::Document(doc) => vec![doc.clone()],
+            };
+            let documents: Vec<DocumentDigest> = docs
+                .iter()
+                .filter_map(|doc| self.feeds.with_feed(doc, |feed| summarize(doc, &feed.since(from), from, now)))
+                .collect();
+            if documents.is_empty() {
+                continue;
+            }
+            let data = serde_json::json!({ "from": from, "to": now, "documents": documents });
+            let payload = self.dispatcher.payload(&hook, WebhookEvent::DailyDigest, None, data, now);
+            due.push((hook, payload));
+        }
+        due
+    }
+
+    /// Delivers the digests due at `now`, returning how many were delivered
+    pub async fn run(&self, now: DateTime<Utc>) -> usize {
+        let due = self.due(now);
+        let deliveries = due.into_iter().map(|(hook, payload)| async move { self.dispatcher.deliver(&hook, payload).await });
+        join_all(deliveries).await.into_iter().filter(Result::is_ok).count()
+    }
+}
+
+/// Runs `run` every hour, so digests go out within an hour of being due
+pub fn spawn_digests(job: DigestJob) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            let delivered = job.run(Utc::now()).await;
+            if delivered > 0 {
+                println!("Delivered {} activity digests", delivered);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrations::webhooks::{WebhookRequest, WebhookStore};
+    use crate::storage::workspace::Workspaces;
+    use chrono::TimeZone;
+
+    fn at(hours: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 4, 8, 0, 0).unwrap() + Duration::hours(hours)
+    }
+
+    #[test]
+    fn test_digests_aggregate_each_period_once() {
+        let workspaces = Workspaces::new();
+        let team = workspaces.create("olga", "Team").unwrap().id;
+        workspaces.add_doc(&team, "olga", "notes.md").unwrap();
+        workspaces.add_doc(&team, "olga", "plan.md").unwrap();
+        let store = WebhookStore::new();
+        let request = WebhookRequest {
+            scope: WebhookScope::Workspace(team.clone()),
+            url: "http://hooks.example.com/digest".to_string(),
+            secret: Some("correct horse battery".to_string()),
+            events: vec![WebhookEvent::DailyDigest],
+        };
+        let hook = store.create("olga", request, at(0)).unwrap();
+        let feeds = ActivityFeeds::new(50);
+        let job = DigestJob::new(WebhookDispatcher::new(store.clone(), workspaces), feeds.clone());
+
+        feeds.with_feed("notes.md", |feed| {
+            feed.record_edit("ed", 3, at(1));
+            feed.record_edit("ed", 2, at(1) + Duration::minutes(5)); // Coalesced into one entry
+            feed.record_save("ed", at(2));
+            feed.record_chat("olga", at(3));
+            feed.record_mention("olga", vec!["ed".to_string(), "pat".to_string()], at(3));
+            feed.record_join("pat", at(4)); // Presence isn't in digests
+        });
+        feeds.with_feed("plan.md", |feed| feed.record_checkpoint("olga", "v1", at(5)));
+        assert!(job.due(at(23)).is_empty());
+
+        let due = job.due(at(24));
+        assert_eq!(due.len(), 1);
+        let (sent_to, payload) = &due[0];
+        assert_eq!((sent_to.id.as_str(), payload.event), (hook.id.as_str(), WebhookEvent::DailyDigest));
+        assert_eq!((payload.data["from"].as_str(), payload.data["to"].as_str()), (Some("2024-03-04T08:00:00Z"), Some("2024-03-05T08:00:00Z")));
+        let mut documents: Vec<DocumentDigest> = serde_json::from_value(payload.data["documents"].clone()).unwrap();
+        documents.sort_by(|a, b| a.doc_id.cmp(&b.doc_id));
+        let notes = DocumentDigest {
+            doc_id: "notes.md".to_string(),
+            edits: 2,
+            lines_changed: 5,
+            saves: 1,
+            chat_messages: 1,
+            mentions: 2,
+            people: vec!["ed".to_string(), "olga".to_string()],
+            ..DocumentDigest::default()
+        };
+        let plan = DocumentDigest { doc_id: "plan.md".to_string(), checkpoints: 1, people: vec!["olga".to_string()], ..DocumentDigest::default() };
+        assert_eq!(documents, vec![notes, plan]);
+
+        // Not again within the next period, then only what happened since
+        assert!(job.due(at(30)).is_empty());
+        feeds.with_feed("plan.md", |feed| feed.record_save("pat", at(40)));
+        let due = job.due(at(48));
+        let documents: Vec<DocumentDigest> = serde_json::from_value(due[0].1.data["documents"].clone()).unwrap();
+        assert_eq!(documents, vec![DocumentDigest { doc_id: "plan.md".to_string(), saves: 1, people: vec!["pat".to_string()], ..DocumentDigest::default() }]);
+
+        // Quiet periods send nothing
+        assert!(job.due(at(72)).is_empty());
+        assert_eq!(store.get(&hook.id).unwrap().last_digest_at, Some(at(72)));
+    }
+}
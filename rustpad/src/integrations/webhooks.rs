@@ -0,0 +1,919 @@
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use warp::http::StatusCode;
+use warp::hyper::header::{CONTENT_TYPE, HOST, USER_AGENT};
+use warp::hyper::{self, Body, Request, Uri};
+use warp::{Filter, Reply};
+
+use crate::auth::auth::{sign_in_required, signed_in_user, SignedOut};
+use crate::networking::linkpreview::{is_public, parse_link, public_address};
+use crate::rate_limit::RateLimiter;
+use crate::storage::activity::{ActivityFeeds, ActivityKind};
+use crate::storage::workspace::{WorkspaceRole, Workspaces};
+use crate::storage::Storage;
+use crate::tokens::is_admin;
+
+/// Header carrying `sha256=<hex HMAC of the body>`, keyed with the webhook's secret
+pub const SIGNATURE_HEADER: &str = "x-rustpad-signature";
+/// Header carrying the event name, the same as the payload's `event`
+pub const EVENT_HEADER: &str = "x-rustpad-event";
+
+/// Limits on webhook configurations
+pub const MAX_WEBHOOKS_PER_SCOPE: usize = 10;
+pub const MIN_SECRET_LEN: usize = 16;
+pub const MAX_SECRET_LEN: usize = 256;
+
+/// Deliveries are attempted this many times, waiting `DEFAULT_BACKOFF` after the first failure
+/// and twice as long after each one since, before the payload is dead-lettered
+pub const DEFAULT_MAX_ATTEMPTS: usize = 5;
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(2);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Deliveries allowed per destination host and window; attempts over it count as failed
+pub const DESTINATION_RATE_LIMIT: usize = 30;
+pub const DESTINATION_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Dead letters kept for the admin API, the oldest dropped first
+pub const MAX_DEAD_LETTERS: usize = 500;
+
+/// Storage identifiers of the configurations and the dead letters
+const WEBHOOKS_ID: &str = "webhooks.json";
+const DEAD_LETTERS_ID: &str = "webhook_dead_letters.json";
+
+/// What a webhook is sent for. `Test` is only ever sent by `POST /api/webhooks/:id/test`
+/// and can't be subscribed to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Saves,
+    Checkpoints,
+    Members,
+    ChatMentions,
+    DailyDigest,
+    Test,
+}
+
+impl WebhookEvent {
+    /// The event an activity entry is delivered as, if webhooks are sent for it at all
+    pub fn for_activity(kind: &ActivityKind) -> Option<Self> {
+        match kind {
+            ActivityKind::Saved => Some(WebhookEvent::Saves),
+            ActivityKind::CheckpointCreated { .. } | ActivityKind::Reverted { .. } => Some(WebhookEvent::Checkpoints),
+            ActivityKind::Joined | ActivityKind::Left => Some(WebhookEvent::Members),
+            ActivityKind::Mentioned { .. } => Some(WebhookEvent::ChatMentions),
+            _ => None, // Edits and chat reach webhooks through the digest only
+        }
+    }
+
+    /// The name sent in `EVENT_HEADER`
+    pub fn name(self) -> &'static str {
+        match self {
+            WebhookEvent::Saves => "saves",
+            WebhookEvent::Checkpoints => "checkpoints",
+            WebhookEvent::Members => "members",
+            WebhookEvent::ChatMentions => "chat_mentions",
+            WebhookEvent::DailyDigest => "daily_digest",
+            WebhookEvent::Test => "test",
+        }
+    }
+}
+
+/// What a webhook is sent for: everything in a workspace, or one document
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookScope {
+    Workspace(String),
+    Document(String),
+}
+
+/// An outbound webhook
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub id: String,
+    pub scope: WebhookScope,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub secret: String, // Signs the payloads; never sent back by the API
+    pub events: Vec<WebhookEvent>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_digest_at: Option<DateTime<Utc>>, // End of the last period a digest was sent for
+}
+
+impl Webhook {
+    /// This webhook without its secret, as the API shows it
+    pub fn redacted(&self) -> Self {
+        Self { secret: String::new(), ..self.clone() }
+    }
+}
+
+/// A webhook as created or replaced through the API. Replacing one without a secret keeps
+/// its secret.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebhookRequest {
+    pub scope: WebhookScope,
+    pub url: String,
+    #[serde(default)]
+    pub secret: Option<String>,
+    pub events: Vec<WebhookEvent>,
+}
+
+/// The JSON body of a delivery
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebhookPayload {
+    pub id: String, // The same for every attempt, so receivers can drop repeats
+    pub webhook_id: String,
+    pub event: WebhookEvent,
+    pub workspace: Option<String>,
+    pub doc_id: Option<String>,
+    pub sent_at: DateTime<Utc>,
+    pub data: serde_json::Value,
+}
+
+/// A payload given up on after its last attempt failed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    pub url: String,
+    pub payload: WebhookPayload,
+    pub attempts: usize,
+    pub error: String, // Why the last attempt failed
+    pub failed_at: DateTime<Utc>,
+}
+
+/// `sha256=<hex>`: the HMAC-SHA256 of `body` keyed with `secret`, as sent in `SIGNATURE_HEADER`
+pub fn sign(secret: &str, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, body.as_bytes());
+    let hex: String = tag.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", hex)
+}
+
+/// Whether `signature` is the one `sign` gives for `body` and `secret`, compared in constant
+/// time. Receivers written in Rust can check deliveries with this.
+pub fn verify(secret: &str, body: &str, signature: &str) -> bool {
+    let Some(hex) = signature.strip_prefix("sha256=") else { return false };
+    let tag: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|index| hex.get(index..index + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    tag.is_some_and(|tag| hmac::verify(&key, body.as_bytes(), &tag).is_ok())
+}
+
+/// Refuses configurations that could never be delivered: URLs other than plain http (there is
+/// no TLS client yet), hosts that are literally private addresses, weak secrets and empty filters
+fn validate(request: &WebhookRequest) -> Result<(), String> {
+    let url = parse_link(&request.url).ok_or("The URL must be an http URL with a host")?;
+    if url.scheme_str() != Some("http") {
+        return Err("Only http URLs can be delivered to".to_string());
+    }
+    let host = url.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    if host.parse::<IpAddr>().is_ok_and(|ip| !is_public(ip)) {
+        return Err(format!("{} isn't a public address", host));
+    }
+    if let Some(secret) = &request.secret {
+        if !(MIN_SECRET_LEN..=MAX_SECRET_LEN).contains(&secret.len()) {
+            return Err(format!("The secret must be {} to {} bytes long", MIN_SECRET_LEN, MAX_SECRET_LEN));
+        }
+    }
+    if request.events.is_empty() {
+        return Err("A webhook needs at least one event".to_string());
+    }
+    if request.events.contains(&WebhookEvent::Test) {
+        return Err("Test events are only sent on request".to_string());
+    }
+    Ok(())
+}
+
+/// Webhook configurations, and the dead letters of the deliveries that failed, persisted through
+/// `Storage` when it is given one
+#[derive(Clone)]
+pub struct WebhookStore {
+    hooks: Arc<Mutex<HashMap<String, Webhook>>>, // By id
+    dead_letters: Arc<Mutex<VecDeque<DeadLetter>>>,
+    storage: Option<Arc<dyn Storage + Send + Sync>>,
+}
+
+impl WebhookStore {
+    /// Creates an empty in-memory store
+    pub fn new() -> Self {
+        Self {
+            hooks: Arc::new(Mutex::new(HashMap::new())),
+            dead_letters: Arc::new(Mutex::new(VecDeque::new())),
+            storage: None,
+        }
+    }
+
+    /// Persists through `storage`, loading any previously saved webhooks and dead letters
+    pub fn with_storage(mut self, storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        if let Ok(saved) = storage.load(WEBHOOKS_ID) {
+            let saved: HashMap<String, Webhook> = serde_json::from_str(&saved).unwrap_or_default();
+            self.hooks.lock().unwrap().extend(saved);
+        }
+        if let Ok(saved) = storage.load(DEAD_LETTERS_ID) {
+            let saved: VecDeque<DeadLetter> = serde_json::from_str(&saved).unwrap_or_default();
+            self.dead_letters.lock().unwrap().extend(saved);
+        }
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Adds a webhook configured by `actor`
+    pub fn create(&self, actor: &str, request: WebhookRequest, now: DateTime<Utc>) -> Result<Webhook, String> {
+        validate(&request)?;
+        let secret = request.secret.ok_or("A webhook needs a secret to sign its payloads with")?;
+        let mut hooks = self.hooks.lock().unwrap();
+        if hooks.values().filter(|hook| hook.scope == request.scope).count() >= MAX_WEBHOOKS_PER_SCOPE {
+            return Err(format!("At most {} webhooks can be set up here", MAX_WEBHOOKS_PER_SCOPE));
+        }
+        let hook = Webhook {
+            id: uuid::Uuid::new_v4().to_string(),
+            scope: request.scope,
+            url: request.url,
+            secret,
+            events: request.events,
+            created_by: actor.to_string(),
+            created_at: now,
+            last_digest_at: None,
+        };
+        hooks.insert(hook.id.clone(), hook.clone());
+        self.save_hooks(&hooks);
+        Ok(hook)
+    }
+
+    /// Replaces the configuration of webhook `id`, keeping its secret when the request has none
+    pub fn update(&self, id: &str, request: WebhookRequest) -> Result<Webhook, String> {
+        validate(&request)?;
+        let mut hooks = self.hooks.lock().unwrap();
+        let hook = hooks.get_mut(id).ok_or_else(|| format!("Unknown webhook {}", id))?;
+        hook.scope = request.scope;
+        hook.url = request.url;
+        hook.events = request.events;
+        if let Some(secret) = request.secret {
+            hook.secret = secret;
+        }
+        let hook = hook.clone();
+        self.save_hooks(&hooks);
+        Ok(hook)
+    }
+
+    /// Removes webhook `id`
+    pub fn delete(&self, id: &str) -> Option<Webhook> {
+        let mut hooks = self.hooks.lock().unwrap();
+        let removed = hooks.remove(id);
+        if removed.is_some() {
+            self.save_hooks(&hooks);
+        }
+        removed
+    }
+
+    pub fn get(&self, id: &str) -> Option<Webhook> {
+        self.hooks.lock().unwrap().get(id).cloned()
+    }
+
+    /// Every webhook, oldest first
+    pub fn all(&self) -> Vec<Webhook> {
+        let mut hooks: Vec<Webhook> = self.hooks.lock().unwrap().values().cloned().collect();
+        hooks.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        hooks
+    }
+
+    /// The webhooks of `scope`, oldest first
+    pub fn in_scope(&self, scope: &WebhookScope) -> Vec<Webhook> {
+        self.all().into_iter().filter(|hook| &hook.scope == scope).collect()
+    }
+
+    /// The webhooks `event` in `doc_id`, of workspace `workspace`, is sent to
+    pub fn matching(&self, doc_id: &str, workspace: Option<&str>, event: WebhookEvent) -> Vec<Webhook> {
+        self.all()
+            .into_iter()
+            .filter(|hook| hook.events.contains(&event))
+            .filter(|hook| match &hook.scope {
+                WebhookScope::Workspace(id) => Some(id.as_str()) == workspace,
+                WebhookScope::Document(doc) => doc == doc_id,
+            })
+            .collect()
+    }
+
+    /// Remembers that the digests of webhook `id` were sent up to `at`
+    pub fn mark_digested(&self, id: &str, at: DateTime<Utc>) {
+        let mut hooks = self.hooks.lock().unwrap();
+        if let Some(hook) = hooks.get_mut(id) {
+            hook.last_digest_at = Some(at);
+            self.save_hooks(&hooks);
+        }
+    }
+
+    /// Failed deliveries, oldest first
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn dead_letter(&self, letter: DeadLetter) {
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        dead_letters.push_back(letter);
+        while dead_letters.len() > MAX_DEAD_LETTERS {
+            dead_letters.pop_front();
+        }
+        self.persist(DEAD_LETTERS_ID, &*dead_letters);
+    }
+
+    fn save_hooks(&self, hooks: &HashMap<String, Webhook>) {
+        self.persist(WEBHOOKS_ID, hooks);
+    }
+
+    fn persist<T: Serialize>(&self, identifier: &str, value: &T) {
+        if let Some(storage) = &self.storage {
+            let saved = serde_json::to_string(value).map_err(|e| e.to_string()).and_then(|json| storage.save(identifier, &json).map_err(|e| e.to_string()));
+            if let Err(e) = saved {
+                eprintln!("Failed to persist {}: {}", identifier, e);
+            }
+        }
+    }
+}
+
+impl Default for WebhookStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Delivers payloads to webhooks, guarding the server's network the way link previews do:
+/// only public addresses are connected to, unless configured with `with_host`. Every delivery
+/// is signed, rate limited per destination host and retried with exponential backoff; payloads
+/// still undelivered after the last attempt become dead letters.
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    store: WebhookStore,
+    workspaces: Workspaces,
+    destinations: RateLimiter,
+    max_attempts: usize,
+    backoff: Duration,
+    timeout: Duration,
+    hosts: HashMap<String, SocketAddr>, // Resolved without DNS and trusted even when not public
+}
+
+impl WebhookDispatcher {
+    /// Creates a dispatcher for the webhooks in `store`, finding documents in `workspaces`
+    pub fn new(store: WebhookStore, workspaces: Workspaces) -> Self {
+        Self {
+            store,
+            workspaces,
+            destinations: RateLimiter::new(DESTINATION_RATE_LIMIT, DESTINATION_RATE_WINDOW),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            backoff: DEFAULT_BACKOFF,
+            timeout: DEFAULT_TIMEOUT,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Gives up on a payload after `max_attempts` failed attempts
+    pub fn with_max_attempts(self, max_attempts: usize) -> Self {
+        Self { max_attempts: max_attempts.max(1), ..self }
+    }
+
+    /// Waits `backoff` after the first failed attempt, doubling it after each one since
+    pub fn with_backoff(self, backoff: Duration) -> Self {
+        Self { backoff, ..self }
+    }
+
+    /// Gives up on an attempt after `timeout`
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Limits deliveries per destination host with `destinations` instead of the default limit
+    pub fn with_destination_limit(self, destinations: RateLimiter) -> Self {
+        Self { destinations, ..self }
+    }
+
+    /// Connects to `addr` for webhooks to `host` instead of resolving it, even though the
+    /// address isn't public: for internal receivers, and for tests
+    pub fn with_host(mut self, host: &str, addr: SocketAddr) -> Self {
+        self.hosts.insert(host.to_ascii_lowercase(), addr);
+        self
+    }
+
+    pub fn store(&self) -> &WebhookStore {
+        &self.store
+    }
+
+    pub fn workspaces(&self) -> &Workspaces {
+        &self.workspaces
+    }
+
+    /// The payload of `event` for `hook`, about `doc_id` when it concerns one document
+    pub fn payload(&self, hook: &Webhook, event: WebhookEvent, doc_id: Option<&str>, data: serde_json::Value, now: DateTime<Utc>) -> WebhookPayload {
+        let workspace = match &hook.scope {
+            WebhookScope::Workspace(id) => Some(id.clone()),
+            WebhookScope::Document(doc) => self.workspaces.workspace_of(doc_id.unwrap_or(doc)),
+        };
+        WebhookPayload {
+            id: uuid::Uuid::new_v4().to_string(),
+            webhook_id: hook.id.clone(),
+            event,
+            workspace,
+            doc_id: doc_id.map(str::to_string),
+            sent_at: now,
+            data,
+        }
+    }
+
+    /// Sends `event` in `doc_id` to every webhook subscribed to it, returning how many of them
+    /// it was delivered to
+    pub async fn dispatch(&self, doc_id: &str, event: WebhookEvent, data: serde_json::Value, now: DateTime<Utc>) -> usize {
+        let workspace = self.workspaces.workspace_of(doc_id);
+        let hooks = self.store.matching(doc_id, workspace.as_deref(), event);
+        let deliveries = hooks.iter().map(|hook| self.deliver(hook, self.payload(hook, event, Some(doc_id), data.clone(), now)));
+        join_all(deliveries).await.into_iter().filter(Result::is_ok).count()
+    }
+
+    /// Delivers `payload` to `hook`, retrying with backoff, and dead-letters it when every
+    /// attempt failed
+    pub async fn deliver(&self, hook: &Webhook, payload: WebhookPayload) -> Result<(), String> {
+        let body = serde_json::to_string(&payload).map_err(|e| e.to_string())?;
+        let mut error = String::new();
+        for attempt in 0..self.max_attempts {
+            if attempt > 0 {
+                tokio::time::sleep(self.backoff * 2u32.saturating_pow(attempt as u32 - 1)).await;
+            }
+            match self.attempt(hook, payload.event, &body).await {
+                Ok(()) => return Ok(()),
+                Err(e) => error = e,
+            }
+        }
+
+        println!("Giving up on webhook {} after {} attempts: {}", hook.id, self.max_attempts, error);
+        self.store.dead_letter(DeadLetter {
+            url: hook.url.clone(),
+            payload,
+            attempts: self.max_attempts,
+            error: error.clone(),
+            failed_at: Utc::now(),
+        });
+        Err(error)
+    }
+
+    /// Sends a sample event to `hook` once, without retrying or dead-lettering it
+    pub async fn send_test(&self, hook: &Webhook, now: DateTime<Utc>) -> Result<(), String> {
+        let data = serde_json::json!({ "message": "A test event from RustPad" });
+        let payload = self.payload(hook, WebhookEvent::Test, None, data, now);
+        let body = serde_json::to_string(&payload).map_err(|e| e.to_string())?;
+        self.attempt(hook, WebhookEvent::Test, &body).await
+    }
+
+    /// One signed POST of `body` to `hook`, succeeding on a 2xx answer
+    async fn attempt(&self, hook: &Webhook, event: WebhookEvent, body: &str) -> Result<(), String> {
+        let url = parse_link(&hook.url).ok_or("Invalid URL")?;
+        let host = url.host().unwrap_or_default().to_ascii_lowercase();
+        self.destinations.check(&host, Instant::now())?;
+        let addr = public_address(&url, &self.hosts).await?;
+        let response = tokio::time::timeout(self.timeout, self.post(&url, addr, hook, event, body)).await.map_err(|_| "Timed out".to_string())??;
+        if !response.status().is_success() {
+            return Err(format!("The receiver answered {}", response.status()));
+        }
+        Ok(())
+    }
+
+    /// Sends the POST to `addr`, the address checked for the URL's host
+    async fn post(&self, url: &Uri, addr: SocketAddr, hook: &Webhook, event: WebhookEvent, body: &str) -> Result<hyper::Response<Body>, String> {
+        let stream = tokio::net::TcpStream::connect(addr).await.map_err(|e| format!("Failed to connect to {}: {}", addr, e))?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.map_err(|e| e.to_string())?;
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+        let path = url.path_and_query().map_or("/", |path| path.as_str());
+        let request = Request::post(path)
+            .header(HOST, url.authority().map_or("", |authority| authority.as_str()))
+            .header(USER_AGENT, "RustPad webhooks")
+            .header(CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event.name())
+            .header(SIGNATURE_HEADER, sign(&hook.secret, body))
+            .body(Body::from(body.to_string()))
+            .map_err(|e| e.to_string())?;
+        sender.send_request(request).await.map_err(|e| e.to_string())
+    }
+}
+
+/// Sends every new activity entry webhooks are subscribed to, each delivery in a task of its own
+pub fn spawn_dispatcher(dispatcher: WebhookDispatcher, feeds: &ActivityFeeds) -> tokio::task::JoinHandle<()> {
+    let mut activity = feeds.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match activity.recv().await {
+                Ok((doc_id, entry)) => {
+                    let Some(event) = WebhookEvent::for_activity(&entry.kind) else { continue };
+                    let dispatcher = dispatcher.clone();
+                    tokio::spawn(async move {
+                        let data = serde_json::to_value(&entry).unwrap_or_default();
+                        dispatcher.dispatch(&doc_id, event, data, Utc::now()).await;
+                    });
+                }
+                Err(RecvError::Lagged(missed)) => eprintln!("Webhooks missed {} activity entries", missed),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+fn error(status: StatusCode, message: &str) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "type": "error", "message": message })), status).into_response()
+}
+
+/// A refused request's status and message
+type Refusal = (StatusCode, String);
+
+/// Refuses anyone but the owner of the workspace of `scope`: webhooks send its activity out
+fn authorize(workspaces: &Workspaces, scope: &WebhookScope, actor: &str) -> Result<(), Refusal> {
+    let role = match scope {
+        WebhookScope::Workspace(id) => {
+            if workspaces.get(id).is_none() {
+                return Err((StatusCode::NOT_FOUND, format!("Unknown workspace {}", id)));
+            }
+            workspaces.role_in(id, actor)
+        }
+        WebhookScope::Document(doc) => {
+            if workspaces.workspace_of(doc).is_none() {
+                return Err((StatusCode::NOT_FOUND, format!("Unknown document {}", doc)));
+            }
+            workspaces.doc_role(doc, actor)
+        }
+    };
+    if role < Some(WorkspaceRole::Owner) {
+        return Err((StatusCode::FORBIDDEN, "Only the owner of the workspace manages its webhooks".to_string()));
+    }
+    Ok(())
+}
+
+/// The webhook `id`, when `actor` may manage it
+fn authorized_hook(store: &WebhookStore, workspaces: &Workspaces, id: &str, actor: &str) -> Result<Webhook, Refusal> {
+    let hook = store.get(id).ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown webhook {}", id)))?;
+    authorize(workspaces, &hook.scope, actor)?;
+    Ok(hook)
+}
+
+/// Routes for webhooks, all acting for the signed-in user, who must own the workspace concerned:
+/// `POST /api/webhooks`, `GET /api/webhooks?workspace=<id>` or `?doc=<id>`, `GET`, `PUT` and
+/// `DELETE /api/webhooks/:id`, and `POST /api/webhooks/:id/test` to send a sample event.
+/// `GET /api/admin/webhooks/dead_letters` lists the failed deliveries, with the admin key as
+/// bearer token.
+pub fn webhook_routes(dispatcher: WebhookDispatcher, admin_key: Option<String>) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let admin = warp::header::optional::<String>("authorization").and_then(move |authorization: Option<String>| {
+        let admin = is_admin(admin_key.as_deref(), authorization.as_deref());
+        async move { if admin { Ok(()) } else { Err(warp::reject::custom(NotAdmin)) } }
+    });
+    let with_dispatcher = warp::any().map(move || dispatcher.clone());
+
+    let create = warp::path!("api" / "webhooks")
+        .and(warp::post())
+        .and(signed_in_user())
+        .and(warp::body::json())
+        .and(with_dispatcher.clone())
+        .map(|actor: String, request: WebhookRequest, dispatcher: WebhookDispatcher| {
+            if let Err((status, message)) = authorize(&dispatcher.workspaces, &request.scope, &actor) {
+                return error(status, &message);
+            }
+            match dispatcher.store.create(&actor, request, Utc::now()) {
+                Ok(hook) => warp::reply::with_status(warp::reply::json(&hook.redacted()), StatusCode::CREATED).into_response(),
+                Err(e) => error(StatusCode::BAD_REQUEST, &e),
+            }
+        });
+    let list = warp::path!("api" / "webhooks")
+        .and(warp::get())
+        .and(signed_in_user())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_dispatcher.clone())
+        .map(|actor: String, query: HashMap<String, String>, dispatcher: WebhookDispatcher| {
+            let scope = match (query.get("workspace"), query.get("doc")) {
+                (Some(id), None) => WebhookScope::Workspace(id.clone()),
+                (None, Some(doc)) => WebhookScope::Document(doc.clone()),
+                _ => return error(StatusCode::BAD_REQUEST, "Listing webhooks needs either ?workspace=<id> or ?doc=<id>"),
+            };
+            if let Err((status, message)) = authorize(&dispatcher.workspaces, &scope, &actor) {
+                return error(status, &message);
+            }
+            let hooks: Vec<Webhook> = dispatcher.store.in_scope(&scope).iter().map(Webhook::redacted).collect();
+            warp::reply::json(&hooks).into_response()
+        });
+    let get = warp::path!("api" / "webhooks" / String)
+        .and(warp::get())
+        .and(signed_in_user())
+        .and(with_dispatcher.clone())
+        .map(|id: String, actor: String, dispatcher: WebhookDispatcher| {
+            match authorized_hook(&dispatcher.store, &dispatcher.workspaces, &id, &actor) {
+                Ok(hook) => warp::reply::json(&hook.redacted()).into_response(),
+                Err((status, message)) => error(status, &message),
+            }
+        });
+    let update = warp::path!("api" / "webhooks" / String)
+        .and(warp::put())
+        .and(signed_in_user())
+        .and(warp::body::json())
+        .and(with_dispatcher.clone())
+        .map(|id: String, actor: String, request: WebhookRequest, dispatcher: WebhookDispatcher| {
+            // Moving a webhook needs ownership of both where it was and where it goes
+            let authorized = authorized_hook(&dispatcher.store, &dispatcher.workspaces, &id, &actor).and_then(|_| authorize(&dispatcher.workspaces, &request.scope, &actor));
+            if let Err((status, message)) = authorized {
+                return error(status, &message);
+            }
+            match dispatcher.store.update(&id, request) {
+                Ok(hook) => warp::reply::json(&hook.redacted()).into_response(),
+                Err(e) => error(StatusCode::BAD_REQUEST, &e),
+            }
+        });
+    let delete = warp::path!("api" / "webhooks" / String)
+        .and(warp::delete())
+        .and(signed_in_user())
+        .and(with_dispatcher.clone())
+        .map(|id: String, actor: String, dispatcher: WebhookDispatcher| {
+            if let Err((status, message)) = authorized_hook(&dispatcher.store, &dispatcher.workspaces, &id, &actor) {
+                return error(status, &message);
+            }
+            dispatcher.store.delete(&id);
+            StatusCode::NO_CONTENT.into_response()
+        });
+    let test = warp::path!("api" / "webhooks" / String / "test")
+        .and(warp::post())
+        .and(signed_in_user())
+        .and(with_dispatcher.clone())
+        .and_then(|id: String, actor: String, dispatcher: WebhookDispatcher| async move {
+            let hook = match authorized_hook(&dispatcher.store, &dispatcher.workspaces, &id, &actor) {
+                Ok(hook) => hook,
+                Err((status, message)) => return Ok::<_, warp::Rejection>(error(status, &message)),
+            };
+            Ok(match dispatcher.send_test(&hook, Utc::now()).await {
+                Ok(()) => warp::reply::json(&serde_json::json!({ "delivered": true })).into_response(),
+                Err(e) => error(StatusCode::BAD_GATEWAY, &format!("The test event wasn't delivered: {}", e)),
+            })
+        });
+    let dead_letters = warp::path!("api" / "admin" / "webhooks" / "dead_letters")
+        .and(warp::get())
+        .and(admin)
+        .and(with_dispatcher)
+        .map(|(), dispatcher: WebhookDispatcher| warp::reply::json(&dispatcher.store.dead_letters()).into_response());
+
+    create
+        .or(list)
+        .unify()
+        .or(get)
+        .unify()
+        .or(update)
+        .unify()
+        .or(delete)
+        .unify()
+        .or(test)
+        .unify()
+        .or(dead_letters)
+        .unify()
+        .recover(|rejection: warp::Rejection| async move {
+            if rejection.find::<NotAdmin>().is_some() {
+                Ok(error(StatusCode::FORBIDDEN, "Dead letters need the admin key"))
+            } else if rejection.find::<SignedOut>().is_some() {
+                Ok(sign_in_required())
+            } else {
+                Err(rejection)
+            }
+        })
+        .unify()
+}
+
+#[derive(Debug)]
+struct NotAdmin;
+
+impl warp::reject::Reject for NotAdmin {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth::bearer_for;
+    use crate::storage::MemoryStorage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use warp::hyper::body::Bytes;
+
+    const SECRET: &str = "correct horse battery";
+
+    /// What a stub receiver got: the event and signature headers, and the body
+    type Received = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    /// A stub receiver on a loopback port answering 500 to the first `failures` requests and
+    /// 204 after that. Counts the requests it gets.
+    fn receiver(failures: usize) -> (SocketAddr, Received, Arc<AtomicUsize>) {
+        let received: Received = Arc::default();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (recorded, counted) = (received.clone(), hits.clone());
+        let routes = warp::post()
+            .and(warp::header::<String>(EVENT_HEADER))
+            .and(warp::header::<String>(SIGNATURE_HEADER))
+            .and(warp::body::bytes())
+            .map(move |event: String, signature: String, body: Bytes| {
+                if counted.fetch_add(1, Ordering::SeqCst) < failures {
+                    return StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                recorded.lock().unwrap().push((event, signature, String::from_utf8_lossy(&body).into_owned()));
+                StatusCode::NO_CONTENT
+            });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, received, hits)
+    }
+
+    /// Olga's "Team" workspace holding notes.md and plan.md, where ed is an editor
+    struct Server {
+        workspaces: Workspaces,
+        store: WebhookStore,
+        team: String,
+    }
+
+    fn server() -> Server {
+        let workspaces = Workspaces::new();
+        let team = workspaces.create("olga", "Team").unwrap().id;
+        workspaces.set_member(&team, "olga", "ed", Some(WorkspaceRole::Editor)).unwrap();
+        workspaces.add_doc(&team, "olga", "notes.md").unwrap();
+        workspaces.add_doc(&team, "olga", "plan.md").unwrap();
+        Server { workspaces, store: WebhookStore::new(), team }
+    }
+
+    fn request(scope: WebhookScope, url: &str, events: Vec<WebhookEvent>) -> WebhookRequest {
+        WebhookRequest { scope, url: url.to_string(), secret: Some(SECRET.to_string()), events }
+    }
+
+    /// A dispatcher sending to `hooks.test` at `addr`, with quick retries
+    fn dispatcher(server: &Server, addr: SocketAddr) -> WebhookDispatcher {
+        WebhookDispatcher::new(server.store.clone(), server.workspaces.clone())
+            .with_host("hooks.test", addr)
+            .with_max_attempts(3)
+            .with_backoff(Duration::from_millis(5))
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_are_signed_with_the_secret() {
+        let server = server();
+        let (addr, received, _) = receiver(0);
+        let dispatcher = dispatcher(&server, addr);
+        server.store.create("olga", request(WebhookScope::Document("notes.md".to_string()), "http://hooks.test/rustpad", vec![WebhookEvent::Saves]), Utc::now()).unwrap();
+
+        assert_eq!(dispatcher.dispatch("notes.md", WebhookEvent::Saves, serde_json::json!({ "user": "ed" }), Utc::now()).await, 1);
+        let (event, signature, body) = received.lock().unwrap()[0].clone();
+        assert_eq!(event, "saves");
+        assert!(verify(SECRET, &body, &signature));
+        assert!(!verify("another secret entirely", &body, &signature));
+        assert!(!verify(SECRET, &body.replace("ed", "eve"), &signature));
+
+        let payload: WebhookPayload = serde_json::from_str(&body).unwrap();
+        assert_eq!((payload.event, payload.doc_id.as_deref()), (WebhookEvent::Saves, Some("notes.md")));
+        assert_eq!(payload.workspace, Some(server.team.clone()));
+        assert_eq!(payload.data["user"], "ed");
+    }
+
+    #[tokio::test]
+    async fn test_failed_deliveries_are_retried_then_dead_lettered() {
+        let server = server();
+        let hook = server.store.create("olga", request(WebhookScope::Workspace(server.team.clone()), "http://hooks.test/", vec![WebhookEvent::Members]), Utc::now()).unwrap();
+
+        // Recovers within the attempts
+        let (addr, received, hits) = receiver(2);
+        assert_eq!(dispatcher(&server, addr).dispatch("notes.md", WebhookEvent::Members, serde_json::json!({}), Utc::now()).await, 1);
+        assert_eq!((hits.load(Ordering::SeqCst), received.lock().unwrap().len()), (3, 1));
+        assert!(server.store.dead_letters().is_empty());
+
+        // Never recovers
+        let (addr, received, hits) = receiver(usize::MAX);
+        assert_eq!(dispatcher(&server, addr).dispatch("notes.md", WebhookEvent::Members, serde_json::json!({}), Utc::now()).await, 0);
+        assert_eq!((hits.load(Ordering::SeqCst), received.lock().unwrap().len()), (3, 0));
+        let dead_letters = server.store.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!((dead_letters[0].attempts, dead_letters[0].payload.webhook_id.as_str()), (3, hook.id.as_str()));
+        assert_eq!(dead_letters[0].error, "The receiver answered 500 Internal Server Error");
+
+        // Listed for admins only
+        let routes = webhook_routes(dispatcher(&server, addr), Some("admin-key".to_string()));
+        let path = "/api/admin/webhooks/dead_letters";
+        assert_eq!(warp::test::request().path(path).reply(&routes).await.status(), 403);
+        let listed = warp::test::request().path(path).header("authorization", "Bearer admin-key").reply(&routes).await;
+        let listed: Vec<DeadLetter> = serde_json::from_slice(listed.body()).unwrap();
+        assert_eq!(listed, dead_letters);
+    }
+
+    #[tokio::test]
+    async fn test_events_are_filtered_by_kind_and_scope() {
+        let server = server();
+        let team = server.store.create("olga", request(WebhookScope::Workspace(server.team.clone()), "http://hooks.test/team", vec![WebhookEvent::Checkpoints, WebhookEvent::Members]), Utc::now()).unwrap();
+        let notes = server.store.create("olga", request(WebhookScope::Document("notes.md".to_string()), "http://hooks.test/notes", vec![WebhookEvent::Saves]), Utc::now()).unwrap();
+        let workspace = Some(server.team.as_str());
+        assert_eq!(server.store.matching("notes.md", workspace, WebhookEvent::Saves), vec![notes]);
+        assert_eq!(server.store.matching("plan.md", workspace, WebhookEvent::Saves), vec![]);
+        assert_eq!(server.store.matching("plan.md", workspace, WebhookEvent::Members), vec![team.clone()]);
+        assert_eq!(server.store.matching("notes.md", None, WebhookEvent::Checkpoints), vec![]); // Moved out of the workspace
+        assert_eq!(server.store.matching("notes.md", workspace, WebhookEvent::ChatMentions), vec![]);
+
+        // Only entries subscribed to are sent on from the activity feed
+        let (addr, received, _) = receiver(0);
+        let feeds = ActivityFeeds::new(50);
+        let task = spawn_dispatcher(dispatcher(&server, addr), &feeds);
+        feeds.with_feed("plan.md", |feed| {
+            feed.record_edit("ed", 3, Utc::now());
+            feed.record_save("ed", Utc::now());
+            feed.record_checkpoint("ed", "Draft", Utc::now());
+        });
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while received.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 1);
+        let payload: WebhookPayload = serde_json::from_str(&received[0].2).unwrap();
+        assert_eq!((payload.event, payload.webhook_id), (WebhookEvent::Checkpoints, team.id));
+        assert_eq!(payload.data["kind"]["name"], "Draft");
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_private_destinations_are_refused() {
+        let server = server();
+        let literal = [("http://127.0.0.1:8080/", "127.0.0.1"), ("http://10.0.0.7/", "10.0.0.7"), ("http://[::1]/", "::1"), ("http://169.254.169.254/latest", "169.254.169.254")];
+        for (url, host) in literal {
+            let refused = server.store.create("olga", request(WebhookScope::Workspace(server.team.clone()), url, vec![WebhookEvent::Saves]), Utc::now());
+            assert_eq!(refused.map(|_| ()), Err(format!("{} isn't a public address", host)));
+        }
+
+        // Names resolving to private addresses are refused when connecting
+        let (addr, _, hits) = receiver(0);
+        let url = format!("http://localhost:{}/", addr.port());
+        server.store.create("olga", request(WebhookScope::Workspace(server.team.clone()), &url, vec![WebhookEvent::Saves]), Utc::now()).unwrap();
+        assert_eq!(dispatcher(&server, addr).dispatch("notes.md", WebhookEvent::Saves, serde_json::json!({}), Utc::now()).await, 0);
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+        assert!(server.store.dead_letters()[0].error.contains("which isn't public"));
+    }
+
+    #[tokio::test]
+    async fn test_config_crud_validation() {
+        let server = server();
+        let storage = Arc::new(MemoryStorage::default());
+        let server = Server { store: WebhookStore::new().with_storage(storage.clone()), ..server };
+        let (addr, received, _) = receiver(0);
+        let routes = webhook_routes(dispatcher(&server, addr), None);
+        let valid = serde_json::json!({ "scope": { "workspace": server.team }, "url": "http://hooks.test/", "secret": SECRET, "events": ["saves"] });
+        let post = |user: &str, body: serde_json::Value| warp::test::request().method("POST").path("/api/webhooks").header("authorization", bearer_for(user)).json(&body);
+
+        // Refused configurations
+        assert_eq!(post("ed", valid.clone()).reply(&routes).await.status(), 403);
+        let mut unknown = valid.clone();
+        unknown["scope"] = serde_json::json!({ "workspace": "nowhere" });
+        assert_eq!(post("olga", unknown).reply(&routes).await.status(), 404);
+        for (field, value) in [("url", "ftp://hooks.test/"), ("url", "https://hooks.test/"), ("secret", "short")] {
+            let mut invalid = valid.clone();
+            invalid[field] = value.into();
+            assert_eq!(post("olga", invalid).reply(&routes).await.status(), 400, "{} {}", field, value);
+        }
+        for events in [serde_json::json!([]), serde_json::json!(["test"])] {
+            let mut invalid = valid.clone();
+            invalid["events"] = events;
+            assert_eq!(post("olga", invalid).reply(&routes).await.status(), 400);
+        }
+
+        // Created, shown without its secret, and persisted
+        let created = post("olga", valid.clone()).reply(&routes).await;
+        assert_eq!(created.status(), 201);
+        let created: serde_json::Value = serde_json::from_slice(created.body()).unwrap();
+        assert!(created.get("secret").is_none());
+        let id = created["id"].as_str().unwrap().to_string();
+        assert_eq!(WebhookStore::new().with_storage(storage).get(&id).unwrap().secret, SECRET);
+        let path = format!("/api/webhooks/{}", id);
+        assert_eq!(warp::test::request().path(&path).header("authorization", bearer_for("ed")).reply(&routes).await.status(), 403);
+        assert_eq!(warp::test::request().path(&format!("{}?user=olga", path)).reply(&routes).await.status(), 401);
+        let listed = warp::test::request().path(&format!("/api/webhooks?workspace={}", server.team)).header("authorization", bearer_for("olga")).reply(&routes).await;
+        assert_eq!(serde_json::from_slice::<Vec<Webhook>>(listed.body()).unwrap().len(), 1);
+
+        // Replaced without a secret, which keeps the one it had
+        let mut replaced = valid.clone();
+        replaced["events"] = serde_json::json!(["checkpoints", "daily_digest"]);
+        replaced.as_object_mut().unwrap().remove("secret");
+        let updated = warp::test::request().method("PUT").path(&path).header("authorization", bearer_for("olga")).json(&replaced).reply(&routes).await;
+        assert_eq!(updated.status(), 200);
+        let hook = server.store.get(&id).unwrap();
+        assert_eq!((hook.events, hook.secret.as_str()), (vec![WebhookEvent::Checkpoints, WebhookEvent::DailyDigest], SECRET));
+
+        // A signed sample event
+        let tested = warp::test::request().method("POST").path(&format!("{}/test", path)).header("authorization", bearer_for("olga")).reply(&routes).await;
+        assert_eq!(tested.status(), 200);
+        let (event, signature, body) = received.lock().unwrap()[0].clone();
+        assert_eq!(event, "test");
+        assert!(verify(SECRET, &body, &signature));
+
+        let deleted = warp::test::request().method("DELETE").path(&path).header("authorization", bearer_for("olga")).reply(&routes).await;
+        assert_eq!(deleted.status(), 204);
+        assert_eq!(warp::test::request().path(&path).header("authorization", bearer_for("olga")).reply(&routes).await.status(), 404);
+    }
+}
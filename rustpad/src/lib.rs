@@ -1,5 +1,25 @@
+// Async handlers share state through std mutexes; a guard held across an await blocks the worker
+#![deny(clippy::await_holding_lock)]
+
 pub mod websocket;
 pub mod document;
+pub mod engine;
 pub mod client;
 pub mod utils;
-pub mod sessions;
\ No newline at end of file
+pub mod sessions;
+pub mod display;
+pub mod config;
+pub mod validation;
+pub mod version;
+pub mod i18n;
+pub mod assets;
+pub mod rooms;
+pub mod rate_limit;
+pub mod tokens;
+pub mod metrics;
+pub mod editor;
+pub mod networking;
+pub mod storage;
+pub mod ui;
+pub mod integrations;
+pub mod auth;
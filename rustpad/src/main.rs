@@ -1,69 +1,437 @@
+#![deny(clippy::await_holding_lock)]
+
 use warp::ws::{Message, WebSocket};
-use warp::{Filter};
+use warp::{Filter, Reply};
 use std::sync::{Arc, Mutex};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use futures_util::{StreamExt, SinkExt};
 use std::collections::HashMap;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::mpsc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use warp::sse::Event;
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+use chrono::Utc;
 use uuid::Uuid; // For generating unique client IDs
+use rustpad::config::ServerConfig;
+use rustpad::editor::diff_engine::DiffEngine;
+use rustpad::i18n::{self, Locale, LocalizedMessage};
+use rustpad::engine::{DeltaUpdate, DocEvent, DocumentEngine, Edit, Resume, SnapshotKind, DEFAULT_EVENT_CAPACITY};
+use rustpad::metrics::{self, Timings};
+use rustpad::rooms::{Notice, Role, RoomRegistry};
+use rustpad::sessions::{self, Sessions, UserSettings};
+use rustpad::tokens::{self, ApiToken, TokenStore};
+use rustpad::version::{self, VersionInfo};
+
+// The library's test helpers aren't compiled into it outside its own tests
+#[cfg(test)]
+#[path = "utils/test_config.rs"]
+mod test_config;
+
+type Clients = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Message>>>>;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct DocumentUpdate {
+/// The document the server hosts, and how long serving it takes. The engine holds the document;
+/// the pad turns what it hands out into frames. New clients load it whole, and so do clients
+/// that fall behind; everyone else gets deltas.
+#[derive(Debug)]
+struct Pad {
+    engine: DocumentEngine,
+    timings: Timings, // How long publishing edits and loading snapshots take
+}
+
+impl Default for Pad {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_CAPACITY)
+    }
+}
+
+type SharedPad = Arc<Mutex<Pad>>;
+
+impl Pad {
+    /// An empty pad whose clients are resynced once they fall `capacity` events behind
+    fn new(capacity: usize) -> Self {
+        Self { engine: DocumentEngine::new(capacity), timings: Timings::new() }
+    }
+
+    /// The whole document, for a client loading it or missing deltas
+    fn frame(&self, kind: SnapshotKind) -> String {
+        let snapshot = self.engine.snapshot(kind);
+        serde_json::json!({ "type": kind.name(), "revision": snapshot.revision, "content": snapshot.content }).to_string()
+    }
+
+    /// What a connecting client gets first: a "resumed" frame and the deltas it missed, or the
+    /// whole document with the token to resume with
+    fn resume_frames(&self, resume: Option<&(String, u64)>) -> Vec<String> {
+        match self.engine.resume(resume) {
+            Resume::Deltas { from, deltas } => {
+                let resumed = serde_json::json!({ "type": "resumed", "from": from, "revision": self.engine.revision() });
+                std::iter::once(resumed.to_string()).chain(deltas.iter().map(|delta| serde_json::to_string(delta).unwrap())).collect()
+            }
+            Resume::Snapshot(snapshot) => {
+                let load = serde_json::json!({ "type": "load", "revision": snapshot.revision, "content": snapshot.content, "resume_token": self.engine.resume_token() });
+                vec![load.to_string()]
+            }
+        }
+    }
+}
+
+/// The server hosts a single pad, which is its only room.
+const ROOM: &str = "pad";
+
+/// Room management requests sent over the WebSocket; anything else is a document update.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RoomCommand {
+    SetMaxEditors { max_editors: usize }, // Owner only
+    KickIdle { idle_secs: u64 },          // Owner only
+    Presence { status: String },          // "active" counts as activity for idle detection
+    Who,                                  // When everyone in the room last did something
+    SaveSettings { settings: UserSettings }, // Kept in the connection's session for its next connections
+}
+
+/// Largest proposed document the diff endpoint takes, in bytes
+const MAX_DIFF_BODY: u64 = 4 * 1024 * 1024;
+
+/// Lines of context around each change in the diff endpoint's unified diff
+const DIFF_CONTEXT_LINES: usize = 3;
+
+/// Content a client proposes for the pad, to see how it differs before sending it
+#[derive(Deserialize, Debug)]
+struct DiffRequest {
     content: String,
-    user: String,
 }
 
-type Clients = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Message>>>>;
+/// Time from receiving an edit to broadcasting its delta, waiting for the pad included
+const PUBLISH_TIMING: &str = "rustpad_publish_seconds";
+
+/// Time to build what a connecting client loads first, waiting for the pad included
+const SNAPSHOT_TIMING: &str = "rustpad_snapshot_seconds";
+
+/// Close code for connections whose API token was revoked
+const TOKEN_REVOKED_CODE: u16 = 4401;
 
 #[tokio::main]
 async fn main() {
     // Shared state: document and list of connected clients
     let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
 
-    // Create a broadcast channel for real-time collaboration
-    let (tx, _rx) = broadcast::channel::<DocumentUpdate>(100);
+    // RUSTPAD_STATIC_DIR serves the frontend from disk while working on it
+    let mut config = ServerConfig::new();
+    if let Ok(dir) = std::env::var("RUSTPAD_STATIC_DIR") {
+        config = config.with_static_dir(dir);
+    }
+    // RUSTPAD_BROADCAST_CAPACITY sets how far a client may fall behind before it is resynced
+    if let Some(capacity) = std::env::var("RUSTPAD_BROADCAST_CAPACITY").ok().and_then(|capacity| capacity.parse().ok()) {
+        config = config.with_broadcast_capacity(capacity);
+    }
+    // RUSTPAD_PORT runs the server elsewhere, as the load generator does
+    if let Some(port) = std::env::var("RUSTPAD_PORT").ok().and_then(|port| port.parse().ok()) {
+        config = config.with_port(port);
+    }
+
+    // The document, broadcasting its changes to every client
+    let pad: SharedPad = Arc::new(Mutex::new(Pad::new(config.broadcast_capacity())));
 
-    // Serve static files (HTML, CSS, JS)
-    let static_files = warp::fs::dir("static");
+    // Serve static files (HTML, CSS, JS), embedded in the binary
+    let static_files = rustpad::assets::routes(config.static_dir.clone());
 
-    // WebSocket route for real-time collaboration
-    let ws_route = warp::path("ws")
-        .and(warp::ws())
-        .and(with_clients(clients.clone()))
-        .and(with_broadcast(tx.clone()))
-        .map(|ws: warp::ws::Ws, clients, tx| {
-            ws.on_upgrade(move |socket| handle_socket(socket, clients, tx))
-        });
+    // Editor slots and the join queue of the pad, and read-only subscribers watching it
+    let mut rooms = RoomRegistry::new(config.max_total_editors);
+    if let Some(max_subscribers) = config.max_subscribers {
+        rooms = rooms.with_max_subscribers(max_subscribers);
+    }
+
+    // API tokens for bots and integrations, managed with RUSTPAD_ADMIN_KEY
+    if let Ok(admin_key) = std::env::var("RUSTPAD_ADMIN_KEY") {
+        config = config.with_admin_key(&admin_key);
+    }
+    let tokens = TokenStore::new();
+    let token_routes = tokens::routes(tokens.clone(), config.admin_key.clone());
 
-    // Combine routes: static files and WebSocket
-    let routes = static_files.or(ws_route);
+    // WebSocket route for real-time collaboration, and the same feed over server-sent events
+    let events_route = events_route(pad.clone(), tokens.clone());
+    let diff_route = diff_route(pad.clone());
+    let presence_route = presence_route(rooms.clone(), config.admin_key.clone());
+    let metrics_route = metrics_route(&pad);
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+    let ws_route = ws_route(clients.clone(), pad, rooms, tokens, sessions);
+
+    // Combine routes: version and token APIs, event feeds, diffs, metrics, static files and WebSocket
+    let routes = version_route().or(token_routes).or(events_route).or(diff_route).or(presence_route).or(metrics_route).or(ws_route).or(static_files);
 
     // Start the server
-    println!("Server running on http://localhost:8080");
-    warp::serve(routes).run(([127, 0, 0, 1], 8080)).await;
+    println!("Server running on http://localhost:{}", config.port());
+    warp::serve(routes).run(([127, 0, 0, 1], config.port())).await;
+}
+
+// Server and protocol versions, so the frontend can tell it's talking to a compatible server
+fn version_route() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "version")
+        .and(warp::get())
+        .map(|| warp::reply::json(&VersionInfo::current()))
+}
+
+// `GET /metrics`: how long publishing edits and loading snapshots of the pad take. The pad is
+// hosted here rather than as a `RoomHost` room, so there are no room gauges: the per-room
+// memory accounting, eviction and trimming apply to servers built on the library's `RoomHost`,
+// which they pass to `metrics::route`.
+fn metrics_route(pad: &SharedPad) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    metrics::route(pad.lock().unwrap().timings.clone(), None)
+}
+
+// Everyone in the pad with when they last did something, and the hidden subscribers, for
+// requests bearing the admin key
+fn presence_route(rooms: RoomRegistry, admin_key: Option<String>) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "presence")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(i18n::accept_language())
+        .map(move |authorization: Option<String>, locale: Locale| {
+            if !tokens::is_admin(admin_key.as_deref(), authorization.as_deref()) {
+                return error_reply(warp::http::StatusCode::FORBIDDEN, LocalizedMessage::new(locale, "error.admin_key_required", &[]));
+            }
+            let presence = serde_json::json!({ "users": rooms.last_seen(ROOM, Utc::now()), "subscribers": rooms.subscribers(ROOM) });
+            warp::reply::json(&presence).into_response()
+        })
+}
+
+fn error_reply(status: warp::http::StatusCode, message: LocalizedMessage) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&message.error_frame()), status).into_response()
+}
+
+fn document_not_found(locale: Locale, doc_id: &str) -> warp::reply::Response {
+    error_reply(warp::http::StatusCode::NOT_FOUND, LocalizedMessage::new(locale, "error.document_not_found", &[("doc_id", doc_id.to_string())]))
+}
+
+/// The API token a request names, as a bearer token or, for clients that can't set headers,
+/// the `token` query parameter: `Ok(None)` without one, the key of an error message when it
+/// isn't valid
+fn authorize(tokens: &TokenStore, authorization: Option<&str>, query: &HashMap<String, String>) -> Result<Option<(ApiToken, watch::Receiver<bool>)>, &'static str> {
+    let Some(secret) = tokens::bearer(authorization).or(query.get("token").map(String::as_str)) else { return Ok(None) };
+    tokens.authorize(secret).map(Some).ok_or("error.invalid_token")
+}
+
+// Server-sent events for integrations that can't hold a WebSocket: `GET /api/docs/pad/events`
+// with an API token streams the pad's deltas, starting with the whole document or, given a
+// `Last-Event-ID`, with the deltas since that revision. Event ids are revisions.
+fn events_route(pad: SharedPad, tokens: TokenStore) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "docs" / String / "events")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::sse::last_event_id::<u64>())
+        .and(i18n::accept_language())
+        .map(move |doc_id: String, authorization: Option<String>, query: HashMap<String, String>, last_event_id: Option<u64>, locale: Locale| {
+            if doc_id != ROOM {
+                return document_not_found(locale, &doc_id);
+            }
+            match authorize(&tokens, authorization.as_deref(), &query) {
+                Ok(Some((_, revoked))) => {
+                    let events = event_stream(&pad, last_event_id, revoked);
+                    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+                }
+                Ok(None) => error_reply(warp::http::StatusCode::UNAUTHORIZED, LocalizedMessage::new(locale, "error.api_token_required", &[])),
+                Err(key) => error_reply(warp::http::StatusCode::UNAUTHORIZED, LocalizedMessage::new(locale, key, &[])),
+            }
+        })
+}
+
+// "Review my changes": `POST /api/docs/pad/diff` with `{"content": ...}` answers with the
+// operations turning the pad at `revision` into that content, and the same as a unified diff
+fn diff_route(pad: SharedPad) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "docs" / String / "diff")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_DIFF_BODY))
+        .and(warp::body::json())
+        .and(i18n::accept_language())
+        .map(move |doc_id: String, request: DiffRequest, locale: Locale| {
+            if doc_id != ROOM {
+                return document_not_found(locale, &doc_id);
+            }
+            let (content, revision) = {
+                let pad = pad.lock().unwrap();
+                (pad.engine.content().to_string(), pad.engine.revision())
+            };
+            let diff = serde_json::json!({
+                "revision": revision,
+                "operations": DiffEngine::diff(&content, &request.content),
+                "unified": DiffEngine::to_unified(&content, &request.content, DIFF_CONTEXT_LINES),
+            });
+            warp::reply::json(&diff).into_response()
+        })
+}
+
+/// The events of an SSE subscriber, ending when its token is revoked
+fn event_stream(pad: &SharedPad, last_event_id: Option<u64>, revoked: watch::Receiver<bool>) -> impl futures_util::Stream<Item = Result<Event, Infallible>> {
+    // Subscribing under the pad's lock, as for WebSocket clients, so no delta is missed or repeated
+    let (rx, backlog) = {
+        let pad = pad.lock().unwrap();
+        let backlog = match last_event_id.and_then(|revision| pad.engine.since(revision)) {
+            Some(deltas) => deltas.iter().map(delta_event).collect(),
+            None => vec![frame_event(&pad, if last_event_id.is_some() { SnapshotKind::Resync } else { SnapshotKind::Load })],
+        };
+        (pad.engine.subscribe(), backlog)
+    };
+
+    let live = futures_util::stream::unfold((rx, pad.clone(), revoked), |(mut rx, pad, mut revoked)| async move {
+        let event = loop {
+            let received = tokio::select! {
+                _ = revoked.wait_for(|revoked| *revoked) => return None,
+                received = rx.recv() => received,
+            };
+            match received {
+                Ok(DocEvent::DeltaApplied(delta)) => break delta_event(&delta),
+                Ok(_) => continue, // Only deltas go out on the feed
+                Err(RecvError::Lagged(_)) => {
+                    let pad = pad.lock().unwrap();
+                    rx = rx.resubscribe();
+                    break frame_event(&pad, SnapshotKind::Resync);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        };
+        Some((event, (rx, pad, revoked)))
+    });
+    futures_util::stream::iter(backlog).chain(live).map(Ok)
+}
+
+fn delta_event(delta: &DeltaUpdate) -> Event {
+    Event::default().event("delta").id((delta.base + 1).to_string()).data(serde_json::to_string(delta).unwrap())
+}
+
+fn frame_event(pad: &Pad, kind: SnapshotKind) -> Event {
+    Event::default().event(kind.name()).id(pad.engine.revision().to_string()).data(pad.frame(kind))
+}
+
+// WebSocket route for real-time collaboration. Clients name their protocol version in the
+// handshake (`/ws?protocol=1.0`) and get "426 Upgrade Required" when the major version differs.
+// Bots and integrations add an API token (`&token=...`); read-only ones watch as hidden subscribers.
+// Connections with a `session_id` cookie get that session's editor settings after the document.
+// Messages meant for the user are written in the `locale` the handshake names, else the one of
+// the session's settings, else the browser's `Accept-Language`.
+// Reconnecting clients pass the `resume_token` of their load frame and the last `revision` they
+// saw, and get a "resumed" frame and the deltas they missed instead of the whole document.
+fn ws_route(clients: Clients, pad: SharedPad, rooms: RoomRegistry, tokens: TokenStore, sessions: Sessions) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("ws")
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("accept-language"))
+        .and(warp::cookie::optional::<String>("session_id"))
+        .and(warp::ws())
+        .and(with_clients(clients))
+        .and(warp::any().map(move || pad.clone()))
+        .and(warp::any().map(move || rooms.clone()))
+        .and(warp::any().map(move || tokens.clone()))
+        .and(warp::any().map(move || sessions.clone()))
+        .map(|query: HashMap<String, String>, authorization: Option<String>, accept_language: Option<String>, session_id: Option<String>, ws: warp::ws::Ws, clients, pad, rooms, tokens: TokenStore, sessions: Sessions| {
+            if let Err(error) = version::negotiate(query.get("protocol").map(String::as_str)) {
+                return warp::reply::with_status(warp::reply::json(&error), warp::http::StatusCode::UPGRADE_REQUIRED).into_response();
+            }
+            match authorize(&tokens, authorization.as_deref(), &query) {
+                Ok(token) => {
+                    let session = session_id.map(|session_id| (sessions, session_id));
+                    let resume = query.get("resume_token").zip(query.get("revision").and_then(|revision| revision.parse().ok()));
+                    let locale = query.get(i18n::LOCALE_PARAM).cloned();
+                    let handshake = Handshake { token, session, resume: resume.map(|(token, revision)| (token.clone(), revision)), locale, accept_language };
+                    ws.on_upgrade(move |socket| handle_socket(socket, clients, pad, rooms, handshake)).into_response()
+                }
+                Err(key) => error_reply(warp::http::StatusCode::UNAUTHORIZED, LocalizedMessage::new(Locale::negotiate(accept_language.as_deref()), key, &[])),
+            }
+        })
+}
+
+/// What a WebSocket client brought to the handshake, besides its protocol version
+struct Handshake {
+    token: Option<(ApiToken, watch::Receiver<bool>)>,
+    session: Option<(Sessions, String)>,
+    resume: Option<(String, u64)>, // Resume token and the last revision seen, when reconnecting
+    locale: Option<String>,          // The `locale` parameter
+    accept_language: Option<String>, // The browser's languages, for when neither the handshake nor the settings name one
 }
 
 // Handler for WebSocket connections
-async fn handle_socket(socket: WebSocket, clients: Clients, tx: broadcast::Sender<DocumentUpdate>) {
+async fn handle_socket(socket: WebSocket, clients: Clients, pad: SharedPad, rooms: RoomRegistry, handshake: Handshake) {
+    let Handshake { token, session, resume, locale, accept_language } = handshake;
+    let settings = session.as_ref().map(|(sessions, session_id)| sessions::load_settings(sessions, session_id));
+    let locale = Locale::negotiate(locale.or(settings.as_ref().and_then(|settings| settings.locale.clone())).or(accept_language).as_deref());
     let client_id = Uuid::new_v4().to_string(); // Generate unique client ID
-    let (client_ws_tx, mut client_ws_rx) = socket.split();
+    let (mut client_ws_tx, mut client_ws_rx) = socket.split();
+    let read_only = token.as_ref().is_some_and(|(token, _)| token.is_read_only());
+    let revoked = token.map(|(_, revoked)| revoked);
+
+    // Read-only tokens watch without taking an editor slot, up to the room's subscriber cap
+    if read_only {
+        if let Err(e) = rooms.subscribe(ROOM, &client_id) {
+            let error = LocalizedMessage::unlocalized(locale, &e).error_frame();
+            let _ = client_ws_tx.send(Message::text(error.to_string())).await;
+            let _ = client_ws_tx.send(Message::close()).await;
+            return;
+        }
+    }
+
+    // Load the whole document first, or what was missed when resuming. Subscribing under the
+    // pad's lock means the deltas that follow are exactly those made after the revision loaded.
+    let (mut rx, first) = {
+        let started = Instant::now();
+        let pad = pad.lock().unwrap();
+        let first = pad.resume_frames(resume.as_ref());
+        pad.timings.record(SNAPSHOT_TIMING, started.elapsed());
+        (pad.engine.subscribe(), first)
+    };
+    for frame in first {
+        if client_ws_tx.send(Message::text(frame)).await.is_err() {
+            rooms.leave(ROOM, &client_id); // Frees the subscriber slot, if it took one
+            return;
+        }
+    }
+
+    // Then the editor settings saved in the session, so the client applies them before editing
+    if let Some(settings) = &settings {
+        let settings = settings_frame(settings);
+        if client_ws_tx.send(Message::text(settings.to_string())).await.is_err() {
+            rooms.leave(ROOM, &client_id);
+            return;
+        }
+    }
 
     // Channel to send messages to the client
     let (sender, mut receiver) = mpsc::unbounded_channel();
     
     // Add the client to the list
+    let error_sender = sender.clone();
     clients.lock().unwrap().insert(client_id.clone(), sender);
 
+    // Join as an editor, or as a queued viewer when the pad is full
+    if !read_only {
+        let (_, notices) = rooms.join(ROOM, &client_id, Instant::now());
+        deliver(&clients, notices);
+    }
+
     // Wrap the WebSocket sender in an Arc<Mutex> for safe sharing between tasks
     let client_ws_tx = Arc::new(tokio::sync::Mutex::new(client_ws_tx));
 
     // Task to receive messages from the broadcast channel and send to WebSocket
     let send_task = {
+        let client_id = client_id.clone();
         let client_ws_tx = client_ws_tx.clone();
-        let mut rx = tx.subscribe();
+        let pad = pad.clone();
         tokio::spawn(async move {
-            while let Ok(update) = rx.recv().await {
-                let message = serde_json::to_string(&update).unwrap();
+            loop {
+                let message = match rx.recv().await {
+                    Ok(DocEvent::DeltaApplied(update)) => serde_json::to_string(&update).unwrap(),
+                    Ok(_) => continue, // Clients only hear of deltas
+                    Err(RecvError::Lagged(skipped)) => {
+                        // Updates were dropped for this client: skip the rest of its backlog and
+                        // send the whole document, resubscribing under the pad's lock so that
+                        // nothing newer is lost or sent twice
+                        println!("Client {} fell {} updates behind; resyncing", client_id, skipped);
+                        let pad = pad.lock().unwrap();
+                        rx = rx.resubscribe();
+                        pad.frame(SnapshotKind::Resync)
+                    }
+                    Err(RecvError::Closed) => break,
+                };
                 if client_ws_tx.lock().await.send(Message::text(message)).await.is_err() {
                     break; // Client disconnected
                 }
@@ -72,19 +440,94 @@ async fn handle_socket(socket: WebSocket, clients: Clients, tx: broadcast::Sende
     };
 
     // Task to receive messages from the WebSocket
-    let recv_task = tokio::spawn(async move {
-        while let Some(result) = client_ws_rx.next().await {
-            if let Ok(message) = result {
-                if let Ok(text) = message.to_str() {
-                    let update: DocumentUpdate = serde_json::from_str(text).unwrap();
-                    println!("Received update from {}: {}", update.user, update.content);
-                    
-                    // Broadcast the update to other clients
-                    let _ = tx.send(update.clone());
+    let recv_task = {
+        let client_id = client_id.clone();
+        let clients = clients.clone();
+        let rooms = rooms.clone();
+        tokio::spawn(async move {
+            let send_error = |key: &str, params: &[(&str, String)]| {
+                let error = LocalizedMessage::new(locale, key, params).error_frame();
+                let _ = error_sender.send(Message::text(error.to_string()));
+            };
+            while let Some(result) = client_ws_rx.next().await {
+                if let Ok(message) = result {
+                    if message.is_close() {
+                        break; // Leave now rather than when a send to the client fails
+                    }
+                    if message.is_ping() {
+                        let _ = error_sender.send(Message::pong(message.into_bytes()));
+                        continue;
+                    }
+                    if message.is_binary() {
+                        println!("Ignoring a binary frame from client {}", client_id);
+                        continue;
+                    }
+                    if let Ok(text) = message.to_str() {
+                        if let Ok(command) = serde_json::from_str::<RoomCommand>(text) {
+                            let result = match command {
+                                RoomCommand::SetMaxEditors { max_editors } => rooms.set_max_editors(ROOM, &client_id, max_editors),
+                                RoomCommand::KickIdle { idle_secs } => {
+                                    rooms.kick_idle(ROOM, &client_id, Duration::from_secs(idle_secs), Instant::now())
+                                }
+                                RoomCommand::Presence { status } => {
+                                    if status == "active" && !read_only {
+                                        rooms.touch(ROOM, &client_id, Instant::now());
+                                        rooms.record_activity(ROOM, &client_id, Utc::now());
+                                    }
+                                    Ok(Vec::new())
+                                }
+                                RoomCommand::Who => {
+                                    let message = serde_json::json!({ "type": "who", "users": rooms.last_seen(ROOM, Utc::now()) });
+                                    Ok(vec![Notice { client_id: client_id.clone(), message }])
+                                }
+                                RoomCommand::SaveSettings { settings } => match &session {
+                                    Some((sessions, session_id)) => {
+                                        let message = settings_frame(&settings);
+                                        sessions::save_settings(sessions, session_id, settings).map(|()| vec![Notice { client_id: client_id.clone(), message }])
+                                    }
+                                    None => {
+                                        send_error("error.session_required", &[]);
+                                        continue;
+                                    }
+                                },
+                            };
+                            match result {
+                                Ok(notices) => deliver(&clients, notices),
+                                Err(e) => send_error("error.request_failed", &[("detail", e)]),
+                            }
+                            continue;
+                        }
+
+                        let edit = match Edit::parse(text) {
+                            Ok(edit) => edit,
+                            Err(e) => {
+                                // Reject invalid input with an error frame instead of broadcasting it
+                                send_error("error.invalid_update", &[("detail", e.to_string())]);
+                                continue;
+                            }
+                        };
+                        if read_only {
+                            send_error("error.read_only_token", &[]);
+                            continue;
+                        }
+                        if rooms.role(ROOM, &client_id) != Some(Role::Editor) {
+                            send_error("error.pad_full", &[]);
+                            continue;
+                        }
+                        rooms.touch(ROOM, &client_id, Instant::now());
+                        rooms.record_activity(ROOM, &client_id, Utc::now());
+
+                        // Apply the edit and broadcast it as a delta; a delta that can't be applied
+                        // leaves the client out of step, so it gets the whole document again
+                        if let Err(e) = publish(&pad, edit) {
+                            send_error("error.request_failed", &[("detail", e)]);
+                            let _ = error_sender.send(Message::text(pad.lock().unwrap().frame(SnapshotKind::Resync)));
+                        }
+                    }
                 }
             }
-        }
-    });
+        })
+    };
 
     // Task to forward messages from the internal channel to the WebSocket
     let forward_task = {
@@ -98,15 +541,49 @@ async fn handle_socket(socket: WebSocket, clients: Clients, tx: broadcast::Sende
         })
     };
 
-    // Wait for either send_task, recv_task, or forward_task to complete
+    // Wait for either send_task, recv_task, or forward_task to complete, or the token to be revoked
+    let (mut send_task, mut recv_task, mut forward_task) = (send_task, recv_task, forward_task);
     tokio::select! {
-        _ = send_task => (),
-        _ = recv_task => (),
-        _ = forward_task => (),
+        _ = &mut send_task => (),
+        _ = &mut recv_task => (),
+        _ = &mut forward_task => (),
+        _ = tokens::revoked(revoked) => {
+            let close = Message::close_with(TOKEN_REVOKED_CODE, "API token revoked");
+            let _ = client_ws_tx.lock().await.send(close).await;
+        }
+    }
+    for task in [send_task, recv_task, forward_task] {
+        task.abort();
     }
 
-    // Remove the client from the list when the connection is closed
+    // Remove the client from the list when the connection is closed, handing its slot on
     clients.lock().unwrap().remove(&client_id);
+    deliver(&clients, rooms.leave(ROOM, &client_id));
+}
+
+// Applies `edit` to the pad, whose engine broadcasts the delta it made
+fn publish(pad: &SharedPad, edit: Edit) -> Result<(), String> {
+    // Applying under the lock broadcasts deltas in the order of the revisions they make
+    let started = Instant::now();
+    let mut pad = pad.lock().unwrap();
+    pad.engine.submit(edit)?;
+    pad.timings.record(PUBLISH_TIMING, started.elapsed());
+    Ok(())
+}
+
+// The frame carrying a connection's editor settings
+fn settings_frame(settings: &UserSettings) -> serde_json::Value {
+    serde_json::json!({ "type": "settings", "settings": settings })
+}
+
+// Sends room notices to the connections they are meant for
+fn deliver(clients: &Clients, notices: Vec<Notice>) {
+    let clients = clients.lock().unwrap();
+    for notice in notices {
+        if let Some(sender) = clients.get(&notice.client_id) {
+            let _ = sender.send(Message::text(notice.message.to_string()));
+        }
+    }
 }
 
 // Utility functions to pass the state around
@@ -114,6 +591,564 @@ fn with_clients(clients: Clients) -> impl Filter<Extract = (Clients,), Error = s
     warp::any().map(move || clients.clone())
 }
 
-fn with_broadcast(tx: broadcast::Sender<DocumentUpdate>) -> impl Filter<Extract = (broadcast::Sender<DocumentUpdate>,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || tx.clone())
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config::config;
+    use proptest::prelude::*;
+    use rustpad::editor::diff_engine::DiffOperation;
+    use rustpad::validation::Username;
+    use std::time::Duration;
+
+    /// How long a client waits for a frame before the room counts as wedged
+    const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Handshake path for a client speaking the server's protocol
+    const WS_PATH: &str = "/ws?protocol=1.0";
+
+    fn test_route() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        route_with_rooms(RoomRegistry::new(None))
+    }
+
+    fn route_with_rooms(rooms: RoomRegistry) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        ws_route(clients, Arc::new(Mutex::new(Pad::default())), rooms, TokenStore::new(), Sessions::default())
+    }
+
+    async fn recv_json(client: &mut warp::test::WsClient) -> serde_json::Value {
+        let reply = tokio::time::timeout(RECV_TIMEOUT, client.recv()).await.expect("no frame").unwrap();
+        serde_json::from_str(reply.to_str().unwrap()).unwrap()
+    }
+
+    async fn expect_error_frame(client: &mut warp::test::WsClient, payload: String) {
+        client.send_text(payload).await;
+        let reply = client.recv().await.unwrap();
+        let reply: serde_json::Value = serde_json::from_str(reply.to_str().unwrap()).unwrap();
+        assert_eq!(reply["type"], "error");
+    }
+
+    /// A test client with its copy of the pad, kept up to date from the frames it receives
+    struct Replica {
+        ws: warp::test::WsClient,
+        content: String,
+        revision: u64,
+    }
+
+    impl Replica {
+        /// Connects, and loads the pad from the first frame
+        async fn connect(route: impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone + Send + Sync + 'static) -> Self {
+            let mut ws = warp::test::ws().path(WS_PATH).handshake(route).await.unwrap();
+            let load = recv_json(&mut ws).await;
+            assert_eq!(load["type"], "load");
+            let mut replica = Replica { ws, content: String::new(), revision: 0 };
+            replica.load(&load);
+            replica
+        }
+
+        /// Takes in a load or resync frame
+        fn load(&mut self, frame: &serde_json::Value) {
+            self.content = frame["content"].as_str().unwrap().to_string();
+            self.revision = frame["revision"].as_u64().unwrap();
+        }
+
+        /// Waits for the next delta and applies it to the copy
+        async fn recv_update(&mut self) -> DeltaUpdate {
+            let frame = tokio::time::timeout(RECV_TIMEOUT, self.ws.recv()).await.expect("room wedged").unwrap();
+            let delta: DeltaUpdate = serde_json::from_str(frame.to_str().unwrap()).unwrap();
+            assert_eq!(delta.base, self.revision, "delta for another revision");
+            self.content = DiffEngine::apply(&self.content, &delta.operations);
+            self.revision += 1;
+            delta
+        }
+    }
+
+    fn user(name: &str) -> Username {
+        serde_json::from_value(serde_json::json!(name)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_invalid_updates_are_rejected_with_error_frame() {
+        let mut client = Replica::connect(test_route()).await;
+
+        let oversized = serde_json::json!({ "content": "x", "user": "a".repeat(1000) });
+        expect_error_frame(&mut client.ws, oversized.to_string()).await;
+
+        let control = serde_json::json!({ "content": "x", "user": "bad\u{0007}name" });
+        expect_error_frame(&mut client.ws, control.to_string()).await;
+
+        // The connection stays usable and valid updates are still broadcast
+        let valid = serde_json::json!({ "content": "hello", "user": "alice" });
+        client.ws.send_text(valid.to_string()).await;
+        let update = client.recv_update().await;
+        assert_eq!((update.user.as_str(), client.content.as_str()), ("alice", "hello"));
+    }
+
+    #[tokio::test]
+    async fn test_bidi_override_stripped_from_username() {
+        let mut client = Replica::connect(test_route()).await;
+
+        let update = serde_json::json!({ "content": "hi", "user": "eve\u{202E}gnp.exe" });
+        client.ws.send_text(update.to_string()).await;
+        assert_eq!(client.recv_update().await.user.as_str(), "evegnp.exe");
+    }
+
+    #[tokio::test]
+    async fn test_version_api() {
+        let response = warp::test::request().path("/api/version").reply(&version_route()).await;
+        let info: VersionInfo = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.protocol, version::PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_protocol_negotiation() {
+        assert!(warp::test::ws().path("/ws?protocol=1.3").handshake(test_route()).await.is_ok());
+
+        for path in ["/ws?protocol=2.0", "/ws"] {
+            assert!(warp::test::ws().path(path).handshake(test_route()).await.is_err());
+            let response = warp::test::request()
+                .path(path)
+                .header("connection", "upgrade")
+                .header("upgrade", "websocket")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .reply(&test_route())
+                .await;
+            assert_eq!(response.status(), 426);
+            let error: version::UpgradeRequired = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(error.kind, "upgrade_required");
+            assert_eq!(error.server_protocol, version::PROTOCOL_VERSION);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_pad_queues_viewers_until_a_slot_frees() {
+        let rooms = RoomRegistry::new(None);
+        rooms.open(ROOM, 1);
+        let route = route_with_rooms(rooms);
+        let editor = Replica::connect(route.clone()).await;
+        let mut viewer = Replica::connect(route.clone()).await;
+        assert_eq!(recv_json(&mut viewer.ws).await, serde_json::json!({ "type": "queued", "position": 1 }));
+
+        // Viewers can't edit, and only the owner can raise the cap
+        let update = serde_json::json!({ "content": "hi", "user": "bob" });
+        viewer.ws.send_text(update.to_string()).await;
+        assert_eq!(recv_json(&mut viewer.ws).await["type"], "error");
+        viewer.ws.send_text(serde_json::json!({ "type": "set_max_editors", "max_editors": 2 }).to_string()).await;
+        assert_eq!(recv_json(&mut viewer.ws).await["type"], "error");
+
+        // The editor leaving promotes the viewer without a reconnect
+        drop(editor);
+        let promoted = recv_json(&mut viewer.ws).await;
+        assert_eq!((promoted["type"].as_str(), promoted["role"].as_str()), (Some("presence"), Some("editor")));
+        viewer.ws.send_text(update.to_string()).await;
+        viewer.recv_update().await;
+        assert_eq!(viewer.content, "hi");
+    }
+
+    #[tokio::test]
+    async fn test_lagging_client_is_resynced() {
+        let config = ServerConfig::new().with_broadcast_capacity(4);
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let pad: SharedPad = Arc::new(Mutex::new(Pad::new(config.broadcast_capacity())));
+        let route = ws_route(clients, pad.clone(), RoomRegistry::new(None), TokenStore::new(), Sessions::default());
+        let mut client = connect(route, 1).await.remove(0);
+
+        // The single-threaded test runtime can't forward anything until the test awaits, so the
+        // client falls more than the channel's capacity behind
+        for n in 0..10 {
+            let update = serde_json::json!({ "content": format!("version {}", n), "user": "alice" });
+            publish(&pad, Edit::Full(serde_json::from_value(update).unwrap())).unwrap();
+        }
+        let resync = recv_json(&mut client.ws).await;
+        assert_eq!(resync, serde_json::json!({ "type": "resync", "revision": 11, "content": "version 9" }));
+        client.load(&resync);
+
+        // The backlog is skipped and later updates arrive as usual
+        let update = serde_json::json!({ "content": "version 10", "user": "alice" });
+        client.ws.send_text(update.to_string()).await;
+        client.recv_update().await;
+        assert_eq!(client.content, "version 10");
+    }
+
+    #[tokio::test]
+    async fn test_late_joiner_loads_current_content() {
+        let route = test_route();
+        let mut early = connect(route.clone(), 1).await.remove(0);
+        let edit = serde_json::json!({ "base": 1, "operations": [{ "Insert": [7, ", wörld"] }], "user": "alice" });
+        early.ws.send_text(edit.to_string()).await;
+        early.recv_update().await;
+
+        // The first frame holds the pad as it is, not a blank document
+        let mut late = warp::test::ws().path(WS_PATH).handshake(route).await.unwrap();
+        let mut load = recv_json(&mut late).await;
+        assert!(load.as_object_mut().unwrap().remove("resume_token").is_some_and(|token| token.is_string()));
+        assert_eq!(load, serde_json::json!({ "type": "load", "revision": 2, "content": "hello 0, wörld" }));
+
+        // Later edits reach it as deltas on top of what it loaded
+        let mut late = Replica { ws: late, content: "hello 0, wörld".to_string(), revision: 2 };
+        early.ws.send_text(serde_json::json!({ "content": "bye", "user": "alice" }).to_string()).await;
+        late.recv_update().await;
+        assert_eq!(late.content, "bye");
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting_client_resumes_with_missed_deltas() {
+        let route = test_route();
+        let mut dropped = warp::test::ws().path(WS_PATH).handshake(route.clone()).await.unwrap();
+        let load = recv_json(&mut dropped).await;
+        let token = load["resume_token"].as_str().unwrap().to_string();
+        drop(dropped);
+
+        let mut editor = connect(route.clone(), 1).await.remove(0);
+        editor.ws.send_text(serde_json::json!({ "content": "bye", "user": "alice" }).to_string()).await;
+        editor.recv_update().await;
+
+        // Only the deltas since the revision seen last come back, not the whole document
+        let path = format!("{}&resume_token={}&revision=0", WS_PATH, token);
+        let ws = warp::test::ws().path(&path).handshake(route.clone()).await.unwrap();
+        let mut resumed = Replica { ws, content: String::new(), revision: 0 };
+        assert_eq!(recv_json(&mut resumed.ws).await, serde_json::json!({ "type": "resumed", "from": 0, "revision": 2 }));
+        resumed.recv_update().await;
+        resumed.recv_update().await;
+        assert_eq!(resumed.content, "bye");
+
+        // Another pad's token, or a revision the pad never had, loads the whole document
+        for path in [format!("{}&resume_token=stale&revision=0", WS_PATH), format!("{}&resume_token={}&revision=9", WS_PATH, token)] {
+            let mut ws = warp::test::ws().path(&path).handshake(route.clone()).await.unwrap();
+            let load = recv_json(&mut ws).await;
+            assert_eq!((load["type"].as_str(), load["content"].as_str(), load["resume_token"].as_str()), (Some("load"), Some("bye"), Some(token.as_str())));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_frame_removes_client_at_once() {
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let route = ws_route(clients.clone(), Arc::new(Mutex::new(Pad::default())), RoomRegistry::new(None), TokenStore::new(), Sessions::default());
+        let mut client = Replica::connect(route).await;
+
+        // Pings are answered and binary frames ignored, without dropping the connection
+        client.ws.send(Message::ping(b"still there?".to_vec())).await;
+        let pong = tokio::time::timeout(RECV_TIMEOUT, client.ws.recv()).await.expect("no pong").unwrap();
+        assert!(pong.is_pong() && pong.as_bytes() == b"still there?");
+        client.ws.send(Message::binary(vec![1, 2, 3])).await;
+        assert_eq!(clients.lock().unwrap().len(), 1);
+
+        // The client is gone as soon as its close frame is read, while its socket is still open
+        client.ws.send(Message::close()).await;
+        tokio::time::timeout(RECV_TIMEOUT, async {
+            while !clients.lock().unwrap().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("client not removed after closing");
+    }
+
+    #[tokio::test]
+    async fn test_messages_follow_the_requested_locale() {
+        let rooms = RoomRegistry::new(None);
+        rooms.open(ROOM, 1);
+        let route = route_with_rooms(rooms);
+        let _editor = Replica::connect(route.clone()).await;
+        let update = serde_json::json!({ "content": "hi", "user": "bob" }).to_string();
+
+        // The handshake's locale wins over the browser's languages
+        for (path, accept_language, message) in [
+            (format!("{}&locale=de-AT", WS_PATH), "en", "Das Pad ist voll; du kannst bearbeiten, sobald ein Platz zum Bearbeiten frei wird"),
+            (format!("{}&locale=en", WS_PATH), "de", "The pad is full; you can edit once an editor slot frees up"),
+            (WS_PATH.to_string(), "fr, de;q=0.5", "Das Pad ist voll; du kannst bearbeiten, sobald ein Platz zum Bearbeiten frei wird"),
+        ] {
+            let mut viewer = warp::test::ws().path(&path).header("accept-language", accept_language).handshake(route.clone()).await.unwrap();
+            assert_eq!(recv_json(&mut viewer).await["type"], "load");
+            assert_eq!(recv_json(&mut viewer).await["type"], "queued");
+            viewer.send_text(update.clone()).await;
+            let error = recv_json(&mut viewer).await;
+            assert_eq!(error, serde_json::json!({ "type": "error", "message_key": "error.pad_full", "message": message }));
+        }
+
+        // HTTP errors go by `Accept-Language`, with the parameters for clients rendering them
+        let events = events_route(Arc::new(Mutex::new(Pad::default())), TokenStore::new());
+        let response = warp::test::request().path("/api/docs/plan/events").header("accept-language", "de").reply(&events).await;
+        assert_eq!(response.status(), 404);
+        let error: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!((error["message_key"].as_str(), error["params"]["doc_id"].as_str()), (Some("error.document_not_found"), Some("plan")));
+        assert_eq!(error["message"], "Dokument plan existiert nicht");
+    }
+
+    #[tokio::test]
+    async fn test_saved_settings_restored_on_reconnect() {
+        let sessions = Sessions::default();
+        let route = ws_route(Arc::new(Mutex::new(HashMap::new())), Arc::new(Mutex::new(Pad::default())), RoomRegistry::new(None), TokenStore::new(), sessions.clone());
+        let handshake = |session_id: &str| warp::test::ws().path(WS_PATH).header("cookie", format!("session_id={}", session_id));
+
+        // A new session starts with the defaults, right after the document
+        let mut first = handshake("abc").handshake(route.clone()).await.unwrap();
+        assert_eq!(recv_json(&mut first).await["type"], "load");
+        let defaults = recv_json(&mut first).await;
+        assert_eq!(defaults, serde_json::json!({ "type": "settings", "settings": UserSettings::default() }));
+
+        // Invalid settings are refused; valid ones are saved and echoed back
+        let mut settings = UserSettings { tab_width: 0, ..UserSettings::default() };
+        first.send_text(serde_json::json!({ "type": "save_settings", "settings": settings }).to_string()).await;
+        assert_eq!(recv_json(&mut first).await["type"], "error");
+        settings = UserSettings { indent_with_spaces: true, tab_width: 2, theme: Some("solarized".to_string()), wrap_width: Some(100), autosave_secs: Some(30), ..UserSettings::default() };
+        first.send_text(serde_json::json!({ "type": "save_settings", "settings": settings }).to_string()).await;
+        assert_eq!(recv_json(&mut first).await["settings"], serde_json::json!(settings));
+        drop(first);
+
+        // A new connection with the same session gets them back; other sessions don't
+        let mut second = handshake("abc").handshake(route.clone()).await.unwrap();
+        recv_json(&mut second).await;
+        assert_eq!(serde_json::from_value::<UserSettings>(recv_json(&mut second).await["settings"].take()).unwrap(), settings);
+        let mut other = handshake("xyz").handshake(route.clone()).await.unwrap();
+        recv_json(&mut other).await;
+        assert_eq!(recv_json(&mut other).await["settings"], serde_json::json!(UserSettings::default()));
+
+        // Without a session there is nowhere to keep them
+        let mut anonymous = Replica::connect(route).await;
+        expect_error_frame(&mut anonymous.ws, serde_json::json!({ "type": "save_settings", "settings": settings }).to_string()).await;
+    }
+
+    #[tokio::test]
+    async fn test_read_only_token_watches_as_hidden_subscriber() {
+        let (clients, rooms, tokens): (Clients, _, _) = (Arc::new(Mutex::new(HashMap::new())), RoomRegistry::new(None), TokenStore::new());
+        let route = ws_route(clients, Arc::new(Mutex::new(Pad::default())), rooms.clone(), tokens.clone(), Sessions::default());
+        let (secret, token) = tokens.create("ci", vec![tokens::Scope::DocRead]).unwrap();
+        let mut editor = connect(route.clone(), 1).await.remove(0);
+
+        assert!(warp::test::ws().path(&format!("{}&token=nope", WS_PATH)).handshake(route.clone()).await.is_err());
+        let ws = warp::test::ws().path(&format!("{}&token={}", WS_PATH, secret)).handshake(route.clone()).await.unwrap();
+        let mut bot = Replica { ws, content: String::new(), revision: 0 };
+        let load = recv_json(&mut bot.ws).await;
+        bot.load(&load);
+        assert_eq!(bot.content, "hello 0");
+
+        // It gets the deltas but isn't among the people in the pad
+        editor.ws.send_text(serde_json::json!({ "content": "hello bots", "user": "alice" }).to_string()).await;
+        editor.recv_update().await;
+        bot.recv_update().await;
+        assert_eq!(bot.content, "hello bots");
+        editor.ws.send_text(serde_json::json!({ "type": "who" }).to_string()).await;
+        assert_eq!(recv_json(&mut editor.ws).await["users"].as_array().unwrap().len(), 1);
+        assert_eq!(rooms.subscribers(ROOM).len(), 1);
+
+        // Its edits are refused
+        expect_error_frame(&mut bot.ws, serde_json::json!({ "content": "spam", "user": "bot" }).to_string()).await;
+
+        // Revoking the token disconnects it
+        tokens.revoke(&token.id).unwrap();
+        tokio::time::timeout(RECV_TIMEOUT, bot.ws.recv_closed()).await.expect("not disconnected").unwrap();
+        tokio::time::timeout(RECV_TIMEOUT, async {
+            while !rooms.subscribers(ROOM).is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("subscriber not removed");
+    }
+
+    #[tokio::test]
+    async fn test_served_metrics_report_the_pad_timings() {
+        let pad: SharedPad = Arc::new(Mutex::new(Pad::default()));
+        let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+        let route = ws_route(clients, pad.clone(), RoomRegistry::new(None), TokenStore::new(), Sessions::default());
+        let mut client = warp::test::ws().path(WS_PATH).handshake(route).await.unwrap();
+        recv_json(&mut client).await;
+
+        let response = warp::test::request().path("/metrics").reply(&metrics_route(&pad)).await;
+        assert_eq!(response.status(), 200);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains(&format!("{}_count 1\n", SNAPSHOT_TIMING)), "{}", body);
+        assert!(!body.contains("rustpad_rooms_loaded"));
+    }
+
+    #[tokio::test]
+    async fn test_diff_against_server_document() {
+        let pad: SharedPad = Arc::new(Mutex::new(Pad::default()));
+        pad.lock().unwrap().engine.replace("fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n", user("alice")).unwrap();
+        let route = diff_route(pad.clone());
+        let proposed = "fn main() {\n    let y = 2;\n    println!(\"{}\", y);\n}\n";
+
+        let response = warp::test::request().method("POST").path("/api/docs/pad/diff").json(&serde_json::json!({ "content": proposed })).reply(&route).await;
+        assert_eq!(response.status(), 200);
+        let diff: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(diff["revision"], 1);
+        let operations: Vec<DiffOperation> = serde_json::from_value(diff["operations"].clone()).unwrap();
+        assert_eq!(
+            operations,
+            vec![DiffOperation::Replace(46, 47, "y".to_string()), DiffOperation::Replace(20, 25, "y = 2".to_string())]
+        );
+        assert_eq!(DiffEngine::apply(pad.lock().unwrap().engine.content(), &operations), proposed);
+        assert_eq!(
+            diff["unified"],
+            "@@ -1,4 +1,4 @@\n fn main() {\n-    let x = 1;\n-    println!(\"{}\", x);\n+    let y = 2;\n+    println!(\"{}\", y);\n }\n"
+        );
+
+        // The pad itself is left alone
+        assert_eq!(pad.lock().unwrap().engine.revision(), 1);
+        let missing = warp::test::request().method("POST").path("/api/docs/other/diff").json(&serde_json::json!({ "content": "" })).reply(&route).await;
+        assert_eq!(missing.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_event_feed_resumes_from_last_event_id() {
+        let (pad, tokens): (SharedPad, _) = (Arc::new(Mutex::new(Pad::default())), TokenStore::new());
+        let route = events_route(pad.clone(), tokens.clone());
+        let (secret, token) = tokens.create("bridge", vec![tokens::Scope::DocRead]).unwrap();
+        for n in 1..=3 {
+            let update = serde_json::json!({ "content": format!("version {}", n), "user": "alice" });
+            publish(&pad, Edit::Full(serde_json::from_value(update).unwrap())).unwrap();
+        }
+
+        let unauthorized = warp::test::request().path("/api/docs/pad/events").reply(&route).await;
+        assert_eq!(unauthorized.status(), 401);
+        let missing = warp::test::request().path(&format!("/api/docs/other/events?token={}", secret)).reply(&route).await;
+        assert_eq!(missing.status(), 404);
+
+        // The feed stays open until the token is revoked, picking up new deltas meanwhile
+        let request = warp::test::request().path("/api/docs/pad/events").header("authorization", format!("Bearer {}", secret)).header("last-event-id", "1");
+        let (response, ()) = tokio::join!(request.reply(&route), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let update = serde_json::json!({ "content": "version 4", "user": "bob" });
+            publish(&pad, Edit::Full(serde_json::from_value(update).unwrap())).unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tokens.revoke(&token.id).unwrap();
+        });
+        assert_eq!(response.status(), 200);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        let events: Vec<(&str, &str)> = body
+            .split("\n\n")
+            .filter_map(|event| Some((event.lines().find_map(|line| line.strip_prefix("event:"))?, event.lines().find_map(|line| line.strip_prefix("id:"))?)))
+            .collect();
+        assert_eq!(events, vec![("delta", "2"), ("delta", "3"), ("delta", "4")]);
+        assert!(body.contains("\"user\":\"bob\""));
+
+        // Without a Last-Event-ID the feed starts from the whole document, and so it does with
+        // one the pad no longer has deltas for
+        let (_, revoked) = watch::channel(false);
+        for (last_event_id, kind) in [(None, "load"), (Some(99), "resync")] {
+            let mut events = Box::pin(event_stream(&pad, last_event_id, revoked.clone()));
+            let first = events.next().await.unwrap().unwrap().to_string();
+            assert!(first.starts_with(&format!("event:{}\n", kind)) && first.contains("version 4") && first.contains("\nid:4\n"), "{}", first);
+        }
+    }
+
+    #[test]
+    fn test_patch_edits_apply_only_where_they_match() {
+        let pad: SharedPad = Arc::new(Mutex::new(Pad::default()));
+        pad.lock().unwrap().engine.replace("fn main() {\n    run();\n}\n", user("alice")).unwrap();
+        let mut rx = pad.lock().unwrap().engine.subscribe();
+
+        let new = "fn main() {\n    setup();\n    run();\n}\n";
+        let patch = DiffEngine::to_unified(pad.lock().unwrap().engine.content(), new, 3);
+        publish(&pad, Edit::parse(&serde_json::json!({ "patch": patch, "user": "bob" }).to_string()).unwrap()).unwrap();
+        assert_eq!(pad.lock().unwrap().engine.content(), new);
+        let Ok(DocEvent::DeltaApplied(delta)) = rx.try_recv() else { panic!("no delta broadcast") };
+        assert_eq!(delta.operations, vec![DiffOperation::Insert(16, "setup();\n    ".to_string())]);
+
+        // Applied again, the patch's context no longer matches and the pad is left alone
+        let stale = serde_json::json!({ "patch": patch, "user": "bob" }).to_string();
+        assert_eq!(publish(&pad, Edit::parse(&stale).unwrap()), Err("Hunk 1 doesn't match the text at line 2".to_string()));
+        let pad = pad.lock().unwrap();
+        assert_eq!((pad.engine.content(), pad.engine.revision()), (new, 2));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_deltas_converge() {
+        let mut clients = connect(test_route(), 2).await; // The pad holds "hello 1"
+        let send = |operations: serde_json::Value| serde_json::json!({ "base": 2, "operations": operations, "user": "alice" }).to_string();
+        clients[0].ws.send_text(send(serde_json::json!([{ "Insert": [0, "say "] }]))).await;
+        clients[1].ws.send_text(send(serde_json::json!([{ "Replace": [6, 7, "world"] }]))).await;
+        for client in clients.iter_mut() {
+            client.recv_update().await;
+            client.recv_update().await;
+            assert_eq!(client.content, "say hello world");
+        }
+
+        // A delta that doesn't fit gets an error and the whole document back
+        clients[0].ws.send_text(send(serde_json::json!([{ "Delete": [0, 99] }]))).await;
+        assert_eq!(recv_json(&mut clients[0].ws).await["type"], "error");
+        assert_eq!(recv_json(&mut clients[0].ws).await, serde_json::json!({ "type": "resync", "revision": 4, "content": "say hello world" }));
+    }
+
+    /// Connects `count` clients and waits until every one of them is subscribed to the broadcast.
+    async fn connect(route: impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone + Send + Sync + 'static, count: usize) -> Vec<Replica> {
+        let mut clients: Vec<Replica> = Vec::new();
+        for i in 0..count {
+            let mut client = Replica::connect(route.clone()).await;
+            // A client that receives its own update is subscribed; earlier clients see it too
+            let hello = serde_json::json!({ "content": format!("hello {}", i), "user": format!("user{}", i) });
+            client.ws.send_text(hello.to_string()).await;
+            client.recv_update().await;
+            assert_eq!(client.content, format!("hello {}", i));
+            for earlier in clients.iter_mut() {
+                earlier.recv_update().await;
+                assert_eq!(earlier.content, format!("hello {}", i));
+            }
+            clients.push(client);
+        }
+        clients
+    }
+
+    proptest! {
+        #![proptest_config(config(32))]
+
+        /// Arbitrary frames never take the handler down: afterwards the room still broadcasts.
+        #[test]
+        fn prop_ws_survives_arbitrary_frames(
+            frames in prop::collection::vec(prop_oneof![
+                ".{0,32}",
+                "\\{\"content\": ?\".{0,8}\", ?\"user\": ?\".{0,40}\"\\}",
+                "\\{\"base\": ?[0-9], ?\"operations\": ?\\[\\{\"(Insert|Delete|Replace)\": ?\\[[0-9], ?([0-9]|\".{0,4}\")(, ?\".{0,4}\")?\\]\\}\\], ?\"user\": ?\"bob\"\\}",
+                Just("{\"content\": 1, \"user\": null}".to_string()),
+            ], 0..8),
+            binary in prop::collection::vec(any::<u8>(), 0..32),
+        ) {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let mut clients = connect(test_route(), 2).await;
+                for frame in &frames {
+                    clients[0].ws.send_text(frame.clone()).await;
+                }
+                clients[0].ws.send(Message::binary(binary.clone())).await;
+
+                let marker = serde_json::json!({ "content": "still alive", "user": "still-alive" });
+                clients[1].ws.send_text(marker.to_string()).await;
+                for client in clients.iter_mut() {
+                    // Skip error frames, resyncs and accepted fuzz updates until the marker arrives
+                    loop {
+                        let reply = recv_json(&mut client.ws).await;
+                        if reply["user"] == "still-alive" {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+
+        /// Clients sending updates concurrently all receive every update, in the same order,
+        /// and end with the same document.
+        #[test]
+        fn prop_clients_converge(script in prop::collection::vec((0..3usize, "[a-z é]{0,6}"), 1..12)) {
+            let histories = tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let mut clients = connect(test_route(), 3).await;
+                for (sender, content) in &script {
+                    let update = serde_json::json!({ "content": content, "user": format!("user{}", sender) });
+                    clients[*sender].ws.send_text(update.to_string()).await;
+                }
+
+                let mut histories = Vec::new();
+                for client in clients.iter_mut() {
+                    let mut history = Vec::new();
+                    for _ in 0..script.len() {
+                        let update = client.recv_update().await;
+                        history.push((update.user.as_str().to_string(), client.content.clone()));
+                    }
+                    histories.push(history);
+                }
+                histories
+            });
+            prop_assert!(histories.iter().all(|history| *history == histories[0]), "clients diverged: {:?}", histories);
+        }
+    }
+}
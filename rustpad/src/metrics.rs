@@ -0,0 +1,137 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::Filter;
+use crate::networking::room_host::RoomHost;
+
+/// Samples kept of each timing; its quantiles are over the most recent ones
+pub const MAX_SAMPLES: usize = 4096;
+
+/// Quantiles `GET /metrics` reports of each timing
+pub const REPORTED_QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// How long the server's hot paths take, by metric name: applying and broadcasting an edit,
+/// building the snapshot a joining client loads. Clones share the timings.
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    timings: Arc<Mutex<BTreeMap<&'static str, Timing>>>,
+}
+
+#[derive(Debug, Default)]
+struct Timing {
+    recent: VecDeque<Duration>, // The latest `MAX_SAMPLES` durations, oldest first
+    count: u64,
+    sum: Duration,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts one run of `name` that took `duration`
+    pub fn record(&self, name: &'static str, duration: Duration) {
+        let mut timings = self.timings.lock().unwrap();
+        let timing = timings.entry(name).or_default();
+        if timing.recent.len() == MAX_SAMPLES {
+            timing.recent.pop_front();
+        }
+        timing.recent.push_back(duration);
+        timing.count += 1;
+        timing.sum += duration;
+    }
+
+    /// The `q` quantile of the recent durations of `name`, if it ran at all
+    pub fn quantile(&self, name: &str, q: f64) -> Option<Duration> {
+        let timings = self.timings.lock().unwrap();
+        let recent: Vec<Duration> = timings.get(name)?.recent.iter().copied().collect();
+        quantile(&recent, q)
+    }
+
+    /// The timings as Prometheus summaries, in seconds
+    pub fn render(&self) -> String {
+        let timings = self.timings.lock().unwrap();
+        let mut rendered = String::new();
+        for (name, timing) in timings.iter() {
+            let recent: Vec<Duration> = timing.recent.iter().copied().collect();
+            rendered.push_str(&format!("# TYPE {} summary\n", name));
+            for q in REPORTED_QUANTILES {
+                if let Some(duration) = quantile(&recent, q) {
+                    rendered.push_str(&format!("{}{{quantile=\"{}\"}} {}\n", name, q, duration.as_secs_f64()));
+                }
+            }
+            rendered.push_str(&format!("{}_sum {}\n{}_count {}\n", name, timing.sum.as_secs_f64(), name, timing.count));
+        }
+        rendered
+    }
+}
+
+/// `GET /metrics`: the timings as Prometheus summaries, for dashboards and the load generator,
+/// then the gauges of the rooms `host` keeps loaded when the server hosts rooms
+pub fn route(timings: Timings, host: Option<RoomHost>) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("metrics").and(warp::get()).map(move || {
+        let mut metrics = timings.render();
+        if let Some(host) = &host {
+            metrics.push_str(&host.metrics(Instant::now()));
+        }
+        warp::reply::with_header(metrics, "content-type", "text/plain; version=0.0.4")
+    })
+}
+
+/// The `q` quantile (0 to 1) of `samples` by nearest rank, or `None` when there are none
+pub fn quantile(samples: &[Duration], q: f64) -> Option<Duration> {
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    let rank = (q.clamp(0.0, 1.0) * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.max(1) - 1).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::room_host::MemoryLimits;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_quantiles_by_nearest_rank() {
+        let samples: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        assert_eq!(quantile(&samples, 0.5), Some(Duration::from_millis(50)));
+        assert_eq!(quantile(&samples, 0.99), Some(Duration::from_millis(99)));
+        assert_eq!(quantile(&samples, 0.0), Some(Duration::from_millis(1)));
+        assert_eq!(quantile(&samples, 1.0), Some(Duration::from_millis(100)));
+        assert_eq!(quantile(&[], 0.5), None);
+    }
+
+    #[test]
+    fn test_timings_render_as_summaries_of_recent_samples() {
+        let timings = Timings::new();
+        for _ in 0..MAX_SAMPLES {
+            timings.record("rustpad_publish_seconds", Duration::from_secs(1));
+        }
+        // Only the latest samples count towards the quantiles; the sum and count cover all
+        timings.clone().record("rustpad_publish_seconds", Duration::from_secs(3));
+        assert_eq!(timings.quantile("rustpad_publish_seconds", 1.0), Some(Duration::from_secs(3)));
+        assert_eq!(timings.quantile("rustpad_snapshot_seconds", 0.5), None);
+
+        let rendered = timings.render();
+        assert!(rendered.starts_with("# TYPE rustpad_publish_seconds summary\nrustpad_publish_seconds{quantile=\"0.5\"} 1\n"));
+        assert!(rendered.ends_with(&format!("rustpad_publish_seconds_sum {}\nrustpad_publish_seconds_count {}\n", MAX_SAMPLES + 3, MAX_SAMPLES + 1)));
+    }
+
+    #[tokio::test]
+    async fn test_one_endpoint_reports_timings_and_room_gauges() {
+        let timings = Timings::new();
+        timings.record("rustpad_publish_seconds", Duration::from_secs(1));
+        let host = RoomHost::new(Arc::new(MemoryStorage::default()), MemoryLimits::new());
+        host.join("pad", "ana", Instant::now()).unwrap();
+
+        let response = warp::test::request().path("/metrics").reply(&route(timings.clone(), Some(host))).await;
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.starts_with("# TYPE rustpad_publish_seconds summary\n"));
+        assert!(body.contains("rustpad_rooms_loaded 1\n"));
+
+        // A server hosting no rooms reports its timings alone
+        let response = warp::test::request().path("/metrics").reply(&route(timings.clone(), None)).await;
+        assert_eq!(response.body().as_ref(), timings.render().as_bytes());
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+/// How long a chat message or annotation waits for its acknowledgement before it is resent
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A chat message or annotation sent but not acknowledged yet
+#[derive(Debug, Clone)]
+struct Outgoing {
+    key: String,
+    frame: String, // As sent, so a resend carries the same idempotency key
+    sent_at: Instant,
+}
+
+/// The ids received in a room: all of them up to `contiguous`, and those in `above` after it
+#[derive(Debug, Clone, Default)]
+struct Received {
+    contiguous: u64,
+    above: BTreeSet<u64>,
+}
+
+/// `ChatDelivery` is the client side of chat and annotation delivery. Outgoing messages get an
+/// idempotency key and are resent with it until the server acknowledges them, which it does
+/// once however often they arrive. Incoming ones are told apart by id, so those received twice,
+/// by broadcast and again when resuming, are shown once.
+pub struct ChatDelivery {
+    outgoing: Vec<Outgoing>, // Oldest first
+    received: HashMap<String, Received>, // By room
+    ack_timeout: Duration,
+}
+
+impl ChatDelivery {
+    /// Creates a delivery tracker resending after `ACK_TIMEOUT`.
+    pub fn new() -> Self {
+        Self { outgoing: Vec::new(), received: HashMap::new(), ack_timeout: ACK_TIMEOUT }
+    }
+
+    /// Resends after `ack_timeout` instead of `ACK_TIMEOUT`.
+    pub fn with_ack_timeout(self, ack_timeout: Duration) -> Self {
+        Self { ack_timeout, ..self }
+    }
+
+    /// Gives a `{"chat_message": ...}` or `{"annotation": ...}` frame an idempotency key and
+    /// returns the text to send, which is kept for resending until it is acknowledged.
+    pub fn send(&mut self, mut frame: serde_json::Value, now: Instant) -> Result<String, String> {
+        let key = Uuid::new_v4().to_string();
+        let kind = ["chat_message", "annotation"]
+            .into_iter()
+            .find(|kind| frame.get(kind).is_some_and(serde_json::Value::is_object))
+            .ok_or_else(|| "Only chat messages and annotations are acknowledged".to_string())?;
+        frame[kind]["key"] = serde_json::Value::from(key.clone());
+        let frame = frame.to_string();
+        self.outgoing.push(Outgoing { key, frame: frame.clone(), sent_at: now });
+        Ok(frame)
+    }
+
+    /// Takes in the server's `{"type":"ack","key":...,"id":...}`, returning whether it was for
+    /// a message still waiting for one.
+    pub fn acknowledge(&mut self, key: &str) -> bool {
+        let waiting = self.outgoing.len();
+        self.outgoing.retain(|outgoing| outgoing.key != key);
+        self.outgoing.len() < waiting
+    }
+
+    /// The frames whose acknowledgement is overdue, to send again; they wait another
+    /// `ack_timeout` from `now`.
+    pub fn due(&mut self, now: Instant) -> Vec<String> {
+        let ack_timeout = self.ack_timeout;
+        self.outgoing
+            .iter_mut()
+            .filter(|outgoing| now.duration_since(outgoing.sent_at) >= ack_timeout)
+            .map(|outgoing| {
+                outgoing.sent_at = now;
+                outgoing.frame.clone()
+            })
+            .collect()
+    }
+
+    /// Every frame not acknowledged yet, to send again on a new connection.
+    pub fn unacknowledged(&mut self, now: Instant) -> Vec<String> {
+        self.outgoing
+            .iter_mut()
+            .map(|outgoing| {
+                outgoing.sent_at = now;
+                outgoing.frame.clone()
+            })
+            .collect()
+    }
+
+    /// Takes in the `last_id` of the initial state sent on connecting to `room`: everything up
+    /// to it came with the initial state.
+    pub fn loaded(&mut self, room: &str, last_id: u64) {
+        let received = self.received.entry(room.to_string()).or_default();
+        if last_id > received.contiguous {
+            received.contiguous = last_id;
+            received.above = received.above.split_off(&(last_id + 1));
+            Self::advance(received);
+        }
+    }
+
+    /// Records message `id` of `room` as received, returning `false` when it already was.
+    pub fn receive(&mut self, room: &str, id: u64) -> bool {
+        let received = self.received.entry(room.to_string()).or_default();
+        if id <= received.contiguous || !received.above.insert(id) {
+            return false;
+        }
+        Self::advance(received);
+        true
+    }
+
+    /// The `{"type":"resume","after":...}` frame asking for what `room` sent after the last
+    /// message received without a gap before it.
+    pub fn resume_frame(&self, room: &str) -> String {
+        let after = self.received.get(room).map_or(0, |received| received.contiguous);
+        serde_json::json!({ "type": "resume", "after": after }).to_string()
+    }
+
+    /// Moves `contiguous` past the ids in `above` that follow it without a gap
+    fn advance(received: &mut Received) {
+        while received.above.remove(&(received.contiguous + 1)) {
+            received.contiguous += 1;
+        }
+    }
+}
+
+impl Default for ChatDelivery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(text: &str) -> serde_json::Value {
+        serde_json::json!({ "chat_message": { "user": "ana", "message": text, "timestamp": "" } })
+    }
+
+    fn resume_after(delivery: &ChatDelivery, room: &str) -> serde_json::Value {
+        serde_json::from_str::<serde_json::Value>(&delivery.resume_frame(room)).unwrap()["after"].clone()
+    }
+
+    fn key(frame: &str) -> String {
+        let frame: serde_json::Value = serde_json::from_str(frame).unwrap();
+        frame["chat_message"]["key"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_unacknowledged_messages_are_resent_with_the_same_key() {
+        let start = Instant::now();
+        let mut delivery = ChatDelivery::new().with_ack_timeout(Duration::from_secs(2));
+        let first = delivery.send(chat("hi"), start).unwrap();
+        let second = delivery.send(chat("there"), start + Duration::from_secs(1)).unwrap();
+        assert_ne!(key(&first), key(&second));
+        assert!(delivery.send(serde_json::json!({ "type": "presence" }), start).is_err());
+
+        assert!(delivery.due(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(delivery.due(start + Duration::from_secs(2)), vec![first.clone()]);
+        assert_eq!(delivery.due(start + Duration::from_secs(3)), vec![second.clone()]); // The first waits again
+
+        // Acknowledged ones are never resent, and acknowledging twice does nothing
+        assert!(delivery.acknowledge(&key(&second)));
+        assert!(!delivery.acknowledge(&key(&second)));
+        assert_eq!(delivery.due(start + Duration::from_secs(10)), vec![first.clone()]);
+        assert_eq!(delivery.unacknowledged(start + Duration::from_secs(10)), vec![first.clone()]);
+        assert!(delivery.acknowledge(&key(&first)));
+        assert!(delivery.due(start + Duration::from_secs(20)).is_empty());
+    }
+
+    #[test]
+    fn test_messages_received_twice_are_dropped() {
+        let mut delivery = ChatDelivery::new();
+        delivery.loaded("doc", 3);
+        assert!(!delivery.receive("doc", 2)); // In the initial state
+        assert!(delivery.receive("doc", 5));
+        assert!(!delivery.receive("doc", 5));
+        assert_eq!(resume_after(&delivery, "doc"), 3);
+
+        // Resuming fills the gap; what arrives again is dropped
+        let recovered: Vec<u64> = [4, 5, 6].into_iter().filter(|id| delivery.receive("doc", *id)).collect();
+        assert_eq!(recovered, vec![4, 6]);
+        assert_eq!(resume_after(&delivery, "doc"), 6);
+        assert_eq!(resume_after(&delivery, "other"), 0);
+    }
+}
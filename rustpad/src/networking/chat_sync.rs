@@ -1,60 +1,257 @@
 use warp::ws::{Message, WebSocket};
-use futures_util::{StreamExt, SinkExt};
-use std::collections::HashMap;
+use warp::Filter;
+use futures_util::StreamExt;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use crate::auth::auth::authenticated_user;
+use crate::client::{self, Client, Clients};
+use crate::networking::document_comments::{DocumentComment, DocumentCommentEvent, DocumentComments};
+use crate::networking::linkpreview::{self, LinkPreviewer};
+use crate::networking::moderation::{AuditEntry, Moderation, Mute};
+use crate::networking::read_receipts::ReadReceipts;
+use crate::storage::activity::{mentions, ActivityFeeds};
+use crate::storage::attachments::{AttachmentRef, AttachmentStore};
+use crate::storage::notifications::{EditWatcher, Presence};
+use crate::validation::{ChatBody, Username};
+
+/// Number of most recent messages included in "seen by" broadcasts
+const SEEN_BY_RECENT: usize = 20;
+
+/// Idempotency keys remembered per room for collapsing resends, and the longest key accepted
+const MAX_IDEMPOTENCY_KEYS: usize = 1024;
+const MAX_KEY_LEN: usize = 64;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatMessage {
-    pub user: String,
-    pub message: String,
+    #[serde(default)]
+    pub id: u64, // Assigned by the server, increasing per room along with annotation ids
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>, // Idempotency key from the sender, the same when it resends
+    #[serde(default)]
+    pub room: String,
+    pub user: Username,
+    pub message: ChatBody,
     pub timestamp: String,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentRef>, // Uploaded beforehand through the attachments API
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Annotation {
-    pub user: String,
-    pub content: String,
+    #[serde(default)]
+    pub id: u64, // Assigned by the server, increasing per room along with chat message ids
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub user: Username,
+    pub content: ChatBody,
     pub line_number: usize,
     pub timestamp: String,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentRef>,
+}
+
+/// A chat message or annotation as broadcast: `{"chat_message": ...}` or `{"annotation": ...}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Delivered {
+    ChatMessage(ChatMessage),
+    Annotation(Annotation),
+}
+
+impl Delivered {
+    /// The id the server gave it
+    pub fn id(&self) -> u64 {
+        match self {
+            Delivered::ChatMessage(chat_message) => chat_message.id,
+            Delivered::Annotation(annotation) => annotation.id,
+        }
+    }
+}
+
+/// What became of a chat message or annotation: stored under a new id, or recognized by its
+/// idempotency key as a resend of one stored before
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Accepted {
+    New(u64),
+    Duplicate(u64),
+}
+
+impl Accepted {
+    /// The id of the stored message, to acknowledge either way
+    pub fn id(&self) -> u64 {
+        match self {
+            Accepted::New(id) | Accepted::Duplicate(id) => *id,
+        }
+    }
+}
+
+/// The ids of a room, shared by its chat messages and annotations, and the ids given to the
+/// latest idempotency keys
+#[derive(Debug, Default)]
+struct Sequence {
+    last_id: u64,
+    keys: HashMap<String, u64>, // By user and key
+    recent_keys: VecDeque<String>, // Oldest first, forgotten beyond `MAX_IDEMPOTENCY_KEYS`
+}
+
+impl Sequence {
+    /// The id for a message from `user`, unless `key` was already given one
+    fn assign(&mut self, user: &str, key: Option<&str>) -> Accepted {
+        let Some(key) = key else {
+            self.last_id += 1;
+            return Accepted::New(self.last_id);
+        };
+        let key = format!("{}\n{}", user, key);
+        if let Some(id) = self.keys.get(&key) {
+            return Accepted::Duplicate(*id);
+        }
+        self.last_id += 1;
+        self.keys.insert(key.clone(), self.last_id);
+        self.recent_keys.push_back(key);
+        if self.recent_keys.len() > MAX_IDEMPOTENCY_KEYS {
+            if let Some(oldest) = self.recent_keys.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        Accepted::New(self.last_id)
+    }
 }
 
-type ChatHistory = Arc<Mutex<Vec<ChatMessage>>>;
-type Annotations = Arc<Mutex<HashMap<usize, Vec<Annotation>>>>; // Keyed by line number
-type ChatClients = Arc<Mutex<Vec<warp::ws::WebSocket>>>;
+type ChatHistory = Arc<Mutex<HashMap<String, Vec<ChatMessage>>>>; // Keyed by room
+type Annotations = Arc<Mutex<HashMap<String, HashMap<usize, Vec<Annotation>>>>>; // Keyed by room, then line number
+type Sequences = Arc<Mutex<HashMap<String, Sequence>>>; // Keyed by room, started on a room's first message
 
 /// Manages chat synchronization between collaborators
+#[derive(Clone)]
 pub struct ChatSyncManager {
     chat_history: ChatHistory,
     annotations: Annotations,
-    clients: ChatClients,
+    sequences: Sequences, // Locked before `chat_history` and `annotations`
+    clients: Clients, // Each client's messages are forwarded to its socket by a task of its own
+    read_receipts: Arc<ReadReceipts>,
+    activity: Option<ActivityFeeds>, // Records joins, leaves and chat bursts per room
+    watcher: Option<EditWatcher>,    // Tracks who is watching each room and holds their queued notifications
+    attachments: Option<AttachmentStore>, // Validates attachment references; without it they are rejected
+    link_previews: Option<LinkPreviewer>, // Previews links posted in chat; without it they stay plain text
+    document_comments: DocumentComments, // Discussion of each document as a whole
+    moderation: Option<Moderation>, // Mutes, slow mode and redaction; without it nobody moderates
 }
 
 impl ChatSyncManager {
     /// Creates a new ChatSyncManager with empty chat history and annotations
     pub fn new() -> Self {
         Self {
-            chat_history: Arc::new(Mutex::new(Vec::new())),
+            chat_history: Arc::new(Mutex::new(HashMap::new())),
             annotations: Arc::new(Mutex::new(HashMap::new())),
-            clients: Arc::new(Mutex::new(Vec::new())),
+            sequences: Arc::new(Mutex::new(HashMap::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            read_receipts: Arc::new(ReadReceipts::new()),
+            activity: None,
+            watcher: None,
+            attachments: None,
+            link_previews: None,
+            document_comments: DocumentComments::new(),
+            moderation: None,
         }
     }
 
-    /// Registers a new WebSocket client and sends the current chat history and annotations
-    pub async fn register_client(&self, socket: WebSocket) {
-        let (mut ws_tx, mut ws_rx) = socket.split();
+    /// Creates a new ChatSyncManager whose read markers are persisted by `read_receipts`
+    pub fn with_read_receipts(read_receipts: Arc<ReadReceipts>) -> Self {
+        Self { read_receipts, ..Self::new() }
+    }
 
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.push(ws_tx.clone());
-        }
+    /// Records presence and chat activity of every room in `feeds`
+    pub fn with_activity(self, feeds: ActivityFeeds) -> Self {
+        Self { activity: Some(feeds), ..self }
+    }
 
-        // Send current chat history and annotations to the newly connected client
-        let chat_history = self.chat_history.lock().unwrap().clone();
-        let annotations = self.annotations.lock().unwrap().clone();
+    /// Reports presence in each room to `watcher` and delivers its queued notifications on connect
+    pub fn with_watcher(self, watcher: EditWatcher) -> Self {
+        Self { watcher: Some(watcher), ..self }
+    }
 
-        let initial_state = serde_json::to_string(&(chat_history, annotations)).unwrap();
-        if ws_tx.send(Message::text(initial_state)).await.is_err() {
+    /// Accepts chat messages and annotations referencing attachments uploaded to `store`
+    pub fn with_attachments(self, store: AttachmentStore) -> Self {
+        Self { attachments: Some(store), ..self }
+    }
+
+    /// Follows each chat message with previews of the links in it, fetched by `previewer`
+    pub fn with_link_previews(self, previewer: LinkPreviewer) -> Self {
+        Self { link_previews: Some(previewer), ..self }
+    }
+
+    /// Keeps the discussion of each document in `comments`, e.g. one backed by storage
+    pub fn with_document_comments(self, comments: DocumentComments) -> Self {
+        Self { document_comments: comments, ..self }
+    }
+
+    /// Lets the owners and moderators of each room moderate its chat and annotations
+    pub fn with_moderation(self, moderation: Moderation) -> Self {
+        Self { moderation: Some(moderation), ..self }
+    }
+
+    /// Registers a new WebSocket client for `user` in `room` and sends the room's chat history,
+    /// the annotations and the last id among them, the document comments, the user's last-read
+    /// marker with their unread count, and any notifications queued while they were away.
+    ///
+    /// Every chat message and annotation accepted is acknowledged to its sender with its id,
+    /// `{"type":"ack","key":...,"id":...}`, before it is broadcast. A resend with the same
+    /// idempotency key is acknowledged again but not stored or broadcast twice. Clients that
+    /// missed messages send `{"type":"resume","after":<id>}` and get those after it in order.
+    ///
+    /// Comments on the document as a whole are added with `{"type":"document_comment","content":...}`
+    /// and resolved with `{"type":"resolve_document_comment","id":...}`; both are broadcast.
+    ///
+    /// Moderators send `{"type":"redact","id":...}`, `{"type":"mute","user":...,"seconds":...,
+    /// "restrict_editing":...}`, `{"type":"unmute","user":...}` and `{"type":"slow_mode","seconds":...}`,
+    /// and the owner `{"type":"audit_log"}`. What muted or slowed down users post is answered
+    /// with `{"type":"rejected","key":...,"reason":...}` instead of an ack.
+    pub async fn register_client(self, socket: WebSocket, user: String, room: String) {
+        let (ws_tx, ws_rx) = socket.split();
+        let (sender, send_task) = client::spawn_writer(ws_tx);
+        let writer = send_task.abort_handle();
+        let client_id = Uuid::new_v4().to_string();
+        client::add_client(self.clients.clone(), client_id.clone(), Client::new(&client_id, &user, sender.clone()).with_writer(writer.clone()));
+
+        let mut ws_rx = ws_rx.take_until(send_task);
+
+        // Send current chat history and annotations to the newly connected client. Anything
+        // after `last_id` is broadcast to it, as it is already registered.
+        let (chat_history, annotations, last_id) = self.snapshot(&room);
+
+        // Summarize what happened since the user's last visit, then record the join
+        let activity_summary = self.activity.as_ref().map(|feeds| {
+            feeds.with_feed(&room, |feed| {
+                let summary = feed.summary_for(&user);
+                feed.record_join(&user, Utc::now());
+                summary
+            })
+        });
+
+        // Watching the room live from now on, so deliver what was queued while away
+        let notifications = self.watcher.as_ref().map(|watcher| {
+            watcher.set_presence(&room, &user, Presence::Active);
+            watcher.take_notifications(&user)
+        });
+
+        let initial_state = serde_json::to_string(&serde_json::json!({
+            "chat_history": chat_history,
+            "annotations": annotations,
+            "last_id": last_id,
+            "document_comments": self.document_comments.list(&room),
+            "last_read": self.read_receipts.last_read(&user, &room),
+            "unread_count": self.read_receipts.unread_count(&user, &room, &chat_history),
+            "activity_summary": activity_summary,
+            "notifications": notifications.unwrap_or_default(),
+            "moderation": self.moderation_state(&room, &user),
+        }))
+        .unwrap();
+        if sender.send(Message::text(initial_state)).await.is_err() {
             println!("Failed to send initial state to the client");
         }
 
@@ -63,104 +260,667 @@ impl ChatSyncManager {
             if let Ok(message) = result {
                 if message.is_text() {
                     // Handle incoming chat or annotation messages
-                    let parsed_message: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+                    let parsed_message: serde_json::Value = match serde_json::from_str(message.to_str().unwrap()) {
+                        Ok(value) => value,
+                        Err(e) => {
+                            Self::send_error(&sender, &e.to_string()).await;
+                            continue;
+                        }
+                    };
 
-                    // Check if it's a chat message
+                    // Check if it's a chat message; invalid names or bodies are rejected, not broadcast
                     if let Some(chat_msg) = parsed_message.get("chat_message") {
-                        let chat_message: ChatMessage = serde_json::from_value(chat_msg.clone()).unwrap();
-                        self.add_chat_message(chat_message.clone()).await;
-                        self.broadcast_chat_message(chat_message).await;
+                        match serde_json::from_value::<ChatMessage>(chat_msg.clone()) {
+                            Ok(mut chat_message) => {
+                                chat_message.attachments = match self.resolve_attachments(&chat_message.attachments) {
+                                    Ok(attachments) => attachments,
+                                    Err(e) => {
+                                        Self::send_error(&sender, &e).await;
+                                        continue;
+                                    }
+                                };
+                                if let Err(e) = check_key(chat_message.key.as_deref()) {
+                                    Self::send_error(&sender, &e).await;
+                                    continue;
+                                }
+                                if let Err(reason) = self.check_post(&room, &user, true) {
+                                    Self::send_rejected(&sender, chat_message.key.as_deref(), &reason).await;
+                                    continue;
+                                }
+                                chat_message.room = room.clone();
+                                let accepted = self.add_chat_message(&mut chat_message);
+                                Self::send_ack(&sender, chat_message.key.as_deref(), accepted).await;
+                                if let Accepted::New(_) = accepted {
+                                    if let Some(feeds) = &self.activity {
+                                        feeds.with_feed(&room, |feed| {
+                                            feed.record_chat(chat_message.user.as_str(), Utc::now());
+                                            feed.record_mention(chat_message.user.as_str(), mentions(chat_message.message.as_str()), Utc::now());
+                                        });
+                                    }
+                                    self.spawn_link_previews(&chat_message);
+                                    self.broadcast(Delivered::ChatMessage(chat_message));
+                                }
+                            }
+                            Err(e) => Self::send_error(&sender, &e.to_string()).await,
+                        }
+                    }
+
+                    // Check if the client has read up to a message
+                    if parsed_message.get("type").and_then(|t| t.as_str()) == Some("chat_read") {
+                        if let Some(message_id) = parsed_message.get("message_id").and_then(|id| id.as_u64()) {
+                            if self.read_receipts.mark_read(&user, &room, message_id) {
+                                self.broadcast_seen_by(&room);
+                            }
+                        }
+                    }
+
+                    // Check if the client is catching up on what it missed while disconnected
+                    if parsed_message.get("type").and_then(|t| t.as_str()) == Some("resume") {
+                        let after = parsed_message.get("after").and_then(|after| after.as_u64()).unwrap_or(0);
+                        let missed = serde_json::json!({ "type": "missed", "messages": self.missed_since(&room, after) });
+                        if sender.send(Message::text(missed.to_string())).await.is_err() {
+                            println!("Failed to send missed messages to the client");
+                        }
+                    }
+
+                    // Check if the client went idle or came back
+                    if parsed_message.get("type").and_then(|t| t.as_str()) == Some("presence") {
+                        match serde_json::from_value::<Presence>(parsed_message["status"].clone()) {
+                            Ok(presence) => {
+                                if let Some(watcher) = &self.watcher {
+                                    watcher.set_presence(&room, &user, presence);
+                                }
+                            }
+                            Err(e) => Self::send_error(&sender, &e.to_string()).await,
+                        }
+                    }
+
+                    // Check if it's a comment on the whole document, or one being resolved
+                    if parsed_message.get("type").and_then(|t| t.as_str()) == Some("document_comment") {
+                        if let Err(reason) = self.check_post(&room, &user, false) {
+                            Self::send_rejected(&sender, None, &reason).await;
+                            continue;
+                        }
+                        let added = serde_json::from_value::<ChatBody>(parsed_message["content"].clone())
+                            .map_err(|e| e.to_string())
+                            .and_then(|content| self.add_document_comment(&room, &user, content));
+                        if let Err(e) = added {
+                            Self::send_error(&sender, &e).await;
+                        }
+                    }
+                    if parsed_message.get("type").and_then(|t| t.as_str()) == Some("resolve_document_comment") {
+                        let resolved = match parsed_message.get("id").and_then(|id| id.as_u64()) {
+                            Some(id) => self.resolve_document_comment(&room, &user, id),
+                            None => Err("Which comment to resolve is missing".to_string()),
+                        };
+                        if let Err(e) = resolved {
+                            Self::send_error(&sender, &e).await;
+                        }
+                    }
+
+                    // Check if it's a moderation request
+                    if let Some(result) = self.moderate(&room, &user, &parsed_message, Utc::now()) {
+                        match result {
+                            Ok(Some(reply)) => {
+                                if sender.send(Message::text(reply.to_string())).await.is_err() {
+                                    println!("Failed to send the moderation log to the client");
+                                }
+                            }
+                            Ok(None) => {}
+                            Err(e) => Self::send_error(&sender, &e).await,
+                        }
                     }
 
                     // Check if it's an annotation
                     if let Some(annotation_msg) = parsed_message.get("annotation") {
-                        let annotation: Annotation = serde_json::from_value(annotation_msg.clone()).unwrap();
-                        self.add_annotation(annotation.clone()).await;
-                        self.broadcast_annotation(annotation).await;
+                        match serde_json::from_value::<Annotation>(annotation_msg.clone()) {
+                            Ok(mut annotation) => {
+                                annotation.attachments = match self.resolve_attachments(&annotation.attachments) {
+                                    Ok(attachments) => attachments,
+                                    Err(e) => {
+                                        Self::send_error(&sender, &e).await;
+                                        continue;
+                                    }
+                                };
+                                if let Err(e) = check_key(annotation.key.as_deref()) {
+                                    Self::send_error(&sender, &e).await;
+                                    continue;
+                                }
+                                if let Err(reason) = self.check_post(&room, &user, false) {
+                                    Self::send_rejected(&sender, annotation.key.as_deref(), &reason).await;
+                                    continue;
+                                }
+                                let accepted = self.add_annotation(&room, &mut annotation);
+                                Self::send_ack(&sender, annotation.key.as_deref(), accepted).await;
+                                if let Accepted::New(_) = accepted {
+                                    self.broadcast(Delivered::Annotation(annotation));
+                                }
+                            }
+                            Err(e) => Self::send_error(&sender, &e.to_string()).await,
+                        }
                     }
                 }
             }
         }
 
         // Remove the WebSocket client when it disconnects
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.retain(|client| !client.is_closed());
+        client::remove_client(self.clients.clone(), &client_id);
+        writer.abort();
+
+        if let Some(feeds) = &self.activity {
+            feeds.with_feed(&room, |feed| {
+                let now = Utc::now();
+                feed.record_leave(&user, now);
+                feed.mark_seen(&user, now);
+            });
+        }
+
+        if let Some(watcher) = &self.watcher {
+            watcher.clear_presence(&room, &user);
         }
     }
 
-    /// Adds a new chat message to the chat history
-    async fn add_chat_message(&self, chat_message: ChatMessage) {
-        let mut chat_history = self.chat_history.lock().unwrap();
-        chat_history.push(chat_message);
+    /// Replaces attachment references from a client with the stored records, failing on unknown ones
+    fn resolve_attachments(&self, attachments: &[AttachmentRef]) -> Result<Vec<AttachmentRef>, String> {
+        match &self.attachments {
+            _ if attachments.is_empty() => Ok(Vec::new()),
+            Some(store) => store.resolve(attachments),
+            None => Err("Attachments are not enabled".to_string()),
+        }
+    }
+
+    /// Hashes of every attachment still referenced by a chat message or annotation
+    pub fn referenced_attachments(&self) -> HashSet<String> {
+        let chat_history = self.chat_history.lock().unwrap();
+        let annotations = self.annotations.lock().unwrap();
+        let from_chat = chat_history.values().flatten().flat_map(|message| &message.attachments);
+        let from_annotations = annotations.values().flat_map(|lines| lines.values()).flatten().flat_map(|annotation| &annotation.attachments);
+        from_chat.chain(from_annotations).map(|attachment| attachment.hash.clone()).collect()
+    }
+
+    /// Sends an error frame to a single client
+    async fn send_error(sender: &mpsc::Sender<Message>, reason: &str) {
+        let error = serde_json::json!({ "type": "error", "message": reason });
+        if sender.send(Message::text(error.to_string())).await.is_err() {
+            println!("Failed to send error to the client");
+        }
     }
 
-    /// Adds a new annotation to the list of annotations
-    async fn add_annotation(&self, annotation: Annotation) {
+    /// Tells the sender of a chat message or annotation why it was refused by moderation
+    async fn send_rejected(sender: &mpsc::Sender<Message>, key: Option<&str>, reason: &str) {
+        let rejected = serde_json::json!({ "type": "rejected", "key": key, "reason": reason });
+        if sender.send(Message::text(rejected.to_string())).await.is_err() {
+            println!("Failed to send a rejection to the client");
+        }
+    }
+
+    /// Acknowledges a chat message or annotation to its sender with the id it was stored under
+    async fn send_ack(sender: &mpsc::Sender<Message>, key: Option<&str>, accepted: Accepted) {
+        let ack = serde_json::json!({ "type": "ack", "key": key, "id": accepted.id() });
+        if sender.send(Message::text(ack.to_string())).await.is_err() {
+            println!("Failed to acknowledge a message to the client");
+        }
+    }
+
+    /// Runs `f` on the id sequence of `room`, with the chat history and annotations locked so ids
+    /// are stored in the order they are given
+    fn with_sequence<T>(&self, room: &str, f: impl FnOnce(&mut Sequence, &mut HashMap<String, Vec<ChatMessage>>, &mut HashMap<String, HashMap<usize, Vec<Annotation>>>) -> T) -> T {
+        let mut sequences = self.sequences.lock().unwrap();
+        let mut chat_history = self.chat_history.lock().unwrap();
         let mut annotations = self.annotations.lock().unwrap();
-        annotations.entry(annotation.line_number).or_insert_with(Vec::new).push(annotation);
+        let sequence = sequences.entry(room.to_string()).or_insert_with(|| {
+            // Carry on from the ids of restored chat and annotations
+            let chat_ids = chat_history.get(room).into_iter().flatten().map(|message| message.id);
+            let annotation_ids = annotations.get(room).into_iter().flat_map(|lines| lines.values()).flatten().map(|annotation| annotation.id);
+            Sequence { last_id: chat_ids.chain(annotation_ids).max().unwrap_or(0), ..Sequence::default() }
+        });
+        f(sequence, &mut chat_history, &mut annotations)
     }
 
-    /// Broadcasts a chat message to all connected clients
-    async fn broadcast_chat_message(&self, chat_message: ChatMessage) {
+    /// Adds a chat message to its room's history under the next id, unless its idempotency key
+    /// shows it is a resend of one already added
+    pub fn add_chat_message(&self, chat_message: &mut ChatMessage) -> Accepted {
+        let room = chat_message.room.clone();
+        self.with_sequence(&room, |sequence, chat_history, _| {
+            let accepted = sequence.assign(chat_message.user.as_str(), chat_message.key.as_deref());
+            if let Accepted::New(id) = accepted {
+                chat_message.id = id;
+                chat_history.entry(room.clone()).or_default().push(chat_message.clone());
+            }
+            accepted
+        })
+    }
+
+    /// The chat history and annotations of `room`, and the last id among them
+    fn snapshot(&self, room: &str) -> (Vec<ChatMessage>, HashMap<usize, Vec<Annotation>>, u64) {
+        self.with_sequence(room, |sequence, chat_history, annotations| {
+            let history = chat_history.get(room).cloned().unwrap_or_default();
+            (history, annotations.get(room).cloned().unwrap_or_default(), sequence.last_id)
+        })
+    }
+
+    /// The chat messages and annotations of `room` with ids after `after`, in id order
+    pub fn missed_since(&self, room: &str, after: u64) -> Vec<Delivered> {
+        let (chat_history, annotations, _) = self.snapshot(room);
+        let chat = chat_history.into_iter().filter(|message| message.id > after).map(Delivered::ChatMessage);
+        let annotations = annotations.into_values().flatten().filter(|annotation| annotation.id > after).map(Delivered::Annotation);
+        let mut missed: Vec<Delivered> = chat.chain(annotations).collect();
+        missed.sort_by_key(Delivered::id);
+        missed
+    }
+
+    /// Returns a copy of the chat history for `room`
+    pub fn room_history(&self, room: &str) -> Vec<ChatMessage> {
+        self.chat_history.lock().unwrap().get(room).cloned().unwrap_or_default()
+    }
+
+    /// Returns a copy of the annotations in `room`, keyed by line number
+    pub fn room_annotations(&self, room: &str) -> HashMap<usize, Vec<Annotation>> {
+        self.annotations.lock().unwrap().get(room).cloned().unwrap_or_default()
+    }
+
+    /// Restores the chat history and annotations of a room that has none, e.g. from an
+    /// imported bundle. Message ids, timestamps and annotation lines are kept as they were.
+    pub fn restore_room(&self, room: &str, messages: Vec<ChatMessage>, annotations: HashMap<usize, Vec<Annotation>>) -> Result<(), String> {
+        let mut sequences = self.sequences.lock().unwrap();
+        let mut chat_history = self.chat_history.lock().unwrap();
+        let mut room_annotations = self.annotations.lock().unwrap();
+        if chat_history.get(room).is_some_and(|history| !history.is_empty()) || room_annotations.contains_key(room) {
+            return Err(format!("{} already has chat or annotations", room));
+        }
+        let messages = messages.into_iter().map(|message| ChatMessage { room: room.to_string(), ..message }).collect();
+        chat_history.insert(room.to_string(), messages);
+        if !annotations.is_empty() {
+            room_annotations.insert(room.to_string(), annotations);
+        }
+        sequences.remove(room); // Started again after the restored ids
+        Ok(())
+    }
+
+    /// Removes the chat history and annotations of a room and returns them, e.g. while its
+    /// document is in the trash; `restore_room` puts them back
+    pub fn take_room(&self, room: &str) -> (Vec<ChatMessage>, HashMap<usize, Vec<Annotation>>) {
+        self.sequences.lock().unwrap().remove(room);
+        let messages = self.chat_history.lock().unwrap().remove(room).unwrap_or_default();
+        let annotations = self.annotations.lock().unwrap().remove(room).unwrap_or_default();
+        (messages, annotations)
+    }
+
+    /// Unread chat counts for `user` in every room, for the document list badges
+    pub fn unread_counts(&self, user: &str) -> HashMap<String, usize> {
+        let chat_history = self.chat_history.lock().unwrap();
+        self.read_receipts.unread_counts(user, &chat_history)
+    }
+
+    /// Broadcasts how many users have seen each recent message in `room`, at most once per throttle window
+    fn broadcast_seen_by(&self, room: &str) {
+        if !self.read_receipts.should_broadcast_seen_by(room, Instant::now()) {
+            return;
+        }
+
+        let chat_history = self.room_history(room);
+        let recent = &chat_history[chat_history.len().saturating_sub(SEEN_BY_RECENT)..];
         let message = serde_json::to_string(&serde_json::json!({
-            "chat_message": chat_message
+            "type": "chat_seen",
+            "room": room,
+            "seen_by": self.read_receipts.seen_by_counts(room, recent),
         }))
         .unwrap();
-        
-        let clients = self.clients.lock().unwrap();
 
-        for client in clients.iter() {
-            if client.send(Message::text(message.clone())).await.is_err() {
-                println!("Failed to send chat message to a client");
+        client::broadcast_message(self.clients.clone(), &message);
+    }
+
+    /// Adds an annotation to the annotations of `room` under the next id, unless its
+    /// idempotency key shows it is a resend of one already added
+    pub fn add_annotation(&self, room: &str, annotation: &mut Annotation) -> Accepted {
+        self.with_sequence(room, |sequence, _, annotations| {
+            let accepted = sequence.assign(annotation.user.as_str(), annotation.key.as_deref());
+            if let Accepted::New(id) = accepted {
+                annotation.id = id;
+                annotations.entry(room.to_string()).or_default().entry(annotation.line_number).or_default().push(annotation.clone());
             }
+            accepted
+        })
+    }
+
+    /// Adds a comment by `user` to the discussion of `room` and broadcasts it
+    pub fn add_document_comment(&self, room: &str, user: &str, content: ChatBody) -> Result<DocumentComment, String> {
+        let user = Username::try_from(user).map_err(|e| e.to_string())?;
+        let comment = self.document_comments.add(room, user, content, crate::i18n::timestamp(Utc::now()));
+        self.broadcast_document_comment(DocumentCommentEvent::DocumentComment { comment: comment.clone() });
+        Ok(comment)
+    }
+
+    /// Resolves comment `id` of the discussion of `room` on behalf of `user` and broadcasts it
+    pub fn resolve_document_comment(&self, room: &str, user: &str, id: u64) -> Result<DocumentComment, String> {
+        let user = Username::try_from(user).map_err(|e| e.to_string())?;
+        let comment = self.document_comments.resolve(room, id, user)?;
+        self.broadcast_document_comment(DocumentCommentEvent::DocumentCommentResolved { comment: comment.clone() });
+        Ok(comment)
+    }
+
+    /// The discussion of `room`, oldest first
+    pub fn document_comments(&self, room: &str) -> Vec<DocumentComment> {
+        self.document_comments.list(room)
+    }
+
+    fn broadcast_document_comment(&self, event: DocumentCommentEvent) {
+        let message = serde_json::to_string(&event).unwrap();
+        client::broadcast_message(self.clients.clone(), &message);
+    }
+
+    /// Refuses a chat message (`chat`) or annotation from `user` while moderation holds them back
+    fn check_post(&self, room: &str, user: &str, chat: bool) -> Result<(), String> {
+        match &self.moderation {
+            Some(moderation) => moderation.check_post(room, user, chat, Utc::now()),
+            None => Ok(()),
         }
     }
 
-    /// Broadcasts an annotation to all connected clients
-    async fn broadcast_annotation(&self, annotation: Annotation) {
-        let message = serde_json::to_string(&serde_json::json!({
-            "annotation": annotation
-        }))
-        .unwrap();
-        
-        let clients = self.clients.lock().unwrap();
+    fn moderation(&self) -> Result<&Moderation, String> {
+        self.moderation.as_ref().ok_or_else(|| "Moderation is not enabled".to_string())
+    }
 
-        for client in clients.iter() {
-            if client.send(Message::text(message.clone())).await.is_err() {
-                println!("Failed to send annotation to a client");
+    /// What a client joining `room` as `user` is told of its moderation: slow mode, and how
+    /// long they are muted for
+    fn moderation_state(&self, room: &str, user: &str) -> serde_json::Value {
+        let Some(moderation) = &self.moderation else { return serde_json::Value::Null };
+        let settings = moderation.settings(room);
+        let muted_until = settings.muted(user, Utc::now()).map(|mute| mute.until);
+        serde_json::json!({ "slow_mode_secs": settings.slow_mode_secs, "muted_until": muted_until })
+    }
+
+    /// Handles a moderation frame from `user`, returning `None` for other frames. The audit
+    /// log is the only one answered to the sender; the rest are broadcast.
+    fn moderate(&self, room: &str, user: &str, frame: &serde_json::Value, now: DateTime<Utc>) -> Option<Result<Option<serde_json::Value>, String>> {
+        let text = |field: &str| frame.get(field).and_then(|value| value.as_str()).ok_or_else(|| format!("{} is missing", field));
+        let number = |field: &str| frame.get(field).and_then(|value| value.as_u64()).ok_or_else(|| format!("{} is missing", field));
+        let result = match frame.get("type").and_then(|t| t.as_str())? {
+            "redact" => number("id").and_then(|id| self.redact(room, user, id, now)).map(|_| None),
+            "mute" => {
+                let restrict_editing = frame.get("restrict_editing").and_then(|value| value.as_bool()).unwrap_or(false);
+                text("user").and_then(|muted| number("seconds").and_then(|seconds| self.mute_user(room, user, muted, seconds, restrict_editing, now))).map(|_| None)
             }
+            "unmute" => text("user").and_then(|muted| self.unmute_user(room, user, muted, now)).map(|_| None),
+            "slow_mode" => number("seconds").and_then(|seconds| self.set_slow_mode(room, user, seconds, now)).map(|_| None),
+            "audit_log" => self.audit_log(room, user).map(|entries| Some(serde_json::json!({ "type": "audit_log", "room": room, "entries": entries }))),
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    /// Removes chat message or annotation `id` from `room` on behalf of `actor`, who must
+    /// moderate it, and broadcasts `{"type":"redacted","room":...,"id":...}` so clients remove it
+    /// too. The original is kept in the room's audit trail.
+    pub fn redact(&self, room: &str, actor: &str, id: u64, now: DateTime<Utc>) -> Result<Delivered, String> {
+        let moderation = self.moderation()?;
+        moderation.check_moderator(room, actor)?;
+        let original = self.with_sequence(room, |_, chat_history, annotations| {
+            if let Some(history) = chat_history.get_mut(room) {
+                if let Some(index) = history.iter().position(|message| message.id == id) {
+                    return Some(Delivered::ChatMessage(history.remove(index)));
+                }
+            }
+            let lines = annotations.get_mut(room)?;
+            let (line_number, index) = lines.iter().find_map(|(line_number, line)| Some((*line_number, line.iter().position(|annotation| annotation.id == id)?)))?;
+            let line = lines.get_mut(&line_number)?;
+            let annotation = line.remove(index);
+            if line.is_empty() {
+                lines.remove(&line_number);
+            }
+            Some(Delivered::Annotation(annotation))
+        });
+        let original = original.ok_or_else(|| format!("No message {} in {}", id, room))?;
+        moderation.record_redaction(room, actor, original.clone(), now)?;
+
+        let redacted = serde_json::json!({ "type": "redacted", "room": room, "id": id });
+        client::broadcast_message(self.clients.clone(), &redacted.to_string());
+        Ok(original)
+    }
+
+    /// Mutes `user` in `room` for `seconds` on behalf of `actor`, and broadcasts it
+    pub fn mute_user(&self, room: &str, actor: &str, user: &str, seconds: u64, restrict_editing: bool, now: DateTime<Utc>) -> Result<Mute, String> {
+        let mute = self.moderation()?.mute(room, actor, user, seconds, restrict_editing, now)?;
+        let muted = serde_json::json!({ "type": "muted", "room": room, "user": user, "until": mute.until, "restrict_editing": restrict_editing });
+        client::broadcast_message(self.clients.clone(), &muted.to_string());
+        Ok(mute)
+    }
+
+    /// Lifts the mute of `user` in `room` on behalf of `actor`, and broadcasts it
+    pub fn unmute_user(&self, room: &str, actor: &str, user: &str, now: DateTime<Utc>) -> Result<(), String> {
+        self.moderation()?.unmute(room, actor, user, now)?;
+        let unmuted = serde_json::json!({ "type": "unmuted", "room": room, "user": user });
+        client::broadcast_message(self.clients.clone(), &unmuted.to_string());
+        Ok(())
+    }
+
+    /// Sets slow mode in `room` on behalf of `actor`, and broadcasts it; 0 turns it off
+    pub fn set_slow_mode(&self, room: &str, actor: &str, seconds: u64, now: DateTime<Utc>) -> Result<(), String> {
+        self.moderation()?.set_slow_mode(room, actor, seconds, now)?;
+        let slow_mode = serde_json::json!({ "type": "slow_mode", "room": room, "seconds": seconds });
+        client::broadcast_message(self.clients.clone(), &slow_mode.to_string());
+        Ok(())
+    }
+
+    /// The moderation audit trail of `room`, for its owner
+    pub fn audit_log(&self, room: &str, actor: &str) -> Result<Vec<AuditEntry>, String> {
+        self.moderation()?.audit_log(room, actor)
+    }
+
+    /// Fetches previews of the links in `chat_message` in the background, broadcasting
+    /// `{"type":"link_preview","message_id":...,"preview":{...}}` for each one that has one
+    fn spawn_link_previews(&self, chat_message: &ChatMessage) {
+        let Some(previewer) = self.link_previews.clone() else { return };
+        let links = linkpreview::detect_urls(chat_message.message.as_str());
+        if links.is_empty() {
+            return;
         }
+        let (clients, room, message_id) = (self.clients.clone(), chat_message.room.clone(), chat_message.id);
+        tokio::spawn(async move {
+            for link in links {
+                if let Some(preview) = previewer.preview(&link, Utc::now()).await {
+                    let message = serde_json::json!({ "type": "link_preview", "room": room, "message_id": message_id, "preview": preview });
+                    client::broadcast_message(clients.clone(), &message.to_string());
+                }
+            }
+        });
+    }
+
+    /// Broadcasts a chat message or annotation to all connected clients
+    fn broadcast(&self, delivered: Delivered) {
+        let message = serde_json::to_string(&delivered).unwrap();
+        client::broadcast_message(self.clients.clone(), &message);
+    }
+}
+
+impl Default for ChatSyncManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks an idempotency key sent with a chat message or annotation
+fn check_key(key: Option<&str>) -> Result<(), String> {
+    match key {
+        Some(key) if key.is_empty() || key.len() > MAX_KEY_LEN => Err(format!("Idempotency keys are 1 to {} bytes long", MAX_KEY_LEN)),
+        _ => Ok(()),
     }
 }
 
 /// WebSocket handler for the chat and annotation synchronization
-pub async fn chat_sync_ws_handler(ws: warp::ws::Ws, manager: ChatSyncManager) -> impl warp::Reply {
-    ws.on_upgrade(move |socket| manager.register_client(socket))
+pub async fn chat_sync_ws_handler(ws: warp::ws::Ws, user: String, room: String, manager: ChatSyncManager) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(ws.on_upgrade(move |socket| manager.register_client(socket, user, room)))
 }
 
 /// Route for the chat synchronization WebSocket
 pub fn chat_sync_route(manager: ChatSyncManager) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path("chat_sync_ws")
         .and(warp::ws())
+        .and(warp::path::param::<String>())  // User name
+        .and(warp::path::param::<String>())  // Room (document) id
         .and(with_manager(manager))
         .and_then(chat_sync_ws_handler)
 }
 
+/// Handler listing the documents with chat, including the signed-in caller's unread chat count for each
+pub async fn docs_handler(user: Option<String>, manager: ChatSyncManager) -> Result<impl warp::Reply, warp::Rejection> {
+    let user = user.unwrap_or_default();
+    let mut docs: Vec<serde_json::Value> = manager
+        .unread_counts(&user)
+        .into_iter()
+        .map(|(id, unread_chat)| serde_json::json!({ "id": id, "unread_chat": unread_chat }))
+        .collect();
+    docs.sort_by(|a, b| a["id"].as_str().cmp(&b["id"].as_str()));
+    Ok(warp::reply::json(&docs))
+}
+
+/// Route for `GET /api/docs`
+pub fn docs_route(manager: ChatSyncManager) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "docs")
+        .and(warp::get())
+        .and(authenticated_user())
+        .and(with_manager(manager))
+        .and_then(docs_handler)
+}
+
 /// Helper function to pass the ChatSyncManager to the route
 fn with_manager(manager: ChatSyncManager) -> impl warp::Filter<Extract = (ChatSyncManager,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || manager.clone())
 }
 
-/// Example main function for setting up the chat sync server
-#[tokio::main]
-async fn main() {
-    let chat_sync_manager = ChatSyncManager::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(user: &str, text: &str, key: Option<&str>) -> ChatMessage {
+        let message = serde_json::json!({ "room": "doc", "user": user, "message": text, "timestamp": "", "key": key });
+        serde_json::from_value(message).unwrap()
+    }
+
+    fn annotation(line_number: usize, text: &str, key: Option<&str>) -> Annotation {
+        let annotation = serde_json::json!({ "user": "ana", "content": text, "line_number": line_number, "timestamp": "", "key": key });
+        serde_json::from_value(annotation).unwrap()
+    }
 
-    // WebSocket route for chat synchronization
-    let chat_sync_ws_route = chat_sync_route(chat_sync_manager.clone());
+    #[test]
+    fn test_resends_with_the_same_key_are_collapsed() {
+        let manager = ChatSyncManager::new();
+        let mut first = chat("ana", "hi", Some("k1"));
+        assert_eq!(manager.add_chat_message(&mut first), Accepted::New(1));
+        let mut resent = chat("ana", "hi", Some("k1"));
+        assert_eq!(manager.add_chat_message(&mut resent), Accepted::Duplicate(1));
+
+        // Keys are per user, and messages without one are never collapsed
+        assert_eq!(manager.add_chat_message(&mut chat("bob", "hi", Some("k1"))), Accepted::New(2));
+        assert_eq!(manager.add_chat_message(&mut chat("ana", "again", None)), Accepted::New(3));
+        assert_eq!(manager.add_chat_message(&mut chat("ana", "again", None)), Accepted::New(4));
+        assert_eq!(manager.add_annotation("doc", &mut annotation(1, "note", Some("k2"))), Accepted::New(5));
+        assert_eq!(manager.add_annotation("doc", &mut annotation(1, "note", Some("k2"))), Accepted::Duplicate(5));
+        assert_eq!(manager.room_history("doc").len(), 4);
+        assert_eq!(manager.room_annotations("doc")[&1].len(), 1);
+    }
 
-    // Start the server
-    println!("Chat and annotation sync server running on ws://localhost:3030/chat_sync_ws");
-    warp::serve(chat_sync_ws_route).run(([127, 0, 0, 1], 3030)).await;
+    #[test]
+    fn test_missed_messages_recovered_in_id_order() {
+        let manager = ChatSyncManager::new();
+        manager.add_chat_message(&mut chat("ana", "one", None));
+        manager.add_annotation("doc", &mut annotation(9, "two", None));
+        manager.add_annotation("doc", &mut annotation(2, "three", None));
+        manager.add_chat_message(&mut chat("bob", "four", None));
+
+        let missed = manager.missed_since("doc", 1);
+        assert_eq!(missed.iter().map(Delivered::id).collect::<Vec<_>>(), vec![2, 3, 4]);
+        let frames: Vec<serde_json::Value> = missed.iter().map(|delivered| serde_json::to_value(delivered).unwrap()).collect();
+        assert_eq!(frames[0]["annotation"]["content"], "two");
+        assert_eq!(frames[2]["chat_message"]["message"], "four");
+        assert!(manager.missed_since("doc", 4).is_empty());
+        assert!(manager.missed_since("other", 0).is_empty());
+
+        // Ids carry on after a room is taken away and restored
+        let (messages, annotations) = manager.take_room("doc");
+        manager.restore_room("doc", messages, annotations).unwrap();
+        assert_eq!(manager.add_chat_message(&mut chat("ana", "five", None)), Accepted::New(5));
+    }
+
+    #[test]
+    fn test_document_comments_broadcast_to_every_participant() {
+        let manager = ChatSyncManager::new();
+        let mut inboxes = Vec::new();
+        for user in ["ana", "ben"] {
+            let (sender, receiver) = mpsc::channel(client::CLIENT_QUEUE_SIZE);
+            client::add_client(manager.clients.clone(), user.to_string(), Client::new(user, user, sender));
+            inboxes.push(receiver);
+        }
+
+        let comment = manager.add_document_comment("doc", "ana", ChatBody::try_from("Ready for review").unwrap()).unwrap();
+        manager.resolve_document_comment("doc", "ben", comment.id).unwrap();
+        assert!(manager.resolve_document_comment("doc", "ben", comment.id).is_err());
+
+        for inbox in inboxes.iter_mut() {
+            let frames: Vec<serde_json::Value> = std::iter::from_fn(|| inbox.try_recv().ok())
+                .map(|message| serde_json::from_str(message.to_str().unwrap()).unwrap())
+                .collect();
+            assert_eq!(frames.len(), 2);
+            assert_eq!(frames[0]["type"], "document_comment");
+            assert_eq!(frames[0]["comment"]["content"], "Ready for review");
+            assert_eq!(frames[0]["comment"]["resolved_by"], serde_json::Value::Null);
+            assert_eq!(frames[1]["type"], "document_comment_resolved");
+            assert_eq!(frames[1]["comment"]["resolved_by"], "ben");
+        }
+        assert_eq!(manager.document_comments("doc").len(), 1);
+        assert!(manager.document_comments("other").is_empty());
+    }
+
+    async fn recv_json(client: &mut warp::test::WsClient) -> serde_json::Value {
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(5), client.recv()).await.expect("no frame").unwrap();
+        serde_json::from_str(frame.to_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_redactions_and_mutes_reach_every_participant() {
+        let workspaces = crate::storage::workspace::Workspaces::new();
+        let id = workspaces.create("olga", "Team").unwrap().id;
+        workspaces.add_doc(&id, "olga", "doc").unwrap();
+        workspaces.set_member(&id, "olga", "mo", Some(crate::storage::workspace::WorkspaceRole::Moderator)).unwrap();
+        let manager = ChatSyncManager::new().with_moderation(Moderation::new(workspaces));
+        let route = chat_sync_route(manager.clone());
+        let connect = |user: &str| warp::test::ws().path(&format!("/chat_sync_ws/{}/doc", user)).handshake(route.clone());
+        let mut mo = connect("mo").await.unwrap();
+        let mut spammer = connect("spammer").await.unwrap();
+        recv_json(&mut mo).await;
+        recv_json(&mut spammer).await;
+
+        let spam = |key: &str| serde_json::json!({ "chat_message": { "user": "spammer", "message": "buy now", "timestamp": "", "key": key } }).to_string();
+        spammer.send_text(spam("k1")).await;
+        assert_eq!(recv_json(&mut spammer).await["type"], "ack");
+        for client in [&mut mo, &mut spammer] {
+            assert_eq!(recv_json(client).await["chat_message"]["id"], 1);
+        }
+
+        // Only moderators redact; everyone is told, and the message is gone from the history
+        spammer.send_text(serde_json::json!({ "type": "redact", "id": 1 }).to_string()).await;
+        assert_eq!(recv_json(&mut spammer).await["type"], "error");
+        mo.send_text(serde_json::json!({ "type": "redact", "id": 1 }).to_string()).await;
+        for client in [&mut mo, &mut spammer] {
+            assert_eq!(recv_json(client).await, serde_json::json!({ "type": "redacted", "room": "doc", "id": 1 }));
+        }
+        assert!(manager.room_history("doc").is_empty() && manager.missed_since("doc", 0).is_empty());
+
+        mo.send_text(serde_json::json!({ "type": "mute", "user": "spammer", "seconds": 600 }).to_string()).await;
+        for client in [&mut mo, &mut spammer] {
+            assert_eq!(recv_json(client).await["type"], "muted");
+        }
+        spammer.send_text(spam("k2")).await;
+        let rejected = recv_json(&mut spammer).await;
+        assert_eq!((rejected["type"].as_str(), rejected["key"].as_str()), (Some("rejected"), Some("k2")));
+
+        // Reconnecting doesn't shake the mute off
+        drop(spammer);
+        let mut spammer = connect("spammer").await.unwrap();
+        assert!(recv_json(&mut spammer).await["moderation"]["muted_until"].is_string());
+        spammer.send_text(spam("k3")).await;
+        assert_eq!(recv_json(&mut spammer).await["type"], "rejected");
+        assert_eq!(manager.audit_log("doc", "olga").unwrap().len(), 2);
+        assert!(manager.audit_log("doc", "mo").is_err());
+    }
 }
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use futures_util::{SinkExt, StreamExt};
-use tokio::net::TcpStream;
-use async_tungstenite::tokio::accept_async;
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use async_tungstenite::tokio::{accept_async, client_async, TokioAdapter};
+use async_tungstenite::tungstenite::protocol::Message;
+use async_tungstenite::WebSocketStream;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::task::JoinHandle;
 use std::net::SocketAddr;
@@ -13,9 +15,13 @@ pub struct PeerConnection {
     pub sender: UnboundedSender<Message>,
 }
 
+type Peers = Arc<Mutex<HashMap<SocketAddr, PeerConnection>>>;
+
 /// `ConnectionManager` manages the WebSocket connections between peers.
+#[derive(Clone)]
 pub struct ConnectionManager {
-    peers: Arc<Mutex<HashMap<SocketAddr, PeerConnection>>>, // Manages peer connections
+    peers: Peers, // Manages peer connections
+    inbox: Option<UnboundedSender<(SocketAddr, String)>>, // Where received messages go; relayed to the other peers without one
 }
 
 impl ConnectionManager {
@@ -23,53 +29,113 @@ impl ConnectionManager {
     pub fn new() -> Self {
         Self {
             peers: Arc::new(Mutex::new(HashMap::new())),
+            inbox: None,
         }
     }
 
+    /// Hands the messages received from peers to `inbox`, with the address of their connection,
+    /// instead of relaying them to the other peers.
+    pub fn with_inbox(self, inbox: UnboundedSender<(SocketAddr, String)>) -> Self {
+        Self { inbox: Some(inbox), ..self }
+    }
+
+    /// Accepts peers on `addr` until the returned task is aborted. Returns the address bound,
+    /// which is the one to announce when `addr` has port 0.
+    pub async fn listen(&self, addr: SocketAddr) -> std::io::Result<(SocketAddr, JoinHandle<()>)> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let manager = self.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, peer_addr)) = listener.accept().await {
+                manager.add_peer(stream, peer_addr).await;
+            }
+        });
+        Ok((local_addr, task))
+    }
+
     /// Adds a new peer to the connection manager and spawns a task to handle its connection.
     pub async fn add_peer(&self, stream: TcpStream, peer_addr: SocketAddr) -> JoinHandle<()> {
-        let (tx, mut rx) = unbounded_channel();
+        let manager = self.clone();
+        tokio::spawn(async move {
+            match accept_async(stream).await {
+                Ok(ws_stream) => manager.run_peer(ws_stream, peer_addr).await,
+                Err(e) => eprintln!("WebSocket handshake with {} failed: {}", peer_addr, e),
+            }
+        })
+    }
+
+    /// Dials the listener of a peer at `peer_addr` and adds the connection.
+    pub async fn connect(&self, peer_addr: SocketAddr) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+        let stream = TcpStream::connect(peer_addr).await?;
+        let (ws_stream, _) = client_async(format!("ws://{}/", peer_addr), stream).await?;
+        // Registered before returning, so messages can be sent to the peer right away
+        let (tx, rx) = unbounded_channel();
+        self.peers.lock().unwrap().insert(peer_addr, PeerConnection { sender: tx });
+        let manager = self.clone();
+        Ok(tokio::spawn(async move { manager.pump(ws_stream, peer_addr, rx).await }))
+    }
+
+    /// Closes the connection to a peer.
+    pub fn disconnect(&self, peer_addr: &SocketAddr) {
+        // Dropping the sender ends the connection's send task, and with it the connection
+        self.peers.lock().unwrap().remove(peer_addr);
+    }
+
+    async fn run_peer<S>(&self, ws_stream: WebSocketStream<TokioAdapter<S>>, peer_addr: SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, rx) = unbounded_channel();
 
         // Add peer to the peer map
         self.peers.lock().unwrap().insert(peer_addr, PeerConnection { sender: tx });
+        self.pump(ws_stream, peer_addr, rx).await;
+    }
 
-        let peers = self.peers.clone();
+    /// Moves messages between a peer's WebSocket and the manager until either side closes.
+    async fn pump<S>(&self, ws_stream: WebSocketStream<TokioAdapter<S>>, peer_addr: SocketAddr, mut rx: tokio::sync::mpsc::UnboundedReceiver<Message>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (mut write, mut read) = ws_stream.split();
 
-        tokio::spawn(async move {
-            let ws_stream = accept_async(stream).await.expect("Error during WebSocket handshake");
-            let (mut write, mut read) = ws_stream.split();
-
-            // Task to send messages to the peer
-            let send_task = tokio::spawn(async move {
-                while let Some(message) = rx.recv().await {
-                    if write.send(message).await.is_err() {
-                        break; // If sending fails, break out of the loop
-                    }
+        // Task to send messages to the peer
+        let send_task = tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if write.send(message).await.is_err() {
+                    break; // If sending fails, break out of the loop
                 }
-            });
+            }
+        });
 
-            // Task to receive messages from the peer
-            let recv_task = tokio::spawn(async move {
-                while let Some(Ok(message)) = read.next().await {
-                    if let Message::Text(text) = message {
-                        ConnectionManager::broadcast_message(&peers, &peer_addr, text).await;
+        // Task to receive messages from the peer
+        let peers = self.peers.clone();
+        let inbox = self.inbox.clone();
+        let recv_task = tokio::spawn(async move {
+            while let Some(Ok(message)) = read.next().await {
+                if let Message::Text(text) = message {
+                    match &inbox {
+                        Some(inbox) => {
+                            let _ = inbox.send((peer_addr, text));
+                        }
+                        None => ConnectionManager::broadcast_message(&peers, &peer_addr, text).await,
                     }
                 }
+            }
 
-                // When the peer disconnects, remove them from the peer map
-                peers.lock().unwrap().remove(&peer_addr);
-            });
+            // When the peer disconnects, remove them from the peer map
+            peers.lock().unwrap().remove(&peer_addr);
+        });
 
-            // Wait for both tasks to complete
-            tokio::select! {
-                _ = send_task => {},
-                _ = recv_task => {},
-            }
-        })
+        // Wait for both tasks to complete
+        tokio::select! {
+            _ = send_task => {},
+            _ = recv_task => {},
+        }
     }
 
     /// Broadcasts a message to all connected peers except the sender.
-    async fn broadcast_message(peers: &Arc<Mutex<HashMap<SocketAddr, PeerConnection>>>, sender_addr: &SocketAddr, message: String) {
+    async fn broadcast_message(peers: &Peers, sender_addr: &SocketAddr, message: String) {
         let peers = peers.lock().unwrap();
         for (peer_addr, peer) in peers.iter() {
             if peer_addr != sender_addr {
@@ -94,3 +160,9 @@ impl ConnectionManager {
         }
     }
 }
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,458 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::storage::workspace::PermissionCache;
+
+/// Shortest time between two cursor updates of one user
+pub const CURSOR_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Shortest time between two viewport updates of one user to their followers
+pub const VIEWPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When a user's position last went out, and the latest one still waiting
+struct CoalescedCursor {
+    last_sent: Instant,
+    pending: Option<usize>,
+}
+
+/// Coalesces cursor moves so each user's position goes out at most once per `interval`, and
+/// always the latest one. The first move after a quiet spell goes out at once; moves within the
+/// interval wait for `due`, which only sends the last of them. Text edits are not held back.
+pub struct CursorCoalescer {
+    interval: Duration,
+    users: HashMap<String, CoalescedCursor>,
+}
+
+impl CursorCoalescer {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, users: HashMap::new() }
+    }
+
+    /// Records that `user`'s cursor moved to `position` at `now`. Returns the position to send
+    /// right away, or `None` when it has to wait for `due`.
+    pub fn moved(&mut self, user: &str, position: usize, now: Instant) -> Option<usize> {
+        match self.users.get_mut(user) {
+            Some(cursor) if now.duration_since(cursor.last_sent) < self.interval => {
+                cursor.pending = Some(position);
+                None
+            }
+            _ => {
+                self.users.insert(user.to_string(), CoalescedCursor { last_sent: now, pending: None });
+                Some(position)
+            }
+        }
+    }
+
+    /// The waiting positions whose interval ran out by `now`, by user, to send
+    pub fn due(&mut self, now: Instant) -> Vec<(String, usize)> {
+        let mut due = Vec::new();
+        for (user, cursor) in self.users.iter_mut() {
+            if now.duration_since(cursor.last_sent) >= self.interval {
+                if let Some(position) = cursor.pending.take() {
+                    cursor.last_sent = now;
+                    due.push((user.clone(), position));
+                }
+            }
+        }
+        due.sort();
+        due
+    }
+
+    /// When the next waiting position is due, for the caller's timer
+    pub fn next_due(&self) -> Option<Instant> {
+        self.users.values().filter(|cursor| cursor.pending.is_some()).map(|cursor| cursor.last_sent + self.interval).min()
+    }
+
+    /// Forgets a user who left, dropping any position still waiting
+    pub fn remove(&mut self, user: &str) {
+        self.users.remove(user);
+    }
+}
+
+impl Default for CursorCoalescer {
+    fn default() -> Self {
+        Self::new(CURSOR_INTERVAL)
+    }
+}
+
+/// The lines a client has on screen, both included
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Viewport {
+    pub first_line: usize,
+    pub last_line: usize,
+}
+
+/// Presence frames from a client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PresenceRequest {
+    Cursor {
+        position: usize,
+        #[serde(default)]
+        selection: Option<(usize, usize)>,
+        #[serde(default)]
+        viewport: Option<Viewport>, // Only sent by clients that can be followed
+    },
+    Follow { target_user: String },
+    Unfollow,
+    Present { active: bool }, // Offer everyone in the room to follow this user
+}
+
+/// Presence frames to a client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PresenceEvent {
+    Cursor { user: String, position: usize, selection: Option<(usize, usize)> },
+    Viewport { user: String, viewport: Viewport }, // Only to the user's followers
+    Following { target_user: String },
+    FollowRejected { target_user: String, reason: String },
+    FollowEnded { target_user: String }, // The followed user left
+    Presenter { user: String },
+    PresenterCleared { user: String },
+}
+
+/// A presence frame for one user. Priority frames go ahead of what is queued for them, so
+/// followers keep up with the screen they follow.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresenceDelivery {
+    pub to: String,
+    pub event: PresenceEvent,
+    pub priority: bool,
+}
+
+/// Where a user is, what they see and whom they follow
+struct Presence {
+    room: String,
+    viewport: Option<Viewport>,
+    following: Option<String>,
+    viewport_sent: Option<Instant>, // When followers last got the viewport
+    viewport_pending: bool,         // The viewport changed since, and waits for `due`
+}
+
+/// Cursors, viewports, follows and presenters of the users in each room. A follower gets the
+/// viewport of the user they follow, throttled to one update per `viewport_interval` but always
+/// the latest; nobody else gets viewports. All of it lives only as long as the connections.
+pub struct PresenceHub {
+    viewport_interval: Duration,
+    users: HashMap<String, Presence>,     // By user
+    presenters: HashMap<String, String>, // Presenting user, by room
+}
+
+impl PresenceHub {
+    pub fn new(viewport_interval: Duration) -> Self {
+        Self { viewport_interval, users: HashMap::new(), presenters: HashMap::new() }
+    }
+
+    /// Adds `user` to `room`. They are told who presents there, if anyone.
+    pub fn join(&mut self, room: &str, user: &str) -> Vec<PresenceDelivery> {
+        self.users.insert(user.to_string(), Presence { room: room.to_string(), viewport: None, following: None, viewport_sent: None, viewport_pending: false });
+        match self.presenters.get(room) {
+            Some(presenter) => vec![delivery(user, PresenceEvent::Presenter { user: presenter.clone() }, false)],
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes `user`. If they presented, the room is told the presenter is gone, and whoever
+    /// followed them stops.
+    pub fn leave(&mut self, user: &str) -> Vec<PresenceDelivery> {
+        let Some(presence) = self.users.remove(user) else { return Vec::new() };
+        let mut deliveries = Vec::new();
+        if self.presenters.get(&presence.room).is_some_and(|presenter| presenter == user) {
+            self.presenters.remove(&presence.room);
+            deliveries.extend(self.to_room(&presence.room, None, PresenceEvent::PresenterCleared { user: user.to_string() }));
+        }
+        let mut followers: Vec<&String> = Vec::new();
+        for (follower, presence) in self.users.iter_mut() {
+            if presence.following.as_deref() == Some(user) {
+                presence.following = None;
+                followers.push(follower);
+            }
+        }
+        followers.sort();
+        deliveries.extend(followers.into_iter().map(|follower| delivery(follower, PresenceEvent::FollowEnded { target_user: user.to_string() }, true)));
+        deliveries
+    }
+
+    /// Handles a presence frame from `user`, connected with `permissions`
+    pub fn handle(&mut self, user: &str, request: PresenceRequest, permissions: &PermissionCache, now: Instant) -> Vec<PresenceDelivery> {
+        let Some(room) = self.users.get(user).map(|presence| presence.room.clone()) else { return Vec::new() };
+        match request {
+            PresenceRequest::Cursor { position, selection, viewport } => {
+                let mut deliveries = self.to_room(&room, Some(user), PresenceEvent::Cursor { user: user.to_string(), position, selection });
+                if let Some(viewport) = viewport {
+                    deliveries.extend(self.move_viewport(user, viewport, now));
+                }
+                deliveries
+            }
+            PresenceRequest::Follow { target_user } => match self.follow(user, &room, &target_user, permissions) {
+                Ok(viewport) => {
+                    let mut deliveries = vec![delivery(user, PresenceEvent::Following { target_user: target_user.clone() }, true)];
+                    if let Some(viewport) = viewport {
+                        deliveries.push(delivery(user, PresenceEvent::Viewport { user: target_user, viewport }, true));
+                    }
+                    deliveries
+                }
+                Err(reason) => vec![delivery(user, PresenceEvent::FollowRejected { target_user, reason }, false)],
+            },
+            PresenceRequest::Unfollow => {
+                self.users.get_mut(user).unwrap().following = None;
+                Vec::new()
+            }
+            PresenceRequest::Present { active: true } => {
+                self.presenters.insert(room.clone(), user.to_string());
+                self.to_room(&room, None, PresenceEvent::Presenter { user: user.to_string() })
+            }
+            PresenceRequest::Present { active: false } => {
+                if self.presenters.get(&room).is_some_and(|presenter| presenter == user) {
+                    self.presenters.remove(&room);
+                    return self.to_room(&room, None, PresenceEvent::PresenterCleared { user: user.to_string() });
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    /// The viewports held back by throttling whose interval ran out by `now`, to the followers
+    pub fn due(&mut self, now: Instant) -> Vec<PresenceDelivery> {
+        let mut due: Vec<(String, Viewport)> = Vec::new();
+        for (user, presence) in self.users.iter_mut() {
+            let ready = presence.viewport_sent.is_none_or(|sent| now.duration_since(sent) >= self.viewport_interval);
+            if presence.viewport_pending && ready {
+                presence.viewport_pending = false;
+                presence.viewport_sent = Some(now);
+                due.extend(presence.viewport.map(|viewport| (user.clone(), viewport)));
+            }
+        }
+        due.sort_by(|a, b| a.0.cmp(&b.0));
+        due.into_iter().flat_map(|(user, viewport)| self.to_followers(&user, viewport)).collect()
+    }
+
+    /// When the next held back viewport is due, for the caller's timer
+    pub fn next_due(&self) -> Option<Instant> {
+        self.users
+            .values()
+            .filter(|presence| presence.viewport_pending)
+            .filter_map(|presence| presence.viewport_sent.map(|sent| sent + self.viewport_interval))
+            .min()
+    }
+
+    /// The presenter of `room`, if anyone presents there
+    pub fn presenter(&self, room: &str) -> Option<&str> {
+        self.presenters.get(room).map(String::as_str)
+    }
+
+    fn move_viewport(&mut self, user: &str, viewport: Viewport, now: Instant) -> Vec<PresenceDelivery> {
+        let interval = self.viewport_interval;
+        let presence = self.users.get_mut(user).unwrap();
+        if presence.viewport == Some(viewport) {
+            return Vec::new();
+        }
+        presence.viewport = Some(viewport);
+        if presence.viewport_sent.is_some_and(|sent| now.duration_since(sent) < interval) {
+            presence.viewport_pending = true;
+            return Vec::new();
+        }
+        presence.viewport_sent = Some(now);
+        presence.viewport_pending = false;
+        self.to_followers(user, viewport)
+    }
+
+    /// Makes `user` follow `target_user`, returning the viewport to start from
+    fn follow(&mut self, user: &str, room: &str, target_user: &str, permissions: &PermissionCache) -> Result<Option<Viewport>, String> {
+        if permissions.doc_id() != room || permissions.role().is_none() {
+            return Err(format!("You can't read {}", room));
+        }
+        if target_user == user {
+            return Err("You can't follow yourself".to_string());
+        }
+        let target = match self.users.get(target_user) {
+            Some(target) if target.room == room => target,
+            _ => return Err(format!("{} isn't in {}", target_user, room)),
+        };
+        // Following someone who, through whoever they follow, follows us would go round in circles
+        let mut next = target.following.as_deref();
+        while let Some(followed) = next {
+            if followed == user {
+                return Err(format!("{} already follows you", target_user));
+            }
+            next = self.users.get(followed).and_then(|presence| presence.following.as_deref());
+        }
+        let viewport = target.viewport;
+        self.users.get_mut(user).unwrap().following = Some(target_user.to_string());
+        Ok(viewport)
+    }
+
+    fn to_followers(&self, user: &str, viewport: Viewport) -> Vec<PresenceDelivery> {
+        let mut followers: Vec<&String> = self.users.iter().filter(|(_, presence)| presence.following.as_deref() == Some(user)).map(|(follower, _)| follower).collect();
+        followers.sort();
+        followers.into_iter().map(|follower| delivery(follower, PresenceEvent::Viewport { user: user.to_string(), viewport }, true)).collect()
+    }
+
+    fn to_room(&self, room: &str, except: Option<&str>, event: PresenceEvent) -> Vec<PresenceDelivery> {
+        let mut users: Vec<&String> = self.users.iter().filter(|(user, presence)| presence.room == room && Some(user.as_str()) != except).map(|(user, _)| user).collect();
+        users.sort();
+        users.into_iter().map(|user| delivery(user, event.clone(), false)).collect()
+    }
+}
+
+impl Default for PresenceHub {
+    fn default() -> Self {
+        Self::new(VIEWPORT_INTERVAL)
+    }
+}
+
+fn delivery(to: &str, event: PresenceEvent, priority: bool) -> PresenceDelivery {
+    PresenceDelivery { to: to.to_string(), event, priority }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::workspace::{WorkspaceRole, Workspaces};
+
+    #[test]
+    fn test_rapid_moves_send_the_final_position_once() {
+        let start = Instant::now();
+        let mut coalescer = CursorCoalescer::new(Duration::from_millis(50));
+        assert_eq!(coalescer.moved("ana", 1, start), Some(1)); // After a quiet spell, at once
+
+        for (ms, position) in [(5, 2), (10, 3), (30, 4), (45, 5)] {
+            assert_eq!(coalescer.moved("ana", position, start + Duration::from_millis(ms)), None);
+        }
+        assert_eq!(coalescer.next_due(), Some(start + Duration::from_millis(50)));
+        assert!(coalescer.due(start + Duration::from_millis(49)).is_empty());
+        assert_eq!(coalescer.due(start + Duration::from_millis(50)), vec![("ana".to_string(), 5)]);
+        assert!(coalescer.due(start + Duration::from_millis(200)).is_empty());
+        assert_eq!(coalescer.next_due(), None);
+    }
+
+    #[test]
+    fn test_users_are_coalesced_separately() {
+        let start = Instant::now();
+        let mut coalescer = CursorCoalescer::default();
+        assert_eq!(coalescer.moved("ana", 1, start), Some(1));
+        assert_eq!(coalescer.moved("ben", 7, start), Some(7));
+        assert_eq!(coalescer.moved("ana", 2, start + Duration::from_millis(10)), None);
+        assert_eq!(coalescer.moved("ben", 8, start + Duration::from_millis(20)), None);
+        coalescer.remove("ben");
+        assert_eq!(coalescer.due(start + CURSOR_INTERVAL), vec![("ana".to_string(), 2)]);
+
+        // Moving again a whole interval after the last update goes out at once
+        assert_eq!(coalescer.moved("ana", 3, start + CURSOR_INTERVAL * 2), Some(3));
+    }
+
+    /// "ana" presents "pad.md" to "ben" and "cy"; "dee" is in "other.md"
+    fn hub() -> (PresenceHub, Workspaces) {
+        let workspaces = Workspaces::new();
+        let id = workspaces.create("ana", "Team").unwrap().id;
+        for user in ["ben", "cy", "dee"] {
+            workspaces.set_member(&id, "ana", user, Some(WorkspaceRole::Viewer)).unwrap();
+        }
+        workspaces.add_doc(&id, "ana", "pad.md").unwrap();
+        workspaces.add_doc(&id, "ana", "other.md").unwrap();
+        let mut hub = PresenceHub::new(Duration::from_millis(100));
+        for user in ["ana", "ben", "cy"] {
+            hub.join("pad.md", user);
+        }
+        hub.join("other.md", "dee");
+        (hub, workspaces)
+    }
+
+    fn follow(hub: &mut PresenceHub, workspaces: &Workspaces, user: &str, target_user: &str) -> Vec<PresenceDelivery> {
+        let room = hub.users[user].room.clone();
+        let permissions = workspaces.open(&room, user, None).unwrap();
+        hub.handle(user, PresenceRequest::Follow { target_user: target_user.to_string() }, &permissions, Instant::now())
+    }
+
+    fn scroll(hub: &mut PresenceHub, workspaces: &Workspaces, user: &str, first_line: usize, now: Instant) -> Vec<PresenceDelivery> {
+        let permissions = workspaces.open("pad.md", user, None).unwrap();
+        let viewport = Some(Viewport { first_line, last_line: first_line + 40 });
+        hub.handle(user, PresenceRequest::Cursor { position: first_line * 10, selection: None, viewport }, &permissions, now)
+    }
+
+    fn viewports(deliveries: &[PresenceDelivery]) -> Vec<(&str, usize)> {
+        deliveries
+            .iter()
+            .filter_map(|delivery| match &delivery.event {
+                PresenceEvent::Viewport { viewport, .. } => Some((delivery.to.as_str(), viewport.first_line)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_viewports_go_to_followers_only() {
+        let (mut hub, workspaces) = hub();
+        assert_eq!(follow(&mut hub, &workspaces, "ben", "ana")[0].event, PresenceEvent::Following { target_user: "ana".to_string() });
+
+        let deliveries = scroll(&mut hub, &workspaces, "ana", 10, Instant::now());
+        assert_eq!(viewports(&deliveries), vec![("ben", 10)]);
+        assert!(deliveries.iter().filter(|delivery| matches!(delivery.event, PresenceEvent::Viewport { .. })).all(|delivery| delivery.priority));
+        let cursors: Vec<&str> = deliveries.iter().filter(|delivery| matches!(delivery.event, PresenceEvent::Cursor { .. })).map(|delivery| delivery.to.as_str()).collect();
+        assert_eq!(cursors, vec!["ben", "cy"]);
+
+        // A new follower starts from the current viewport; one who stopped gets no more
+        assert_eq!(viewports(&follow(&mut hub, &workspaces, "cy", "ana")), vec![("cy", 10)]);
+        let permissions = workspaces.open("pad.md", "ben", None).unwrap();
+        hub.handle("ben", PresenceRequest::Unfollow, &permissions, Instant::now());
+        let later = Instant::now() + Duration::from_secs(1);
+        assert_eq!(viewports(&scroll(&mut hub, &workspaces, "ana", 20, later)), vec![("cy", 20)]);
+    }
+
+    #[test]
+    fn test_presenter_leaving_clears_it() {
+        let (mut hub, workspaces) = hub();
+        let permissions = workspaces.open("pad.md", "ana", None).unwrap();
+        let announced = hub.handle("ana", PresenceRequest::Present { active: true }, &permissions, Instant::now());
+        assert_eq!(announced.len(), 3);
+        assert_eq!(hub.join("pad.md", "eve"), vec![delivery("eve", PresenceEvent::Presenter { user: "ana".to_string() }, false)]);
+        follow(&mut hub, &workspaces, "ben", "ana");
+
+        let deliveries = hub.leave("ana");
+        let cleared: Vec<&str> = deliveries.iter().filter(|delivery| delivery.event == PresenceEvent::PresenterCleared { user: "ana".to_string() }).map(|delivery| delivery.to.as_str()).collect();
+        assert_eq!(cleared, vec!["ben", "cy", "eve"]);
+        assert!(deliveries.contains(&delivery("ben", PresenceEvent::FollowEnded { target_user: "ana".to_string() }, true)));
+        assert_eq!(hub.presenter("pad.md"), None);
+        assert!(hub.users["ben"].following.is_none());
+    }
+
+    #[test]
+    fn test_cyclic_follow_rejected() {
+        let (mut hub, workspaces) = hub();
+        follow(&mut hub, &workspaces, "ben", "ana");
+        follow(&mut hub, &workspaces, "cy", "ben");
+        for (user, target) in [("ana", "ben"), ("ana", "cy"), ("ana", "ana")] {
+            let deliveries = follow(&mut hub, &workspaces, user, target);
+            assert!(matches!(&deliveries[0].event, PresenceEvent::FollowRejected { target_user, .. } if target_user == target), "{} following {}", user, target);
+        }
+        assert!(hub.users["ana"].following.is_none());
+    }
+
+    #[test]
+    fn test_throttled_viewport_delivers_the_latest() {
+        let (mut hub, workspaces) = hub();
+        follow(&mut hub, &workspaces, "ben", "ana");
+        let start = Instant::now();
+        assert_eq!(viewports(&scroll(&mut hub, &workspaces, "ana", 1, start)), vec![("ben", 1)]);
+        for (ms, line) in [(10, 2), (40, 3), (90, 4)] {
+            assert!(viewports(&scroll(&mut hub, &workspaces, "ana", line, start + Duration::from_millis(ms))).is_empty());
+        }
+        assert_eq!(hub.next_due(), Some(start + Duration::from_millis(100)));
+        assert!(hub.due(start + Duration::from_millis(99)).is_empty());
+        assert_eq!(viewports(&hub.due(start + Duration::from_millis(100))), vec![("ben", 4)]);
+        assert!(hub.due(start + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn test_follow_across_rooms_rejected() {
+        let (mut hub, workspaces) = hub();
+        let deliveries = follow(&mut hub, &workspaces, "dee", "ana");
+        assert_eq!(deliveries, vec![delivery("dee", PresenceEvent::FollowRejected { target_user: "ana".to_string(), reason: "ana isn't in other.md".to_string() }, false)]);
+
+        // Nor may anyone follow with permissions for another document, or none at all
+        let permissions = workspaces.open("other.md", "ben", None).unwrap();
+        let deliveries = hub.handle("ben", PresenceRequest::Follow { target_user: "ana".to_string() }, &permissions, Instant::now());
+        assert!(matches!(deliveries[0].event, PresenceEvent::FollowRejected { .. }));
+        assert!(workspaces.open("pad.md", "mallory", None).is_err());
+    }
+}
@@ -1,10 +1,14 @@
-use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::task::JoinHandle;
 use tokio::net::TcpStream;
 use serde::{Serialize, Deserialize};
 use std::error::Error;
+use tokio::net::UdpSocket;
+use crate::networking::fallback::Announcement;
+
+/// UDP port clients announce themselves on to the LAN while the server is unreachable
+pub const LAN_DISCOVERY_PORT: u16 = 45_454;
 
 /// Message sent to the signaling server to register a peer.
 #[derive(Serialize, Deserialize, Debug)]
@@ -21,7 +25,6 @@ pub struct PeerListMessage {
 /// `Discovery` is responsible for discovering and connecting to peers.
 pub struct Discovery {
     signaling_server_url: String,
-    peers: HashMap<SocketAddr, UnboundedSender<String>>, // Stores discovered peers
 }
 
 impl Discovery {
@@ -29,7 +32,6 @@ impl Discovery {
     pub fn new(signaling_server_url: &str) -> Self {
         Self {
             signaling_server_url: signaling_server_url.to_string(),
-            peers: HashMap::new(),
         }
     }
 
@@ -85,4 +87,41 @@ impl Discovery {
 
         Ok(())
     }
+
+    /// Registers `announcement` with the signaling server, for when only the main server is
+    /// down, and returns the other peers editing the same document.
+    pub async fn announce(&self, announcement: &Announcement) -> Result<Vec<Announcement>, Box<dyn Error>> {
+        let client = reqwest::Client::new();
+        let response = client
+            .post(&self.signaling_server_url)
+            .json(announcement)
+            .send()
+            .await?;
+        let peers: Vec<Announcement> = response.json().await?;
+        Ok(peers.into_iter().filter(|peer| peer.doc_id == announcement.doc_id && peer.peer_id != announcement.peer_id).collect())
+    }
+}
+
+/// Broadcasts `announcement` to the LAN, for the `listen_on_lan` of other clients.
+pub async fn announce_on_lan(announcement: &Announcement) -> Result<(), Box<dyn Error>> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&serde_json::to_vec(announcement)?, ("255.255.255.255", LAN_DISCOVERY_PORT)).await?;
+    Ok(())
+}
+
+/// Passes the announcements broadcast on the LAN to `found` until the returned task is aborted.
+/// Datagrams that aren't announcements are ignored.
+pub async fn listen_on_lan(found: UnboundedSender<Announcement>) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let socket = UdpSocket::bind(("0.0.0.0", LAN_DISCOVERY_PORT)).await?;
+    Ok(tokio::spawn(async move {
+        let mut buffer = [0u8; 2048];
+        while let Ok((len, _)) = socket.recv_from(&mut buffer).await {
+            if let Ok(announcement) = serde_json::from_slice::<Announcement>(&buffer[..len]) {
+                if found.send(announcement).is_err() {
+                    break; // Nobody is looking any more
+                }
+            }
+        }
+    }))
 }
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::storage::Storage;
+use crate::validation::{ChatBody, Username};
+
+/// Storage identifier under which document comments are persisted
+const DOCUMENT_COMMENTS_ID: &str = "document_comments.json";
+
+/// A comment on a document as a whole, not on any of its lines
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DocumentComment {
+    pub id: u64, // Increasing per room
+    pub room: String,
+    pub user: Username,
+    pub content: ChatBody,
+    pub timestamp: String,
+    #[serde(default)]
+    pub resolved_by: Option<Username>, // Who marked the discussion as settled, if anyone
+}
+
+/// Frames broadcast to the participants when the document discussion changes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DocumentCommentEvent {
+    DocumentComment { comment: DocumentComment },
+    DocumentCommentResolved { comment: DocumentComment },
+}
+
+/// The discussion thread of each document, apart from the annotations on its lines, persisted
+/// through a `Storage` backend
+#[derive(Clone)]
+pub struct DocumentComments {
+    comments: Arc<Mutex<HashMap<String, Vec<DocumentComment>>>>, // By room, oldest first
+    storage: Option<Arc<dyn Storage + Send + Sync>>,
+}
+
+impl DocumentComments {
+    /// Creates an in-memory store
+    pub fn new() -> Self {
+        Self { comments: Arc::new(Mutex::new(HashMap::new())), storage: None }
+    }
+
+    /// Creates a store backed by `storage`, loading the comments saved before
+    pub fn with_storage(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        let store = Self { storage: Some(storage.clone()), ..Self::new() };
+
+        if let Ok(saved) = storage.load(DOCUMENT_COMMENTS_ID) {
+            let saved: Vec<DocumentComment> = serde_json::from_str(&saved).unwrap_or_default();
+            let mut comments = store.comments.lock().unwrap();
+            for comment in saved {
+                comments.entry(comment.room.clone()).or_default().push(comment);
+            }
+        }
+        store
+    }
+
+    /// Adds a comment by `user` to the discussion of `room` under the next id
+    pub fn add(&self, room: &str, user: Username, content: ChatBody, timestamp: String) -> DocumentComment {
+        let (comment, saved) = {
+            let mut comments = self.comments.lock().unwrap();
+            let thread = comments.entry(room.to_string()).or_default();
+            let id = thread.last().map_or(1, |last| last.id + 1);
+            let comment = DocumentComment { id, room: room.to_string(), user, content, timestamp, resolved_by: None };
+            thread.push(comment.clone());
+            (comment, Self::all(&comments))
+        };
+        self.persist(&saved);
+        comment
+    }
+
+    /// The discussion of `room`, oldest first
+    pub fn list(&self, room: &str) -> Vec<DocumentComment> {
+        self.comments.lock().unwrap().get(room).cloned().unwrap_or_default()
+    }
+
+    /// Marks comment `id` of `room` as resolved by `user`, failing when there is no such
+    /// comment or it was resolved already
+    pub fn resolve(&self, room: &str, id: u64, user: Username) -> Result<DocumentComment, String> {
+        let (comment, saved) = {
+            let mut comments = self.comments.lock().unwrap();
+            let comment = comments
+                .get_mut(room)
+                .and_then(|thread| thread.iter_mut().find(|comment| comment.id == id))
+                .ok_or_else(|| format!("No comment {} on {}", id, room))?;
+            if let Some(resolved_by) = &comment.resolved_by {
+                return Err(format!("Comment {} was already resolved by {}", id, resolved_by.as_str()));
+            }
+            comment.resolved_by = Some(user);
+            (comment.clone(), Self::all(&comments))
+        };
+        self.persist(&saved);
+        Ok(comment)
+    }
+
+    /// Every comment of every room, to save
+    fn all(comments: &HashMap<String, Vec<DocumentComment>>) -> Vec<DocumentComment> {
+        comments.values().flatten().cloned().collect()
+    }
+
+    fn persist(&self, comments: &[DocumentComment]) {
+        if let Some(storage) = &self.storage {
+            match serde_json::to_string(comments) {
+                Ok(json) => {
+                    if let Err(e) = storage.save(DOCUMENT_COMMENTS_ID, &json) {
+                        eprintln!("Failed to persist document comments: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Failed to serialize document comments: {}", e),
+            }
+        }
+    }
+}
+
+impl Default for DocumentComments {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn user(name: &str) -> Username {
+        Username::try_from(name).unwrap()
+    }
+
+    #[test]
+    fn test_comments_resolved_and_persisted() {
+        let storage = Arc::new(MemoryStorage::default());
+        let comments = DocumentComments::with_storage(storage.clone());
+        let first = comments.add("doc", user("ana"), ChatBody::try_from("Ready to publish?").unwrap(), String::new());
+        let second = comments.add("doc", user("ben"), ChatBody::try_from("Needs an intro").unwrap(), String::new());
+        comments.add("other", user("ana"), ChatBody::try_from("Elsewhere").unwrap(), String::new());
+        assert_eq!((first.id, second.id), (1, 2));
+
+        let resolved = comments.resolve("doc", 2, user("ana")).unwrap();
+        assert_eq!(resolved.resolved_by, Some(user("ana")));
+        assert!(comments.resolve("doc", 2, user("ben")).is_err());
+        assert!(comments.resolve("doc", 7, user("ben")).is_err());
+
+        // Loaded again from storage, ids carry on
+        let reloaded = DocumentComments::with_storage(storage);
+        assert_eq!(reloaded.list("doc"), vec![first, resolved]);
+        assert_eq!(reloaded.add("doc", user("cy"), ChatBody::try_from("Done").unwrap(), String::new()).id, 3);
+        assert_eq!(reloaded.list("other").len(), 1);
+    }
+}
@@ -0,0 +1,449 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use crate::editor::diff_engine::DiffEngine;
+use crate::storage::file_storage::content_hash;
+
+/// How long the server may stay unreachable before collaborators are looked for on the LAN
+pub const OFFLINE_AFTER: Duration = Duration::from_secs(15);
+
+/// Where a client editing a document can be reached while the server is down. Broadcast on
+/// the LAN, or registered with the signaling server when only the main server is down.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Announcement {
+    pub peer_id: String,
+    pub doc_id: String,
+    pub base_hash: String, // `content_hash` of the last content the server confirmed
+    pub addr: SocketAddr,  // The client's `ConnectionManager` listener
+}
+
+/// Frames exchanged between peers over the `ConnectionManager` connections
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PeerFrame {
+    Hello(Announcement), // First frame on a connection, from both ends
+    State { from: String, doc_id: String, content: String },
+    Merged { from: String, doc_id: String, content: String }, // The link leader's merge of a `State`
+}
+
+/// Where edits go
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    Server,     // Through the server, as deltas
+    PeerToPeer, // Straight to peers on the LAN, as merged states
+    Resyncing,  // Handing the merged state back to the server
+}
+
+/// Mode changes for the UI to show
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ModeEvent {
+    ServerUnreachable, // Reconnecting; peers are looked for after `offline_after`
+    PeerToPeer,
+    PeerConnected { peer_id: String, diverged: bool }, // `diverged`: the peer last saw another server revision
+    Resyncing,
+    Online,
+}
+
+/// What the `Networking` controller has to do for the fallback
+#[derive(Debug, Clone, PartialEq)]
+pub enum FallbackAction {
+    Listen,                                       // Start a `ConnectionManager` listener, then call `listening`
+    Connect(SocketAddr),                          // Dial a discovered peer and send it `hello`
+    Announce,                                     // Announce again, for a peer that came later to dial us
+    Send { peer_id: String, frame: PeerFrame },
+    Resync(String),                               // Submit this content as a local edit through the server
+    Disconnect(Vec<String>),                      // Tear down the connections to these peers
+}
+
+/// One connection to a peer. Of the two ends the one with the smaller id leads: it merges the
+/// states the other sends and answers with the result, so both ends end up with the same text.
+struct PeerLink {
+    agreed: String,       // The last content both ends had
+    sent: Option<String>, // Our state awaiting the leader's `Merged`, when following
+}
+
+/// Keeps collaborators editing while the server is unreachable. After `offline_after` without
+/// the server the client listens for peers, announces itself and connects to peers editing the
+/// same document; edits then travel between peers as states merged against the last content
+/// both had. When the server is back the merged content is resynced through the normal delta
+/// flow and the peer connections are torn down.
+///
+/// Nothing here does I/O: the `FallbackAction`s returned are carried out by `Networking`.
+pub struct FallbackController {
+    peer_id: String,
+    doc_id: String,
+    offline_after: Duration,
+    mode: SyncMode,
+    unreachable_since: Option<Instant>,
+    server_base: String, // The last content the server confirmed
+    content: String,     // The local content
+    addr: Option<SocketAddr>,
+    links: BTreeMap<String, PeerLink>, // By peer id
+    events: Vec<ModeEvent>,
+}
+
+impl FallbackController {
+    /// Creates a controller for `doc_id`, whose content as loaded from the server is `content`
+    pub fn new(peer_id: &str, doc_id: &str, content: &str) -> Self {
+        Self {
+            peer_id: peer_id.to_string(),
+            doc_id: doc_id.to_string(),
+            offline_after: OFFLINE_AFTER,
+            mode: SyncMode::Server,
+            unreachable_since: None,
+            server_base: content.to_string(),
+            content: content.to_string(),
+            addr: None,
+            links: BTreeMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Falls back to peers after `offline_after` without the server instead of `OFFLINE_AFTER`
+    pub fn with_offline_after(self, offline_after: Duration) -> Self {
+        Self { offline_after, ..self }
+    }
+
+    pub fn mode(&self) -> SyncMode {
+        self.mode
+    }
+
+    /// The local content, merged with the peers' in peer-to-peer mode
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// The ids of the connected peers
+    pub fn peers(&self) -> Vec<String> {
+        self.links.keys().cloned().collect()
+    }
+
+    /// Mode changes since the last call, for the UI
+    pub fn take_events(&mut self) -> Vec<ModeEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Records content the server confirmed, the common ground with peers should it go away
+    pub fn server_confirmed(&mut self, content: &str) {
+        if self.mode == SyncMode::Server {
+            self.server_base = content.to_string();
+            self.content = content.to_string();
+        }
+    }
+
+    /// Records that reconnecting to the server failed at `now`
+    pub fn server_lost(&mut self, now: Instant) {
+        if self.mode == SyncMode::Server && self.unreachable_since.is_none() {
+            self.unreachable_since = Some(now);
+            self.events.push(ModeEvent::ServerUnreachable);
+        }
+    }
+
+    /// Switches to peer-to-peer once the server has been unreachable for `offline_after`
+    pub fn tick(&mut self, now: Instant) -> Vec<FallbackAction> {
+        match self.unreachable_since {
+            Some(since) if self.mode == SyncMode::Server && now.duration_since(since) >= self.offline_after => {
+                self.mode = SyncMode::PeerToPeer;
+                self.events.push(ModeEvent::PeerToPeer);
+                vec![FallbackAction::Listen]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Records the address the listener is bound to, returning what to announce
+    pub fn listening(&mut self, addr: SocketAddr) -> Announcement {
+        self.addr = Some(addr);
+        self.announcement(addr)
+    }
+
+    /// The first frame to send on a new peer connection, from either end
+    pub fn hello(&self) -> Option<PeerFrame> {
+        self.addr.map(|addr| PeerFrame::Hello(self.announcement(addr)))
+    }
+
+    /// Handles a peer announcing itself. Of two peers editing the same document, the one with
+    /// the smaller id dials, so they connect only once; the other announces itself again in
+    /// case the peer came later and never heard it.
+    pub fn discovered(&mut self, announcement: &Announcement) -> Vec<FallbackAction> {
+        let relevant = self.mode == SyncMode::PeerToPeer
+            && announcement.doc_id == self.doc_id
+            && announcement.peer_id != self.peer_id
+            && !self.links.contains_key(&announcement.peer_id);
+        match relevant {
+            true if self.leads(&announcement.peer_id) => vec![FallbackAction::Connect(announcement.addr)],
+            true => vec![FallbackAction::Announce],
+            false => Vec::new(),
+        }
+    }
+
+    /// Applies a local edit, sending the new state to the peers
+    pub fn local_edit(&mut self, content: &str) -> Vec<FallbackAction> {
+        self.content = content.to_string();
+        if self.mode != SyncMode::PeerToPeer {
+            return Vec::new();
+        }
+        let peers = self.peers();
+        peers.iter().filter_map(|peer_id| self.send_state(peer_id)).collect()
+    }
+
+    /// Handles a frame from a peer
+    pub fn receive(&mut self, frame: PeerFrame) -> Vec<FallbackAction> {
+        if self.mode != SyncMode::PeerToPeer {
+            return Vec::new();
+        }
+        match frame {
+            PeerFrame::Hello(announcement) => self.greeted(announcement),
+            PeerFrame::State { from, doc_id, content } if doc_id == self.doc_id => self.merge_state(&from, content),
+            PeerFrame::Merged { from, doc_id, content } if doc_id == self.doc_id => self.merged(&from, content),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Handles the server coming back: the merged content goes to it as a local edit, through
+    /// the usual delta and merge flow, and the peers are let go
+    pub fn server_restored(&mut self) -> Vec<FallbackAction> {
+        self.unreachable_since = None;
+        if self.mode != SyncMode::PeerToPeer {
+            if self.mode == SyncMode::Server {
+                self.events.push(ModeEvent::Online);
+            }
+            return Vec::new();
+        }
+        self.mode = SyncMode::Resyncing;
+        self.addr = None;
+        self.events.push(ModeEvent::Resyncing);
+        let peers = std::mem::take(&mut self.links).into_keys().collect();
+        vec![FallbackAction::Resync(self.content.clone()), FallbackAction::Disconnect(peers)]
+    }
+
+    /// Records that the server applied the resync, leaving `content`
+    pub fn resynced(&mut self, content: &str) {
+        self.mode = SyncMode::Server;
+        self.server_base = content.to_string();
+        self.content = content.to_string();
+        self.events.push(ModeEvent::Online);
+    }
+
+    fn announcement(&self, addr: SocketAddr) -> Announcement {
+        Announcement { peer_id: self.peer_id.clone(), doc_id: self.doc_id.clone(), base_hash: content_hash(&self.server_base), addr }
+    }
+
+    fn leads(&self, peer_id: &str) -> bool {
+        self.peer_id.as_str() < peer_id
+    }
+
+    /// A peer's `Hello`. Both ends start from their last server content; when those differ the
+    /// peers still merge, so neither overwrites the other.
+    fn greeted(&mut self, announcement: Announcement) -> Vec<FallbackAction> {
+        if announcement.doc_id != self.doc_id || announcement.peer_id == self.peer_id || self.links.contains_key(&announcement.peer_id) {
+            return Vec::new();
+        }
+        let diverged = announcement.base_hash != content_hash(&self.server_base);
+        self.links.insert(announcement.peer_id.clone(), PeerLink { agreed: self.server_base.clone(), sent: None });
+        self.events.push(ModeEvent::PeerConnected { peer_id: announcement.peer_id.clone(), diverged });
+        // The follower opens with its state and the leader answers with the merge, so both ends
+        // agree on a text before anything else is merged against it
+        if self.leads(&announcement.peer_id) {
+            Vec::new()
+        } else {
+            self.send_state(&announcement.peer_id).into_iter().collect()
+        }
+    }
+
+    /// Our state for `peer_id`, if it doesn't have it yet
+    fn send_state(&mut self, peer_id: &str) -> Option<FallbackAction> {
+        let leads = self.leads(peer_id);
+        let link = self.links.get_mut(peer_id)?;
+        if !leads {
+            link.sent = Some(self.content.clone());
+        }
+        let frame = PeerFrame::State { from: self.peer_id.clone(), doc_id: self.doc_id.clone(), content: self.content.clone() };
+        Some(FallbackAction::Send { peer_id: peer_id.to_string(), frame })
+    }
+
+    /// A peer's state, merged against what we last agreed on. The leader's text goes first
+    /// in the merge on both ends, so the result is the same whoever computes it.
+    fn merge_state(&mut self, from: &str, theirs: String) -> Vec<FallbackAction> {
+        let leads = self.leads(from);
+        let Some(link) = self.links.get_mut(from) else { return Vec::new() };
+        if leads {
+            let merged = DiffEngine::merge3(&link.agreed, &self.content, &theirs).merged;
+            link.agreed = merged.clone();
+            self.content = merged.clone();
+            let frame = PeerFrame::Merged { from: self.peer_id.clone(), doc_id: self.doc_id.clone(), content: merged };
+            let mut actions = vec![FallbackAction::Send { peer_id: from.to_string(), frame }];
+            actions.extend(self.forward(from));
+            return actions;
+        }
+        if link.sent.is_some() {
+            return Vec::new(); // The leader's answer to our state will include this
+        }
+        let merged = DiffEngine::merge3(&link.agreed, &theirs, &self.content).merged;
+        if merged == theirs {
+            link.agreed = theirs;
+            self.content = merged;
+            self.forward(from)
+        } else {
+            self.content = merged;
+            let mut actions: Vec<FallbackAction> = self.send_state(from).into_iter().collect();
+            actions.extend(self.forward(from));
+            actions
+        }
+    }
+
+    /// The leader's merge of the state we sent. Edits made since are merged on top.
+    fn merged(&mut self, from: &str, merged: String) -> Vec<FallbackAction> {
+        let Some(link) = self.links.get_mut(from) else { return Vec::new() };
+        let base = link.sent.take().unwrap_or_else(|| link.agreed.clone());
+        self.content = DiffEngine::merge3(&base, &merged, &self.content).merged;
+        link.agreed = merged;
+        let mut actions: Vec<FallbackAction> = if self.content != self.links[from].agreed { self.send_state(from).into_iter().collect() } else { Vec::new() };
+        actions.extend(self.forward(from));
+        actions
+    }
+
+    /// Our state for the peers other than `from`, when a merge changed it
+    fn forward(&mut self, from: &str) -> Vec<FallbackAction> {
+        let others: Vec<String> = self.links.iter().filter(|(peer_id, link)| peer_id.as_str() != from && link.agreed != self.content).map(|(peer_id, _)| peer_id.clone()).collect();
+        others.iter().filter_map(|peer_id| self.send_state(peer_id)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "one\ntwo\nthree\n";
+
+    /// Two clients that lost the server at `start` and have gone peer-to-peer
+    fn offline_pair(base_a: &str, base_b: &str, start: Instant) -> (FallbackController, FallbackController) {
+        let mut clients = [FallbackController::new("a", "pad.md", base_a), FallbackController::new("b", "pad.md", base_b)];
+        for (port, client) in clients.iter_mut().enumerate() {
+            client.server_lost(start);
+            assert!(client.tick(start + Duration::from_secs(1)).is_empty());
+            assert_eq!(client.tick(start + OFFLINE_AFTER), vec![FallbackAction::Listen]);
+            client.listening(SocketAddr::from(([127, 0, 0, 1], 4000 + port as u16)));
+        }
+        let [a, b] = clients;
+        (a, b)
+    }
+
+    /// Connects the pair and delivers frames between them until they go quiet
+    fn connect(a: &mut FallbackController, b: &mut FallbackController) {
+        let announcement = b.listening(SocketAddr::from(([127, 0, 0, 1], 4001)));
+        assert_eq!(a.discovered(&announcement), vec![FallbackAction::Connect(announcement.addr)]);
+        let announcement = a.listening(SocketAddr::from(([127, 0, 0, 1], 4000)));
+        assert_eq!(b.discovered(&announcement), vec![FallbackAction::Announce]);
+        let (mut to_a, mut to_b) = (b.receive(a.hello().unwrap()), a.receive(b.hello().unwrap()));
+        deliver(a, b, &mut to_a, &mut to_b);
+    }
+
+    /// Delivers `Send` actions, the ones `a` and `b` return in `to_b` and `to_a`, until none are left
+    fn deliver(a: &mut FallbackController, b: &mut FallbackController, to_a: &mut Vec<FallbackAction>, to_b: &mut Vec<FallbackAction>) {
+        for _ in 0..20 {
+            if to_a.is_empty() && to_b.is_empty() {
+                return;
+            }
+            let (for_a, for_b) = (std::mem::take(to_a), std::mem::take(to_b));
+            for action in for_a {
+                if let FallbackAction::Send { peer_id, frame } = action {
+                    assert_eq!(peer_id, "a");
+                    to_b.extend(a.receive(frame));
+                }
+            }
+            for action in for_b {
+                if let FallbackAction::Send { peer_id, frame } = action {
+                    assert_eq!(peer_id, "b");
+                    to_a.extend(b.receive(frame));
+                }
+            }
+        }
+        panic!("Peers never settled");
+    }
+
+    #[test]
+    fn test_peers_converge_without_the_server() {
+        let start = Instant::now();
+        let (mut a, mut b) = offline_pair(BASE, BASE, start);
+        connect(&mut a, &mut b);
+        assert_eq!((a.peers(), b.peers()), (vec!["b".to_string()], vec!["a".to_string()]));
+
+        // Concurrent edits cross on the wire
+        let mut to_b = a.local_edit("ONE\ntwo\nthree\n");
+        let mut to_a = b.local_edit("one\ntwo\nthree\nfour\n");
+        deliver(&mut a, &mut b, &mut to_a, &mut to_b);
+        assert_eq!(a.content(), "ONE\ntwo\nthree\nfour\n");
+        assert_eq!(b.content(), a.content());
+
+        // One after the other, both ways
+        let mut to_a = b.local_edit("ONE\n2\nthree\nfour\n");
+        deliver(&mut a, &mut b, &mut to_a, &mut Vec::new());
+        let mut to_b = a.local_edit("ONE\n2\n3\nfour\n");
+        deliver(&mut a, &mut b, &mut Vec::new(), &mut to_b);
+        assert_eq!(a.content(), "ONE\n2\n3\nfour\n");
+        assert_eq!(b.content(), a.content());
+    }
+
+    #[test]
+    fn test_server_return_resyncs_merged_content() {
+        let (mut a, mut b) = offline_pair(BASE, BASE, Instant::now());
+        connect(&mut a, &mut b);
+        let mut to_b = a.local_edit("ONE\ntwo\nthree\n");
+        let mut to_a = b.local_edit("one\ntwo\nTHREE\n");
+        deliver(&mut a, &mut b, &mut to_a, &mut to_b);
+
+        let actions = a.server_restored();
+        assert_eq!(actions, vec![FallbackAction::Resync("ONE\ntwo\nTHREE\n".to_string()), FallbackAction::Disconnect(vec!["b".to_string()])]);
+        assert_eq!(a.mode(), SyncMode::Resyncing);
+        assert!(a.peers().is_empty());
+        assert!(a.local_edit("ONE\ntwo\nTHREE\n!").is_empty()); // Edits go to the server again
+        a.resynced("ONE\ntwo\nTHREE\n!");
+        assert_eq!(a.mode(), SyncMode::Server);
+        assert!(a.hello().is_none());
+    }
+
+    #[test]
+    fn test_mode_transitions_emit_events() {
+        let start = Instant::now();
+        let mut client = FallbackController::new("a", "pad.md", BASE).with_offline_after(Duration::from_secs(5));
+        client.server_lost(start);
+        client.server_lost(start + Duration::from_secs(1));
+        assert_eq!(client.take_events(), vec![ModeEvent::ServerUnreachable]);
+        client.server_restored(); // Back before falling back
+        assert_eq!(client.take_events(), vec![ModeEvent::Online]);
+        assert!(client.tick(start + Duration::from_secs(10)).is_empty());
+
+        let (mut a, mut b) = offline_pair(BASE, BASE, start);
+        assert_eq!(a.take_events(), vec![ModeEvent::ServerUnreachable, ModeEvent::PeerToPeer]);
+        connect(&mut a, &mut b);
+        assert_eq!(a.take_events(), vec![ModeEvent::PeerConnected { peer_id: "b".to_string(), diverged: false }]);
+        a.server_restored();
+        a.resynced(BASE);
+        assert_eq!(a.take_events(), vec![ModeEvent::Resyncing, ModeEvent::Online]);
+        let json = serde_json::to_value(ModeEvent::PeerConnected { peer_id: "b".to_string(), diverged: true }).unwrap();
+        assert_eq!(json, serde_json::json!({ "type": "peer_connected", "peer_id": "b", "diverged": true }));
+    }
+
+    #[test]
+    fn test_diverged_peers_merge_instead_of_overwriting() {
+        // b saw a later server revision than a before the server went away
+        let (mut a, mut b) = offline_pair(BASE, "one\ntwo\nthree\nfour\n", Instant::now());
+        a.local_edit("ONE\ntwo\nthree\n");
+        connect(&mut a, &mut b);
+        assert_eq!(b.take_events().last(), Some(&ModeEvent::PeerConnected { peer_id: "a".to_string(), diverged: true }));
+        assert_eq!(a.content(), "ONE\ntwo\nthree\nfour\n");
+        assert_eq!(b.content(), a.content());
+
+        // Other documents are left alone
+        let mut other = FallbackController::new("c", "other.md", BASE);
+        other.server_lost(Instant::now());
+        other.tick(Instant::now() + OFFLINE_AFTER);
+        let announcement = other.listening(SocketAddr::from(([127, 0, 0, 1], 4002)));
+        assert!(a.discovered(&announcement).is_empty());
+        assert!(a.receive(other.hello().unwrap()).is_empty());
+    }
+}
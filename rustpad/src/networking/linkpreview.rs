@@ -0,0 +1,500 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use warp::hyper::body::HttpBody;
+use warp::hyper::header::{CONTENT_TYPE, HOST, LOCATION, USER_AGENT};
+use warp::hyper::{self, Body, Request, Uri};
+
+use crate::rate_limit::RateLimiter;
+use crate::storage::Storage;
+
+/// Longest URL looked at, and most links previewed per message
+pub const MAX_URL_LEN: usize = 2048;
+pub const MAX_LINKS_PER_MESSAGE: usize = 3;
+
+/// Limits on fetching a page for its preview, unless configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024;
+pub const MAX_REDIRECTS: usize = 3;
+
+/// Fetches per domain every `DOMAIN_RATE_WINDOW`
+pub const DOMAIN_RATE_LIMIT: usize = 5;
+pub const DOMAIN_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a preview is served from the cache, unless configured otherwise
+pub const DEFAULT_CACHE_TTL_SECS: i64 = 6 * 60 * 60;
+
+/// Longest title and description kept
+const MAX_TITLE_LEN: usize = 200;
+const MAX_DESCRIPTION_LEN: usize = 500;
+
+/// What a link leads to, shown under the message it was posted in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LinkPreview {
+    pub url: String, // As posted, before any redirects
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub favicon: Option<String>, // Absolute URL
+}
+
+/// A preview as cached, with when it was fetched
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedPreview {
+    fetched_at: DateTime<Utc>,
+    preview: LinkPreview,
+}
+
+/// The http and https links in `text`, at most `MAX_LINKS_PER_MESSAGE` and each once. Anything
+/// that doesn't parse as an absolute http or https URL with a host is left alone.
+pub fn detect_urls(text: &str) -> Vec<Uri> {
+    let mut links: Vec<Uri> = Vec::new();
+    for word in text.split_whitespace() {
+        let word = word.trim_start_matches(['(', '<', '[', '"', '\'']);
+        let word = word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '>', ']', '"', '\'']);
+        let Some(uri) = parse_link(word) else { continue };
+        if !links.contains(&uri) {
+            links.push(uri);
+        }
+        if links.len() == MAX_LINKS_PER_MESSAGE {
+            break;
+        }
+    }
+    links
+}
+
+/// `text` as an http or https URL with a host and no credentials
+pub fn parse_link(text: &str) -> Option<Uri> {
+    let lowercase = text.get(..8).unwrap_or(text).to_ascii_lowercase();
+    if text.len() > MAX_URL_LEN || !(lowercase.starts_with("http://") || lowercase.starts_with("https://")) {
+        return None;
+    }
+    let uri: Uri = text.parse().ok()?;
+    let authority = uri.authority()?;
+    let scheme_allowed = matches!(uri.scheme_str(), Some("http" | "https"));
+    (scheme_allowed && !authority.as_str().contains('@') && !authority.host().is_empty()).then_some(uri)
+}
+
+/// `reference`, as found in a page at `base` or in a redirect from it, as an absolute link
+fn resolve(base: &Uri, reference: &str) -> Option<Uri> {
+    let reference = reference.trim();
+    let scheme = base.scheme_str()?;
+    let authority = base.authority()?.as_str();
+    let absolute = if reference.contains("://") {
+        reference.to_string()
+    } else if let Some(rest) = reference.strip_prefix("//") {
+        format!("{}://{}", scheme, rest)
+    } else if reference.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, reference)
+    } else {
+        let directory = base.path().rsplit_once('/').map_or("", |(directory, _)| directory);
+        format!("{}://{}{}/{}", scheme, authority, directory, reference)
+    };
+    parse_link(&absolute)
+}
+
+/// Whether `ip` is on the public internet: not loopback, private, link-local, shared, multicast
+/// or otherwise reserved, so fetching it can't reach the server's own network
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_v4(mapped),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    let shared = a == 100 && (64..128).contains(&b); // Carrier-grade NAT, 100.64.0.0/10
+    let reserved = a == 0 || a >= 240 || (a == 192 && b == 0) || (a == 198 && (18..20).contains(&b));
+    !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_multicast() || ip.is_broadcast() || ip.is_documentation() || shared || reserved)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    let unique_local = first & 0xfe00 == 0xfc00; // fc00::/7
+    let link_local = first & 0xffc0 == 0xfe80; // fe80::/10
+    let documentation = first == 0x2001 && ip.segments()[1] == 0x0db8;
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || link_local || documentation)
+}
+
+/// The address to connect to for `url`: the one configured for its host in `hosts`, which is
+/// trusted even when it isn't public, or else the first it resolves to when every one of them is
+/// public. Anything else the server sends requests to on behalf of users goes through this too.
+pub async fn public_address(url: &Uri, hosts: &HashMap<String, SocketAddr>) -> Result<SocketAddr, String> {
+    let host = url.host().ok_or("No host")?.to_ascii_lowercase();
+    if let Some(addr) = hosts.get(&host) {
+        return Ok(*addr);
+    }
+    let port = url.port_u16().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host.as_str(), port)).await.map_err(|e| format!("Failed to resolve {}: {}", host, e))?.collect(),
+    };
+    if let Some(refused) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(format!("{} resolves to {}, which isn't public", host, refused.ip()));
+    }
+    addrs.first().copied().ok_or_else(|| format!("{} has no addresses", host))
+}
+
+/// Fetches previews of links posted in chat, guarding the server's network: only public
+/// addresses are connected to, checked after resolving every host including those redirected
+/// to, and the address checked is the one connected to. Fetches are capped in time and size and
+/// rate limited per domain; previews are cached in `Storage` for a while. Only plain http pages
+/// are fetched, as there is no TLS client yet; https links are validated but get no preview.
+#[derive(Clone)]
+pub struct LinkPreviewer {
+    storage: Arc<dyn Storage + Send + Sync>, // Cached previews
+    domains: RateLimiter,
+    timeout: Duration,
+    max_bytes: usize,
+    cache_ttl: ChronoDuration,
+    hosts: HashMap<String, SocketAddr>, // Resolved without DNS and trusted even when not public
+}
+
+impl LinkPreviewer {
+    /// Creates a previewer caching previews in `storage`, with the default limits
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            storage,
+            domains: RateLimiter::new(DOMAIN_RATE_LIMIT, DOMAIN_RATE_WINDOW),
+            timeout: DEFAULT_TIMEOUT,
+            max_bytes: DEFAULT_MAX_BYTES,
+            cache_ttl: ChronoDuration::seconds(DEFAULT_CACHE_TTL_SECS),
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Gives up on a page, redirects included, after `timeout`
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Reads at most `max_bytes` of a page; its metadata is expected near the top
+    pub fn with_max_bytes(self, max_bytes: usize) -> Self {
+        Self { max_bytes, ..self }
+    }
+
+    /// Serves cached previews for `cache_ttl` before fetching them again
+    pub fn with_cache_ttl(self, cache_ttl: Duration) -> Self {
+        Self { cache_ttl: ChronoDuration::from_std(cache_ttl).unwrap_or(ChronoDuration::MAX), ..self }
+    }
+
+    /// Limits fetches per domain with `domains` instead of the default limit
+    pub fn with_domain_limit(self, domains: RateLimiter) -> Self {
+        Self { domains, ..self }
+    }
+
+    /// Connects to `addr` for links to `host` instead of resolving it, even though the address
+    /// isn't public: for internal sites previews are wanted for, and for tests
+    pub fn with_host(mut self, host: &str, addr: SocketAddr) -> Self {
+        self.hosts.insert(host.to_ascii_lowercase(), addr);
+        self
+    }
+
+    /// The preview of `url`, from the cache when it was fetched within the TTL. Failed fetches,
+    /// refused addresses and pages without a title or description have none.
+    pub async fn preview(&self, url: &Uri, now: DateTime<Utc>) -> Option<LinkPreview> {
+        let identifier = cache_identifier(url);
+        if let Some(cached) = self.storage.load(&identifier).ok().and_then(|cached| serde_json::from_str::<CachedPreview>(&cached).ok()) {
+            if now - cached.fetched_at < self.cache_ttl {
+                return Some(cached.preview);
+            }
+        }
+
+        let host = url.host().unwrap_or_default().to_ascii_lowercase();
+        if let Err(e) = self.domains.check(&host, Instant::now()) {
+            println!("Not previewing {}: {}", url, e);
+            return None;
+        }
+        let preview = match tokio::time::timeout(self.timeout, self.fetch(url)).await {
+            Ok(Ok(preview)) => preview,
+            Ok(Err(e)) => {
+                println!("No preview of {}: {}", url, e);
+                return None;
+            }
+            Err(_) => {
+                println!("No preview of {}: timed out", url);
+                return None;
+            }
+        };
+
+        let cached = CachedPreview { fetched_at: now, preview: preview.clone() };
+        if let Err(e) = self.storage.save(&identifier, &serde_json::to_string(&cached).unwrap()) {
+            eprintln!("Failed to cache the preview of {}: {}", url, e);
+        }
+        Some(preview)
+    }
+
+    /// Fetches `url`, following redirects to public addresses, and reads its preview
+    async fn fetch(&self, url: &Uri) -> Result<LinkPreview, String> {
+        let mut current = url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let addr = self.address(&current).await?;
+            let response = self.get(&current, addr).await?;
+            if response.status().is_redirection() {
+                let location = response.headers().get(LOCATION).and_then(|location| location.to_str().ok()).ok_or("Redirect without a location")?;
+                current = resolve(&current, location).ok_or_else(|| format!("Redirect to an invalid link {:?}", location))?;
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(format!("The page answered {}", response.status()));
+            }
+            let is_html = response.headers().get(CONTENT_TYPE).and_then(|kind| kind.to_str().ok()).is_some_and(|kind| kind.to_ascii_lowercase().starts_with("text/html"));
+            if !is_html {
+                return Err("Not an HTML page".to_string());
+            }
+            let page = self.read_capped(response.into_body()).await?;
+            return read_preview(url, &current, &page).ok_or_else(|| "The page has no title or description".to_string());
+        }
+        Err(format!("More than {} redirects", MAX_REDIRECTS))
+    }
+
+    /// The address to connect to for `url`: its configured host, or the first it resolves to
+    /// when every one of them is public
+    async fn address(&self, url: &Uri) -> Result<SocketAddr, String> {
+        if url.scheme_str() != Some("http") {
+            return Err("Only http pages are fetched".to_string());
+        }
+        public_address(url, &self.hosts).await
+    }
+
+    /// Sends a GET for `url` to `addr`, the address checked for its host
+    async fn get(&self, url: &Uri, addr: SocketAddr) -> Result<hyper::Response<Body>, String> {
+        let stream = tokio::net::TcpStream::connect(addr).await.map_err(|e| format!("Failed to connect to {}: {}", addr, e))?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.map_err(|e| e.to_string())?;
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+        let path = url.path_and_query().map_or("/", |path| path.as_str());
+        let request = Request::get(path)
+            .header(HOST, url.authority().map_or("", |authority| authority.as_str()))
+            .header(USER_AGENT, "RustPad link preview")
+            .body(Body::empty())
+            .map_err(|e| e.to_string())?;
+        sender.send_request(request).await.map_err(|e| e.to_string())
+    }
+
+    /// The start of `body`, at most `max_bytes` of it; the rest is never read
+    async fn read_capped(&self, mut body: Body) -> Result<String, String> {
+        let mut page = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| e.to_string())?;
+            page.extend_from_slice(&chunk[..chunk.len().min(self.max_bytes - page.len())]);
+            if page.len() >= self.max_bytes {
+                break;
+            }
+        }
+        Ok(String::from_utf8_lossy(&page).into_owned())
+    }
+}
+
+/// Storage identifier of the cached preview of `url`
+fn cache_identifier(url: &Uri) -> String {
+    let hash = digest::digest(&digest::SHA256, url.to_string().as_bytes());
+    let hex: String = hash.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("link-preview-{}", hex)
+}
+
+/// The preview of `url` from `page`, found at `location` after any redirects: the Open Graph
+/// title and description, or the `<title>` and description meta tag, and the icon
+fn read_preview(url: &Uri, location: &Uri, page: &str) -> Option<LinkPreview> {
+    let mut meta = HashMap::new(); // `property` or `name`, lowercase, to `content`
+    let mut icon = None;
+    for tag in tags(page, "meta") {
+        if let (Some(key), Some(content)) = (attribute(tag, "property").or_else(|| attribute(tag, "name")), attribute(tag, "content")) {
+            meta.entry(key.to_ascii_lowercase()).or_insert(content);
+        }
+    }
+    for tag in tags(page, "link") {
+        let is_icon = attribute(tag, "rel").is_some_and(|rel| rel.to_ascii_lowercase().split_whitespace().any(|rel| rel == "icon"));
+        if is_icon && icon.is_none() {
+            icon = attribute(tag, "href");
+        }
+    }
+
+    let title = meta.remove("og:title").or_else(|| title_element(page)).map(|title| clean(&title, MAX_TITLE_LEN));
+    let description = meta.remove("og:description").or_else(|| meta.remove("description")).map(|description| clean(&description, MAX_DESCRIPTION_LEN));
+    let title = title.filter(|title| !title.is_empty());
+    let description = description.filter(|description| !description.is_empty());
+    if title.is_none() && description.is_none() {
+        return None;
+    }
+    let favicon = resolve(location, icon.as_deref().unwrap_or("/favicon.ico")).map(|favicon| favicon.to_string());
+    Some(LinkPreview { url: url.to_string(), title, description, favicon })
+}
+
+/// The tags named `name` in `page`, each from its name to before its `>`
+fn tags<'a>(page: &'a str, name: &str) -> Vec<&'a str> {
+    let lowercase = page.to_ascii_lowercase(); // Same byte offsets as `page`
+    let opening = format!("<{}", name);
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(start) = lowercase[from..].find(&opening).map(|at| from + at + opening.len()) {
+        let end = lowercase[start..].find('>').map_or(page.len(), |at| start + at);
+        if page[start..].starts_with(|c: char| c.is_ascii_whitespace() || c == '/') {
+            found.push(&page[start..end]);
+        }
+        from = end;
+    }
+    found
+}
+
+/// The value of `name="..."` (or single-quoted, or bare) in `tag`, with entities decoded
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lowercase = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(at) = lowercase[from..].find(name).map(|at| from + at) {
+        from = at + name.len();
+        let preceded = lowercase[..at].ends_with(|c: char| c.is_ascii_whitespace());
+        let rest = tag[from..].trim_start();
+        let Some(value) = rest.strip_prefix('=').map(str::trim_start) else { continue };
+        if !preceded {
+            continue;
+        }
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or_default(),
+            _ => value.split(|c: char| c.is_ascii_whitespace() || c == '/').next().unwrap_or_default(),
+        };
+        return Some(decode_entities(value));
+    }
+    None
+}
+
+/// The text of the page's `<title>` element
+fn title_element(page: &str) -> Option<String> {
+    let lowercase = page.to_ascii_lowercase();
+    let start = lowercase.find("<title")?;
+    let start = start + lowercase[start..].find('>')? + 1;
+    let end = start + lowercase[start..].find("</title")?;
+    Some(decode_entities(&page[start..end]))
+}
+
+/// The common HTML entities in `text`, decoded
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&#x27;", "'").replace("&nbsp;", " ").replace("&amp;", "&")
+}
+
+/// `text` on one line without control characters, cut to `max_len` bytes
+fn clean(text: &str, max_len: usize) -> String {
+    let mut cleaned = String::new();
+    for word in text.split_whitespace() {
+        let word: String = word.chars().filter(|c| !c.is_control()).collect();
+        let separator = usize::from(!cleaned.is_empty());
+        if cleaned.len() + separator + word.len() > max_len {
+            let room = max_len.saturating_sub(cleaned.len() + separator);
+            let cut = (0..=room.min(word.len())).rev().find(|at| word.is_char_boundary(*at)).unwrap_or(0);
+            if cut > 0 {
+                cleaned.push_str(&" "[..separator]);
+                cleaned.push_str(&word[..cut]);
+            }
+            break;
+        }
+        cleaned.push_str(&" "[..separator]);
+        cleaned.push_str(&word);
+    }
+    cleaned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use warp::Filter;
+
+    const PAGE: &str = r#"<html><head><TITLE>Fallback</TITLE>
+        <meta property="og:title" content="Rust &amp; friends">
+        <meta name='description' content='All about   crabs'>
+        <link rel="shortcut icon" href="/static/crab.png"></head><body>Hello</body></html>"#;
+
+    /// A stub site on a loopback port: `/` is `PAGE`, `/away` redirects to `location`, `/big`
+    /// is a page longer than any cap, `/slow` never answers. Counts the requests it gets.
+    fn stub_site(location: String) -> (SocketAddr, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        let html = |body: String| warp::reply::with_header(body, "content-type", "text/html; charset=utf-8");
+        let page = warp::path::end().map(move || html(PAGE.to_string()));
+        let away = warp::path("away").map(move || warp::redirect::found(location.parse::<Uri>().unwrap()));
+        let big = warp::path("big").map(move || html(format!("<title>{}</title>", "x".repeat(10_000))));
+        let slow = warp::path("slow").and_then(|| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok::<_, warp::Rejection>("late")
+        });
+        let count = warp::any().map(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        let routes = count.untuple_one().and(page.or(away).or(big).or(slow));
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, hits)
+    }
+
+    fn link(text: &str) -> Uri {
+        parse_link(text).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_preview_of_a_stub_page_is_cached() {
+        let (addr, hits) = stub_site("/".to_string());
+        let previewer = LinkPreviewer::new(Arc::new(MemoryStorage::default())).with_host("crabs.test", addr);
+        let preview = previewer.preview(&link("http://crabs.test/"), Utc::now()).await.unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Rust & friends"));
+        assert_eq!(preview.description.as_deref(), Some("All about crabs"));
+        assert_eq!(preview.favicon.as_deref(), Some("http://crabs.test/static/crab.png"));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // Served from the cache until the TTL runs out
+        assert_eq!(previewer.preview(&link("http://crabs.test/"), Utc::now()).await, Some(preview));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        previewer.preview(&link("http://crabs.test/"), Utc::now() + ChronoDuration::days(1)).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_private_addresses_and_redirects_to_them_are_refused() {
+        let (internal, internal_hits) = stub_site("/".to_string());
+        let (addr, _) = stub_site(format!("http://{}/", internal));
+        let previewer = LinkPreviewer::new(Arc::new(MemoryStorage::default())).with_host("crabs.test", addr);
+        for url in ["http://127.0.0.1/", "http://10.1.2.3/", "http://[::1]/", "http://169.254.169.254/latest/meta-data", "http://localhost/", "http://crabs.test/away"] {
+            assert_eq!(previewer.preview(&link(url), Utc::now()).await, None, "{}", url);
+        }
+        assert_eq!(internal_hits.load(Ordering::SeqCst), 0);
+
+        assert!(!is_public("192.168.1.1".parse().unwrap()) && !is_public("100.64.0.1".parse().unwrap()));
+        assert!(!is_public("::ffff:127.0.0.1".parse().unwrap()) && !is_public("fd00::1".parse().unwrap()));
+        assert!(is_public("93.184.216.34".parse().unwrap()) && is_public("2606:4700::1111".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_size_and_time_caps() {
+        let (addr, _) = stub_site("/".to_string());
+        let previewer = LinkPreviewer::new(Arc::new(MemoryStorage::default()))
+            .with_host("crabs.test", addr)
+            .with_max_bytes(1000)
+            .with_timeout(Duration::from_millis(200));
+        let big = previewer.preview(&link("http://crabs.test/big"), Utc::now()).await;
+        assert_eq!(big, None); // The title never ends within the cap
+
+        let started = Instant::now();
+        assert_eq!(previewer.preview(&link("http://crabs.test/slow"), Utc::now()).await, None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_url_detection() {
+        let found = detect_urls("see (http://a.test/x?y=1), <https://b.test>, ftp://c.test and http://a.test/x?y=1.");
+        assert_eq!(found, vec![link("http://a.test/x?y=1"), link("https://b.test")]);
+        for malformed in ["http://", "http://user:pw@a.test/", "javascript:alert(1)", "http//a.test", "http://[::1/"] {
+            assert!(detect_urls(malformed).is_empty(), "{}", malformed);
+        }
+        assert_eq!(detect_urls("http://a.test/1 http://a.test/2 http://a.test/3 http://a.test/4").len(), MAX_LINKS_PER_MESSAGE);
+        assert_eq!(resolve(&link("http://a.test/docs/page"), "icon.png").unwrap().to_string(), "http://a.test/docs/icon.png");
+        assert_eq!(resolve(&link("http://a.test/docs/page"), "//cdn.test/i.png").unwrap().to_string(), "http://cdn.test/i.png");
+    }
+}
@@ -1,15 +1,67 @@
 pub mod websocket;
+pub mod sync;
 pub mod peer_sync;
 pub mod protocol;
+pub mod optimistic;
+pub mod revision_log;
+pub mod chat_sync;
+pub mod chat_delivery;
+pub mod linkpreview;
+pub mod document_comments;
+pub mod moderation;
+pub mod read_receipts;
+pub mod task_sync;
+pub mod room_host;
+pub mod suggestions;
+pub mod structured_sync;
+pub mod connection_manager;
+pub mod discovery;
+pub mod fallback;
+pub mod supervisor;
+pub mod cursors;
 
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use websocket::WebSocketClient;
 use peer_sync::PeerSync;
+use optimistic::{OfflineJournal, OptimisticBuffer};
+use protocol::{PasteConfirmMessage, PasteDecisionMessage, ProtocolMessage, SyncMessage};
+use connection_manager::ConnectionManager;
+use cursors::CursorCoalescer;
+use discovery::Discovery;
+use fallback::{Announcement, FallbackAction, FallbackController, ModeEvent, PeerFrame, SyncMode};
+use crate::editor::diff_engine::{DiffEngine, DiffOperation};
+use crate::editor::state::EditorState;
+
+/// Address the peer-to-peer listener binds to; the system picks the port
+const PEER_LISTEN_ADDR: ([u8; 4], u16) = ([0, 0, 0, 0], 0);
+
+/// The peer-to-peer fallback of a `Networking` controller, and the connections it runs on
+struct PeerFallback {
+    controller: FallbackController,
+    connections: ConnectionManager,
+    discovery: Option<Discovery>, // A signaling server apart from the main server, if any
+    frames: mpsc::UnboundedReceiver<(SocketAddr, String)>, // From the peer connections
+    announcements: (mpsc::UnboundedSender<Announcement>, mpsc::UnboundedReceiver<Announcement>),
+    peer_addrs: HashMap<String, SocketAddr>, // Connection of each peer, by peer id
+    greeted: HashSet<SocketAddr>,            // Connections we sent our `Hello` on
+    announcement: Option<Announcement>,
+    tasks: Vec<JoinHandle<()>>, // The listener, LAN discovery and connections, ended when the server is back
+}
 
 /// `Networking` struct acts as the central controller for managing the peer-to-peer
 /// communication and WebSocket connections for collaborative editing.
 pub struct Networking {
     websocket_client: WebSocketClient,
     peer_sync: PeerSync,
+    state: EditorState,        // The document here, with the corrections from the server and peers applied
+    pending: OptimisticBuffer, // Local edits awaiting server acknowledgement
+    paste_request: Option<PasteConfirmMessage>, // A large paste the server wants confirmed
+    fallback: Option<PeerFallback>, // Editing with peers on the LAN while the server is unreachable
+    cursor: CursorCoalescer,        // Holds back cursor moves made in quick succession
 }
 
 impl Networking {
@@ -18,15 +70,40 @@ impl Networking {
         Self {
             websocket_client: WebSocketClient::new(server_url),
             peer_sync: PeerSync::new(),
+            state: EditorState::new(),
+            pending: OptimisticBuffer::new("", 0).with_client_id(&uuid::Uuid::new_v4().to_string()),
+            paste_request: None,
+            fallback: None,
+            cursor: CursorCoalescer::default(),
         }
     }
 
+    /// Keeps editing `doc_id` with peers on the LAN while the server is unreachable, also
+    /// looking for them through `signaling_url` when there is a signaling server.
+    pub fn with_fallback(mut self, doc_id: &str, signaling_url: Option<&str>) -> Self {
+        let (inbox, frames) = mpsc::unbounded_channel();
+        let peer_id = uuid::Uuid::new_v4().to_string();
+        self.fallback = Some(PeerFallback {
+            controller: FallbackController::new(&peer_id, doc_id, self.pending.acked_text()),
+            connections: ConnectionManager::new().with_inbox(inbox),
+            discovery: signaling_url.map(Discovery::new),
+            frames,
+            announcements: mpsc::unbounded_channel(),
+            peer_addrs: HashMap::new(),
+            greeted: HashSet::new(),
+            announcement: None,
+            tasks: Vec::new(),
+        });
+        self
+    }
+
     /// Starts the networking service by connecting to the WebSocket server and handling
     /// incoming messages.
     pub async fn start(&mut self) {
         // Establish WebSocket connection
         if let Err(e) = self.websocket_client.connect().await {
             eprintln!("Failed to connect to WebSocket server: {}", e);
+            self.server_unreachable().await;
             return;
         }
 
@@ -37,11 +114,138 @@ impl Networking {
     /// Processes incoming messages from the WebSocket connection and applies them to the peer sync.
     async fn process_incoming_messages(&mut self) {
         while let Some(message) = self.websocket_client.receive_message().await {
-            // Apply the received message to the peer synchronization logic
-            self.peer_sync.handle_incoming_message(message).await;
+            let patch = match ProtocolMessage::from_json(&message) {
+                Ok(ProtocolMessage::Ack(ack)) => self.pending.handle_ack(&ack),
+                Ok(ProtocolMessage::Reject(reject)) => {
+                    if self.paste_request.as_ref().is_some_and(|request| request.seq == reject.seq) {
+                        self.paste_request = None; // Timed out before it was answered
+                    }
+                    self.pending.handle_reject(&reject)
+                }
+                Ok(ProtocolMessage::Nack(nack)) => self.pending.handle_nack(&nack).map(|_| Vec::new()),
+                Ok(ProtocolMessage::RemoteDelta(remote)) => Ok(self.pending.handle_remote(&remote)),
+                Ok(ProtocolMessage::PasteConfirm(request)) => {
+                    self.paste_request = Some(request);
+                    continue;
+                }
+                _ => {
+                    // Apply the received message to the peer synchronization logic
+                    if let Err(e) = self.peer_sync.handle_incoming_message(&message, &mut self.state) {
+                        eprintln!("Failed to apply message: {}", e);
+                    }
+                    continue;
+                }
+            };
+
+            match patch {
+                Ok(operations) => self.apply_local_patch(operations).await,
+                Err(e) => {
+                    // Acknowledgements are out of step with our buffer; fall back to the server state
+                    eprintln!("Failed to reconcile pending edits: {}", e);
+                    let operations = self.pending.rollback();
+                    self.apply_local_patch(operations).await;
+                }
+            }
+            self.flush_pending().await;
+            self.track_server_state();
+        }
+    }
+
+    /// Applies a local edit immediately and queues it for the server, or hands it to the peers
+    /// while falling back to them.
+    pub async fn submit_local_edit(&mut self, new_text: &str) {
+        if let Some(fallback) = self.fallback.as_mut().filter(|fallback| fallback.controller.mode() == SyncMode::PeerToPeer) {
+            let actions = fallback.controller.local_edit(new_text);
+            self.apply_local_text(new_text);
+            self.perform(actions).await;
+            return;
+        }
+        if self.pending.local_edit(new_text).is_some() {
+            self.apply_local_text(new_text);
+            self.flush_pending().await;
+        }
+    }
+
+    /// Applies a paste immediately and queues it for the server, which may ask to confirm it
+    /// first if it is large; see `paste_request`.
+    pub async fn submit_local_paste(&mut self, new_text: &str) {
+        if self.pending.local_paste(new_text).is_some() {
+            self.apply_local_text(new_text);
+            self.flush_pending().await;
+        }
+    }
+
+    /// The large paste the server is waiting for the user to confirm, with its size and effect.
+    pub fn paste_request(&self) -> Option<&PasteConfirmMessage> {
+        self.paste_request.as_ref()
+    }
+
+    /// Answers the server's paste confirmation request. A cancelled paste comes back as a
+    /// rejection and is undone locally.
+    pub async fn decide_paste(&mut self, confirm: bool) {
+        let Some(request) = self.paste_request.take() else { return };
+        match ProtocolMessage::PasteDecision(PasteDecisionMessage { seq: request.seq, confirm }).to_json() {
+            Ok(json) => self.broadcast_change(&json).await,
+            Err(e) => eprintln!("Failed to serialize paste decision: {}", e),
         }
     }
 
+    /// Sends the next pending edit to the server if none is awaiting acknowledgement.
+    async fn flush_pending(&mut self) {
+        if let Some(delta) = self.pending.take_outgoing() {
+            match ProtocolMessage::Delta(delta).to_json() {
+                Ok(json) => self.broadcast_change(&json).await,
+                Err(e) => eprintln!("Failed to serialize pending edit: {}", e),
+            }
+        }
+    }
+
+    /// Brings `state` to `new_text`, edited here. The peer sync takes it as its base, since the
+    /// corrections that follow are made against the text with the edit in it.
+    fn apply_local_text(&mut self, new_text: &str) {
+        for operation in DiffEngine::diff(&self.state.get_text(), new_text) {
+            let (start, end, text) = DiffEngine::to_range(operation);
+            self.state.apply_remote(start, end, &text);
+        }
+        self.peer_sync.set_base(&self.state);
+    }
+
+    /// Hands a correction to the local document over to the peer sync as a regular sync message.
+    async fn apply_local_patch(&mut self, operations: Vec<DiffOperation>) {
+        if operations.is_empty() {
+            return;
+        }
+        if let Ok(json) = ProtocolMessage::Sync(SyncMessage::new(operations)).to_json() {
+            if let Err(e) = self.peer_sync.handle_incoming_message(&json, &mut self.state) {
+                eprintln!("Failed to apply correction: {}", e);
+            }
+        }
+    }
+
+    /// The document as edited here
+    pub fn state(&self) -> &EditorState {
+        &self.state
+    }
+
+    /// Number of local edits not yet acknowledged by the server, for an "unsynced changes" badge.
+    pub fn pending_count(&self) -> usize {
+        self.pending.pending_count()
+    }
+
+    /// The unacknowledged local edits, saved with the editor session when handing off to another device.
+    pub fn journal(&self) -> OfflineJournal {
+        self.pending.journal()
+    }
+
+    /// Takes over edits handed off from another device. They are sent with the next outgoing
+    /// edit and merged with whatever changed on the server meanwhile.
+    pub fn resume_journal(&mut self, journal: OfflineJournal) {
+        self.pending = OptimisticBuffer::from_journal(journal);
+        self.paste_request = None;
+        let local_text = self.pending.local_text().to_string();
+        self.apply_local_text(&local_text);
+    }
+
     /// Sends a document change to all connected peers via WebSocket.
     pub async fn broadcast_change(&mut self, change: &str) {
         if let Err(e) = self.websocket_client.send_message(change).await {
@@ -49,11 +253,199 @@ impl Networking {
         }
     }
 
-    /// Broadcasts cursor position to all connected peers (optional).
+    /// Broadcasts cursor position to all connected peers (optional). Moves in quick succession
+    /// are coalesced: call `flush_cursor` at `cursor_due` to send the last of them.
     pub async fn broadcast_cursor(&mut self, cursor_position: usize) {
+        if let Some(position) = self.cursor.moved("", cursor_position, Instant::now()) {
+            self.send_cursor(position).await;
+        }
+    }
+
+    /// When a held back cursor position is due to be sent with `flush_cursor`
+    pub fn cursor_due(&self) -> Option<Instant> {
+        self.cursor.next_due()
+    }
+
+    /// Sends the latest cursor position if it was held back and is now due.
+    pub async fn flush_cursor(&mut self) {
+        for (_, position) in self.cursor.due(Instant::now()) {
+            self.send_cursor(position).await;
+        }
+    }
+
+    async fn send_cursor(&mut self, cursor_position: usize) {
         let message = format!("{{\"cursor_position\": {}}}", cursor_position);
         if let Err(e) = self.websocket_client.send_message(&message).await {
             eprintln!("Failed to broadcast cursor position: {}", e);
         }
     }
+
+    /// Records that reaching the server failed. Call it on every failed reconnection attempt;
+    /// once the server has been unreachable long enough, the peers on the LAN take over.
+    pub async fn server_unreachable(&mut self) {
+        let Some(fallback) = self.fallback.as_mut() else { return };
+        fallback.controller.server_lost(Instant::now());
+        let actions = fallback.controller.tick(Instant::now());
+        self.perform(actions).await;
+    }
+
+    /// Records that the server is reachable again. What was merged with the peers goes back to
+    /// it as a local edit, and the peers are let go.
+    pub async fn server_reachable(&mut self) {
+        let Some(fallback) = self.fallback.as_mut() else { return };
+        let actions = fallback.controller.server_restored();
+        self.perform(actions).await;
+    }
+
+    /// Where edits go at the moment
+    pub fn sync_mode(&self) -> SyncMode {
+        self.fallback.as_ref().map_or(SyncMode::Server, |fallback| fallback.controller.mode())
+    }
+
+    /// Changes of `sync_mode` and of the peers since the last call, for the UI to show.
+    pub fn mode_events(&mut self) -> Vec<ModeEvent> {
+        self.fallback.as_mut().map(|fallback| fallback.controller.take_events()).unwrap_or_default()
+    }
+
+    /// Handles what peers and LAN discovery sent since the last call.
+    pub async fn process_peer_messages(&mut self) {
+        loop {
+            let Some(fallback) = self.fallback.as_mut() else { return };
+            let before = fallback.controller.content().to_string();
+            let actions = if let Ok(announcement) = fallback.announcements.1.try_recv() {
+                fallback.controller.discovered(&announcement)
+            } else if let Ok((addr, text)) = fallback.frames.try_recv() {
+                let Ok(frame) = serde_json::from_str::<PeerFrame>(&text) else { continue };
+                let mut actions = Vec::new();
+                if let PeerFrame::Hello(announcement) = &frame {
+                    fallback.peer_addrs.insert(announcement.peer_id.clone(), addr);
+                    // Greet back a peer that dialed us
+                    if fallback.greeted.insert(addr) {
+                        if let Some(hello) = fallback.controller.hello() {
+                            fallback.connections.send_to_peer(&addr, serde_json::to_string(&hello).unwrap()).await;
+                        }
+                    }
+                }
+                actions.extend(fallback.controller.receive(frame));
+                actions
+            } else {
+                return;
+            };
+            let after = fallback.controller.content().to_string();
+            if after != before {
+                self.apply_local_patch(DiffEngine::diff(&before, &after)).await;
+            }
+            self.perform(actions).await;
+        }
+    }
+
+    /// Carries out what the fallback controller asked for.
+    async fn perform(&mut self, actions: Vec<FallbackAction>) {
+        for action in actions {
+            let Some(fallback) = self.fallback.as_mut() else { return };
+            match action {
+                FallbackAction::Listen => match fallback.connections.listen(SocketAddr::from(PEER_LISTEN_ADDR)).await {
+                    Ok((addr, task)) => {
+                        fallback.tasks.push(task);
+                        fallback.announcement = Some(fallback.controller.listening(addr));
+                        match discovery::listen_on_lan(fallback.announcements.0.clone()).await {
+                            Ok(task) => fallback.tasks.push(task),
+                            Err(e) => eprintln!("Failed to listen for peers on the LAN: {}", e),
+                        }
+                        fallback.announce().await;
+                    }
+                    Err(e) => eprintln!("Failed to listen for peers: {}", e),
+                },
+                FallbackAction::Announce => fallback.announce().await,
+                FallbackAction::Connect(addr) => match fallback.connections.connect(addr).await {
+                    Ok(task) => {
+                        fallback.tasks.push(task);
+                        fallback.greeted.insert(addr);
+                        if let Some(hello) = fallback.controller.hello() {
+                            fallback.connections.send_to_peer(&addr, serde_json::to_string(&hello).unwrap()).await;
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to connect to peer {}: {}", addr, e),
+                },
+                FallbackAction::Send { peer_id, frame } => {
+                    if let Some(addr) = fallback.peer_addrs.get(&peer_id) {
+                        fallback.connections.send_to_peer(addr, serde_json::to_string(&frame).unwrap()).await;
+                    }
+                }
+                FallbackAction::Resync(content) => {
+                    if self.pending.local_edit(&content).is_some() {
+                        self.flush_pending().await;
+                    }
+                    self.track_server_state();
+                }
+                FallbackAction::Disconnect(peers) => {
+                    for peer_id in peers {
+                        if let Some(addr) = fallback.peer_addrs.remove(&peer_id) {
+                            fallback.connections.disconnect(&addr);
+                        }
+                    }
+                    for task in fallback.tasks.drain(..) {
+                        task.abort();
+                    }
+                    fallback.greeted.clear();
+                    fallback.announcement = None;
+                }
+            }
+        }
+    }
+
+    /// Keeps the fallback up to date with what the server confirmed, and ends a resync once the
+    /// server has acknowledged all of it.
+    fn track_server_state(&mut self) {
+        let Some(fallback) = self.fallback.as_mut() else { return };
+        match fallback.controller.mode() {
+            SyncMode::Server => fallback.controller.server_confirmed(self.pending.acked_text()),
+            SyncMode::Resyncing if self.pending.pending_count() == 0 => fallback.controller.resynced(self.pending.acked_text()),
+            _ => {}
+        }
+    }
+}
+
+impl PeerFallback {
+    /// Announces the listener on the LAN, and to the signaling server if there is one
+    async fn announce(&mut self) {
+        let Some(announcement) = self.announcement.clone() else { return };
+        if let Err(e) = discovery::announce_on_lan(&announcement).await {
+            eprintln!("Failed to announce on the LAN: {}", e);
+        }
+        if let Some(discovery) = &self.discovery {
+            match discovery.announce(&announcement).await {
+                Ok(peers) => {
+                    for peer in peers {
+                        let _ = self.announcements.0.send(peer);
+                    }
+                }
+                Err(e) => eprintln!("Signaling server unreachable too: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::RemoteDeltaMessage;
+
+    #[tokio::test]
+    async fn test_state_follows_local_edits_and_corrections() {
+        let mut networking = Networking::new("ws://127.0.0.1:9");
+        networking.submit_local_edit("hello").await;
+        networking.submit_local_paste("hello world").await;
+        assert_eq!(networking.state().get_text(), "hello world");
+
+        // Another client's edit comes back rebased over ours, and lands on the edited text
+        let remote = RemoteDeltaMessage { revision: 1, operations: vec![DiffOperation::Insert(0, "> ".to_string())], author: None, checkpoint_id: None };
+        let patch = networking.pending.handle_remote(&remote);
+        networking.apply_local_patch(patch).await;
+        assert_eq!(networking.state().get_text(), "> hello world");
+        assert_eq!(networking.state().get_text(), networking.pending.local_text());
+
+        networking.submit_local_edit("> hello world!").await;
+        assert_eq!(networking.state().get_text(), "> hello world!");
+    }
 }
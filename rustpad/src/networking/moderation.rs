@@ -0,0 +1,292 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::networking::chat_sync::Delivered;
+use crate::networking::room_host::RoomHost;
+use crate::storage::workspace::{WorkspaceRole, Workspaces};
+
+/// Metadata key holding a room's mutes, slow mode and moderation audit trail, as JSON
+pub const MODERATION_KEY: &str = "moderation";
+
+/// Moderation actions kept in each room's audit trail; the oldest are dropped beyond this
+pub const MAX_AUDIT_ENTRIES: usize = 1000;
+
+/// Longest mute and slow mode interval accepted
+pub const MAX_MUTE_SECS: u64 = 30 * 24 * 60 * 60;
+pub const MAX_SLOW_MODE_SECS: u64 = 60 * 60;
+
+/// A user kept from posting in a room for a while
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Mute {
+    pub until: DateTime<Utc>,
+    #[serde(default)]
+    pub restrict_editing: bool, // Also kept from editing the document
+}
+
+/// Something a moderator did, as kept in the audit trail
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ModerationAction {
+    Redact { id: u64, original: Delivered }, // The original stays here, for the owner only
+    Mute { user: String, until: DateTime<Utc>, restrict_editing: bool },
+    Unmute { user: String },
+    SlowMode { seconds: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub actor: String,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub action: ModerationAction,
+}
+
+/// The moderation state of a room, saved in its metadata so it outlives reconnects and restarts
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ModerationSettings {
+    #[serde(default)]
+    pub mutes: HashMap<String, Mute>, // By user; expired ones are dropped on the next change
+    #[serde(default)]
+    pub slow_mode_secs: u64, // Least time between chat messages of anyone but moderators; 0 for none
+    #[serde(default)]
+    pub audit: Vec<AuditEntry>, // Oldest first
+}
+
+impl ModerationSettings {
+    /// Parses settings saved under `MODERATION_KEY`
+    pub fn parse(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| format!("Invalid moderation settings: {}", e))
+    }
+
+    /// The mute `user` is under at `now`, if any
+    pub fn muted(&self, user: &str, now: DateTime<Utc>) -> Option<&Mute> {
+        self.mutes.get(user).filter(|mute| mute.until > now)
+    }
+}
+
+type LastChat = Arc<Mutex<HashMap<(String, String), DateTime<Utc>>>>; // By room and user
+
+/// Moderation of chat and annotations. The owner of a document and its moderators, as
+/// `Workspaces` resolves their roles, can redact messages, mute users for a while and slow
+/// chat down. Every action goes to the room's audit trail, which only the owner can read.
+#[derive(Clone)]
+pub struct Moderation {
+    workspaces: Workspaces,
+    host: Option<RoomHost>, // Saves the settings in each room's metadata; kept in memory without
+    settings: Arc<Mutex<HashMap<String, ModerationSettings>>>, // By room, once loaded
+    last_chat: LastChat, // When each user last sent a chat message, for slow mode
+}
+
+impl Moderation {
+    /// Creates moderation going by the document roles in `workspaces`, with settings in memory
+    pub fn new(workspaces: Workspaces) -> Self {
+        Self {
+            workspaces,
+            host: None,
+            settings: Arc::new(Mutex::new(HashMap::new())),
+            last_chat: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Keeps each room's settings in its metadata on `host`
+    pub fn with_room_host(self, host: RoomHost) -> Self {
+        Self { host: Some(host), ..self }
+    }
+
+    /// Whether `user` may moderate `room`: its owner and moderators may
+    pub fn is_moderator(&self, room: &str, user: &str) -> bool {
+        self.workspaces.doc_role(room, user) >= Some(WorkspaceRole::Moderator)
+    }
+
+    /// The settings of `room`
+    pub fn settings(&self, room: &str) -> ModerationSettings {
+        self.with_settings(room, |settings| settings.clone())
+    }
+
+    /// Refuses a chat message (`chat`) or annotation from `user` while they are muted, or a chat
+    /// message sent sooner than slow mode allows. Moderators are never slowed down.
+    pub fn check_post(&self, room: &str, user: &str, chat: bool, now: DateTime<Utc>) -> Result<(), String> {
+        let (mute, slow_mode_secs) = self.with_settings(room, |settings| (settings.muted(user, now).cloned(), settings.slow_mode_secs));
+        if let Some(mute) = mute {
+            return Err(format!("You are muted in {} until {}", room, crate::i18n::timestamp(mute.until)));
+        }
+        if !chat || slow_mode_secs == 0 || self.is_moderator(room, user) {
+            return Ok(());
+        }
+
+        let mut last_chat = self.last_chat.lock().unwrap();
+        let key = (room.to_string(), user.to_string());
+        if let Some(last) = last_chat.get(&key) {
+            let wait = *last + Duration::seconds(slow_mode_secs as i64) - now;
+            if wait > Duration::zero() {
+                return Err(format!("Slow mode is on; you can send another message in {} seconds", wait.num_seconds().max(1)));
+            }
+        }
+        last_chat.insert(key, now);
+        Ok(())
+    }
+
+    /// Mutes `user` in `room` for `seconds`, and keeps them from editing too with
+    /// `restrict_editing`. Moderators can't be muted.
+    pub fn mute(&self, room: &str, actor: &str, user: &str, seconds: u64, restrict_editing: bool, now: DateTime<Utc>) -> Result<Mute, String> {
+        if seconds == 0 || seconds > MAX_MUTE_SECS {
+            return Err(format!("Mutes last 1 to {} seconds", MAX_MUTE_SECS));
+        }
+        if self.is_moderator(room, user) {
+            return Err(format!("{} moderates {} and can't be muted", user, room));
+        }
+        let mute = Mute { until: now + Duration::seconds(seconds as i64), restrict_editing };
+        let action = ModerationAction::Mute { user: user.to_string(), until: mute.until, restrict_editing };
+        self.change(room, actor, action, now, |settings| {
+            settings.mutes.insert(user.to_string(), mute.clone());
+        })?;
+        Ok(mute)
+    }
+
+    /// Lifts the mute of `user` in `room`
+    pub fn unmute(&self, room: &str, actor: &str, user: &str, now: DateTime<Utc>) -> Result<(), String> {
+        if self.settings(room).muted(user, now).is_none() {
+            return Err(format!("{} is not muted", user));
+        }
+        self.change(room, actor, ModerationAction::Unmute { user: user.to_string() }, now, |settings| {
+            settings.mutes.remove(user);
+        })
+    }
+
+    /// Sets the least time between chat messages in `room`, turning slow mode off with 0
+    pub fn set_slow_mode(&self, room: &str, actor: &str, seconds: u64, now: DateTime<Utc>) -> Result<(), String> {
+        if seconds > MAX_SLOW_MODE_SECS {
+            return Err(format!("Slow mode is at most {} seconds", MAX_SLOW_MODE_SECS));
+        }
+        self.change(room, actor, ModerationAction::SlowMode { seconds }, now, |settings| settings.slow_mode_secs = seconds)
+    }
+
+    /// Records that `actor` redacted `original` from `room`; they must moderate it
+    pub fn record_redaction(&self, room: &str, actor: &str, original: Delivered, now: DateTime<Utc>) -> Result<(), String> {
+        self.change(room, actor, ModerationAction::Redact { id: original.id(), original }, now, |_| {})
+    }
+
+    /// Refuses `actor` unless they moderate `room`
+    pub fn check_moderator(&self, room: &str, actor: &str) -> Result<(), String> {
+        match self.is_moderator(room, actor) {
+            true => Ok(()),
+            false => Err(format!("Only the owner and moderators of {} can do that", room)),
+        }
+    }
+
+    /// The audit trail of `room`, oldest first; only the owner may read it
+    pub fn audit_log(&self, room: &str, actor: &str) -> Result<Vec<AuditEntry>, String> {
+        if self.workspaces.doc_role(room, actor) != Some(WorkspaceRole::Owner) {
+            return Err(format!("Only the owner of {} can read its moderation log", room));
+        }
+        Ok(self.settings(room).audit)
+    }
+
+    /// Makes a change on behalf of `actor`, who must moderate `room`, records it in the audit
+    /// trail and saves the settings
+    fn change(&self, room: &str, actor: &str, action: ModerationAction, now: DateTime<Utc>, change: impl FnOnce(&mut ModerationSettings)) -> Result<(), String> {
+        self.check_moderator(room, actor)?;
+        let saved = self.with_settings(room, |settings| {
+            change(settings);
+            settings.mutes.retain(|_, mute| mute.until > now);
+            settings.audit.push(AuditEntry { actor: actor.to_string(), at: now, action });
+            let excess = settings.audit.len().saturating_sub(MAX_AUDIT_ENTRIES);
+            settings.audit.drain(..excess);
+            serde_json::to_string(settings).unwrap()
+        });
+        match &self.host {
+            Some(host) => host.set_moderation(room, &saved),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `f` on the settings of `room`, loading them from its metadata the first time
+    fn with_settings<T>(&self, room: &str, f: impl FnOnce(&mut ModerationSettings) -> T) -> T {
+        let mut settings = self.settings.lock().unwrap();
+        let room_settings = settings.entry(room.to_string()).or_insert_with(|| {
+            let saved = self.host.as_ref().and_then(|host| host.moderation(room));
+            saved.and_then(|json| ModerationSettings::parse(&json).ok()).unwrap_or_default()
+        });
+        f(room_settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::room_host::MemoryLimits;
+    use crate::storage::MemoryStorage;
+
+    /// A workspace owned by "olga" holding "doc", where "mo" moderates and "ed" edits
+    fn workspaces() -> Workspaces {
+        let workspaces = Workspaces::new();
+        let id = workspaces.create("olga", "Team").unwrap().id;
+        workspaces.add_doc(&id, "olga", "doc").unwrap();
+        workspaces.set_member(&id, "olga", "mo", Some(WorkspaceRole::Moderator)).unwrap();
+        workspaces.set_member(&id, "olga", "ed", Some(WorkspaceRole::Editor)).unwrap();
+        workspaces
+    }
+
+    #[test]
+    fn test_moderators_assigned_through_workspace_roles() {
+        let workspaces = workspaces();
+        let moderation = Moderation::new(workspaces.clone());
+        assert!(moderation.is_moderator("doc", "olga") && moderation.is_moderator("doc", "mo"));
+        assert!(!moderation.is_moderator("doc", "ed"));
+        assert!(moderation.set_slow_mode("doc", "ed", 5, Utc::now()).is_err());
+
+        // A document override makes an editor a moderator of that document only
+        let id = workspaces.workspace_of("doc").unwrap();
+        workspaces.set_doc_role(&id, "olga", "doc", "ed", Some(WorkspaceRole::Moderator)).unwrap();
+        assert!(moderation.is_moderator("doc", "ed"));
+        assert!(workspaces.create_share_token(&id, "olga", "doc", WorkspaceRole::Moderator).is_err());
+    }
+
+    #[test]
+    fn test_mute_expires_and_survives_reloading() {
+        let host = RoomHost::new(Arc::new(MemoryStorage::default()), MemoryLimits::new());
+        let moderation = Moderation::new(workspaces()).with_room_host(host.clone());
+        let start = Utc::now();
+        moderation.mute("doc", "mo", "spammer", 60, false, start).unwrap();
+        assert!(moderation.mute("doc", "spammer", "ed", 60, false, start).is_err());
+        assert!(moderation.mute("doc", "olga", "mo", 60, false, start).is_err());
+
+        // Muted for chat and annotations alike, until the mute runs out
+        assert!(moderation.check_post("doc", "spammer", true, start).unwrap_err().contains("muted"));
+        assert!(moderation.check_post("doc", "spammer", false, start + Duration::seconds(59)).is_err());
+        assert!(moderation.check_post("doc", "spammer", true, start + Duration::seconds(60)).is_ok());
+        assert!(moderation.check_post("other", "spammer", true, start).is_ok());
+
+        // The mute is in the document's metadata, so a fresh server still knows of it
+        let reloaded = Moderation::new(workspaces()).with_room_host(host);
+        assert!(reloaded.check_post("doc", "spammer", true, start + Duration::seconds(30)).is_err());
+        reloaded.unmute("doc", "olga", "spammer", start + Duration::seconds(30)).unwrap();
+        assert!(reloaded.check_post("doc", "spammer", true, start + Duration::seconds(30)).is_ok());
+
+        let actions: Vec<String> = reloaded.audit_log("doc", "olga").unwrap().iter().map(|entry| serde_json::to_value(entry).unwrap()["action"].as_str().unwrap().to_string()).collect();
+        assert_eq!(actions, vec!["mute", "unmute"]);
+        assert!(reloaded.audit_log("doc", "mo").is_err());
+    }
+
+    #[test]
+    fn test_slow_mode_spaces_chat_messages() {
+        let moderation = Moderation::new(workspaces());
+        let start = Utc::now();
+        moderation.set_slow_mode("doc", "mo", 10, start).unwrap();
+
+        assert!(moderation.check_post("doc", "ed", true, start).is_ok());
+        let refused = moderation.check_post("doc", "ed", true, start + Duration::seconds(4)).unwrap_err();
+        assert!(refused.contains("6 seconds"), "{}", refused);
+        assert!(moderation.check_post("doc", "ed", true, start + Duration::seconds(10)).is_ok());
+
+        // Annotations and moderators aren't slowed down
+        assert!(moderation.check_post("doc", "ed", false, start + Duration::seconds(11)).is_ok());
+        for _ in 0..3 {
+            assert!(moderation.check_post("doc", "mo", true, start).is_ok());
+        }
+        moderation.set_slow_mode("doc", "olga", 0, start).unwrap();
+        assert!(moderation.check_post("doc", "ed", true, start + Duration::seconds(11)).is_ok());
+    }
+}
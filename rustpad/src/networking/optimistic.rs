@@ -0,0 +1,602 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::editor::diff_engine::{DiffEngine, DiffOperation};
+use crate::networking::protocol::{AckMessage, BulkOperation, DeltaMessage, NackMessage, RejectMessage, RemoteDeltaMessage};
+
+/// A local edit that has been applied to the editor but not yet acknowledged by the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingDelta {
+    pub seq: u64,
+    pub paste: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bulk: Option<BulkOperation>,
+    pub operations: Vec<DiffOperation>,
+}
+
+/// Unacknowledged local edits and the server state they are based on, saved so another device
+/// can resume them. The edits keep their client id and seqs, so one the server applied before
+/// the handoff is acknowledged again rather than applied twice.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OfflineJournal {
+    pub client_id: String,
+    pub acked_text: String,
+    pub revision: u64,
+    pub pending: Vec<PendingDelta>,
+    pub next_seq: u64,
+}
+
+/// `OptimisticBuffer` applies local edits immediately and keeps them pending until the server
+/// acknowledges them, rebasing or rolling back the pending edits as acknowledgements,
+/// rejections and remote deltas arrive. Only the oldest pending edit is in flight at a time, so
+/// every delta sent is based on a revision the server knows about. The in-flight edit may be
+/// sent again when its acknowledgement is lost; the server applies it only once.
+pub struct OptimisticBuffer {
+    client_id: String,
+    acked_text: String,        // Last server-confirmed document
+    revision: u64,             // Server revision of `acked_text`
+    local_text: String,        // `acked_text` with all pending edits applied
+    pending: VecDeque<PendingDelta>,
+    in_flight: bool,           // Whether the oldest pending edit has been sent
+    next_seq: u64,
+    held: Vec<RemoteDeltaMessage>, // Remote deltas that arrived ahead of the in-flight edit's acknowledgement
+}
+
+impl OptimisticBuffer {
+    /// Creates a new buffer starting from a server-confirmed document at the given revision.
+    pub fn new(text: &str, revision: u64) -> Self {
+        OptimisticBuffer {
+            client_id: String::new(),
+            acked_text: text.to_string(),
+            revision,
+            local_text: text.to_string(),
+            pending: VecDeque::new(),
+            in_flight: false,
+            next_seq: 0,
+            held: Vec::new(),
+        }
+    }
+
+    /// Tags outgoing deltas with `client_id`, so the server can tell retried deltas apart
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.client_id = client_id.to_string();
+        self
+    }
+
+    /// Records a local edit that turned the local document into `new_text`.
+    /// Returns the sequence id assigned to the edit, or `None` if nothing changed.
+    pub fn local_edit(&mut self, new_text: &str) -> Option<u64> {
+        self.record(new_text, false, None)
+    }
+
+    /// Records a paste that turned the local document into `new_text`. The server may hold a
+    /// large paste until it is confirmed, and drop it if it isn't.
+    pub fn local_paste(&mut self, new_text: &str) -> Option<u64> {
+        self.record(new_text, true, None)
+    }
+
+    /// Records a bulk operation, such as a replace-all or a format, that turned the local
+    /// document into `new_text`. The server checkpoints the document before applying it, and
+    /// acknowledges it with the checkpoint to roll it back by.
+    pub fn local_bulk_edit(&mut self, new_text: &str, operation: BulkOperation) -> Option<u64> {
+        self.record(new_text, false, Some(operation))
+    }
+
+    fn record(&mut self, new_text: &str, paste: bool, bulk: Option<BulkOperation>) -> Option<u64> {
+        let operations = DiffEngine::diff(&self.local_text, new_text);
+        if operations.is_empty() {
+            return None;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.local_text = new_text.to_string();
+        self.pending.push_back(PendingDelta { seq, paste, bulk, operations });
+        Some(seq)
+    }
+
+    /// Returns the next delta to send to the server, if nothing is currently awaiting acknowledgement.
+    pub fn take_outgoing(&mut self) -> Option<DeltaMessage> {
+        if self.in_flight || self.pending.is_empty() {
+            return None;
+        }
+        self.in_flight = true;
+        self.retransmit()
+    }
+
+    /// Returns the in-flight delta again, for when its acknowledgement hasn't arrived in time or
+    /// the connection was re-established. It keeps its seq, so the server won't apply it twice.
+    pub fn retransmit(&self) -> Option<DeltaMessage> {
+        let delta = self.pending.front().filter(|_| self.in_flight)?;
+        Some(DeltaMessage {
+            client_id: self.client_id.clone(),
+            seq: delta.seq,
+            base_revision: self.revision,
+            paste: delta.paste,
+            bulk: delta.bulk,
+            operations: delta.operations.clone(),
+        })
+    }
+
+    /// Handles the server's acknowledgement of the oldest pending edit.
+    /// Returns the operations to apply to the local document (empty unless the server
+    /// transformed the edit, or remote deltas were held back waiting for it). A repeated
+    /// acknowledgement of an edit already confirmed is ignored.
+    pub fn handle_ack(&mut self, ack: &AckMessage) -> Result<Vec<DiffOperation>, String> {
+        match self.pending.front() {
+            Some(delta) if delta.seq == ack.seq => {}
+            Some(delta) if ack.seq < delta.seq => return Ok(Vec::new()),
+            None if ack.seq < self.next_seq => return Ok(Vec::new()),
+            _ => return Err(format!("Unexpected acknowledgement for seq {}", ack.seq)),
+        }
+        let delta = self.pending.pop_front().unwrap();
+        self.in_flight = false;
+        self.revision = ack.revision;
+
+        let mut patch = match &ack.transformed {
+            Some(transformed) if *transformed != delta.operations => {
+                // The remaining edits were based on our version of the delta; rebase them onto
+                // the version the server actually applied.
+                let expected = DiffEngine::apply(&self.acked_text, &delta.operations);
+                self.acked_text = DiffEngine::apply(&self.acked_text, transformed);
+                let correction = DiffEngine::diff(&expected, &self.acked_text);
+
+                let patch = Self::rebase(self.pending.iter_mut(), correction, false);
+                self.local_text = DiffEngine::apply(&self.local_text, &patch);
+                patch
+            }
+            _ => {
+                self.acked_text = DiffEngine::apply(&self.acked_text, &delta.operations);
+                Vec::new()
+            }
+        };
+
+        self.held.sort_by_key(|remote| remote.revision);
+        for remote in std::mem::take(&mut self.held) {
+            patch.extend(self.handle_remote(&remote));
+        }
+        Ok(patch)
+    }
+
+    /// Handles the server's request to resend from `nack.resend_from`, after it missed an
+    /// earlier delta. The oldest pending edit is sent again by the next `take_outgoing`.
+    pub fn handle_nack(&mut self, nack: &NackMessage) -> Result<(), String> {
+        match self.pending.front() {
+            Some(delta) if delta.seq == nack.resend_from => {
+                self.in_flight = false;
+                Ok(())
+            }
+            _ => Err(format!("Cannot resend from seq {}", nack.resend_from)),
+        }
+    }
+
+    /// Handles the server's rejection of a pending edit, rolling back exactly that edit while
+    /// keeping later local edits. Returns the operations to apply to the local document.
+    pub fn handle_reject(&mut self, reject: &RejectMessage) -> Result<Vec<DiffOperation>, String> {
+        let index = self
+            .pending
+            .iter()
+            .position(|delta| delta.seq == reject.seq)
+            .ok_or_else(|| format!("Unknown rejected seq {}", reject.seq))?;
+
+        let before = self
+            .pending
+            .iter()
+            .take(index)
+            .fold(self.acked_text.clone(), |text, delta| DiffEngine::apply(&text, &delta.operations));
+        let after = DiffEngine::apply(&before, &self.pending[index].operations);
+        let inverse = DiffEngine::diff(&after, &before);
+
+        self.pending.remove(index);
+        if index == 0 {
+            self.in_flight = false;
+        }
+        let patch = Self::rebase(self.pending.iter_mut().skip(index), inverse, true);
+        self.local_text = DiffEngine::apply(&self.local_text, &patch);
+        Ok(patch)
+    }
+
+    /// Handles an edit made by another client against our last acknowledged revision.
+    /// Pending edits are rebased on top of it. Returns the operations to apply to the local document.
+    /// An edit that skips a revision while ours is in flight came after ours, whose
+    /// acknowledgement is missing, so it is held until that arrives; one already seen is ignored.
+    pub fn handle_remote(&mut self, remote: &RemoteDeltaMessage) -> Vec<DiffOperation> {
+        if remote.revision <= self.revision {
+            return Vec::new();
+        }
+        if self.in_flight && remote.revision > self.revision + 1 {
+            self.held.push(remote.clone());
+            return Vec::new();
+        }
+        self.acked_text = DiffEngine::apply(&self.acked_text, &remote.operations);
+        self.revision = remote.revision;
+
+        let patch = Self::rebase(self.pending.iter_mut(), remote.operations.clone(), false);
+        self.local_text = DiffEngine::apply(&self.local_text, &patch);
+        patch
+    }
+
+    /// Discards every pending edit and returns the local document to the last acknowledged state.
+    /// Returns the operations to apply to the local document.
+    pub fn rollback(&mut self) -> Vec<DiffOperation> {
+        self.pending.clear();
+        self.held.clear();
+        self.in_flight = false;
+        let patch = DiffEngine::diff(&self.local_text, &self.acked_text);
+        self.local_text = self.acked_text.clone();
+        patch
+    }
+
+    /// Rebases `deltas` over `operations`, returning `operations` transformed to apply after them.
+    fn rebase<'a>(
+        deltas: impl Iterator<Item = &'a mut PendingDelta>,
+        mut operations: Vec<DiffOperation>,
+        pending_first: bool,
+    ) -> Vec<DiffOperation> {
+        for delta in deltas {
+            let (rebased, remaining) = DiffEngine::transform(&delta.operations, &operations, pending_first);
+            delta.operations = rebased;
+            operations = remaining;
+        }
+        operations
+    }
+
+    /// Saves the pending edits, for `from_journal` to resume on another device. Remote edits held
+    /// back for an acknowledgement are left out; they are newer than the saved revision, so the
+    /// resumed buffer receives them again when it catches up.
+    pub fn journal(&self) -> OfflineJournal {
+        OfflineJournal {
+            client_id: self.client_id.clone(),
+            acked_text: self.acked_text.clone(),
+            revision: self.revision,
+            pending: self.pending.iter().cloned().collect(),
+            next_seq: self.next_seq,
+        }
+    }
+
+    /// Resumes the edits saved by `journal`. Nothing is in flight yet, so the next `take_outgoing`
+    /// sends the oldest edit again, and remote edits since the saved revision are rebased over the
+    /// pending ones as usual.
+    pub fn from_journal(journal: OfflineJournal) -> Self {
+        let local_text = journal
+            .pending
+            .iter()
+            .fold(journal.acked_text.clone(), |text, delta| DiffEngine::apply(&text, &delta.operations));
+        OptimisticBuffer {
+            client_id: journal.client_id,
+            acked_text: journal.acked_text,
+            revision: journal.revision,
+            local_text,
+            pending: journal.pending.into(),
+            in_flight: false,
+            next_seq: journal.next_seq,
+            held: Vec::new(),
+        }
+    }
+
+    /// Number of local edits still awaiting acknowledgement.
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The document as the user currently sees it.
+    pub fn local_text(&self) -> &str {
+        &self.local_text
+    }
+
+    /// The last document state confirmed by the server.
+    pub fn acked_text(&self) -> &str {
+        &self.acked_text
+    }
+
+    /// The server revision of the last confirmed document state.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::revision_log::{Receipt, RevisionLog};
+    use proptest::test_runner::{Config, TestCaseError, TestError, TestRunner};
+    use proptest::prelude::*;
+    use std::collections::VecDeque;
+
+    /// Server side of the tests, unwrapping acknowledgements of valid deltas.
+    struct TestServer {
+        log: RevisionLog,
+    }
+
+    impl TestServer {
+        fn new(text: &str) -> Self {
+            TestServer { log: RevisionLog::new(text) }
+        }
+
+        fn receive(&mut self, delta: &DeltaMessage) -> (AckMessage, RemoteDeltaMessage) {
+            match self.log.receive(delta).unwrap() {
+                Receipt::Applied(ack, remote) => (ack, remote),
+                receipt => panic!("Delta not applied: {:?}", receipt),
+            }
+        }
+
+        fn text(&self) -> &str {
+            self.log.text()
+        }
+    }
+
+    #[test]
+    fn test_ack_happy_path() {
+        let mut buffer = OptimisticBuffer::new("hello", 0);
+        buffer.local_edit("hello world").unwrap();
+        assert_eq!(buffer.pending_count(), 1);
+        assert_eq!(buffer.local_text(), "hello world");
+        assert_eq!(buffer.acked_text(), "hello");
+
+        let delta = buffer.take_outgoing().unwrap();
+        assert!(buffer.take_outgoing().is_none());
+        let patch = buffer.handle_ack(&AckMessage { seq: delta.seq, revision: 1, transformed: None, checkpoint_id: None }).unwrap();
+        assert!(patch.is_empty());
+        assert_eq!(buffer.pending_count(), 0);
+        assert_eq!(buffer.acked_text(), "hello world");
+        assert_eq!(buffer.revision(), 1);
+    }
+
+    #[test]
+    fn test_transformed_ack_rebases_pending() {
+        let mut buffer = OptimisticBuffer::new("abc", 0);
+        buffer.local_edit("abcX").unwrap();
+        buffer.local_edit("abcXY").unwrap();
+        let first = buffer.take_outgoing().unwrap();
+
+        // The server placed the first edit at the start of the document instead
+        let ack = AckMessage { seq: first.seq, revision: 1, transformed: Some(vec![DiffOperation::Insert(0, "X".to_string())]), checkpoint_id: None };
+        buffer.handle_ack(&ack).unwrap();
+
+        assert_eq!(buffer.acked_text(), "Xabc");
+        assert_eq!(buffer.local_text(), "XabcY");
+        assert_eq!(buffer.pending_count(), 1);
+        assert_eq!(buffer.take_outgoing().unwrap().operations, vec![DiffOperation::Insert(4, "Y".to_string())]);
+    }
+
+    #[test]
+    fn test_reject_rolls_back_only_rejected_edit() {
+        let mut buffer = OptimisticBuffer::new("abc", 0);
+        buffer.local_edit("abcX").unwrap();
+        buffer.local_edit("abcXY").unwrap();
+        let first = buffer.take_outgoing().unwrap();
+
+        buffer.handle_reject(&RejectMessage { seq: first.seq, reason: "read-only".to_string(), quota: None }).unwrap();
+        assert_eq!(buffer.local_text(), "abcY");
+        assert_eq!(buffer.pending_count(), 1);
+
+        let second = buffer.take_outgoing().unwrap();
+        buffer.handle_ack(&AckMessage { seq: second.seq, revision: 1, transformed: None, checkpoint_id: None }).unwrap();
+        assert_eq!(buffer.acked_text(), "abcY");
+    }
+
+    #[test]
+    fn test_rollback_restores_acked_state() {
+        let mut buffer = OptimisticBuffer::new("abc", 0);
+        buffer.local_edit("abcd").unwrap();
+        buffer.local_edit("xabcd").unwrap();
+
+        let patch = buffer.rollback();
+        assert_eq!(DiffEngine::apply("xabcd", &patch), "abc");
+        assert_eq!(buffer.local_text(), "abc");
+        assert_eq!(buffer.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_interleaved_remote_deltas_converge() {
+        let mut server = TestServer::new("shared");
+        let mut alice = OptimisticBuffer::new("shared", 0);
+        let mut bob = OptimisticBuffer::new("shared", 0);
+
+        alice.local_edit("A shared").unwrap();
+        bob.local_edit("B shared").unwrap();
+        bob.local_edit("B shared doc").unwrap();
+
+        let (ack_a1, remote_a1) = server.receive(&alice.take_outgoing().unwrap());
+        let (ack_b1, remote_b1) = server.receive(&bob.take_outgoing().unwrap());
+        alice.handle_ack(&ack_a1).unwrap();
+        bob.handle_remote(&remote_a1);
+        let text = format!("{}!", alice.local_text());
+        alice.local_edit(&text).unwrap();
+        alice.handle_remote(&remote_b1);
+        bob.handle_ack(&ack_b1).unwrap();
+
+        let (ack_a2, remote_a2) = server.receive(&alice.take_outgoing().unwrap());
+        bob.handle_remote(&remote_a2);
+        let (ack_b2, remote_b2) = server.receive(&bob.take_outgoing().unwrap());
+        alice.handle_ack(&ack_a2).unwrap();
+        alice.handle_remote(&remote_b2);
+        bob.handle_ack(&ack_b2).unwrap();
+
+        assert_eq!(alice.pending_count(), 0);
+        assert_eq!(bob.pending_count(), 0);
+        assert_eq!(alice.local_text(), server.text());
+        assert_eq!(bob.local_text(), server.text());
+        assert_eq!(server.text(), "A B shared! doc");
+    }
+
+    #[test]
+    fn test_retry_after_lost_ack_reconciles() {
+        let mut server = TestServer::new("shared");
+        let mut alice = OptimisticBuffer::new("shared", 0).with_client_id("alice");
+        let mut bob = OptimisticBuffer::new("shared", 0).with_client_id("bob");
+
+        alice.local_edit("shared!").unwrap();
+        let sent = alice.take_outgoing().unwrap();
+        let (_lost_ack, remote_a) = server.receive(&sent);
+        bob.handle_remote(&remote_a);
+        bob.local_edit(">shared!").unwrap();
+        let (ack_b, remote_b) = server.receive(&bob.take_outgoing().unwrap());
+        bob.handle_ack(&ack_b).unwrap();
+
+        // Bob's edit reaches Alice before she gives up on her acknowledgement and retries
+        assert!(alice.handle_remote(&remote_b).is_empty());
+        let Receipt::Duplicate(ack) = server.log.receive(&alice.retransmit().unwrap()).unwrap() else { panic!("applied twice") };
+        let patch = alice.handle_ack(&ack).unwrap();
+        assert_eq!(DiffEngine::apply("shared!", &patch), ">shared!");
+        assert!(alice.handle_ack(&ack).unwrap().is_empty());
+
+        assert_eq!(server.text(), ">shared!");
+        assert_eq!((alice.local_text(), alice.revision()), (server.text(), 2));
+        assert_eq!(alice.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_nack_resends_from_missing_seq() {
+        let mut server = TestServer::new("");
+        let mut client = OptimisticBuffer::new("", 0).with_client_id("ana");
+        client.local_edit("a").unwrap();
+        let (ack, _) = server.receive(&client.take_outgoing().unwrap());
+        client.handle_ack(&ack).unwrap();
+        client.local_edit("ab").unwrap();
+        let _lost = client.take_outgoing().unwrap();
+        client.local_edit("abc").unwrap();
+
+        // A delta from after the lost one is refused until the lost one arrives
+        let ahead = DeltaMessage { client_id: "ana".to_string(), seq: 2, base_revision: 1, paste: false, bulk: None, operations: vec![DiffOperation::Insert(1, "c".to_string())] };
+        let Receipt::Gap(nack) = server.log.receive(&ahead).unwrap() else { panic!("applied out of order") };
+        client.handle_nack(&nack).unwrap();
+
+        while let Some(delta) = client.take_outgoing() {
+            let (ack, _) = server.receive(&delta);
+            client.handle_ack(&ack).unwrap();
+        }
+        assert_eq!(server.text(), "abc");
+        assert_eq!(client.acked_text(), "abc");
+    }
+
+    /// Number of simulated clients in the convergence suite
+    const CLIENTS: usize = 3;
+
+    /// One step of a simulated session. Positions and lengths are reduced to fit the client's
+    /// document, so every script is valid and shrinks to a smaller valid script.
+    #[derive(Debug, Clone)]
+    enum Step {
+        Edit { client: usize, start: usize, len: usize, text: String },
+        Send { client: usize },       // Client hands its next delta to the network
+        Serve,                        // Server handles the oldest delta in flight
+        Deliver { client: usize },    // Client handles the oldest server message addressed to it
+    }
+
+    enum ServerMessage {
+        Ack(AckMessage),
+        Remote(RemoteDeltaMessage),
+    }
+
+    fn step() -> impl Strategy<Value = Step> {
+        prop_oneof![
+            3 => (0..CLIENTS, 0..64usize, 0..4usize, "[ab\né]{0,3}").prop_map(|(client, start, len, text)| Step::Edit { client, start, len, text }),
+            2 => (0..CLIENTS).prop_map(|client| Step::Send { client }),
+            2 => Just(Step::Serve),
+            3 => (0..CLIENTS).prop_map(|client| Step::Deliver { client }),
+        ]
+    }
+
+    /// Runs `script` with messages in flight in virtual time, then lets the network drain, and
+    /// checks every client ends on the server's document and revision.
+    fn run_script(script: &[Step]) -> Result<(), TestCaseError> {
+        let mut server = RevisionLog::new("héllo\nworld");
+        let mut clients: Vec<OptimisticBuffer> = (0..CLIENTS).map(|_| OptimisticBuffer::new("héllo\nworld", 0)).collect();
+        let mut to_server: VecDeque<(usize, DeltaMessage)> = VecDeque::new();
+        let mut to_client: Vec<VecDeque<ServerMessage>> = (0..CLIENTS).map(|_| VecDeque::new()).collect();
+
+        let serve = |server: &mut RevisionLog, to_client: &mut Vec<VecDeque<ServerMessage>>, (sender, delta): (usize, DeltaMessage)| {
+            let Receipt::Applied(ack, remote) = server.receive(&delta).map_err(|reject| TestCaseError::fail(reject.reason))? else {
+                return Err(TestCaseError::fail("Delta not applied"));
+            };
+            for (client, inbox) in to_client.iter_mut().enumerate() {
+                inbox.push_back(if client == sender { ServerMessage::Ack(ack.clone()) } else { ServerMessage::Remote(remote.clone()) });
+            }
+            Ok::<(), TestCaseError>(())
+        };
+        let deliver = |client: &mut OptimisticBuffer, message: ServerMessage| match message {
+            ServerMessage::Ack(ack) => client.handle_ack(&ack).map(|_| ()).map_err(TestCaseError::fail),
+            ServerMessage::Remote(remote) => {
+                client.handle_remote(&remote);
+                Ok(())
+            }
+        };
+
+        for step in script {
+            match step {
+                Step::Edit { client, start, len, text } => {
+                    let local = clients[*client].local_text().to_string();
+                    let boundaries: Vec<usize> = local.char_indices().map(|(i, _)| i).chain(std::iter::once(local.len())).collect();
+                    let start_index = start % boundaries.len();
+                    let end_index = (start_index + len).min(boundaries.len() - 1);
+                    let mut edited = local.clone();
+                    edited.replace_range(boundaries[start_index]..boundaries[end_index], text);
+                    clients[*client].local_edit(&edited);
+                }
+                Step::Send { client } => {
+                    if let Some(delta) = clients[*client].take_outgoing() {
+                        to_server.push_back((*client, delta));
+                    }
+                }
+                Step::Serve => {
+                    if let Some(message) = to_server.pop_front() {
+                        serve(&mut server, &mut to_client, message)?;
+                    }
+                }
+                Step::Deliver { client } => {
+                    if let Some(message) = to_client[*client].pop_front() {
+                        deliver(&mut clients[*client], message)?;
+                    }
+                }
+            }
+        }
+
+        // Drain: keep sending, serving and delivering until nothing is pending anywhere
+        loop {
+            for (client, buffer) in clients.iter_mut().enumerate() {
+                if let Some(delta) = buffer.take_outgoing() {
+                    to_server.push_back((client, delta));
+                }
+            }
+            while let Some(message) = to_server.pop_front() {
+                serve(&mut server, &mut to_client, message)?;
+            }
+            for (client, inbox) in to_client.iter_mut().enumerate() {
+                while let Some(message) = inbox.pop_front() {
+                    deliver(&mut clients[client], message)?;
+                }
+            }
+            if clients.iter().all(|buffer| buffer.pending_count() == 0) {
+                break;
+            }
+        }
+
+        for buffer in &clients {
+            prop_assert_eq!(buffer.local_text(), server.text());
+            prop_assert_eq!(buffer.acked_text(), server.text());
+            prop_assert_eq!(buffer.revision(), server.revision());
+        }
+        Ok(())
+    }
+
+    /// Convergence property over random scripts. On failure the shrunk script is written to
+    /// `target/proptest-repros/convergence.txt`. Set `PROPTEST_CASES` for longer runs and
+    /// `PROPTEST_RNG_SEED` to replay a run.
+    #[test]
+    fn prop_clients_converge() {
+        let cases = std::env::var("PROPTEST_CASES").ok().and_then(|cases| cases.parse().ok()).unwrap_or(256);
+        let mut runner = TestRunner::new(Config { cases, ..Config::default() });
+        let scripts = prop::collection::vec(step(), 0..120);
+
+        if let Err(error) = runner.run(&scripts, |script| run_script(&script)) {
+            let report = match &error {
+                TestError::Fail(reason, script) => format!("{}\n{:#?}\n", reason, script),
+                TestError::Abort(reason) => format!("{}\n", reason),
+            };
+            let directory = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("target/proptest-repros");
+            let _ = std::fs::create_dir_all(&directory);
+            let _ = std::fs::write(directory.join("convergence.txt"), &report);
+            panic!("Clients diverged, minimal script written to {}:\n{}", directory.display(), report);
+        }
+    }
+}
@@ -1,9 +1,83 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use futures_util::{StreamExt, SinkExt};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use tokio::task::JoinHandle;
 use warp::ws::{Message, WebSocket};
+use warp::Filter;
 use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use crate::editor::diff_engine::{DiffEngine, MergeResult};
+use crate::engine::apply_checked;
+use crate::editor::state::EditorState;
+use crate::networking::cursors::CursorCoalescer;
+use crate::networking::protocol::{CursorMessage, ProtocolMessage, SyncMessage};
+use crate::networking::websocket::WebSocketClient;
+
+/// How a node settles an edit from a peer that collides with its own unsent changes. Both
+/// versions were made from `base`; `ours` is the local one and `theirs` the peer's.
+pub trait ConflictStrategy: Send + Sync {
+    fn resolve(&self, base: &str, ours: &str, theirs: &str) -> Resolution;
+}
+
+/// What a `ConflictStrategy` decided
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+    Apply(MergeResult),  // Take `merged` as the document; conflicts, if any, are marked in it
+    Review(MergeResult), // Keep the local version until the user settles the conflicts shown
+}
+
+/// The peer's version replaces the local one
+pub struct LastWriteWins;
+
+impl ConflictStrategy for LastWriteWins {
+    fn resolve(&self, _base: &str, _ours: &str, theirs: &str) -> Resolution {
+        Resolution::Apply(MergeResult { merged: theirs.to_string(), conflicts: Vec::new() })
+    }
+}
+
+/// Changes to different lines are combined; changes to the same lines are kept side by side
+/// between conflict markers
+pub struct ThreeWayMerge;
+
+impl ConflictStrategy for ThreeWayMerge {
+    fn resolve(&self, base: &str, ours: &str, theirs: &str) -> Resolution {
+        Resolution::Apply(DiffEngine::merge3(base, ours, theirs))
+    }
+}
+
+/// Like `ThreeWayMerge`, except that changes to the same lines go to the user rather than
+/// into the document
+pub struct ManualReview;
+
+impl ConflictStrategy for ManualReview {
+    fn resolve(&self, base: &str, ours: &str, theirs: &str) -> Resolution {
+        let merge = DiffEngine::merge3(base, ours, theirs);
+        if merge.is_clean() {
+            Resolution::Apply(merge)
+        } else {
+            Resolution::Review(merge)
+        }
+    }
+}
+
+/// An edit from a peer waiting for the user, as `ManualReview` leaves it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConflictReview {
+    pub sender_id: String,
+    pub ours: String,
+    pub theirs: String,
+    pub merge: MergeResult, // The attempted merge, with the conflicts to show
+}
+
+/// The document as this node holds it
+#[derive(Default)]
+struct PeerDocument {
+    base: String,    // The last version received from a peer
+    content: String, // The current version, with local changes made since `base`
+    reviews: Vec<ConflictReview>,
+}
 
 /// Represents a peer in the P2P network
 #[derive(Debug, Clone)]
@@ -12,17 +86,193 @@ pub struct Peer {
     pub sender: mpsc::UnboundedSender<PeerMessage>,
 }
 
+/// How long a gap in a sender's sequence may stay open before a resync is requested
+pub const REORDER_GAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for an ACK before retransmitting an edit
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of retransmits before a delivery is reported as failed
+pub const MAX_RETRANSMITS: u32 = 3;
+
+/// Callback invoked with the recipient id and the message when a delivery permanently fails
+pub type DeliveryFailedCallback = Box<dyn Fn(&str, &PeerMessage) + Send>;
+
+/// A message addressed to a recipient, as `(recipient id, message)`
+pub type Delivery = (String, PeerMessage);
+
+/// Kind of a message exchanged between peers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum PeerMessageKind {
+    #[default]
+    Edit,
+    ResyncRequest, // Asks the recipient to resend its full state
+    Ack,           // Confirms receipt of the edit whose id is carried in `id`
+}
+
 /// Message format for synchronization between peers
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PeerMessage {
+    #[serde(default)]
+    pub id: String, // Unique edit id, acknowledged by recipients
     pub sender_id: String,
+    pub seq: u64, // Per-sender sequence number, starting at 1
     pub content: String,
     pub timestamp: String,
+    #[serde(default)]
+    pub kind: PeerMessageKind,
+}
+
+/// Holds out-of-order messages per sender until their sequence is contiguous
+pub struct ReorderBuffer {
+    expected: HashMap<String, u64>,                  // Next sequence number expected per sender
+    held: HashMap<String, BTreeMap<u64, PeerMessage>>, // Messages waiting for a gap to fill
+    gap_since: HashMap<String, Instant>,             // When each sender's current gap opened
+    resyncing: HashSet<String>,                      // Senders whose next message starts a new baseline
+    gap_timeout: Duration,
+}
+
+impl ReorderBuffer {
+    /// Creates a new ReorderBuffer that requests a resync after `gap_timeout`
+    pub fn new(gap_timeout: Duration) -> Self {
+        Self {
+            expected: HashMap::new(),
+            held: HashMap::new(),
+            gap_since: HashMap::new(),
+            resyncing: HashSet::new(),
+            gap_timeout,
+        }
+    }
+
+    /// Accepts a received message and returns the messages that are now ready to apply, in order
+    pub fn push(&mut self, message: PeerMessage) -> Vec<PeerMessage> {
+        let sender_id = message.sender_id.clone();
+        if self.resyncing.remove(&sender_id) {
+            self.expected.insert(sender_id.clone(), message.seq);
+        }
+        let expected = self.expected.entry(sender_id.clone()).or_insert(1);
+        if message.seq < *expected {
+            return Vec::new(); // Duplicate or already applied
+        }
+
+        let held = self.held.entry(sender_id.clone()).or_default();
+        held.insert(message.seq, message);
+
+        let mut ready = Vec::new();
+        while let Some(next) = held.remove(expected) {
+            ready.push(next);
+            *expected += 1;
+        }
+
+        if held.is_empty() {
+            self.gap_since.remove(&sender_id);
+        } else if !ready.is_empty() || !self.gap_since.contains_key(&sender_id) {
+            self.gap_since.insert(sender_id, Instant::now());
+        }
+        ready
+    }
+
+    /// Returns the senders whose gap has been open longer than the timeout at `now`.
+    /// Their held messages are dropped and their next message is taken as a new baseline.
+    pub fn expired_gaps(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<String> = self
+            .gap_since
+            .iter()
+            .filter(|(_, since)| now.duration_since(**since) >= self.gap_timeout)
+            .map(|(sender_id, _)| sender_id.clone())
+            .collect();
+
+        for sender_id in &expired {
+            self.gap_since.remove(sender_id);
+            self.held.remove(sender_id);
+            self.expected.remove(sender_id);
+            self.resyncing.insert(sender_id.clone());
+        }
+        expired
+    }
+}
+
+/// An edit sent to a recipient that has not been acknowledged yet
+struct PendingDelivery {
+    recipient: String,
+    message: PeerMessage,
+    retransmits: u32,
+    last_sent: Instant,
+}
+
+/// Tracks unacknowledged edits per recipient and decides when to retransmit them
+pub struct DeliveryTracker {
+    pending: HashMap<(String, String), PendingDelivery>, // Keyed by (message id, recipient)
+    ack_timeout: Duration,
+    max_retransmits: u32,
+}
+
+impl DeliveryTracker {
+    /// Creates a new DeliveryTracker
+    pub fn new(ack_timeout: Duration, max_retransmits: u32) -> Self {
+        Self {
+            pending: HashMap::new(),
+            ack_timeout,
+            max_retransmits,
+        }
+    }
+
+    /// Records that `message` was sent to `recipient` at `now`
+    pub fn track(&mut self, recipient: &str, message: PeerMessage, now: Instant) {
+        self.pending.insert(
+            (message.id.clone(), recipient.to_string()),
+            PendingDelivery {
+                recipient: recipient.to_string(),
+                message,
+                retransmits: 0,
+                last_sent: now,
+            },
+        );
+    }
+
+    /// Records an ACK from `recipient`. Returns whether the edit was still pending.
+    pub fn acknowledge(&mut self, message_id: &str, recipient: &str) -> bool {
+        self.pending.remove(&(message_id.to_string(), recipient.to_string())).is_some()
+    }
+
+    /// Returns the `(recipient, message)` pairs to retransmit at `now`, and the ones that have
+    /// run out of retransmits and are dropped.
+    pub fn due(&mut self, now: Instant) -> (Vec<Delivery>, Vec<Delivery>) {
+        let mut retransmit = Vec::new();
+        let mut failed = Vec::new();
+
+        self.pending.retain(|_, delivery| {
+            if now.duration_since(delivery.last_sent) < self.ack_timeout {
+                return true;
+            }
+            if delivery.retransmits >= self.max_retransmits {
+                failed.push((delivery.recipient.clone(), delivery.message.clone()));
+                return false;
+            }
+            delivery.retransmits += 1;
+            delivery.last_sent = now;
+            retransmit.push((delivery.recipient.clone(), delivery.message.clone()));
+            true
+        });
+
+        (retransmit, failed)
+    }
+
+    /// Number of deliveries awaiting an ACK
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
 }
 
 /// Peer-to-peer synchronization manager
+#[derive(Clone)]
 pub struct PeerSyncManager {
     peers: Arc<Mutex<HashMap<String, Peer>>>,  // Stores peers keyed by their ID
+    sequences: Arc<Mutex<HashMap<String, u64>>>, // Last sequence number sent per sender
+    deliveries: Arc<Mutex<DeliveryTracker>>,     // Edits awaiting ACKs
+    on_delivery_failed: Arc<Mutex<Option<DeliveryFailedCallback>>>,
+    conflict_strategy: Arc<dyn ConflictStrategy>, // Settles edits that collide with local changes
+    document: Arc<Mutex<PeerDocument>>,
 }
 
 impl PeerSyncManager {
@@ -30,15 +280,32 @@ impl PeerSyncManager {
     pub fn new() -> Self {
         Self {
             peers: Arc::new(Mutex::new(HashMap::new())),
+            sequences: Arc::new(Mutex::new(HashMap::new())),
+            deliveries: Arc::new(Mutex::new(DeliveryTracker::new(ACK_TIMEOUT, MAX_RETRANSMITS))),
+            on_delivery_failed: Arc::new(Mutex::new(None)),
+            conflict_strategy: Arc::new(ThreeWayMerge),
+            document: Arc::new(Mutex::new(PeerDocument::default())),
         }
     }
 
-    /// Registers a new peer and returns a mpsc sender for communication
-    pub fn register_peer(&self, peer_id: String, ws_socket: WebSocket) {
+    /// Settles conflicting edits with `strategy` instead of `ThreeWayMerge`
+    pub fn with_conflict_strategy(mut self, strategy: impl ConflictStrategy + 'static) -> Self {
+        self.conflict_strategy = Arc::new(strategy);
+        self
+    }
+
+    /// Sets the callback invoked when an edit could not be delivered to a peer after all retransmits
+    pub fn set_delivery_failed_callback(&self, callback: DeliveryFailedCallback) {
+        *self.on_delivery_failed.lock().unwrap() = Some(callback);
+    }
+
+    /// Registers a new peer and serves its connection until it closes
+    pub async fn register_peer(self, peer_id: String, ws_socket: WebSocket) {
         let (mut ws_tx, mut ws_rx) = ws_socket.split();
         let (sender, mut receiver) = mpsc::unbounded_channel();
 
         // Add the peer to the peer map
+        let reply_sender = sender.clone();
         let peer = Peer {
             id: peer_id.clone(),
             sender,
@@ -46,13 +313,62 @@ impl PeerSyncManager {
         self.peers.lock().unwrap().insert(peer_id.clone(), peer);
 
         // Task to handle receiving messages from the WebSocket
+        let recv_peer_id = peer_id.clone();
+        let deliveries = self.deliveries.clone();
+        let manager = self.clone();
         let recv_task = tokio::spawn(async move {
-            while let Some(Ok(msg)) = ws_rx.next().await {
-                if let Ok(text) = msg.to_str() {
-                    let received_message: PeerMessage = serde_json::from_str(text).unwrap();
-                    println!("Received message from {}: {}", received_message.sender_id, received_message.content);
+            let mut reorder = ReorderBuffer::new(REORDER_GAP_TIMEOUT);
+            let mut gap_check = tokio::time::interval(REORDER_GAP_TIMEOUT / 2);
+            loop {
+                tokio::select! {
+                    msg = ws_rx.next() => {
+                        let msg = match msg {
+                            Some(Ok(msg)) => msg,
+                            _ => break,
+                        };
+                        if let Ok(text) = msg.to_str() {
+                            let received_message: PeerMessage = match serde_json::from_str(text) {
+                                Ok(message) => message,
+                                Err(_) => continue, // Ignore malformed messages
+                            };
+
+                            match received_message.kind {
+                                PeerMessageKind::Ack => {
+                                    deliveries.lock().unwrap().acknowledge(&received_message.id, &received_message.sender_id);
+                                    continue;
+                                }
+                                PeerMessageKind::ResyncRequest => continue,
+                                PeerMessageKind::Edit => {
+                                    // ACK every copy, including retransmits the reorder buffer drops
+                                    let _ = reply_sender.send(PeerMessage {
+                                        id: received_message.id.clone(),
+                                        sender_id: recv_peer_id.clone(),
+                                        seq: 0,
+                                        content: String::new(),
+                                        timestamp: crate::i18n::timestamp(chrono::Utc::now()),
+                                        kind: PeerMessageKind::Ack,
+                                    });
+                                }
+                            }
 
-                    // Apply conflict resolution or synchronization logic here
+                            for message in reorder.push(received_message) {
+                                manager.receive_edit(&message);
+                            }
+                        }
+                    }
+                    _ = gap_check.tick() => {
+                        // Ask senders with a stale gap to resend their full state
+                        for sender_id in reorder.expired_gaps(Instant::now()) {
+                            let _ = reply_sender.send(PeerMessage {
+                                id: Uuid::new_v4().to_string(),
+                                sender_id: recv_peer_id.clone(),
+                                seq: 0,
+                                content: sender_id,
+                                timestamp: crate::i18n::timestamp(chrono::Utc::now()),
+                                kind: PeerMessageKind::ResyncRequest,
+                            });
+                        }
+                    }
                 }
             }
         });
@@ -78,37 +394,111 @@ impl PeerSyncManager {
 
     /// Broadcasts a message to all peers in the network
     pub fn broadcast_message(&self, sender_id: String, content: String) {
-        let timestamp = chrono::Utc::now().to_rfc3339();
+        let timestamp = crate::i18n::timestamp(chrono::Utc::now());
+        let seq = {
+            let mut sequences = self.sequences.lock().unwrap();
+            let seq = sequences.entry(sender_id.clone()).or_insert(0);
+            *seq += 1;
+            *seq
+        };
         let message = PeerMessage {
+            id: Uuid::new_v4().to_string(),
             sender_id: sender_id.clone(),
+            seq,
             content,
             timestamp,
+            kind: PeerMessageKind::Edit,
         };
 
-        // Broadcast the message to all peers
+        // Broadcast the message to all peers, tracking each delivery until it is acknowledged
+        let recipients = self.peer_senders(|peer_id| peer_id != sender_id);
+        let mut deliveries = self.deliveries.lock().unwrap();
+        for (peer_id, sender) in recipients {
+            let _ = sender.send(message.clone());
+            deliveries.track(&peer_id, message.clone(), Instant::now());
+        }
+    }
+
+    /// Snapshots the senders of the peers matching `filter`, so sending never holds the peer map
+    fn peer_senders(&self, filter: impl Fn(&str) -> bool) -> Vec<(String, mpsc::UnboundedSender<PeerMessage>)> {
         let peers = self.peers.lock().unwrap();
-        for (peer_id, peer) in peers.iter() {
-            if *peer_id != sender_id {
-                let _ = peer.sender.send(message.clone());
+        peers.iter().filter(|(peer_id, _)| filter(peer_id)).map(|(peer_id, peer)| (peer_id.clone(), peer.sender.clone())).collect()
+    }
+
+    /// Retransmits edits whose ACK is overdue at `now` and reports deliveries that ran out of
+    /// retransmits to the failure callback. Meant to be called periodically.
+    pub fn retransmit_pending(&self, now: Instant) {
+        let (retransmit, failed) = self.deliveries.lock().unwrap().due(now);
+
+        let peers: HashMap<_, _> = self.peer_senders(|_| true).into_iter().collect();
+        for (recipient, message) in retransmit {
+            if let Some(sender) = peers.get(&recipient) {
+                let _ = sender.send(message);
+            }
+        }
+
+        if let Some(callback) = self.on_delivery_failed.lock().unwrap().as_ref() {
+            for (recipient, message) in &failed {
+                callback(recipient, message);
             }
         }
     }
 
-    /// Handles conflict resolution for synchronized content (e.g., last-write-wins)
-    pub fn resolve_conflict(&self, existing_content: &str, new_content: &str) -> String {
-        // Example conflict resolution logic (last-write-wins)
-        // This can be extended to use OT/CRDT algorithms for more complex conflict resolution
-        if existing_content == new_content {
-            existing_content.to_string()
-        } else {
-            new_content.to_string()  // Assume last-write-wins for simplicity
+    /// Settles `new_content` from a peer against `existing_content`, both made from `base`,
+    /// with the manager's conflict strategy
+    pub fn resolve_conflict(&self, base: &str, existing_content: &str, new_content: &str) -> Resolution {
+        self.conflict_strategy.resolve(base, existing_content, new_content)
+    }
+
+    /// Records a local change to the document, to be settled against edits from peers
+    pub fn edit_local(&self, content: &str) {
+        self.document.lock().unwrap().content = content.to_string();
+    }
+
+    /// The document as this node holds it
+    pub fn content(&self) -> String {
+        self.document.lock().unwrap().content.clone()
+    }
+
+    /// Takes in an edit from a peer, in sequence. Without local changes it replaces the
+    /// document; otherwise the conflict strategy decides, and the resolution is returned.
+    pub fn receive_edit(&self, message: &PeerMessage) -> Option<Resolution> {
+        let mut document = self.document.lock().unwrap();
+        let theirs = message.content.clone();
+        if document.content == document.base || document.content == theirs {
+            document.content = theirs.clone();
+            document.base = theirs;
+            return None;
+        }
+        let resolution = self.resolve_conflict(&document.base, &document.content, &theirs);
+        match &resolution {
+            Resolution::Apply(merge) => document.content = merge.merged.clone(),
+            Resolution::Review(merge) => {
+                let review = ConflictReview { sender_id: message.sender_id.clone(), ours: document.content.clone(), theirs: theirs.clone(), merge: merge.clone() };
+                document.reviews.push(review);
+            }
         }
+        // Later edits from peers build on theirs, with whatever is kept locally as our changes
+        document.base = theirs;
+        Some(resolution)
+    }
+
+    /// Removes and returns the conflicts waiting for the user. Settling one is a local edit
+    /// with the text the user chose.
+    pub fn take_reviews(&self) -> Vec<ConflictReview> {
+        std::mem::take(&mut self.document.lock().unwrap().reviews)
+    }
+}
+
+impl Default for PeerSyncManager {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 /// WebSocket handler for peer synchronization
-pub async fn peer_sync_handler(ws: warp::ws::Ws, manager: PeerSyncManager, peer_id: String) -> impl warp::Reply {
-    ws.on_upgrade(move |socket| manager.register_peer(peer_id, socket))
+pub async fn peer_sync_handler(ws: warp::ws::Ws, peer_id: String, manager: PeerSyncManager) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(ws.on_upgrade(move |socket| manager.register_peer(peer_id, socket)))
 }
 
 /// Route for peer synchronization WebSocket
@@ -125,15 +515,556 @@ fn with_manager(manager: PeerSyncManager) -> impl warp::Filter<Extract = (PeerSy
     warp::any().map(move || manager.clone())
 }
 
-/// Example main function for setting up the peer sync server
-#[tokio::main]
-async fn main() {
-    let peer_sync_manager = PeerSyncManager::new();
+/// The editor's side of collaborating: sends changes to an `EditorState` as `Sync` frames,
+/// diffed against the text peers last had, shares the cursor, and applies what peers send back
+/// onto the state. Runs over a `WebSocketClient`, or any sink and stream of text frames.
+pub struct PeerSync {
+    outgoing: Option<mpsc::UnboundedSender<String>>,   // Frames to peers, while connected
+    incoming: Option<mpsc::UnboundedReceiver<String>>, // Frames from peers, until `sync` applies them
+    tasks: Vec<JoinHandle<()>>,                         // Move frames between the channels and the connection
+    shared_text: String,        // The document as peers have it; local changes are diffed against it
+    cursor: CursorCoalescer,    // Holds back cursor moves made in quick succession
+    peer_cursor: Option<usize>, // Where the last cursor update from a peer put them
+}
+
+impl PeerSync {
+    /// Creates a disconnected `PeerSync` for an empty document
+    pub fn new() -> Self {
+        Self {
+            outgoing: None,
+            incoming: None,
+            tasks: Vec::new(),
+            shared_text: String::new(),
+            cursor: CursorCoalescer::default(),
+            peer_cursor: None,
+        }
+    }
+
+    /// Connects to the server at `url`, replacing any earlier connection
+    pub async fn connect(&mut self, url: &str) -> Result<(), String> {
+        let mut client = WebSocketClient::new(url);
+        client.connect().await?;
+        let (sink, stream) = client.into_split()?;
+        self.connect_with(sink, stream);
+        Ok(())
+    }
+
+    /// Sends frames into `sink` and takes them from `stream` instead of a WebSocket, replacing
+    /// any earlier connection. Must be called within a Tokio runtime.
+    pub fn connect_with<Tx, Rx>(&mut self, sink: Tx, stream: Rx)
+    where
+        Tx: Sink<String> + Send + 'static,
+        Rx: Stream<Item = String> + Send + 'static,
+    {
+        self.disconnect();
+        let (outgoing, mut to_send) = mpsc::unbounded_channel::<String>();
+        let (received, incoming) = mpsc::unbounded_channel();
+        let send_task = tokio::spawn(async move {
+            let mut sink = Box::pin(sink);
+            while let Some(frame) = to_send.recv().await {
+                if sink.send(frame).await.is_err() {
+                    break; // The connection is gone
+                }
+            }
+        });
+        let recv_task = tokio::spawn(async move {
+            let mut stream = Box::pin(stream);
+            while let Some(frame) = stream.next().await {
+                if received.send(frame).is_err() {
+                    break;
+                }
+            }
+        });
+        self.outgoing = Some(outgoing);
+        self.incoming = Some(incoming);
+        self.tasks = vec![send_task, recv_task];
+    }
+
+    /// Closes the connection. Frames not yet applied with `sync` are dropped.
+    pub fn disconnect(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.outgoing = None;
+        self.incoming = None;
+    }
+
+    /// Whether frames can still be sent
+    pub fn is_connected(&self) -> bool {
+        self.outgoing.as_ref().is_some_and(|outgoing| !outgoing.is_closed())
+    }
+
+    /// Takes the text of `state` as what peers already have, such as a document just opened
+    /// from the server. Only changes made after it are broadcast.
+    pub fn set_base(&mut self, state: &EditorState) {
+        self.shared_text = state.get_text().into_owned();
+    }
+
+    /// Where the last cursor update from a peer put them
+    pub fn peer_cursor(&self) -> Option<usize> {
+        self.peer_cursor
+    }
 
-    // WebSocket route for peer synchronization
-    let peer_sync_ws_route = peer_sync_route(peer_sync_manager.clone());
+    /// Sends how `state` changed since peers last heard from us. Changes made while
+    /// disconnected go out together with the first change after connecting.
+    pub fn broadcast_change(&mut self, state: &EditorState) {
+        let text = state.get_text();
+        let operations = DiffEngine::diff(&self.shared_text, &text);
+        if operations.is_empty() {
+            return;
+        }
+        if self.send(&ProtocolMessage::Sync(SyncMessage::new(operations))) {
+            self.shared_text = text.into_owned();
+        }
+    }
 
-    // Start the server
-    println!("Peer-to-peer sync server running on ws://localhost:3030/peer_sync_ws/{peer_id}");
-    warp::serve(peer_sync_ws_route).run(([127, 0, 0, 1], 3030)).await;
+    /// Shares the primary cursor of `state`. Moves in quick succession are coalesced: `sync`
+    /// sends the last of them once it is due.
+    pub fn broadcast_cursor(&mut self, state: &EditorState) {
+        self.cursor_moved(state.get_cursor_position(), Instant::now());
+    }
+
+    /// When a held back cursor position is due to be sent by `flush_cursor`
+    pub fn cursor_due(&self) -> Option<Instant> {
+        self.cursor.next_due()
+    }
+
+    /// Sends the latest cursor position if it was held back and is due at `now`
+    pub fn flush_cursor(&mut self, now: Instant) {
+        for (_, position) in self.cursor.due(now) {
+            self.send(&ProtocolMessage::Cursor(CursorMessage::new(position)));
+        }
+    }
+
+    /// Applies the frames received since the last call onto `state` and sends a held back
+    /// cursor position that is due. Meant to be called from the editor's loop.
+    pub fn sync(&mut self, state: &mut EditorState) {
+        let mut frames = Vec::new();
+        if let Some(incoming) = self.incoming.as_mut() {
+            while let Ok(frame) = incoming.try_recv() {
+                frames.push(frame);
+            }
+        }
+        for frame in frames {
+            if let Err(e) = self.handle_incoming_message(&frame, state) {
+                eprintln!("Ignoring frame from peers: {}", e);
+            }
+        }
+        self.flush_cursor(Instant::now());
+    }
+
+    /// Applies a frame from a peer onto `state`. A remote edit is rebased over local changes
+    /// not broadcast yet, and the local cursors keep their place in the text around it. Edits
+    /// not fitting the shared text, or splitting a character, are refused untouched.
+    pub fn handle_incoming_message(&mut self, message: &str, state: &mut EditorState) -> Result<(), String> {
+        match ProtocolMessage::from_json(message).map_err(|e| format!("Malformed frame: {}", e))? {
+            ProtocolMessage::Sync(sync) => {
+                let (shared_text, _) = apply_checked(&self.shared_text, &sync.operations)?;
+                let unsent = DiffEngine::diff(&self.shared_text, &state.get_text());
+                let (operations, _) = DiffEngine::transform(&sync.operations, &unsent, false);
+                for operation in operations {
+                    let (start, end, new_text) = DiffEngine::to_range(operation);
+                    state.apply_remote(start, end, &new_text);
+                }
+                self.shared_text = shared_text;
+            }
+            ProtocolMessage::Cursor(cursor) => self.peer_cursor = Some(cursor.cursor_position),
+            _ => {} // Deltas and their acknowledgements are between `Networking` and the server
+        }
+        Ok(())
+    }
+
+    fn cursor_moved(&mut self, position: usize, now: Instant) {
+        if let Some(position) = self.cursor.moved("", position, now) {
+            self.send(&ProtocolMessage::Cursor(CursorMessage::new(position)));
+        }
+    }
+
+    /// Queues `message` for the connection; false when there is none
+    fn send(&self, message: &ProtocolMessage) -> bool {
+        let Some(outgoing) = &self.outgoing else { return false };
+        match message.to_json() {
+            Ok(json) => outgoing.send(json).is_ok(),
+            Err(e) => {
+                eprintln!("Failed to serialize frame for peers: {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl Default for PeerSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PeerSync {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+/// Relays every text frame a `PeerSync` client sends to all other clients connected to it,
+/// for editors collaborating without a document server
+#[derive(Clone, Default)]
+pub struct PeerRelay {
+    clients: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Message>>>>,
+}
+
+impl PeerRelay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of clients connected
+    pub fn connection_count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Serves a client's connection until it closes
+    pub async fn register_client(self, socket: WebSocket) {
+        let client_id = Uuid::new_v4().to_string();
+        let (mut ws_tx, mut ws_rx) = socket.split();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        self.clients.lock().unwrap().insert(client_id.clone(), sender);
+
+        let send_task = tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                if ws_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+        while let Some(Ok(message)) = ws_rx.next().await {
+            if !message.is_text() {
+                continue;
+            }
+            let others: Vec<_> = {
+                let clients = self.clients.lock().unwrap();
+                clients.iter().filter(|(id, _)| **id != client_id).map(|(_, sender)| sender.clone()).collect()
+            };
+            for other in others {
+                let _ = other.send(message.clone());
+            }
+        }
+
+        self.clients.lock().unwrap().remove(&client_id);
+        send_task.abort();
+    }
+}
+
+/// Route relaying frames between `PeerSync` clients
+pub fn peer_relay_route(relay: PeerRelay) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("peer_relay_ws")
+        .and(warp::ws())
+        .map(move |ws: warp::ws::Ws| {
+            let relay = relay.clone();
+            ws.on_upgrade(move |socket| relay.register_client(socket))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::diff_engine::DiffOperation;
+    use crate::editor::editor::Editor;
+    use crate::editor::events::{CursorMove, InputEvent};
+    use crate::networking::cursors::CURSOR_INTERVAL;
+    use futures::channel::mpsc as frames;
+
+    fn message(sender_id: &str, seq: u64) -> PeerMessage {
+        PeerMessage {
+            id: format!("{}-{}", sender_id, seq),
+            sender_id: sender_id.to_string(),
+            seq,
+            content: format!("edit {}", seq),
+            timestamp: String::new(),
+            kind: PeerMessageKind::Edit,
+        }
+    }
+
+    #[test]
+    fn test_reorder_buffer_applies_in_sequence() {
+        let mut reorder = ReorderBuffer::new(REORDER_GAP_TIMEOUT);
+        let mut applied = Vec::new();
+
+        for seq in [1, 3, 2] {
+            applied.extend(reorder.push(message("alice", seq)).into_iter().map(|m| m.seq));
+        }
+
+        assert_eq!(applied, vec![1, 2, 3]);
+        assert!(reorder.push(message("alice", 2)).is_empty()); // Duplicates are dropped
+    }
+
+    #[test]
+    fn test_reorder_buffer_gap_timeout_requests_resync() {
+        let mut reorder = ReorderBuffer::new(Duration::from_millis(10));
+        reorder.push(message("alice", 1));
+        assert!(reorder.push(message("alice", 3)).is_empty());
+        assert_eq!(reorder.push(message("bob", 1)).len(), 1);
+
+        assert!(reorder.expired_gaps(Instant::now()).is_empty());
+        let expired = reorder.expired_gaps(Instant::now() + Duration::from_millis(20));
+        assert_eq!(expired, vec!["alice".to_string()]);
+
+        // The next message after a resync becomes the new baseline
+        let ready = reorder.push(message("alice", 7));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(reorder.push(message("alice", 8)).len(), 1);
+    }
+
+    fn register_test_peer(manager: &PeerSyncManager, peer_id: &str) -> mpsc::UnboundedReceiver<PeerMessage> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        manager.peers.lock().unwrap().insert(peer_id.to_string(), Peer { id: peer_id.to_string(), sender });
+        receiver
+    }
+
+    #[test]
+    fn test_unacknowledged_edit_is_retransmitted() {
+        let manager = PeerSyncManager::new();
+        let mut bob = register_test_peer(&manager, "bob");
+
+        manager.broadcast_message("alice".to_string(), "hello".to_string());
+        let sent = bob.try_recv().unwrap();
+
+        // Bob withholds the ACK, so the edit is sent again once the timeout passes
+        manager.retransmit_pending(Instant::now());
+        assert!(bob.try_recv().is_err());
+        manager.retransmit_pending(Instant::now() + ACK_TIMEOUT);
+        let retransmitted = bob.try_recv().unwrap();
+        assert_eq!(retransmitted.id, sent.id);
+        assert_eq!(retransmitted.seq, sent.seq);
+
+        // Once acknowledged, nothing is retransmitted
+        assert!(manager.deliveries.lock().unwrap().acknowledge(&sent.id, "bob"));
+        manager.retransmit_pending(Instant::now() + ACK_TIMEOUT * 4);
+        assert!(bob.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_delivery_failure_callback_after_max_retransmits() {
+        let manager = PeerSyncManager::new();
+        let _bob = register_test_peer(&manager, "bob");
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let recorded = failures.clone();
+        manager.set_delivery_failed_callback(Box::new(move |recipient, message| {
+            recorded.lock().unwrap().push((recipient.to_string(), message.content.clone()));
+        }));
+
+        manager.broadcast_message("alice".to_string(), "hello".to_string());
+        let mut now = Instant::now();
+        for _ in 0..=MAX_RETRANSMITS {
+            now += ACK_TIMEOUT;
+            manager.retransmit_pending(now);
+        }
+
+        assert_eq!(*failures.lock().unwrap(), vec![("bob".to_string(), "hello".to_string())]);
+        assert_eq!(manager.deliveries.lock().unwrap().pending_count(), 0);
+    }
+
+    #[test]
+    fn test_resolve_conflict_clean_merge() {
+        let manager = PeerSyncManager::new();
+        let base = "fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n";
+        let existing = "fn main() {\n    let x = 2;\n    println!(\"{}\", x);\n}\n";
+        let new = "fn main() {\n    let x = 1;\n    println!(\"x = {}\", x);\n}\n";
+
+        let Resolution::Apply(result) = manager.resolve_conflict(base, existing, new) else { panic!("A clean merge is applied") };
+
+        assert!(result.is_clean());
+        assert_eq!(result.merged, "fn main() {\n    let x = 2;\n    println!(\"x = {}\", x);\n}\n");
+    }
+
+    #[test]
+    fn test_resolve_conflict_same_line() {
+        let manager = PeerSyncManager::new();
+        let base = "first\nsecond\nthird\n";
+        let existing = "first\nsecond (ours)\nthird\n";
+        let new = "first\nsecond (theirs)\nthird\n";
+
+        let Resolution::Apply(result) = manager.resolve_conflict(base, existing, new) else { panic!("Merges are applied by default") };
+
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].ours, "second (ours)\n");
+        assert_eq!(result.conflicts[0].theirs, "second (theirs)\n");
+        assert_eq!(
+            result.merged,
+            "first\n<<<<<<< ours\nsecond (ours)\n=======\nsecond (theirs)\n>>>>>>> theirs\nthird\n"
+        );
+        assert_eq!(&result.merged[result.conflicts[0].start..result.conflicts[0].end], "<<<<<<< ours\nsecond (ours)\n=======\nsecond (theirs)\n>>>>>>> theirs\n");
+    }
+
+    #[test]
+    fn test_strategies_settle_the_same_conflict() {
+        let (base, ours, theirs) = ("title\nbody\nend\n", "Title\nbody\nend\n", "TITLE\nbody\nEnd\n");
+
+        let Resolution::Apply(last_write) = PeerSyncManager::new().with_conflict_strategy(LastWriteWins).resolve_conflict(base, ours, theirs) else { panic!() };
+        assert_eq!(last_write, MergeResult { merged: theirs.to_string(), conflicts: Vec::new() });
+
+        let Resolution::Apply(merged) = PeerSyncManager::new().with_conflict_strategy(ThreeWayMerge).resolve_conflict(base, ours, theirs) else { panic!() };
+        assert_eq!(merged.conflicts.len(), 1);
+        assert_eq!(merged.merged, "<<<<<<< ours\nTitle\n=======\nTITLE\n>>>>>>> theirs\nbody\nEnd\n");
+
+        let Resolution::Review(review) = PeerSyncManager::new().with_conflict_strategy(ManualReview).resolve_conflict(base, ours, theirs) else { panic!() };
+        assert_eq!(review, merged);
+        assert!(matches!(ManualReview.resolve(base, ours, "title\nbody\nEnd\n"), Resolution::Apply(merge) if merge.merged == "Title\nbody\nEnd\n"));
+    }
+
+    #[test]
+    fn test_received_edits_go_through_the_strategy() {
+        let manager = PeerSyncManager::new().with_conflict_strategy(ManualReview);
+        let edit = |content: &str| PeerMessage { content: content.to_string(), ..message("bob", 1) };
+
+        // Without local changes, edits from peers are simply taken
+        assert_eq!(manager.receive_edit(&edit("title\nbody\n")), None);
+        manager.edit_local("Title\nbody\n");
+
+        // A conflicting edit leaves the local version for the user to settle
+        assert!(matches!(manager.receive_edit(&edit("TITLE\nbody\n")), Some(Resolution::Review(_))));
+        assert_eq!(manager.content(), "Title\nbody\n");
+        let reviews = manager.take_reviews();
+        assert_eq!((reviews.len(), reviews[0].sender_id.as_str(), reviews[0].theirs.as_str()), (1, "bob", "TITLE\nbody\n"));
+        assert!(manager.take_reviews().is_empty());
+
+        // Later edits build on the peer's version, so only new changes are merged in
+        assert!(matches!(manager.receive_edit(&edit("TITLE\nbody\nfooter\n")), Some(Resolution::Apply(_))));
+        assert_eq!(manager.content(), "Title\nbody\nfooter\n");
+    }
+
+    /// A `PeerSync` over channels, with the frames it sends
+    fn connected() -> (PeerSync, frames::UnboundedReceiver<String>, frames::UnboundedSender<String>) {
+        let (sink, sent) = frames::unbounded();
+        let (peer, stream) = frames::unbounded();
+        let mut sync = PeerSync::new();
+        sync.connect_with(sink, stream);
+        (sync, sent, peer)
+    }
+
+    async fn next_frame(sent: &mut frames::UnboundedReceiver<String>) -> ProtocolMessage {
+        let frame = tokio::time::timeout(Duration::from_secs(5), sent.next()).await.unwrap().unwrap();
+        ProtocolMessage::from_json(&frame).unwrap()
+    }
+
+    fn state_with(text: &str) -> EditorState {
+        let mut state = EditorState::new();
+        state.insert_text(text);
+        state
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_sends_the_diff_since_the_last_broadcast() {
+        let (mut sync, mut sent, _peer) = connected();
+        let mut state = state_with("hello world");
+        sync.set_base(&state);
+        state.move_cursor(6);
+        state.insert_text("brave ");
+        sync.broadcast_change(&state);
+        sync.broadcast_change(&state); // Nothing changed since
+        state.move_cursor(0);
+        state.insert_text("> ");
+        sync.broadcast_change(&state);
+
+        let ProtocolMessage::Sync(first) = next_frame(&mut sent).await else { panic!("expected a sync frame") };
+        assert_eq!(first.operations, vec![DiffOperation::Insert(6, "brave ".to_string())]);
+        let ProtocolMessage::Sync(second) = next_frame(&mut sent).await else { panic!("expected a sync frame") };
+        assert_eq!(second.operations, vec![DiffOperation::Insert(0, "> ".to_string())]);
+    }
+
+    #[test]
+    fn test_remote_insert_before_the_cursor_shifts_it() {
+        let mut sync = PeerSync::new();
+        let mut state = state_with("world");
+        sync.set_base(&state);
+        state.move_cursor(2);
+
+        let insert = |position: usize, text: &str| ProtocolMessage::Sync(SyncMessage::new(vec![DiffOperation::Insert(position, text.to_string())])).to_json().unwrap();
+        sync.handle_incoming_message(&insert(0, "hello "), &mut state).unwrap();
+        assert_eq!(state.get_text(), "hello world");
+        assert_eq!(state.get_cursor_position(), 8);
+        sync.handle_incoming_message(&insert(11, "!"), &mut state).unwrap();
+        assert_eq!(state.get_cursor_position(), 8);
+
+        // A local change not broadcast yet stays, with the remote edit placed around it
+        state.move_cursor(0);
+        state.insert_text(">");
+        sync.handle_incoming_message(&insert(6, "big "), &mut state).unwrap();
+        assert_eq!(state.get_text(), ">hello big world!");
+        assert_eq!(state.get_cursor_position(), 1);
+        assert!(sync.handle_incoming_message("not a frame", &mut state).is_err());
+    }
+
+    #[test]
+    fn test_edits_splitting_a_character_are_refused() {
+        let mut sync = PeerSync::new();
+        let mut state = state_with("héllo");
+        sync.set_base(&state);
+
+        let frame = |operation: DiffOperation| ProtocolMessage::Sync(SyncMessage::new(vec![operation])).to_json().unwrap();
+        for operation in [DiffOperation::Insert(2, "x".to_string()), DiffOperation::Delete(1, 2), DiffOperation::Replace(3, 2, String::new()), DiffOperation::Delete(0, 99)] {
+            assert!(sync.handle_incoming_message(&frame(operation), &mut state).is_err());
+        }
+        assert_eq!(state.get_text(), "héllo");
+        sync.handle_incoming_message(&frame(DiffOperation::Insert(3, "x".to_string())), &mut state).unwrap();
+        assert_eq!(state.get_text(), "héxllo");
+    }
+
+    #[tokio::test]
+    async fn test_cursor_moves_are_throttled() {
+        let (mut sync, mut sent, _peer) = connected();
+        let start = Instant::now();
+        for (ms, position) in [(0, 1), (10, 2), (20, 3), (40, 4)] {
+            sync.cursor_moved(position, start + Duration::from_millis(ms));
+        }
+        assert_eq!(sync.cursor_due(), Some(start + CURSOR_INTERVAL));
+        sync.flush_cursor(start + CURSOR_INTERVAL - Duration::from_millis(1));
+        sync.flush_cursor(start + CURSOR_INTERVAL);
+
+        // The first move goes out at once; of the rest, only the last
+        for expected in [1, 4] {
+            let ProtocolMessage::Cursor(cursor) = next_frame(&mut sent).await else { panic!("expected a cursor frame") };
+            assert_eq!(cursor.cursor_position, expected);
+        }
+        assert_eq!(sync.cursor_due(), None);
+    }
+
+    async fn wait_for_text(editor: &mut Editor, text: &str) {
+        for _ in 0..500 {
+            editor.sync();
+            if editor.get_state().get_text() == text {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("expected {:?}, have {:?}", text, editor.get_state().get_text());
+    }
+
+    #[tokio::test]
+    async fn test_headless_editors_round_trip_through_the_relay() {
+        let relay = PeerRelay::new();
+        let (addr, server) = warp::serve(peer_relay_route(relay.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = format!("ws://{}/peer_relay_ws", addr);
+        let (mut ana, mut ben) = (Editor::new(), Editor::new());
+        ana.peer_sync.connect(&url).await.unwrap();
+        ben.peer_sync.connect(&url).await.unwrap();
+        while relay.connection_count() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        ana.handle_input_event(InputEvent::InsertText("world".to_string()));
+        wait_for_text(&mut ben, "world").await;
+
+        ben.handle_input_event(InputEvent::MoveCursor(CursorMove::ToPosition(0)));
+        ben.handle_input_event(InputEvent::InsertText("hello ".to_string()));
+        wait_for_text(&mut ana, "hello world").await;
+        assert_eq!(ana.get_state().get_cursor_position(), 11);
+        assert_eq!(ana.peer_sync.peer_cursor(), Some(0));
+
+        ana.peer_sync.disconnect();
+        assert!(!ana.peer_sync.is_connected());
+        while relay.connection_count() > 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
 }
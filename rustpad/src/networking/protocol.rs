@@ -1,6 +1,11 @@
 use crate::editor::diff_engine::DiffOperation;
+use crate::editor::linter::LintError;
+use crate::networking::suggestions::Suggestion;
+use crate::storage::quota::QuotaExceeded;
 use serde::{Serialize, Deserialize};
 
+pub use crate::version::PROTOCOL_VERSION;
+
 /// `SyncMessage` represents a message that contains a series of diff operations
 /// to apply changes to the document for synchronization between peers.
 #[derive(Serialize, Deserialize, Debug)]
@@ -40,9 +45,16 @@ impl SyncMessage {
         let operations = crate::editor::diff_engine::DiffEngine::diff(prev_state, current_state);
         SyncMessage { operations }
     }
+
+    /// Create a `SyncMessage` carrying the last local edit of the editor as one batched
+    /// delta, covering every cursor of a multi-cursor edit.
+    pub fn from_last_edit(state: &crate::editor::state::EditorState) -> Self {
+        SyncMessage::new(state.last_edit().to_vec())
+    }
 }
 
 /// `CursorMessage` represents a message that communicates a user's cursor position.
+/// Only the primary cursor is shared; secondary cursors stay local.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CursorMessage {
     pub cursor_position: usize,
@@ -53,6 +65,209 @@ impl CursorMessage {
     pub fn new(cursor_position: usize) -> Self {
         CursorMessage { cursor_position }
     }
+
+    /// Creates a `CursorMessage` for the primary cursor of `state`.
+    pub fn from_state(state: &crate::editor::state::EditorState) -> Self {
+        CursorMessage::new(state.get_cursor_position())
+    }
+}
+
+/// `DeltaMessage` carries a local edit to the server, tagged with a client-assigned sequence id
+/// and the last server revision the edit was based on. Sequence ids count up by one per client,
+/// so the server can recognize a retried delta and notice a missing one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeltaMessage {
+    #[serde(default)]
+    pub client_id: String, // Empty for edits made by the server itself, which are never deduplicated
+    pub seq: u64,
+    pub base_revision: u64,
+    #[serde(default)]
+    pub paste: bool, // The inserted text was pasted; large pastes need confirming before they apply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bulk: Option<BulkOperation>, // Set for edits rewriting much of the document, which the server checkpoints first
+    pub operations: Vec<DiffOperation>,
+}
+
+/// `BulkOperation` names an edit that rewrites large swaths of a document at once. The server
+/// keeps a version from before each, so it can be rolled back for everyone.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkOperation {
+    ReplaceAll,
+    Format,
+    Revert,
+    Merge,
+    Paste, // Large pastes, once confirmed
+}
+
+impl BulkOperation {
+    /// The operation as it is named on the wire and in version tags
+    pub fn name(&self) -> &'static str {
+        match self {
+            BulkOperation::ReplaceAll => "replace_all",
+            BulkOperation::Format => "format",
+            BulkOperation::Revert => "revert",
+            BulkOperation::Merge => "merge",
+            BulkOperation::Paste => "paste",
+        }
+    }
+}
+
+impl DeltaMessage {
+    /// The pieces of text the delta inserts
+    pub fn inserted_text(&self) -> impl Iterator<Item = &str> {
+        self.operations.iter().filter_map(|operation| match operation {
+            DiffOperation::Insert(_, text) | DiffOperation::Replace(_, _, text) => Some(text.as_str()),
+            DiffOperation::Delete(..) => None,
+        })
+    }
+}
+
+/// `AckMessage` is the server's acknowledgement of a `DeltaMessage`. When the server had to
+/// transform the edit, `transformed` holds the operations it actually applied.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AckMessage {
+    pub seq: u64,
+    pub revision: u64,
+    pub transformed: Option<Vec<DiffOperation>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint_id: Option<u64>, // Set when the edit was a bulk operation, to roll it back by
+}
+
+/// `RejectMessage` tells the client that the server refused a `DeltaMessage`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RejectMessage {
+    pub seq: u64,
+    pub reason: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<QuotaExceeded>, // The limit a large paste would have gone over
+}
+
+/// `NackMessage` tells the client that deltas before `seq` never arrived, so the server did not
+/// apply it. The client sends its pending deltas again, starting from `resend_from`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NackMessage {
+    pub seq: u64,
+    pub resend_from: u64,
+}
+
+/// `PasteConfirmMessage` asks the client to confirm a large pasted delta before the server
+/// applies it. Without an answer within `expires_in_secs` the paste is cancelled.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PasteConfirmMessage {
+    pub seq: u64,
+    pub bytes: usize,
+    pub lines: usize,
+    pub effect: String, // What confirming does, to show the user
+    pub expires_in_secs: u64,
+}
+
+/// `PasteDecisionMessage` is the client's answer to a `PasteConfirmMessage`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PasteDecisionMessage {
+    pub seq: u64,
+    pub confirm: bool,
+}
+
+/// `EditCollisionMessage` tells two users they are editing the same place at once, so their
+/// editors can flash the region and show who else is there. Both edits were applied; it is only
+/// advice. Sent on its own as `{"type":"edit_collision",...}`, not wrapped in a `ProtocolMessage`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "edit_collision")]
+pub struct EditCollisionMessage {
+    pub users: Vec<String>,
+    pub range: (usize, usize), // Byte range covering both edits, in the document at `revision`
+    pub revision: u64,
+}
+
+/// `SnapshotMessage` is the first thing a client joining a room gets: the document, its
+/// revision and its language, so every highlighter starts out the same. Sent on its own as
+/// `{"type":"snapshot",...}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "snapshot")]
+pub struct SnapshotMessage {
+    pub text: String,
+    pub revision: u64,
+    pub language: Option<String>, // `None` for plain text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<Suggestion>, // Open suggestions, to show inline
+}
+
+/// `SetLanguageMessage` asks the server to change the language of the client's room. Only
+/// editors may; the change reaches everyone as a `LanguageMessage`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "set_language")]
+pub struct SetLanguageMessage {
+    pub language: String,
+}
+
+/// `LanguageMessage` tells every client of a room, and the server's own export and diagnostics
+/// paths, that the room's language changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "language")]
+pub struct LanguageMessage {
+    pub room: String,
+    pub language: String,
+}
+
+/// `SuggestMessage` proposes replacing `range` of the document with `text`, without changing
+/// it. The range is in the document at `revision`, which must be the room's latest; the
+/// suggestion reaches everyone as a `SuggestionMessage`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "suggest")]
+pub struct SuggestMessage {
+    pub range: (usize, usize),
+    pub text: String,
+    pub revision: u64,
+}
+
+/// `ResolveSuggestionMessage` accepts or rejects a suggestion. Only editors may; an accepted
+/// suggestion is applied as an edit by the accepting user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "resolve_suggestion")]
+pub struct ResolveSuggestionMessage {
+    pub id: u64,
+    pub accept: bool,
+}
+
+/// `SuggestionMessage` tells every client of a room that a suggestion was made, or that its
+/// status changed. Clients move open suggestions along the edits they receive themselves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "suggestion")]
+pub struct SuggestionMessage {
+    pub room: String,
+    pub revision: u64, // The revision of the document `suggestion.range` is in
+    pub suggestion: Suggestion,
+}
+
+/// `RemoteDeltaMessage` carries an edit made by another client, at the revision it produced.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteDeltaMessage {
+    pub revision: u64,
+    pub operations: Vec<DiffOperation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>, // Set for edits the server made on someone's behalf, such as save hooks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint_id: Option<u64>, // Set when the edit was a bulk operation, to roll it back by
+}
+
+/// `SavedMessage` tells the saver its document was saved, at `revision`. Hooks that were
+/// skipped for failing or timing out are listed in `warnings`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "saved")]
+pub struct SavedMessage {
+    pub revision: u64,
+    pub warnings: Vec<String>,
+}
+
+/// `SaveRejectedMessage` tells the saver its document was not saved, such as when a save hook
+/// blocked it with `diagnostics`. The document keeps its edits; only the save didn't happen.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "save_rejected")]
+pub struct SaveRejectedMessage {
+    pub reason: String,
+    pub hook: Option<String>, // The hook that blocked the save
+    pub diagnostics: Vec<LintError>,
 }
 
 /// `ProtocolMessage` represents all possible messages that can be sent between peers.
@@ -62,6 +277,13 @@ impl CursorMessage {
 pub enum ProtocolMessage {
     Sync(SyncMessage),
     Cursor(CursorMessage),
+    Delta(DeltaMessage),
+    Ack(AckMessage),
+    Reject(RejectMessage),
+    Nack(NackMessage),
+    PasteConfirm(PasteConfirmMessage),
+    PasteDecision(PasteDecisionMessage),
+    RemoteDelta(RemoteDeltaMessage),
 }
 
 impl ProtocolMessage {
@@ -74,4 +296,193 @@ impl ProtocolMessage {
     pub fn from_json(json: &str) -> Result<ProtocolMessage, serde_json::Error> {
         serde_json::from_str(json)
     }
+
+    /// Serializes the protocol message to MessagePack. Structs are written as maps with their
+    /// field names, the same shape as the JSON, so optional and defaulted fields behave alike.
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        rmp_serde::to_vec_named(self)
+    }
+
+    /// Deserializes MessagePack bytes into a `ProtocolMessage`.
+    pub fn from_msgpack(bytes: &[u8]) -> Result<ProtocolMessage, rmp_serde::decode::Error> {
+        rmp_serde::from_slice(bytes)
+    }
+
+    /// Serializes the protocol message into a frame of the connection's `encoding`.
+    pub fn encode(&self, encoding: WireEncoding) -> Result<Frame, String> {
+        match encoding {
+            WireEncoding::Json => self.to_json().map(Frame::Text).map_err(|e| e.to_string()),
+            WireEncoding::MessagePack => self.to_msgpack().map(Frame::Binary).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Deserializes a frame: text frames are JSON and binary frames MessagePack, whatever was
+    /// negotiated, so a JSON message typed into the browser console still works.
+    pub fn decode(frame: &Frame) -> Result<ProtocolMessage, String> {
+        match frame {
+            Frame::Text(json) => Self::from_json(json).map_err(|e| e.to_string()),
+            Frame::Binary(bytes) => Self::from_msgpack(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Query parameter of the WebSocket handshake naming the encodings the client accepts, most
+/// preferred first (`/ws?protocol=1.0&encoding=msgpack,json`)
+pub const ENCODING_PARAM: &str = "encoding";
+
+/// How `ProtocolMessage`s travel over a connection, agreed on connect. JSON text frames are the
+/// default, as they can be read in the browser's developer tools; MessagePack binary frames
+/// cut the overhead of the many small edits.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WireEncoding {
+    #[default]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl WireEncoding {
+    /// The first of the encodings `offered` in the handshake that this server speaks, or JSON
+    /// when none is
+    pub fn negotiate(offered: Option<&str>) -> WireEncoding {
+        offered
+            .into_iter()
+            .flat_map(|offered| offered.split(','))
+            .find_map(|name| WireEncoding::from_name(name.trim()))
+            .unwrap_or_default()
+    }
+
+    /// The encoding called `name` in the handshake
+    pub fn from_name(name: &str) -> Option<WireEncoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(WireEncoding::Json),
+            "msgpack" | "messagepack" => Some(WireEncoding::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// The name of the encoding, as sent back to the client once agreed
+    pub fn name(&self) -> &'static str {
+        match self {
+            WireEncoding::Json => "json",
+            WireEncoding::MessagePack => "msgpack",
+        }
+    }
+}
+
+/// A WebSocket frame carrying a `ProtocolMessage`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config::config;
+    use proptest::prelude::*;
+
+    /// Arbitrary JSON, biased towards the shapes the decoder looks for.
+    fn json() -> impl Strategy<Value = serde_json::Value> {
+        let leaf = prop_oneof![
+            Just(serde_json::Value::Null),
+            any::<bool>().prop_map(serde_json::Value::from),
+            any::<i64>().prop_map(serde_json::Value::from),
+            any::<f64>().prop_map(serde_json::Value::from),
+            prop_oneof![Just("Sync"), Just("Delta"), Just("Ack"), Just("Insert"), Just("Delete")].prop_map(serde_json::Value::from),
+            ".{0,8}".prop_map(serde_json::Value::from),
+        ];
+        leaf.prop_recursive(4, 32, 4, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4).prop_map(serde_json::Value::from),
+                prop::collection::vec((prop_oneof![Just("type".to_string()), Just("data".to_string()), Just("seq".to_string()), Just("operations".to_string()), ".{0,4}"], inner), 0..4)
+                    .prop_map(|fields| serde_json::Value::Object(fields.into_iter().collect())),
+            ]
+        })
+    }
+
+    /// One message of every variant, with optional fields both set and unset
+    fn every_variant() -> Vec<ProtocolMessage> {
+        let operations = vec![DiffOperation::Insert(0, "fn main() {}\n".to_string()), DiffOperation::Delete(3, 7), DiffOperation::Replace(1, 2, "é".to_string())];
+        vec![
+            ProtocolMessage::Sync(SyncMessage::new(operations.clone())),
+            ProtocolMessage::Cursor(CursorMessage::new(42)),
+            ProtocolMessage::Delta(DeltaMessage { client_id: "ana".to_string(), seq: 7, base_revision: 12, paste: true, bulk: None, operations: operations.clone() }),
+            ProtocolMessage::Delta(DeltaMessage { client_id: "ana".to_string(), seq: 8, base_revision: 13, paste: false, bulk: Some(BulkOperation::ReplaceAll), operations: operations.clone() }),
+            ProtocolMessage::Ack(AckMessage { seq: 7, revision: 13, transformed: None, checkpoint_id: None }),
+            ProtocolMessage::Ack(AckMessage { seq: 8, revision: 14, transformed: Some(operations.clone()), checkpoint_id: Some(3) }),
+            ProtocolMessage::Reject(RejectMessage { seq: 9, reason: "Read-only".to_string(), quota: None }),
+            ProtocolMessage::Nack(NackMessage { seq: 11, resend_from: 10 }),
+            ProtocolMessage::PasteConfirm(PasteConfirmMessage { seq: 12, bytes: 40_000, lines: 900, effect: "Replaces the document".to_string(), expires_in_secs: 30 }),
+            ProtocolMessage::PasteDecision(PasteDecisionMessage { seq: 12, confirm: false }),
+            ProtocolMessage::RemoteDelta(RemoteDeltaMessage { revision: 15, operations: operations.clone(), author: None, checkpoint_id: None }),
+            ProtocolMessage::RemoteDelta(RemoteDeltaMessage { revision: 16, operations, author: Some("formatter".to_string()), checkpoint_id: Some(3) }),
+        ]
+    }
+
+    #[test]
+    fn test_binary_encoding_matches_json() {
+        for message in every_variant() {
+            let from_binary = ProtocolMessage::from_msgpack(&message.to_msgpack().unwrap()).unwrap();
+            let from_json = ProtocolMessage::from_json(&message.to_json().unwrap()).unwrap();
+            assert_eq!(format!("{:?}", from_binary), format!("{:?}", message));
+            assert_eq!(serde_json::to_value(&from_binary).unwrap(), serde_json::to_value(&from_json).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_frames_follow_the_encoding() {
+        let variants = every_variant();
+        let delta = &variants[2];
+        let Frame::Text(json) = delta.encode(WireEncoding::Json).unwrap() else { panic!("JSON goes in text frames") };
+        let binary = delta.encode(WireEncoding::MessagePack).unwrap();
+        let Frame::Binary(bytes) = &binary else { panic!("MessagePack goes in binary frames") };
+        assert!(bytes.len() < json.len());
+
+        // Either kind of frame decodes, whichever encoding was agreed
+        let expected = format!("{:?}", delta);
+        assert_eq!(format!("{:?}", ProtocolMessage::decode(&binary).unwrap()), expected);
+        assert_eq!(format!("{:?}", ProtocolMessage::decode(&Frame::Text(json)).unwrap()), expected);
+        assert!(ProtocolMessage::decode(&Frame::Binary(b"{}".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        assert_eq!(WireEncoding::negotiate(None), WireEncoding::Json);
+        assert_eq!(WireEncoding::negotiate(Some("msgpack")), WireEncoding::MessagePack);
+        assert_eq!(WireEncoding::negotiate(Some("cbor, MessagePack, json")), WireEncoding::MessagePack);
+        assert_eq!(WireEncoding::negotiate(Some("json,msgpack")), WireEncoding::Json);
+        assert_eq!(WireEncoding::negotiate(Some("cbor")), WireEncoding::Json);
+        assert_eq!(WireEncoding::from_name(WireEncoding::MessagePack.name()), Some(WireEncoding::MessagePack));
+    }
+
+    proptest! {
+        #![proptest_config(config(256))]
+
+        #[test]
+        fn prop_decoder_never_panics_on_bytes(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = ProtocolMessage::from_json(&String::from_utf8_lossy(&bytes));
+        }
+
+        #[test]
+        fn prop_decoder_never_panics_on_json(value in json()) {
+            let _ = ProtocolMessage::from_json(&value.to_string());
+        }
+
+        #[test]
+        fn prop_delta_round_trips(seq in any::<u64>(), base_revision in any::<u64>(), position in any::<usize>(), text in ".{0,8}") {
+            let message = ProtocolMessage::Delta(DeltaMessage { client_id: "ana".to_string(), seq, base_revision, paste: false, bulk: None, operations: vec![DiffOperation::Insert(position, text)] });
+            let decoded = ProtocolMessage::from_json(&message.to_json().unwrap()).unwrap();
+            prop_assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+            let decoded = ProtocolMessage::from_msgpack(&message.to_msgpack().unwrap()).unwrap();
+            prop_assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+        }
+
+        #[test]
+        fn prop_binary_decoder_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = ProtocolMessage::from_msgpack(&bytes);
+        }
+    }
 }
@@ -0,0 +1,233 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::networking::chat_sync::ChatMessage;
+use crate::storage::Storage;
+
+/// Storage identifier under which read markers are persisted
+const READ_RECEIPTS_ID: &str = "chat_read_receipts.json";
+
+/// Minimum interval between two "seen by" broadcasts for the same room
+pub const SEEN_BY_THROTTLE: Duration = Duration::from_secs(2);
+
+/// Last chat message a user has read in a room
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReadMarker {
+    pub user: String,
+    pub room: String,
+    pub message_id: u64,
+}
+
+/// Tracks per-(user, room) read markers for chat, persisted through a `Storage` backend
+pub struct ReadReceipts {
+    last_read: Arc<Mutex<HashMap<(String, String), u64>>>, // Keyed by (user, room)
+    last_broadcast: Arc<Mutex<HashMap<String, Instant>>>,  // Last "seen by" broadcast per room
+    storage: Option<Arc<dyn Storage + Send + Sync>>,
+}
+
+impl ReadReceipts {
+    /// Creates an in-memory ReadReceipts tracker
+    pub fn new() -> Self {
+        Self {
+            last_read: Arc::new(Mutex::new(HashMap::new())),
+            last_broadcast: Arc::new(Mutex::new(HashMap::new())),
+            storage: None,
+        }
+    }
+
+    /// Creates a ReadReceipts tracker backed by `storage`, loading any previously saved markers
+    pub fn with_storage(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        let receipts = Self { storage: Some(storage.clone()), ..Self::new() };
+
+        if let Ok(saved) = storage.load(READ_RECEIPTS_ID) {
+            let markers: Vec<ReadMarker> = serde_json::from_str(&saved).unwrap_or_default();
+            let mut last_read = receipts.last_read.lock().unwrap();
+            for marker in markers {
+                last_read.insert((marker.user, marker.room), marker.message_id);
+            }
+        }
+        receipts
+    }
+
+    /// Marks `message_id` as read by `user` in `room`. Markers only move forward, so repeated or
+    /// older reads are ignored. Returns whether the marker changed.
+    pub fn mark_read(&self, user: &str, room: &str, message_id: u64) -> bool {
+        let markers = {
+            let mut last_read = self.last_read.lock().unwrap();
+            let current = last_read.entry((user.to_string(), room.to_string())).or_insert(0);
+            if message_id <= *current {
+                return false;
+            }
+            *current = message_id;
+            Self::to_markers(&last_read)
+        };
+
+        if let Some(storage) = &self.storage {
+            match serde_json::to_string(&markers) {
+                Ok(json) => {
+                    if let Err(e) = storage.save(READ_RECEIPTS_ID, &json) {
+                        eprintln!("Failed to persist read receipts: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Failed to serialize read receipts: {}", e),
+            }
+        }
+        true
+    }
+
+    /// Returns the last message id `user` has read in `room`, if any
+    pub fn last_read(&self, user: &str, room: &str) -> Option<u64> {
+        self.last_read.lock().unwrap().get(&(user.to_string(), room.to_string())).copied()
+    }
+
+    /// Counts the messages in `room` newer than the user's marker, ignoring the user's own messages
+    pub fn unread_count(&self, user: &str, room: &str, messages: &[ChatMessage]) -> usize {
+        let last_read = self.last_read(user, room).unwrap_or(0);
+        messages
+            .iter()
+            .filter(|message| message.id > last_read && message.user.as_str() != user)
+            .count()
+    }
+
+    /// Unread counts for `user` in every room, used for the document list badges
+    pub fn unread_counts(&self, user: &str, rooms: &HashMap<String, Vec<ChatMessage>>) -> HashMap<String, usize> {
+        rooms
+            .iter()
+            .map(|(room, messages)| (room.clone(), self.unread_count(user, room, messages)))
+            .collect()
+    }
+
+    /// Number of users other than the author who have read `message` in `room`
+    pub fn seen_by(&self, room: &str, message: &ChatMessage) -> usize {
+        self.last_read
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((user, marker_room), last_read)| {
+                marker_room == room && user.as_str() != message.user.as_str() && **last_read >= message.id
+            })
+            .count()
+    }
+
+    /// Aggregated "seen by" counts for `messages`, keyed by message id
+    pub fn seen_by_counts(&self, room: &str, messages: &[ChatMessage]) -> HashMap<u64, usize> {
+        messages.iter().map(|message| (message.id, self.seen_by(room, message))).collect()
+    }
+
+    /// Whether a "seen by" broadcast for `room` is allowed at `now`; records it if so
+    pub fn should_broadcast_seen_by(&self, room: &str, now: Instant) -> bool {
+        let mut last_broadcast = self.last_broadcast.lock().unwrap();
+        match last_broadcast.get(room) {
+            Some(last) if now.duration_since(*last) < SEEN_BY_THROTTLE => false,
+            _ => {
+                last_broadcast.insert(room.to_string(), now);
+                true
+            }
+        }
+    }
+
+    /// Flattens the marker map into a serializable list
+    fn to_markers(last_read: &HashMap<(String, String), u64>) -> Vec<ReadMarker> {
+        last_read
+            .iter()
+            .map(|((user, room), message_id)| ReadMarker {
+                user: user.clone(),
+                room: room.clone(),
+                message_id: *message_id,
+            })
+            .collect()
+    }
+}
+
+impl Default for ReadReceipts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::validation::{ChatBody, Username};
+
+    fn chat(id: u64, user: &str) -> ChatMessage {
+        ChatMessage {
+            id,
+            key: None,
+            room: "doc".to_string(),
+            user: Username::try_from(user).unwrap(),
+            message: ChatBody::try_from(format!("message {}", id).as_str()).unwrap(),
+            timestamp: String::new(),
+            attachments: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_unread_count_across_reconnects() {
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::default());
+        let messages = vec![chat(1, "bob"), chat(2, "bob"), chat(3, "alice"), chat(4, "bob")];
+
+        let receipts = ReadReceipts::with_storage(storage.clone());
+        assert_eq!(receipts.unread_count("alice", "doc", &messages), 3);
+        receipts.mark_read("alice", "doc", 2);
+
+        let reloaded = ReadReceipts::with_storage(storage);
+        assert_eq!(reloaded.last_read("alice", "doc"), Some(2));
+        assert_eq!(reloaded.unread_count("alice", "doc", &messages), 1);
+    }
+
+    #[test]
+    fn test_mark_read_is_monotonic_and_idempotent() {
+        let receipts = ReadReceipts::new();
+        assert!(receipts.mark_read("alice", "doc", 5));
+        assert!(!receipts.mark_read("alice", "doc", 5));
+        assert!(!receipts.mark_read("alice", "doc", 3));
+        assert_eq!(receipts.last_read("alice", "doc"), Some(5));
+    }
+
+    #[test]
+    fn test_unread_counts_for_doc_listing() {
+        let receipts = ReadReceipts::new();
+        let mut rooms = HashMap::new();
+        rooms.insert("doc".to_string(), vec![chat(1, "bob"), chat(2, "bob")]);
+        rooms.insert("notes".to_string(), vec![chat(1, "carol")]);
+        receipts.mark_read("alice", "doc", 1);
+
+        let counts = receipts.unread_counts("alice", &rooms);
+        assert_eq!(counts["doc"], 1);
+        assert_eq!(counts["notes"], 1);
+    }
+
+    #[test]
+    fn test_seen_by_aggregation() {
+        let receipts = ReadReceipts::new();
+        let messages = vec![chat(1, "alice"), chat(2, "alice")];
+        receipts.mark_read("bob", "doc", 2);
+        receipts.mark_read("carol", "doc", 1);
+        receipts.mark_read("alice", "doc", 2); // The author does not count
+        receipts.mark_read("dave", "other", 2); // Other rooms do not count
+
+        let counts = receipts.seen_by_counts("doc", &messages);
+        assert_eq!(counts[&1], 2);
+        assert_eq!(counts[&2], 1);
+
+        let now = Instant::now();
+        assert!(receipts.should_broadcast_seen_by("doc", now));
+        assert!(!receipts.should_broadcast_seen_by("doc", now + Duration::from_millis(500)));
+        assert!(receipts.should_broadcast_seen_by("doc", now + SEEN_BY_THROTTLE));
+    }
+
+    #[test]
+    fn test_read_markers_are_per_user() {
+        let receipts = ReadReceipts::new();
+        let messages = vec![chat(1, "carol"), chat(2, "carol")];
+        receipts.mark_read("alice", "doc", 2);
+
+        assert_eq!(receipts.unread_count("alice", "doc", &messages), 0);
+        assert_eq!(receipts.unread_count("bob", "doc", &messages), 2);
+        assert_eq!(receipts.last_read("bob", "doc"), None);
+    }
+}
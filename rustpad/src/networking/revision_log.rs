@@ -0,0 +1,315 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use crate::editor::diff_engine::{DiffEngine, DiffOperation};
+use crate::networking::protocol::{AckMessage, DeltaMessage, NackMessage, PasteConfirmMessage, RejectMessage, RemoteDeltaMessage};
+
+/// What became of a delta sent to the server
+#[derive(Debug, Clone)]
+pub enum Receipt {
+    Applied(AckMessage, RemoteDeltaMessage), // Acknowledge the sender, broadcast to everyone else
+    Duplicate(AckMessage),                   // Applied before; acknowledge again and broadcast nothing
+    Gap(NackMessage),                        // Earlier deltas of the sender are missing; nothing applied
+    Held(PasteConfirmMessage),               // A large paste waiting for the sender to confirm; nothing applied yet
+}
+
+/// The sequence ids a client has used, so a retried delta gets the answer the original got
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+struct ClientSeqs {
+    last_seq: u64,             // Highest seq received from the client
+    recent: VecDeque<Outcome>, // Deltas still covered by the history, oldest first
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Outcome {
+    seq: u64,
+    revision: u64,           // The revision the delta produced, or the document's when it was refused
+    refused: Option<String>, // Why the delta was rejected
+}
+
+/// `RevisionLog` is the server's authoritative copy of a document. Incoming deltas are
+/// transformed against everything applied since their base revision, applied, and answered
+/// with an acknowledgement for the sender and a remote delta for everyone else.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RevisionLog {
+    text: String,
+    history: Vec<Vec<DiffOperation>>, // Operations applied at each revision since `trimmed`, oldest first
+    #[serde(default)]
+    trimmed: u64, // Revisions dropped from the front of `history`
+    #[serde(default)]
+    clients: HashMap<String, ClientSeqs>, // Keyed by client id
+}
+
+impl RevisionLog {
+    /// Creates a log for a document at revision 0.
+    pub fn new(text: &str) -> Self {
+        RevisionLog {
+            text: text.to_string(),
+            history: Vec::new(),
+            trimmed: 0,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Applies `delta`, or rejects it when its base revision is unknown or its operations
+    /// don't fit the document. A rejected delta leaves the document untouched.
+    ///
+    /// Deltas with a client id are applied at most once and in the order of their sequence ids.
+    /// A seq seen before gets the original answer again: the revision it was applied at, or its
+    /// rejection. A seq past the next expected one is not applied, and the client is asked to
+    /// resend from the missing one.
+    pub fn receive(&mut self, delta: &DeltaMessage) -> Result<Receipt, RejectMessage> {
+        if let Some(answer) = self.replay(delta) {
+            return answer;
+        }
+        let result = self.apply(delta);
+        self.record(delta, result.as_ref().err().map(|reject| reject.reason.clone()));
+        result.map(|(ack, remote)| Receipt::Applied(ack, remote))
+    }
+
+    /// Whether `delta` is the next one expected from its client, rather than a retry or ahead
+    /// of a missing one
+    pub fn is_next(&self, delta: &DeltaMessage) -> bool {
+        self.replay(delta).is_none()
+    }
+
+    /// Refuses the next delta of a client without applying it, for policies the log doesn't
+    /// know about. Like a rejection, the refusal is remembered for retries of the same seq.
+    pub fn refuse(&mut self, delta: &DeltaMessage, reason: &str) -> RejectMessage {
+        self.record(delta, Some(reason.to_string()));
+        RejectMessage { seq: delta.seq, reason: reason.to_string(), quota: None }
+    }
+
+    /// Remembers what became of the next delta of a client
+    fn record(&mut self, delta: &DeltaMessage, refused: Option<String>) {
+        if !delta.client_id.is_empty() {
+            let revision = self.revision();
+            let client = self.clients.entry(delta.client_id.clone()).or_default();
+            client.last_seq = delta.seq;
+            client.recent.push_back(Outcome { seq: delta.seq, revision, refused });
+        }
+    }
+
+    /// The answer to a delta whose seq its client has used before or skipped past, or `None`
+    /// when it is the next one expected. Duplicates older than the history are acknowledged at
+    /// the oldest revision kept, since the one they were applied at is gone.
+    fn replay(&self, delta: &DeltaMessage) -> Option<Result<Receipt, RejectMessage>> {
+        let client = self.clients.get(&delta.client_id)?;
+        if delta.seq == client.last_seq.wrapping_add(1) {
+            return None;
+        }
+        if delta.seq > client.last_seq {
+            return Some(Ok(Receipt::Gap(NackMessage { seq: delta.seq, resend_from: client.last_seq + 1 })));
+        }
+        let ack = match client.recent.iter().find(|outcome| outcome.seq == delta.seq) {
+            Some(Outcome { refused: Some(reason), .. }) => return Some(Err(RejectMessage { seq: delta.seq, reason: reason.clone(), quota: None })),
+            Some(outcome) => AckMessage {
+                seq: delta.seq,
+                revision: outcome.revision,
+                transformed: Some(self.history[(outcome.revision - self.trimmed - 1) as usize].clone()),
+                checkpoint_id: None,
+            },
+            None => AckMessage { seq: delta.seq, revision: self.trimmed, transformed: None, checkpoint_id: None },
+        };
+        Some(Ok(Receipt::Duplicate(ack)))
+    }
+
+    fn apply(&mut self, delta: &DeltaMessage) -> Result<(AckMessage, RemoteDeltaMessage), RejectMessage> {
+        let reject = |reason: String| RejectMessage { seq: delta.seq, reason, quota: None };
+        if delta.base_revision > self.revision() {
+            return Err(reject(format!("Unknown base revision {}", delta.base_revision)));
+        }
+        if delta.base_revision < self.trimmed {
+            return Err(reject(format!("Revision {} is no longer kept; reload the document", delta.base_revision)));
+        }
+        if let Some(operation) = delta.operations.iter().find(|operation| !is_well_formed(operation)) {
+            return Err(reject(format!("Malformed operation {:?}", operation)));
+        }
+
+        let mut operations = delta.operations.clone();
+        for applied in &self.history[(delta.base_revision - self.trimmed) as usize..] {
+            operations = DiffEngine::transform(&operations, applied, false).0;
+        }
+        check_fits(&self.text, &operations).map_err(reject)?;
+
+        self.text = DiffEngine::apply(&self.text, &operations);
+        self.history.push(operations.clone());
+
+        let revision = self.revision();
+        let transformed = if operations != delta.operations { Some(operations.clone()) } else { None };
+        Ok((
+            AckMessage { seq: delta.seq, revision, transformed, checkpoint_id: None },
+            RemoteDeltaMessage { revision, operations, author: None, checkpoint_id: None },
+        ))
+    }
+
+    /// The current document.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The revision of the current document; 0 before any delta was applied.
+    pub fn revision(&self) -> u64 {
+        self.trimmed + self.history.len() as u64
+    }
+
+    /// The oldest revision deltas may still be based on.
+    pub fn oldest_revision(&self) -> u64 {
+        self.trimmed
+    }
+
+    /// The operations applied at each revision after `revision`, oldest first; `None` once the
+    /// history from `revision` on was trimmed.
+    pub fn operations_since(&self, revision: u64) -> Option<&[Vec<DiffOperation>]> {
+        let skip = revision.checked_sub(self.trimmed)?;
+        self.history.get(skip as usize..)
+    }
+
+    /// Drops the history deltas based before `revision` would need. Later deltas still apply.
+    pub fn trim_before(&mut self, revision: u64) {
+        let drop = revision.clamp(self.trimmed, self.revision()) - self.trimmed;
+        self.history.drain(..drop as usize);
+        self.trimmed += drop;
+        for client in self.clients.values_mut() {
+            client.recent.retain(|outcome| outcome.revision > self.trimmed);
+        }
+    }
+
+    /// Approximate bytes held by the history from `revision` on.
+    pub fn history_bytes_since(&self, revision: u64) -> usize {
+        let skip = revision.saturating_sub(self.trimmed) as usize;
+        self.history.iter().skip(skip).flatten().map(operation_bytes).sum()
+    }
+}
+
+/// Approximate memory used by one operation: its text plus the positions and enum tag.
+fn operation_bytes(operation: &DiffOperation) -> usize {
+    let text = match operation {
+        DiffOperation::Insert(_, text) | DiffOperation::Replace(_, _, text) => text.len(),
+        DiffOperation::Delete(_, _) => 0,
+    };
+    text + std::mem::size_of::<DiffOperation>()
+}
+
+fn is_well_formed(operation: &DiffOperation) -> bool {
+    match operation {
+        DiffOperation::Delete(start, end) | DiffOperation::Replace(start, end, _) => start <= end,
+        DiffOperation::Insert(_, _) => true,
+    }
+}
+
+/// Checks that each operation, applied in order, stays inside the text and on character boundaries.
+fn check_fits(text: &str, operations: &[DiffOperation]) -> Result<(), String> {
+    let mut text = text.to_string();
+    for operation in operations {
+        let (start, end) = match operation {
+            DiffOperation::Insert(position, _) => (*position, *position),
+            DiffOperation::Delete(start, end) | DiffOperation::Replace(start, end, _) => (*start, *end),
+        };
+        if end > text.len() || !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+            return Err(format!("Operation {:?} does not fit the document", operation));
+        }
+        text = DiffEngine::apply(&text, std::slice::from_ref(operation));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_config::config;
+    use proptest::prelude::*;
+
+    fn operation() -> impl Strategy<Value = DiffOperation> {
+        prop_oneof![
+            (0..12usize, "[aé]{0,3}").prop_map(|(position, text)| DiffOperation::Insert(position, text)),
+            (0..12usize, 0..12usize).prop_map(|(start, end)| DiffOperation::Delete(start, end)),
+            (0..12usize, 0..12usize, "[bß]{0,3}").prop_map(|(start, end, text)| DiffOperation::Replace(start, end, text)),
+        ]
+    }
+
+    #[test]
+    fn test_transforms_against_history() {
+        let mut log = RevisionLog::new("abc");
+        let first = DeltaMessage { client_id: String::new(), seq: 0, base_revision: 0, paste: false, bulk: None, operations: vec![DiffOperation::Insert(0, "X".to_string())] };
+        let second = DeltaMessage { client_id: String::new(), seq: 0, base_revision: 0, paste: false, bulk: None, operations: vec![DiffOperation::Insert(3, "Y".to_string())] };
+
+        log.receive(&first).unwrap();
+        let Receipt::Applied(ack, remote) = log.receive(&second).unwrap() else { panic!("not applied") };
+        assert_eq!(log.text(), "XabcY");
+        assert_eq!(ack.revision, 2);
+        assert_eq!(ack.transformed, Some(vec![DiffOperation::Insert(4, "Y".to_string())]));
+        assert_eq!(remote.operations, vec![DiffOperation::Insert(4, "Y".to_string())]);
+    }
+
+    #[test]
+    fn test_rejects_deltas_that_do_not_fit() {
+        // Found by fuzzing: a future base revision sliced past the end of the history, and an
+        // insertion inside a multi-byte character panicked in `String::replace_range`
+        let mut log = RevisionLog::new("é");
+        let future = DeltaMessage { client_id: String::new(), seq: 1, base_revision: 5, paste: false, bulk: None, operations: Vec::new() };
+        let split = DeltaMessage { client_id: String::new(), seq: 2, base_revision: 0, paste: false, bulk: None, operations: vec![DiffOperation::Insert(1, "x".to_string())] };
+        let backwards = DeltaMessage { client_id: String::new(), seq: 3, base_revision: 0, paste: false, bulk: None, operations: vec![DiffOperation::Delete(2, 0)] };
+
+        assert_eq!(log.receive(&future).unwrap_err().seq, 1);
+        assert_eq!(log.receive(&split).unwrap_err().seq, 2);
+        assert_eq!(log.receive(&backwards).unwrap_err().seq, 3);
+        assert_eq!(log.text(), "é");
+        assert_eq!(log.revision(), 0);
+    }
+
+    #[test]
+    fn test_duplicate_delivery_applies_once() {
+        let mut log = RevisionLog::new("abc");
+        let delta = DeltaMessage { client_id: "ana".to_string(), seq: 7, base_revision: 0, paste: false, bulk: None, operations: vec![DiffOperation::Insert(3, "!".to_string())] };
+        let other = DeltaMessage { client_id: "bob".to_string(), seq: 0, base_revision: 0, paste: false, bulk: None, operations: vec![DiffOperation::Insert(0, ">".to_string())] };
+
+        assert!(matches!(log.receive(&delta), Ok(Receipt::Applied(..))));
+        log.receive(&other).unwrap();
+        let Receipt::Duplicate(ack) = log.receive(&delta).unwrap() else { panic!("applied twice") };
+        assert_eq!((ack.seq, ack.revision), (7, 1));
+        assert_eq!(ack.transformed, Some(vec![DiffOperation::Insert(3, "!".to_string())]));
+        assert_eq!(log.text(), ">abc!");
+        assert_eq!(log.revision(), 2);
+
+        // A refused delta is refused again rather than applied on retry
+        let bad = DeltaMessage { client_id: "ana".to_string(), seq: 8, base_revision: 9, paste: false, bulk: None, operations: Vec::new() };
+        let reason = log.receive(&bad).unwrap_err().reason;
+        assert_eq!(log.receive(&bad).unwrap_err().reason, reason);
+    }
+
+    #[test]
+    fn test_gap_asks_for_resend() {
+        let mut log = RevisionLog::new("");
+        let insert = |seq: u64, base_revision: u64| DeltaMessage { client_id: "ana".to_string(), seq, base_revision, paste: false, bulk: None, operations: vec![DiffOperation::Insert(0, seq.to_string())] };
+        log.receive(&insert(0, 0)).unwrap();
+
+        let Receipt::Gap(nack) = log.receive(&insert(2, 1)).unwrap() else { panic!("applied out of order") };
+        assert_eq!((nack.seq, nack.resend_from), (2, 1));
+        assert_eq!(log.revision(), 1);
+
+        assert!(matches!(log.receive(&insert(1, 1)), Ok(Receipt::Applied(..))));
+        assert!(matches!(log.receive(&insert(2, 2)), Ok(Receipt::Applied(..))));
+        assert_eq!(log.text(), "210");
+    }
+
+    proptest! {
+        #![proptest_config(config(256))]
+
+        /// Arbitrary deltas never panic, and the text always matches the applied history.
+        #[test]
+        fn prop_arbitrary_deltas_keep_log_consistent(
+            deltas in prop::collection::vec((0..4u64, prop::collection::vec(operation(), 0..4)), 0..12)
+        ) {
+            let mut log = RevisionLog::new("héllo");
+            let mut replayed = "héllo".to_string();
+            for (seq, (base_revision, operations)) in deltas.into_iter().enumerate() {
+                let delta = DeltaMessage { client_id: String::new(), seq: seq as u64, base_revision, paste: false, bulk: None, operations };
+                if let Ok(Receipt::Applied(ack, remote)) = log.receive(&delta) {
+                    prop_assert_eq!(ack.revision, log.revision());
+                    replayed = DiffEngine::apply(&replayed, &remote.operations);
+                }
+                prop_assert_eq!(log.text(), replayed.as_str());
+            }
+        }
+    }
+}
@@ -0,0 +1,2228 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+use crate::auth::auth::authenticated_user;
+use crate::editor::diff_engine::{DiffEngine, DiffOperation};
+use crate::editor::language::{language_for_path, validate_language};
+use crate::editor::save_hooks::{SaveHooks, SaveHooksConfig, SaveKind, SAVE_HOOKS_ACTOR, SAVE_HOOKS_KEY};
+use crate::networking::chat_sync::ChatMessage;
+use crate::networking::moderation::{ModerationSettings, MODERATION_KEY};
+use crate::networking::protocol::{AckMessage, BulkOperation, DeltaMessage, EditCollisionMessage, LanguageMessage, PasteConfirmMessage, PasteDecisionMessage, RejectMessage, RemoteDeltaMessage, ResolveSuggestionMessage, SaveRejectedMessage, SavedMessage, SnapshotMessage, SuggestMessage, SuggestionMessage};
+use crate::networking::revision_log::{Receipt, RevisionLog};
+use crate::networking::suggestions::{Suggestion, SuggestionManager, SuggestionStatus};
+use crate::storage::history::HistoryManager;
+use crate::storage::quota::QuotaManager;
+use crate::storage::workspace::{PermissionCache, Workspaces};
+use crate::storage::Storage;
+use crate::tokens;
+
+/// Metadata key holding a room's language, the one every highlighter and the server's export
+/// and diagnostics go by
+pub const LANGUAGE_KEY: &str = "language";
+
+/// Metadata key holding a room's title, set through `set_metadata`
+pub const TITLE_KEY: &str = "title";
+
+/// Memory caps for each room, and how long a room without connections stays loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryLimits {
+    pub max_edit_log_bytes: usize, // Past this, history no client can resume from is dropped
+    pub max_chat_bytes: usize,     // Past this, chat older than `chat_window` is flushed to storage
+    pub chat_window: usize,        // Recent chat messages kept in memory
+    pub resume_window: u64,        // Recent revisions kept for clients that reconnect
+    pub idle_eviction: Duration,   // How long an empty room stays loaded before it is unloaded
+}
+
+impl MemoryLimits {
+    /// Limits suited to a server hosting a few hundred active rooms
+    pub fn new() -> Self {
+        Self {
+            max_edit_log_bytes: 1024 * 1024,
+            max_chat_bytes: 256 * 1024,
+            chat_window: 200,
+            resume_window: 500,
+            idle_eviction: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl Default for MemoryLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How pasted deltas are handled: small pastes apply like any edit, large ones wait for the
+/// sender to confirm them, and huge ones are refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PastePolicy {
+    pub confirm_above: usize,      // Pastes inserting more bytes than this need confirming
+    pub max_bytes: usize,          // Pastes inserting more bytes than this are refused outright
+    pub confirm_timeout: Duration, // Unconfirmed pastes are cancelled after this
+}
+
+impl PastePolicy {
+    /// Confirmation above 100KB, refusal above 5MB
+    pub fn new() -> Self {
+        Self { confirm_above: 100 * 1024, max_bytes: 5 * 1024 * 1024, confirm_timeout: Duration::from_secs(60) }
+    }
+}
+
+impl Default for PastePolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// When edits from different users count as colliding, and how often a user is told about it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CollisionPolicy {
+    pub proximity: usize,   // Edits this many bytes apart or closer collide
+    pub window: Duration,   // Only edits this close in time collide
+    pub cooldown: Duration, // Each user gets at most one advisory per cooldown
+}
+
+impl CollisionPolicy {
+    /// Edits within 8 bytes and 3 seconds, one advisory per user every 30 seconds
+    pub fn new() -> Self {
+        Self { proximity: 8, window: Duration::from_secs(3), cooldown: Duration::from_secs(30) }
+    }
+}
+
+impl Default for CollisionPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Who may suggest edits: editors always, and viewers too unless `viewers_may_suggest` is off
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SuggestionPolicy {
+    pub viewers_may_suggest: bool,
+}
+
+impl SuggestionPolicy {
+    /// Viewers may suggest
+    pub fn new() -> Self {
+        Self { viewers_may_suggest: true }
+    }
+}
+
+impl Default for SuggestionPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How far the edits made since a bulk operation may overlap what it wrote before rolling it
+/// back would undo them too, and is refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RollbackPolicy {
+    pub max_overlap: usize, // Bytes of the operation's text later edits deleted or wrote into
+}
+
+impl RollbackPolicy {
+    /// Any overlap refuses the rollback
+    pub fn new() -> Self {
+        Self { max_overlap: 0 }
+    }
+}
+
+impl Default for RollbackPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checkpoints kept per room; the oldest are dropped first
+const MAX_CHECKPOINTS: usize = 10;
+
+/// Bulk checkpoints kept per room; older bulk operations can't be rolled back
+const MAX_BULK_CHECKPOINTS: usize = 20;
+
+/// The document as it was before a large change, to revert to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub name: String,
+    pub revision: u64,
+    pub text: String,
+}
+
+/// A bulk operation applied to a room, with what it takes to roll it back for everyone
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BulkCheckpoint {
+    pub id: u64,
+    pub operation: BulkOperation,
+    pub actor: String,
+    pub revision: u64,                 // The revision the operation produced
+    pub inverse: Vec<DiffOperation>,   // Turns the document at `revision` back into the one before
+    pub ranges: Vec<(usize, usize)>,   // What the operation wrote, in the document at `revision`
+    pub version_id: Option<usize>,     // The version from before it, when the host keeps a history
+    #[serde(default)]
+    pub rolled_back: bool,
+}
+
+/// Why `RoomHost::rollback` didn't roll a bulk operation back
+#[derive(Debug, Clone, PartialEq)]
+pub enum RollbackError {
+    Forbidden(String),
+    NotFound(String),
+    Conflict(Vec<(usize, usize)>), // The ranges of the current document later edits overlap the operation in
+    Refused(String),
+}
+
+impl From<String> for RollbackError {
+    fn from(reason: String) -> Self {
+        RollbackError::NotFound(reason) // Only the room not being open, from `change_and_save`
+    }
+}
+
+/// Approximate bytes a room holds in memory
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct MemoryUsage {
+    pub document: usize,
+    pub edit_log: usize,      // History older than any client could resume from; trimmable
+    pub chat: usize,
+    pub resume_buffer: usize, // History connected and reconnecting clients may still need
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.document + self.edit_log + self.chat + self.resume_buffer
+    }
+}
+
+/// A room in the admin rooms list
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomSummary {
+    pub room: String,
+    pub connections: usize,
+    pub revision: u64,
+    pub idle_secs: u64, // Since the last join, leave or edit
+    pub memory: MemoryUsage,
+}
+
+/// What `GET /api/docs/:id/meta` tells about a room
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomMeta {
+    pub id: String,
+    pub revision: u64,
+    pub language: Option<String>, // `None` for plain text
+    pub saved_revision: u64,      // Behind `revision` while the document has unsaved edits
+}
+
+/// What became of a `RoomHost::save`
+#[derive(Debug, Clone)]
+pub struct SaveReceipt {
+    pub reply: Result<SavedMessage, SaveRejectedMessage>, // For the saver
+    pub edit: Option<RemoteDeltaMessage>,                 // What the save hooks changed, for everyone in the room
+}
+
+/// What a room saves to storage when it is unloaded, and restores on the next join
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RoomState {
+    log: RevisionLog,
+    chat: Vec<ChatMessage>, // The recent window; older messages are in the chat archive
+    metadata: HashMap<String, String>,
+    #[serde(default)]
+    checkpoints: Vec<Checkpoint>, // Oldest first
+    #[serde(default)]
+    saved_revision: u64, // The revision last saved through `RoomHost::save`
+    #[serde(default)]
+    suggestions: SuggestionManager,
+    #[serde(default)]
+    bulk_checkpoints: Vec<BulkCheckpoint>, // Oldest first
+    #[serde(default)]
+    last_bulk_checkpoint: u64, // Ids count up from 1 and are never reused
+}
+
+struct Room {
+    state: RoomState,
+    clients: HashMap<String, u64>, // Connected clients and the last revision each has
+    users: HashMap<String, String>, // User each connected client edits as
+    pending_pastes: HashMap<String, PendingPaste>, // Large pastes awaiting confirmation, by client
+    recent_edits: Vec<RecentEdit>, // Edits within the collision window
+    advised: HashMap<String, Instant>, // When each user was last sent a collision advisory
+    advisories: Vec<(String, EditCollisionMessage)>, // Advisories awaiting delivery, by client
+    last_active: Instant,
+    unloading: bool, // Being saved for eviction; any join or change cancels the eviction
+}
+
+struct PendingPaste {
+    delta: DeltaMessage,
+    expires: Instant,
+}
+
+/// An applied edit, kept for a while to detect others editing the same place
+struct RecentEdit {
+    client_id: String,
+    user: String,
+    range: (usize, usize), // In the current document; moved along by every later edit
+    at: Instant,
+}
+
+/// Keeps rooms in memory while they are used, within `MemoryLimits`, and unloads idle ones to
+/// storage. Rooms are loaded again transparently on the next join.
+#[derive(Clone)]
+pub struct RoomHost {
+    rooms: Arc<Mutex<HashMap<String, Room>>>,
+    storage: Arc<dyn Storage + Send + Sync>,
+    limits: MemoryLimits,
+    paste_policy: PastePolicy,
+    collision_policy: CollisionPolicy,
+    suggestion_policy: SuggestionPolicy,
+    rollback_policy: RollbackPolicy,
+    quotas: Option<QuotaManager>, // Large pastes must fit the quota of the document's owner
+    history: Option<Arc<Mutex<HistoryManager>>>, // Keeps the document from before each bulk operation
+    evicting: Arc<Mutex<()>>, // Held for a whole eviction pass, so passes never interleave
+    languages: broadcast::Sender<LanguageMessage>,
+    rollbacks: broadcast::Sender<(String, RemoteDeltaMessage)>,
+}
+
+impl RoomHost {
+    /// Creates a host saving unloaded rooms and flushed chat to `storage`
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>, limits: MemoryLimits) -> Self {
+        Self {
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            storage,
+            limits,
+            paste_policy: PastePolicy::new(),
+            collision_policy: CollisionPolicy::new(),
+            suggestion_policy: SuggestionPolicy::new(),
+            rollback_policy: RollbackPolicy::new(),
+            quotas: None,
+            history: None,
+            evicting: Arc::new(Mutex::new(())),
+            languages: broadcast::channel(256).0,
+            rollbacks: broadcast::channel(256).0,
+        }
+    }
+
+    /// Handles pasted deltas according to `paste_policy`
+    pub fn with_paste_policy(self, paste_policy: PastePolicy) -> Self {
+        Self { paste_policy, ..self }
+    }
+
+    /// Detects edit collisions according to `collision_policy`
+    pub fn with_collision_policy(self, collision_policy: CollisionPolicy) -> Self {
+        Self { collision_policy, ..self }
+    }
+
+    /// Takes suggestions according to `suggestion_policy`
+    pub fn with_suggestion_policy(self, suggestion_policy: SuggestionPolicy) -> Self {
+        Self { suggestion_policy, ..self }
+    }
+
+    /// Refuses rollbacks of bulk operations according to `rollback_policy`
+    pub fn with_rollback_policy(self, rollback_policy: RollbackPolicy) -> Self {
+        Self { rollback_policy, ..self }
+    }
+
+    /// Adds the document from before each bulk operation to `history`, as a version tagged with
+    /// the operation and who ran it
+    pub fn with_history(self, history: Arc<Mutex<HistoryManager>>) -> Self {
+        Self { history: Some(history), ..self }
+    }
+
+    /// Refuses pastes large enough to need confirming when they would take the owner of the
+    /// room's document over their storage quota
+    pub fn with_quotas(self, quotas: QuotaManager) -> Self {
+        Self { quotas: Some(quotas), ..self }
+    }
+
+    /// Connects `client_id` to `room_id`, loading the room from storage if it was unloaded or
+    /// creating it empty. Returns the document and its revision.
+    pub fn join(&self, room_id: &str, client_id: &str, now: Instant) -> Result<(String, u64), String> {
+        self.join_as(room_id, client_id, client_id, now)
+    }
+
+    /// Like `join`, for a client editing as `user`. Edits from the same user's connections never
+    /// count as colliding with each other.
+    pub fn join_as(&self, room_id: &str, client_id: &str, user: &str, now: Instant) -> Result<(String, u64), String> {
+        // Loading happens under the lock: an eviction only removes a room once it is saved, so a
+        // room that isn't in the map is complete in storage
+        let mut rooms = self.rooms.lock().unwrap();
+        if !rooms.contains_key(room_id) {
+            let state = self.load_state(room_id)?;
+            let room = Room {
+                state,
+                clients: HashMap::new(),
+                users: HashMap::new(),
+                pending_pastes: HashMap::new(),
+                recent_edits: Vec::new(),
+                advised: HashMap::new(),
+                advisories: Vec::new(),
+                last_active: now,
+                unloading: false,
+            };
+            rooms.insert(room_id.to_string(), room);
+        }
+
+        let room = rooms.get_mut(room_id).unwrap();
+        let revision = room.state.log.revision();
+        room.clients.insert(client_id.to_string(), revision);
+        room.users.insert(client_id.to_string(), user.to_string());
+        room.last_active = now;
+        room.unloading = false;
+        Ok((room.state.log.text().to_string(), revision))
+    }
+
+    /// Disconnects `client_id`, dropping any paste it hadn't confirmed and any advisory not yet
+    /// delivered to it; the room stays loaded until it has been idle for `idle_eviction`
+    pub fn leave(&self, room_id: &str, client_id: &str, now: Instant) {
+        if let Some(room) = self.rooms.lock().unwrap().get_mut(room_id) {
+            room.clients.remove(client_id);
+            room.users.remove(client_id);
+            room.pending_pastes.remove(client_id);
+            room.recent_edits.retain(|edit| edit.client_id != client_id);
+            room.advisories.retain(|(client, _)| client != client_id);
+            room.last_active = now;
+        }
+    }
+
+    /// Applies a delta from `client_id`, then trims the room if it went over its caps. The delta
+    /// counts against the connection's client id whatever it claims, and the seqs it has seen
+    /// are saved with the room, so retries after a restart are still recognized.
+    ///
+    /// Pastes over `PastePolicy::max_bytes` are refused, and pastes over `confirm_above` are
+    /// held until `resolve_paste`; the sender is asked to confirm with the `Held` receipt.
+    /// Those that would go over the quota of the document's owner are refused with it.
+    ///
+    /// Deltas marked `bulk` are kept as a bulk checkpoint to `rollback`, and acknowledged and
+    /// broadcast with its id.
+    pub fn receive(&self, room_id: &str, client_id: &str, delta: &DeltaMessage, now: Instant) -> Result<Receipt, RejectMessage> {
+        let delta = DeltaMessage { client_id: client_id.to_string(), ..delta.clone() };
+        let (seq, policy, collisions) = (delta.seq, self.paste_policy, self.collision_policy);
+        // Counting the quota reads the workspaces and storage, so it happens outside the rooms lock
+        let exceeded = match &self.quotas {
+            Some(quotas) if delta.paste => {
+                let bytes: usize = delta.inserted_text().map(str::len).sum();
+                let owner = quotas.owner_of(room_id).filter(|_| bytes > policy.confirm_above);
+                owner.and_then(|owner| quotas.check_bytes(&owner, bytes as u64).err())
+            }
+            _ => None,
+        };
+        self.with_room(room_id, now, |room| {
+            if let Some(reason) = Self::editing_restricted(room, client_id) {
+                if room.state.log.is_next(&delta) {
+                    return Err(room.state.log.refuse(&delta, &reason));
+                }
+            }
+            if delta.paste && room.state.log.is_next(&delta) {
+                let bytes: usize = delta.inserted_text().map(str::len).sum();
+                if bytes > policy.max_bytes {
+                    let reason = format!("Pastes are limited to {} bytes; this one has {}", policy.max_bytes, bytes);
+                    return Err(room.state.log.refuse(&delta, &reason));
+                }
+                if let Some(exceeded) = exceeded {
+                    let reject = room.state.log.refuse(&delta, &exceeded.to_string());
+                    return Err(RejectMessage { quota: Some(exceeded), ..reject });
+                }
+                if bytes > policy.confirm_above {
+                    let request = PasteConfirmMessage {
+                        seq: delta.seq,
+                        bytes,
+                        lines: delta.inserted_text().map(|text| text.matches('\n').count()).sum::<usize>() + 1,
+                        effect: "The paste will be sent to everyone in the room and kept in its history. A checkpoint is saved first, so the room can be reverted.".to_string(),
+                        expires_in_secs: policy.confirm_timeout.as_secs(),
+                    };
+                    room.pending_pastes.insert(client_id.to_string(), PendingPaste { delta, expires: now + policy.confirm_timeout });
+                    return Ok(Receipt::Held(request));
+                }
+            }
+            match delta.bulk {
+                Some(operation) => {
+                    let before = room.state.log.text().to_string();
+                    let receipt = Self::apply(room, client_id, &delta, collisions, now)?;
+                    let actor = room.users.get(client_id).cloned().unwrap_or_else(|| client_id.to_string());
+                    Ok(self.record_bulk(room_id, room, receipt, &before, operation, &actor))
+                }
+                None => Self::apply(room, client_id, &delta, collisions, now),
+            }
+        })
+        .unwrap_or_else(|| Err(RejectMessage { seq, reason: format!("Room {} is not open", room_id), quota: None }))
+    }
+
+    /// Applies or cancels the paste `client_id` was asked to confirm. A confirmed paste is applied
+    /// after checkpointing the document, so the room can be reverted to before it, and is kept
+    /// as a bulk checkpoint to `rollback`.
+    pub fn resolve_paste(&self, room_id: &str, client_id: &str, decision: &PasteDecisionMessage, now: Instant) -> Result<Receipt, RejectMessage> {
+        let not_awaiting = || RejectMessage { seq: decision.seq, reason: "No paste is awaiting confirmation".to_string(), quota: None };
+        let collisions = self.collision_policy;
+        self.with_room(room_id, now, |room| {
+            let pending = match room.pending_pastes.get(client_id) {
+                Some(pending) if pending.delta.seq == decision.seq => room.pending_pastes.remove(client_id).unwrap(),
+                _ => return Err(not_awaiting()),
+            };
+            if now >= pending.expires {
+                return Err(room.state.log.refuse(&pending.delta, "Paste confirmation timed out"));
+            }
+            if !decision.confirm {
+                return Err(room.state.log.refuse(&pending.delta, "Paste cancelled"));
+            }
+            Self::checkpoint(room, &format!("Before paste by {}", client_id));
+            let before = room.state.log.text().to_string();
+            let receipt = Self::apply(room, client_id, &pending.delta, collisions, now)?;
+            let actor = room.users.get(client_id).cloned().unwrap_or_else(|| client_id.to_string());
+            Ok(self.record_bulk(room_id, room, receipt, &before, pending.delta.bulk.unwrap_or(BulkOperation::Paste), &actor))
+        })
+        .unwrap_or_else(|| Err(not_awaiting()))
+    }
+
+    /// Cancels pastes whose confirmation timed out by `now`, returning the room, client and
+    /// rejection to send for each. Meant to be called periodically by the connection layer.
+    pub fn expire_pastes(&self, now: Instant) -> Vec<(String, String, RejectMessage)> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut expired = Vec::new();
+        for (room_id, room) in rooms.iter_mut() {
+            let clients: Vec<String> = room.pending_pastes.iter().filter(|(_, pending)| now >= pending.expires).map(|(client, _)| client.clone()).collect();
+            for client_id in clients {
+                let pending = room.pending_pastes.remove(&client_id).unwrap();
+                let reject = room.state.log.refuse(&pending.delta, "Paste confirmation timed out");
+                expired.push((room_id.clone(), client_id, reject));
+            }
+        }
+        expired
+    }
+
+    /// Checkpoints of the room's document, oldest first
+    pub fn checkpoints(&self, room_id: &str) -> Option<Vec<Checkpoint>> {
+        self.rooms.lock().unwrap().get(room_id).map(|room| room.state.checkpoints.clone())
+    }
+
+    /// Reverts the document to the checkpoint taken at `revision`, as a server edit by `user` to
+    /// broadcast like any other. The revert is kept as a bulk checkpoint, so it can be rolled
+    /// back in turn.
+    pub fn revert_to_checkpoint(&self, room_id: &str, revision: u64, user: &str, now: Instant) -> Result<Receipt, RejectMessage> {
+        let unknown = || RejectMessage { seq: 0, reason: format!("No checkpoint at revision {}", revision), quota: None };
+        self.with_room(room_id, now, |room| {
+            let checkpoint = room.state.checkpoints.iter().find(|checkpoint| checkpoint.revision == revision).ok_or_else(unknown)?;
+            let log = &mut room.state.log;
+            let before = log.text().to_string();
+            let operations = DiffEngine::diff(&before, &checkpoint.text);
+            let receipt = log.receive(&DeltaMessage { client_id: String::new(), seq: 0, base_revision: log.revision(), paste: false, bulk: None, operations })?;
+            let receipt = match receipt {
+                Receipt::Applied(ack, remote) => {
+                    for edit in room.recent_edits.iter_mut() {
+                        edit.range = map_range(edit.range, &remote.operations);
+                    }
+                    room.state.suggestions.remap(&remote.operations);
+                    Receipt::Applied(ack, RemoteDeltaMessage { author: Some(user.to_string()), ..remote })
+                }
+                receipt => receipt,
+            };
+            Ok(self.record_bulk(room_id, room, receipt, &before, BulkOperation::Revert, user))
+        })
+        .unwrap_or_else(|| Err(unknown()))
+    }
+
+    /// Keeps the current document as a checkpoint, dropping the oldest beyond `MAX_CHECKPOINTS`
+    fn checkpoint(room: &mut Room, name: &str) {
+        let checkpoint = Checkpoint { name: name.to_string(), revision: room.state.log.revision(), text: room.state.log.text().to_string() };
+        room.state.checkpoints.push(checkpoint);
+        let excess = room.state.checkpoints.len().saturating_sub(MAX_CHECKPOINTS);
+        room.state.checkpoints.drain(..excess);
+    }
+
+    /// Keeps the applied bulk `operation` by `actor`, which turned `before` into the current
+    /// document, as a bulk checkpoint, dropping the oldest beyond `MAX_BULK_CHECKPOINTS`, and
+    /// adds `before` to the history when the host keeps one. The receipt gets the checkpoint's
+    /// id; receipts of deltas that weren't applied are returned as they are.
+    fn record_bulk(&self, room_id: &str, room: &mut Room, receipt: Receipt, before: &str, operation: BulkOperation, actor: &str) -> Receipt {
+        let Receipt::Applied(ack, remote) = receipt else { return receipt };
+        let version_id = self.history.as_ref().and_then(|history| {
+            let version = history.lock().unwrap().add_bulk_version_at(room_id, before, operation.name(), actor, Utc::now());
+            version.map_err(|e| eprintln!("Failed to keep the version of {} before a {}: {}", room_id, operation.name(), e)).ok()
+        });
+        room.state.last_bulk_checkpoint += 1;
+        let id = room.state.last_bulk_checkpoint;
+        room.state.bulk_checkpoints.push(BulkCheckpoint {
+            id,
+            operation,
+            actor: actor.to_string(),
+            revision: remote.revision,
+            inverse: inverse(before, &remote.operations),
+            ranges: written_ranges(&remote.operations),
+            version_id,
+            rolled_back: false,
+        });
+        let excess = room.state.bulk_checkpoints.len().saturating_sub(MAX_BULK_CHECKPOINTS);
+        room.state.bulk_checkpoints.drain(..excess);
+        Receipt::Applied(AckMessage { checkpoint_id: Some(id), ..ack }, RemoteDeltaMessage { checkpoint_id: Some(id), ..remote })
+    }
+
+    /// Rolls back the bulk operation kept as checkpoint `checkpoint_id` for everyone, applying its
+    /// inverse as an edit by the user of `permissions`, who must be able to edit the room. Edits
+    /// made since are kept: the inverse is transformed over them like any late delta. When they
+    /// overlap what the operation wrote by more than the `RollbackPolicy` allows, the rollback
+    /// is refused with the ranges they overlap it in. Saved right away; the edit is returned and
+    /// broadcast to `subscribe_rollbacks`.
+    pub fn rollback(&self, room_id: &str, permissions: &PermissionCache, checkpoint_id: u64, now: Instant) -> Result<RemoteDeltaMessage, RollbackError> {
+        if permissions.doc_id() != room_id {
+            return Err(RollbackError::Forbidden(format!("Not connected to {}", room_id)));
+        }
+        permissions.check_edit().map_err(RollbackError::Forbidden)?;
+        let user = permissions.user();
+        let max_overlap = self.rollback_policy.max_overlap;
+        let remote = self.change_and_save(room_id, now, |room| {
+            if let Some(reason) = Self::user_restricted(room, user) {
+                return Err(RollbackError::Forbidden(reason));
+            }
+            let unknown = || RollbackError::NotFound(format!("No bulk checkpoint {} in {}", checkpoint_id, room_id));
+            let checkpoint = room.state.bulk_checkpoints.iter().find(|checkpoint| checkpoint.id == checkpoint_id).ok_or_else(unknown)?;
+            if checkpoint.rolled_back {
+                return Err(RollbackError::Refused(format!("The {} was already rolled back", checkpoint.operation.name().replace('_', " "))));
+            }
+            let log = &mut room.state.log;
+            let since = log.operations_since(checkpoint.revision).ok_or_else(|| {
+                RollbackError::Refused(format!("The document changed too much since the {} to roll it back", checkpoint.operation.name().replace('_', " ")))
+            })?;
+            let (overlap, conflicts) = overlaps(&checkpoint.ranges, since);
+            if overlap > max_overlap {
+                return Err(RollbackError::Conflict(conflicts));
+            }
+
+            let delta = DeltaMessage { client_id: String::new(), seq: 0, base_revision: checkpoint.revision, paste: false, bulk: None, operations: checkpoint.inverse.clone() };
+            let Receipt::Applied(_, remote) = log.receive(&delta).map_err(|reject| RollbackError::Refused(reject.reason))? else {
+                return Err(RollbackError::Refused(format!("Bulk checkpoint {} could not be rolled back", checkpoint_id)));
+            };
+            for edit in room.recent_edits.iter_mut() {
+                edit.range = map_range(edit.range, &remote.operations);
+            }
+            room.state.suggestions.remap(&remote.operations);
+            if let Some(checkpoint) = room.state.bulk_checkpoints.iter_mut().find(|checkpoint| checkpoint.id == checkpoint_id) {
+                checkpoint.rolled_back = true;
+            }
+            Ok(RemoteDeltaMessage { author: Some(user.to_string()), ..remote })
+        })?;
+        let _ = self.rollbacks.send((room_id.to_string(), remote.clone())); // Fails only when nobody is listening
+        Ok(remote)
+    }
+
+    /// The bulk checkpoints of a loaded room, oldest first
+    pub fn bulk_checkpoints(&self, room_id: &str) -> Option<Vec<BulkCheckpoint>> {
+        self.rooms.lock().unwrap().get(room_id).map(|room| room.state.bulk_checkpoints.clone())
+    }
+
+    /// Applies `delta` to the room's log, keeping track of the revision its sender has,
+    /// moving suggestions along and advising users who edit the same place at once
+    fn apply(room: &mut Room, client_id: &str, delta: &DeltaMessage, collisions: CollisionPolicy, now: Instant) -> Result<Receipt, RejectMessage> {
+        let receipt = room.state.log.receive(delta)?;
+        if let Receipt::Applied(ack, _) | Receipt::Duplicate(ack) = &receipt {
+            let known = room.clients.entry(client_id.to_string()).or_default();
+            *known = (*known).max(ack.revision);
+        }
+        if let Receipt::Applied(_, remote) = &receipt {
+            room.state.suggestions.remap(&remote.operations);
+            Self::detect_collisions(room, client_id, &remote.operations, remote.revision, collisions, now);
+        }
+        Ok(receipt)
+    }
+
+    /// Compares an edit, as transformed and applied, with the other users' recent edits, and
+    /// queues an advisory for each involved user who isn't cooling down from the last one
+    fn detect_collisions(room: &mut Room, client_id: &str, operations: &[DiffOperation], revision: u64, policy: CollisionPolicy, now: Instant) {
+        room.recent_edits.retain(|edit| now.saturating_duration_since(edit.at) <= policy.window);
+        for edit in room.recent_edits.iter_mut() {
+            edit.range = map_range(edit.range, operations);
+        }
+        let Some(range) = touched_range(operations) else { return };
+        let user = room.users.get(client_id).cloned().unwrap_or_else(|| client_id.to_string());
+
+        let collision = room.recent_edits.iter().rev().find(|edit| {
+            let gap = range.0.saturating_sub(edit.range.1).max(edit.range.0.saturating_sub(range.1));
+            edit.user != user && gap <= policy.proximity
+        });
+        if let Some(other) = collision {
+            let message = EditCollisionMessage {
+                users: vec![other.user.clone(), user.clone()],
+                range: (range.0.min(other.range.0), range.1.max(other.range.1)),
+                revision,
+            };
+            for (client, involved) in [(other.client_id.clone(), other.user.clone()), (client_id.to_string(), user.clone())] {
+                let cooling = room.advised.get(&involved).is_some_and(|at| now.saturating_duration_since(*at) < policy.cooldown);
+                if !cooling {
+                    room.advised.insert(involved, now);
+                    room.advisories.push((client, message.clone()));
+                }
+            }
+        }
+        room.recent_edits.push(RecentEdit { client_id: client_id.to_string(), user, range, at: now });
+    }
+
+    /// Takes the collision advisories queued for clients of the room, to send each to its client
+    pub fn take_advisories(&self, room_id: &str) -> Vec<(String, EditCollisionMessage)> {
+        self.rooms.lock().unwrap().get_mut(room_id).map(|room| std::mem::take(&mut room.advisories)).unwrap_or_default()
+    }
+
+    /// Records that `client_id` has applied everything up to `revision`, so older history
+    /// can be trimmed
+    pub fn acknowledge(&self, room_id: &str, client_id: &str, revision: u64) {
+        if let Some(room) = self.rooms.lock().unwrap().get_mut(room_id) {
+            if let Some(known) = room.clients.get_mut(client_id) {
+                *known = (*known).max(revision);
+            }
+        }
+    }
+
+    /// Adds a chat message to the room, flushing older ones to storage when over the cap
+    pub fn post_chat(&self, room_id: &str, message: ChatMessage, now: Instant) -> Result<(), String> {
+        self.with_room(room_id, now, |room| room.state.chat.push(message)).ok_or_else(|| format!("Room {} is not open", room_id))
+    }
+
+    /// Sets a metadata entry of the room, such as its title. The language goes through
+    /// `set_language`, so everyone hears of it.
+    pub fn set_metadata(&self, room_id: &str, key: &str, value: &str) -> Result<(), String> {
+        self.with_room(room_id, Instant::now(), |room| room.state.metadata.insert(key.to_string(), value.to_string()))
+            .map(|_| ())
+            .ok_or_else(|| format!("Room {} is not open", room_id))
+    }
+
+    /// What a client is sent once it joined: the document, its revision, its language and the
+    /// open suggestions
+    pub fn snapshot(&self, room_id: &str) -> Option<SnapshotMessage> {
+        let rooms = self.rooms.lock().unwrap();
+        let room = rooms.get(room_id)?;
+        let log = &room.state.log;
+        Some(SnapshotMessage {
+            text: log.text().to_string(),
+            revision: log.revision(),
+            language: room.state.metadata.get(LANGUAGE_KEY).cloned(),
+            suggestions: room.state.suggestions.list(Some(SuggestionStatus::Open)),
+        })
+    }
+
+    /// Stores a suggested edit by the user of `permissions`, who need only view the room unless
+    /// the `SuggestionPolicy` says otherwise. Saved right away; the returned message is for
+    /// everyone in the room.
+    pub fn suggest(&self, room_id: &str, permissions: &PermissionCache, request: &SuggestMessage, now: Instant) -> Result<SuggestionMessage, String> {
+        if permissions.doc_id() != room_id {
+            return Err(format!("Not connected to {}", room_id));
+        }
+        if !self.suggestion_policy.viewers_may_suggest || permissions.role().is_none() {
+            permissions.check_edit()?;
+        }
+        self.change_and_save(room_id, now, |room| {
+            let log = &room.state.log;
+            if request.revision != log.revision() {
+                return Err(format!("The document changed since revision {}; suggest again", request.revision));
+            }
+            let suggestion = room.state.suggestions.create(permissions.user(), request.range, &request.text, log.text())?;
+            Ok(SuggestionMessage { room: room_id.to_string(), revision: log.revision(), suggestion })
+        })
+    }
+
+    /// Accepts or rejects a suggestion; `permissions` must let the sender edit the room. An
+    /// accepted suggestion is applied as an edit by the sender, returned to broadcast like any
+    /// other, along with every suggestion that changed: the resolved one first, then those the
+    /// accepted edit invalidated. Saved right away.
+    pub fn resolve_suggestion(&self, room_id: &str, permissions: &PermissionCache, decision: &ResolveSuggestionMessage, now: Instant) -> Result<(Option<RemoteDeltaMessage>, Vec<SuggestionMessage>), String> {
+        if permissions.doc_id() != room_id {
+            return Err(format!("Not connected to {}", room_id));
+        }
+        permissions.check_edit()?;
+        let user = permissions.user();
+        self.change_and_save(room_id, now, |room| {
+            let message = |room: &Room, suggestion| SuggestionMessage { room: room_id.to_string(), revision: room.state.log.revision(), suggestion };
+            if !decision.accept {
+                let rejected = room.state.suggestions.reject(decision.id, user)?;
+                return Ok((None, vec![message(room, rejected)]));
+            }
+            if let Some(reason) = Self::user_restricted(room, user) {
+                return Err(reason);
+            }
+            let operations = room.state.suggestions.open(decision.id)?.operations();
+            let log = &mut room.state.log;
+            let delta = DeltaMessage { client_id: String::new(), seq: 0, base_revision: log.revision(), paste: false, bulk: None, operations };
+            let Receipt::Applied(_, remote) = log.receive(&delta).map_err(|reject| reject.reason)? else {
+                return Err(format!("Suggestion {} could not be applied", decision.id));
+            };
+            for edit in room.recent_edits.iter_mut() {
+                edit.range = map_range(edit.range, &remote.operations);
+            }
+            let changed = room.state.suggestions.accepted(decision.id, user, &remote.operations)?;
+            let messages = changed.into_iter().map(|suggestion| message(room, suggestion)).collect();
+            Ok((Some(RemoteDeltaMessage { author: Some(user.to_string()), ..remote }), messages))
+        })
+    }
+
+    /// The suggestions of a room, loaded or not, with `status` or all of them, oldest first
+    pub fn suggestions(&self, room_id: &str, status: Option<SuggestionStatus>) -> Result<Vec<Suggestion>, String> {
+        let rooms = self.rooms.lock().unwrap();
+        match rooms.get(room_id) {
+            Some(room) => Ok(room.state.suggestions.list(status)),
+            None => self.load_state(room_id).map(|state| state.suggestions.list(status)),
+        }
+    }
+
+    /// Changes the language of the room for everyone in it; `permissions` must let the sender
+    /// edit it. The change is saved right away and broadcast to `subscribe_languages`.
+    pub fn set_language(&self, room_id: &str, permissions: &PermissionCache, language: &str, now: Instant) -> Result<LanguageMessage, String> {
+        if permissions.doc_id() != room_id {
+            return Err(format!("Not connected to {}", room_id));
+        }
+        permissions.check_edit()?;
+        let language = validate_language(language)?;
+        self.save_metadata(room_id, LANGUAGE_KEY, &language, now)?;
+
+        let change = LanguageMessage { room: room_id.to_string(), language };
+        let _ = self.languages.send(change.clone()); // Fails only when nobody is listening
+        Ok(change)
+    }
+
+    /// Sets the language of a room without telling anyone, such as one just imported from a
+    /// bundle. The room needn't be loaded.
+    pub fn restore_language(&self, room_id: &str, language: &str) -> Result<(), String> {
+        self.save_metadata(room_id, LANGUAGE_KEY, &validate_language(language)?, Instant::now())
+    }
+
+    /// Changes the save hooks of the room; `permissions` must let the sender edit it. Saved
+    /// right away, like the language.
+    pub fn set_save_hooks(&self, room_id: &str, permissions: &PermissionCache, config: &SaveHooksConfig, now: Instant) -> Result<(), String> {
+        if permissions.doc_id() != room_id {
+            return Err(format!("Not connected to {}", room_id));
+        }
+        permissions.check_edit()?;
+        config.validate()?;
+        self.save_metadata(room_id, SAVE_HOOKS_KEY, &serde_json::to_string(config).unwrap(), now)
+    }
+
+    /// The save hooks of a room, loaded or not; `None` when it uses its workspace's
+    pub fn save_hooks(&self, room_id: &str) -> Option<SaveHooksConfig> {
+        let rooms = self.rooms.lock().unwrap();
+        let saved = match rooms.get(room_id) {
+            Some(room) => room.state.metadata.get(SAVE_HOOKS_KEY).cloned(),
+            None => self.load_state(room_id).ok()?.metadata.get(SAVE_HOOKS_KEY).cloned(),
+        };
+        saved.and_then(|json| SaveHooksConfig::parse(&json).ok())
+    }
+
+    /// Saves the moderation settings of a room, loaded or not, as `Moderation` serialized them.
+    /// Mutes that restrict editing apply to the room's edits from then on.
+    pub fn set_moderation(&self, room_id: &str, settings: &str) -> Result<(), String> {
+        self.save_metadata(room_id, MODERATION_KEY, settings, Instant::now())
+    }
+
+    /// The moderation settings of a room, loaded or not, as saved by `set_moderation`
+    pub fn moderation(&self, room_id: &str) -> Option<String> {
+        let rooms = self.rooms.lock().unwrap();
+        match rooms.get(room_id) {
+            Some(room) => room.state.metadata.get(MODERATION_KEY).cloned(),
+            None => self.load_state(room_id).ok()?.metadata.get(MODERATION_KEY).cloned(),
+        }
+    }
+
+    /// Why `client_id` may not edit `room`, if its user is muted with editing restricted
+    fn editing_restricted(room: &Room, client_id: &str) -> Option<String> {
+        Self::user_restricted(room, room.users.get(client_id)?)
+    }
+
+    /// Why `user` may not edit `room`, if they are muted with editing restricted
+    fn user_restricted(room: &Room, user: &str) -> Option<String> {
+        let settings = ModerationSettings::parse(room.state.metadata.get(MODERATION_KEY)?).ok()?;
+        let mute = settings.muted(user, Utc::now()).filter(|mute| mute.restrict_editing)?;
+        Some(format!("You are muted and can't edit until {}", crate::i18n::timestamp(mute.until)))
+    }
+
+    /// Saves a loaded room to storage, after running its save hooks on the document, or
+    /// `defaults`, its workspace's, when it has none. Hooks can take a while, so call this off
+    /// the async workers.
+    ///
+    /// The hooks run outside the lock, and what they change is applied on top of the edits made
+    /// meanwhile, as an edit by `SAVE_HOOKS_ACTOR` to broadcast, kept as a bulk checkpoint to
+    /// `rollback`. A veto leaves the document edited but unsaved. Checkpoint saves also keep the
+    /// saved document as a checkpoint.
+    pub fn save(&self, room_id: &str, kind: SaveKind, hooks: &SaveHooks, defaults: &SaveHooksConfig, now: Instant) -> SaveReceipt {
+        let rejected = |reason: String| SaveReceipt { reply: Err(SaveRejectedMessage { reason, hook: None, diagnostics: Vec::new() }), edit: None };
+        let not_open = || rejected(format!("Room {} is not open", room_id));
+        let snapshot = self.with_room(room_id, now, |room| {
+            let metadata = &room.state.metadata;
+            let config = metadata.get(SAVE_HOOKS_KEY).and_then(|json| SaveHooksConfig::parse(json).ok());
+            (room.state.log.text().to_string(), room.state.log.revision(), metadata.get(LANGUAGE_KEY).cloned(), config)
+        });
+        let Some((text, base_revision, language, config)) = snapshot else { return not_open() };
+        let report = match hooks.run(config.as_ref().unwrap_or(defaults), kind, language.as_deref(), &text) {
+            Ok(report) => report,
+            Err(veto) => {
+                let reason = format!("Blocked by the {} save hook", veto.hook);
+                return SaveReceipt { reply: Err(SaveRejectedMessage { reason, hook: Some(veto.hook), diagnostics: veto.diagnostics }), edit: None };
+            }
+        };
+
+        let _pass = self.evicting.lock().unwrap(); // No eviction saves an older state over this one
+        let applied = self.with_room(room_id, now, |room| -> Result<_, RejectMessage> {
+            let mut edit = None;
+            if report.content != text {
+                let operations = DiffEngine::diff(&text, &report.content);
+                let delta = DeltaMessage { client_id: String::new(), seq: 0, base_revision, paste: false, bulk: None, operations };
+                let before = room.state.log.text().to_string();
+                let receipt = room.state.log.receive(&delta)?;
+                if let Receipt::Applied(_, remote) = self.record_bulk(room_id, room, receipt, &before, BulkOperation::Format, SAVE_HOOKS_ACTOR) {
+                    for recent in room.recent_edits.iter_mut() {
+                        recent.range = map_range(recent.range, &remote.operations);
+                    }
+                    room.state.suggestions.remap(&remote.operations);
+                    edit = Some(RemoteDeltaMessage { author: Some(SAVE_HOOKS_ACTOR.to_string()), ..remote });
+                }
+            }
+            if kind == SaveKind::Checkpoint {
+                Self::checkpoint(room, "Saved");
+            }
+            let revision = room.state.log.revision();
+            let previous = std::mem::replace(&mut room.state.saved_revision, revision);
+            Ok((edit, revision, previous, serde_json::to_string(&room.state).unwrap()))
+        });
+        let (edit, revision, previous, saved) = match applied {
+            Some(Ok(applied)) => applied,
+            Some(Err(reject)) => return rejected(reject.reason),
+            None => return not_open(),
+        };
+        if let Err(e) = self.storage.save(&room_key(room_id), &saved) {
+            self.with_room(room_id, now, |room| {
+                if room.state.saved_revision == revision {
+                    room.state.saved_revision = previous;
+                }
+            });
+            return SaveReceipt { edit, ..rejected(format!("Failed to save room {}: {}", room_id, e)) };
+        }
+        SaveReceipt { reply: Ok(SavedMessage { revision, warnings: report.warnings }), edit }
+    }
+
+    /// Language changes of every room, for clients' highlighters and the server's own export
+    /// and diagnostics to switch together
+    pub fn subscribe_languages(&self) -> broadcast::Receiver<LanguageMessage> {
+        self.languages.subscribe()
+    }
+
+    /// Rollbacks of bulk operations in every room, with the room, for the connection layer to
+    /// send to the room's clients like any other edit
+    pub fn subscribe_rollbacks(&self) -> broadcast::Receiver<(String, RemoteDeltaMessage)> {
+        self.rollbacks.subscribe()
+    }
+
+    /// Revision and language of a room, loaded or not. Rooms never opened get what they would
+    /// start with.
+    pub fn meta(&self, room_id: &str) -> Result<RoomMeta, String> {
+        let rooms = self.rooms.lock().unwrap();
+        let meta = |state: &RoomState| RoomMeta {
+            id: room_id.to_string(),
+            revision: state.log.revision(),
+            language: state.metadata.get(LANGUAGE_KEY).cloned(),
+            saved_revision: state.saved_revision,
+        };
+        match rooms.get(room_id) {
+            Some(room) => Ok(meta(&room.state)),
+            None => self.load_state(room_id).map(|state| meta(&state)),
+        }
+    }
+
+    /// The language of a room, loaded or not; `None` for plain text
+    pub fn language(&self, room_id: &str) -> Option<String> {
+        self.meta(room_id).ok().and_then(|meta| meta.language)
+    }
+
+    /// The document and revision of a loaded room
+    pub fn document(&self, room_id: &str) -> Option<(String, u64)> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(room_id).map(|room| (room.state.log.text().to_string(), room.state.log.revision()))
+    }
+
+    /// The document and title of a room, loaded or not. Unloaded rooms are read from storage
+    /// without being loaded, and outside the lock, so rooms can be read side by side.
+    pub fn content(&self, room_id: &str) -> Result<(String, Option<String>), String> {
+        let content = |state: &RoomState| (state.log.text().to_string(), state.metadata.get(TITLE_KEY).cloned());
+        let loaded = self.rooms.lock().unwrap().get(room_id).map(|room| content(&room.state));
+        match loaded {
+            Some(loaded) => Ok(loaded),
+            None => self.load_state(room_id).map(|state| content(&state)),
+        }
+    }
+
+    /// The metadata of a loaded room
+    pub fn metadata(&self, room_id: &str) -> Option<HashMap<String, String>> {
+        self.rooms.lock().unwrap().get(room_id).map(|room| room.state.metadata.clone())
+    }
+
+    /// The chat messages of a loaded room still held in memory
+    pub fn recent_chat(&self, room_id: &str) -> Option<Vec<ChatMessage>> {
+        self.rooms.lock().unwrap().get(room_id).map(|room| room.state.chat.clone())
+    }
+
+    /// Chat messages flushed to storage for `room_id`, oldest first
+    pub fn archived_chat(&self, room_id: &str) -> Vec<ChatMessage> {
+        self.storage
+            .load(&chat_archive_key(room_id))
+            .ok()
+            .and_then(|saved| serde_json::from_str(&saved).ok())
+            .unwrap_or_default()
+    }
+
+    /// Memory accounting of a loaded room
+    pub fn usage(&self, room_id: &str) -> Option<MemoryUsage> {
+        self.rooms.lock().unwrap().get(room_id).map(|room| self.usage_of(room))
+    }
+
+    /// Every loaded room with its connections and memory use, for the admin rooms list
+    pub fn rooms(&self, now: Instant) -> Vec<RoomSummary> {
+        let rooms = self.rooms.lock().unwrap();
+        let mut summaries: Vec<RoomSummary> = rooms
+            .iter()
+            .map(|(id, room)| RoomSummary {
+                room: id.clone(),
+                connections: room.clients.len(),
+                revision: room.state.log.revision(),
+                idle_secs: now.saturating_duration_since(room.last_active).as_secs(),
+                memory: self.usage_of(room),
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.room.cmp(&b.room));
+        summaries
+    }
+
+    /// Memory gauges in the Prometheus text format
+    pub fn metrics(&self, now: Instant) -> String {
+        let rooms = self.rooms(now);
+        let mut metrics = format!("# TYPE rustpad_rooms_loaded gauge\nrustpad_rooms_loaded {}\n", rooms.len());
+        metrics.push_str("# TYPE rustpad_room_memory_bytes gauge\n");
+        for summary in &rooms {
+            let memory = summary.memory;
+            let kinds = [("document", memory.document), ("edit_log", memory.edit_log), ("chat", memory.chat), ("resume_buffer", memory.resume_buffer)];
+            for (kind, bytes) in kinds {
+                metrics.push_str(&format!("rustpad_room_memory_bytes{{room=\"{}\",kind=\"{}\"}} {}\n", summary.room, kind, bytes));
+            }
+        }
+        let total: usize = rooms.iter().map(|summary| summary.memory.total()).sum();
+        metrics.push_str(&format!("# TYPE rustpad_memory_bytes gauge\nrustpad_memory_bytes {}\n", total));
+        metrics
+    }
+
+    /// Saves and unloads rooms that have had no connections for `idle_eviction`, returning their
+    /// ids. Rooms are saved outside the lock; a client joining meanwhile cancels the unload, and
+    /// the room stays loaded with whatever it changed.
+    pub fn evict_idle(&self, now: Instant) -> Vec<String> {
+        let _pass = self.evicting.lock().unwrap();
+        let idle: Vec<(String, String)> = {
+            let mut rooms = self.rooms.lock().unwrap();
+            rooms
+                .iter_mut()
+                .filter(|(_, room)| room.clients.is_empty() && now.saturating_duration_since(room.last_active) >= self.limits.idle_eviction)
+                .map(|(id, room)| {
+                    room.unloading = true;
+                    (id.clone(), serde_json::to_string(&room.state).unwrap())
+                })
+                .collect()
+        };
+
+        let mut evicted = Vec::new();
+        for (id, saved) in idle {
+            let result = self.storage.save(&room_key(&id), &saved);
+            let mut rooms = self.rooms.lock().unwrap();
+            let Some(room) = rooms.get_mut(&id) else { continue };
+            match result {
+                Ok(()) if room.unloading => {
+                    rooms.remove(&id);
+                    evicted.push(id);
+                }
+                Ok(()) => {} // Rejoined while saving
+                Err(e) => {
+                    eprintln!("Failed to save room {}, keeping it loaded: {}", id, e);
+                    room.unloading = false;
+                }
+            }
+        }
+        evicted
+    }
+
+    /// Saves and unloads `room_id` at once, whoever is connected, and returns the clients that
+    /// were, for the connection layer to disconnect. Used when the document is deleted; the
+    /// saved room is what a restore brings back.
+    pub fn close_room(&self, room_id: &str) -> Result<Vec<String>, String> {
+        let _pass = self.evicting.lock().unwrap(); // No eviction saves an older state over this one
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get(room_id) else { return Ok(Vec::new()) };
+        let saved = serde_json::to_string(&room.state).unwrap();
+        // Saved under the lock, so a join can't load the room from before the close
+        self.storage.save(&room_key(room_id), &saved).map_err(|e| format!("Failed to save room {}: {}", room_id, e))?;
+        let mut clients: Vec<String> = rooms.remove(room_id).unwrap().clients.into_keys().collect();
+        clients.sort();
+        Ok(clients)
+    }
+
+    /// Deletes everything saved for `room_id`, its state and its chat archive, unloading it
+    /// first if it is loaded
+    pub fn purge(&self, room_id: &str) -> Result<(), String> {
+        let _pass = self.evicting.lock().unwrap();
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.remove(room_id);
+        for key in [room_key(room_id), chat_archive_key(room_id)] {
+            if self.storage.load(&key).is_ok() {
+                self.storage.delete(&key).map_err(|e| format!("Failed to purge room {}: {}", room_id, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The saved state of `room_id`, or a new empty room. Rooms without a language get the one
+    /// of the file backing them, from the extension of their id.
+    fn load_state(&self, room_id: &str) -> Result<RoomState, String> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+        if room_id.is_empty() || room_id.starts_with('.') || !room_id.chars().all(valid) {
+            return Err(format!("Invalid room id {:?}", room_id));
+        }
+        let mut state = match self.storage.load(&room_key(room_id)) {
+            Ok(saved) => serde_json::from_str(&saved).map_err(|e| format!("Corrupt room {}: {}", room_id, e))?,
+            Err(_) => RoomState {
+                log: RevisionLog::new(""),
+                chat: Vec::new(),
+                metadata: HashMap::new(),
+                checkpoints: Vec::new(),
+                saved_revision: 0,
+                suggestions: SuggestionManager::new(),
+                bulk_checkpoints: Vec::new(),
+                last_bulk_checkpoint: 0,
+            },
+        };
+        if let Some(language) = language_for_path(Path::new(room_id)) {
+            state.metadata.entry(LANGUAGE_KEY.to_string()).or_insert(language);
+        }
+        Ok(state)
+    }
+
+    /// Sets a metadata entry of `room_id`, loaded or not, and saves the room right away so the
+    /// change survives a restart
+    fn save_metadata(&self, room_id: &str, key: &str, value: &str, now: Instant) -> Result<(), String> {
+        let _pass = self.evicting.lock().unwrap(); // No eviction saves an older state over this one
+        let mut rooms = self.rooms.lock().unwrap();
+        let saved = match rooms.get_mut(room_id) {
+            Some(room) => {
+                room.state.metadata.insert(key.to_string(), value.to_string());
+                room.last_active = now;
+                serde_json::to_string(&room.state).unwrap()
+            }
+            None => {
+                // Saved under the lock, so a join can't load the room from before the change
+                let mut state = self.load_state(room_id)?;
+                state.metadata.insert(key.to_string(), value.to_string());
+                return self.storage.save(&room_key(room_id), &serde_json::to_string(&state).unwrap()).map_err(|e| format!("Failed to save room {}: {}", room_id, e));
+            }
+        };
+        drop(rooms);
+        self.storage.save(&room_key(room_id), &saved).map_err(|e| format!("Failed to save room {}: {}", room_id, e))
+    }
+
+    /// Runs `change` on a loaded room and saves the room right away if it succeeded. A failed
+    /// save is only logged: the change was made, and is saved again when the room is unloaded.
+    fn change_and_save<T, E: From<String>>(&self, room_id: &str, now: Instant, change: impl FnOnce(&mut Room) -> Result<T, E>) -> Result<T, E> {
+        let _pass = self.evicting.lock().unwrap(); // No eviction saves an older state over this one
+        let (result, saved) = self
+            .with_room(room_id, now, |room| change(room).map(|result| (result, serde_json::to_string(&room.state).unwrap())))
+            .unwrap_or_else(|| Err(E::from(format!("Room {} is not open", room_id))))?;
+        if let Err(e) = self.storage.save(&room_key(room_id), &saved) {
+            eprintln!("Failed to save room {}: {}", room_id, e);
+        }
+        Ok(result)
+    }
+
+    /// Runs `change` on a loaded room, then trims it back under its caps
+    fn with_room<T>(&self, room_id: &str, now: Instant, change: impl FnOnce(&mut Room) -> T) -> Option<T> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.get_mut(room_id)?;
+        room.unloading = false;
+        room.last_active = now;
+        let result = change(room);
+        self.enforce_limits(room_id, room);
+        Some(result)
+    }
+
+    /// Drops history no client can resume from once the edit log is over its cap, and flushes
+    /// chat older than the recent window once chat is over its cap
+    fn enforce_limits(&self, room_id: &str, room: &mut Room) {
+        let usage = self.usage_of(room);
+        if usage.edit_log > self.limits.max_edit_log_bytes {
+            room.state.log.trim_before(self.resume_floor(room));
+        }
+        if usage.chat > self.limits.max_chat_bytes && room.state.chat.len() > self.limits.chat_window {
+            let flushed: Vec<ChatMessage> = room.state.chat.drain(..room.state.chat.len() - self.limits.chat_window).collect();
+            let mut archive = self.archived_chat(room_id);
+            archive.extend(flushed.iter().cloned());
+            if let Err(e) = self.storage.save(&chat_archive_key(room_id), &serde_json::to_string(&archive).unwrap()) {
+                eprintln!("Failed to flush chat of room {}, keeping it in memory: {}", room_id, e);
+                room.state.chat.splice(0..0, flushed);
+            }
+        }
+    }
+
+    /// The oldest revision a connected client has, or a reconnecting one could resume from
+    fn resume_floor(&self, room: &Room) -> u64 {
+        let window_start = room.state.log.revision().saturating_sub(self.limits.resume_window);
+        room.clients.values().copied().fold(window_start, u64::min)
+    }
+
+    fn usage_of(&self, room: &Room) -> MemoryUsage {
+        let log = &room.state.log;
+        let history = log.history_bytes_since(log.oldest_revision());
+        let resume_buffer = log.history_bytes_since(self.resume_floor(room));
+        MemoryUsage {
+            document: log.text().len(),
+            edit_log: history - resume_buffer,
+            chat: room.state.chat.iter().map(|message| serde_json::to_string(message).map_or(0, |json| json.len())).sum(),
+            resume_buffer,
+        }
+    }
+}
+
+/// Where `range` ends up after `operations`. Text inserted at either edge stays outside it, and
+/// a range an operation overlaps is clipped to what remains.
+pub(crate) fn map_range((mut start, mut end): (usize, usize), operations: &[DiffOperation]) -> (usize, usize) {
+    for operation in operations {
+        let (op_start, op_end, text) = DiffEngine::to_range(operation.clone());
+        let shift = |position: usize| position - (op_end - op_start) + text.len();
+        start = if start < op_start || (start == op_start && op_start < op_end) {
+            start
+        } else if start >= op_end {
+            shift(start)
+        } else {
+            op_start
+        };
+        end = if end <= op_start {
+            end
+        } else if end >= op_end {
+            shift(end)
+        } else {
+            op_start + text.len()
+        }
+        .max(start);
+    }
+    (start, end)
+}
+
+/// The range of the document after `operations` that they wrote to or deleted at
+fn touched_range(operations: &[DiffOperation]) -> Option<(usize, usize)> {
+    let mut touched: Option<(usize, usize)> = None;
+    for operation in operations {
+        let (op_start, _, text) = DiffEngine::to_range(operation.clone());
+        let written = (op_start, op_start + text.len());
+        touched = Some(match touched {
+            Some(range) => {
+                let range = map_range(range, std::slice::from_ref(operation));
+                (range.0.min(written.0), range.1.max(written.1))
+            }
+            None => written,
+        });
+    }
+    touched
+}
+
+/// The operations undoing `operations` applied to `before`, for the document they produced
+fn inverse(before: &str, operations: &[DiffOperation]) -> Vec<DiffOperation> {
+    let mut text = before.to_string();
+    let mut inverse = Vec::new();
+    for operation in operations {
+        let (start, end, inserted) = DiffEngine::to_range(operation.clone());
+        let removed = text[start..end].to_string();
+        match (inserted.is_empty(), removed.is_empty()) {
+            (true, true) => {}
+            (false, true) => inverse.push(DiffOperation::Delete(start, start + inserted.len())),
+            (true, false) => inverse.push(DiffOperation::Insert(start, removed)),
+            (false, false) => inverse.push(DiffOperation::Replace(start, start + inserted.len(), removed)),
+        }
+        text = DiffEngine::apply(&text, std::slice::from_ref(operation));
+    }
+    inverse.reverse();
+    inverse
+}
+
+/// The ranges of the document after `operations` that they wrote, one per operation
+fn written_ranges(operations: &[DiffOperation]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for operation in operations {
+        for range in ranges.iter_mut() {
+            *range = map_range(*range, std::slice::from_ref(operation));
+        }
+        let (start, _, text) = DiffEngine::to_range(operation.clone());
+        ranges.push((start, start + text.len()));
+    }
+    ranges
+}
+
+/// How many bytes of `ranges` the edits `since` deleted or wrote into, and where in the
+/// document after them, as sorted ranges with overlapping ones merged
+fn overlaps(ranges: &[(usize, usize)], since: &[Vec<DiffOperation>]) -> (usize, Vec<(usize, usize)>) {
+    let mut ranges = ranges.to_vec();
+    let mut overlap = 0;
+    let mut conflicts: Vec<(usize, usize)> = Vec::new();
+    for operation in since.iter().flatten() {
+        let (op_start, op_end, text) = DiffEngine::to_range(operation.clone());
+        let mut conflicting = false;
+        for &(start, end) in &ranges {
+            let deleted = op_end.min(end).saturating_sub(op_start.max(start));
+            let inserted = if start < op_start && op_start < end { text.len() } else { 0 };
+            if deleted + inserted > 0 {
+                overlap += deleted + inserted;
+                conflicting = true;
+            }
+        }
+        let operation = std::slice::from_ref(operation);
+        for range in ranges.iter_mut().chain(conflicts.iter_mut()) {
+            *range = map_range(*range, operation);
+        }
+        if conflicting {
+            conflicts.push((op_start, op_start + text.len()));
+        }
+    }
+    conflicts.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in conflicts {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    (overlap, merged)
+}
+
+fn room_key(room_id: &str) -> String {
+    format!("rooms/{}", room_id)
+}
+
+fn chat_archive_key(room_id: &str) -> String {
+    format!("rooms/{}.chat", room_id)
+}
+
+/// Unloads idle rooms once a minute
+pub fn spawn_evictor(host: RoomHost) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let host = host.clone();
+            // Saving rooms blocks on storage, so keep it off the async workers
+            let evicted = tokio::task::spawn_blocking(move || host.evict_idle(Instant::now())).await.unwrap_or_default();
+            if !evicted.is_empty() {
+                println!("Unloaded {} idle rooms", evicted.len());
+            }
+        }
+    })
+}
+
+/// `GET /api/admin/rooms`, the loaded rooms with their memory use, for requests bearing the
+/// admin key, and `GET /api/docs/:id/meta`, a room's revision and language. The rooms' gauges
+/// are part of `metrics::route`.
+pub fn room_routes(host: RoomHost, admin_key: Option<String>) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let rooms_host = host.clone();
+    let rooms = warp::path!("api" / "admin" / "rooms")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |authorization: Option<String>| {
+            if !tokens::is_admin(admin_key.as_deref(), authorization.as_deref()) {
+                return warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": "The admin key is required" })), StatusCode::FORBIDDEN).into_response();
+            }
+            warp::reply::json(&rooms_host.rooms(Instant::now())).into_response()
+        });
+    let meta = warp::path!("api" / "docs" / String / "meta").and(warp::get()).map(move |doc_id: String| match host.meta(&doc_id) {
+        Ok(meta) => warp::reply::json(&meta).into_response(),
+        Err(e) => warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": e })), StatusCode::NOT_FOUND).into_response(),
+    });
+    rooms.or(meta)
+}
+
+/// `PUT /api/docs/:id/save-hooks`, setting the save hooks of a document the signed-in user
+/// may edit, or anyone through a share link (`?token=`)
+pub fn save_hook_routes(host: RoomHost, workspaces: Workspaces) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "docs" / String / "save-hooks")
+        .and(warp::put())
+        .and(authenticated_user())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::bytes())
+        .map(move |doc_id: String, user: Option<String>, query: HashMap<String, String>, body: warp::hyper::body::Bytes| {
+            let error = |message: String, status: StatusCode| {
+                warp::reply::with_status(warp::reply::json(&serde_json::json!({ "type": "error", "message": message })), status).into_response()
+            };
+            let permissions = match workspaces.open(&doc_id, &user.unwrap_or_default(), query.get("token").map(String::as_str)) {
+                Ok(permissions) => permissions,
+                Err(e) => return error(e, StatusCode::FORBIDDEN),
+            };
+            if let Err(e) = permissions.check_edit() {
+                return error(e, StatusCode::FORBIDDEN);
+            }
+            let config = match SaveHooksConfig::parse(&String::from_utf8_lossy(&body)) {
+                Ok(config) => config,
+                Err(e) => return error(e, StatusCode::BAD_REQUEST),
+            };
+            match host.set_save_hooks(&doc_id, &permissions, &config, Instant::now()) {
+                Ok(()) => warp::reply::json(&config).into_response(),
+                Err(e) => error(e, StatusCode::BAD_REQUEST),
+            }
+        })
+}
+
+/// `GET /api/docs/:id/suggestions?status=open`, the suggestions of a document the signed-in
+/// user may view, or anyone through a share link (`&token=`), all of them without `status`
+pub fn suggestion_routes(host: RoomHost, workspaces: Workspaces) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "docs" / String / "suggestions")
+        .and(warp::get())
+        .and(authenticated_user())
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |doc_id: String, user: Option<String>, query: HashMap<String, String>| {
+            let error = |message: String, status: StatusCode| {
+                warp::reply::with_status(warp::reply::json(&serde_json::json!({ "type": "error", "message": message })), status).into_response()
+            };
+            if let Err(e) = workspaces.open(&doc_id, &user.unwrap_or_default(), query.get("token").map(String::as_str)) {
+                return error(e, StatusCode::FORBIDDEN);
+            }
+            let status = match query.get("status").map(|status| SuggestionStatus::parse(status)).transpose() {
+                Ok(status) => status,
+                Err(e) => return error(e, StatusCode::BAD_REQUEST),
+            };
+            match host.suggestions(&doc_id, status) {
+                Ok(suggestions) => warp::reply::json(&suggestions).into_response(),
+                Err(e) => error(e, StatusCode::NOT_FOUND),
+            }
+        })
+}
+
+/// `POST /api/docs/:id/rollback/:checkpoint_id`, rolling back a bulk operation for everyone in
+/// a document the signed-in user may edit, or anyone through a share link (`?token=`).
+/// Edits since that overlap the operation too much refuse it with 409 and their `conflicts`.
+pub fn rollback_routes(host: RoomHost, workspaces: Workspaces) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "docs" / String / "rollback" / u64)
+        .and(warp::post())
+        .and(authenticated_user())
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |doc_id: String, checkpoint_id: u64, user: Option<String>, query: HashMap<String, String>| {
+            let error = |message: String, status: StatusCode| {
+                warp::reply::with_status(warp::reply::json(&serde_json::json!({ "type": "error", "message": message })), status).into_response()
+            };
+            let permissions = match workspaces.open(&doc_id, &user.unwrap_or_default(), query.get("token").map(String::as_str)) {
+                Ok(permissions) => permissions,
+                Err(e) => return error(e, StatusCode::FORBIDDEN),
+            };
+            match host.rollback(&doc_id, &permissions, checkpoint_id, Instant::now()) {
+                Ok(edit) => warp::reply::json(&edit).into_response(),
+                Err(RollbackError::Forbidden(e)) => error(e, StatusCode::FORBIDDEN),
+                Err(RollbackError::NotFound(e)) => error(e, StatusCode::NOT_FOUND),
+                Err(RollbackError::Refused(e)) => error(e, StatusCode::CONFLICT),
+                Err(RollbackError::Conflict(conflicts)) => {
+                    let message = "Edits made since overlap the operation; undo them first";
+                    let body = serde_json::json!({ "type": "error", "message": message, "conflicts": conflicts });
+                    warp::reply::with_status(warp::reply::json(&body), StatusCode::CONFLICT).into_response()
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth::bearer_for;
+    use crate::storage::MemoryStorage;
+    use crate::editor::diff_engine::DiffOperation;
+    use crate::editor::linter::{LintError, Linter};
+    use crate::editor::save_hooks::SaveHook;
+    use crate::networking::protocol::{ProtocolMessage, SetLanguageMessage};
+    use crate::networking::structured_sync::StructuredSync;
+    use crate::storage::workspace::{WorkspaceRole, WorkspaceSettings};
+    use crate::validation::{ChatBody, Username};
+
+    fn host(limits: MemoryLimits) -> RoomHost {
+        RoomHost::new(Arc::new(MemoryStorage::default()), limits)
+    }
+
+    fn insert(seq: u64, base_revision: u64, at: usize, text: &str) -> DeltaMessage {
+        DeltaMessage { client_id: String::new(), seq, base_revision, paste: false, bulk: None, operations: vec![DiffOperation::Insert(at, text.to_string())] }
+    }
+
+    fn paste(seq: u64, base_revision: u64, text: &str) -> DeltaMessage {
+        DeltaMessage { paste: true, ..insert(seq, base_revision, 0, text) }
+    }
+
+    /// Confirmation above 10 bytes, refusal above 20, cancelled after 30 seconds
+    fn paste_host() -> RoomHost {
+        let policy = PastePolicy { confirm_above: 10, max_bytes: 20, confirm_timeout: Duration::from_secs(30) };
+        host(MemoryLimits::new()).with_paste_policy(policy)
+    }
+
+    fn chat(id: u64, text: &str) -> ChatMessage {
+        ChatMessage {
+            id,
+            key: None,
+            room: "pad".to_string(),
+            user: Username::try_from("ana").unwrap(),
+            message: ChatBody::try_from(text).unwrap(),
+            timestamp: String::new(),
+            attachments: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_edit_log_trimming_keeps_resume() {
+        let host = host(MemoryLimits { max_edit_log_bytes: 256, resume_window: 4, ..MemoryLimits::new() });
+        let now = Instant::now();
+        host.join("pad", "writer", now).unwrap();
+        host.join("pad", "reader", now).unwrap();
+
+        // The reader still has revision 0, so nothing can go yet
+        for revision in 0..20 {
+            host.receive("pad", "writer", &insert(revision, revision, 0, "0123456789"), now).unwrap();
+        }
+        assert_eq!(host.usage("pad").unwrap().edit_log, 0);
+
+        // Once the reader has caught up to 10, the history before it is dropped
+        host.acknowledge("pad", "reader", 10);
+        host.receive("pad", "writer", &insert(20, 20, 0, "x"), now).unwrap();
+        let usage = host.usage("pad").unwrap();
+        assert_eq!(usage.edit_log, 0);
+        assert!(usage.resume_buffer > 0);
+
+        // The reader can still send edits based on what it has; older bases are refused
+        let Receipt::Applied(ack, _) = host.receive("pad", "reader", &insert(0, 10, 0, "r"), now).unwrap() else { panic!("not applied") };
+        assert_eq!(ack.revision, 22);
+        assert!(ack.transformed.is_some());
+        assert!(host.receive("pad", "reader", &insert(1, 9, 0, "r"), now).is_err());
+        assert_eq!(host.document("pad").unwrap().0.len(), 200 + 2);
+    }
+
+    #[tokio::test]
+    async fn test_admin_rooms_need_the_admin_key() {
+        let pads = host(MemoryLimits::new());
+        pads.join("pad", "ana", Instant::now()).unwrap();
+        let routes = room_routes(pads.clone(), Some("secret".to_string()));
+        let request = |authorization: &str| warp::test::request().path("/api/admin/rooms").header("authorization", authorization);
+
+        assert_eq!(warp::test::request().path("/api/admin/rooms").reply(&routes).await.status(), 403);
+        assert_eq!(request("Bearer wrong").reply(&routes).await.status(), 403);
+        let response = request("Bearer secret").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        let rooms: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(rooms[0]["room"], "pad");
+
+        // Without a configured key nobody is admin
+        let response = warp::test::request().path("/api/admin/rooms").header("authorization", "Bearer ").reply(&room_routes(pads, None)).await;
+        assert_eq!(response.status(), 403);
+    }
+
+    #[test]
+    fn test_idle_room_unloads_and_reloads_identically() {
+        let host = host(MemoryLimits { idle_eviction: Duration::from_secs(60), ..MemoryLimits::new() });
+        let start = Instant::now();
+        host.join("pad", "ana", start).unwrap();
+        host.receive("pad", "ana", &insert(0, 0, 0, "fn main() {}"), start).unwrap();
+        host.post_chat("pad", chat(1, "ship it"), start).unwrap();
+        host.set_metadata("pad", "language", "rust").unwrap();
+        host.leave("pad", "ana", start);
+
+        assert!(host.evict_idle(start + Duration::from_secs(30)).is_empty());
+        assert_eq!(host.evict_idle(start + Duration::from_secs(61)), vec!["pad".to_string()]);
+        assert!(host.rooms(start).is_empty());
+        assert!(host.document("pad").is_none());
+
+        let later = start + Duration::from_secs(120);
+        assert_eq!(host.join("pad", "bob", later).unwrap(), ("fn main() {}".to_string(), 1));
+        assert_eq!(host.metadata("pad").unwrap()["language"], "rust");
+        assert_eq!(host.recent_chat("pad").unwrap()[0].message.as_str(), "ship it");
+
+        // Clients from before the unload can resume
+        let Receipt::Applied(ack, _) = host.receive("pad", "bob", &insert(0, 1, 12, "\n"), later).unwrap() else { panic!("not applied") };
+        assert_eq!(ack.revision, 2);
+        assert!(host.join("../etc", "bob", later).is_err());
+    }
+
+    #[test]
+    fn test_dedup_survives_unload() {
+        let host = host(MemoryLimits { idle_eviction: Duration::ZERO, ..MemoryLimits::new() });
+        let now = Instant::now();
+        host.join("pad", "ana", now).unwrap();
+        host.receive("pad", "ana", &insert(0, 0, 0, "hello"), now).unwrap();
+        host.leave("pad", "ana", now);
+        assert_eq!(host.evict_idle(now), vec!["pad".to_string()]);
+
+        // Ana reconnects and retries the delta whose acknowledgement she never got
+        host.join("pad", "ana", now).unwrap();
+        let Receipt::Duplicate(ack) = host.receive("pad", "ana", &insert(0, 0, 0, "hello"), now).unwrap() else { panic!("applied twice") };
+        assert_eq!(ack.revision, 1);
+        assert_eq!(host.document("pad").unwrap(), ("hello".to_string(), 1));
+        assert!(matches!(host.receive("pad", "ana", &insert(2, 1, 0, "!"), now), Ok(Receipt::Gap(_))));
+    }
+
+    #[test]
+    fn test_join_during_eviction_loses_nothing() {
+        let host = host(MemoryLimits { idle_eviction: Duration::ZERO, ..MemoryLimits::new() });
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let evictor = {
+            let (host, done) = (host.clone(), done.clone());
+            std::thread::spawn(move || {
+                let mut evictions = 0;
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    evictions += host.evict_idle(Instant::now()).len();
+                }
+                evictions
+            })
+        };
+
+        for i in 0..300 {
+            let (text, revision) = host.join("pad", "ana", Instant::now()).unwrap();
+            assert_eq!(text.len(), i, "lost an edit after {} joins", i);
+            host.receive("pad", "ana", &insert(i as u64, revision, text.len(), "x"), Instant::now()).unwrap();
+            host.leave("pad", "ana", Instant::now());
+            if i % 10 == 0 {
+                // Make sure some joins find the room unloaded rather than always racing the unload
+                while host.document("pad").is_some() {
+                    std::thread::yield_now();
+                }
+            }
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(evictor.join().unwrap() > 0);
+        assert_eq!(host.join("pad", "ana", Instant::now()).unwrap().0, "x".repeat(300));
+    }
+
+    #[test]
+    fn test_accounting_tracks_sizes() {
+        let host = host(MemoryLimits { max_chat_bytes: 4096, chat_window: 10, ..MemoryLimits::new() });
+        let now = Instant::now();
+        host.join("pad", "ana", now).unwrap();
+        host.receive("pad", "ana", &insert(0, 0, 0, &"a".repeat(10_000)), now).unwrap();
+        for seq in 1..5 {
+            host.receive("pad", "ana", &insert(seq, seq, 0, &"b".repeat(1000)), now).unwrap();
+        }
+        let usage = host.usage("pad").unwrap();
+        assert_eq!(usage.document, 14_000);
+        assert!((14_000..15_000).contains(&(usage.edit_log + usage.resume_buffer)));
+
+        let message = "m".repeat(100);
+        for id in 0..30 {
+            host.post_chat("pad", chat(id, &message), now).unwrap();
+        }
+        let usage = host.usage("pad").unwrap();
+        assert!(usage.chat <= 4096 && usage.chat >= 10 * 100);
+
+        // Older messages went to storage, none were lost
+        let archived = host.archived_chat("pad");
+        let recent = host.recent_chat("pad").unwrap();
+        assert_eq!(archived.len() + recent.len(), 30);
+        assert_eq!(archived.iter().chain(&recent).map(|message| message.id).collect::<Vec<_>>(), (0..30).collect::<Vec<_>>());
+
+        let metrics = host.metrics(now);
+        assert!(metrics.contains("rustpad_room_memory_bytes{room=\"pad\",kind=\"document\"} 14000"));
+        assert!(metrics.contains("rustpad_rooms_loaded 1"));
+    }
+
+    #[test]
+    fn test_paste_thresholds() {
+        let host = paste_host();
+        let now = Instant::now();
+        host.join("pad", "ana", now).unwrap();
+        assert!(matches!(host.receive("pad", "ana", &paste(0, 0, "0123456789"), now), Ok(Receipt::Applied(..))));
+        assert!(matches!(host.receive("pad", "ana", &insert(1, 1, 0, &"x".repeat(25)), now), Ok(Receipt::Applied(..)))); // Typed, not pasted
+
+        let Ok(Receipt::Held(request)) = host.receive("pad", "bob", &paste(0, 2, "line one\nline two"), now) else { panic!("not held") };
+        assert_eq!((request.seq, request.bytes, request.lines, request.expires_in_secs), (0, 17, 2, 30));
+        assert_eq!(host.document("pad").unwrap().1, 2);
+
+        // Over the hard cap: refused with the limit, and the refusal stands for retries
+        let reject = host.receive("pad", "cy", &paste(0, 2, &"y".repeat(21)), now).unwrap_err();
+        assert_eq!((reject.seq, reject.reason.as_str()), (0, "Pastes are limited to 20 bytes; this one has 21"));
+        assert_eq!(host.receive("pad", "cy", &paste(0, 2, &"y".repeat(21)), now).unwrap_err().reason, reject.reason);
+        assert!(matches!(host.receive("pad", "cy", &insert(1, 2, 0, "ok"), now), Ok(Receipt::Applied(..))));
+    }
+
+    #[test]
+    fn test_confirmed_paste_checkpoints_first() {
+        let host = paste_host();
+        let now = Instant::now();
+        host.join("pad", "ana", now).unwrap();
+        host.receive("pad", "ana", &insert(0, 0, 0, "fn main() {}"), now).unwrap();
+        assert!(matches!(host.receive("pad", "ana", &paste(1, 1, &"log\n".repeat(4)), now), Ok(Receipt::Held(_))));
+        assert!(host.resolve_paste("pad", "ana", &PasteDecisionMessage { seq: 0, confirm: true }, now).is_err()); // Not the held seq
+
+        let Ok(Receipt::Applied(ack, _)) = host.resolve_paste("pad", "ana", &PasteDecisionMessage { seq: 1, confirm: true }, now) else { panic!("not applied") };
+        assert_eq!((ack.seq, ack.revision), (1, 2));
+        assert_eq!(host.document("pad").unwrap().0, format!("{}fn main() {{}}", "log\n".repeat(4)));
+        let checkpoints = host.checkpoints("pad").unwrap();
+        assert_eq!(checkpoints, vec![Checkpoint { name: "Before paste by ana".to_string(), revision: 1, text: "fn main() {}".to_string() }]);
+
+        assert!(matches!(host.revert_to_checkpoint("pad", 1, "ana", now), Ok(Receipt::Applied(..))));
+        assert_eq!(host.document("pad").unwrap(), ("fn main() {}".to_string(), 3));
+    }
+
+    #[test]
+    fn test_paste_cancel_timeout_and_disconnect() {
+        let host = paste_host();
+        let now = Instant::now();
+        let big = "z".repeat(15);
+        host.join("pad", "ana", now).unwrap();
+
+        // Cancelled: nothing applied, and the client's next seq follows on
+        host.receive("pad", "ana", &paste(0, 0, &big), now).unwrap();
+        let reject = host.resolve_paste("pad", "ana", &PasteDecisionMessage { seq: 0, confirm: false }, now).unwrap_err();
+        assert_eq!((reject.seq, reject.reason.as_str()), (0, "Paste cancelled"));
+        assert!(matches!(host.receive("pad", "ana", &insert(1, 0, 0, "a"), now), Ok(Receipt::Applied(..))));
+
+        // Timed out: the sweep cancels it, and a late confirmation finds nothing
+        host.receive("pad", "ana", &paste(2, 1, &big), now).unwrap();
+        assert!(host.expire_pastes(now + Duration::from_secs(29)).is_empty());
+        let expired = host.expire_pastes(now + Duration::from_secs(30));
+        assert_eq!(expired.len(), 1);
+        assert_eq!((expired[0].0.as_str(), expired[0].1.as_str(), expired[0].2.reason.as_str()), ("pad", "ana", "Paste confirmation timed out"));
+        assert!(host.resolve_paste("pad", "ana", &PasteDecisionMessage { seq: 2, confirm: true }, now).is_err());
+
+        // Disconnected: the pending paste is dropped
+        host.receive("pad", "ana", &paste(3, 1, &big), now).unwrap();
+        host.leave("pad", "ana", now);
+        let reject = host.resolve_paste("pad", "ana", &PasteDecisionMessage { seq: 3, confirm: true }, now).unwrap_err();
+        assert_eq!(reject.reason, "No paste is awaiting confirmation");
+        assert_eq!(host.document("pad").unwrap(), ("a".to_string(), 1));
+    }
+
+    /// Collisions within 2 bytes and 3 seconds, one advisory per user every 30 seconds
+    fn collision_host() -> RoomHost {
+        let policy = CollisionPolicy { proximity: 2, window: Duration::from_secs(3), cooldown: Duration::from_secs(30) };
+        host(MemoryLimits::new()).with_collision_policy(policy)
+    }
+
+    fn clients_advised(advisories: &[(String, EditCollisionMessage)]) -> Vec<&str> {
+        advisories.iter().map(|(client, _)| client.as_str()).collect()
+    }
+
+    #[test]
+    fn test_collisions_advise_both_users_once_per_cooldown() {
+        let host = collision_host();
+        let now = Instant::now();
+        for (client, user) in [("a1", "alice"), ("b1", "bob"), ("c1", "carol")] {
+            host.join_as("pad", client, user, now).unwrap();
+        }
+
+        host.receive("pad", "a1", &insert(0, 0, 0, "hello world"), now).unwrap();
+        assert!(host.take_advisories("pad").is_empty());
+        host.receive("pad", "b1", &insert(0, 1, 6, "big "), now + Duration::from_secs(1)).unwrap();
+        let advisories = host.take_advisories("pad");
+        assert_eq!(clients_advised(&advisories), vec!["a1", "b1"]);
+        assert_eq!(advisories[0].1, EditCollisionMessage { users: vec!["alice".to_string(), "bob".to_string()], range: (0, 15), revision: 2 });
+        assert_eq!(serde_json::to_value(&advisories[0].1).unwrap()["type"], "edit_collision");
+
+        // Still pair-editing, but both are cooling down
+        host.receive("pad", "b1", &insert(1, 2, 10, "!"), now + Duration::from_secs(2)).unwrap();
+        host.receive("pad", "a1", &insert(1, 3, 0, "oh, "), now + Duration::from_secs(2)).unwrap();
+        assert!(host.take_advisories("pad").is_empty());
+
+        // Once the cooldown is over they are told again
+        let later = now + Duration::from_secs(40);
+        host.receive("pad", "a1", &insert(2, 4, 0, "x"), later).unwrap();
+        host.receive("pad", "b1", &insert(2, 5, 1, "y"), later).unwrap();
+        assert_eq!(clients_advised(&host.take_advisories("pad")), vec!["a1", "b1"]);
+    }
+
+    #[test]
+    fn test_collision_proximity_and_same_user() {
+        let host = collision_host();
+        let now = Instant::now();
+        let later = now + Duration::from_secs(5); // Out of the setup edit's window
+        for room in ["near", "far", "self"] {
+            for (client, user) in [("s1", "setup"), ("a1", "alice"), ("a2", "alice"), ("b1", "bob")] {
+                host.join_as(room, client, user, now).unwrap();
+            }
+            host.receive(room, "s1", &insert(0, 0, 0, "0123456789"), now).unwrap();
+            host.receive(room, "a1", &insert(0, 1, 0, "ab"), later).unwrap(); // Writes 0..2
+        }
+
+        host.receive("near", "b1", &insert(0, 2, 4, "x"), later).unwrap(); // 2 bytes away
+        assert_eq!(clients_advised(&host.take_advisories("near")), vec!["a1", "b1"]);
+
+        host.receive("far", "b1", &insert(0, 2, 5, "x"), later).unwrap(); // 3 bytes away
+        assert!(host.take_advisories("far").is_empty());
+
+        host.receive("self", "a2", &insert(0, 2, 1, "x"), later).unwrap(); // alice's other connection
+        assert!(host.take_advisories("self").is_empty());
+    }
+
+    #[test]
+    fn test_collisions_use_transformed_ranges() {
+        let host = collision_host();
+        let now = Instant::now();
+        for (client, user) in [("s1", "setup"), ("a1", "alice"), ("b1", "bob"), ("d1", "dave")] {
+            host.join_as("pad", client, user, now).unwrap();
+        }
+        host.receive("pad", "s1", &insert(0, 0, 0, "0123456789abcdefghij"), now).unwrap();
+        let now = now + Duration::from_secs(5); // Out of the setup edit's window
+
+        // All three edit revision 1. bob's insertion at the start would overlap alice's at 10 if
+        // her range weren't moved past it
+        host.receive("pad", "a1", &insert(0, 1, 10, "XXXX"), now).unwrap();
+        host.receive("pad", "b1", &insert(0, 1, 0, &"Y".repeat(20)), now).unwrap();
+        assert!(host.take_advisories("pad").is_empty());
+
+        // dave's insertion at 12 lands at 36 after both, 2 bytes past alice's text at 30..34
+        host.receive("pad", "d1", &insert(0, 1, 12, "Z"), now).unwrap();
+        let advisories = host.take_advisories("pad");
+        assert_eq!(clients_advised(&advisories), vec!["a1", "d1"]);
+        assert_eq!(advisories[0].1.range, (30, 37));
+        assert_eq!(&host.document("pad").unwrap().0[30..37], "XXXXabZ");
+    }
+
+    /// Permissions in a workspace owned by "ana", where "ed" edits and "vic" views `docs`
+    fn permissions(docs: &[&str]) -> Workspaces {
+        let workspaces = Workspaces::new();
+        let id = workspaces.create("ana", "Team").unwrap().id;
+        workspaces.set_member(&id, "ana", "ed", Some(WorkspaceRole::Editor)).unwrap();
+        workspaces.set_member(&id, "ana", "vic", Some(WorkspaceRole::Viewer)).unwrap();
+        for doc in docs {
+            workspaces.add_doc(&id, "ana", doc).unwrap();
+        }
+        workspaces
+    }
+
+    #[test]
+    fn test_late_joiner_gets_language_in_snapshot() {
+        let host = host(MemoryLimits::new());
+        let workspaces = permissions(&["main.rs"]);
+        let now = Instant::now();
+
+        // A new room starts with the language of its file
+        host.join("main.rs", "ed1", now).unwrap();
+        assert_eq!(host.snapshot("main.rs").unwrap().language.as_deref(), Some("rs"));
+        host.join("notes", "ed1", now).unwrap();
+        assert_eq!(host.snapshot("notes").unwrap().language, None);
+
+        host.receive("main.rs", "ed1", &insert(0, 0, 0, "print('hi')"), now).unwrap();
+        let ed = workspaces.open("main.rs", "ed", None).unwrap();
+        host.set_language("main.rs", &ed, "Python", now).unwrap();
+
+        host.join("main.rs", "late", now).unwrap();
+        let snapshot = host.snapshot("main.rs").unwrap();
+        let expected = SnapshotMessage { text: "print('hi')".to_string(), revision: 1, language: Some("python".to_string()), suggestions: Vec::new() };
+        assert_eq!(snapshot, expected);
+        assert_eq!(serde_json::to_value(&snapshot).unwrap()["type"], "snapshot");
+    }
+
+    #[test]
+    fn test_set_language_fans_out_to_clients_and_diagnostics() {
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::default());
+        let host = RoomHost::new(storage.clone(), MemoryLimits::new());
+        let workspaces = permissions(&["config"]);
+        let now = Instant::now();
+        let diagnostics = StructuredSync::new(storage);
+        let mut server_languages = host.subscribe_languages();
+        let mut client_languages = host.subscribe_languages();
+        host.join("config", "ed1", now).unwrap();
+        host.join("config", "other", now).unwrap();
+        diagnostics.open("config", "", "{ oops");
+        assert!(diagnostics.diagnostics("config").unwrap().is_empty());
+
+        // The frame a client sends to switch its room to JSON
+        let request: SetLanguageMessage = serde_json::from_str(r#"{"type":"set_language","language":"json"}"#).unwrap();
+        let ed = workspaces.open("config", "ed", None).unwrap();
+        let change = host.set_language("config", &ed, &request.language, now).unwrap();
+
+        // The other client is told to switch its highlighter
+        let received = client_languages.try_recv().unwrap();
+        assert_eq!(received, change);
+        assert_eq!(serde_json::to_value(&received).unwrap(), serde_json::json!({ "type": "language", "room": "config", "language": "json" }));
+
+        // And the server's diagnostics start treating the document as JSON
+        diagnostics.follow_languages(&mut server_languages, now);
+        assert_eq!(diagnostics.due_broadcasts(now).len(), 1);
+        assert!(!diagnostics.diagnostics("config").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_language_persists_across_restart() {
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::default());
+        let workspaces = permissions(&["script.js"]);
+        let now = Instant::now();
+        let before = RoomHost::new(storage.clone(), MemoryLimits::new());
+        before.join("script.js", "ed1", now).unwrap();
+        before.set_language("script.js", &workspaces.open("script.js", "ed", None).unwrap(), "typescript", now).unwrap();
+
+        // Saved as soon as it changed, without waiting for the room to be unloaded
+        let after = RoomHost::new(storage, MemoryLimits::new());
+        assert_eq!(after.language("script.js").as_deref(), Some("typescript"));
+        let response = warp::test::request().path("/api/docs/script.js/meta").reply(&room_routes(after.clone(), None)).await;
+        let meta: RoomMeta = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(meta, RoomMeta { id: "script.js".to_string(), revision: 0, language: Some("typescript".to_string()), saved_revision: 0 });
+        assert_eq!(warp::test::request().path("/api/docs/..x/meta").reply(&room_routes(after.clone(), None)).await.status(), 404);
+
+        after.join("script.js", "late", now).unwrap();
+        assert_eq!(after.snapshot("script.js").unwrap().language.as_deref(), Some("typescript"));
+    }
+
+    #[test]
+    fn test_viewer_cannot_change_language() {
+        let host = host(MemoryLimits::new());
+        let workspaces = permissions(&["main.rs", "other.rs"]);
+        let now = Instant::now();
+        let mut languages = host.subscribe_languages();
+        host.join("main.rs", "vic1", now).unwrap();
+
+        let vic = workspaces.open("main.rs", "vic", None).unwrap();
+        assert!(host.set_language("main.rs", &vic, "python", now).is_err());
+        let ed_elsewhere = workspaces.open("other.rs", "ed", None).unwrap();
+        assert!(host.set_language("main.rs", &ed_elsewhere, "python", now).is_err());
+        let ed = workspaces.open("main.rs", "ed", None).unwrap();
+        assert!(host.set_language("main.rs", &ed, "<b>", now).is_err());
+
+        assert_eq!(host.language("main.rs").as_deref(), Some("rs"));
+        assert!(languages.try_recv().is_err());
+    }
+
+    /// Reports an error for every `panic!`
+    struct PanicLinter;
+
+    impl Linter for PanicLinter {
+        fn lint_code(&self, code: &str) -> Vec<LintError> {
+            code.match_indices("panic!").map(|(at, _)| LintError { line: 1, column: at + 1, message: "No panics".to_string(), severity: "error".to_string() }).collect()
+        }
+    }
+
+    #[test]
+    fn test_save_hook_edits_are_broadcast_and_saved() {
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::default());
+        let host = RoomHost::new(storage.clone(), MemoryLimits::new());
+        let workspaces = permissions(&["notes.md"]);
+        let id = workspaces.workspace_of("notes.md").unwrap();
+        let settings = WorkspaceSettings { save_hooks: Some(SaveHooksConfig::new().with_hooks(vec![SaveHook::TrimTrailingWhitespace, SaveHook::FinalNewline])), ..WorkspaceSettings::default() };
+        workspaces.configure(&id, "ana", settings, None).unwrap();
+        let now = Instant::now();
+        host.join("notes.md", "ed1", now).unwrap();
+        host.receive("notes.md", "ed1", &insert(0, 0, 0, "Hello  \nworld"), now).unwrap();
+
+        // The workspace's hooks apply, as an edit by the hooks for everyone in the room
+        let receipt = host.save("notes.md", SaveKind::Manual, &SaveHooks::new(), &workspaces.save_hook_defaults("notes.md"), now);
+        assert_eq!(receipt.reply, Ok(SavedMessage { revision: 2, warnings: Vec::new() }));
+        let edit = receipt.edit.unwrap();
+        assert_eq!((edit.revision, edit.author.as_deref()), (2, Some(SAVE_HOOKS_ACTOR)));
+        assert_eq!(host.document("notes.md").unwrap(), ("Hello\nworld\n".to_string(), 2));
+
+        let after = RoomHost::new(storage, MemoryLimits::new());
+        assert_eq!(after.join("notes.md", "ed1", now).unwrap(), ("Hello\nworld\n".to_string(), 2));
+        assert_eq!(after.meta("notes.md").unwrap().saved_revision, 2);
+
+        // The document's own hooks override the workspace's
+        let ed = workspaces.open("notes.md", "ed", None).unwrap();
+        after.set_save_hooks("notes.md", &ed, &SaveHooksConfig::new(), now).unwrap();
+        after.receive("notes.md", "ed1", &insert(1, 2, 0, "# "), now).unwrap();
+        let receipt = after.save("notes.md", SaveKind::Manual, &SaveHooks::new(), &workspaces.save_hook_defaults("notes.md"), now);
+        assert!(receipt.edit.is_none());
+        assert_eq!(receipt.reply.unwrap().revision, 3);
+    }
+
+    #[test]
+    fn test_vetoed_save_leaves_document_unsaved() {
+        let host = host(MemoryLimits::new());
+        let workspaces = permissions(&["main.rs"]);
+        let hooks = SaveHooks::new().with_linter("rs", Arc::new(PanicLinter));
+        let now = Instant::now();
+        host.join("main.rs", "ed1", now).unwrap();
+        let config = SaveHooksConfig::new().with_hooks(vec![SaveHook::TrimTrailingWhitespace, SaveHook::LintGate { block_on: "error".to_string() }]);
+        host.set_save_hooks("main.rs", &workspaces.open("main.rs", "ed", None).unwrap(), &config, now).unwrap();
+        host.receive("main.rs", "ed1", &insert(0, 0, 0, "fn main() { panic!() }  "), now).unwrap();
+
+        let receipt = host.save("main.rs", SaveKind::Checkpoint, &hooks, &SaveHooksConfig::new(), now);
+        let rejected = receipt.reply.unwrap_err();
+        assert_eq!(rejected.hook.as_deref(), Some("lint_gate"));
+        assert_eq!(rejected.diagnostics, vec![LintError { line: 1, column: 13, message: "No panics".to_string(), severity: "error".to_string() }]);
+        assert_eq!(serde_json::to_value(&rejected).unwrap()["type"], "save_rejected");
+
+        // Nothing the hooks did is kept, and the edits stay unsaved
+        assert!(receipt.edit.is_none());
+        assert_eq!(host.document("main.rs").unwrap().0, "fn main() { panic!() }  ");
+        let meta = host.meta("main.rs").unwrap();
+        assert_eq!((meta.revision, meta.saved_revision), (1, 0));
+        assert!(host.checkpoints("main.rs").unwrap().is_empty());
+
+        // Autosaves skip the hooks by default
+        assert_eq!(host.save("main.rs", SaveKind::Autosave, &hooks, &SaveHooksConfig::new(), now).reply.unwrap().revision, 1);
+
+        host.receive("main.rs", "ed1", &DeltaMessage { operations: vec![DiffOperation::Replace(12, 20, "()".to_string())], ..insert(1, 1, 0, "") }, now).unwrap();
+        let receipt = host.save("main.rs", SaveKind::Checkpoint, &hooks, &SaveHooksConfig::new(), now);
+        assert_eq!(receipt.reply.unwrap().revision, 3);
+        assert_eq!(host.checkpoints("main.rs").unwrap(), vec![Checkpoint { name: "Saved".to_string(), revision: 3, text: "fn main() { () }".to_string() }]);
+    }
+
+    #[tokio::test]
+    async fn test_save_hooks_route_validates_config() {
+        let host = host(MemoryLimits::new());
+        let workspaces = permissions(&["main.rs"]);
+        let routes = save_hook_routes(host.clone(), workspaces);
+        let put = |user: &str, body: &str| warp::test::request().method("PUT").path("/api/docs/main.rs/save-hooks").header("authorization", bearer_for(user)).body(body);
+
+        let valid = r#"{"hooks":[{"hook":"format"},{"hook":"final_newline"}],"on_autosave":true}"#;
+        assert_eq!(put("vic", valid).reply(&routes).await.status(), 403);
+        // Naming an editor isn't signing in as one
+        let unsigned = warp::test::request().method("PUT").path("/api/docs/main.rs/save-hooks?user=ed").body(valid);
+        assert_eq!(unsigned.reply(&routes).await.status(), 403);
+        let response = put("ed", r#"{"hooks":[{"hook":"minify"}]}"#).reply(&routes).await;
+        assert_eq!(response.status(), 400);
+        let error: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(error["message"].as_str().unwrap().contains("minify"));
+        assert_eq!(host.save_hooks("main.rs"), None);
+
+        assert_eq!(put("ed", valid).reply(&routes).await.status(), 200);
+        let config = host.save_hooks("main.rs").unwrap();
+        assert_eq!((config.hooks, config.on_autosave), (vec![SaveHook::Format, SaveHook::FinalNewline], true));
+    }
+
+    fn suggest(range: (usize, usize), text: &str, revision: u64) -> SuggestMessage {
+        SuggestMessage { range, text: text.to_string(), revision }
+    }
+
+    fn accept(id: u64) -> ResolveSuggestionMessage {
+        ResolveSuggestionMessage { id, accept: true }
+    }
+
+    #[test]
+    fn test_accepted_suggestion_applies_and_invalidates_overlaps() {
+        let host = host(MemoryLimits::new());
+        let workspaces = permissions(&["main.rs"]);
+        let (ed, vic) = (workspaces.open("main.rs", "ed", None).unwrap(), workspaces.open("main.rs", "vic", None).unwrap());
+        let now = Instant::now();
+        host.join("main.rs", "ed1", now).unwrap();
+        host.receive("main.rs", "ed1", &insert(0, 0, 0, "let x = 1;\nlet y = 2;\n"), now).unwrap();
+
+        let rename = host.suggest("main.rs", &vic, &suggest((4, 5), "count", 1), now).unwrap().suggestion;
+        let overlapping = host.suggest("main.rs", &ed, &suggest((4, 9), "total = 10", 1), now).unwrap().suggestion;
+        let elsewhere = host.suggest("main.rs", &vic, &suggest((15, 16), "z", 1), now).unwrap().suggestion;
+        assert_eq!(host.document("main.rs").unwrap(), ("let x = 1;\nlet y = 2;\n".to_string(), 1));
+
+        let (edit, changed) = host.resolve_suggestion("main.rs", &ed, &accept(rename.id), now).unwrap();
+        let edit = edit.unwrap();
+        assert_eq!((edit.revision, edit.operations, edit.author.as_deref()), (2, vec![DiffOperation::Replace(4, 5, "count".to_string())], Some("ed")));
+        assert_eq!(host.document("main.rs").unwrap(), ("let count = 1;\nlet y = 2;\n".to_string(), 2));
+        let statuses: Vec<(u64, SuggestionStatus)> = changed.iter().map(|message| (message.suggestion.id, message.suggestion.status)).collect();
+        assert_eq!(statuses, vec![(rename.id, SuggestionStatus::Accepted), (overlapping.id, SuggestionStatus::Invalidated)]);
+        assert_eq!(changed[0].suggestion.resolved_by.as_deref(), Some("ed"));
+
+        // The one elsewhere moved along with the accepted edit, and applies where it should
+        let open = host.suggestions("main.rs", Some(SuggestionStatus::Open)).unwrap();
+        assert_eq!(open, vec![Suggestion { range: (19, 20), ..elsewhere.clone() }]);
+        host.resolve_suggestion("main.rs", &ed, &accept(elsewhere.id), now).unwrap();
+        assert_eq!(host.document("main.rs").unwrap().0, "let count = 1;\nlet z = 2;\n");
+        assert!(host.resolve_suggestion("main.rs", &ed, &accept(overlapping.id), now).is_err());
+        assert!(host.resolve_suggestion("main.rs", &ed, &accept(rename.id), now).is_err());
+        assert_eq!(host.document("main.rs").unwrap().1, 3);
+    }
+
+    #[test]
+    fn test_only_editors_resolve_suggestions() {
+        let host = host(MemoryLimits::new());
+        let workspaces = permissions(&["main.rs", "other.rs"]);
+        let (ed, vic) = (workspaces.open("main.rs", "ed", None).unwrap(), workspaces.open("main.rs", "vic", None).unwrap());
+        let now = Instant::now();
+        host.join("main.rs", "ed1", now).unwrap();
+        host.receive("main.rs", "ed1", &insert(0, 0, 0, "fn main() {}"), now).unwrap();
+        let suggestion = host.suggest("main.rs", &vic, &suggest((12, 12), "\n", 1), now).unwrap().suggestion;
+
+        assert!(host.resolve_suggestion("main.rs", &vic, &accept(suggestion.id), now).is_err());
+        let ed_elsewhere = workspaces.open("other.rs", "ed", None).unwrap();
+        assert!(host.resolve_suggestion("main.rs", &ed_elsewhere, &accept(suggestion.id), now).is_err());
+        assert_eq!(host.document("main.rs").unwrap(), ("fn main() {}".to_string(), 1));
+        assert_eq!(host.suggestions("main.rs", Some(SuggestionStatus::Open)).unwrap().len(), 1);
+
+        // Rejecting leaves the document as it is
+        let (edit, changed) = host.resolve_suggestion("main.rs", &ed, &ResolveSuggestionMessage { id: suggestion.id, accept: false }, now).unwrap();
+        assert!(edit.is_none());
+        assert_eq!(changed[0].suggestion, Suggestion { status: SuggestionStatus::Rejected, resolved_by: Some("ed".to_string()), ..suggestion.clone() });
+        assert_eq!(host.document("main.rs").unwrap(), ("fn main() {}".to_string(), 1));
+        assert!(host.suggestions("main.rs", Some(SuggestionStatus::Open)).unwrap().is_empty());
+        assert!(host.resolve_suggestion("main.rs", &ed, &accept(suggestion.id), now).is_err());
+    }
+
+    #[test]
+    fn test_viewer_suggestions_follow_policy() {
+        let workspaces = permissions(&["main.rs"]);
+        let (ed, vic) = (workspaces.open("main.rs", "ed", None).unwrap(), workspaces.open("main.rs", "vic", None).unwrap());
+        let now = Instant::now();
+        let open = host(MemoryLimits::new());
+        let closed = host(MemoryLimits::new()).with_suggestion_policy(SuggestionPolicy { viewers_may_suggest: false });
+        for host in [&open, &closed] {
+            host.join("main.rs", "ed1", now).unwrap();
+            host.receive("main.rs", "ed1", &insert(0, 0, 0, "hello"), now).unwrap();
+        }
+
+        let made = open.suggest("main.rs", &vic, &suggest((0, 5), "goodbye", 1), now).unwrap();
+        assert_eq!((made.revision, made.suggestion.author.as_str(), made.suggestion.status), (1, "vic", SuggestionStatus::Open));
+        assert_eq!(serde_json::to_value(&made).unwrap()["type"], "suggestion");
+        assert!(closed.suggest("main.rs", &vic, &suggest((0, 5), "goodbye", 1), now).is_err());
+        assert!(closed.suggest("main.rs", &ed, &suggest((0, 5), "goodbye", 1), now).is_ok());
+
+        // Ranges must be in the latest document
+        assert!(open.suggest("main.rs", &vic, &suggest((0, 5), "goodbye", 0), now).is_err());
+        assert!(open.suggest("main.rs", &vic, &suggest((3, 9), "p", 1), now).is_err());
+        assert!(open.suggest("main.rs", &vic, &suggest((0, 5), "hello", 1), now).is_err());
+        assert_eq!(open.suggestions("main.rs", None).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_suggestions_persist_and_list_by_status() {
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::default());
+        let workspaces = permissions(&["pad.md"]);
+        let (ed, vic) = (workspaces.open("pad.md", "ed", None).unwrap(), workspaces.open("pad.md", "vic", None).unwrap());
+        let now = Instant::now();
+        let before = RoomHost::new(storage.clone(), MemoryLimits::new());
+        before.join("pad.md", "ed1", now).unwrap();
+        before.receive("pad.md", "ed1", &insert(0, 0, 0, "# Notes"), now).unwrap();
+        let kept = before.suggest("pad.md", &vic, &suggest((2, 7), "Minutes", 1), now).unwrap().suggestion;
+        let dropped = before.suggest("pad.md", &vic, &suggest((0, 0), "\n", 1), now).unwrap().suggestion;
+        before.receive("pad.md", "ed1", &insert(1, 1, 0, "Draft\n"), now).unwrap();
+        let kept = Suggestion { range: (8, 13), ..kept };
+        assert_eq!(before.snapshot("pad.md").unwrap().suggestions, vec![kept.clone(), Suggestion { range: (6, 6), ..dropped.clone() }]);
+        before.resolve_suggestion("pad.md", &ed, &ResolveSuggestionMessage { id: dropped.id, accept: false }, now).unwrap();
+
+        // Saved right away, along with the edits before
+        let after = RoomHost::new(storage, MemoryLimits::new());
+        assert_eq!(after.suggestions("pad.md", Some(SuggestionStatus::Open)).unwrap(), vec![kept.clone()]);
+        let routes = suggestion_routes(after.clone(), workspaces);
+        let get = |user: &str, query: &str| warp::test::request().path(&format!("/api/docs/pad.md/suggestions?{}", query)).header("authorization", bearer_for(user));
+        let response = get("vic", "status=open").reply(&routes).await;
+        assert_eq!(serde_json::from_slice::<Vec<Suggestion>>(response.body()).unwrap(), vec![kept.clone()]);
+        let response = get("vic", "").reply(&routes).await;
+        assert_eq!(serde_json::from_slice::<Vec<Suggestion>>(response.body()).unwrap().len(), 2);
+        assert_eq!(get("vic", "status=maybe").reply(&routes).await.status(), 400);
+        assert_eq!(get("eve", "status=open").reply(&routes).await.status(), 403);
+
+        after.join("pad.md", "late", now).unwrap();
+        assert_eq!(after.snapshot("pad.md").unwrap().suggestions, vec![kept.clone()]);
+        after.resolve_suggestion("pad.md", &ed, &accept(kept.id), now).unwrap();
+        assert_eq!(after.document("pad.md").unwrap().0, "Draft\n# Minutes");
+    }
+
+    fn bulk(seq: u64, base_revision: u64, operation: BulkOperation, operations: Vec<DiffOperation>) -> DeltaMessage {
+        DeltaMessage { bulk: Some(operation), operations, ..insert(seq, base_revision, 0, "") }
+    }
+
+    #[test]
+    fn test_bulk_operations_are_checkpointed() {
+        let history_dir = std::env::temp_dir().join(format!("rustpad-bulk-{}", std::process::id()));
+        let history = Arc::new(Mutex::new(HistoryManager::new(&history_dir.to_string_lossy(), 20)));
+        let host = paste_host().with_history(history.clone());
+        let now = Instant::now();
+        host.join_as("notes.txt", "ana1", "ana", now).unwrap();
+        host.receive("notes.txt", "ana1", &insert(0, 0, 0, "one two one"), now).unwrap();
+
+        // The checkpoint id comes back in both the acknowledgement and the broadcast edit
+        let replace_all = bulk(1, 1, BulkOperation::ReplaceAll, vec![DiffOperation::Replace(8, 11, "1".to_string()), DiffOperation::Replace(0, 3, "1".to_string())]);
+        let Ok(Receipt::Applied(ack, remote)) = host.receive("notes.txt", "ana1", &replace_all, now) else { panic!("not applied") };
+        assert_eq!((ack.checkpoint_id, remote.checkpoint_id, remote.revision), (Some(1), Some(1), 2));
+        let frame = serde_json::to_value(ProtocolMessage::RemoteDelta(remote)).unwrap();
+        assert_eq!(frame["data"]["checkpoint_id"], 1);
+
+        let format = bulk(2, 2, BulkOperation::Format, vec![DiffOperation::Replace(2, 5, "TWO".to_string())]);
+        assert!(matches!(host.receive("notes.txt", "ana1", &format, now), Ok(Receipt::Applied(ack, _)) if ack.checkpoint_id == Some(2)));
+        let merge = bulk(3, 3, BulkOperation::Merge, vec![DiffOperation::Insert(7, "\nmerged".to_string())]);
+        assert!(matches!(host.receive("notes.txt", "ana1", &merge, now), Ok(Receipt::Applied(ack, _)) if ack.checkpoint_id == Some(3)));
+        assert!(matches!(host.receive("notes.txt", "ana1", &paste(4, 4, "0123456789AB"), now), Ok(Receipt::Held(_))));
+        let Ok(Receipt::Applied(_, remote)) = host.resolve_paste("notes.txt", "ana1", &PasteDecisionMessage { seq: 4, confirm: true }, now) else { panic!("not applied") };
+        assert_eq!(remote.checkpoint_id, Some(4));
+        let Ok(Receipt::Applied(_, remote)) = host.revert_to_checkpoint("notes.txt", 4, "ana", now) else { panic!("not applied") };
+        assert_eq!((remote.checkpoint_id, remote.author.as_deref()), (Some(5), Some("ana")));
+
+        // Save hooks that change the document format it
+        host.receive("notes.txt", "ana1", &insert(5, 6, 14, "  "), now).unwrap();
+        let trim = SaveHooksConfig::new().with_hooks(vec![SaveHook::TrimTrailingWhitespace]);
+        let edit = host.save("notes.txt", SaveKind::Manual, &SaveHooks::new(), &trim, now).edit.unwrap();
+        assert_eq!((edit.checkpoint_id, edit.revision), (Some(6), 8));
+        // Small edits and pastes aren't bulk operations
+        assert!(matches!(host.receive("notes.txt", "ana1", &paste(6, 8, "hi"), now), Ok(Receipt::Applied(ack, _)) if ack.checkpoint_id.is_none()));
+
+        let checkpoints = host.bulk_checkpoints("notes.txt").unwrap();
+        let kept: Vec<(u64, BulkOperation, &str, u64, Option<usize>)> =
+            checkpoints.iter().map(|checkpoint| (checkpoint.id, checkpoint.operation, checkpoint.actor.as_str(), checkpoint.revision, checkpoint.version_id)).collect();
+        assert_eq!(kept, vec![
+            (1, BulkOperation::ReplaceAll, "ana", 2, Some(1)),
+            (2, BulkOperation::Format, "ana", 3, Some(2)),
+            (3, BulkOperation::Merge, "ana", 4, Some(3)),
+            (4, BulkOperation::Paste, "ana", 5, Some(4)),
+            (5, BulkOperation::Revert, "ana", 6, Some(5)),
+            (6, BulkOperation::Format, SAVE_HOOKS_ACTOR, 8, Some(6)),
+        ]);
+        assert_eq!(checkpoints[0].ranges, vec![(6, 7), (0, 1)]);
+
+        // The history has the document from before each, tagged with the operation and actor
+        let versions = history.lock().unwrap().list_versions("notes.txt");
+        let tagged: Vec<(&str, Option<&str>, Option<&str>)> = versions.iter().map(|version| (version.content.as_str(), version.operation.as_deref(), version.actor.as_deref())).collect();
+        assert_eq!(tagged, vec![
+            ("one two one", Some("replace_all"), Some("ana")),
+            ("1 two 1", Some("format"), Some("ana")),
+            ("1 TWO 1", Some("merge"), Some("ana")),
+            ("1 TWO 1\nmerged", Some("paste"), Some("ana")),
+            ("0123456789AB1 TWO 1\nmerged", Some("revert"), Some("ana")),
+            ("1 TWO 1\nmerged  ", Some("format"), Some(SAVE_HOOKS_ACTOR)),
+        ]);
+        assert_eq!(versions[0].description, "Before replace all by ana");
+        std::fs::remove_dir_all(&history_dir).unwrap();
+    }
+
+    /// A room "main.rs" where "ed" replaced `b` with `total` as a bulk operation, kept as checkpoint 1
+    fn replaced(host: &RoomHost, now: Instant) {
+        host.join_as("main.rs", "ed1", "ed", now).unwrap();
+        host.join_as("main.rs", "ana1", "ana", now).unwrap();
+        host.receive("main.rs", "ed1", &insert(0, 0, 0, "let a = 1;\nlet b = 2;\n"), now).unwrap();
+        host.receive("main.rs", "ed1", &bulk(1, 1, BulkOperation::ReplaceAll, vec![DiffOperation::Replace(15, 16, "total".to_string())]), now).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_is_broadcast_and_keeps_later_edits() {
+        let host = host(MemoryLimits::new());
+        let workspaces = permissions(&["main.rs", "other.rs"]);
+        let now = Instant::now();
+        replaced(&host, now);
+        host.receive("main.rs", "ana1", &insert(0, 2, 0, "// vars\n"), now).unwrap();
+        let (mut ed_client, mut ana_client) = (host.subscribe_rollbacks(), host.subscribe_rollbacks());
+
+        // Only editors of the room may roll back
+        let vic = workspaces.open("main.rs", "vic", None).unwrap();
+        assert!(matches!(host.rollback("main.rs", &vic, 1, now), Err(RollbackError::Forbidden(_))));
+        let ed_elsewhere = workspaces.open("other.rs", "ed", None).unwrap();
+        assert!(matches!(host.rollback("main.rs", &ed_elsewhere, 1, now), Err(RollbackError::Forbidden(_))));
+        assert!(ed_client.try_recv().is_err());
+
+        let ed = workspaces.open("main.rs", "ed", None).unwrap();
+        let edit = host.rollback("main.rs", &ed, 1, now).unwrap();
+        assert_eq!((edit.revision, edit.operations.clone(), edit.author.as_deref()), (4, vec![DiffOperation::Replace(23, 28, "b".to_string())], Some("ed")));
+        assert_eq!(host.document("main.rs").unwrap(), ("// vars\nlet a = 1;\nlet b = 2;\n".to_string(), 4));
+        for client in [&mut ed_client, &mut ana_client] {
+            let (room, broadcast) = client.try_recv().unwrap();
+            assert_eq!((room.as_str(), broadcast.revision, broadcast.operations), ("main.rs", 4, edit.operations.clone()));
+        }
+
+        // Once only
+        assert!(matches!(host.rollback("main.rs", &ed, 1, now), Err(RollbackError::Refused(_))));
+        assert!(matches!(host.rollback("main.rs", &ed, 7, now), Err(RollbackError::NotFound(_))));
+        assert!(host.bulk_checkpoints("main.rs").unwrap()[0].rolled_back);
+    }
+
+    #[tokio::test]
+    async fn test_rollback_route_refuses_conflicts_with_their_ranges() {
+        let host = host(MemoryLimits::new());
+        let workspaces = permissions(&["main.rs"]);
+        let now = Instant::now();
+        replaced(&host, now);
+        // "total" becomes "tOTal", overlapping the replacement by 4 bytes; the comment doesn't
+        host.receive("main.rs", "ana1", &DeltaMessage { operations: vec![DiffOperation::Replace(16, 18, "OT".to_string())], ..insert(0, 2, 0, "") }, now).unwrap();
+        host.receive("main.rs", "ana1", &insert(1, 3, 0, "// x\n"), now).unwrap();
+        let post = |user: &str, id: u64| warp::test::request().method("POST").path(&format!("/api/docs/main.rs/rollback/{}", id)).header("authorization", bearer_for(user));
+
+        let routes = rollback_routes(host.clone(), workspaces.clone());
+        assert_eq!(post("vic", 1).reply(&routes).await.status(), 403);
+        assert_eq!(post("ed", 7).reply(&routes).await.status(), 404);
+        let response = post("ed", 1).reply(&routes).await;
+        assert_eq!(response.status(), 409);
+        let error: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(error["conflicts"], serde_json::json!([[21, 23]]));
+        assert_eq!(host.document("main.rs").unwrap(), ("// x\nlet a = 1;\nlet tOTal = 2;\n".to_string(), 4));
+
+        // Within a more lenient threshold it goes through, keeping the unrelated edit
+        let lenient = rollback_routes(host.clone().with_rollback_policy(RollbackPolicy { max_overlap: 4 }), workspaces);
+        let response = post("ed", 1).reply(&lenient).await;
+        assert_eq!(response.status(), 200);
+        let edit: RemoteDeltaMessage = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!((edit.revision, edit.author.as_deref()), (5, Some("ed")));
+        assert!(host.document("main.rs").unwrap().0.starts_with("// x\nlet a = 1;\nlet "));
+    }
+}
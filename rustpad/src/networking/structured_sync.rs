@@ -0,0 +1,277 @@
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+use crate::editor::linter::LintError;
+use crate::editor::structured::{analyze, StructuredFormat};
+use crate::networking::protocol::{DeltaMessage, LanguageMessage, RejectMessage};
+use crate::networking::revision_log::{Receipt, RevisionLog};
+use crate::storage::Storage;
+
+/// How long a structured document must stay unchanged before it is validated again
+pub const DIAGNOSTICS_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Largest JSON Schema accepted, in bytes
+pub const MAX_SCHEMA_SIZE: usize = 256 * 1024;
+
+/// Storage namespace schemas are saved under, followed by the document id
+const NAMESPACE: &str = "schemas/";
+
+struct StructuredDoc {
+    log: RevisionLog,
+    format: Option<StructuredFormat>,   // `None` when the document isn't in structured mode
+    schema: Option<Arc<JSONSchema>>,
+    diagnostics: Vec<LintError>,        // As of the last validation
+    due_at: Option<Instant>,            // When to validate again and broadcast, if pending
+}
+
+/// Parses and validates documents in structured mode as they change, and keeps each
+/// document's JSON Schema in storage
+#[derive(Clone)]
+pub struct StructuredSync {
+    docs: Arc<Mutex<HashMap<String, StructuredDoc>>>, // Keyed by document id
+    storage: Arc<dyn Storage + Send + Sync>,
+}
+
+impl StructuredSync {
+    /// Creates a StructuredSync tracking no documents, keeping schemas in `storage`
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self { docs: Arc::new(Mutex::new(HashMap::new())), storage }
+    }
+
+    /// Starts tracking `doc_id` with its current `text`. Documents whose `language` is JSON or
+    /// YAML are in structured mode, validated against the schema stored for them, if any.
+    pub fn open(&self, doc_id: &str, language: &str, text: &str) {
+        let schema = self
+            .storage
+            .load(&schema_key(doc_id))
+            .ok()
+            .and_then(|saved| compile_schema(saved.as_bytes()).ok())
+            .map(Arc::new);
+        let mut doc = StructuredDoc { log: RevisionLog::new(text), format: StructuredFormat::for_language(language), schema, diagnostics: Vec::new(), due_at: None };
+        doc.validate();
+        self.docs.lock().unwrap().insert(doc_id.to_string(), doc);
+    }
+
+    /// Switches `doc_id` in or out of structured mode when its language metadata changes
+    pub fn set_language(&self, doc_id: &str, language: &str, now: Instant) {
+        if let Some(doc) = self.docs.lock().unwrap().get_mut(doc_id) {
+            doc.format = StructuredFormat::for_language(language);
+            doc.due_at = Some(now);
+        }
+    }
+
+    /// Applies the room language changes that arrived on `languages` (from
+    /// `RoomHost::subscribe_languages`) since the last call
+    pub fn follow_languages(&self, languages: &mut broadcast::Receiver<LanguageMessage>, now: Instant) {
+        loop {
+            match languages.try_recv() {
+                Ok(change) => self.set_language(&change.room, &change.language, now),
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Applies a client's delta to `doc_id`; it is validated once edits settle
+    pub fn receive(&self, doc_id: &str, delta: &DeltaMessage, now: Instant) -> Result<Receipt, RejectMessage> {
+        let mut docs = self.docs.lock().unwrap();
+        let doc = docs.get_mut(doc_id).ok_or_else(|| RejectMessage { seq: delta.seq, reason: format!("Unknown document {}", doc_id), quota: None })?;
+        let receipt = doc.log.receive(delta)?;
+        if doc.format.is_some() && matches!(receipt, Receipt::Applied(..)) {
+            doc.due_at = Some(now + DIAGNOSTICS_DEBOUNCE);
+        }
+        Ok(receipt)
+    }
+
+    /// Attaches the JSON Schema in `schema` to `doc_id`, saving it to storage. Connected clients
+    /// get the new diagnostics with the next broadcasts, without waiting for an edit.
+    pub fn set_schema(&self, doc_id: &str, schema: &[u8], now: Instant) -> Result<(), String> {
+        if schema.len() > MAX_SCHEMA_SIZE {
+            return Err(format!("Schemas are limited to {} bytes", MAX_SCHEMA_SIZE));
+        }
+        let compiled = compile_schema(schema)?;
+        let mut docs = self.docs.lock().unwrap();
+        let doc = docs.get_mut(doc_id).ok_or_else(|| format!("Unknown document {}", doc_id))?;
+        self.storage
+            .save(&schema_key(doc_id), &String::from_utf8_lossy(schema))
+            .map_err(|e| format!("Failed to store schema: {}", e))?;
+        doc.schema = Some(Arc::new(compiled));
+        doc.due_at = Some(now);
+        Ok(())
+    }
+
+    /// Whether `doc_id` is being tracked
+    pub fn is_open(&self, doc_id: &str) -> bool {
+        self.docs.lock().unwrap().contains_key(doc_id)
+    }
+
+    /// Diagnostics of `doc_id` as of its last validation
+    pub fn diagnostics(&self, doc_id: &str) -> Option<Vec<LintError>> {
+        self.docs.lock().unwrap().get(doc_id).map(|doc| doc.diagnostics.clone())
+    }
+
+    /// The current revision of `doc_id` parsed, or the parse error. `None` if the document
+    /// isn't open or isn't in structured mode.
+    pub fn parsed(&self, doc_id: &str) -> Option<(u64, Result<Value, LintError>)> {
+        let docs = self.docs.lock().unwrap();
+        let doc = docs.get(doc_id)?;
+        Some((doc.log.revision(), doc.format?.parse(doc.log.text())))
+    }
+
+    /// `{"type":"diagnostics"}` broadcasts for documents that are due for validation: edits
+    /// settled for `DIAGNOSTICS_DEBOUNCE`, or the schema or language changed
+    pub fn due_broadcasts(&self, now: Instant) -> Vec<(String, Value)> {
+        let mut docs = self.docs.lock().unwrap();
+        let mut due = Vec::new();
+        for (doc_id, doc) in docs.iter_mut() {
+            if doc.due_at.is_some_and(|due_at| now >= due_at) {
+                doc.validate();
+                due.push((doc_id.clone(), serde_json::json!({ "type": "diagnostics", "doc": doc_id, "diagnostics": doc.diagnostics })));
+            }
+        }
+        due
+    }
+}
+
+impl StructuredDoc {
+    fn validate(&mut self) {
+        self.due_at = None;
+        self.diagnostics = match self.format {
+            Some(format) => analyze(format, self.log.text(), self.schema.as_deref()).1,
+            None => Vec::new(),
+        };
+    }
+}
+
+fn compile_schema(schema: &[u8]) -> Result<JSONSchema, String> {
+    let schema: Value = serde_json::from_slice(schema).map_err(|e| format!("Schema is not valid JSON: {}", e))?;
+    JSONSchema::compile(&schema).map_err(|e| format!("Invalid schema: {}", e))
+}
+
+fn schema_key(doc_id: &str) -> String {
+    format!("{}{}.json", NAMESPACE, doc_id)
+}
+
+fn error_reply(status: StatusCode, message: &str) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": message })), status).into_response()
+}
+
+/// Handler for `PUT /api/docs/:id/schema`
+pub async fn schema_handler(doc_id: String, body: warp::hyper::body::Bytes, sync: StructuredSync) -> Result<warp::reply::Response, warp::Rejection> {
+    if !sync.is_open(&doc_id) {
+        return Err(warp::reject::not_found());
+    }
+    match sync.set_schema(&doc_id, &body, Instant::now()) {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(e) if body.len() > MAX_SCHEMA_SIZE => Ok(error_reply(StatusCode::PAYLOAD_TOO_LARGE, &e)),
+        Err(e) => Ok(error_reply(StatusCode::BAD_REQUEST, &e)),
+    }
+}
+
+/// Handler for `GET /api/docs/:id/parsed`. The ETag is the document revision, so clients
+/// polling with `If-None-Match` only get a body when the document changed.
+pub async fn parsed_handler(doc_id: String, if_none_match: Option<String>, sync: StructuredSync) -> Result<warp::reply::Response, warp::Rejection> {
+    let (revision, parsed) = sync.parsed(&doc_id).ok_or_else(warp::reject::not_found)?;
+    let etag = format!("\"{}\"", revision);
+    if if_none_match.as_deref() == Some(etag.as_str()) {
+        return Ok(warp::reply::with_header(StatusCode::NOT_MODIFIED, "etag", etag).into_response());
+    }
+    let reply = match parsed {
+        Ok(value) => warp::reply::json(&value).into_response(),
+        Err(error) => warp::reply::with_status(warp::reply::json(&error), StatusCode::UNPROCESSABLE_ENTITY).into_response(),
+    };
+    Ok(warp::reply::with_header(reply, "etag", etag).into_response())
+}
+
+/// Routes for attaching schemas to documents and reading their parsed value
+pub fn structured_routes(sync: StructuredSync) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let schema_sync = sync.clone();
+    let schema = warp::path!("api" / "docs" / String / "schema")
+        .and(warp::put())
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || schema_sync.clone()))
+        .and_then(schema_handler);
+    let parsed = warp::path!("api" / "docs" / String / "parsed")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::any().map(move || sync.clone()))
+        .and_then(parsed_handler);
+    schema.or(parsed).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::editor::diff_engine::DiffOperation;
+
+    const CONFIG: &str = "{\n  \"name\": \"pad\",\n  \"port\": \"80\"\n}";
+    const SCHEMA: &str = r#"{ "properties": { "port": { "type": "integer" } } }"#;
+
+    fn sync_with_config() -> StructuredSync {
+        let sync = StructuredSync::new(Arc::new(MemoryStorage::default()));
+        sync.open("config", "json", CONFIG);
+        sync
+    }
+
+    #[test]
+    fn test_schema_update_revalidates_connected_clients() {
+        let sync = sync_with_config();
+        let now = Instant::now();
+        assert!(sync.diagnostics("config").unwrap().is_empty());
+
+        sync.set_schema("config", SCHEMA.as_bytes(), now).unwrap();
+        let due = sync.due_broadcasts(now);
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].1["type"].as_str(), due[0].1["diagnostics"][0]["line"].as_u64()), (Some("diagnostics"), Some(3)));
+        assert!(sync.due_broadcasts(now).is_empty());
+
+        // Edits are validated once they settle, and the schema survives reopening
+        let delta = DeltaMessage { client_id: String::new(), seq: 0, base_revision: 0, paste: false, bulk: None, operations: vec![DiffOperation::Replace(29, 33, "80".to_string())] };
+        sync.receive("config", &delta, now).unwrap();
+        assert!(sync.due_broadcasts(now + Duration::from_millis(100)).is_empty());
+        assert_eq!(sync.due_broadcasts(now + DIAGNOSTICS_DEBOUNCE)[0].1["diagnostics"], serde_json::json!([]));
+
+        sync.open("config", "json", CONFIG);
+        assert_eq!(sync.diagnostics("config").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_oversized_or_invalid_schema_rejected() {
+        let sync = sync_with_config();
+        let oversized = format!(r#"{{ "description": "{}" }}"#, "x".repeat(MAX_SCHEMA_SIZE));
+        assert!(sync.set_schema("config", oversized.as_bytes(), Instant::now()).is_err());
+        assert!(sync.set_schema("config", br#"{ "type": 12 }"#, Instant::now()).is_err());
+        assert!(sync.set_schema("other", SCHEMA.as_bytes(), Instant::now()).is_err());
+        assert!(sync.due_broadcasts(Instant::now()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_parsed_endpoint_etag() {
+        let sync = sync_with_config();
+        let route = structured_routes(sync.clone());
+
+        let response = warp::test::request().path("/api/docs/config/parsed").reply(&route).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["etag"], "\"0\"");
+        let value: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(value["name"], "pad");
+
+        let cached = warp::test::request().path("/api/docs/config/parsed").header("if-none-match", "\"0\"").reply(&route).await;
+        assert_eq!(cached.status(), 304);
+
+        let delta = DeltaMessage { client_id: String::new(), seq: 0, base_revision: 0, paste: false, bulk: None, operations: vec![DiffOperation::Delete(0, 1)] };
+        sync.receive("config", &delta, Instant::now()).unwrap();
+        let changed = warp::test::request().path("/api/docs/config/parsed").header("if-none-match", "\"0\"").reply(&route).await;
+        assert_eq!((changed.status().as_u16(), changed.headers()["etag"].to_str().unwrap()), (422, "\"1\""));
+
+        let oversized = vec![b' '; MAX_SCHEMA_SIZE + 1];
+        let rejected = warp::test::request().method("PUT").path("/api/docs/config/schema").body(oversized).reply(&route).await;
+        assert_eq!(rejected.status(), 413);
+    }
+}
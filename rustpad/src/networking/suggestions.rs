@@ -0,0 +1,212 @@
+use serde::{Deserialize, Serialize};
+
+use crate::editor::diff_engine::DiffOperation;
+use crate::networking::room_host::map_range;
+
+/// Longest replacement text a suggestion may propose
+pub const MAX_SUGGESTION_BYTES: usize = 64 * 1024;
+
+/// Accepted, rejected and invalidated suggestions kept per room; the oldest are dropped beyond this
+pub const MAX_RESOLVED_SUGGESTIONS: usize = 200;
+
+/// Where a suggestion stands. Only open suggestions are shown inline and follow the document.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SuggestionStatus {
+    Open,
+    Accepted,
+    Rejected,
+    Invalidated, // The text it would replace was deleted, or an overlapping suggestion was accepted
+}
+
+impl SuggestionStatus {
+    /// The status called `name`, as in `?status=open`
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "open" => Ok(SuggestionStatus::Open),
+            "accepted" => Ok(SuggestionStatus::Accepted),
+            "rejected" => Ok(SuggestionStatus::Rejected),
+            "invalidated" => Ok(SuggestionStatus::Invalidated),
+            _ => Err(format!("Unknown suggestion status {:?}", name)),
+        }
+    }
+}
+
+/// Text a user proposes in place of `range` of the document, shown inline to everyone without
+/// changing the document until an editor accepts it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub id: u64,
+    pub author: String,
+    pub range: (usize, usize), // Byte range in the current document; moved along by every edit while open
+    pub replacement_text: String,
+    pub status: SuggestionStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved_by: Option<String>, // Who accepted or rejected it
+}
+
+impl Suggestion {
+    /// The edit accepting the suggestion makes
+    pub fn operations(&self) -> Vec<DiffOperation> {
+        let (start, end) = self.range;
+        let text = self.replacement_text.clone();
+        vec![match (start == end, text.is_empty()) {
+            (true, _) => DiffOperation::Insert(start, text),
+            (false, true) => DiffOperation::Delete(start, end),
+            (false, false) => DiffOperation::Replace(start, end, text),
+        }]
+    }
+}
+
+/// The suggestions of one document, saved with it. Open suggestions are remapped across every
+/// edit of the document, the same way clients remap the ones they show.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SuggestionManager {
+    suggestions: Vec<Suggestion>, // Oldest first
+    next_id: u64,
+}
+
+impl SuggestionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores a suggestion by `author` to replace `range` of `document` with `text`
+    pub fn create(&mut self, author: &str, range: (usize, usize), text: &str, document: &str) -> Result<Suggestion, String> {
+        let (start, end) = range;
+        if start > end || end > document.len() || !document.is_char_boundary(start) || !document.is_char_boundary(end) {
+            return Err(format!("Range {}..{} is not in the document", start, end));
+        }
+        if text.len() > MAX_SUGGESTION_BYTES {
+            return Err(format!("Suggestions are limited to {} bytes; this one has {}", MAX_SUGGESTION_BYTES, text.len()));
+        }
+        if document[start..end] == *text {
+            return Err("The suggestion doesn't change anything".to_string());
+        }
+        self.next_id += 1;
+        let suggestion = Suggestion {
+            id: self.next_id,
+            author: author.to_string(),
+            range,
+            replacement_text: text.to_string(),
+            status: SuggestionStatus::Open,
+            resolved_by: None,
+        };
+        self.suggestions.push(suggestion.clone());
+        Ok(suggestion)
+    }
+
+    /// The suggestion `id`, whatever its status
+    pub fn get(&self, id: u64) -> Option<&Suggestion> {
+        self.suggestions.iter().find(|suggestion| suggestion.id == id)
+    }
+
+    /// The suggestions with `status`, or all of them, oldest first
+    pub fn list(&self, status: Option<SuggestionStatus>) -> Vec<Suggestion> {
+        self.suggestions.iter().filter(|suggestion| status.is_none_or(|status| suggestion.status == status)).cloned().collect()
+    }
+
+    /// Moves open suggestions along an applied edit. A suggestion whose text was deleted
+    /// entirely is invalidated, as there is nothing left for it to replace.
+    pub fn remap(&mut self, operations: &[DiffOperation]) {
+        for suggestion in self.suggestions.iter_mut().filter(|suggestion| suggestion.status == SuggestionStatus::Open) {
+            let range = map_range(suggestion.range, operations);
+            if suggestion.range.0 < suggestion.range.1 && range.0 == range.1 {
+                suggestion.status = SuggestionStatus::Invalidated;
+            }
+            suggestion.range = range;
+        }
+    }
+
+    /// The open suggestion `id`, to apply its `operations` before calling `accepted`
+    pub fn open(&self, id: u64) -> Result<&Suggestion, String> {
+        match self.get(id) {
+            Some(suggestion) if suggestion.status == SuggestionStatus::Open => Ok(suggestion),
+            Some(suggestion) => Err(format!("Suggestion {} is already {:?}", id, suggestion.status).to_lowercase()),
+            None => Err(format!("No suggestion {}", id)),
+        }
+    }
+
+    /// Marks the open suggestion `id` accepted by `user`, once its edit was applied as
+    /// `operations`. Open suggestions overlapping it are invalidated and the others remapped.
+    /// Returns every suggestion that changed, the accepted one first.
+    pub fn accepted(&mut self, id: u64, user: &str, operations: &[DiffOperation]) -> Result<Vec<Suggestion>, String> {
+        let range = self.open(id)?.range;
+        let mut changed = Vec::new();
+        for suggestion in self.suggestions.iter_mut().filter(|suggestion| suggestion.status == SuggestionStatus::Open) {
+            if suggestion.id == id {
+                suggestion.status = SuggestionStatus::Accepted;
+                suggestion.resolved_by = Some(user.to_string());
+                changed.insert(0, suggestion.clone());
+            } else if overlaps(suggestion.range, range) {
+                suggestion.status = SuggestionStatus::Invalidated;
+                changed.push(suggestion.clone());
+            }
+        }
+        self.remap(operations);
+        self.trim();
+        Ok(changed)
+    }
+
+    /// Marks the open suggestion `id` rejected by `user`, leaving the document as it is
+    pub fn reject(&mut self, id: u64, user: &str) -> Result<Suggestion, String> {
+        self.open(id)?;
+        let suggestion = self.suggestions.iter_mut().find(|suggestion| suggestion.id == id).unwrap();
+        suggestion.status = SuggestionStatus::Rejected;
+        suggestion.resolved_by = Some(user.to_string());
+        let rejected = suggestion.clone();
+        self.trim();
+        Ok(rejected)
+    }
+
+    /// Drops the oldest resolved suggestions beyond `MAX_RESOLVED_SUGGESTIONS`
+    fn trim(&mut self) {
+        let resolved = self.suggestions.iter().filter(|suggestion| suggestion.status != SuggestionStatus::Open).count();
+        let mut excess = resolved.saturating_sub(MAX_RESOLVED_SUGGESTIONS);
+        self.suggestions.retain(|suggestion| {
+            let drop = excess > 0 && suggestion.status != SuggestionStatus::Open;
+            excess -= drop as usize;
+            !drop
+        });
+    }
+}
+
+/// Whether two suggestions would change the same text: their ranges share text, an insertion
+/// falls inside the other's range, or both insert at the same place
+fn overlaps(a: (usize, usize), b: (usize, usize)) -> bool {
+    match (a.0 == a.1, b.0 == b.1) {
+        (false, false) => a.0 < b.1 && b.0 < a.1,
+        (true, true) => a.0 == b.0,
+        (true, false) => b.0 < a.0 && a.0 < b.1,
+        (false, true) => a.0 < b.0 && b.0 < a.1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_suggestions_follow_edits() {
+        let document = "let total = price * count;";
+        let mut suggestions = SuggestionManager::new();
+        let price = suggestions.create("vic", (12, 17), "cost", document).unwrap();
+        let count = suggestions.create("vic", (20, 25), "amount", document).unwrap();
+        let append = suggestions.create("ed", (26, 26), " // each", document).unwrap();
+        assert!(suggestions.create("vic", (25, 27), "", document).is_err());
+        assert_eq!(price.operations(), vec![DiffOperation::Replace(12, 17, "cost".to_string())]);
+
+        // Text typed before a suggestion moves it; text typed at its edges stays outside it
+        suggestions.remap(&[DiffOperation::Insert(0, "pub ".to_string())]);
+        suggestions.remap(&[DiffOperation::Insert(16, "(".to_string())]);
+        let ranges: Vec<(usize, usize)> = suggestions.list(None).iter().map(|suggestion| suggestion.range).collect();
+        assert_eq!(ranges, vec![(17, 22), (25, 30), (31, 31)]);
+
+        // Deleting what a suggestion would replace leaves it nothing to do
+        suggestions.remap(&[DiffOperation::Delete(23, 31)]);
+        assert_eq!(suggestions.get(count.id).unwrap().status, SuggestionStatus::Invalidated);
+        assert_eq!(suggestions.get(count.id).unwrap().range, (23, 23));
+        assert_eq!(suggestions.list(Some(SuggestionStatus::Open)).iter().map(|suggestion| suggestion.id).collect::<Vec<_>>(), vec![price.id, append.id]);
+        assert_eq!(suggestions.get(append.id).unwrap().range, (23, 23));
+    }
+}
@@ -0,0 +1,476 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+use crate::editor::diff_engine::{DiffEngine, DiffOperation};
+
+/// Delay before the first reconnection attempt, doubled on every failed one
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest delay between reconnection attempts
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Local edits kept while the server is unreachable before typing is disabled
+pub const MAX_BUFFERED_EDITS: usize = 200;
+
+/// The connection to the server, as shown to the user
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    Connecting,                                         // Before the document first loaded
+    Connected,
+    Reconnecting { attempt: u32, retry_in: Duration },  // Edits are buffered meanwhile
+    Resuming,                                           // Back, catching up on what was missed
+    Unsynced { buffered: usize },                       // Too many edits buffered: typing is disabled until they are synced
+}
+
+/// What the web UI has to do for the supervisor
+#[derive(Debug, Clone, PartialEq)]
+pub enum SupervisorAction {
+    Connect(String),                                      // Open a socket to this URL, then call `opened`, or `closed` when it fails
+    RetryIn(Duration),                                    // Call `retry` after this long
+    Send(String),                                         // Send this frame on the socket
+    Apply { operations: Vec<DiffOperation>, cursor: usize }, // Change the textarea, then put the caret at `cursor`
+    State(ConnectionState),                               // Show this state
+}
+
+/// Frames the server sends on the document socket
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ServerFrame {
+    Delta { base: u64, operations: Vec<DiffOperation>, user: String },
+    Typed(TypedFrame),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TypedFrame {
+    Load { revision: u64, content: String, resume_token: Option<String> },
+    Resync { revision: u64, content: String },
+    Resumed { from: u64, revision: u64 }, // The deltas from `from` up to `revision` follow
+    #[serde(other)]
+    Other,
+}
+
+/// Keeps the web client in step with the server across dropped connections. A closed socket
+/// is reopened after an exponential backoff with jitter; local edits made meanwhile are
+/// buffered, up to `max_buffered_edits`, and rebased over whatever the others did. On
+/// reconnecting the client resumes from the last revision it saw, using the token of the
+/// document it loaded; when the server can't resume (it restarted, or too much happened) it
+/// sends the whole document, and the buffered edits are rebased over the difference between
+/// it and the text last seen, so the textarea changes only where it has to and keeps the caret.
+///
+/// Edits go to the server one at a time, in the order they were made: the next one is sent
+/// once the server broadcast the previous one back. An edit in flight when the connection
+/// dropped is sent again unless the missed deltas show it arrived; after a whole document it
+/// can't be told whether it did, and it is sent again.
+///
+/// Nothing here does I/O: the `SupervisorAction`s returned are carried out by the web UI.
+/// Positions, the caret's included, are byte offsets into the text.
+pub struct ConnectionSupervisor {
+    url: String,
+    user: String,
+    max_buffered_edits: usize,
+    state: ConnectionState,      // Of the link; `Unsynced` is reported over it while locked
+    attempt: u32,                // Failed reconnection attempts in a row
+    resume_token: Option<String>,
+    revision: u64,               // The last revision seen
+    server_text: String,         // The document at `revision`
+    local_text: String,          // What the textarea holds
+    pending: VecDeque<Vec<DiffOperation>>, // Local edits the server hasn't broadcast back, oldest first, against `server_text`
+    in_flight: bool,             // The first pending edit was sent on this connection
+    catch_up_to: Option<u64>,    // The revision a resumed connection is caught up at
+    locked: bool,
+}
+
+impl ConnectionSupervisor {
+    /// Creates a supervisor connecting to `url` (with its `protocol` query) and editing as `user`
+    pub fn new(url: &str, user: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            user: user.to_string(),
+            max_buffered_edits: MAX_BUFFERED_EDITS,
+            state: ConnectionState::Connecting,
+            attempt: 0,
+            resume_token: None,
+            revision: 0,
+            server_text: String::new(),
+            local_text: String::new(),
+            pending: VecDeque::new(),
+            in_flight: false,
+            catch_up_to: None,
+            locked: false,
+        }
+    }
+
+    /// Disables typing after `max_buffered_edits` edits made offline instead of `MAX_BUFFERED_EDITS`
+    pub fn with_max_buffered_edits(self, max_buffered_edits: usize) -> Self {
+        Self { max_buffered_edits: max_buffered_edits.max(1), ..self }
+    }
+
+    /// The state to show, `Unsynced` while typing is disabled
+    pub fn state(&self) -> ConnectionState {
+        match self.locked {
+            true => ConnectionState::Unsynced { buffered: self.pending.len() },
+            false => self.state.clone(),
+        }
+    }
+
+    /// Whether typing is disabled until the buffered edits are synced
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// What the textarea holds
+    pub fn local_text(&self) -> &str {
+        &self.local_text
+    }
+
+    /// Local edits the server hasn't confirmed yet
+    pub fn buffered(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Opens the first connection
+    pub fn start(&mut self) -> Vec<SupervisorAction> {
+        vec![SupervisorAction::Connect(self.connect_url())]
+    }
+
+    /// The URL to connect to, asking to resume from the last revision once a document loaded
+    pub fn connect_url(&self) -> String {
+        match &self.resume_token {
+            Some(token) => {
+                let separator = if self.url.contains('?') { '&' } else { '?' };
+                format!("{}{}resume_token={}&revision={}", self.url, separator, token, self.revision)
+            }
+            None => self.url.clone(),
+        }
+    }
+
+    /// Records that the socket opened; the document or the missed deltas come next
+    pub fn opened(&mut self) -> Vec<SupervisorAction> {
+        let before = self.state();
+        if self.resume_token.is_some() {
+            self.state = ConnectionState::Resuming;
+        }
+        self.report(before, Vec::new())
+    }
+
+    /// Records that the socket closed, or failed to open. `jitter`, from 0 to 1, spreads the
+    /// retries of clients that lost the server at the same time.
+    pub fn closed(&mut self, jitter: f64) -> Vec<SupervisorAction> {
+        let before = self.state();
+        self.attempt += 1;
+        self.in_flight = false;
+        self.catch_up_to = None;
+        let retry_in = backoff(self.attempt, jitter);
+        self.state = ConnectionState::Reconnecting { attempt: self.attempt, retry_in };
+        self.report(before, vec![SupervisorAction::RetryIn(retry_in)])
+    }
+
+    /// Tries to connect again, when the delay of `RetryIn` is up
+    pub fn retry(&mut self) -> Vec<SupervisorAction> {
+        vec![SupervisorAction::Connect(self.connect_url())]
+    }
+
+    /// Takes in what the user typed, `new_text` being the whole textarea. While typing is
+    /// disabled the edit is undone.
+    pub fn local_edit(&mut self, new_text: &str, cursor: usize) -> Vec<SupervisorAction> {
+        let before = self.state();
+        let operations = DiffEngine::diff(&self.local_text, new_text);
+        if operations.is_empty() {
+            return Vec::new();
+        }
+        if self.locked {
+            let undo = DiffEngine::diff(new_text, &self.local_text);
+            let cursor = map_cursor(cursor, &undo);
+            return vec![SupervisorAction::Apply { operations: undo, cursor }];
+        }
+
+        // Buffered edits stay separate so they are sent in order; rebased, they apply after
+        // the server text and all the edits before them
+        self.local_text = new_text.to_string();
+        self.pending.push_back(operations);
+        let mut actions = Vec::new();
+        if self.state == ConnectionState::Connected {
+            actions.extend(self.flush());
+        } else if self.pending.len() >= self.max_buffered_edits {
+            self.locked = true;
+        }
+        self.report(before, actions)
+    }
+
+    /// Takes in a frame from the server, the caret being at `cursor`
+    pub fn receive(&mut self, frame: &str, cursor: usize) -> Vec<SupervisorAction> {
+        let before = self.state();
+        let actions = match serde_json::from_str::<ServerFrame>(frame) {
+            Ok(ServerFrame::Delta { base, operations, user }) => self.delta(base, operations, &user, cursor),
+            Ok(ServerFrame::Typed(TypedFrame::Load { revision, content, resume_token })) => {
+                self.resume_token = resume_token.or(self.resume_token.take());
+                self.snapshot(revision, &content, cursor)
+            }
+            Ok(ServerFrame::Typed(TypedFrame::Resync { revision, content })) => self.snapshot(revision, &content, cursor),
+            Ok(ServerFrame::Typed(TypedFrame::Resumed { from, revision })) if from == self.revision => {
+                self.catch_up_to = Some(revision);
+                self.caught_up()
+            }
+            _ => Vec::new(), // Errors, chat, presence and resumes from elsewhere are not ours to handle
+        };
+        self.report(before, actions)
+    }
+
+    /// A delta as broadcast: our own edit coming back, or someone else's to rebase ours over
+    fn delta(&mut self, base: u64, operations: Vec<DiffOperation>, user: &str, cursor: usize) -> Vec<SupervisorAction> {
+        if base != self.revision {
+            return Vec::new(); // Out of step; the server resyncs us
+        }
+        self.server_text = DiffEngine::apply(&self.server_text, &operations);
+        self.revision += 1;
+
+        let mut actions = Vec::new();
+        let echo = self.in_flight && user == self.user && self.pending.front() == Some(&operations);
+        if echo {
+            self.pending.pop_front();
+            self.in_flight = false;
+        } else {
+            let operations = self.rebase_pending(operations);
+            self.local_text = DiffEngine::apply(&self.local_text, &operations);
+            let cursor = map_cursor(cursor, &operations);
+            actions.push(SupervisorAction::Apply { operations, cursor });
+        }
+        actions.extend(self.caught_up());
+        actions
+    }
+
+    /// The whole document: the buffered edits are rebased over how it differs from the text
+    /// last seen, and the textarea changes by the difference only
+    fn snapshot(&mut self, revision: u64, content: &str, cursor: usize) -> Vec<SupervisorAction> {
+        let theirs = DiffEngine::diff(&self.server_text, content);
+        self.rebase_pending(theirs);
+        let reconciled = self.pending.iter().fold(content.to_string(), |text, edit| DiffEngine::apply(&text, edit));
+        let operations = DiffEngine::diff(&self.local_text, &reconciled);
+
+        self.server_text = content.to_string();
+        self.local_text = reconciled;
+        self.revision = revision;
+        self.in_flight = false;
+        self.catch_up_to = Some(revision);
+
+        let mut actions = Vec::new();
+        if !operations.is_empty() {
+            let cursor = map_cursor(cursor, &operations);
+            actions.push(SupervisorAction::Apply { operations, cursor });
+        }
+        actions.extend(self.caught_up());
+        actions
+    }
+
+    /// Rebases the pending edits over `operations`, applied to the server text first, and
+    /// returns `operations` rebased to apply after the pending edits, to the local text
+    fn rebase_pending(&mut self, operations: Vec<DiffOperation>) -> Vec<DiffOperation> {
+        self.pending.iter_mut().fold(operations, |operations, edit| {
+            let (rebased, operations) = DiffEngine::transform(edit, &operations, false);
+            *edit = rebased;
+            operations
+        })
+    }
+
+    /// Back to editing once the missed revisions are in: typing is enabled again and the
+    /// buffered edits start going out, or the next one does when connected already
+    fn caught_up(&mut self) -> Vec<SupervisorAction> {
+        match self.catch_up_to {
+            Some(revision) if revision == self.revision => {
+                self.catch_up_to = None;
+                self.state = ConnectionState::Connected;
+                self.attempt = 0;
+                self.locked = false;
+                self.flush()
+            }
+            None if self.state == ConnectionState::Connected => self.flush(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Sends the oldest pending edit unless one is in flight
+    fn flush(&mut self) -> Vec<SupervisorAction> {
+        match self.pending.front() {
+            Some(operations) if !self.in_flight => {
+                self.in_flight = true;
+                let frame = serde_json::json!({ "base": self.revision, "operations": operations, "user": self.user });
+                vec![SupervisorAction::Send(frame.to_string())]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Adds the state to show to `actions` when it changed since `before`
+    fn report(&self, before: ConnectionState, mut actions: Vec<SupervisorAction>) -> Vec<SupervisorAction> {
+        let after = self.state();
+        if after != before {
+            actions.push(SupervisorAction::State(after));
+        }
+        actions
+    }
+}
+
+/// How long to wait before reconnection attempt `attempt`, counted from 1: at least half the
+/// exponential delay, plus up to the other half by `jitter`
+fn backoff(attempt: u32, jitter: f64) -> Duration {
+    let delay = INITIAL_BACKOFF.saturating_mul(1u32 << attempt.saturating_sub(1).min(16)).min(MAX_BACKOFF);
+    delay / 2 + delay.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// Where the caret at `cursor` ends up after `operations`. Text inserted at the caret goes
+/// after it; a caret in replaced or deleted text moves to where it was.
+fn map_cursor(cursor: usize, operations: &[DiffOperation]) -> usize {
+    operations.iter().fold(cursor, |cursor, operation| {
+        let (start, end, inserted) = match operation {
+            DiffOperation::Insert(position, text) => (*position, *position, text.len()),
+            DiffOperation::Delete(start, end) => (*start, *end, 0),
+            DiffOperation::Replace(start, end, text) => (*start, *end, text.len()),
+        };
+        if cursor > end || (cursor == end && start < end) {
+            cursor - (end - start) + inserted
+        } else if cursor > start {
+            start
+        } else {
+            cursor
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "ws://localhost:8080/ws?protocol=1.0";
+
+    fn load(revision: u64, content: &str, token: &str) -> String {
+        serde_json::json!({ "type": "load", "revision": revision, "content": content, "resume_token": token }).to_string()
+    }
+
+    fn delta(base: u64, operations: &[DiffOperation], user: &str) -> String {
+        serde_json::json!({ "base": base, "operations": operations, "user": user }).to_string()
+    }
+
+    /// A supervisor that loaded `content` at `revision` and then lost the connection
+    fn disconnected(content: &str, revision: u64) -> ConnectionSupervisor {
+        let mut supervisor = ConnectionSupervisor::new(URL, "ana");
+        supervisor.start();
+        supervisor.opened();
+        supervisor.receive(&load(revision, content, "token"), 0);
+        supervisor.closed(0.0);
+        supervisor
+    }
+
+    fn sent(actions: &[SupervisorAction]) -> Vec<serde_json::Value> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                SupervisorAction::Send(frame) => Some(serde_json::from_str(frame).unwrap()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reconnects_with_backoff_and_resumes_after_close() {
+        let mut supervisor = ConnectionSupervisor::new(URL, "ana");
+        assert_eq!(supervisor.start(), vec![SupervisorAction::Connect(URL.to_string())]);
+        let actions = supervisor.receive(&load(3, "abc", "token"), 0);
+        assert_eq!(actions[0], SupervisorAction::Apply { operations: vec![DiffOperation::Insert(0, "abc".to_string())], cursor: 0 });
+        assert_eq!(supervisor.state(), ConnectionState::Connected);
+
+        // Forced close, then a failed attempt: the delay doubles, jitter adds up to half again
+        let retry_in = Duration::from_millis(250);
+        assert_eq!(
+            supervisor.closed(0.0),
+            vec![SupervisorAction::RetryIn(retry_in), SupervisorAction::State(ConnectionState::Reconnecting { attempt: 1, retry_in })]
+        );
+        assert_eq!(supervisor.closed(1.0)[0], SupervisorAction::RetryIn(Duration::from_secs(1)));
+        assert_eq!(backoff(40, 1.0), MAX_BACKOFF);
+
+        // Resumes from the revision seen last, and is connected once caught up
+        let url = format!("{}&resume_token=token&revision=3", URL);
+        assert_eq!(supervisor.retry(), vec![SupervisorAction::Connect(url)]);
+        assert_eq!(supervisor.opened(), vec![SupervisorAction::State(ConnectionState::Resuming)]);
+        assert!(supervisor.receive(r#"{"type":"resumed","from":3,"revision":4}"#, 0).is_empty());
+        let actions = supervisor.receive(&delta(3, &[DiffOperation::Insert(3, "d".to_string())], "ben"), 1);
+        assert_eq!(
+            actions,
+            vec![
+                SupervisorAction::Apply { operations: vec![DiffOperation::Insert(3, "d".to_string())], cursor: 1 },
+                SupervisorAction::State(ConnectionState::Connected),
+            ]
+        );
+        assert_eq!(supervisor.local_text(), "abcd");
+
+        // The next drop starts over from the shortest delay
+        assert_eq!(supervisor.closed(0.0)[0], SupervisorAction::RetryIn(retry_in));
+    }
+
+    #[test]
+    fn test_buffered_edits_flushed_in_order() {
+        let mut supervisor = disconnected("abc", 0);
+        assert!(sent(&supervisor.local_edit("abcd", 4)).is_empty());
+        assert!(sent(&supervisor.local_edit("abcde", 5)).is_empty());
+        assert_eq!(supervisor.buffered(), 2);
+
+        // Someone else's edit came meanwhile: ours are rebased over it and go out one at a time
+        supervisor.retry();
+        supervisor.opened();
+        supervisor.receive(r#"{"type":"resumed","from":0,"revision":1}"#, 5);
+        let actions = supervisor.receive(&delta(0, &[DiffOperation::Insert(0, "x".to_string())], "ben"), 5);
+        assert_eq!(actions[0], SupervisorAction::Apply { operations: vec![DiffOperation::Insert(0, "x".to_string())], cursor: 6 });
+        assert_eq!(supervisor.local_text(), "xabcde");
+        let first = vec![DiffOperation::Insert(4, "d".to_string())];
+        assert_eq!(sent(&actions), vec![serde_json::json!({ "base": 1, "operations": first, "user": "ana" })]);
+
+        // Our first edit coming back sends the second, which applies after it
+        let actions = supervisor.receive(&delta(1, &first, "ana"), 6);
+        let second = vec![DiffOperation::Insert(5, "e".to_string())];
+        assert_eq!(sent(&actions), vec![serde_json::json!({ "base": 2, "operations": second, "user": "ana" })]);
+        assert!(supervisor.receive(&delta(2, &second, "ana"), 6).is_empty());
+        assert_eq!((supervisor.buffered(), supervisor.local_text()), (0, "xabcde"));
+    }
+
+    #[test]
+    fn test_snapshot_reconciled_keeping_cursor() {
+        let mut supervisor = disconnected("hello world", 5);
+        supervisor.local_edit("hello brave world", 12);
+
+        // The server restarted: its token is unknown, so it sends the whole document
+        supervisor.retry();
+        supervisor.opened();
+        let actions = supervisor.receive(&load(1, "hello world!", "restarted"), 12);
+        assert_eq!(actions[0], SupervisorAction::Apply { operations: vec![DiffOperation::Insert(17, "!".to_string())], cursor: 12 });
+        assert_eq!(supervisor.local_text(), "hello brave world!");
+        let edit = vec![DiffOperation::Insert(6, "brave ".to_string())];
+        assert_eq!(sent(&actions), vec![serde_json::json!({ "base": 1, "operations": edit, "user": "ana" })]);
+        assert!(supervisor.connect_url().ends_with("resume_token=restarted&revision=1"));
+
+        // Text changed before the caret moves it along
+        let actions = supervisor.receive(&load(2, "oh, hello world!", "restarted"), 12);
+        assert_eq!(actions[0], SupervisorAction::Apply { operations: vec![DiffOperation::Insert(0, "oh, ".to_string())], cursor: 16 });
+        assert_eq!(map_cursor(8, &[DiffOperation::Replace(6, 11, "there".to_string())]), 6);
+        assert_eq!(map_cursor(11, &[DiffOperation::Delete(6, 11)]), 6);
+    }
+
+    #[test]
+    fn test_overflow_locks_typing_until_synced() {
+        let mut supervisor = disconnected("", 0).with_max_buffered_edits(2);
+        supervisor.local_edit("a", 1);
+        let actions = supervisor.local_edit("ab", 2);
+        assert_eq!(actions, vec![SupervisorAction::State(ConnectionState::Unsynced { buffered: 2 })]);
+        assert!(supervisor.locked());
+
+        // Typing is undone, and reconnection attempts carry on without hiding the lockout
+        let actions = supervisor.local_edit("abc", 3);
+        assert_eq!(actions, vec![SupervisorAction::Apply { operations: vec![DiffOperation::Delete(2, 3)], cursor: 2 }]);
+        assert_eq!(supervisor.closed(0.0).len(), 1);
+        assert_eq!(supervisor.state(), ConnectionState::Unsynced { buffered: 2 });
+
+        supervisor.retry();
+        supervisor.opened();
+        let actions = supervisor.receive(r#"{"type":"resumed","from":0,"revision":0}"#, 2);
+        assert_eq!(sent(&actions).len(), 1);
+        assert_eq!(actions.last(), Some(&SupervisorAction::State(ConnectionState::Connected)));
+        assert!(!supervisor.locked());
+    }
+}
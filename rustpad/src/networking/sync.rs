@@ -1,8 +1,13 @@
 use serde::{Deserialize, Serialize};
 use warp::ws::{Message, WebSocket};
-use futures_util::{StreamExt, SinkExt};
+use warp::Filter;
+use futures_util::StreamExt;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use crate::storage::file_storage::FileStorage;
+use uuid::Uuid;
+use crate::client::{self, Client, Clients};
+use crate::storage::encoding::{normalize_line_endings, LineEnding};
+use crate::storage::file_storage::{content_hash, FileStorage, SaveConflict};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileChange {
@@ -10,78 +15,200 @@ pub struct FileChange {
     pub content: String,
     pub user: String,
     pub timestamp: String,
+    #[serde(default)]
+    pub base_hash: Option<String>, // Hash of the version the change was made on; in broadcasts, of the saved version
+    #[serde(default)]
+    pub version: u64, // In broadcasts, the file's version after the change; counts up from 1 per file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line_ending: Option<LineEnding>, // The sender's `EditorConfig` preference, if it changed the file's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>, // Id of the connection that made the change, set by the server
 }
 
-type SyncClients = Arc<Mutex<Vec<warp::ws::WebSocket>>>;
+/// Requests a client sends besides file changes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SyncRequest {
+    Resync(ResyncRequest), // Answered with the whole current file
+}
+
+/// Asks for the whole current `file_name`, after missing an update of it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResyncRequest {
+    pub file_name: String,
+}
+
+/// Messages a client sends
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    Request(SyncRequest),
+    Change(FileChange),
+}
+
+/// What a client should do with an update of a file
+#[derive(Debug, Clone, PartialEq)]
+pub enum VersionCheck {
+    Apply,                  // The next version; apply it
+    Stale,                  // Already seen or superseded; ignore it
+    Resync(SyncRequest),    // Updates were missed; send this request instead of applying it
+}
+
+/// The last version of each file a client has seen, to notice missed updates
+#[derive(Debug, Default)]
+pub struct FileVersions {
+    seen: HashMap<String, u64>,
+}
+
+impl FileVersions {
+    /// Creates a tracker that has seen no versions yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks a version from a broadcast change, or from the acknowledgement of the client's own
+    /// change, and records it if it is the next one. Updates can arrive out of order when
+    /// clients save at the same time, so a resync may also follow an update that was only late.
+    pub fn check(&mut self, file_name: &str, version: u64) -> VersionCheck {
+        let seen = self.seen.entry(file_name.to_string()).or_insert(0);
+        if version <= *seen {
+            VersionCheck::Stale
+        } else if version == *seen + 1 {
+            *seen = version;
+            VersionCheck::Apply
+        } else {
+            VersionCheck::Resync(SyncRequest::Resync(ResyncRequest { file_name: file_name.to_string() }))
+        }
+    }
+
+    /// Records the version of a whole file received in answer to a resync request
+    pub fn resynced(&mut self, file_name: &str, version: u64) {
+        self.seen.insert(file_name.to_string(), version);
+    }
+}
 
 /// Manages file synchronization between the server and clients
+#[derive(Clone)]
 pub struct SyncManager {
-    clients: SyncClients,
+    clients: Clients, // Each client's messages are forwarded to its socket by a task of its own
     file_storage: Arc<FileStorage>,
+    versions: Arc<Mutex<HashMap<String, u64>>>, // Version of each file changed since the server started
 }
 
 impl SyncManager {
     /// Creates a new SyncManager with a list of connected clients and file storage
     pub fn new(file_storage: Arc<FileStorage>) -> Self {
         Self {
-            clients: Arc::new(Mutex::new(Vec::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
             file_storage,
+            versions: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
     /// Registers a new WebSocket client for file synchronization
-    pub async fn register_client(&self, socket: WebSocket) {
-        let (mut ws_tx, mut ws_rx) = socket.split();
+    pub async fn register_client(self, socket: WebSocket) {
+        let (ws_tx, ws_rx) = socket.split();
+        let (sender, send_task) = client::spawn_writer(ws_tx);
+        let writer = send_task.abort_handle();
+        let client_id = Uuid::new_v4().to_string(); // Echoed in acknowledgements as `origin`, so the client knows which changes are its own
+        client::add_client(self.clients.clone(), client_id.clone(), Client::new(&client_id, "", sender.clone()).with_writer(writer.clone()));
 
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.push(ws_tx.clone());
-        }
+        let mut ws_rx = ws_rx.take_until(send_task);
 
-        // Listen for incoming file changes from the client
+        // Listen for incoming file changes and resync requests from the client
         while let Some(result) = ws_rx.next().await {
-            if let Ok(message) = result {
-                if message.is_text() {
-                    let file_change: FileChange = serde_json::from_str(message.to_str().unwrap()).unwrap();
-                    self.apply_file_change(file_change.clone()).await;
-                    self.broadcast_file_change(file_change).await;
-                }
-            }
+            let message = match result {
+                Ok(message) if message.is_text() => message,
+                _ => continue,
+            };
+            let reply = match serde_json::from_str::<Incoming>(message.to_str().unwrap()) {
+                Ok(Incoming::Request(SyncRequest::Resync(request))) => self.current_file(&request.file_name),
+                Ok(Incoming::Change(file_change)) => match self.apply_file_change(FileChange { origin: Some(client_id.clone()), ..file_change }).await {
+                    Ok(saved) => {
+                        // The sender already has the content; it only needs the new hash and version
+                        let reply = serde_json::json!({
+                            "type": "saved",
+                            "file_name": saved.file_name,
+                            "hash": saved.base_hash,
+                            "version": saved.version,
+                            "origin": saved.origin,
+                        });
+                        self.broadcast_file_change(saved, &client_id);
+                        reply
+                    }
+                    // Tell the client what it would have overwritten, so it can merge and retry
+                    Err(e) => match SaveConflict::from_error(&e) {
+                        Some(conflict) => serde_json::json!({
+                            "type": "conflict",
+                            "file_name": conflict.file_name,
+                            "current_hash": conflict.current_hash,
+                            "content": self.file_storage.load_file(&conflict.file_name).unwrap_or_default(),
+                        }),
+                        None => serde_json::json!({ "type": "error", "message": e.to_string() }),
+                    },
+                },
+                Err(e) => serde_json::json!({ "type": "error", "message": format!("Invalid message: {}", e) }),
+            };
+            let _ = sender.send(Message::text(reply.to_string())).await;
         }
 
         // Remove the WebSocket client when it disconnects
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.retain(|client| !client.is_closed());
-        }
+        client::remove_client(self.clients.clone(), &client_id);
+        writer.abort();
     }
 
-    /// Applies a file change to the server's file storage
-    pub async fn apply_file_change(&self, file_change: FileChange) {
-        // Save the file change to the file system using FileStorage
-        let result = self.file_storage.save_file(&file_change.file_name, &file_change.content);
-        
-        if let Err(e) = result {
-            eprintln!("Failed to save file: {}", e);
+    /// Applies a file change to the server's file storage and returns it as saved, with the new
+    /// hash and version. A change carrying a `base_hash` is refused with a `SaveConflict` if the
+    /// file changed since that version. The file keeps its encoding and line endings unless the
+    /// change asks for other line endings.
+    pub async fn apply_file_change(&self, file_change: FileChange) -> std::io::Result<FileChange> {
+        // Saving and numbering under one lock keeps versions in the order the saves happened
+        let mut versions = self.versions.lock().unwrap();
+        match self.file_storage.save_file_as(&file_change.file_name, &file_change.content, file_change.base_hash.as_deref(), file_change.line_ending) {
+            Ok(info) => {
+                let version = versions.entry(file_change.file_name.clone()).or_insert(0);
+                *version += 1;
+                let content = normalize_line_endings(&file_change.content);
+                Ok(FileChange { content, base_hash: Some(info.hash), version: *version, ..file_change })
+            }
+            Err(e) => {
+                eprintln!("Failed to save file: {}", e);
+                Err(e)
+            }
         }
     }
 
-    /// Broadcasts a file change to all connected clients
-    pub async fn broadcast_file_change(&self, file_change: FileChange) {
+    /// Broadcasts a saved file change to every connected client except the one that made it. It
+    /// carries the `origin` of the change, so a client relaying changes, or one connected twice,
+    /// can still tell its own changes from others' and attribute them.
+    pub fn broadcast_file_change(&self, file_change: FileChange, sender_id: &str) {
         let message = serde_json::to_string(&file_change).unwrap();
-        let clients = self.clients.lock().unwrap();
+        client::broadcast_message_except(self.clients.clone(), &message, sender_id);
+    }
 
-        for client in clients.iter() {
-            if client.send(Message::text(message.clone())).await.is_err() {
-                eprintln!("Failed to send file change to client");
-            }
+    /// The whole current file with its version, for a client opening it or that missed an
+    /// update. Says how the file is stored and warns of anything lost reading it, so the editor
+    /// can show them.
+    pub fn current_file(&self, file_name: &str) -> serde_json::Value {
+        let versions = self.versions.lock().unwrap();
+        match self.file_storage.load_file_with_format(file_name) {
+            Ok(decoded) => serde_json::json!({
+                "type": "file",
+                "file_name": file_name,
+                "hash": content_hash(&decoded.text),
+                "version": versions.get(file_name).copied().unwrap_or(0),
+                "content": decoded.text,
+                "warnings": decoded.format.warnings(),
+                "format": decoded.format,
+            }),
+            Err(e) => serde_json::json!({ "type": "error", "message": e.to_string() }),
         }
     }
 }
 
 /// WebSocket handler for file synchronization
-pub async fn sync_ws_handler(ws: warp::ws::Ws, manager: SyncManager) -> impl warp::Reply {
-    ws.on_upgrade(move |socket| manager.register_client(socket))
+pub async fn sync_ws_handler(ws: warp::ws::Ws, manager: SyncManager) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(ws.on_upgrade(move |socket| manager.register_client(socket)))
 }
 
 /// Route for file synchronization WebSocket
@@ -97,16 +224,138 @@ fn with_manager(manager: SyncManager) -> impl warp::Filter<Extract = (SyncManage
     warp::any().map(move || manager.clone())
 }
 
-/// Example main function for setting up the file sync server
-#[tokio::main]
-async fn main() {
-    let file_storage = Arc::new(FileStorage::new("project_files"));
-    let sync_manager = SyncManager::new(file_storage.clone());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
 
-    // WebSocket route for file synchronization
-    let sync_ws_route = sync_route(sync_manager.clone());
+    fn change(content: &str, base_hash: &str) -> FileChange {
+        FileChange {
+            file_name: "main.rs".to_string(),
+            content: content.to_string(),
+            user: "ana".to_string(),
+            timestamp: String::new(),
+            base_hash: Some(base_hash.to_string()),
+            version: 0,
+            line_ending: None,
+            origin: None,
+        }
+    }
+
+    fn manager(name: &str) -> (SyncManager, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("rustpad-sync-{}-{}", name, std::process::id()));
+        (SyncManager::new(Arc::new(FileStorage::new(&dir.to_string_lossy()))), dir)
+    }
+
+    async fn recv_json(client: &mut warp::test::WsClient) -> serde_json::Value {
+        serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_stale_change_is_reported_to_its_client() {
+        let (manager, dir) = manager("conflict");
+        let base = manager.apply_file_change(change("fn main() {}", &content_hash(""))).await.unwrap().base_hash.unwrap();
+        let route = sync_route(manager.clone());
+        let mut ana = warp::test::ws().path("/sync_ws").handshake(route.clone()).await.unwrap();
+        let mut bo = warp::test::ws().path("/sync_ws").handshake(route).await.unwrap();
+
+        // Both edit the same version; ana's change is saved and broadcast with its new hash
+        ana.send_text(serde_json::to_string(&change("fn main() { ana() }", &base)).unwrap()).await;
+        let saved: FileChange = serde_json::from_str(bo.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(saved.base_hash, Some(content_hash("fn main() { ana() }")));
+        assert_eq!(recv_json(&mut ana).await["type"], "saved");
+
+        // bo's stale change comes back to bo alone as a conflict, and the file keeps ana's version
+        bo.send_text(serde_json::to_string(&change("fn main() { bo() }", &base)).unwrap()).await;
+        let reply = recv_json(&mut bo).await;
+        assert_eq!(reply["type"], "conflict");
+        assert_eq!(reply["current_hash"], content_hash("fn main() { ana() }"));
+        assert_eq!(reply["content"], "fn main() { ana() }");
+        assert_eq!(manager.file_storage.load_file("main.rs").unwrap(), "fn main() { ana() }");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sender_only_gets_an_acknowledgement() {
+        let (manager, dir) = manager("sender");
+        let route = sync_route(manager);
+        let mut ana = warp::test::ws().path("/sync_ws").handshake(route.clone()).await.unwrap();
+        let mut bo = warp::test::ws().path("/sync_ws").handshake(route).await.unwrap();
+
+        // A client can't pass its change off as someone else's; the server sets the origin
+        let spoofed = FileChange { origin: Some("bo".to_string()), ..change("fn main() {}", &content_hash("")) };
+        ana.send_text(serde_json::to_string(&spoofed).unwrap()).await;
+        let broadcast: FileChange = serde_json::from_str(bo.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!((broadcast.content.as_str(), broadcast.version), ("fn main() {}", 1));
 
-    // Start the server
-    println!("File sync server running on ws://localhost:3030/sync_ws");
-    warp::serve(sync_ws_route).run(([127, 0, 0, 1], 3030)).await;
+        let ack = recv_json(&mut ana).await;
+        assert_eq!(ack["type"], "saved");
+        assert_eq!(ack["version"], 1);
+        assert_eq!(ack["hash"], content_hash("fn main() {}"));
+        assert!(tokio::time::timeout(Duration::from_millis(200), ana.recv()).await.is_err(), "The change was echoed to its sender");
+        assert_eq!(broadcast.origin.as_deref(), ack["origin"].as_str());
+        assert_ne!(broadcast.origin.as_deref(), Some("bo"));
+
+        // bo's change reaches ana marked as bo's, and isn't echoed to bo
+        bo.send_text(serde_json::to_string(&change("fn main() { bo() }", ack["hash"].as_str().unwrap())).unwrap()).await;
+        let from_bo: FileChange = serde_json::from_str(ana.recv().await.unwrap().to_str().unwrap()).unwrap();
+        let bo_ack = recv_json(&mut bo).await;
+        assert_eq!(from_bo.origin.as_deref(), bo_ack["origin"].as_str());
+        assert_ne!(from_bo.origin, broadcast.origin);
+        assert!(tokio::time::timeout(Duration::from_millis(200), bo.recv()).await.is_err(), "The change was echoed to its sender");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_version_gap_requests_the_whole_file() {
+        let (manager, dir) = manager("gap");
+        let route = sync_route(manager);
+        let mut ana = warp::test::ws().path("/sync_ws").handshake(route.clone()).await.unwrap();
+        let mut bo = warp::test::ws().path("/sync_ws").handshake(route).await.unwrap();
+        let mut versions = FileVersions::new();
+
+        let mut base = content_hash("");
+        for content in ["one", "two", "three"] {
+            ana.send_text(serde_json::to_string(&change(content, &base)).unwrap()).await;
+            base = recv_json(&mut ana).await["hash"].as_str().unwrap().to_string();
+        }
+        let first: FileChange = serde_json::from_str(bo.recv().await.unwrap().to_str().unwrap()).unwrap();
+        let missed: FileChange = serde_json::from_str(bo.recv().await.unwrap().to_str().unwrap()).unwrap();
+        let third: FileChange = serde_json::from_str(bo.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(versions.check("main.rs", first.version), VersionCheck::Apply);
+
+        // bo never saw the second update, so the third can't be applied on top of the first
+        let VersionCheck::Resync(request) = versions.check("main.rs", third.version) else { panic!("Gap not noticed") };
+        bo.send_text(serde_json::to_string(&request).unwrap()).await;
+        let file = recv_json(&mut bo).await;
+        assert_eq!(file["type"], "file");
+        assert_eq!(file["content"], "three");
+        assert_eq!(file["version"], 3);
+
+        versions.resynced("main.rs", 3);
+        assert_eq!(versions.check("main.rs", missed.version), VersionCheck::Stale);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_frame_reports_format_and_warnings() {
+        let (manager, dir) = manager("format");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.rs"), b"// caf\xe9\r\nfn main() {\r\n}\n").unwrap();
+        let route = sync_route(manager);
+        let mut ana = warp::test::ws().path("/sync_ws").handshake(route).await.unwrap();
+
+        ana.send_text(serde_json::json!({ "type": "resync", "file_name": "main.rs" }).to_string()).await;
+        let file = recv_json(&mut ana).await;
+        assert_eq!(file["content"], "// café\nfn main() {\n}\n");
+        assert_eq!(file["format"], serde_json::json!({ "encoding": "windows-1252", "line_ending": "crlf", "mixed_line_endings": true, "replaced_characters": 0 }));
+        assert_eq!(file["warnings"], serde_json::json!(["The file mixes line endings; saving it will use CRLF throughout"]));
+
+        // Saved in the file's encoding, with the line endings the editor prefers
+        let edit = FileChange { content: "// café\nfn main() {\n    run();\n}\n".to_string(), line_ending: Some(LineEnding::Lf), ..change("", file["hash"].as_str().unwrap()) };
+        ana.send_text(serde_json::to_string(&edit).unwrap()).await;
+        assert_eq!(recv_json(&mut ana).await["type"], "saved");
+        assert_eq!(std::fs::read(dir.join("main.rs")).unwrap(), b"// caf\xe9\nfn main() {\n    run();\n}\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
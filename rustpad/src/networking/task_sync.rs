@@ -0,0 +1,189 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::Filter;
+use crate::editor::tasks::{LineChange, Task, TaskExtractor};
+use crate::networking::protocol::{DeltaMessage, RejectMessage, RemoteDeltaMessage};
+use crate::networking::revision_log::{Receipt, RevisionLog};
+
+/// How long a document's tasks must stay unchanged before the new list is broadcast
+pub const TASKS_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Task requests sent over a document's WebSocket
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TaskCommand {
+    ToggleTask { id: String }, // Checks or unchecks a markdown checkbox
+}
+
+struct DocTasks {
+    log: RevisionLog,
+    extractor: TaskExtractor,
+    broadcast: Vec<Task>,          // The list clients last received
+    changed_at: Option<Instant>,   // When the list last changed, while a broadcast is pending
+}
+
+/// The task lists of the open documents, kept up to date as deltas arrive
+#[derive(Clone)]
+pub struct TaskSync {
+    docs: Arc<Mutex<HashMap<String, DocTasks>>>, // Keyed by document id
+}
+
+impl TaskSync {
+    /// Creates a TaskSync tracking no documents
+    pub fn new() -> Self {
+        Self { docs: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Starts tracking `doc_id`, with its `language` and current `text`
+    pub fn open(&self, doc_id: &str, language: &str, text: &str) {
+        let extractor = TaskExtractor::new(language, text);
+        let doc = DocTasks { log: RevisionLog::new(text), broadcast: extractor.tasks(), extractor, changed_at: None };
+        self.docs.lock().unwrap().insert(doc_id.to_string(), doc);
+    }
+
+    /// Applies a client's delta to `doc_id` and rescans the lines it changed
+    pub fn receive(&self, doc_id: &str, delta: &DeltaMessage, now: Instant) -> Result<Receipt, RejectMessage> {
+        let mut docs = self.docs.lock().unwrap();
+        let doc = docs.get_mut(doc_id).ok_or_else(|| RejectMessage { seq: delta.seq, reason: format!("Unknown document {}", doc_id), quota: None })?;
+
+        let before = doc.log.text().to_string();
+        let receipt = doc.log.receive(delta)?;
+        if let Receipt::Applied(..) = receipt {
+            doc.extractor.update(doc.log.text(), LineChange::between(&before, doc.log.text()));
+            if doc.extractor.tasks() != doc.broadcast {
+                doc.changed_at = Some(now);
+            }
+        }
+        Ok(receipt)
+    }
+
+    /// Toggles checkbox task `id` by editing its `[ ]` or `[x]`. The edit goes through the
+    /// revision log like any other, so it comes back as a remote delta for every client,
+    /// including the one that asked, and undoes like a typed change.
+    pub fn toggle(&self, doc_id: &str, id: &str, now: Instant) -> Result<RemoteDeltaMessage, String> {
+        let delta = {
+            let docs = self.docs.lock().unwrap();
+            let doc = docs.get(doc_id).ok_or_else(|| format!("Unknown document {}", doc_id))?;
+            let operation = doc.extractor.toggle(doc.log.text(), id)?;
+            DeltaMessage { client_id: String::new(), seq: 0, base_revision: doc.log.revision(), paste: false, bulk: None, operations: vec![operation] }
+        };
+        match self.receive(doc_id, &delta, now).map_err(|reject| reject.reason)? {
+            Receipt::Applied(_, remote) => Ok(remote),
+            receipt => Err(format!("Toggle was not applied: {:?}", receipt)),
+        }
+    }
+
+    /// Handles a WebSocket frame if it is a task command; `None` for anything else
+    pub fn handle_command(&self, doc_id: &str, frame: &str, now: Instant) -> Option<Result<RemoteDeltaMessage, String>> {
+        match serde_json::from_str::<TaskCommand>(frame).ok()? {
+            TaskCommand::ToggleTask { id } => Some(self.toggle(doc_id, &id, now)),
+        }
+    }
+
+    /// The current tasks of `doc_id`
+    pub fn tasks(&self, doc_id: &str) -> Option<Vec<Task>> {
+        self.docs.lock().unwrap().get(doc_id).map(|doc| doc.extractor.tasks())
+    }
+
+    /// `{"type":"tasks"}` broadcasts for documents whose tasks changed and then stayed unchanged
+    /// for `TASKS_DEBOUNCE`, so typing in a task doesn't send a list per keystroke
+    pub fn due_broadcasts(&self, now: Instant) -> Vec<(String, serde_json::Value)> {
+        let mut docs = self.docs.lock().unwrap();
+        let mut due = Vec::new();
+        for (doc_id, doc) in docs.iter_mut() {
+            if doc.changed_at.is_some_and(|changed_at| now.duration_since(changed_at) >= TASKS_DEBOUNCE) {
+                doc.changed_at = None;
+                doc.broadcast = doc.extractor.tasks();
+                due.push((doc_id.clone(), serde_json::json!({ "type": "tasks", "doc": doc_id, "tasks": doc.broadcast })));
+            }
+        }
+        due
+    }
+}
+
+impl Default for TaskSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handler for `GET /api/docs/:id/tasks`
+pub async fn tasks_handler(doc_id: String, sync: TaskSync) -> Result<impl warp::Reply, warp::Rejection> {
+    let tasks = sync.tasks(&doc_id).ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&tasks))
+}
+
+/// Route serving each document's aggregated task list
+pub fn tasks_route(sync: TaskSync) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "docs" / String / "tasks")
+        .and(warp::get())
+        .and(warp::any().map(move || sync.clone()))
+        .and_then(tasks_handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::diff_engine::DiffOperation;
+
+    const PLAN: &str = "# Plan\n- [ ] Draft @ana\n- [ ] Review";
+
+    fn sync_with_plan() -> TaskSync {
+        let sync = TaskSync::new();
+        sync.open("plan", "md", PLAN);
+        sync
+    }
+
+    #[test]
+    fn test_toggle_command_goes_through_delta_path() {
+        let sync = sync_with_plan();
+        let now = Instant::now();
+        let id = sync.tasks("plan").unwrap()[1].id.clone();
+
+        let frame = serde_json::json!({ "type": "toggle_task", "id": id }).to_string();
+        let remote = sync.handle_command("plan", &frame, now).unwrap().unwrap();
+        assert_eq!((remote.revision, remote.operations), (1, vec![DiffOperation::Replace(26, 29, "[x]".to_string())]));
+        assert!(sync.tasks("plan").unwrap()[1].done);
+
+        // A concurrent edit based on the old revision is transformed past the toggle
+        let delta = DeltaMessage { client_id: String::new(), seq: 1, base_revision: 0, paste: false, bulk: None, operations: vec![DiffOperation::Insert(0, "Draft\n".to_string())] };
+        sync.receive("plan", &delta, now).unwrap();
+        let tasks = sync.tasks("plan").unwrap();
+        assert_eq!((tasks[1].id.as_str(), tasks[1].line, tasks[1].done), (id.as_str(), 3, true));
+
+        assert!(sync.handle_command("plan", r#"{"content":"x"}"#, now).is_none());
+        assert!(sync.handle_command("plan", r#"{"type":"toggle_task","id":"nope"}"#, now).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_broadcasts_debounced_until_edits_settle() {
+        let sync = sync_with_plan();
+        let start = Instant::now();
+        let insert = |seq, at: usize, text: &str, now| {
+            let delta = DeltaMessage { client_id: String::new(), seq, base_revision: seq, paste: false, bulk: None, operations: vec![DiffOperation::Insert(at, text.to_string())] };
+            sync.receive("plan", &delta, now).unwrap();
+        };
+
+        insert(0, 0, "Intro\n", start); // Moves both tasks down a line
+        insert(1, PLAN.len() + 6, " it", start);
+        insert(2, PLAN.len() + 9, "!", start + Duration::from_millis(300));
+        assert!(sync.due_broadcasts(start + Duration::from_millis(600)).is_empty());
+
+        let due = sync.due_broadcasts(start + Duration::from_millis(800));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].1["tasks"][1]["text"], "Review it!");
+        assert!(sync.due_broadcasts(start + Duration::from_secs(5)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tasks_api() {
+        let route = tasks_route(sync_with_plan());
+        let response = warp::test::request().path("/api/docs/plan/tasks").reply(&route).await;
+        let tasks: Vec<Task> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].assignee.as_deref(), Some("ana"));
+        assert_eq!(warp::test::request().path("/api/docs/other/tasks").reply(&route).await.status(), 404);
+    }
+}
@@ -1,8 +1,16 @@
-use warp::ws::{Message, WebSocket};
+use warp::ws::WebSocket;
+use warp::Filter;
 use futures_util::{StreamExt, SinkExt};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use serde::{Serialize, Deserialize};
 use tokio::sync::broadcast;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message as ClientMessage;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use futures_util::{future, Sink, Stream};
+use uuid::Uuid;
+use crate::client::{self, Client, Clients};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RealTimeMessage {
@@ -11,10 +19,10 @@ pub struct RealTimeMessage {
     pub timestamp: String,
 }
 
-type WebSocketClients = Arc<Mutex<Vec<warp::ws::WebSocket>>>;
-
+/// Relays every message a client sends to all connected clients, itself included
+#[derive(Clone)]
 pub struct WebSocketManager {
-    clients: WebSocketClients,
+    clients: Clients, // Each client's messages are forwarded to its socket by a task of its own
     broadcaster: broadcast::Sender<RealTimeMessage>,
 }
 
@@ -23,63 +31,61 @@ impl WebSocketManager {
     pub fn new() -> Self {
         let (broadcaster, _) = broadcast::channel(100); // Creates a broadcast channel with 100 capacity
         Self {
-            clients: Arc::new(Mutex::new(Vec::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
             broadcaster,
         }
     }
 
-    /// Registers a new WebSocket client and starts listening for messages
-    pub async fn register_client(&self, socket: WebSocket) {
-        let (mut ws_tx, mut ws_rx) = socket.split();
-
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.push(ws_tx.clone());
-        }
-
-        let mut rx = self.broadcaster.subscribe();
-
-        // Task to forward messages from broadcast channel to this client
-        let send_task = tokio::spawn(async move {
-            while let Ok(message) = rx.recv().await {
-                let msg_text = serde_json::to_string(&message).unwrap();
-                if ws_tx.send(Message::text(msg_text)).await.is_err() {
-                    break; // Client disconnected
-                }
-            }
-        });
+    /// Receives every message broadcast from now on, for listeners on the server
+    pub fn subscribe(&self) -> broadcast::Receiver<RealTimeMessage> {
+        self.broadcaster.subscribe()
+    }
 
-        // Task to receive messages from this WebSocket client
-        let recv_task = tokio::spawn(async move {
-            while let Some(result) = ws_rx.next().await {
-                if let Ok(msg) = result {
-                    if msg.is_text() {
-                        let msg_text = msg.to_str().unwrap();
-                        let received_message: RealTimeMessage = serde_json::from_str(msg_text).unwrap();
-                        // Broadcast the received message to all clients
-                        let _ = self.broadcaster.send(received_message);
-                    }
+    /// Registers a new WebSocket client and starts listening for messages
+    pub async fn register_client(self, socket: WebSocket) {
+        let (ws_tx, ws_rx) = socket.split();
+        let (sender, send_task) = client::spawn_writer(ws_tx);
+        let writer = send_task.abort_handle();
+        let client_id = Uuid::new_v4().to_string();
+        client::add_client(self.clients.clone(), client_id.clone(), Client::new(&client_id, "", sender).with_writer(writer.clone()));
+
+        let mut ws_rx = ws_rx.take_until(send_task);
+
+        // Broadcast each message received from this client, skipping frames that aren't one
+        while let Some(result) = ws_rx.next().await {
+            if let Ok(msg) = result {
+                if let Ok(received_message) = serde_json::from_str::<RealTimeMessage>(msg.to_str().unwrap_or_default()) {
+                    self.broadcast(received_message);
                 }
             }
-        });
-
-        // Wait for either the send or receive task to complete
-        tokio::select! {
-            _ = send_task => (),
-            _ = recv_task => (),
         }
 
         // Remove the client when the connection is closed
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.retain(|client| !client.is_closed());
-        }
+        client::remove_client(self.clients.clone(), &client_id);
+        writer.abort();
+    }
+
+    /// Sends `message` to all connected clients and to the server's subscribers
+    pub fn broadcast(&self, message: RealTimeMessage) {
+        client::broadcast_message(self.clients.clone(), &serde_json::to_string(&message).unwrap());
+        let _ = self.broadcaster.send(message); // Fails only when no one subscribed
+    }
+
+    /// How many clients are connected
+    pub fn client_count(&self) -> usize {
+        client::get_client_count(self.clients.clone())
+    }
+}
+
+impl Default for WebSocketManager {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 /// WebSocket handler for real-time communication
-pub async fn websocket_handler(ws: warp::ws::Ws, manager: WebSocketManager) -> impl warp::Reply {
-    ws.on_upgrade(move |socket| manager.register_client(socket))
+pub async fn websocket_handler(ws: warp::ws::Ws, manager: WebSocketManager) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(ws.on_upgrade(move |socket| manager.register_client(socket)))
 }
 
 /// Route for WebSocket real-time communication
@@ -95,15 +101,115 @@ fn with_manager(manager: WebSocketManager) -> impl warp::Filter<Extract = (WebSo
     warp::any().map(move || manager.clone())
 }
 
-/// Example main function for setting up the WebSocket server
-#[tokio::main]
-async fn main() {
-    let ws_manager = WebSocketManager::new();
+/// Client side of a WebSocket connection to the server, exchanging text frames
+pub struct WebSocketClient {
+    url: String,
+    stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>, // Set while connected
+}
+
+impl WebSocketClient {
+    /// Creates a client for `url`; nothing is connected until `connect`
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_string(), stream: None }
+    }
 
-    // WebSocket route for real-time communication
-    let ws_route = websocket_route(ws_manager.clone());
+    /// Opens the connection, replacing any earlier one
+    pub async fn connect(&mut self) -> Result<(), String> {
+        let (stream, _) = connect_async(self.url.as_str()).await.map_err(|e| format!("Failed to connect to {}: {}", self.url, e))?;
+        self.stream = Some(stream);
+        Ok(())
+    }
 
-    // Start the WebSocket server
-    println!("WebSocket server running on ws://localhost:3030/ws");
-    warp::serve(ws_route).run(([127, 0, 0, 1], 3030)).await;
+    /// Whether `connect` succeeded and the connection wasn't closed since
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Sends a text frame
+    pub async fn send_message(&mut self, text: &str) -> Result<(), String> {
+        let stream = self.stream.as_mut().ok_or("Not connected")?;
+        stream.send(ClientMessage::text(text)).await.map_err(|e| e.to_string())
+    }
+
+    /// Waits for the next text frame, skipping other frames. `None` once the connection is closed.
+    pub async fn receive_message(&mut self) -> Option<String> {
+        let stream = self.stream.as_mut()?;
+        while let Some(Ok(message)) = stream.next().await {
+            if let ClientMessage::Text(text) = message {
+                return Some(text);
+            }
+        }
+        self.stream = None;
+        None
+    }
+
+    /// Closes the connection, if open
+    pub async fn disconnect(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.close(None).await;
+        }
+    }
+
+    /// Splits the open connection into a sink of outgoing and a stream of incoming text frames,
+    /// for a reader and a writer running apart. The stream ends when the connection does.
+    pub fn into_split(self) -> Result<(impl Sink<String, Error = String>, impl Stream<Item = String>), String> {
+        let (sink, stream) = self.stream.ok_or("Not connected")?.split();
+        let sink = sink.sink_map_err(|e| e.to_string()).with(|text: String| future::ready(Ok::<_, String>(ClientMessage::Text(text))));
+        let stream = stream.take_while(|message| future::ready(message.is_ok())).filter_map(|message| {
+            future::ready(match message {
+                Ok(ClientMessage::Text(text)) => Some(text),
+                _ => None,
+            })
+        });
+        Ok((sink, stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(sender: &str, content: &str) -> RealTimeMessage {
+        RealTimeMessage { sender: sender.to_string(), content: content.to_string(), timestamp: String::new() }
+    }
+
+    async fn recv_message(client: &mut warp::test::WsClient) -> RealTimeMessage {
+        serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap()
+    }
+
+    /// Waits until `count` clients are connected, failing rather than hanging if they never are
+    async fn wait_for_clients(manager: &WebSocketManager, count: usize) {
+        let settled = async {
+            while manager.client_count() != count {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), settled).await.expect("the client count never settled");
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_broadcast_to_every_client() {
+        let manager = WebSocketManager::new();
+        let mut events = manager.subscribe();
+        let route = websocket_route(manager.clone());
+        let mut ana = warp::test::ws().path("/ws").handshake(route.clone()).await.unwrap();
+        let mut bo = warp::test::ws().path("/ws").handshake(route).await.unwrap();
+        wait_for_clients(&manager, 2).await;
+
+        // A message from one client reaches both, and the server's subscribers
+        ana.send_text(serde_json::to_string(&message("ana", "hello")).unwrap()).await;
+        assert_eq!(recv_message(&mut bo).await.content, "hello");
+        assert_eq!(recv_message(&mut ana).await.content, "hello");
+        assert_eq!(events.recv().await.unwrap().sender, "ana");
+
+        // Frames that aren't messages are skipped; broadcasts from the server reach every client
+        bo.send_text("not a message").await;
+        manager.broadcast(message("server", "restarting"));
+        assert_eq!(recv_message(&mut ana).await.content, "restarting");
+        assert_eq!(recv_message(&mut bo).await.content, "restarting");
+
+        // Closed connections leave the client list
+        drop(ana);
+        wait_for_clients(&manager, 1).await;
+    }
 }
@@ -0,0 +1,73 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limits how many messages each user may send within a sliding window
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: usize,     // Messages allowed per window
+    window: Duration,
+    sent: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>, // When each user's messages in the window were sent
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing each user `limit` messages every `window`
+    pub fn new(limit: usize, window: Duration) -> Self {
+        Self { limit, window, sent: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Counts a message from `user` sent at `now`, or refuses it when the user has already sent
+    /// `limit` messages in the window. Refused messages don't count against the user.
+    pub fn check(&self, user: &str, now: Instant) -> Result<(), String> {
+        let mut sent = self.sent.lock().unwrap();
+        if !sent.contains_key(user) {
+            // Forget users who have been quiet for a whole window, so the map stays small
+            sent.retain(|_, times| times.back().is_some_and(|last| now.duration_since(*last) < self.window));
+        }
+
+        let times = sent.entry(user.to_string()).or_default();
+        while times.front().is_some_and(|first| now.duration_since(*first) >= self.window) {
+            times.pop_front();
+        }
+        if times.len() >= self.limit {
+            return Err(format!(
+                "Slow down: at most {} messages every {} seconds",
+                self.limit,
+                self.window.as_secs()
+            ));
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flood_is_throttled_per_user() {
+        let limiter = RateLimiter::new(3, Duration::from_secs(10));
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.check("alice", start).unwrap();
+        }
+        assert!(limiter.check("alice", start + Duration::from_secs(1)).is_err());
+        assert!(limiter.check("bob", start + Duration::from_secs(1)).is_ok());
+
+        // The window slides: once the first messages age out, more are allowed
+        assert!(limiter.check("alice", start + Duration::from_secs(10)).is_ok());
+        assert!(limiter.check("alice", start + Duration::from_secs(10)).is_ok());
+    }
+
+    #[test]
+    fn test_quiet_users_are_forgotten() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(1));
+        let start = Instant::now();
+        for user in 0..100 {
+            limiter.check(&user.to_string(), start).unwrap();
+        }
+        limiter.check("late", start + Duration::from_secs(2)).unwrap();
+        assert_eq!(limiter.sent.lock().unwrap().len(), 1);
+    }
+}
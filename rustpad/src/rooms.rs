@@ -0,0 +1,448 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Concurrent editors a room allows unless its document says otherwise.
+pub const DEFAULT_MAX_EDITORS: usize = 50;
+
+/// Read-only API token connections a room allows, unless the registry says otherwise.
+pub const DEFAULT_MAX_SUBSCRIBERS: usize = 20;
+
+/// How long a connection may go without doing anything before it is shown as away.
+pub fn away_after() -> chrono::Duration {
+    chrono::Duration::minutes(5)
+}
+
+/// What a connection may do in its room.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Editor,
+    Viewer,     // Waiting in the join queue for an editor slot
+    Subscriber, // Watching with a read-only API token; hidden from presence
+}
+
+/// When a connection in a room last did something, for a "who's here" panel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LastSeen {
+    pub client: String,
+    pub last_activity: DateTime<Utc>,
+    pub away: bool, // Idle for `away_after()` or longer
+}
+
+/// A message for one connection, produced when roles or queue positions change.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notice {
+    pub client_id: String,
+    pub message: serde_json::Value,
+}
+
+struct Room {
+    max_editors: usize,
+    owner: Option<String>,                  // Client that may change the cap and kick idle editors
+    editors: Vec<String>,                   // In the order they became editors
+    queue: VecDeque<(u64, String)>,         // Waiting viewers with their server-wide ticket, oldest first
+    last_active: HashMap<String, Instant>,  // Last edit of each editor, for kicking idle ones
+    last_seen: HashMap<String, DateTime<Utc>>, // Last edit, cursor move or chat message of every connection
+    subscribers: Vec<String>,               // Read-only token connections, outside the editor slots and presence
+}
+
+impl Room {
+    fn new(max_editors: usize) -> Self {
+        Room {
+            max_editors,
+            owner: None,
+            editors: Vec::new(),
+            queue: VecDeque::new(),
+            last_active: HashMap::new(),
+            last_seen: HashMap::new(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Every connection in the room, editors first.
+    fn members(&self) -> Vec<String> {
+        self.editors.iter().cloned().chain(self.queue.iter().map(|(_, id)| id.clone())).collect()
+    }
+
+    /// Tells every queued viewer from `from` on (0-based) its 1-based position.
+    fn queue_positions(&self, from: usize) -> Vec<Notice> {
+        self.queue
+            .iter()
+            .enumerate()
+            .skip(from)
+            .map(|(index, (_, client_id))| queued(client_id, index + 1))
+            .collect()
+    }
+}
+
+/// Editor slots and join queues of every room. Rooms are capped by their document's
+/// `max_editors`, and all rooms together by the server-wide ceiling from `ServerConfig`.
+/// Queued viewers are promoted in the order they joined, across rooms.
+#[derive(Clone)]
+pub struct RoomRegistry {
+    rooms: Arc<Mutex<HashMap<String, Room>>>,
+    max_total_editors: Option<usize>,
+    max_subscribers: usize, // Per room
+    next_ticket: Arc<Mutex<u64>>,
+}
+
+impl RoomRegistry {
+    /// Creates a registry; `max_total_editors` caps the editors of all rooms together.
+    pub fn new(max_total_editors: Option<usize>) -> Self {
+        RoomRegistry {
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            max_total_editors,
+            max_subscribers: DEFAULT_MAX_SUBSCRIBERS,
+            next_ticket: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Caps the read-only token connections of each room.
+    pub fn with_max_subscribers(self, max_subscribers: usize) -> Self {
+        RoomRegistry { max_subscribers, ..self }
+    }
+
+    /// Opens `room` with the cap from its document metadata. Rooms joined without being
+    /// opened get `DEFAULT_MAX_EDITORS`.
+    pub fn open(&self, room: &str, max_editors: usize) {
+        self.rooms.lock().unwrap().entry(room.to_string()).or_insert_with(|| Room::new(max_editors));
+    }
+
+    /// Adds a connection to `room`. The first one owns the room. When the room or the server
+    /// is full the connection joins as a viewer and is told its place in the queue.
+    pub fn join(&self, room: &str, client_id: &str, now: Instant) -> (Role, Vec<Notice>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let total = total_editors(&rooms);
+        let entry = rooms.entry(room.to_string()).or_insert_with(|| Room::new(DEFAULT_MAX_EDITORS));
+        if entry.owner.is_none() {
+            entry.owner = Some(client_id.to_string());
+        }
+        entry.last_seen.insert(client_id.to_string(), Utc::now());
+
+        let server_full = self.max_total_editors.is_some_and(|max| total >= max);
+        if entry.editors.len() < entry.max_editors && !server_full && entry.queue.is_empty() {
+            entry.editors.push(client_id.to_string());
+            entry.last_active.insert(client_id.to_string(), now);
+            return (Role::Editor, Vec::new());
+        }
+
+        entry.queue.push_back((self.ticket(), client_id.to_string()));
+        (Role::Viewer, vec![queued(client_id, entry.queue.len())])
+    }
+
+    /// Adds a read-only token connection to `room`. It takes no editor slot and is left out of
+    /// presence, but the room only takes so many.
+    pub fn subscribe(&self, room: &str, client_id: &str) -> Result<(), String> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let entry = rooms.entry(room.to_string()).or_insert_with(|| Room::new(DEFAULT_MAX_EDITORS));
+        if entry.subscribers.len() >= self.max_subscribers {
+            return Err(format!("Room {} already has {} subscribers", room, self.max_subscribers));
+        }
+        entry.subscribers.push(client_id.to_string());
+        Ok(())
+    }
+
+    /// The read-only token connections of `room`, for the admin view of presence.
+    pub fn subscribers(&self, room: &str) -> Vec<String> {
+        self.rooms.lock().unwrap().get(room).map(|entry| entry.subscribers.clone()).unwrap_or_default()
+    }
+
+    /// Removes a disconnected connection. A freed editor slot goes to the longest-waiting
+    /// viewer that may take it; viewers behind a departed one move up.
+    pub fn leave(&self, room: &str, client_id: &str) -> Vec<Notice> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(entry) = rooms.get_mut(room) else { return Vec::new() };
+
+        let mut notices = Vec::new();
+        entry.subscribers.retain(|id| id != client_id);
+        entry.editors.retain(|id| id != client_id);
+        entry.last_active.remove(client_id);
+        entry.last_seen.remove(client_id);
+        if let Some(index) = entry.queue.iter().position(|(_, id)| id == client_id) {
+            entry.queue.remove(index);
+            notices.extend(entry.queue_positions(index));
+        }
+        if entry.owner.as_deref() == Some(client_id) {
+            entry.owner = entry.editors.first().cloned(); // The longest-standing editor takes over
+        }
+
+        notices.extend(self.fill_slots(&mut rooms));
+        notices
+    }
+
+    /// Role of a connection in `room`, if it is there.
+    pub fn role(&self, room: &str, client_id: &str) -> Option<Role> {
+        let rooms = self.rooms.lock().unwrap();
+        let entry = rooms.get(room)?;
+        if entry.editors.iter().any(|id| id == client_id) {
+            Some(Role::Editor)
+        } else if entry.queue.iter().any(|(_, id)| id == client_id) {
+            Some(Role::Viewer)
+        } else if entry.subscribers.iter().any(|id| id == client_id) {
+            Some(Role::Subscriber)
+        } else {
+            None
+        }
+    }
+
+    /// Records an edit by `client_id`, so it doesn't count as idle.
+    pub fn touch(&self, room: &str, client_id: &str, now: Instant) {
+        if let Some(entry) = self.rooms.lock().unwrap().get_mut(room) {
+            if let Some(last_active) = entry.last_active.get_mut(client_id) {
+                *last_active = now;
+            }
+        }
+    }
+
+    /// Records that `client_id` edited, moved its cursor or chatted at `at`. Unlike `touch` this
+    /// counts for viewers too; it is what `active_users` and `last_seen` go by.
+    pub fn record_activity(&self, room: &str, client_id: &str, at: DateTime<Utc>) {
+        if let Some(entry) = self.rooms.lock().unwrap().get_mut(room) {
+            if let Some(last_seen) = entry.last_seen.get_mut(client_id) {
+                *last_seen = (*last_seen).max(at);
+            }
+        }
+    }
+
+    /// When each connection in `room` last did something, most recent first, with those idle
+    /// for `away_after()` by `now` marked away.
+    pub fn last_seen(&self, room: &str, now: DateTime<Utc>) -> Vec<LastSeen> {
+        let rooms = self.rooms.lock().unwrap();
+        let Some(entry) = rooms.get(room) else { return Vec::new() };
+        let mut seen: Vec<LastSeen> = entry
+            .last_seen
+            .iter()
+            .map(|(client, last_activity)| LastSeen { client: client.clone(), last_activity: *last_activity, away: now - *last_activity >= away_after() })
+            .collect();
+        seen.sort_by(|a, b| b.last_activity.cmp(&a.last_activity).then_with(|| a.client.cmp(&b.client)));
+        seen
+    }
+
+    /// The connections in `room` that did something within `within` of `now`, sorted.
+    pub fn active_users(&self, room: &str, within: chrono::Duration, now: DateTime<Utc>) -> Vec<String> {
+        let mut active: Vec<String> = self.last_seen(room, now).into_iter().filter(|seen| now - seen.last_activity < within).map(|seen| seen.client).collect();
+        active.sort();
+        active
+    }
+
+    /// Changes the cap of `room`; only its owner may. Raising it promotes queued viewers.
+    /// Lowering it keeps current editors and only stops new ones from joining.
+    pub fn set_max_editors(&self, room: &str, client_id: &str, max_editors: usize) -> Result<Vec<Notice>, String> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let entry = owned_room(&mut rooms, room, client_id)?;
+        entry.max_editors = max_editors;
+        Ok(self.fill_slots(&mut rooms))
+    }
+
+    /// Moves editors of `room` who haven't edited for `idle_for` to the back of the join queue,
+    /// handing their slots to waiting viewers; only the owner may, and is never kicked.
+    pub fn kick_idle(&self, room: &str, client_id: &str, idle_for: Duration, now: Instant) -> Result<Vec<Notice>, String> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let entry = owned_room(&mut rooms, room, client_id)?;
+
+        let idle: Vec<String> = entry
+            .editors
+            .iter()
+            .filter(|id| *id != client_id)
+            .filter(|id| entry.last_active.get(*id).is_some_and(|last| now.duration_since(*last) >= idle_for))
+            .cloned()
+            .collect();
+        let mut notices = Vec::new();
+        for id in &idle {
+            entry.editors.retain(|editor| editor != id);
+            entry.last_active.remove(id);
+            entry.queue.push_back((self.ticket(), id.clone()));
+            notices.extend(presence(entry, id, Role::Viewer));
+            notices.push(queued(id, entry.queue.len()));
+        }
+
+        notices.extend(self.fill_slots(&mut rooms));
+        Ok(notices)
+    }
+
+    /// Promotes queued viewers, oldest ticket first across all rooms, while any of them has
+    /// a free slot in its room and the server is below its ceiling.
+    fn fill_slots(&self, rooms: &mut HashMap<String, Room>) -> Vec<Notice> {
+        let mut notices = Vec::new();
+        while self.max_total_editors.is_none_or(|max| total_editors(rooms) < max) {
+            let next = rooms
+                .values_mut()
+                .filter(|entry| entry.editors.len() < entry.max_editors)
+                .filter_map(|entry| entry.queue.front().map(|(ticket, _)| *ticket).map(|ticket| (ticket, entry)))
+                .min_by_key(|(ticket, _)| *ticket);
+            let Some((_, entry)) = next else { break };
+
+            let (_, client_id) = entry.queue.pop_front().unwrap();
+            entry.editors.push(client_id.clone());
+            entry.last_active.insert(client_id.clone(), Instant::now());
+            notices.extend(presence(entry, &client_id, Role::Editor));
+            notices.extend(entry.queue_positions(0));
+        }
+        notices
+    }
+
+    fn ticket(&self) -> u64 {
+        let mut next_ticket = self.next_ticket.lock().unwrap();
+        *next_ticket += 1;
+        *next_ticket
+    }
+}
+
+fn total_editors(rooms: &HashMap<String, Room>) -> usize {
+    rooms.values().map(|entry| entry.editors.len()).sum()
+}
+
+fn owned_room<'a>(rooms: &'a mut HashMap<String, Room>, room: &str, client_id: &str) -> Result<&'a mut Room, String> {
+    let entry = rooms.get_mut(room).ok_or_else(|| format!("Room {} does not exist", room))?;
+    if entry.owner.as_deref() != Some(client_id) {
+        return Err("Only the room owner can do that".to_string());
+    }
+    Ok(entry)
+}
+
+fn queued(client_id: &str, position: usize) -> Notice {
+    Notice {
+        client_id: client_id.to_string(),
+        message: serde_json::json!({ "type": "queued", "position": position }),
+    }
+}
+
+/// Announces to everyone in the room that `client_id` now has `role`.
+fn presence(entry: &Room, client_id: &str, role: Role) -> Vec<Notice> {
+    let message = serde_json::json!({ "type": "presence", "client": client_id, "role": role });
+    entry
+        .members()
+        .into_iter()
+        .map(|member| Notice { client_id: member, message: message.clone() })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages_for<'a>(notices: &'a [Notice], client_id: &str) -> Vec<&'a serde_json::Value> {
+        notices.iter().filter(|notice| notice.client_id == client_id).map(|notice| &notice.message).collect()
+    }
+
+    #[test]
+    fn test_full_room_queues_viewers_and_promotes_in_order() {
+        let rooms = RoomRegistry::new(None);
+        let now = Instant::now();
+        rooms.open("pad", 1);
+        assert_eq!(rooms.join("pad", "a", now).0, Role::Editor);
+
+        let (role, notices) = rooms.join("pad", "b", now);
+        assert_eq!(role, Role::Viewer);
+        assert_eq!(notices, vec![queued("b", 1)]);
+        assert_eq!(rooms.join("pad", "c", now).1, vec![queued("c", 2)]);
+
+        // The editor leaving promotes the first viewer; the other moves up
+        let notices = rooms.leave("pad", "a");
+        assert_eq!(rooms.role("pad", "b"), Some(Role::Editor));
+        assert_eq!(messages_for(&notices, "b")[0]["role"], "editor");
+        assert_eq!(messages_for(&notices, "c").last().unwrap()["position"], 1);
+
+        // A queued viewer leaving drops out of the queue
+        rooms.join("pad", "d", now);
+        let notices = rooms.leave("pad", "c");
+        assert_eq!(notices, vec![queued("d", 1)]);
+        rooms.leave("pad", "b");
+        assert_eq!(rooms.role("pad", "d"), Some(Role::Editor));
+    }
+
+    #[test]
+    fn test_owner_raises_cap_and_kicks_idle_editors() {
+        let rooms = RoomRegistry::new(None);
+        let start = Instant::now();
+        rooms.open("pad", 2);
+        rooms.join("pad", "owner", start);
+        rooms.join("pad", "idle", start);
+        rooms.join("pad", "b", start);
+        rooms.join("pad", "c", start);
+
+        assert!(rooms.set_max_editors("pad", "b", 10).is_err());
+        rooms.set_max_editors("pad", "owner", 3).unwrap();
+        assert_eq!(rooms.role("pad", "b"), Some(Role::Editor));
+        assert_eq!(rooms.role("pad", "c"), Some(Role::Viewer));
+
+        // The idle editor's slot goes to the waiting viewer; the owner is never kicked
+        let later = start + Duration::from_secs(600);
+        rooms.touch("pad", "b", later);
+        rooms.kick_idle("pad", "owner", Duration::from_secs(300), later).unwrap();
+        assert_eq!(rooms.role("pad", "idle"), Some(Role::Viewer));
+        assert_eq!(rooms.role("pad", "c"), Some(Role::Editor));
+        assert_eq!(rooms.role("pad", "owner"), Some(Role::Editor));
+        assert_eq!(rooms.role("pad", "b"), Some(Role::Editor));
+    }
+
+    #[test]
+    fn test_server_wide_ceiling_across_rooms() {
+        let rooms = RoomRegistry::new(Some(2));
+        let now = Instant::now();
+        rooms.join("one", "a", now);
+        rooms.join("two", "b", now);
+        assert_eq!(rooms.join("two", "c", now).0, Role::Viewer);
+        assert_eq!(rooms.join("one", "d", now).0, Role::Viewer);
+
+        // A slot freed in room one goes to the oldest waiter, even though it waits in room two
+        rooms.leave("one", "a");
+        assert_eq!(rooms.role("two", "c"), Some(Role::Editor));
+        assert_eq!(rooms.role("one", "d"), Some(Role::Viewer));
+    }
+
+    #[test]
+    fn test_last_seen_advances_and_goes_away() {
+        let rooms = RoomRegistry::new(None);
+        let start = Utc::now();
+        rooms.join("pad", "ana", Instant::now());
+        rooms.join("pad", "ben", Instant::now());
+        assert_eq!(rooms.active_users("pad", chrono::Duration::minutes(1), start), vec!["ana", "ben"]);
+
+        let later = start + chrono::Duration::minutes(3);
+        rooms.record_activity("pad", "ana", later);
+        rooms.record_activity("pad", "ana", start); // Arriving late doesn't move it back
+        rooms.record_activity("pad", "nobody", later);
+        let seen = rooms.last_seen("pad", later);
+        assert_eq!((seen[0].client.as_str(), seen[0].last_activity), ("ana", later));
+        assert_eq!(seen.len(), 2);
+        assert_eq!(rooms.active_users("pad", chrono::Duration::minutes(1), later), vec!["ana"]);
+
+        // Idle past the threshold shows as away, and a left connection is gone
+        let much_later = later + away_after();
+        let away: Vec<(String, bool)> = rooms.last_seen("pad", much_later).into_iter().map(|seen| (seen.client, seen.away)).collect();
+        assert_eq!(away, vec![("ana".to_string(), true), ("ben".to_string(), true)]);
+        assert!(rooms.active_users("pad", chrono::Duration::minutes(1), much_later).is_empty());
+        rooms.leave("pad", "ben");
+        assert_eq!(rooms.last_seen("pad", much_later).len(), 1);
+    }
+
+    #[test]
+    fn test_subscribers_skip_slots_and_presence() {
+        let rooms = RoomRegistry::new(None).with_max_subscribers(2);
+        rooms.open("pad", 1);
+        rooms.subscribe("pad", "bot1").unwrap();
+        rooms.subscribe("pad", "bot2").unwrap();
+        assert!(rooms.subscribe("pad", "bot3").is_err());
+
+        // The editor slot and ownership still go to the first person
+        assert_eq!(rooms.join("pad", "ana", Instant::now()).0, Role::Editor);
+        assert_eq!(rooms.role("pad", "bot1"), Some(Role::Subscriber));
+        assert_eq!(rooms.set_max_editors("pad", "ana", 2).unwrap(), Vec::new());
+        let seen: Vec<String> = rooms.last_seen("pad", Utc::now()).into_iter().map(|seen| seen.client).collect();
+        assert_eq!(seen, vec!["ana"]);
+
+        // Presence changes go to people only
+        rooms.join("pad", "ben", Instant::now());
+        rooms.join("pad", "cy", Instant::now()); // Queued behind ana and ben
+        let notices = rooms.leave("pad", "ana");
+        assert!(messages_for(&notices, "bot1").is_empty() && !messages_for(&notices, "cy").is_empty());
+
+        rooms.leave("pad", "bot1");
+        assert_eq!(rooms.subscribers("pad"), vec!["bot2"]);
+        rooms.subscribe("pad", "bot3").unwrap();
+    }
+}
@@ -3,8 +3,10 @@ use std::sync::{Arc, Mutex};
 use serde::{Deserialize, Serialize};
 use warp::{Filter, Rejection, Reply, http::header::SET_COOKIE};
 use uuid::Uuid;
-use warp::http::HeaderValue;
-use warp::reply::Response;
+use warp::http::{HeaderValue, StatusCode};
+use crate::display::DisplayPreferences;
+use crate::i18n::Locale;
+use crate::validation::Username;
 
 /// Type alias for session store which keeps track of active user sessions.
 pub type Sessions = Arc<Mutex<HashMap<String, UserSession>>>;
@@ -12,20 +14,95 @@ pub type Sessions = Arc<Mutex<HashMap<String, UserSession>>>;
 /// Struct representing a user session.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserSession {
-    pub user_id: String,
+    pub user_id: Username,
     pub is_authenticated: bool,
+    #[serde(default)]
+    pub settings: UserSettings, // Editor preferences, restored on every connection with this session
 }
 
 impl UserSession {
     /// Creates a new user session.
-    pub fn new(user_id: String) -> Self {
+    pub fn new(user_id: Username) -> Self {
         UserSession {
             user_id,
             is_authenticated: true,
+            settings: UserSettings::default(),
         }
     }
 }
 
+/// Editor preferences that follow a user from one connection to the next.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UserSettings {
+    #[serde(default)]
+    pub indent_with_spaces: bool, // Spaces instead of tabs
+    #[serde(default = "default_tab_width")]
+    pub tab_width: usize, // Columns per indentation level
+    #[serde(default)]
+    pub theme: Option<String>, // Color theme by name; `None` for the default
+    #[serde(default)]
+    pub wrap_width: Option<usize>, // Column long lines wrap at; `None` doesn't wrap
+    #[serde(default)]
+    pub autosave_secs: Option<u64>, // Seconds between autosaves; `None` saves only when asked
+    #[serde(default)]
+    pub display: DisplayPreferences, // Cursor palette and how other users are shown
+    #[serde(default)]
+    pub locale: Option<String>, // Language of server messages, e.g. "de"; `None` goes by the browser's
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        UserSettings { indent_with_spaces: false, tab_width: default_tab_width(), theme: None, wrap_width: None, autosave_secs: None, display: DisplayPreferences::default(), locale: None }
+    }
+}
+
+impl UserSettings {
+    /// Checks that the settings are within what the editor supports.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=16).contains(&self.tab_width) {
+            return Err(format!("Tab width must be between 1 and 16, not {}", self.tab_width));
+        }
+        if self.theme.as_ref().is_some_and(|theme| theme.is_empty() || theme.len() > 64) {
+            return Err("Theme names are 1 to 64 bytes long".to_string());
+        }
+        if self.wrap_width.is_some_and(|width| !(20..=1000).contains(&width)) {
+            return Err("Lines wrap at between 20 and 1000 columns".to_string());
+        }
+        if self.autosave_secs.is_some_and(|secs| !(1..=3600).contains(&secs)) {
+            return Err("Autosave runs every 1 to 3600 seconds".to_string());
+        }
+        if let Some(locale) = self.locale.as_deref().filter(|locale| Locale::from_tag(locale).is_none()) {
+            return Err(format!("Server messages aren't available in {:?}", locale));
+        }
+        Ok(())
+    }
+}
+
+fn default_tab_width() -> usize {
+    4
+}
+
+/// The editor settings of session `session_id`. Unknown ids start a guest session, as in `with_session`.
+pub fn load_settings(sessions: &Sessions, session_id: &str) -> UserSettings {
+    let mut sessions = sessions.lock().unwrap();
+    sessions
+        .entry(session_id.to_string())
+        .or_insert_with(|| UserSession::new(Username::guest()))
+        .settings
+        .clone()
+}
+
+/// Validates `settings` and saves them to session `session_id`, for its later connections.
+pub fn save_settings(sessions: &Sessions, session_id: &str, settings: UserSettings) -> Result<(), String> {
+    settings.validate()?;
+    let mut sessions = sessions.lock().unwrap();
+    sessions
+        .entry(session_id.to_string())
+        .or_insert_with(|| UserSession::new(Username::guest()))
+        .settings = settings;
+    Ok(())
+}
+
 /// Generates a unique session ID using UUID.
 pub fn generate_session_id() -> String {
     Uuid::new_v4().to_string()
@@ -53,7 +130,7 @@ pub fn with_session(
                 // Retrieve existing session or create a new one.
                 let session = sessions
                     .entry(session_id.clone())
-                    .or_insert_with(|| UserSession::new("guest".to_string()))
+                    .or_insert_with(|| UserSession::new(Username::guest()))
                     .clone();
 
                 Ok::<_, Rejection>(session)
@@ -68,6 +145,15 @@ pub async fn create_session(
 ) -> Result<impl Reply, Rejection> {
     let session_id = generate_session_id();
 
+    // Reject user IDs that fail validation before they reach the session store.
+    let user_id = match Username::try_from(user_id) {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            let reply = warp::reply::json(&serde_json::json!({ "type": "error", "message": e.to_string() }));
+            return Ok(warp::reply::with_status(reply, StatusCode::BAD_REQUEST).into_response());
+        }
+    };
+
     // Create a new session with the provided user ID.
     let new_session = UserSession::new(user_id);
 
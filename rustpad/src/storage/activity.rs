@@ -0,0 +1,556 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use warp::http::StatusCode;
+use warp::Filter;
+
+use crate::auth::auth::authenticated_user;
+use crate::storage::Storage;
+
+/// Default number of entries kept per document
+pub const DEFAULT_MAX_ENTRIES: usize = 200;
+
+/// Consecutive edits by the same user within this window collapse into one entry
+pub fn edit_coalesce_window() -> Duration {
+    Duration::minutes(10)
+}
+
+/// New entries kept for subscribers that fall behind before the oldest are dropped
+const PUBLISHED_CAPACITY: usize = 256;
+
+/// Consecutive chat messages by the same user within this window collapse into one burst
+pub fn chat_burst_window() -> Duration {
+    Duration::minutes(2)
+}
+
+/// What happened in a document, at a coarse grain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ActivityKind {
+    Joined,
+    Left,
+    Edited { lines_changed: usize, edits: usize },
+    CheckpointCreated { name: String },
+    Reverted { version_id: usize },
+    Renamed { from: String, to: String },
+    ChatBurst { messages: usize },
+    Saved,
+    Mentioned { users: Vec<String> },
+}
+
+/// A single entry of the activity feed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ActivityEvent {
+    pub id: u64,
+    pub user: String,
+    pub kind: ActivityKind,
+    pub started_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>, // Moves forward when later edits are coalesced into this entry
+}
+
+/// "12 changes by 2 people since your last visit"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ActivitySummary {
+    pub changes: usize,
+    pub people: usize,
+    pub since: Option<DateTime<Utc>>,
+    pub message: String,
+}
+
+/// Persisted form of a feed
+#[derive(Serialize, Deserialize, Default)]
+struct SavedFeed {
+    events: Vec<ActivityEvent>,
+    last_seen: HashMap<String, DateTime<Utc>>,
+    next_id: u64,
+}
+
+/// Bounded feed of coarse-grained events for one document
+pub struct ActivityFeed {
+    doc_id: String,
+    max_entries: usize,
+    events: VecDeque<ActivityEvent>,
+    last_seen: HashMap<String, DateTime<Utc>>, // Per-user last visit
+    next_id: u64,
+    storage: Option<Arc<dyn Storage + Send + Sync>>,
+    published: Option<broadcast::Sender<(String, ActivityEvent)>>, // New entries, with the document id
+}
+
+impl ActivityFeed {
+    /// Creates an empty in-memory feed for `doc_id`
+    pub fn new(doc_id: &str, max_entries: usize) -> Self {
+        Self {
+            doc_id: doc_id.to_string(),
+            max_entries,
+            events: VecDeque::new(),
+            last_seen: HashMap::new(),
+            next_id: 1,
+            storage: None,
+            published: None,
+        }
+    }
+
+    /// Creates a feed persisted through `storage`, loading any previously saved entries
+    pub fn with_storage(doc_id: &str, max_entries: usize, storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        let mut feed = Self::new(doc_id, max_entries);
+        if let Ok(saved) = storage.load(&feed.storage_id()) {
+            if let Ok(saved) = serde_json::from_str::<SavedFeed>(&saved) {
+                feed.events = saved.events.into_iter().collect();
+                feed.last_seen = saved.last_seen;
+                feed.next_id = saved.next_id.max(1);
+                feed.trim();
+            }
+        }
+        feed.storage = Some(storage);
+        feed
+    }
+
+    /// Records a user joining the document
+    pub fn record_join(&mut self, user: &str, at: DateTime<Utc>) {
+        self.push(user, ActivityKind::Joined, at);
+    }
+
+    /// Records a user leaving the document
+    pub fn record_leave(&mut self, user: &str, at: DateTime<Utc>) {
+        self.push(user, ActivityKind::Left, at);
+    }
+
+    /// Records an edit touching `lines_changed` lines, coalescing it into the user's previous edit
+    /// entry if nothing else was recorded for that user in between and it is within the window
+    pub fn record_edit(&mut self, user: &str, lines_changed: usize, at: DateTime<Utc>) {
+        if let Some(event) = self.latest_for(user, edit_coalesce_window(), at) {
+            if let ActivityKind::Edited { lines_changed: lines, edits } = &mut event.kind {
+                *lines += lines_changed;
+                *edits += 1;
+                event.updated_at = at;
+                self.save();
+                return;
+            }
+        }
+        self.push(user, ActivityKind::Edited { lines_changed, edits: 1 }, at);
+    }
+
+    /// Records a chat message, grouped into bursts per user
+    pub fn record_chat(&mut self, user: &str, at: DateTime<Utc>) {
+        if let Some(event) = self.latest_for(user, chat_burst_window(), at) {
+            if let ActivityKind::ChatBurst { messages } = &mut event.kind {
+                *messages += 1;
+                event.updated_at = at;
+                self.save();
+                return;
+            }
+        }
+        self.push(user, ActivityKind::ChatBurst { messages: 1 }, at);
+    }
+
+    /// Records a checkpoint being created
+    pub fn record_checkpoint(&mut self, user: &str, name: &str, at: DateTime<Utc>) {
+        self.push(user, ActivityKind::CheckpointCreated { name: name.to_string() }, at);
+    }
+
+    /// Records the document being reverted to a version
+    pub fn record_revert(&mut self, user: &str, version_id: usize, at: DateTime<Utc>) {
+        self.push(user, ActivityKind::Reverted { version_id }, at);
+    }
+
+    /// Records the document being saved
+    pub fn record_save(&mut self, user: &str, at: DateTime<Utc>) {
+        self.push(user, ActivityKind::Saved, at);
+    }
+
+    /// Records a chat message by `user` mentioning `users`; nothing when it mentions no one
+    pub fn record_mention(&mut self, user: &str, users: Vec<String>, at: DateTime<Utc>) {
+        if !users.is_empty() {
+            self.push(user, ActivityKind::Mentioned { users }, at);
+        }
+    }
+
+    /// Records the file being renamed
+    pub fn record_rename(&mut self, user: &str, from: &str, to: &str, at: DateTime<Utc>) {
+        self.push(user, ActivityKind::Renamed { from: from.to_string(), to: to.to_string() }, at);
+    }
+
+    /// Entries created or updated after `since`, oldest first
+    pub fn since(&self, since: DateTime<Utc>) -> Vec<ActivityEvent> {
+        self.events.iter().filter(|event| event.updated_at > since).cloned().collect()
+    }
+
+    /// All retained entries, oldest first
+    pub fn events(&self) -> Vec<ActivityEvent> {
+        self.events.iter().cloned().collect()
+    }
+
+    /// The user's last visit, if any
+    pub fn last_seen(&self, user: &str) -> Option<DateTime<Utc>> {
+        self.last_seen.get(user).copied()
+    }
+
+    /// Remembers that `user` has caught up with the feed at `at`
+    pub fn mark_seen(&mut self, user: &str, at: DateTime<Utc>) {
+        self.last_seen.insert(user.to_string(), at);
+        self.save();
+    }
+
+    /// Summarizes what other people did since the user's last visit
+    pub fn summary_for(&self, user: &str) -> ActivitySummary {
+        let since = self.last_seen(user);
+        let mut changes = 0;
+        let mut people = HashSet::new();
+
+        for event in &self.events {
+            if event.user == user || since.is_some_and(|since| event.updated_at <= since) {
+                continue;
+            }
+            let count = match &event.kind {
+                ActivityKind::Edited { edits, .. } => *edits,
+                ActivityKind::CheckpointCreated { .. }
+                | ActivityKind::Reverted { .. }
+                | ActivityKind::Renamed { .. } => 1,
+                _ => 0, // Presence and chat are not document changes
+            };
+            if count > 0 {
+                changes += count;
+                people.insert(event.user.as_str());
+            }
+        }
+
+        let message = format!(
+            "{} {} by {} {} since your last visit",
+            changes,
+            if changes == 1 { "change" } else { "changes" },
+            people.len(),
+            if people.len() == 1 { "person" } else { "people" },
+        );
+        ActivitySummary { changes, people: people.len(), since, message }
+    }
+
+    /// The user's most recent entry, if nothing was recorded for them since and it is within `window`
+    fn latest_for(&mut self, user: &str, window: Duration, at: DateTime<Utc>) -> Option<&mut ActivityEvent> {
+        self.events
+            .iter_mut()
+            .rev()
+            .find(|event| event.user == user)
+            .filter(|event| at - event.updated_at <= window)
+    }
+
+    /// Appends a new entry, dropping the oldest ones beyond the retention bound
+    fn push(&mut self, user: &str, kind: ActivityKind, at: DateTime<Utc>) {
+        let event = ActivityEvent {
+            id: self.next_id,
+            user: user.to_string(),
+            kind,
+            started_at: at,
+            updated_at: at,
+        };
+        if let Some(published) = &self.published {
+            let _ = published.send((self.doc_id.clone(), event.clone())); // No subscribers is fine
+        }
+        self.events.push_back(event);
+        self.next_id += 1;
+        self.trim();
+        self.save();
+    }
+
+    fn trim(&mut self) {
+        while self.events.len() > self.max_entries {
+            self.events.pop_front();
+        }
+    }
+
+    fn storage_id(&self) -> String {
+        format!("activity_{}.json", self.doc_id)
+    }
+
+    /// Persists the feed if it has a storage backend
+    fn save(&self) {
+        if let Some(storage) = &self.storage {
+            let saved = SavedFeed {
+                events: self.events(),
+                last_seen: self.last_seen.clone(),
+                next_id: self.next_id,
+            };
+            match serde_json::to_string(&saved) {
+                Ok(json) => {
+                    if let Err(e) = storage.save(&self.storage_id(), &json) {
+                        eprintln!("Failed to persist activity for {}: {}", self.doc_id, e);
+                    }
+                }
+                Err(e) => eprintln!("Failed to serialize activity for {}: {}", self.doc_id, e),
+            }
+        }
+    }
+}
+
+/// Number of lines touched by changing `old` into `new`, ignoring the unchanged lines around the edit
+pub fn lines_changed(old: &str, new: &str) -> usize {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    let prefix = old_lines.iter().zip(&new_lines).take_while(|(a, b)| a == b).count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    (old_lines.len() - prefix - suffix).max(new_lines.len() - prefix - suffix)
+}
+
+/// The users `@mentioned` in `text`, each once, in order of first mention
+pub fn mentions(text: &str) -> Vec<String> {
+    let mut users: Vec<String> = Vec::new();
+    let names = text
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-')));
+    for name in names {
+        if !name.is_empty() && !users.iter().any(|user| user == name) {
+            users.push(name.to_string());
+        }
+    }
+    users
+}
+
+/// Activity feeds for every document, created on first use
+#[derive(Clone)]
+pub struct ActivityFeeds {
+    feeds: Arc<Mutex<HashMap<String, ActivityFeed>>>,
+    max_entries: usize,
+    storage: Option<Arc<dyn Storage + Send + Sync>>,
+    published: broadcast::Sender<(String, ActivityEvent)>,
+}
+
+impl ActivityFeeds {
+    /// Creates in-memory feeds keeping `max_entries` per document
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            feeds: Arc::new(Mutex::new(HashMap::new())),
+            max_entries,
+            storage: None,
+            published: broadcast::channel(PUBLISHED_CAPACITY).0,
+        }
+    }
+
+    /// Creates feeds persisted through `storage`
+    pub fn with_storage(max_entries: usize, storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self { storage: Some(storage), ..Self::new(max_entries) }
+    }
+
+    /// Runs `f` against the feed of `doc_id`
+    pub fn with_feed<R>(&self, doc_id: &str, f: impl FnOnce(&mut ActivityFeed) -> R) -> R {
+        let mut feeds = self.feeds.lock().unwrap();
+        let feed = feeds.entry(doc_id.to_string()).or_insert_with(|| {
+            let mut feed = match &self.storage {
+                Some(storage) => ActivityFeed::with_storage(doc_id, self.max_entries, storage.clone()),
+                None => ActivityFeed::new(doc_id, self.max_entries),
+            };
+            feed.published = Some(self.published.clone());
+            feed
+        });
+        f(feed)
+    }
+
+    /// Receives every new entry of every document, with its document id. Edits and chat
+    /// coalesced into an existing entry are not sent again.
+    pub fn subscribe(&self) -> broadcast::Receiver<(String, ActivityEvent)> {
+        self.published.subscribe()
+    }
+}
+
+/// Handler for `GET /api/docs/:id/activity?since=<rfc3339>`. Without `since` the signed-in
+/// user's last visit is used; signed in, the last-visit summary is returned too.
+pub async fn activity_handler(
+    doc_id: String,
+    user: Option<String>,
+    query: HashMap<String, String>,
+    feeds: ActivityFeeds,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let since = match query.get("since") {
+        Some(since) => match DateTime::parse_from_rfc3339(since) {
+            Ok(since) => Some(since.with_timezone(&Utc)),
+            Err(e) => {
+                let error = serde_json::json!({ "type": "error", "message": format!("Invalid since: {}", e) });
+                return Ok(warp::reply::with_status(warp::reply::json(&error), StatusCode::BAD_REQUEST));
+            }
+        },
+        None => None,
+    };
+
+    let body = feeds.with_feed(&doc_id, |feed| {
+        let since = since.or_else(|| user.as_deref().and_then(|user| feed.last_seen(user)));
+        let events = match since {
+            Some(since) => feed.since(since),
+            None => feed.events(),
+        };
+        serde_json::json!({
+            "events": events,
+            "summary": user.as_deref().map(|user| feed.summary_for(user)),
+        })
+    });
+    Ok(warp::reply::with_status(warp::reply::json(&body), StatusCode::OK))
+}
+
+/// Route for `GET /api/docs/:id/activity`
+pub fn activity_route(feeds: ActivityFeeds) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "docs" / String / "activity")
+        .and(warp::get())
+        .and(authenticated_user())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::any().map(move || feeds.clone()))
+        .and_then(activity_handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth::bearer_for;
+    use chrono::TimeZone;
+    use crate::storage::MemoryStorage;
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap() + Duration::minutes(minutes)
+    }
+
+    #[test]
+    fn test_edits_coalesce_within_window() {
+        let mut feed = ActivityFeed::new("doc", DEFAULT_MAX_ENTRIES);
+        feed.record_edit("alice", 2, at(0));
+        feed.record_edit("alice", 3, at(9));
+        feed.record_edit("alice", 1, at(18)); // Within 10 minutes of the last coalesced edit
+        feed.record_edit("alice", 4, at(40)); // Window elapsed
+
+        let events = feed.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, ActivityKind::Edited { lines_changed: 6, edits: 3 });
+        assert_eq!(events[0].started_at, at(0));
+        assert_eq!(events[0].updated_at, at(18));
+        assert_eq!(events[1].kind, ActivityKind::Edited { lines_changed: 4, edits: 1 });
+    }
+
+    #[test]
+    fn test_other_activity_breaks_coalescing() {
+        let mut feed = ActivityFeed::new("doc", DEFAULT_MAX_ENTRIES);
+        feed.record_edit("alice", 1, at(0));
+        feed.record_edit("bob", 1, at(1)); // Other users do not interrupt alice's run
+        feed.record_edit("alice", 1, at(2));
+        feed.record_checkpoint("alice", "draft", at(3));
+        feed.record_edit("alice", 1, at(4));
+
+        let kinds: Vec<ActivityKind> = feed.events().into_iter().map(|event| event.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ActivityKind::Edited { lines_changed: 2, edits: 2 },
+                ActivityKind::Edited { lines_changed: 1, edits: 1 },
+                ActivityKind::CheckpointCreated { name: "draft".to_string() },
+                ActivityKind::Edited { lines_changed: 1, edits: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_since_filtering_includes_updated_entries() {
+        let mut feed = ActivityFeed::new("doc", DEFAULT_MAX_ENTRIES);
+        feed.record_join("bob", at(0));
+        feed.record_edit("alice", 1, at(1));
+        feed.record_rename("carol", "a.txt", "b.txt", at(3));
+        feed.record_edit("alice", 1, at(5)); // Updates the entry started at minute 1
+
+        let since: Vec<u64> = feed.since(at(2)).into_iter().map(|event| event.id).collect();
+        assert_eq!(since, vec![2, 3]);
+        assert!(feed.since(at(5)).is_empty());
+    }
+
+    #[test]
+    fn test_last_visit_summary() {
+        let mut feed = ActivityFeed::new("doc", DEFAULT_MAX_ENTRIES);
+        feed.record_edit("alice", 5, at(0));
+        feed.mark_seen("alice", at(1));
+
+        feed.record_edit("bob", 1, at(2));
+        feed.record_edit("bob", 1, at(3));
+        feed.record_edit("alice", 2, at(4)); // Own edits are not counted
+        feed.record_join("dave", at(5)); // Presence is not a change
+        feed.record_revert("carol", 3, at(6));
+
+        let summary = feed.summary_for("alice");
+        assert_eq!(summary.changes, 3);
+        assert_eq!(summary.people, 2);
+        assert_eq!(summary.since, Some(at(1)));
+        assert_eq!(summary.message, "3 changes by 2 people since your last visit");
+
+        // A first-time visitor sees everything done by others
+        assert_eq!(feed.summary_for("erin").changes, 5);
+    }
+
+    #[test]
+    fn test_bounded_retention_and_persistence() {
+        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(MemoryStorage::default());
+        let mut feed = ActivityFeed::with_storage("doc", 3, storage.clone());
+        for minute in 0..5 {
+            feed.record_checkpoint("alice", &format!("c{}", minute), at(minute));
+        }
+        feed.mark_seen("bob", at(10));
+
+        let reloaded = ActivityFeed::with_storage("doc", 3, storage);
+        let ids: Vec<u64> = reloaded.events().into_iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![3, 4, 5]);
+        assert_eq!(reloaded.last_seen("bob"), Some(at(10)));
+    }
+
+    #[test]
+    fn test_scripted_session() {
+        let mut feed = ActivityFeed::new("doc", DEFAULT_MAX_ENTRIES);
+        let mut text = String::from("one\ntwo\nthree\n");
+
+        feed.record_join("alice", at(0));
+        for (minute, next) in [(1, "one\n2\nthree\n"), (2, "one\n2\n3\n"), (3, "one\n2\n3\nfour\n")] {
+            feed.record_edit("alice", lines_changed(&text, next), at(minute));
+            text = next.to_string();
+        }
+        feed.record_chat("alice", at(4));
+        feed.record_chat("alice", at(5));
+        feed.record_checkpoint("alice", "v1", at(6));
+        feed.record_leave("alice", at(7));
+
+        let kinds: Vec<ActivityKind> = feed.events().into_iter().map(|event| event.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ActivityKind::Joined,
+                ActivityKind::Edited { lines_changed: 3, edits: 3 },
+                ActivityKind::ChatBurst { messages: 2 },
+                ActivityKind::CheckpointCreated { name: "v1".to_string() },
+                ActivityKind::Left,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_activity_route() {
+        let feeds = ActivityFeeds::new(DEFAULT_MAX_ENTRIES);
+        feeds.with_feed("doc", |feed| {
+            feed.record_edit("bob", 1, at(0));
+            feed.record_edit("bob", 1, at(30));
+        });
+
+        let response = warp::test::request()
+            .path("/api/docs/doc/activity?since=2024-01-01T09:10:00Z")
+            .header("authorization", bearer_for("alice"))
+            .reply(&activity_route(feeds.clone()))
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["events"].as_array().unwrap().len(), 1);
+        assert_eq!(body["summary"]["message"], "2 changes by 1 person since your last visit");
+
+        let response = warp::test::request()
+            .path("/api/docs/doc/activity?since=yesterday")
+            .reply(&activity_route(feeds))
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+}
@@ -0,0 +1,416 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use crate::storage::quota::{QuotaExceeded, QuotaManager};
+use crate::storage::Storage;
+
+/// Largest attachment accepted, in bytes
+pub const MAX_ATTACHMENT_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Storage namespace attachments are saved under, followed by their hash
+const NAMESPACE: &str = "attachments/";
+
+/// How long an upload may go unreferenced before it is garbage-collected
+pub fn orphan_retention() -> Duration {
+    Duration::hours(24)
+}
+
+/// An uploaded file as referenced from chat messages and annotations
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttachmentRef {
+    pub hash: String, // SHA-256 of the content, hex; also the attachment's id
+    pub name: String,
+    pub mime: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>, // Pixels, for images, so clients can reserve layout space
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+}
+
+/// Response of a successful upload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UploadedAttachment {
+    #[serde(flatten)]
+    pub attachment: AttachmentRef,
+    pub url: String, // Where `GET /api/attachments/:hash` serves it
+}
+
+struct Upload {
+    attachment: AttachmentRef,
+    doc_id: String,
+    uploaded_at: DateTime<Utc>,
+}
+
+/// Content-addressed attachments kept in a `Storage` backend
+#[derive(Clone)]
+pub struct AttachmentStore {
+    storage: Arc<dyn Storage + Send + Sync>,
+    uploads: Arc<Mutex<HashMap<String, Upload>>>, // Keyed by hash
+    quotas: Option<Arc<QuotaManager>>, // Limits on what the owner of each document may upload
+}
+
+impl AttachmentStore {
+    /// Creates a store saving attachments to `storage`
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self { storage, uploads: Arc::new(Mutex::new(HashMap::new())), quotas: None }
+    }
+
+    /// Holds uploads to the owner's quota of the document they are for
+    pub fn with_quotas(self, quotas: QuotaManager) -> Self {
+        Self { quotas: Some(Arc::new(quotas)), ..self }
+    }
+
+    /// Refuses an upload of `bytes` to `doc_id` if it is larger than the document's owner may
+    /// upload, or would take them over their storage
+    pub fn check_quota(&self, doc_id: &str, bytes: u64) -> Result<(), QuotaExceeded> {
+        let Some(quotas) = &self.quotas else { return Ok(()) };
+        match quotas.owner_of(doc_id) {
+            Some(owner) => quotas.check_attachment(&owner, bytes),
+            None => Ok(()), // Documents outside workspaces count against nobody
+        }
+    }
+
+    /// Stores an upload for `doc_id`. Images are recognized by their content, text files by the
+    /// declared type; anything else, anything over `MAX_ATTACHMENT_SIZE`, or anything over the
+    /// owner's quota, is rejected. Uploading the same content twice yields the same attachment,
+    /// and only counts once against the quota.
+    pub fn upload(&self, doc_id: &str, name: &str, declared_mime: &str, content: &[u8], now: DateTime<Utc>) -> Result<AttachmentRef, String> {
+        if content.len() as u64 > MAX_ATTACHMENT_SIZE {
+            return Err(format!("Attachments are limited to {} bytes", MAX_ATTACHMENT_SIZE));
+        }
+        self.check_quota(doc_id, content.len() as u64).map_err(|e| e.to_string())?;
+        let mime = accepted_mime(declared_mime, content)?;
+        let hash = sha256_hex(content);
+        let (width, height) = image_dimensions(mime, content).unzip();
+
+        if !self.uploads.lock().unwrap().contains_key(&hash) {
+            self.storage
+                .save_bytes(&format!("{}{}", NAMESPACE, hash), content)
+                .map_err(|e| format!("Failed to store attachment: {}", e))?;
+            if let Some(quotas) = &self.quotas {
+                if let Some(owner) = quotas.owner_of(doc_id) {
+                    quotas.record(&owner, 0, content.len() as i64);
+                }
+            }
+        }
+        let attachment = AttachmentRef {
+            hash: hash.clone(),
+            name: name.to_string(),
+            mime: mime.to_string(),
+            size: content.len() as u64,
+            width,
+            height,
+        };
+        let upload = Upload { attachment: attachment.clone(), doc_id: doc_id.to_string(), uploaded_at: now };
+        self.uploads.lock().unwrap().insert(hash, upload);
+        Ok(attachment)
+    }
+
+    /// The attachment with `hash` and its content
+    pub fn get(&self, hash: &str) -> Option<(AttachmentRef, Vec<u8>)> {
+        let attachment = self.uploads.lock().unwrap().get(hash)?.attachment.clone();
+        let content = self.storage.load_bytes(&format!("{}{}", NAMESPACE, hash)).ok()?;
+        Some((attachment, content))
+    }
+
+    /// Checks references sent by a client and replaces them with the server's records, so a
+    /// message can't claim another size, type or dimensions. Fails if any hash is unknown.
+    pub fn resolve(&self, references: &[AttachmentRef]) -> Result<Vec<AttachmentRef>, String> {
+        let uploads = self.uploads.lock().unwrap();
+        references
+            .iter()
+            .map(|reference| match uploads.get(&reference.hash) {
+                Some(upload) => Ok(AttachmentRef { name: reference.name.clone(), ..upload.attachment.clone() }),
+                None => Err(format!("Unknown attachment {}", reference.hash)),
+            })
+            .collect()
+    }
+
+    /// Document each attachment was uploaded to, keyed by hash
+    pub fn documents(&self) -> HashMap<String, String> {
+        self.uploads.lock().unwrap().iter().map(|(hash, upload)| (hash.clone(), upload.doc_id.clone())).collect()
+    }
+
+    /// Bytes of the attachments uploaded to each document, by doc id
+    pub fn sizes_by_document(&self) -> HashMap<String, u64> {
+        let mut sizes = HashMap::new();
+        for upload in self.uploads.lock().unwrap().values() {
+            *sizes.entry(upload.doc_id.clone()).or_insert(0) += upload.attachment.size;
+        }
+        sizes
+    }
+
+    /// Deletes attachments older than `orphan_retention()` whose hash is not in `referenced`,
+    /// returning their hashes
+    pub fn collect_garbage(&self, referenced: &HashSet<String>, now: DateTime<Utc>) -> Vec<String> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let orphans: Vec<String> = uploads
+            .iter()
+            .filter(|(hash, upload)| !referenced.contains(*hash) && now - upload.uploaded_at >= orphan_retention())
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in &orphans {
+            if let Err(e) = self.storage.delete(&format!("{}{}", NAMESPACE, hash)) {
+                eprintln!("Failed to delete orphaned attachment {}: {}", hash, e);
+                continue;
+            }
+            uploads.remove(hash);
+        }
+        orphans
+    }
+
+    /// Deletes the attachments uploaded to `doc_id` whose hash is not in `referenced`, e.g. when
+    /// the document is purged, returning their hashes
+    pub fn delete_document(&self, doc_id: &str, referenced: &HashSet<String>) -> Result<Vec<String>, String> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let hashes: Vec<String> = uploads.iter().filter(|(hash, upload)| upload.doc_id == doc_id && !referenced.contains(*hash)).map(|(hash, _)| hash.clone()).collect();
+        for hash in &hashes {
+            self.storage.delete(&format!("{}{}", NAMESPACE, hash)).map_err(|e| format!("Failed to delete attachment {}: {}", hash, e))?;
+            uploads.remove(hash);
+        }
+        Ok(hashes)
+    }
+}
+
+/// Runs `collect_garbage` every hour with the hashes `referenced` returns
+pub fn spawn_garbage_collector<F>(store: AttachmentStore, referenced: F) -> tokio::task::JoinHandle<()>
+where
+    F: Fn() -> HashSet<String> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            let removed = store.collect_garbage(&referenced(), Utc::now());
+            if !removed.is_empty() {
+                println!("Removed {} orphaned attachments", removed.len());
+            }
+        }
+    })
+}
+
+/// MIME type an upload is stored with: the sniffed type for images, the declared one for text
+fn accepted_mime(declared: &str, content: &[u8]) -> Result<&'static str, String> {
+    if let Some(image) = sniff_image(content) {
+        return Ok(image);
+    }
+    let declared = declared.split(';').next().unwrap_or("").trim();
+    let text = match declared {
+        "text/plain" => "text/plain",
+        "text/x-patch" | "text/x-diff" => "text/x-patch",
+        _ => return Err(format!("Attachments of type {:?} are not allowed", declared)),
+    };
+    std::str::from_utf8(content).map(|_| text).map_err(|_| "Text attachments must be UTF-8".to_string())
+}
+
+fn sniff_image(content: &[u8]) -> Option<&'static str> {
+    if content.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if content.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if content.starts_with(b"GIF87a") || content.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else {
+        None
+    }
+}
+
+/// Width and height of a PNG, JPEG or GIF image, read from its header
+pub fn image_dimensions(mime: &str, content: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |at: usize| Some(u16::from_be_bytes([*content.get(at)?, *content.get(at + 1)?]) as u32);
+    match mime {
+        "image/png" => {
+            let be32 = |at: usize| Some(u32::from_be_bytes(content.get(at..at + 4)?.try_into().ok()?));
+            Some((be32(16)?, be32(20)?)) // IHDR follows the signature
+        }
+        "image/gif" => {
+            let le16 = |at: usize| Some(u16::from_le_bytes([*content.get(at)?, *content.get(at + 1)?]) as u32);
+            Some((le16(6)?, le16(8)?)) // Logical screen size
+        }
+        "image/jpeg" => {
+            // Walk the segments up to the first start-of-frame, which holds the size
+            let mut at = 2;
+            while *content.get(at)? == 0xFF {
+                let marker = *content.get(at + 1)?;
+                if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+                    return Some((be16(at + 7)?, be16(at + 5)?));
+                }
+                at += 2 + be16(at + 2)? as usize;
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// SHA-256 of `content`, hex-encoded
+pub(crate) fn sha256_hex(content: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, content).as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn error_reply(status: StatusCode, message: &str) -> warp::reply::Response {
+    let error = warp::reply::json(&serde_json::json!({ "type": "error", "message": message }));
+    warp::reply::with_status(error, status).into_response()
+}
+
+/// Handler for `POST /api/docs/:id/attachments?name=<file name>`, with the file as the body
+pub async fn upload_handler(
+    doc_id: String,
+    query: HashMap<String, String>,
+    content_type: Option<String>,
+    body: warp::hyper::body::Bytes,
+    store: AttachmentStore,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let name = query.get("name").cloned().unwrap_or_else(|| "attachment".to_string());
+    if let Err(exceeded) = store.check_quota(&doc_id, body.len() as u64) {
+        return Ok(exceeded.reply());
+    }
+    match store.upload(&doc_id, &name, content_type.as_deref().unwrap_or(""), &body, Utc::now()) {
+        Ok(attachment) => {
+            let url = format!("/api/attachments/{}", attachment.hash);
+            let reply = warp::reply::json(&UploadedAttachment { attachment, url });
+            Ok(warp::reply::with_status(reply, StatusCode::CREATED).into_response())
+        }
+        Err(e) if body.len() as u64 > MAX_ATTACHMENT_SIZE => Ok(error_reply(StatusCode::PAYLOAD_TOO_LARGE, &e)),
+        Err(e) => Ok(error_reply(StatusCode::UNSUPPORTED_MEDIA_TYPE, &e)),
+    }
+}
+
+/// Handler for `GET /api/attachments/:hash`. Content never changes under a hash, so it may be cached forever.
+pub async fn download_handler(hash: String, store: AttachmentStore) -> Result<warp::reply::Response, warp::Rejection> {
+    let (attachment, content) = store.get(&hash).ok_or_else(warp::reject::not_found)?;
+    let reply = warp::reply::with_header(content, "content-type", attachment.mime);
+    Ok(warp::reply::with_header(reply, "cache-control", "public, max-age=31536000, immutable").into_response())
+}
+
+/// Routes for uploading and downloading attachments
+pub fn attachment_routes(store: AttachmentStore) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let upload_store = store.clone();
+    let upload = warp::path!("api" / "docs" / String / "attachments")
+        .and(warp::post())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || upload_store.clone()))
+        .and_then(upload_handler);
+    let download = warp::path!("api" / "attachments" / String)
+        .and(warp::get())
+        .and(warp::any().map(move || store.clone()))
+        .and_then(download_handler);
+    upload.or(download).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn store() -> (AttachmentStore, Arc<MemoryStorage>) {
+        let storage = Arc::new(MemoryStorage::default());
+        (AttachmentStore::new(storage.clone()), storage)
+    }
+
+    /// A PNG header for an image of `width` by `height`; enough for sniffing and dimensions
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut content = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        content.extend(width.to_be_bytes());
+        content.extend(height.to_be_bytes());
+        content
+    }
+
+    #[tokio::test]
+    async fn test_upload_download_round_trip() {
+        let (store, storage) = store();
+        let routes = attachment_routes(store);
+        let content = png(640, 480);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/docs/design.md/attachments?name=bug.png")
+            .header("content-type", "application/octet-stream")
+            .body(content.clone())
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 201);
+        let uploaded: UploadedAttachment = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(uploaded.attachment.hash, sha256_hex(&content));
+        assert_eq!((uploaded.attachment.mime.as_str(), uploaded.attachment.width, uploaded.attachment.height), ("image/png", Some(640), Some(480)));
+        assert!(storage.files.lock().unwrap().contains_key(&format!("attachments/{}", uploaded.attachment.hash)));
+
+        let response = warp::test::request().path(&uploaded.url).reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "image/png");
+        assert_eq!(response.headers()["cache-control"], "public, max-age=31536000, immutable");
+        assert_eq!(sha256_hex(response.body()), uploaded.attachment.hash);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_disallowed_types_and_oversized_uploads() {
+        let (store, _) = store();
+        let routes = attachment_routes(store.clone());
+        let upload = |content_type: &'static str, body: Vec<u8>| {
+            warp::test::request()
+                .method("POST")
+                .path("/api/docs/design.md/attachments?name=file")
+                .header("content-type", content_type)
+                .body(body)
+        };
+
+        assert_eq!(upload("application/x-msdownload", b"MZ\x90\0".to_vec()).reply(&routes).await.status(), 415);
+        assert_eq!(upload("text/plain", vec![0xFF, 0xFE]).reply(&routes).await.status(), 415); // Not UTF-8
+        assert_eq!(upload("text/x-patch", b"--- a\n+++ b\n".to_vec()).reply(&routes).await.status(), 201);
+
+        let oversized = vec![b'a'; MAX_ATTACHMENT_SIZE as usize + 1];
+        assert_eq!(upload("text/plain", oversized).reply(&routes).await.status(), 413);
+        assert_eq!(store.documents().len(), 1);
+    }
+
+    #[test]
+    fn test_references_must_exist() {
+        let (store, _) = store();
+        let uploaded = store.upload("design.md", "notes.txt", "text/plain", b"hello", Utc::now()).unwrap();
+
+        // Sizes and types come from the server's record, not the client
+        let claimed = AttachmentRef { name: "renamed.txt".to_string(), size: 1, mime: "image/png".to_string(), ..uploaded.clone() };
+        let resolved = store.resolve(&[claimed]).unwrap();
+        assert_eq!(resolved, vec![AttachmentRef { name: "renamed.txt".to_string(), ..uploaded.clone() }]);
+
+        let unknown = AttachmentRef { hash: sha256_hex(b"other"), ..uploaded };
+        assert!(store.resolve(&[unknown]).is_err());
+    }
+
+    #[test]
+    fn test_garbage_collects_orphans_only() {
+        let (store, storage) = store();
+        let start = Utc::now();
+        let kept = store.upload("design.md", "a.txt", "text/plain", b"referenced", start).unwrap();
+        let orphan = store.upload("design.md", "b.txt", "text/plain", b"orphaned", start).unwrap();
+        let fresh = store.upload("design.md", "c.txt", "text/plain", b"fresh", start + Duration::hours(23)).unwrap();
+
+        let referenced = HashSet::from([kept.hash.clone()]);
+        assert!(store.collect_garbage(&referenced, start + Duration::hours(23)).is_empty());
+        assert_eq!(store.collect_garbage(&referenced, start + Duration::hours(25)), vec![orphan.hash.clone()]);
+
+        assert!(store.get(&kept.hash).is_some());
+        assert!(store.get(&fresh.hash).is_some());
+        assert!(store.get(&orphan.hash).is_none());
+        assert_eq!(storage.files.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_image_dimensions() {
+        assert_eq!(image_dimensions("image/png", &png(1920, 1080)), Some((1920, 1080)));
+        assert_eq!(image_dimensions("image/gif", b"GIF89a\x40\x01\xf0\x00"), Some((320, 240)));
+
+        // SOI, an APP0 segment, then a baseline frame header of 800x600
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00];
+        jpeg.extend([0xFF, 0xC0, 0x00, 0x11, 0x08, 0x02, 0x58, 0x03, 0x20]);
+        assert_eq!(image_dimensions("image/jpeg", &jpeg), Some((800, 600)));
+        assert_eq!(image_dimensions("image/png", b"\x89PNG"), None); // Truncated
+    }
+}
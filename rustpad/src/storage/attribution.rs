@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+
+/// A run of consecutive lines last written by one author (0-based, end exclusive)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttributionSpan {
+    pub author: String,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+/// The lines an edit replaced in the old text and wrote in the new text (0-based, end exclusive)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineEdit {
+    pub old_start: usize,
+    pub old_end: usize,
+    pub new_end: usize, // The new lines start at `old_start` as well
+}
+
+/// Line-level blame for one document: who last wrote each line
+pub struct AttributionMap {
+    lines: Vec<Option<String>>, // Author per line, `None` for text that predates tracking
+}
+
+impl AttributionMap {
+    /// Creates a map for an empty document
+    pub fn new() -> Self {
+        Self { lines: vec![None] }
+    }
+
+    /// Creates a map for existing text whose authors are unknown
+    pub fn for_text(text: &str) -> Self {
+        Self { lines: vec![None; text.split('\n').count()] }
+    }
+
+    /// Author of `line`, if known
+    pub fn author_of(&self, line: usize) -> Option<&str> {
+        self.lines.get(line).and_then(|author| author.as_deref())
+    }
+
+    /// Blame spans covering every attributed line
+    pub fn spans(&self) -> Vec<AttributionSpan> {
+        self.spans_in(0, self.lines.len())
+    }
+
+    /// Blame spans clipped to lines `start..end`
+    pub fn spans_in(&self, start: usize, end: usize) -> Vec<AttributionSpan> {
+        let end = end.min(self.lines.len());
+        let mut spans: Vec<AttributionSpan> = Vec::new();
+        for line in start..end {
+            let Some(author) = &self.lines[line] else { continue };
+            match spans.last_mut() {
+                Some(span) if span.author == *author && span.end_line == line => span.end_line = line + 1,
+                _ => spans.push(AttributionSpan { author: author.clone(), start_line: line, end_line: line + 1 }),
+            }
+        }
+        spans
+    }
+
+    /// Spans that an edit from `old` to `new` touches. Lines inserted
+    /// between two lines of the same author touch that author's span.
+    pub fn touched_by(&self, old: &str, new: &str) -> Vec<AttributionSpan> {
+        let Some(edit) = line_edit(old, new) else { return Vec::new() };
+        if edit.old_start < edit.old_end {
+            return self.spans_in(edit.old_start, edit.old_end);
+        }
+
+        // Pure insertion: only counts when it lands inside a span
+        match (edit.old_start.checked_sub(1).and_then(|line| self.author_of(line)), self.author_of(edit.old_start)) {
+            (Some(before), Some(after)) if before == after => self.spans_in(edit.old_start - 1, edit.old_start + 1),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Attributes the lines an edit from `old` to `new` wrote to `author`
+    pub fn apply_edit(&mut self, author: &str, old: &str, new: &str) {
+        if self.lines.len() != old.split('\n').count() {
+            *self = Self::for_text(old); // Out of sync; start over rather than misattribute
+        }
+        if let Some(edit) = line_edit(old, new) {
+            let written = vec![Some(author.to_string()); edit.new_end - edit.old_start];
+            self.lines.splice(edit.old_start..edit.old_end, written);
+        }
+    }
+}
+
+impl Default for AttributionMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lines that differ between `old` and `new`, after trimming the lines they share at both ends
+pub fn line_edit(old: &str, new: &str) -> Option<LineEdit> {
+    if old == new {
+        return None;
+    }
+    let old_lines: Vec<&str> = old.split('\n').collect();
+    let new_lines: Vec<&str> = new.split('\n').collect();
+
+    let prefix = old_lines.iter().zip(&new_lines).take_while(|(a, b)| a == b).count();
+    let max_suffix = old_lines.len().min(new_lines.len()) - prefix;
+    let suffix = old_lines
+        .iter()
+        .rev()
+        .zip(new_lines.iter().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    Some(LineEdit {
+        old_start: prefix,
+        old_end: old_lines.len() - suffix,
+        new_end: new_lines.len() - suffix,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlap_with_attribution_spans() {
+        let mut map = AttributionMap::new();
+        map.apply_edit("alice", "", "fn a() {\n    1\n}");
+        map.apply_edit("bob", "fn a() {\n    1\n}", "fn a() {\n    1\n}\nfn b() {}");
+        assert_eq!(
+            map.spans(),
+            vec![
+                AttributionSpan { author: "alice".to_string(), start_line: 0, end_line: 3 },
+                AttributionSpan { author: "bob".to_string(), start_line: 3, end_line: 4 },
+            ]
+        );
+
+        // Changing a line of alice's function touches only her span
+        let old = "fn a() {\n    1\n}\nfn b() {}";
+        let touched = map.touched_by(old, "fn a() {\n    2\n}\nfn b() {}");
+        assert_eq!(touched, vec![AttributionSpan { author: "alice".to_string(), start_line: 1, end_line: 2 }]);
+
+        // Inserting a line inside her function touches it, appending after bob's line does not
+        assert_eq!(map.touched_by(old, "fn a() {\n    0\n    1\n}\nfn b() {}").len(), 1);
+        assert!(map.touched_by(old, "fn a() {\n    1\n}\nfn b() {}\n").is_empty());
+
+        map.apply_edit("carol", old, "fn a() {\n    2\n}\nfn b() {}");
+        assert_eq!(map.author_of(1), Some("carol"));
+        assert_eq!(map.author_of(2), Some("alice"));
+    }
+}
@@ -0,0 +1,780 @@
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Reply};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::auth::auth::{recover_signed_out, signed_in_user};
+use crate::networking::chat_sync::{Annotation, ChatMessage, ChatSyncManager};
+use crate::networking::room_host::RoomHost;
+use crate::storage::attachments::{AttachmentRef, AttachmentStore, MAX_ATTACHMENT_SIZE};
+use crate::storage::history::{FileVersion, HistoryManager};
+use crate::storage::quota::QuotaExceeded;
+use crate::storage::workspace::{WorkspaceRole, Workspaces};
+use crate::storage::Storage;
+
+/// Format version written by `export`
+pub const BUNDLE_FORMAT_VERSION: u32 = 1;
+
+/// Largest bundle accepted for import, in bytes as uploaded
+pub const MAX_BUNDLE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Largest single entry read from a bundle, after decompression
+const MAX_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Bytes per chunk when streaming bundles to and from disk
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const MANIFEST: &str = "manifest.json";
+const CONTENT: &str = "content.txt";
+const HISTORY: &str = "history.json";
+const CHAT: &str = "chat.jsonl";
+const ANNOTATIONS: &str = "annotations.json";
+const ATTACHMENTS: &str = "attachments.json";
+const ATTACHMENT_DIR: &str = "attachments/";
+
+/// The document a bundle was exported from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BundleDocument {
+    pub id: String,
+    pub exported_by: String,
+    pub exported_at: DateTime<Utc>,
+    #[serde(default)]
+    pub language: Option<String>, // The room's language; `None` for plain text
+}
+
+/// `manifest.json` of a `.rustpad` bundle
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BundleManifest {
+    pub format_version: u32,
+    pub document: BundleDocument,
+    pub checksums: BTreeMap<String, String>, // SHA-256 of every other entry, hex, by path
+}
+
+/// What an import created, and what it had to leave out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImportReport {
+    pub doc_id: String,
+    pub skipped: Vec<String>, // One line per piece left out, with the reason
+}
+
+/// Exports documents with everything attached to them as `.rustpad` bundles, and imports them
+/// as new documents: a zip of the manifest, the content, the version history, the chat as
+/// JSONL, the annotations and the attachments.
+#[derive(Clone)]
+pub struct Bundles {
+    documents: Arc<dyn Storage + Send + Sync>, // Document content, by doc id
+    history: Arc<Mutex<HistoryManager>>,       // Versions, keyed by doc id
+    chat: ChatSyncManager,                     // Chat and annotations, with the doc id as room
+    attachments: AttachmentStore,
+    workspaces: Workspaces, // Who owns what; imports join a workspace
+    max_attachment_size: u64, // Larger attachments are skipped on import
+    rooms: Option<RoomHost>,  // Where documents' languages are kept
+}
+
+impl Bundles {
+    /// Creates bundles of the documents in `documents` and what the other stores hold for them
+    pub fn new(
+        documents: Arc<dyn Storage + Send + Sync>,
+        history: Arc<Mutex<HistoryManager>>,
+        chat: ChatSyncManager,
+        attachments: AttachmentStore,
+        workspaces: Workspaces,
+    ) -> Self {
+        Self { documents, history, chat, attachments, workspaces, max_attachment_size: MAX_ATTACHMENT_SIZE, rooms: None }
+    }
+
+    /// Exports the language of each document's room, and restores it on import
+    pub fn with_rooms(self, rooms: RoomHost) -> Self {
+        Self { rooms: Some(rooms), ..self }
+    }
+
+    /// Skips imported attachments over `max_attachment_size` bytes instead of `MAX_ATTACHMENT_SIZE`
+    pub fn with_max_attachment_size(self, max_attachment_size: u64) -> Self {
+        Self { max_attachment_size, ..self }
+    }
+
+    /// Whether `user` may export `doc_id`; only its owner may
+    pub fn can_export(&self, doc_id: &str, user: &str) -> bool {
+        self.workspaces.doc_role(doc_id, user) == Some(WorkspaceRole::Owner)
+    }
+
+    /// Writes the bundle of `doc_id` to `out`. Ownership is checked by the caller.
+    pub fn export<W: Write + Seek>(&self, doc_id: &str, user: &str, out: W) -> Result<(), String> {
+        let content = self.documents.load(doc_id).map_err(|e| format!("Failed to load {}: {}", doc_id, e))?;
+        let versions = {
+            let mut history = self.history.lock().unwrap();
+            history.load_history(doc_id).map_err(|e| format!("Failed to load the history of {}: {}", doc_id, e))?;
+            history.list_versions(doc_id)
+        };
+        let messages = self.chat.room_history(doc_id);
+        let mut annotations: Vec<(usize, Vec<Annotation>)> = self.chat.room_annotations(doc_id).into_iter().collect();
+        annotations.sort_by_key(|(line, _)| *line);
+        let annotations: Vec<Annotation> = annotations.into_iter().flat_map(|(_, line)| line).collect();
+
+        // Every attachment referenced from the chat or the annotations, once
+        let mut seen = HashSet::new();
+        let attachments: Vec<(AttachmentRef, Vec<u8>)> = messages
+            .iter()
+            .flat_map(|message| &message.attachments)
+            .chain(annotations.iter().flat_map(|annotation| &annotation.attachments))
+            .filter(|attachment| seen.insert(attachment.hash.clone()))
+            .filter_map(|attachment| self.attachments.get(&attachment.hash))
+            .collect();
+
+        let mut writer = BundleWriter::new(out);
+        writer.add(CONTENT, content.as_bytes())?;
+        writer.add(HISTORY, &to_json(&versions)?)?;
+        let mut chat = Vec::new();
+        for message in &messages {
+            chat.extend(to_json(message)?);
+            chat.push(b'\n');
+        }
+        writer.add(CHAT, &chat)?;
+        writer.add(ANNOTATIONS, &to_json(&annotations)?)?;
+        let references: Vec<&AttachmentRef> = attachments.iter().map(|(attachment, _)| attachment).collect();
+        writer.add(ATTACHMENTS, &to_json(&references)?)?;
+        for (attachment, content) in &attachments {
+            writer.add(&format!("{}{}", ATTACHMENT_DIR, attachment.hash), content)?;
+        }
+
+        let language = self.rooms.as_ref().and_then(|rooms| rooms.language(doc_id));
+        let document = BundleDocument { id: doc_id.to_string(), exported_by: user.to_string(), exported_at: Utc::now(), language };
+        writer.finish(document)
+    }
+
+    /// Refuses importing `bundle` into `workspace` if the new document, with its content and
+    /// the attachments that would be restored, would take the workspace's owner over their
+    /// quota. Bundles that can't be read pass, for `import` to report.
+    pub fn check_quota<R: Read + Seek>(&self, bundle: R, workspace: &str) -> Result<(), QuotaExceeded> {
+        let (Some(quotas), Some(workspace)) = (self.workspaces.quotas(), self.workspaces.get(workspace)) else { return Ok(()) };
+        let Ok(mut archive) = ZipArchive::new(bundle) else { return Ok(()) };
+        quotas.check_document(&workspace.owner)?;
+        quotas.check_bytes(&workspace.owner, self.import_bytes(&mut archive))
+    }
+
+    /// The bytes an import of `archive` would store: the content, and the attachments under
+    /// `max_attachment_size`
+    fn import_bytes<R: Read + Seek>(&self, archive: &mut ZipArchive<R>) -> u64 {
+        (0..archive.len())
+            .filter_map(|index| {
+                let entry = archive.by_index(index).ok()?;
+                let restored = entry.name() == CONTENT || (entry.name().starts_with(ATTACHMENT_DIR) && entry.size() <= self.max_attachment_size);
+                restored.then(|| entry.size())
+            })
+            .sum()
+    }
+
+    /// Restores a bundle as a new document in `workspace`, which `user` must be able to add
+    /// documents to, within the quota of its owner. The manifest version and every checksum
+    /// are checked before anything is restored; attachments this server can't take are skipped
+    /// and reported instead.
+    pub fn import<R: Read + Seek>(&self, bundle: R, user: &str, workspace: &str) -> Result<ImportReport, String> {
+        let mut archive = ZipArchive::new(bundle).map_err(|e| format!("Not a RustPad bundle: {}", e))?;
+        let manifest = read_manifest(&mut archive)?;
+        verify_checksums(&mut archive, &manifest)?;
+        if let (Some(quotas), Some(workspace)) = (self.workspaces.quotas(), self.workspaces.get(workspace)) {
+            quotas.check_bytes(&workspace.owner, self.import_bytes(&mut archive)).map_err(|e| e.to_string())?;
+        }
+
+        let content = String::from_utf8(read_entry(&mut archive, CONTENT)?).map_err(|e| format!("Corrupt {}: {}", CONTENT, e))?;
+        let versions: Vec<FileVersion> = from_json(HISTORY, &read_entry(&mut archive, HISTORY)?)?;
+        let mut messages: Vec<ChatMessage> = Vec::new();
+        for line in BufReader::new(read_entry(&mut archive, CHAT)?.as_slice()).lines() {
+            let line = line.map_err(|e| format!("Corrupt {}: {}", CHAT, e))?;
+            if !line.trim().is_empty() {
+                messages.push(from_json(CHAT, line.as_bytes())?);
+            }
+        }
+        let annotations: Vec<Annotation> = from_json(ANNOTATIONS, &read_entry(&mut archive, ANNOTATIONS)?)?;
+        let references: Vec<AttachmentRef> = from_json(ATTACHMENTS, &read_entry(&mut archive, ATTACHMENTS)?)?;
+
+        let doc_id = Uuid::new_v4().to_string();
+        self.workspaces.add_doc(workspace, user, &doc_id)?;
+        let mut skipped = Vec::new();
+
+        // Attachments first, so references to the ones left out can be dropped
+        let now = Utc::now();
+        let mut restored = HashSet::new();
+        for reference in references {
+            let path = format!("{}{}", ATTACHMENT_DIR, reference.hash);
+            let size = archive.by_name(&path).map(|entry| entry.size()).map_err(|e| format!("Missing {}: {}", path, e))?;
+            if size > self.max_attachment_size {
+                skipped.push(format!("Attachment {} ({} bytes) is over the {}-byte limit", reference.name, size, self.max_attachment_size));
+                continue;
+            }
+            let content = read_entry(&mut archive, &path)?;
+            match self.attachments.upload(&doc_id, &reference.name, &reference.mime, &content, now) {
+                Ok(attachment) => {
+                    restored.insert(attachment.hash);
+                }
+                Err(e) => skipped.push(format!("Attachment {}: {}", reference.name, e)),
+            }
+        }
+        let keep_restored = |attachments: &mut Vec<AttachmentRef>| attachments.retain(|attachment| restored.contains(&attachment.hash));
+        for message in &mut messages {
+            keep_restored(&mut message.attachments);
+        }
+        let mut by_line: HashMap<usize, Vec<Annotation>> = HashMap::new();
+        for mut annotation in annotations {
+            keep_restored(&mut annotation.attachments);
+            by_line.entry(annotation.line_number).or_default().push(annotation);
+        }
+
+        self.documents.save(&doc_id, &content).map_err(|e| format!("Failed to save {}: {}", doc_id, e))?;
+        // The document and its attachments were counted as they were added; its content wasn't there yet
+        if let (Some(quotas), Some(owner)) = (self.workspaces.quotas(), self.workspaces.owner_of(&doc_id)) {
+            quotas.record(&owner, 0, content.len() as i64);
+        }
+        self.history
+            .lock()
+            .unwrap()
+            .restore_versions(&doc_id, versions)
+            .map_err(|e| format!("Failed to restore the history: {}", e))?;
+        self.chat.restore_room(&doc_id, messages, by_line)?;
+        if let (Some(rooms), Some(language)) = (&self.rooms, &manifest.document.language) {
+            if let Err(e) = rooms.restore_language(&doc_id, language) {
+                skipped.push(format!("Language {}: {}", language, e));
+            }
+        }
+        Ok(ImportReport { doc_id, skipped })
+    }
+}
+
+/// Writes bundle entries, keeping the checksums for the manifest written last
+struct BundleWriter<W: Write + Seek> {
+    zip: ZipWriter<W>,
+    checksums: BTreeMap<String, String>,
+}
+
+impl<W: Write + Seek> BundleWriter<W> {
+    fn new(out: W) -> Self {
+        Self { zip: ZipWriter::new(out), checksums: BTreeMap::new() }
+    }
+
+    fn add(&mut self, path: &str, content: &[u8]) -> Result<(), String> {
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        self.zip.start_file(path, options).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        self.zip.write_all(content).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        self.checksums.insert(path.to_string(), hex(digest::digest(&digest::SHA256, content).as_ref()));
+        Ok(())
+    }
+
+    fn finish(mut self, document: BundleDocument) -> Result<(), String> {
+        let manifest = BundleManifest { format_version: BUNDLE_FORMAT_VERSION, document, checksums: std::mem::take(&mut self.checksums) };
+        let manifest = to_json(&manifest)?;
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        self.zip.start_file(MANIFEST, options).map_err(|e| format!("Failed to write {}: {}", MANIFEST, e))?;
+        self.zip.write_all(&manifest).map_err(|e| format!("Failed to write {}: {}", MANIFEST, e))?;
+        self.zip.finish().map(|_| ()).map_err(|e| format!("Failed to write the bundle: {}", e))
+    }
+}
+
+/// Reads the manifest, refusing bundles written by a newer version of RustPad rather than
+/// guessing at them
+fn read_manifest<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<BundleManifest, String> {
+    let manifest: serde_json::Value = from_json(MANIFEST, &read_entry(archive, MANIFEST)?)?;
+    let version = manifest.get("format_version").and_then(|version| version.as_u64()).ok_or("The manifest has no format version")?;
+    if version > BUNDLE_FORMAT_VERSION as u64 {
+        return Err(format!(
+            "This bundle was exported by a newer version of RustPad (format {}, this version reads up to {}); update to import it",
+            version, BUNDLE_FORMAT_VERSION
+        ));
+    }
+    serde_json::from_value(manifest).map_err(|e| format!("Corrupt {}: {}", MANIFEST, e))
+}
+
+/// Checks that the archive holds exactly the entries the manifest lists, with their checksums
+fn verify_checksums<R: Read + Seek>(archive: &mut ZipArchive<R>, manifest: &BundleManifest) -> Result<(), String> {
+    let mut verified = 0;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(|e| format!("Corrupt bundle: {}", e))?;
+        if entry.name() == MANIFEST {
+            continue;
+        }
+        let name = entry.name().to_string();
+        let expected = manifest.checksums.get(&name).ok_or_else(|| format!("{} is not listed in the manifest", name))?;
+
+        let mut context = digest::Context::new(&digest::SHA256);
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let read = entry.read(&mut buffer).map_err(|e| format!("Corrupt {}: {}", name, e))?;
+            if read == 0 {
+                break;
+            }
+            context.update(&buffer[..read]);
+        }
+        if &hex(context.finish().as_ref()) != expected {
+            return Err(format!("Checksum mismatch for {}", name));
+        }
+        verified += 1;
+    }
+    if verified != manifest.checksums.len() {
+        return Err("The bundle is missing entries listed in its manifest".to_string());
+    }
+    Ok(())
+}
+
+/// Reads a whole entry, up to `MAX_ENTRY_SIZE`
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Vec<u8>, String> {
+    let entry = archive.by_name(name).map_err(|e| format!("Missing {}: {}", name, e))?;
+    if entry.size() > MAX_ENTRY_SIZE {
+        return Err(format!("{} is larger than {} bytes", name, MAX_ENTRY_SIZE));
+    }
+    let mut content = Vec::with_capacity(entry.size() as usize);
+    entry.take(MAX_ENTRY_SIZE).read_to_end(&mut content).map_err(|e| format!("Corrupt {}: {}", name, e))?;
+    Ok(content)
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, String> {
+    serde_json::to_vec(value).map_err(|e| format!("Failed to serialize the bundle: {}", e))
+}
+
+fn from_json<T: for<'de> Deserialize<'de>>(name: &str, content: &[u8]) -> Result<T, String> {
+    serde_json::from_slice(content).map_err(|e| format!("Corrupt {}: {}", name, e))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A temporary file for a bundle on its way in or out
+fn temp_bundle_path() -> PathBuf {
+    std::env::temp_dir().join(format!("rustpad-bundle-{}.rustpad", Uuid::new_v4()))
+}
+
+/// Streams the file at `path` in `CHUNK_SIZE` chunks, deleting it once it is open; on Unix
+/// the open file stays readable
+async fn stream_file(path: &Path) -> io::Result<impl Stream<Item = io::Result<Bytes>>> {
+    let file = tokio::fs::File::open(path).await?;
+    let _ = fs::remove_file(path);
+    Ok(futures_util::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buffer = vec![0; CHUNK_SIZE];
+        match file.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(read) => {
+                buffer.truncate(read);
+                Some((Ok(Bytes::from(buffer)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    }))
+}
+
+fn error_reply(status: StatusCode, message: &str) -> warp::reply::Response {
+    let error = warp::reply::json(&serde_json::json!({ "type": "error", "message": message }));
+    warp::reply::with_status(error, status).into_response()
+}
+
+/// Handler for `GET /api/docs/:id/bundle`, streaming the bundle from a temporary file to the
+/// signed-in owner
+pub async fn export_handler(doc_id: String, user: String, bundles: Bundles) -> Result<warp::reply::Response, warp::Rejection> {
+    if !bundles.can_export(&doc_id, &user) {
+        return Ok(error_reply(StatusCode::FORBIDDEN, "Only the document's owner can export it"));
+    }
+
+    let path = temp_bundle_path();
+    let (export_path, export_doc) = (path.clone(), doc_id.clone());
+    let exported = tokio::task::spawn_blocking(move || {
+        let file = fs::File::create(&export_path).map_err(|e| format!("Failed to create the bundle: {}", e))?;
+        bundles.export(&export_doc, &user, file)
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    if let Err(e) = exported {
+        let _ = fs::remove_file(&path);
+        return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, &e));
+    }
+
+    match stream_file(&path).await {
+        Ok(stream) => {
+            let body = warp::hyper::Body::wrap_stream(stream);
+            let disposition = format!("attachment; filename=\"{}.rustpad\"", doc_id.replace('"', ""));
+            let response = warp::reply::with_header(warp::reply::Response::new(body), "content-type", "application/zip");
+            Ok(warp::reply::with_header(response, "content-disposition", disposition).into_response())
+        }
+        Err(e) => Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to read the bundle: {}", e))),
+    }
+}
+
+/// Handler for `POST /api/docs/import-bundle?workspace=<id>`, with the bundle as the body,
+/// imported for the signed-in user. The upload is spooled to a temporary file rather than held
+/// in memory.
+pub async fn import_handler<S, B>(user: String, query: HashMap<String, String>, body: S, bundles: Bundles) -> Result<warp::reply::Response, warp::Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Unpin,
+    B: warp::Buf,
+{
+    let workspace = query.get("workspace").cloned().unwrap_or_default();
+
+    let path = temp_bundle_path();
+    let spooled = spool(body, &path).await;
+    let result = match spooled {
+        Ok(()) => {
+            let import_path = path.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut file = fs::File::open(&import_path).map_err(|e| format!("Failed to read the bundle: {}", e))?;
+                if let Err(exceeded) = bundles.check_quota(&mut file, &workspace) {
+                    return Ok(Err(exceeded));
+                }
+                file.rewind().map_err(|e| format!("Failed to read the bundle: {}", e))?;
+                bundles.import(file, &user, &workspace).map(Ok)
+            })
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()))
+        }
+        Err(e) => Err(e),
+    };
+    let _ = fs::remove_file(&path);
+
+    match result {
+        Ok(Err(exceeded)) => Ok(exceeded.reply()),
+        Ok(Ok(report)) => Ok(warp::reply::with_status(warp::reply::json(&report), StatusCode::CREATED).into_response()),
+        Err(e) if e.starts_with("Bundles are limited") => Ok(error_reply(StatusCode::PAYLOAD_TOO_LARGE, &e)),
+        Err(e) => Ok(error_reply(StatusCode::BAD_REQUEST, &e)),
+    }
+}
+
+/// Writes an uploaded body to `path` chunk by chunk, up to `MAX_BUNDLE_SIZE`
+async fn spool<S, B>(mut body: S, path: &Path) -> Result<(), String>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Unpin,
+    B: warp::Buf,
+{
+    use tokio::io::AsyncWriteExt;
+    let mut file = tokio::fs::File::create(path).await.map_err(|e| format!("Failed to store the bundle: {}", e))?;
+    let mut size = 0u64;
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|e| format!("Failed to receive the bundle: {}", e))?;
+        size += chunk.remaining() as u64;
+        if size > MAX_BUNDLE_SIZE {
+            return Err(format!("Bundles are limited to {} bytes", MAX_BUNDLE_SIZE));
+        }
+        while chunk.has_remaining() {
+            let written = file.write(chunk.chunk()).await.map_err(|e| format!("Failed to store the bundle: {}", e))?;
+            chunk.advance(written);
+        }
+    }
+    file.flush().await.map_err(|e| format!("Failed to store the bundle: {}", e))
+}
+
+/// Routes for `GET /api/docs/:id/bundle` and `POST /api/docs/import-bundle`
+pub fn bundle_routes(bundles: Bundles) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let import_bundles = bundles.clone();
+    let export = warp::path!("api" / "docs" / String / "bundle")
+        .and(warp::get())
+        .and(signed_in_user())
+        .and(warp::any().map(move || bundles.clone()))
+        .and_then(export_handler);
+    let import = warp::path!("api" / "docs" / "import-bundle")
+        .and(warp::post())
+        .and(signed_in_user())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::stream())
+        .and(warp::any().map(move || import_bundles.clone()))
+        .and_then(|user, query, body, bundles| import_handler(user, query, Box::pin(body), bundles));
+    import.or(export).unify().recover(recover_signed_out).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth::bearer_for;
+    use crate::storage::MemoryStorage;
+    use crate::networking::room_host::MemoryLimits;
+    use crate::storage::quota::{QuotaLimits, QuotaManager, QuotaResource, Usage};
+    use std::io::Cursor;
+    use std::time::Instant;
+
+    /// A server with one document, "pad.md", owned by olga through her workspace
+    struct Server {
+        bundles: Bundles,
+        history: Arc<Mutex<HistoryManager>>,
+        chat: ChatSyncManager,
+        attachments: AttachmentStore,
+        workspace: String,
+        history_dir: PathBuf,
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.history_dir);
+        }
+    }
+
+    fn server(name: &str, content: &str) -> Server {
+        let documents = Arc::new(MemoryStorage::default());
+        documents.save("pad.md", content).unwrap();
+        let history_dir = std::env::temp_dir().join(format!("rustpad-bundle-{}-{}", name, std::process::id()));
+        let history = Arc::new(Mutex::new(HistoryManager::new(&history_dir.to_string_lossy(), 3)));
+        let chat = ChatSyncManager::new();
+        let attachments = AttachmentStore::new(Arc::new(MemoryStorage::default()));
+        let workspaces = Workspaces::new();
+        let workspace = workspaces.create("olga", "Team").unwrap().id;
+        workspaces.add_doc(&workspace, "olga", "pad.md").unwrap();
+        let bundles = Bundles::new(documents, history.clone(), chat.clone(), attachments.clone(), workspaces);
+        Server { bundles, history, chat, attachments, workspace, history_dir }
+    }
+
+    /// A document with trimmed history, chat with an attachment and annotations on two lines
+    fn populated(name: &str) -> Server {
+        let server = server(name, "line one\nline two\nline three");
+        {
+            let mut history = server.history.lock().unwrap();
+            for (content, description) in [("a", "first"), ("ab", "second"), ("abc", "third"), ("abcd", "fourth")] {
+                history.add_version("pad.md", content, description).unwrap();
+            }
+        }
+        let notes = server.attachments.upload("pad.md", "notes.txt", "text/plain", b"see line two", Utc::now()).unwrap();
+        let messages: Vec<ChatMessage> = serde_json::from_value(serde_json::json!([
+            { "id": 1, "user": "olga", "message": "hello", "timestamp": "2024-01-01T09:00:00Z" },
+            { "id": 2, "user": "ed", "message": "notes attached", "timestamp": "2024-01-01T09:01:00Z", "attachments": [notes] },
+        ]))
+        .unwrap();
+        let annotations: Vec<Annotation> = serde_json::from_value(serde_json::json!([
+            { "user": "ed", "content": "typo", "line_number": 2, "timestamp": "t1" },
+            { "user": "olga", "content": "fixed", "line_number": 2, "timestamp": "t2" },
+            { "user": "olga", "content": "why?", "line_number": 3, "timestamp": "t3", "attachments": [notes] },
+        ]))
+        .unwrap();
+        let mut by_line: HashMap<usize, Vec<Annotation>> = HashMap::new();
+        for annotation in annotations {
+            by_line.entry(annotation.line_number).or_default().push(annotation);
+        }
+        server.chat.restore_room("pad.md", messages, by_line).unwrap();
+        server
+    }
+
+    /// Chat messages and annotations don't implement `PartialEq`, so they are compared as JSON
+    fn json<T: Serialize>(value: &T) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    fn export(server: &Server) -> Vec<u8> {
+        let mut bundle = Cursor::new(Vec::new());
+        server.bundles.export("pad.md", "olga", &mut bundle).unwrap();
+        bundle.into_inner()
+    }
+
+    /// Copies `bundle`, passing each entry through `change`; entries it returns `None` for are dropped
+    fn rewrite(bundle: &[u8], change: impl Fn(&str, Vec<u8>) -> Option<Vec<u8>>) -> Vec<u8> {
+        let mut archive = ZipArchive::new(Cursor::new(bundle)).unwrap();
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).unwrap();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            if let Some(content) = change(entry.name(), content) {
+                zip.start_file(entry.name(), FileOptions::default()).unwrap();
+                zip.write_all(&content).unwrap();
+            }
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_round_trip_preserves_every_component() {
+        let server = populated("round-trip");
+        let bundle = export(&server);
+        let report = server.bundles.import(Cursor::new(bundle), "olga", &server.workspace).unwrap();
+        assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+        let copy = report.doc_id.as_str();
+        assert_ne!(copy, "pad.md");
+
+        assert_eq!(server.bundles.documents.load(copy).unwrap(), "line one\nline two\nline three");
+        let history = server.history.lock().unwrap();
+        let versions = history.list_versions(copy);
+        assert_eq!(versions, history.list_versions("pad.md"));
+        assert_eq!(versions.iter().map(|version| version.version_id).collect::<Vec<_>>(), vec![2, 3, 4]);
+        drop(history);
+        assert_eq!(server.history.lock().unwrap().add_version(copy, "abcde", "fifth").unwrap(), 5);
+
+        let original = server.chat.room_history("pad.md");
+        let imported = server.chat.room_history(copy);
+        assert!(imported.iter().all(|message| message.room == copy));
+        let moved = |message: &ChatMessage| json(&ChatMessage { room: copy.to_string(), ..message.clone() });
+        assert_eq!(imported.iter().map(json).collect::<Vec<_>>(), original.iter().map(moved).collect::<Vec<_>>());
+
+        let anchors = |room: &str| {
+            let mut lines: Vec<(usize, Vec<serde_json::Value>)> =
+                server.chat.room_annotations(room).into_iter().map(|(line, annotations)| (line, annotations.iter().map(json).collect())).collect();
+            lines.sort_by_key(|(line, _)| *line);
+            lines
+        };
+        assert_eq!(anchors(copy), anchors("pad.md"));
+        assert_eq!(anchors(copy)[0].1.len(), 2);
+
+        let hash = &original[1].attachments[0].hash;
+        assert_eq!(server.attachments.get(hash).unwrap().1, b"see line two");
+        assert_eq!(server.attachments.documents().get(hash).map(String::as_str), Some(copy));
+
+        // The copy belongs to the workspace, so its owner can export it in turn
+        assert!(server.bundles.can_export(copy, "olga"));
+        assert!(!server.bundles.can_export(copy, "ed"));
+    }
+
+    #[test]
+    fn test_tampered_bundles_are_rejected() {
+        let server = populated("tampered");
+        let bundle = export(&server);
+
+        let edited = rewrite(&bundle, |name, content| Some(if name == CONTENT { b"line one\nEVIL\nline three".to_vec() } else { content }));
+        let error = server.bundles.import(Cursor::new(edited), "olga", &server.workspace).unwrap_err();
+        assert_eq!(error, "Checksum mismatch for content.txt");
+
+        let missing = rewrite(&bundle, |name, content| if name == HISTORY { None } else { Some(content) });
+        assert!(server.bundles.import(Cursor::new(missing), "olga", &server.workspace).is_err());
+        let smuggled = rewrite(&bundle, |name, content| Some(if name == CHAT { [content, b"extra\n".to_vec()].concat() } else { content }));
+        assert!(server.bundles.import(Cursor::new(smuggled), "olga", &server.workspace).is_err());
+        assert!(server.bundles.import(Cursor::new(b"not a zip".to_vec()), "olga", &server.workspace).is_err());
+
+        // Nothing was created along the way
+        assert_eq!(server.bundles.workspaces.get(&server.workspace).unwrap().docs, vec!["pad.md".to_string()]);
+    }
+
+    #[test]
+    fn test_oversized_attachments_are_skipped_and_reported() {
+        let source = populated("partial-source");
+        let target = server("partial-target", "");
+        let target_bundles = target.bundles.clone().with_max_attachment_size(4);
+
+        let report = target_bundles.import(Cursor::new(export(&source)), "olga", &target.workspace).unwrap();
+        assert_eq!(report.skipped, vec!["Attachment notes.txt (12 bytes) is over the 4-byte limit".to_string()]);
+        let messages = target.chat.room_history(&report.doc_id);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].attachments.is_empty());
+        assert!(target.chat.room_annotations(&report.doc_id)[&3][0].attachments.is_empty());
+        assert!(target.attachments.documents().is_empty());
+        assert_eq!(target.history.lock().unwrap().list_versions(&report.doc_id).len(), 3);
+
+        // Importing needs a workspace the user may add documents to
+        assert!(target.bundles.import(Cursor::new(export(&source)), "stranger", &target.workspace).is_err());
+    }
+
+    #[test]
+    fn test_newer_format_is_refused() {
+        let server = populated("newer");
+        let newer = rewrite(&export(&server), |name, content| {
+            if name != MANIFEST {
+                return Some(content);
+            }
+            let mut manifest: serde_json::Value = serde_json::from_slice(&content).unwrap();
+            manifest["format_version"] = serde_json::json!(BUNDLE_FORMAT_VERSION + 1);
+            manifest["layout"] = serde_json::json!("something new");
+            Some(serde_json::to_vec(&manifest).unwrap())
+        });
+        let error = server.bundles.import(Cursor::new(newer), "olga", &server.workspace).unwrap_err();
+        assert!(error.contains("newer version of RustPad"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_import_needs_room_in_the_owners_quota() {
+        let source = populated("quota-source");
+        let target = server("quota-target", "");
+        let with_limits = |limits: QuotaLimits| {
+            let workspaces = target.bundles.workspaces.clone();
+            let quotas = QuotaManager::new(workspaces.clone(), target.bundles.documents.clone(), target.attachments.clone(), limits);
+            let attachments = target.attachments.clone().with_quotas(quotas.clone());
+            (Bundles { workspaces: workspaces.with_quotas(quotas.clone()), attachments, ..target.bundles.clone() }, quotas)
+        };
+
+        // 28 bytes of content and a 12-byte attachment don't fit in 30, which the route tells
+        let (bundles, _) = with_limits(QuotaLimits { max_storage_bytes: 30, ..QuotaLimits::new() });
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/api/docs/import-bundle?workspace={}", target.workspace))
+            .header("authorization", bearer_for("olga"))
+            .body(export(&source))
+            .reply(&bundle_routes(bundles.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let frame: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let exceeded = QuotaExceeded { resource: QuotaResource::StorageBytes, usage: 0, requested: 40, limit: 30 };
+        assert_eq!(frame, exceeded.frame());
+        assert_eq!(bundles.import(Cursor::new(export(&source)), "olga", &target.workspace).unwrap_err(), exceeded.to_string());
+
+        // Nor does a second document when olga may have one
+        let (bundles, _) = with_limits(QuotaLimits { max_documents: 1, ..QuotaLimits::new() });
+        assert!(bundles.check_quota(Cursor::new(export(&source)), &target.workspace).is_err());
+        assert!(bundles.import(Cursor::new(export(&source)), "olga", &target.workspace).is_err());
+        assert_eq!(target.bundles.workspaces.get(&target.workspace).unwrap().docs.len(), 1);
+
+        // With room, the import counts in full
+        let (bundles, quotas) = with_limits(QuotaLimits::new());
+        assert_eq!(quotas.usage("olga"), Usage { documents: 1, storage_bytes: 0 });
+        bundles.import(Cursor::new(export(&source)), "olga", &target.workspace).unwrap();
+        assert_eq!(quotas.usage("olga"), Usage { documents: 2, storage_bytes: 40 });
+        assert_eq!(quotas.usage("olga"), quotas.recount("olga"));
+    }
+
+    #[test]
+    fn test_export_carries_room_language() {
+        let server = server("language", "# Notes");
+        let rooms = RoomHost::new(Arc::new(MemoryStorage::default()), MemoryLimits::new());
+        let bundles = server.bundles.clone().with_rooms(rooms.clone());
+        rooms.join("pad.md", "olga1", Instant::now()).unwrap();
+        let olga = server.bundles.workspaces.open("pad.md", "olga", None).unwrap();
+        rooms.set_language("pad.md", &olga, "markdown", Instant::now()).unwrap();
+
+        let mut bundle = Cursor::new(Vec::new());
+        bundles.export("pad.md", "olga", &mut bundle).unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(bundle.get_ref().clone())).unwrap();
+        assert_eq!(read_manifest(&mut archive).unwrap().document.language.as_deref(), Some("markdown"));
+
+        // The copy gets the language too, though its id has no extension to go by
+        let report = bundles.import(Cursor::new(bundle.into_inner()), "olga", &server.workspace).unwrap();
+        assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+        assert_eq!(rooms.language(&report.doc_id).as_deref(), Some("markdown"));
+    }
+
+    #[tokio::test]
+    async fn test_large_bundle_streams_in_chunks() {
+        // Pseudo-random text, so compression can't shrink the bundle below a chunk
+        let mut seed = 42u32;
+        let content: String = (0..2 * 1024 * 1024)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (b'a' + (seed >> 16) as u8 % 26) as char
+            })
+            .collect();
+        let server = server("large", &content);
+
+        let path = temp_bundle_path();
+        server.bundles.export("pad.md", "olga", fs::File::create(&path).unwrap()).unwrap();
+        let chunks: Vec<Bytes> = stream_file(&path).await.unwrap().map(|chunk| chunk.unwrap()).collect().await;
+        assert!(chunks.len() > 16);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= CHUNK_SIZE));
+        assert!(!path.exists());
+
+        let routes = bundle_routes(server.bundles.clone());
+        let response = warp::test::request().path("/api/docs/pad.md/bundle?user=olga").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = warp::test::request().path("/api/docs/pad.md/bundle").header("authorization", bearer_for("ed")).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = warp::test::request().path("/api/docs/pad.md/bundle").header("authorization", bearer_for("olga")).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/api/docs/import-bundle?workspace={}", server.workspace))
+            .header("authorization", bearer_for("olga"))
+            .body(response.body().clone())
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let report: ImportReport = serde_json::from_slice(response.body()).unwrap();
+        assert!(server.bundles.documents.load(&report.doc_id).unwrap() == content);
+    }
+}
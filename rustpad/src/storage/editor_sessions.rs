@@ -0,0 +1,165 @@
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use crate::auth::auth::{recover_signed_out, signed_in_user};
+use crate::editor::session::SessionBlob;
+use crate::storage::Storage;
+
+/// Largest session accepted, in encoded bytes
+pub const MAX_SESSION_SIZE: usize = 2 * 1024 * 1024;
+
+/// Storage namespace sessions are saved under, followed by the document id and user
+const NAMESPACE: &str = "sessions/";
+
+/// Editor sessions saved for handing off between devices, one per user and document
+#[derive(Clone)]
+pub struct EditorSessions {
+    storage: Arc<dyn Storage + Send + Sync>,
+    max_size: usize, // Largest session accepted, in bytes
+}
+
+impl EditorSessions {
+    /// Creates a store saving sessions to `storage`
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self { storage, max_size: MAX_SESSION_SIZE }
+    }
+
+    /// Caps sessions at `max_size` bytes instead of `MAX_SESSION_SIZE`
+    pub fn with_max_size(self, max_size: usize) -> Self {
+        Self { max_size, ..self }
+    }
+
+    /// Saves `user`'s session for `doc_id`, replacing the previous one. The content must be a
+    /// session encoded by `SessionBlob::to_bytes`; its format is checked when it is restored.
+    pub fn save(&self, doc_id: &str, user: &str, content: &[u8]) -> Result<(), String> {
+        if content.len() > self.max_size {
+            return Err(format!("Sessions are limited to {} bytes; this one has {}", self.max_size, content.len()));
+        }
+        SessionBlob::from_bytes(content)?;
+        self.storage
+            .save_bytes(&key(doc_id, user)?, content)
+            .map_err(|e| format!("Failed to store session: {}", e))
+    }
+
+    /// Loads `user`'s session for `doc_id`, if one was saved
+    pub fn load(&self, doc_id: &str, user: &str) -> Option<Vec<u8>> {
+        self.storage.load_bytes(&key(doc_id, user).ok()?).ok()
+    }
+}
+
+/// Storage identifier of a session; ids that could escape the namespace are refused
+fn key(doc_id: &str, user: &str) -> Result<String, String> {
+    for part in [doc_id, user] {
+        if part.is_empty() || part.contains('/') || part.contains("..") {
+            return Err(format!("Invalid session key {:?}", part));
+        }
+    }
+    Ok(format!("{}{}/{}", NAMESPACE, doc_id, user))
+}
+
+fn error_reply(status: StatusCode, message: &str) -> warp::reply::Response {
+    let error = warp::reply::json(&serde_json::json!({ "type": "error", "message": message }));
+    warp::reply::with_status(error, status).into_response()
+}
+
+/// Handler for `GET /api/docs/:id/session`, fetching the signed-in user's
+pub async fn get_session_handler(doc_id: String, user: String, sessions: EditorSessions) -> Result<warp::reply::Response, warp::Rejection> {
+    match sessions.load(&doc_id, &user) {
+        Some(content) => Ok(warp::reply::with_header(content, "content-type", "application/octet-stream").into_response()),
+        None => Ok(error_reply(StatusCode::NOT_FOUND, "No saved session")),
+    }
+}
+
+/// Handler for `PUT /api/docs/:id/session`, with the signed-in user's encoded session as the body
+pub async fn put_session_handler(
+    doc_id: String,
+    user: String,
+    body: warp::hyper::body::Bytes,
+    sessions: EditorSessions,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    match sessions.save(&doc_id, &user, &body) {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(e) if body.len() > sessions.max_size => Ok(error_reply(StatusCode::PAYLOAD_TOO_LARGE, &e)),
+        Err(e) => Ok(error_reply(StatusCode::BAD_REQUEST, &e)),
+    }
+}
+
+/// Routes for saving and fetching the session a user hands off to their other devices
+pub fn session_routes(sessions: EditorSessions) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let put_sessions = sessions.clone();
+    let get = warp::path!("api" / "docs" / String / "session")
+        .and(warp::get())
+        .and(signed_in_user())
+        .and(warp::any().map(move || sessions.clone()))
+        .and_then(get_session_handler);
+    let put = warp::path!("api" / "docs" / String / "session")
+        .and(warp::put())
+        .and(signed_in_user())
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || put_sessions.clone()))
+        .and_then(put_session_handler);
+    get.or(put).unify().recover(recover_signed_out).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth::bearer_for;
+    use crate::storage::MemoryStorage;
+    use crate::editor::session::serialize_session;
+    use crate::editor::state::EditorState;
+    use crate::editor::version_control::VersionControl;
+
+    fn session(text: &str) -> Vec<u8> {
+        let mut state = EditorState::new();
+        state.insert_text(text);
+        serialize_session(&state, &mut VersionControl::new(), None).unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_kept_per_user() {
+        let routes = session_routes(EditorSessions::new(Arc::new(MemoryStorage::default())));
+        let content = session("draft");
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/docs/doc1/session")
+            .header("authorization", bearer_for("alice"))
+            .body(content.clone())
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = warp::test::request().path("/api/docs/doc1/session").header("authorization", bearer_for("alice")).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().to_vec(), content);
+
+        let response = warp::test::request().path("/api/docs/doc1/session").header("authorization", bearer_for("bob")).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = warp::test::request().path("/api/docs/doc1/session?user=alice").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_and_invalid_sessions_are_refused() {
+        let storage = Arc::new(MemoryStorage::default());
+        let content = session(&"x".repeat(1000));
+        let sessions = EditorSessions::new(storage.clone()).with_max_size(content.len() - 1);
+        let routes = session_routes(sessions.clone());
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/docs/doc1/session")
+            .header("authorization", bearer_for("alice"))
+            .body(content.clone())
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(storage.files.lock().unwrap().is_empty());
+
+        assert_eq!(sessions.save("doc1", "alice", b"not a session"), Err("Not a RustPad session".to_string()));
+        assert!(sessions.save("..", "alice", &session("x")).is_err());
+        assert!(sessions.save("doc1", "", &session("x")).is_err());
+    }
+}
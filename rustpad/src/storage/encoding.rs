@@ -0,0 +1,287 @@
+use serde::{Deserialize, Serialize};
+
+const UTF8_BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+const UTF16LE_BOM: &[u8] = &[0xff, 0xfe];
+const UTF16BE_BOM: &[u8] = &[0xfe, 0xff];
+
+/// Characters of Windows-1252 bytes 0x80 to 0x9f. The five bytes it leaves undefined stand for
+/// the control characters of the same number, as in Latin-1.
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+/// Encodings files are read in and written back in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextEncoding {
+    #[default]
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[serde(rename = "utf-8-bom")]
+    Utf8Bom, // UTF-8 starting with a byte order mark
+    #[serde(rename = "utf-16le")]
+    Utf16le, // Always with a byte order mark, which is how it is recognized
+    #[serde(rename = "utf-16be")]
+    Utf16be,
+    #[serde(rename = "windows-1252")]
+    Windows1252, // Also read for Latin-1, which it extends
+}
+
+impl TextEncoding {
+    /// The name to show users
+    pub fn label(&self) -> &'static str {
+        match self {
+            TextEncoding::Utf8 => "UTF-8",
+            TextEncoding::Utf8Bom => "UTF-8 with BOM",
+            TextEncoding::Utf16le => "UTF-16LE",
+            TextEncoding::Utf16be => "UTF-16BE",
+            TextEncoding::Windows1252 => "Windows-1252",
+        }
+    }
+}
+
+/// How lines of a file end on disk; in documents they always end with `\n`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+    Cr,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+
+    /// The name to show users
+    pub fn label(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::Crlf => "CRLF",
+            LineEnding::Cr => "CR",
+        }
+    }
+}
+
+/// How a file was stored, to write it back the same way, and what reading it lost
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FileFormat {
+    pub encoding: TextEncoding,
+    pub line_ending: LineEnding, // The most common one in the file
+    #[serde(default)]
+    pub mixed_line_endings: bool, // Saving makes them all `line_ending`
+    #[serde(default)]
+    pub replaced_characters: usize, // Undecodable bytes replaced with U+FFFD
+}
+
+impl FileFormat {
+    /// What users should be told about the file before editing it
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.replaced_characters > 0 {
+            warnings.push(format!(
+                "{} character{} couldn't be read as {} and {} replaced with \u{fffd}",
+                self.replaced_characters,
+                if self.replaced_characters == 1 { "" } else { "s" },
+                self.encoding.label(),
+                if self.replaced_characters == 1 { "was" } else { "were" },
+            ));
+        }
+        if self.mixed_line_endings {
+            warnings.push(format!("The file mixes line endings; saving it will use {} throughout", self.line_ending.label()));
+        }
+        warnings
+    }
+}
+
+/// A file's content as a document, with `\n` line endings, and how it was stored
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedText {
+    pub text: String,
+    pub format: FileFormat,
+}
+
+/// Reads `bytes` as text. A byte order mark decides the encoding; without one, bytes that
+/// aren't UTF-8 are taken as Windows-1252, unless they are mostly UTF-8 with a few bytes
+/// damaged, which are replaced.
+pub fn decode(bytes: &[u8]) -> DecodedText {
+    let (encoding, text, replaced_characters) = if let Some(rest) = bytes.strip_prefix(UTF8_BOM) {
+        let (text, replaced) = decode_utf8(rest);
+        (TextEncoding::Utf8Bom, text, replaced)
+    } else if let Some(rest) = bytes.strip_prefix(UTF16LE_BOM) {
+        let (text, replaced) = decode_utf16(rest, u16::from_le_bytes);
+        (TextEncoding::Utf16le, text, replaced)
+    } else if let Some(rest) = bytes.strip_prefix(UTF16BE_BOM) {
+        let (text, replaced) = decode_utf16(rest, u16::from_be_bytes);
+        (TextEncoding::Utf16be, text, replaced)
+    } else if looks_like_utf8(bytes) {
+        let (text, replaced) = decode_utf8(bytes);
+        (TextEncoding::Utf8, text, replaced)
+    } else {
+        (TextEncoding::Windows1252, bytes.iter().map(|byte| windows_1252_char(*byte)).collect(), 0)
+    };
+
+    let (crlf, lf, cr) = count_line_endings(&text);
+    let line_ending = [(lf, LineEnding::Lf), (crlf, LineEnding::Crlf), (cr, LineEnding::Cr)]
+        .into_iter()
+        .rev()
+        .max_by_key(|(count, _)| *count) // The last of equal counts, so LF wins ties
+        .filter(|(count, _)| *count > 0)
+        .map_or(LineEnding::Lf, |(_, line_ending)| line_ending);
+    let kinds = [crlf, lf, cr].iter().filter(|count| **count > 0).count();
+    DecodedText {
+        text: normalize_line_endings(&text),
+        format: FileFormat { encoding, line_ending, mixed_line_endings: kinds > 1, replaced_characters },
+    }
+}
+
+/// Writes `text` in `encoding` with lines ending in `line_ending`. Fails on characters the
+/// encoding has no bytes for.
+pub fn encode(text: &str, encoding: TextEncoding, line_ending: LineEnding) -> Result<Vec<u8>, String> {
+    let text = normalize_line_endings(text);
+    let text = match line_ending {
+        LineEnding::Lf => text,
+        line_ending => text.replace('\n', line_ending.as_str()),
+    };
+    Ok(match encoding {
+        TextEncoding::Utf8 => text.into_bytes(),
+        TextEncoding::Utf8Bom => [UTF8_BOM, text.as_bytes()].concat(),
+        TextEncoding::Utf16le => UTF16LE_BOM.iter().copied().chain(text.encode_utf16().flat_map(u16::to_le_bytes)).collect(),
+        TextEncoding::Utf16be => UTF16BE_BOM.iter().copied().chain(text.encode_utf16().flat_map(u16::to_be_bytes)).collect(),
+        TextEncoding::Windows1252 => text
+            .chars()
+            .map(|c| windows_1252_byte(c).ok_or_else(|| format!("{:?} can't be saved in {}", c, encoding.label())))
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+/// `text` with every `\r\n` and lone `\r` made `\n`
+pub fn normalize_line_endings(text: &str) -> String {
+    if !text.contains('\r') {
+        return text.to_string();
+    }
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// `\r\n`, lone `\n` and lone `\r` line endings in `text`
+fn count_line_endings(text: &str) -> (usize, usize, usize) {
+    let crlf = text.matches("\r\n").count();
+    (crlf, text.matches('\n').count() - crlf, text.matches('\r').count() - crlf)
+}
+
+/// Whether `bytes` are better read as UTF-8 than as Windows-1252: they are valid, or have more
+/// multi-byte characters than damaged spots
+fn looks_like_utf8(bytes: &[u8]) -> bool {
+    let (mut multibyte, mut damaged) = (0, 0);
+    for chunk in bytes.utf8_chunks() {
+        multibyte += chunk.valid().chars().filter(|c| !c.is_ascii()).count();
+        damaged += usize::from(!chunk.invalid().is_empty());
+    }
+    damaged == 0 || multibyte > damaged
+}
+
+/// `bytes` as UTF-8, with the number of damaged spots replaced
+fn decode_utf8(bytes: &[u8]) -> (String, usize) {
+    let replaced = bytes.utf8_chunks().filter(|chunk| !chunk.invalid().is_empty()).count();
+    (String::from_utf8_lossy(bytes).into_owned(), replaced)
+}
+
+/// `bytes` as UTF-16 in the byte order of `unit`, with the number of unpaired surrogates and
+/// odd trailing bytes replaced
+fn decode_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> (String, usize) {
+    let units = bytes.chunks_exact(2).map(|pair| unit([pair[0], pair[1]]));
+    let mut replaced = bytes.len() % 2;
+    let mut text: String = char::decode_utf16(units)
+        .map(|c| {
+            c.unwrap_or_else(|_| {
+                replaced += 1;
+                char::REPLACEMENT_CHARACTER
+            })
+        })
+        .collect();
+    if bytes.len() % 2 == 1 {
+        text.push(char::REPLACEMENT_CHARACTER);
+    }
+    (text, replaced)
+}
+
+fn windows_1252_char(byte: u8) -> char {
+    match byte {
+        0x80..=0x9f => WINDOWS_1252_HIGH[(byte - 0x80) as usize],
+        byte => byte as char,
+    }
+}
+
+fn windows_1252_byte(c: char) -> Option<u8> {
+    match c as u32 {
+        code @ (0..=0x7f | 0xa0..=0xff) => Some(code as u8),
+        _ => WINDOWS_1252_HIGH.iter().position(|high| *high == c).map(|index| 0x80 + index as u8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utf16le_with_bom() {
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend("naïve\r\nwörld €\r\n".encode_utf16().flat_map(u16::to_le_bytes));
+        let decoded = decode(&bytes);
+        assert_eq!(decoded.text, "naïve\nwörld €\n");
+        assert_eq!(decoded.format, FileFormat { encoding: TextEncoding::Utf16le, line_ending: LineEnding::Crlf, mixed_line_endings: false, replaced_characters: 0 });
+        assert_eq!(encode(&decoded.text, decoded.format.encoding, decoded.format.line_ending).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_latin1_heuristic() {
+        let decoded = decode(b"caf\xe9 cr\xe8me br\xfbl\xe9e \x80 5\n");
+        assert_eq!((decoded.text.as_str(), decoded.format.encoding), ("café crème brûlée € 5\n", TextEncoding::Windows1252));
+        assert!(decoded.format.warnings().is_empty());
+        assert_eq!(encode("café €", TextEncoding::Windows1252, LineEnding::Lf).unwrap(), b"caf\xe9 \x80");
+        assert!(encode("emoji 🦀", TextEncoding::Windows1252, LineEnding::Lf).is_err());
+
+        // Plain ASCII and valid UTF-8 stay UTF-8
+        assert_eq!(decode(b"plain").format.encoding, TextEncoding::Utf8);
+        assert_eq!(decode("café".as_bytes()).format.encoding, TextEncoding::Utf8);
+    }
+
+    #[test]
+    fn test_crlf_round_trip_is_byte_identical() {
+        for bytes in [&b"line one\r\nline two\r\n"[..], b"\xef\xbb\xbfbom\r\nkept\r\n", b"old mac\rline\r", b"no ending"] {
+            let decoded = decode(bytes);
+            assert!(!decoded.text.contains('\r'));
+            assert_eq!(encode(&decoded.text, decoded.format.encoding, decoded.format.line_ending).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_mixed_line_endings_are_flagged() {
+        let decoded = decode(b"one\r\ntwo\r\nthree\nfour");
+        assert_eq!(decoded.text, "one\ntwo\nthree\nfour");
+        assert_eq!((decoded.format.line_ending, decoded.format.mixed_line_endings), (LineEnding::Crlf, true));
+        assert_eq!(decoded.format.warnings(), vec!["The file mixes line endings; saving it will use CRLF throughout".to_string()]);
+        assert!(!decode(b"one\ntwo\n").format.mixed_line_endings);
+    }
+
+    #[test]
+    fn test_lossy_replacements_are_counted() {
+        // Mostly UTF-8 with two damaged spots
+        let decoded = decode(b"r\xc3\xa9sum\xc3\xa9 \xff na\xc3\xafve \xc3");
+        assert_eq!((decoded.text.as_str(), decoded.format.encoding), ("résumé \u{fffd} naïve \u{fffd}", TextEncoding::Utf8));
+        assert_eq!(decoded.format.replaced_characters, 2);
+        assert_eq!(decoded.format.warnings(), vec!["2 characters couldn't be read as UTF-8 and were replaced with \u{fffd}".to_string()]);
+
+        // An unpaired surrogate and an odd trailing byte in UTF-16
+        let decoded = decode(&[0xff, 0xfe, b'a', 0, 0x00, 0xd8, b'b', 0, b'c']);
+        assert_eq!((decoded.text.as_str(), decoded.format.replaced_characters), ("a\u{fffd}b\u{fffd}", 2));
+    }
+}
@@ -1,19 +1,68 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::io::{self, Write};
-use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
 use serde::{Serialize, Deserialize};
+use super::attachments::sha256_hex;
+use super::encoding::{self, DecodedText, FileFormat, LineEnding};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileInfo {
     pub file_name: String,
     pub file_path: String,
     pub last_modified: String,
+    #[serde(default)]
+    pub hash: String, // Of the content; pass it back to `save_file_checked` to detect concurrent writes
 }
 
+/// Returned, wrapped in an `io::Error`, when a checked save finds the file changed since it was read
+#[derive(Debug, Clone, PartialEq)]
+pub struct SaveConflict {
+    pub file_name: String,
+    pub current_hash: String, // Of the content now on disk; empty if the file was deleted
+}
+
+impl fmt::Display for SaveConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} was changed by someone else since it was opened", self.file_name)
+    }
+}
+
+impl std::error::Error for SaveConflict {}
+
+impl SaveConflict {
+    /// The conflict behind `error`, if it is one
+    pub fn from_error(error: &io::Error) -> Option<&SaveConflict> {
+        error.get_ref()?.downcast_ref::<SaveConflict>()
+    }
+}
+
+/// Hash identifying a version of a file's content, as reported in `FileInfo::hash`. Taken over
+/// the content as a document, so it doesn't depend on the file's encoding or line endings.
+pub fn content_hash(content: &str) -> String {
+    sha256_hex(content.as_bytes())
+}
+
+/// `content_hash` of a file's bytes
+fn file_hash(bytes: &[u8]) -> String {
+    content_hash(&encoding::decode(bytes).text)
+}
+
+/// Largest file `load_file` opens unless configured otherwise
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How much of a file `is_binary` looks at
+const BINARY_SAMPLE_SIZE: usize = 8192;
+
 /// Manages file storage operations including saving, loading, deleting, and renaming files.
 pub struct FileStorage {
     base_dir: PathBuf,
+    max_file_size: u64, // Larger files are refused instead of read into memory
+    write_lock: Mutex<()>, // Makes checking and writing a file one step for concurrent saves
+    formats: Mutex<HashMap<String, FileFormat>>, // How each file read or written was stored, to save it the same way
 }
 
 impl FileStorage {
@@ -21,14 +70,86 @@ impl FileStorage {
     pub fn new(base_dir: &str) -> Self {
         Self {
             base_dir: PathBuf::from(base_dir),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            write_lock: Mutex::new(()),
+            formats: Mutex::new(HashMap::new()),
         }
     }
 
-    /// Saves content to a file in the base directory.
+    /// Refuses to load files larger than `max_file_size` bytes
+    pub fn with_max_file_size(self, max_file_size: u64) -> Self {
+        Self { max_file_size, ..self }
+    }
+
+    /// Where `file_name` is: within the base directory, refusing names that would leave it by
+    /// being absolute, going up with `..` or passing through a symlink. With an empty base
+    /// directory, as for the desktop editor's local files, names are used as given.
+    fn path_of(&self, file_name: &str) -> io::Result<PathBuf> {
+        if self.base_dir.as_os_str().is_empty() {
+            return Ok(PathBuf::from(file_name));
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is outside the storage directory", file_name));
+        let mut path = self.base_dir.clone();
+        for component in Path::new(file_name).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => continue,
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => return Err(invalid()),
+            }
+            if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+                return Err(invalid());
+            }
+        }
+        if path == self.base_dir {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No file name given"));
+        }
+        Ok(path)
+    }
+
+    /// Saves content to a file in the base directory, creating the directories it goes in.
     pub fn save_file(&self, file_name: &str, content: &str) -> io::Result<FileInfo> {
-        let file_path = self.base_dir.join(file_name);
+        self.save_file_checked(file_name, content, None)
+    }
+
+    /// Saves content to a file, but only if the file on disk still has `expected_hash`: the hash
+    /// of the version the content was based on, or of the empty string for a new file. Otherwise
+    /// fails with a `SaveConflict` instead of overwriting someone else's changes.
+    pub fn save_file_checked(&self, file_name: &str, content: &str, expected_hash: Option<&str>) -> io::Result<FileInfo> {
+        self.save_file_as(file_name, content, expected_hash, None)
+    }
+
+    /// `save_file_checked`, writing the file in the encoding and with the line endings it had,
+    /// or with `line_ending` when the user prefers other ones. New files are UTF-8 with `\n`.
+    /// Fails with `InvalidData` when the content has characters the encoding can't hold.
+    pub fn save_file_as(&self, file_name: &str, content: &str, expected_hash: Option<&str>, line_ending: Option<LineEnding>) -> io::Result<FileInfo> {
+        let file_path = self.path_of(file_name)?;
+        let _guard = self.write_lock.lock().unwrap();
+        let known = self.formats.lock().unwrap().get(file_name).cloned();
+        let current = match (&known, expected_hash) {
+            (Some(_), None) => None,
+            _ => match fs::read(&file_path) {
+                Ok(current) => Some(current),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            },
+        };
+        if let Some(expected_hash) = expected_hash {
+            let current_hash = current.as_deref().map_or_else(|| content_hash(""), file_hash);
+            if current_hash != expected_hash {
+                return Err(io::Error::other(SaveConflict { file_name: file_name.to_string(), current_hash }));
+            }
+        }
+
+        let format = known.or_else(|| current.map(|current| encoding::decode(&current).format)).unwrap_or_default();
+        let format = FileFormat { line_ending: line_ending.unwrap_or(format.line_ending), mixed_line_endings: false, replaced_characters: 0, ..format };
+        let content = encoding::normalize_line_endings(content);
+        let bytes = encoding::encode(&content, format.encoding, format.line_ending).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?; // Names may have directories, like `src/new/lib.rs` or `attachments/<hash>`
+        }
         let mut file = fs::File::create(&file_path)?;
-        file.write_all(content.as_bytes())?;
+        file.write_all(&bytes)?;
+        self.formats.lock().unwrap().insert(file_name.to_string(), format);
 
         let last_modified = Self::get_last_modified(&file_path)?;
 
@@ -36,28 +157,57 @@ impl FileStorage {
             file_name: file_name.to_string(),
             file_path: file_path.to_string_lossy().to_string(),
             last_modified,
+            hash: content_hash(&content),
         })
     }
 
-    /// Loads the content of a file from the base directory.
+    /// Loads the content of a file from the base directory as UTF-8 with `\n` line endings,
+    /// whatever it was stored in. Files over the size limit and binary files fail with
+    /// `InvalidData` and a message saying why.
     pub fn load_file(&self, file_name: &str) -> io::Result<String> {
-        let file_path = self.base_dir.join(file_name);
-        let content = fs::read_to_string(file_path)?;
-        Ok(content)
+        self.load_file_with_format(file_name).map(|decoded| decoded.text)
+    }
+
+    /// `load_file`, also saying how the file was stored and what reading it lost. Later saves of
+    /// the file write it back the same way.
+    pub fn load_file_with_format(&self, file_name: &str) -> io::Result<DecodedText> {
+        let file_path = self.path_of(file_name)?;
+        let size = fs::metadata(&file_path)?.len();
+        if size > self.max_file_size {
+            let message = format!("{} is too large to open ({} bytes, the limit is {})", file_name, size, self.max_file_size);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        let bytes = fs::read(file_path)?;
+        let decoded = encoding::decode(&bytes);
+        let binary = match decoded.format.encoding {
+            encoding::TextEncoding::Utf16le | encoding::TextEncoding::Utf16be => decoded.text.contains('\0'),
+            _ => is_binary(&bytes),
+        };
+        if binary {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is a binary file", file_name)));
+        }
+        self.formats.lock().unwrap().insert(file_name.to_string(), decoded.format.clone());
+        Ok(decoded)
     }
 
     /// Deletes a file from the base directory.
     pub fn delete_file(&self, file_name: &str) -> io::Result<()> {
-        let file_path = self.base_dir.join(file_name);
+        let file_path = self.path_of(file_name)?;
         fs::remove_file(file_path)?;
+        self.formats.lock().unwrap().remove(file_name);
         Ok(())
     }
 
     /// Renames a file in the base directory.
     pub fn rename_file(&self, old_name: &str, new_name: &str) -> io::Result<FileInfo> {
-        let old_path = self.base_dir.join(old_name);
-        let new_path = self.base_dir.join(new_name);
+        let old_path = self.path_of(old_name)?;
+        let new_path = self.path_of(new_name)?;
         fs::rename(&old_path, &new_path)?;
+        let mut formats = self.formats.lock().unwrap();
+        if let Some(format) = formats.remove(old_name) {
+            formats.insert(new_name.to_string(), format);
+        }
+        drop(formats);
 
         let last_modified = Self::get_last_modified(&new_path)?;
 
@@ -65,6 +215,7 @@ impl FileStorage {
             file_name: new_name.to_string(),
             file_path: new_path.to_string_lossy().to_string(),
             last_modified,
+            hash: file_hash(&fs::read(&new_path)?),
         })
     }
 
@@ -84,6 +235,7 @@ impl FileStorage {
                     file_name,
                     file_path: path.to_string_lossy().to_string(),
                     last_modified,
+                    hash: file_hash(&fs::read(&path)?),
                 });
             }
         }
@@ -108,6 +260,37 @@ impl FileStorage {
     }
 }
 
+/// Guesses whether `bytes` are binary rather than text: a null byte, or more than one in ten
+/// bytes not being valid UTF-8 while more than a third are outside ASCII, which text in a
+/// legacy encoding such as Latin-1 rarely is, near the start of the content
+pub fn is_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(BINARY_SAMPLE_SIZE)];
+    if sample.contains(&0) {
+        return true;
+    }
+    let mut invalid: usize = sample.utf8_chunks().map(|chunk| chunk.invalid().len()).sum();
+    if sample.len() < bytes.len() {
+        invalid -= sample.utf8_chunks().last().map_or(0, |chunk| chunk.invalid().len()); // A character cut off by the sample
+    }
+    let high = sample.iter().filter(|byte| !byte.is_ascii()).count();
+    invalid * 10 > sample.len() && high * 3 > sample.len()
+}
+
+impl super::Storage for FileStorage {
+    fn save(&self, identifier: &str, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.save_file(identifier, content)?;
+        Ok(())
+    }
+
+    fn load(&self, identifier: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(self.load_file(identifier)?)
+    }
+
+    fn delete(&self, identifier: &str) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(self.delete_file(identifier)?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -138,4 +321,135 @@ mod tests {
         // Clean up
         fs::remove_dir_all(temp_dir).unwrap();
     }
+
+    #[test]
+    fn test_stale_write_is_rejected() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-storage-conflict-{}", std::process::id()));
+        let storage = FileStorage::new(&dir.to_string_lossy());
+        let created = storage.save_file_checked("notes.md", "v1", Some(&content_hash(""))).unwrap();
+        assert_eq!(created.hash, content_hash("v1"));
+
+        // Both collaborators opened v1; the first save wins, the second is refused
+        let saved = storage.save_file_checked("notes.md", "v2 by ana", Some(&created.hash)).unwrap();
+        let error = storage.save_file_checked("notes.md", "v2 by bo", Some(&created.hash)).unwrap_err();
+        let conflict = SaveConflict::from_error(&error).unwrap();
+        assert_eq!(conflict.current_hash, saved.hash);
+        assert_eq!(storage.load_file("notes.md").unwrap(), "v2 by ana");
+
+        // Rebased on the current version, the save goes through; unchecked saves always do
+        storage.save_file_checked("notes.md", "v3 by bo", Some(&conflict.current_hash)).unwrap();
+        assert!(storage.save_file_checked("notes.md", "new", Some(&content_hash(""))).is_err());
+        storage.save_file("notes.md", "forced").unwrap();
+        assert!(SaveConflict::from_error(&io::Error::other("unrelated")).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_creates_missing_directories_within_base_dir() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-storage-nested-{}", std::process::id()));
+        let storage = FileStorage::new(&dir.to_string_lossy());
+        let info = storage.save_file("src/new/deep/file.rs", "fn main() {}").unwrap();
+        assert!(dir.join("src/new/deep").is_dir());
+        assert_eq!(fs::read_to_string(dir.join("src/new/deep/file.rs")).unwrap(), "fn main() {}");
+        assert_eq!(info.file_path, dir.join("src/new/deep/file.rs").to_string_lossy());
+        assert_eq!(storage.load_file("./src/new/deep/file.rs").unwrap(), "fn main() {}");
+
+        // Nothing is written or created outside the base directory
+        let outside = format!("rustpad-file-storage-escaped-{}", std::process::id());
+        for name in [format!("../{}/file.rs", outside), format!("src/../../{}/file.rs", outside), std::env::temp_dir().join(&outside).to_string_lossy().to_string(), String::new()] {
+            assert_eq!(storage.save_file(&name, "gotcha").unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", name);
+        }
+        assert!(!std::env::temp_dir().join(&outside).exists());
+        assert!(storage.load_file("../etc/passwd").is_err());
+        assert!(storage.rename_file("src/new/deep/file.rs", "../file.rs").is_err());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(std::env::temp_dir(), dir.join("link")).unwrap();
+            assert!(storage.save_file(&format!("link/{}/file.rs", outside), "gotcha").is_err());
+            assert!(!std::env::temp_dir().join(&outside).exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_oversized_files() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-storage-large-{}", std::process::id()));
+        let storage = FileStorage::new(&dir.to_string_lossy()).with_max_file_size(16);
+        storage.save_file("small.txt", "sixteen bytes!!!").unwrap();
+        storage.save_file("large.txt", "seventeen bytes!!").unwrap();
+
+        assert_eq!(storage.load_file("small.txt").unwrap(), "sixteen bytes!!!");
+        let error = storage.load_file("large.txt").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "large.txt is too large to open (17 bytes, the limit is 16)");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_binary_files() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-storage-binary-{}", std::process::id()));
+        let storage = FileStorage::new(&dir.to_string_lossy());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("image.png"), [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00]).unwrap();
+        fs::write(dir.join("latin1.txt"), b"caf\xe9 au lait").unwrap();
+
+        assert_eq!(storage.load_file("image.png").unwrap_err().to_string(), "image.png is a binary file");
+        assert_eq!(storage.load_file("latin1.txt").unwrap(), "café au lait"); // Text in another encoding
+        assert!(is_binary(&[0xff; 64]));
+        assert!(!is_binary("naïve résumé, ünïcödé".as_bytes()));
+
+        // A multi-byte character cut off at the end of the sample doesn't count
+        let mut text = "a".repeat(BINARY_SAMPLE_SIZE - 1).into_bytes();
+        text.extend("é".as_bytes());
+        assert!(!is_binary(&text));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unedited_files_are_saved_byte_identical() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-storage-encoding-{}", std::process::id()));
+        let storage = FileStorage::new(&dir.to_string_lossy());
+        fs::create_dir_all(&dir).unwrap();
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend("größe\r\n".encode_utf16().flat_map(u16::to_le_bytes));
+        let files: [(&str, &[u8]); 3] = [("windows.txt", b"one\r\ntwo\r\n"), ("latin1.txt", b"na\xefve\r\n"), ("utf16.txt", &utf16)];
+        for (name, bytes) in files {
+            fs::write(dir.join(name), bytes).unwrap();
+            let decoded = storage.load_file_with_format(name).unwrap();
+            assert!(!decoded.text.contains('\r'));
+
+            // Checked against the hash of the document, not of the bytes on disk
+            storage.save_file_checked(name, &decoded.text, Some(&content_hash(&decoded.text))).unwrap();
+            assert_eq!(fs::read(dir.join(name)).unwrap(), bytes, "{}", name);
+        }
+
+        // The format is picked up again by a storage that never loaded the file
+        FileStorage::new(&dir.to_string_lossy()).save_file("windows.txt", "one\ntwo\nthree\n").unwrap();
+        assert_eq!(fs::read(dir.join("windows.txt")).unwrap(), b"one\r\ntwo\r\nthree\r\n");
+        assert!(storage.save_file("latin1.txt", "🦀").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_honors_line_ending_preference() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-storage-line-endings-{}", std::process::id()));
+        let storage = FileStorage::new(&dir.to_string_lossy());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("mixed.txt"), b"one\r\ntwo\r\nthree\n").unwrap();
+        let decoded = storage.load_file_with_format("mixed.txt").unwrap();
+        assert!(decoded.format.mixed_line_endings);
+
+        storage.save_file_as("mixed.txt", &decoded.text, None, Some(LineEnding::Lf)).unwrap();
+        assert_eq!(fs::read(dir.join("mixed.txt")).unwrap(), b"one\ntwo\nthree\n");
+        let reloaded = storage.load_file_with_format("mixed.txt").unwrap();
+        assert_eq!((reloaded.format.line_ending, reloaded.format.mixed_line_endings), (LineEnding::Lf, false));
+
+        // New files are UTF-8, with the preference when there is one
+        storage.save_file_as("new.txt", "a\nb", None, Some(LineEnding::Crlf)).unwrap();
+        assert_eq!(fs::read(dir.join("new.txt")).unwrap(), b"a\r\nb");
+        storage.save_file("plain.txt", "a\r\nb").unwrap();
+        assert_eq!(fs::read(dir.join("plain.txt")).unwrap(), b"a\nb");
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
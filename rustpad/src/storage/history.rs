@@ -1,69 +1,220 @@
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fs;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use chrono::{Utc, DateTime};
+use chrono::{Utc, DateTime, Duration};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct FileVersion {
     pub version_id: usize,
     pub content: String,
     pub timestamp: DateTime<Utc>,
     pub description: String, // Optional description or commit message for the version
+    #[serde(default)]
+    pub checkpoint: bool, // Kept whatever the retention policy, see `HistoryManager::set_checkpoint`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>, // Names for releases or milestones, each on one version of the file at most
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation: Option<String>, // For versions kept before a bulk operation, the operation's name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>, // And who ran it
+}
+
+impl FileVersion {
+    /// Whether the retention policy has to keep the version
+    fn kept(&self) -> bool {
+        self.checkpoint || !self.tags.is_empty()
+    }
+}
+
+/// Which versions of each file `HistoryManager` keeps. Whenever a version is added, the oldest
+/// versions are dropped while any limit is exceeded. Checkpoints are never dropped and don't
+/// count towards the limits, and neither do tagged versions; the newest version is never dropped either.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct RetentionPolicy {
+    #[serde(default)]
+    pub max_versions: Option<usize>,
+    #[serde(default)]
+    pub max_age_days: Option<i64>, // Versions older than this many days are dropped
+    #[serde(default)]
+    pub max_bytes: Option<usize>, // Budget for the content of the versions kept
+}
+
+impl RetentionPolicy {
+    /// Keeps every version
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps at most `max_versions` versions of each file
+    pub fn with_max_versions(mut self, max_versions: usize) -> Self {
+        self.max_versions = Some(max_versions);
+        self
+    }
+
+    /// Keeps the versions from the last `days` days
+    pub fn with_max_age_days(mut self, days: i64) -> Self {
+        self.max_age_days = Some(days);
+        self
+    }
+
+    /// Keeps versions whose content adds up to at most `max_bytes` for each file
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+}
+
+/// Subdirectory of the base directory holding a directory of versions for each file
+const VERSIONS_DIR: &str = ".versions";
+
+/// The versions of one file
+#[derive(Default)]
+struct FileHistory {
+    versions: VecDeque<FileVersion>, // Oldest first, as the retention policy leaves them
+    next_id: usize,                  // Never reused, even once older versions are trimmed
 }
 
 pub struct HistoryManager {
     base_dir: PathBuf,
-    max_versions: usize, // Maximum number of versions to retain
-    versions: VecDeque<FileVersion>, // Keeps versions in a queue with a maximum length
+    retention: RetentionPolicy, // Which versions of each file are kept
+    histories: HashMap<String, FileHistory>, // Loaded histories, by file name
 }
 
 impl HistoryManager {
-    /// Creates a new HistoryManager for tracking file versions
+    /// Creates a new HistoryManager for tracking file versions, keeping at most `max_versions`
+    /// of each file
     pub fn new(base_dir: &str, max_versions: usize) -> Self {
         Self {
             base_dir: PathBuf::from(base_dir),
-            max_versions,
-            versions: VecDeque::new(),
+            retention: RetentionPolicy::new().with_max_versions(max_versions),
+            histories: HashMap::new(),
         }
     }
 
-    /// Adds a new version to the version history, saving the file and tracking its content
-    pub fn add_version(&mut self, file_name: &str, content: &str, description: &str) -> io::Result<()> {
-        let version_id = self.versions.len() + 1; // Increment version ID
-        let timestamp = Utc::now();
+    /// Keeps versions as `retention` says instead of by count alone
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Adds a new version of `file_name`, saving it to disk, and returns its id. Ids count up
+    /// per file and are never reused, so an id keeps naming the same version until it is trimmed.
+    pub fn add_version(&mut self, file_name: &str, content: &str, description: &str) -> io::Result<usize> {
+        self.add_version_at(file_name, content, description, Utc::now())
+    }
+
+    /// Adds a new version of `file_name` made at `now`, then drops the versions the retention
+    /// policy no longer keeps
+    pub fn add_version_at(&mut self, file_name: &str, content: &str, description: &str, now: DateTime<Utc>) -> io::Result<usize> {
+        self.push_version(file_name, content, description, None, now)
+    }
+
+    /// Adds the version of `file_name` from before bulk `operation` by `actor`, such as a
+    /// replace-all, tagged with both
+    pub fn add_bulk_version_at(&mut self, file_name: &str, content: &str, operation: &str, actor: &str, now: DateTime<Utc>) -> io::Result<usize> {
+        let description = format!("Before {} by {}", operation.replace('_', " "), actor);
+        self.push_version(file_name, content, &description, Some((operation, actor)), now)
+    }
+
+    fn push_version(&mut self, file_name: &str, content: &str, description: &str, bulk: Option<(&str, &str)>, now: DateTime<Utc>) -> io::Result<usize> {
+        self.load_history(file_name)?;
+        let dir = self.versions_dir(file_name);
+        let history = self.histories.get_mut(file_name).unwrap();
 
-        // Create a new FileVersion instance
         let version = FileVersion {
-            version_id,
+            version_id: history.next_id,
             content: content.to_string(),
-            timestamp,
+            timestamp: now,
             description: description.to_string(),
+            checkpoint: false,
+            tags: Vec::new(),
+            operation: bulk.map(|(operation, _)| operation.to_string()),
+            actor: bulk.map(|(_, actor)| actor.to_string()),
         };
+        save_version(&dir, &version)?;
+        history.next_id += 1;
+        history.versions.push_back(version);
 
-        // Save the version to disk
-        self.save_version(file_name, &version)?;
+        trim(&dir, history, &self.retention, now);
+        Ok(history.next_id - 1)
+    }
 
-        // Add the version to the queue
-        self.versions.push_back(version);
+    /// Marks version `version_id` of `file_name` as a checkpoint, which the retention policy
+    /// keeps however old it gets, or unmarks it so it can be dropped once a version is added
+    pub fn set_checkpoint(&mut self, file_name: &str, version_id: usize, checkpoint: bool) -> io::Result<()> {
+        let dir = self.versions_dir(file_name);
+        let version = self
+            .histories
+            .get_mut(file_name)
+            .and_then(|history| history.versions.iter_mut().find(|version| version.version_id == version_id))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Version not found"))?;
+        version.checkpoint = checkpoint;
+        save_version(&dir, version)
+    }
 
-        // Trim the queue to maintain the max_versions limit
-        if self.versions.len() > self.max_versions {
-            self.versions.pop_front(); // Remove the oldest version
+    /// Tags version `version_id` of `file_name`, e.g. `v1.0`, so it can be found with
+    /// `get_version_by_tag` and is kept like a checkpoint. A tag names one version per file:
+    /// it is moved off whichever version had it before.
+    pub fn tag_version(&mut self, file_name: &str, version_id: usize, tag: &str) -> io::Result<()> {
+        let tag = tag.trim();
+        if tag.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Tags can't be empty"));
+        }
+        let dir = self.versions_dir(file_name);
+        let history = self
+            .histories
+            .get_mut(file_name)
+            .filter(|history| history.versions.iter().any(|version| version.version_id == version_id))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Version not found"))?;
+        for version in history.versions.iter_mut() {
+            let had_tag = version.tags.iter().any(|existing| existing == tag);
+            if version.version_id == version_id && !had_tag {
+                version.tags.push(tag.to_string());
+            } else if version.version_id != version_id && had_tag {
+                version.tags.retain(|existing| existing != tag);
+            } else {
+                continue;
+            }
+            save_version(&dir, version)?;
         }
+        Ok(())
+    }
 
+    /// The version of `file_name` tagged `tag`
+    pub fn get_version_by_tag(&self, file_name: &str, tag: &str) -> Option<FileVersion> {
+        self.histories.get(file_name)?.versions.iter().find(|version| version.tags.iter().any(|existing| existing == tag)).cloned()
+    }
+
+    /// Restores versions of `file_name` saved elsewhere, e.g. from an imported bundle, keeping
+    /// their ids, timestamps and order. The file must not have versions of its own yet.
+    pub fn restore_versions(&mut self, file_name: &str, versions: Vec<FileVersion>) -> io::Result<()> {
+        self.load_history(file_name)?;
+        let dir = self.versions_dir(file_name);
+        let history = self.histories.get_mut(file_name).unwrap();
+        if !history.versions.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already has versions", file_name)));
+        }
+        let mut versions = versions;
+        versions.sort_by_key(|version| version.version_id);
+        for version in versions {
+            save_version(&dir, &version)?;
+            history.next_id = history.next_id.max(version.version_id + 1);
+            history.versions.push_back(version);
+        }
+        trim(&dir, history, &self.retention, Utc::now());
         Ok(())
     }
 
-    /// Retrieves a specific version by its ID
-    pub fn get_version(&self, version_id: usize) -> Option<FileVersion> {
-        self.versions.iter().find(|&v| v.version_id == version_id).cloned()
+    /// Retrieves a version of `file_name` by its ID
+    pub fn get_version(&self, file_name: &str, version_id: usize) -> Option<FileVersion> {
+        self.histories.get(file_name)?.versions.iter().find(|v| v.version_id == version_id).cloned()
     }
 
     /// Reverts the file to a specific version by overwriting the current file with the version's content
     pub fn revert_to_version(&self, file_name: &str, version_id: usize) -> io::Result<()> {
-        if let Some(version) = self.get_version(version_id) {
+        if let Some(version) = self.get_version(file_name, version_id) {
             let file_path = self.base_dir.join(file_name);
             let mut file = fs::File::create(file_path)?;
             file.write_all(version.content.as_bytes())?;
@@ -73,49 +224,168 @@ impl HistoryManager {
         }
     }
 
-    /// Saves the content of a version to a file on disk
-    fn save_version(&self, file_name: &str, version: &FileVersion) -> io::Result<()> {
-        let version_file_name = format!("{}_v{}.txt", file_name, version.version_id);
-        let version_path = self.base_dir.join(version_file_name);
-        let mut file = fs::File::create(version_path)?;
-        file.write_all(version.content.as_bytes())?;
+    /// Loads the saved versions of `file_name` from disk, once. Version files of the old flat
+    /// layout (`<file name>_v<id>.txt` next to where the file would be) are moved into the
+    /// file's own directory first.
+    pub fn load_history(&mut self, file_name: &str) -> io::Result<()> {
+        if self.histories.contains_key(file_name) {
+            return Ok(());
+        }
+        let dir = self.versions_dir(file_name);
+        self.migrate_flat_layout(file_name, &dir)?;
+
+        let mut versions = Vec::new();
+        if dir.is_dir() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|extension| extension == "json") {
+                    match serde_json::from_str::<FileVersion>(&fs::read_to_string(&path)?) {
+                        Ok(version) => versions.push(version),
+                        Err(e) => eprintln!("Skipping unreadable version {}: {}", path.display(), e),
+                    }
+                }
+            }
+        }
+        versions.sort_by_key(|version| version.version_id);
+
+        let next_id = versions.last().map_or(1, |newest| newest.version_id + 1);
+        let mut history = FileHistory { versions: versions.into(), next_id };
+        trim(&dir, &mut history, &self.retention, Utc::now());
+        self.histories.insert(file_name.to_string(), history);
         Ok(())
     }
 
-    /// Loads version history from disk (if required)
-    pub fn load_history(&mut self, file_name: &str) -> io::Result<()> {
-        // This can be implemented as needed to load previously saved history
-        // This could involve reading saved version files from the base directory
-        // For now, we assume the history is kept in memory during runtime
+    /// Deletes every saved version of `file_name`, e.g. when the document is purged
+    pub fn delete_history(&mut self, file_name: &str) -> io::Result<()> {
+        let dir = self.versions_dir(file_name);
+        self.migrate_flat_layout(file_name, &dir)?; // So no old-layout versions are left behind
+        self.histories.remove(file_name);
+        if dir.is_dir() {
+            fs::remove_dir_all(&dir)?;
+        }
         Ok(())
     }
 
-    /// Lists all versions in the history for a specific file
-    pub fn list_versions(&self) -> Vec<FileVersion> {
-        self.versions.iter().cloned().collect()
+    /// Lists the versions of `file_name`, oldest first. Empty until its history is loaded.
+    pub fn list_versions(&self, file_name: &str) -> Vec<FileVersion> {
+        self.histories.get(file_name).map(|history| history.versions.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Directory holding the versions of `file_name`
+    fn versions_dir(&self, file_name: &str) -> PathBuf {
+        self.base_dir.join(VERSIONS_DIR).join(encode_file_name(file_name))
+    }
+
+    /// Moves version files `file_name` had in the old flat layout into `dir`. They only hold
+    /// the content, so the time they were written stands in for the version's timestamp.
+    fn migrate_flat_layout(&self, file_name: &str, dir: &Path) -> io::Result<()> {
+        let legacy_path = self.base_dir.join(file_name);
+        let (Some(legacy_dir), Some(name)) = (legacy_path.parent(), legacy_path.file_name()) else { return Ok(()) };
+        if Path::new(file_name).is_absolute() || !legacy_dir.is_dir() {
+            return Ok(()); // Only paths under the base directory were ever versioned there
+        }
+        let prefix = format!("{}_v", name.to_string_lossy());
+
+        for entry in fs::read_dir(legacy_dir)? {
+            let path = entry?.path();
+            let entry_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let Some(version_id) = entry_name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".txt"))
+                .and_then(|id| id.parse::<usize>().ok())
+            else {
+                continue;
+            };
+            let version = FileVersion {
+                version_id,
+                content: fs::read_to_string(&path)?,
+                timestamp: fs::metadata(&path)?.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now()),
+                description: "Migrated from the flat version layout".to_string(),
+                checkpoint: false,
+                tags: Vec::new(),
+                operation: None,
+                actor: None,
+            };
+            save_version(dir, &version)?;
+            fs::remove_file(&path)?;
+        }
+        Ok(())
     }
 }
 
+/// Saves a version as JSON in its file's version directory
+fn save_version(dir: &Path, version: &FileVersion) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut file = fs::File::create(dir.join(format!("{}.json", version.version_id)))?;
+    file.write_all(serde_json::to_string(version)?.as_bytes())?;
+    Ok(())
+}
+
+/// Drops the versions `retention` doesn't keep at `now`, oldest first, from memory and from `dir`
+fn trim(dir: &Path, history: &mut FileHistory, retention: &RetentionPolicy, now: DateTime<Utc>) {
+    let newest = history.versions.back().map(|version| version.version_id);
+    let counted = |version: &FileVersion| !version.kept() && Some(version.version_id) != newest;
+    let mut count = history.versions.iter().filter(|version| counted(version)).count() + usize::from(newest.is_some());
+    let mut bytes: usize = history.versions.iter().filter(|version| counted(version)).map(|version| version.content.len()).sum();
+    let newest_bytes = history.versions.back().map_or(0, |version| version.content.len());
+    let oldest_kept = retention.max_age_days.map(|days| now - Duration::days(days));
+
+    history.versions.retain(|version| {
+        let over = retention.max_versions.is_some_and(|max| count > max)
+            || retention.max_bytes.is_some_and(|max| bytes + newest_bytes > max)
+            || oldest_kept.is_some_and(|oldest| version.timestamp < oldest);
+        if !counted(version) || !over {
+            return true;
+        }
+        count -= 1;
+        bytes -= version.content.len();
+        if let Err(e) = fs::remove_file(dir.join(format!("{}.json", version.version_id))) {
+            eprintln!("Failed to remove trimmed version {}: {}", version.version_id, e);
+        }
+        false
+    });
+}
+
+/// A directory name unique to `file_name`: anything but letters, digits, `-`, `_` and inner
+/// dots is percent-encoded, so `a/test.txt` and `b/test.txt` get directories of their own
+fn encode_file_name(file_name: &str) -> String {
+    file_name
+        .bytes()
+        .enumerate()
+        .map(|(index, byte)| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (byte as char).to_string(),
+            b'.' if index > 0 => ".".to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs;
 
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rustpad-history-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
     #[test]
     fn test_history_manager() {
-        let temp_dir = "test_history";
-        fs::create_dir(temp_dir).unwrap();
-        let mut history_manager = HistoryManager::new(temp_dir, 5);
+        let temp_dir = temp_dir("basic");
+        let mut history_manager = HistoryManager::new(&temp_dir, 5);
 
         // Test adding versions
         history_manager.add_version("test.txt", "Version 1 content", "Initial version").unwrap();
         history_manager.add_version("test.txt", "Version 2 content", "Second version").unwrap();
 
         // Test get_version
-        let version_1 = history_manager.get_version(1).unwrap();
+        let version_1 = history_manager.get_version("test.txt", 1).unwrap();
         assert_eq!(version_1.content, "Version 1 content");
 
-        let version_2 = history_manager.get_version(2).unwrap();
+        let version_2 = history_manager.get_version("test.txt", 2).unwrap();
         assert_eq!(version_2.content, "Version 2 content");
 
         // Test reverting to a version
@@ -129,10 +399,150 @@ mod tests {
         history_manager.add_version("test.txt", "Version 5 content", "Fifth version").unwrap();
         history_manager.add_version("test.txt", "Version 6 content", "Sixth version").unwrap();
 
-        assert_eq!(history_manager.versions.len(), 5); // Max versions is 5
-        assert!(history_manager.get_version(1).is_none()); // Oldest version should be removed
+        assert_eq!(history_manager.list_versions("test.txt").len(), 5); // Max versions is 5
+        assert!(history_manager.get_version("test.txt", 1).is_none()); // Oldest version should be removed
 
         // Clean up
         fs::remove_dir_all(temp_dir).unwrap();
     }
+
+    #[test]
+    fn test_files_have_separate_histories() {
+        let temp_dir = temp_dir("separate");
+        let mut history_manager = HistoryManager::new(&temp_dir, 5);
+        fs::create_dir_all(Path::new(&temp_dir).join("a")).unwrap();
+        fs::create_dir_all(Path::new(&temp_dir).join("b")).unwrap();
+
+        assert_eq!(history_manager.add_version("a/test.txt", "a1", "").unwrap(), 1);
+        assert_eq!(history_manager.add_version("b/test.txt", "b1", "").unwrap(), 1);
+        assert_eq!(history_manager.add_version("a/test.txt", "a2", "").unwrap(), 2);
+        assert_eq!(history_manager.list_versions("b/test.txt").len(), 1);
+
+        // Reverting writes the version to the file it belongs to, and only that file
+        history_manager.revert_to_version("b/test.txt", 1).unwrap();
+        assert_eq!(fs::read_to_string(Path::new(&temp_dir).join("b/test.txt")).unwrap(), "b1");
+        assert!(!Path::new(&temp_dir).join("a/test.txt").exists());
+        assert!(history_manager.revert_to_version("b/test.txt", 2).is_err());
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn test_ids_keep_increasing_after_trimming_and_reloading() {
+        let temp_dir = temp_dir("ids");
+        let mut history_manager = HistoryManager::new(&temp_dir, 2);
+        for content in ["one", "two", "three"] {
+            history_manager.add_version("notes.md", content, "").unwrap();
+        }
+        let ids: Vec<usize> = history_manager.list_versions("notes.md").iter().map(|v| v.version_id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(history_manager.add_version("notes.md", "four", "").unwrap(), 4);
+
+        // A fresh manager picks the history up from disk and carries on numbering
+        let mut reloaded = HistoryManager::new(&temp_dir, 2);
+        reloaded.load_history("notes.md").unwrap();
+        assert_eq!(reloaded.get_version("notes.md", 4).unwrap().content, "four");
+        assert!(reloaded.get_version("notes.md", 2).is_none());
+        assert_eq!(reloaded.add_version("notes.md", "five", "").unwrap(), 5);
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn test_old_versions_expire_but_checkpoints_stay() {
+        let temp_dir = temp_dir("age");
+        let retention = RetentionPolicy::new().with_max_age_days(30);
+        let mut history_manager = HistoryManager::new(&temp_dir, 100).with_retention(retention);
+        // Versions are timed by the test rather than the clock; day 40 is today, as loading trims by the clock
+        let start = Utc::now() - Duration::days(40);
+        let day = |n: i64| start + Duration::days(n);
+
+        history_manager.add_version_at("plan.md", "draft", "", day(0)).unwrap();
+        history_manager.add_version_at("plan.md", "approved", "", day(1)).unwrap();
+        history_manager.set_checkpoint("plan.md", 2, true).unwrap();
+        history_manager.add_version_at("plan.md", "tweaked", "", day(20)).unwrap();
+
+        // Forty days on, only the version from day 20 is recent enough, besides the checkpoint
+        history_manager.add_version_at("plan.md", "final", "", day(40)).unwrap();
+        let ids: Vec<usize> = history_manager.list_versions("plan.md").iter().map(|v| v.version_id).collect();
+        assert_eq!(ids, vec![2, 3, 4]);
+        assert!(!Path::new(&temp_dir).join(VERSIONS_DIR).join("plan.md").join("1.json").exists());
+
+        // The mark is saved with the version, and unmarked it expires like the others
+        let mut reloaded = HistoryManager::new(&temp_dir, 100).with_retention(RetentionPolicy::new().with_max_age_days(30));
+        reloaded.load_history("plan.md").unwrap();
+        assert!(reloaded.get_version("plan.md", 2).unwrap().checkpoint);
+        reloaded.set_checkpoint("plan.md", 2, false).unwrap();
+        reloaded.add_version_at("plan.md", "later", "", day(45)).unwrap();
+        let ids: Vec<usize> = reloaded.list_versions("plan.md").iter().map(|v| v.version_id).collect();
+        assert_eq!(ids, vec![3, 4, 5]);
+        assert!(reloaded.set_checkpoint("plan.md", 1, true).is_err());
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn test_tagged_versions_are_found_and_kept() {
+        let temp_dir = temp_dir("tags");
+        let mut history_manager = HistoryManager::new(&temp_dir, 2);
+        history_manager.add_version("app.rs", "first release", "").unwrap();
+        history_manager.tag_version("app.rs", 1, "v1.0").unwrap();
+        assert!(history_manager.tag_version("app.rs", 7, "v2.0").is_err());
+        assert!(history_manager.tag_version("app.rs", 1, " ").is_err());
+
+        // Tagged versions don't count towards the limit, so two untagged ones are kept beside it
+        for content in ["two", "three", "four"] {
+            history_manager.add_version("app.rs", content, "").unwrap();
+        }
+        let ids: Vec<usize> = history_manager.list_versions("app.rs").iter().map(|v| v.version_id).collect();
+        assert_eq!(ids, vec![1, 3, 4]);
+        assert_eq!(history_manager.get_version_by_tag("app.rs", "v1.0").unwrap().content, "first release");
+        assert!(history_manager.get_version_by_tag("other.rs", "v1.0").is_none());
+
+        // Tagging another version moves the tag, and the tags are saved with the versions.
+        // Untagged, the first version counts again and is trimmed as soon as the history loads.
+        history_manager.tag_version("app.rs", 4, "v1.0").unwrap();
+        assert!(history_manager.get_version("app.rs", 1).unwrap().tags.is_empty());
+        let mut reloaded = HistoryManager::new(&temp_dir, 2);
+        reloaded.load_history("app.rs").unwrap();
+        assert_eq!(reloaded.get_version_by_tag("app.rs", "v1.0").unwrap().version_id, 4);
+        assert!(reloaded.get_version("app.rs", 1).is_none());
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn test_byte_budget_drops_oldest_versions() {
+        let temp_dir = temp_dir("bytes");
+        let mut history_manager = HistoryManager::new(&temp_dir, 100).with_retention(RetentionPolicy::new().with_max_bytes(10));
+        for content in ["aaaa", "bbbb", "cccc"] {
+            history_manager.add_version("notes.md", content, "").unwrap();
+        }
+        let contents: Vec<String> = history_manager.list_versions("notes.md").into_iter().map(|v| v.content).collect();
+        assert_eq!(contents, vec!["bbbb", "cccc"]);
+
+        // The newest version is kept even when it alone is over the budget
+        history_manager.add_version("notes.md", &"d".repeat(20), "").unwrap();
+        assert_eq!(history_manager.list_versions("notes.md").len(), 1);
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
+
+    #[test]
+    fn test_flat_layout_is_migrated_on_first_load() {
+        let temp_dir = temp_dir("migrate");
+        fs::write(Path::new(&temp_dir).join("test.txt_v1.txt"), "old one").unwrap();
+        fs::write(Path::new(&temp_dir).join("test.txt_v2.txt"), "old two").unwrap();
+        fs::write(Path::new(&temp_dir).join("other.txt_v1.txt"), "other").unwrap();
+
+        let mut history_manager = HistoryManager::new(&temp_dir, 5);
+        history_manager.load_history("test.txt").unwrap();
+        let contents: Vec<String> = history_manager.list_versions("test.txt").into_iter().map(|v| v.content).collect();
+        assert_eq!(contents, vec!["old one", "old two"]);
+        assert!(!Path::new(&temp_dir).join("test.txt_v1.txt").exists());
+        assert!(Path::new(&temp_dir).join("other.txt_v1.txt").exists()); // Left for its own file's first load
+        assert_eq!(history_manager.add_version("test.txt", "new", "").unwrap(), 3);
+
+        fs::remove_dir_all(temp_dir).unwrap();
+    }
 }
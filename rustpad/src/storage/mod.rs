@@ -1,7 +1,17 @@
-pub mod local_storage;
-pub mod ipfs_storage;
 pub mod theme;
 pub mod file_storage;
+pub mod encoding;
+pub mod history;
+pub mod activity;
+pub mod attribution;
+pub mod notifications;
+pub mod attachments;
+pub mod workspace;
+pub mod editor_sessions;
+pub mod bundle;
+pub mod trash;
+pub mod quota;
+pub mod search;
 
 
 use std::error::Error;
@@ -17,4 +27,47 @@ pub trait Storage {
 
     /// Deletes a document from storage using the identifier.
     fn delete(&self, identifier: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Saves binary content, such as an uploaded image. Stored hex-encoded through `save`
+    /// unless the backend overrides it.
+    fn save_bytes(&self, identifier: &str, content: &[u8]) -> Result<(), Box<dyn Error>> {
+        let encoded: String = content.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.save(identifier, &encoded)
+    }
+
+    /// Loads binary content saved with `save_bytes`.
+    fn load_bytes(&self, identifier: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let encoded = self.load(identifier)?;
+        (0..encoded.len())
+            .step_by(2)
+            .map(|index| {
+                let pair = encoded.get(index..index + 2).ok_or("Truncated binary content")?;
+                u8::from_str_radix(pair, 16).map_err(|e| e.into())
+            })
+            .collect()
+    }
+}
+
+/// Storage keeping everything in memory, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    files: std::sync::Mutex<std::collections::HashMap<String, String>>,
+}
+
+#[cfg(test)]
+impl Storage for MemoryStorage {
+    fn save(&self, identifier: &str, content: &str) -> Result<(), Box<dyn Error>> {
+        self.files.lock().unwrap().insert(identifier.to_string(), content.to_string());
+        Ok(())
+    }
+
+    fn load(&self, identifier: &str) -> Result<String, Box<dyn Error>> {
+        self.files.lock().unwrap().get(identifier).cloned().ok_or_else(|| "Not found".into())
+    }
+
+    fn delete(&self, identifier: &str) -> Result<(), Box<dyn Error>> {
+        self.files.lock().unwrap().remove(identifier);
+        Ok(())
+    }
 }
@@ -0,0 +1,348 @@
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use warp::Filter;
+
+use crate::auth::auth::{recover_signed_out, signed_in_user};
+use crate::editor::config::DocumentPreferences;
+use crate::i18n::{Locale, LocalizedMessage};
+use crate::storage::attribution::AttributionMap;
+
+/// Edits by one editor to one author's lines within this window share a digest
+pub fn digest_window() -> Duration {
+    Duration::hours(1)
+}
+
+/// How a connected user is engaging with a document
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Presence {
+    Active, // Watching the document, sees edits live
+    Away,   // Connected but idle or in another tab
+}
+
+/// A notification waiting for its recipient to connect
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notification {
+    /// "Bob edited 3 regions you authored in design.md"
+    EditDigest {
+        editor: String,
+        doc_id: String,
+        window_start: DateTime<Utc>,
+        regions: Vec<(usize, usize)>, // 1-based inclusive line ranges of the recipient's text
+        #[serde(flatten)]
+        message: LocalizedMessage, // Written in English until delivered
+    },
+}
+
+impl Notification {
+    /// The notification with its message written in `locale`, for delivery
+    pub fn to_locale(&self, locale: Locale) -> Notification {
+        let mut localized = self.clone();
+        let Notification::EditDigest { message, .. } = &mut localized;
+        *message = message.to_locale(locale);
+        localized
+    }
+}
+
+/// Offline notification queue, drained when the recipient next connects
+#[derive(Clone)]
+pub struct NotificationQueue {
+    pending: Arc<Mutex<HashMap<String, Vec<Notification>>>>, // Keyed by recipient
+}
+
+impl NotificationQueue {
+    /// Creates an empty queue
+    pub fn new() -> Self {
+        Self {
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Queues `notification` for `user`
+    pub fn push(&self, user: &str, notification: Notification) {
+        self.pending.lock().unwrap().entry(user.to_string()).or_default().push(notification);
+    }
+
+    /// Adds `regions` edited by `editor` to `author`'s digest for `doc_id`, starting a new
+    /// digest when none exists for the current window
+    pub fn add_edit_digest(&self, author: &str, editor: &str, doc_id: &str, regions: &[(usize, usize)], at: DateTime<Utc>) {
+        let window_start = at.duration_trunc(digest_window()).unwrap_or(at);
+        let mut pending = self.pending.lock().unwrap();
+        let queue = pending.entry(author.to_string()).or_default();
+
+        let existing = queue.iter_mut().find_map(|notification| match notification {
+            Notification::EditDigest { editor: e, doc_id: d, window_start: w, regions, message } if e == editor && d == doc_id && *w == window_start => {
+                Some((regions, message))
+            }
+            _ => None,
+        });
+        match existing {
+            Some((digest_regions, message)) => {
+                digest_regions.extend_from_slice(regions);
+                *digest_regions = merge_regions(std::mem::take(digest_regions));
+                *message = digest_message(editor, digest_regions.len(), doc_id);
+            }
+            None => {
+                let regions = merge_regions(regions.to_vec());
+                queue.push(Notification::EditDigest {
+                    editor: editor.to_string(),
+                    doc_id: doc_id.to_string(),
+                    window_start,
+                    message: digest_message(editor, regions.len(), doc_id),
+                    regions,
+                });
+            }
+        }
+    }
+
+    /// Notifications waiting for `user`, without removing them
+    pub fn pending(&self, user: &str) -> Vec<Notification> {
+        self.pending.lock().unwrap().get(user).cloned().unwrap_or_default()
+    }
+
+    /// Removes and returns the notifications waiting for `user`
+    pub fn take(&self, user: &str) -> Vec<Notification> {
+        self.pending.lock().unwrap().remove(user).unwrap_or_default()
+    }
+}
+
+impl Default for NotificationQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn digest_message(editor: &str, regions: usize, doc_id: &str) -> LocalizedMessage {
+    let key = if regions == 1 { "notification.edit_digest.one" } else { "notification.edit_digest.other" };
+    LocalizedMessage::new(Locale::En, key, &[("editor", editor.to_string()), ("regions", regions.to_string()), ("doc_id", doc_id.to_string())])
+}
+
+/// Sorts line ranges and joins the ones that overlap or touch
+fn merge_regions(mut regions: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    regions.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in regions {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Watches applied edits and tells authors who are not around when their lines change
+#[derive(Clone)]
+pub struct EditWatcher {
+    attribution: Arc<Mutex<HashMap<String, AttributionMap>>>,              // Keyed by document id
+    presence: Arc<Mutex<HashMap<(String, String), Presence>>>,             // Keyed by (document id, user); absent means offline
+    preferences: Arc<Mutex<HashMap<(String, String), DocumentPreferences>>>, // Keyed by (user, document id)
+    queue: NotificationQueue,
+}
+
+impl EditWatcher {
+    /// Creates a watcher that queues digests on `queue`
+    pub fn new(queue: NotificationQueue) -> Self {
+        Self {
+            attribution: Arc::new(Mutex::new(HashMap::new())),
+            presence: Arc::new(Mutex::new(HashMap::new())),
+            preferences: Arc::new(Mutex::new(HashMap::new())),
+            queue,
+        }
+    }
+
+    /// Records how `user` is engaging with `doc_id`
+    pub fn set_presence(&self, doc_id: &str, user: &str, presence: Presence) {
+        self.presence.lock().unwrap().insert((doc_id.to_string(), user.to_string()), presence);
+    }
+
+    /// Marks `user` as offline for `doc_id`
+    pub fn clear_presence(&self, doc_id: &str, user: &str) {
+        self.presence.lock().unwrap().remove(&(doc_id.to_string(), user.to_string()));
+    }
+
+    /// `user`'s preferences for `doc_id`
+    pub fn preferences(&self, user: &str, doc_id: &str) -> DocumentPreferences {
+        self.preferences
+            .lock()
+            .unwrap()
+            .get(&(user.to_string(), doc_id.to_string()))
+            .cloned()
+            .unwrap_or_else(DocumentPreferences::new)
+    }
+
+    /// Replaces `user`'s preferences for `doc_id`
+    pub fn set_preferences(&self, user: &str, doc_id: &str, preferences: DocumentPreferences) {
+        self.preferences.lock().unwrap().insert((user.to_string(), doc_id.to_string()), preferences);
+    }
+
+    /// Records that `editor` changed `doc_id` from `old` to `new`, queueing digests for the
+    /// authors of touched lines unless they are watching or have opted out
+    pub fn on_edit(&self, doc_id: &str, editor: &str, old: &str, new: &str, at: DateTime<Utc>) {
+        let mut attribution = self.attribution.lock().unwrap();
+        let map = attribution.entry(doc_id.to_string()).or_insert_with(|| AttributionMap::for_text(old));
+
+        let mut touched: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+        for span in map.touched_by(old, new) {
+            if span.author != editor {
+                touched.entry(span.author).or_default().push((span.start_line + 1, span.end_line));
+            }
+        }
+        map.apply_edit(editor, old, new);
+        drop(attribution);
+
+        for (author, regions) in touched {
+            if self.is_watching(doc_id, &author) || !self.preferences(&author, doc_id).notify_on_edits {
+                continue;
+            }
+            self.queue.add_edit_digest(&author, editor, doc_id, &regions, at);
+        }
+    }
+
+    /// Removes and returns the notifications waiting for `user`, for delivery on connect
+    pub fn take_notifications(&self, user: &str) -> Vec<Notification> {
+        self.queue.take(user)
+    }
+
+    fn is_watching(&self, doc_id: &str, user: &str) -> bool {
+        self.presence.lock().unwrap().get(&(doc_id.to_string(), user.to_string())) == Some(&Presence::Active)
+    }
+}
+
+/// Handler for `PUT /api/docs/:id/preferences`, setting the signed-in user's
+pub async fn preferences_handler(
+    doc_id: String,
+    user: String,
+    preferences: DocumentPreferences,
+    watcher: EditWatcher,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    watcher.set_preferences(&user, &doc_id, preferences.clone());
+    Ok(warp::reply::json(&preferences))
+}
+
+/// Route for `PUT /api/docs/:id/preferences`
+pub fn preferences_route(watcher: EditWatcher) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "docs" / String / "preferences")
+        .and(warp::put())
+        .and(signed_in_user())
+        .and(warp::body::json())
+        .and(warp::any().map(move || watcher.clone()))
+        .and_then(preferences_handler)
+        .recover(recover_signed_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth::bearer_for;
+    use chrono::TimeZone;
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap() + Duration::minutes(minutes)
+    }
+
+    /// A watcher where alice wrote the first three lines of design.md and bob the fourth
+    fn watcher() -> EditWatcher {
+        let watcher = EditWatcher::new(NotificationQueue::new());
+        watcher.on_edit("design.md", "alice", "", "one\ntwo\nthree", at(0));
+        watcher.on_edit("design.md", "bob", "one\ntwo\nthree", "one\ntwo\nthree\nfour", at(0));
+        watcher
+    }
+
+    #[test]
+    fn test_edits_coalesce_into_hourly_digest() {
+        let watcher = watcher();
+        watcher.on_edit("design.md", "bob", "one\ntwo\nthree\nfour", "ONE\ntwo\nthree\nfour", at(5));
+        watcher.on_edit("design.md", "bob", "ONE\ntwo\nthree\nfour", "ONE\ntwo\nTHREE\nfour", at(20));
+        watcher.on_edit("design.md", "bob", "ONE\ntwo\nTHREE\nfour", "ONE\ntwo\nTHREE!\nfour", at(40));
+        watcher.on_edit("design.md", "bob", "ONE\ntwo\nTHREE!\nfour", "ONE\ntwo\nTHREE!\nfour!", at(45));
+
+        // bob's later edits of his own lines leave alice's digest alone
+        let pending = watcher.queue.pending("alice");
+        assert_eq!(
+            pending,
+            vec![Notification::EditDigest {
+                editor: "bob".to_string(),
+                doc_id: "design.md".to_string(),
+                window_start: at(0),
+                regions: vec![(1, 1), (3, 3)],
+                message: digest_message("bob", 2, "design.md"),
+            }]
+        );
+        assert!(watcher.queue.pending("bob").is_empty());
+
+        // A new hour starts a new digest
+        watcher.on_edit("design.md", "bob", "ONE\ntwo\nTHREE!\nfour!", "ONE\n2\nTHREE!\nfour!", at(65));
+        assert_eq!(watcher.queue.pending("alice").len(), 2);
+    }
+
+    #[test]
+    fn test_opt_out_is_honored() {
+        let watcher = watcher();
+        let mut preferences = DocumentPreferences::new();
+        preferences.notify_on_edits = false;
+        watcher.set_preferences("alice", "design.md", preferences);
+
+        watcher.on_edit("design.md", "bob", "one\ntwo\nthree\nfour", "1\ntwo\nthree\nfour", at(5));
+        assert!(watcher.queue.pending("alice").is_empty());
+
+        // The flag is per document
+        watcher.on_edit("notes.md", "alice", "", "a", at(5));
+        watcher.on_edit("notes.md", "bob", "a", "b", at(6));
+        assert_eq!(watcher.queue.pending("alice").len(), 1);
+    }
+
+    #[test]
+    fn test_active_watchers_are_not_notified() {
+        let watcher = watcher();
+        watcher.set_presence("design.md", "alice", Presence::Active);
+        watcher.on_edit("design.md", "bob", "one\ntwo\nthree\nfour", "1\ntwo\nthree\nfour", at(5));
+        assert!(watcher.queue.pending("alice").is_empty());
+
+        // Away counts as not watching
+        watcher.set_presence("design.md", "alice", Presence::Away);
+        watcher.on_edit("design.md", "bob", "1\ntwo\nthree\nfour", "1\n2\nthree\nfour", at(6));
+        assert_eq!(watcher.queue.pending("alice").len(), 1);
+    }
+
+    #[test]
+    fn test_delivery_on_reconnect() {
+        let watcher = watcher();
+        watcher.set_presence("design.md", "alice", Presence::Active);
+        watcher.clear_presence("design.md", "alice");
+        watcher.on_edit("design.md", "bob", "one\ntwo\nthree\nfour", "one\n2\nthree\nfour", at(5));
+
+        let delivered = watcher.take_notifications("alice");
+        assert_eq!(delivered.len(), 1);
+        let Notification::EditDigest { regions, message, .. } = &delivered[0];
+        assert_eq!(regions, &vec![(2, 2)]);
+        assert_eq!(message.message, "bob edited 1 region you authored in design.md");
+        assert_eq!(message.message_key, "notification.edit_digest.one");
+
+        // Written in the recipient's language on the way out, keeping the key and parameters
+        let Notification::EditDigest { message: localized, .. } = delivered[0].to_locale(Locale::De);
+        assert_eq!(localized.message, "bob hat 1 Bereich bearbeitet, den du in design.md verfasst hast");
+        assert_eq!(localized.params, message.params);
+        let frame = serde_json::to_value(&delivered[0]).unwrap();
+        assert_eq!((frame["type"].as_str(), frame["message_key"].as_str()), (Some("edit_digest"), Some("notification.edit_digest.one")));
+
+        // Delivered once
+        assert!(watcher.take_notifications("alice").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_preferences_route() {
+        let watcher = watcher();
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/docs/design.md/preferences")
+            .header("authorization", bearer_for("alice"))
+            .json(&serde_json::json!({ "notify_on_edits": false }))
+            .reply(&preferences_route(watcher.clone()))
+            .await;
+        assert_eq!(response.status(), 200);
+        assert!(!watcher.preferences("alice", "design.md").notify_on_edits);
+    }
+}
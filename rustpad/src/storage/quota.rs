@@ -0,0 +1,595 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use crate::auth::auth::{authenticated_user, sign_in_required};
+use crate::i18n::{Locale, LocalizedMessage};
+use crate::storage::attachments::{AttachmentStore, MAX_ATTACHMENT_SIZE};
+use crate::storage::workspace::Workspaces;
+use crate::storage::Storage;
+use crate::tokens::is_admin;
+
+/// Code of the error sent when a request would take a user over one of their limits
+pub const QUOTA_EXCEEDED: &str = "quota_exceeded";
+
+/// Storage identifier under which the per-user overrides are persisted
+const OVERRIDES_ID: &str = "quota_overrides.json";
+
+/// What each user may own. Documents count against the owner of their workspace, trashed ones
+/// until they are purged, and storage is their content and attachments together.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct QuotaLimits {
+    pub max_documents: u64,
+    pub max_storage_bytes: u64,
+    pub max_attachment_bytes: u64, // Largest single attachment
+}
+
+impl QuotaLimits {
+    /// 500 documents and 1GB, with attachments up to `MAX_ATTACHMENT_SIZE`
+    pub fn new() -> Self {
+        Self { max_documents: 500, max_storage_bytes: 1024 * 1024 * 1024, max_attachment_bytes: MAX_ATTACHMENT_SIZE }
+    }
+
+    /// These limits with the ones `exception` sets taking their place
+    fn overridden_by(self, exception: &QuotaOverride) -> Self {
+        Self {
+            max_documents: exception.max_documents.unwrap_or(self.max_documents),
+            max_storage_bytes: exception.max_storage_bytes.unwrap_or(self.max_storage_bytes),
+            max_attachment_bytes: exception.max_attachment_bytes.unwrap_or(self.max_attachment_bytes),
+        }
+    }
+}
+
+impl Default for QuotaLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An admin's exception to the global limits for one user; limits it leaves unset stay global
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct QuotaOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_documents: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_storage_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attachment_bytes: Option<u64>,
+}
+
+/// What a user owns, as counted against their limits
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Usage {
+    pub documents: u64,
+    pub storage_bytes: u64,
+}
+
+/// The limit a refused request would have gone over
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaResource {
+    Documents,
+    StorageBytes,
+    AttachmentBytes,
+}
+
+/// A request refused for going over a limit: what is used, what the request needed, and the limit
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub resource: QuotaResource,
+    pub usage: u64,
+    pub requested: u64,
+    pub limit: u64,
+}
+
+impl QuotaExceeded {
+    /// The message telling the user which limit they hit, written in `locale`
+    pub fn message(&self, locale: Locale) -> LocalizedMessage {
+        let key = match self.resource {
+            QuotaResource::Documents => "error.quota_exceeded.documents",
+            QuotaResource::StorageBytes => "error.quota_exceeded.storage_bytes",
+            QuotaResource::AttachmentBytes => "error.quota_exceeded.attachment_bytes",
+        };
+        let params = [("usage", self.usage.to_string()), ("requested", self.requested.to_string()), ("limit", self.limit.to_string())];
+        LocalizedMessage::new(locale, key, &params)
+    }
+
+    /// The error frame: `{"type": "error", "code": "quota_exceeded", "resource", "usage",
+    /// "requested", "limit"}` with the message, its key and parameters
+    pub fn frame(&self) -> serde_json::Value {
+        let mut frame = self.message(Locale::En).error_frame();
+        frame["code"] = QUOTA_EXCEEDED.into();
+        frame["resource"] = serde_json::to_value(self.resource).unwrap();
+        frame["usage"] = self.usage.into();
+        frame["requested"] = self.requested.into();
+        frame["limit"] = self.limit.into();
+        frame
+    }
+
+    /// The frame as a 403 reply
+    pub fn reply(&self) -> warp::reply::Response {
+        warp::reply::with_status(warp::reply::json(&self.frame()), StatusCode::FORBIDDEN).into_response()
+    }
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message(Locale::En).message)
+    }
+}
+
+/// A limit and how much of it is used
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allowance {
+    pub used: u64,
+    pub limit: u64,
+}
+
+/// What `GET /api/user/usage` tells a user about their quota
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UsageReport {
+    pub user: String,
+    pub documents: Allowance,
+    pub storage_bytes: Allowance,
+    pub max_attachment_bytes: u64,
+    pub overridden: bool, // An admin set limits of the user's own
+}
+
+/// Holds users to their `QuotaLimits`. Usage is counted from the workspaces and the attachment
+/// store the first time a user is checked, then kept up to date as documents are added, moved,
+/// imported, uploaded to and purged; `reconcile` recounts it to correct any drift, such as from
+/// edits growing documents.
+#[derive(Clone)]
+pub struct QuotaManager {
+    workspaces: Workspaces,
+    documents: Arc<dyn Storage + Send + Sync>, // Document content, by doc id
+    attachments: AttachmentStore,
+    limits: QuotaLimits,
+    overrides: Arc<Mutex<HashMap<String, QuotaOverride>>>, // By user
+    usage: Arc<Mutex<HashMap<String, Usage>>>,             // Counted usage, by user
+    storage: Option<Arc<dyn Storage + Send + Sync>>,       // Where the overrides are persisted
+}
+
+impl QuotaManager {
+    /// Holds the owners of `workspaces` to `limits`, counting the documents in `documents` and
+    /// their uploads to `attachments`
+    pub fn new(workspaces: Workspaces, documents: Arc<dyn Storage + Send + Sync>, attachments: AttachmentStore, limits: QuotaLimits) -> Self {
+        Self {
+            workspaces,
+            documents,
+            attachments,
+            limits,
+            overrides: Arc::new(Mutex::new(HashMap::new())),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            storage: None,
+        }
+    }
+
+    /// Persists the per-user overrides through `storage`, loading any previously saved ones
+    pub fn with_storage(mut self, storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        if let Ok(saved) = storage.load(OVERRIDES_ID) {
+            let saved: HashMap<String, QuotaOverride> = serde_json::from_str(&saved).unwrap_or_default();
+            self.overrides.lock().unwrap().extend(saved);
+        }
+        self.storage = Some(storage);
+        self
+    }
+
+    /// The limits of `user`: the global ones, with their override taking precedence
+    pub fn limits_for(&self, user: &str) -> QuotaLimits {
+        match self.overrides.lock().unwrap().get(user) {
+            Some(exception) => self.limits.overridden_by(exception),
+            None => self.limits,
+        }
+    }
+
+    /// Every per-user override, by user
+    pub fn overrides(&self) -> HashMap<String, QuotaOverride> {
+        self.overrides.lock().unwrap().clone()
+    }
+
+    /// Gives `user` limits of their own; `None` goes back to the global limits
+    pub fn set_override(&self, user: &str, exception: Option<QuotaOverride>) {
+        let mut overrides = self.overrides.lock().unwrap();
+        match exception {
+            Some(exception) => overrides.insert(user.to_string(), exception),
+            None => overrides.remove(user),
+        };
+        if let Some(storage) = &self.storage {
+            let saved = serde_json::to_string(&*overrides).map_err(|e| e.to_string()).and_then(|json| storage.save(OVERRIDES_ID, &json).map_err(|e| e.to_string()));
+            if let Err(e) = saved {
+                eprintln!("Failed to persist quota overrides: {}", e);
+            }
+        }
+    }
+
+    /// What `user` owns, counted on first use and kept up to date after
+    pub fn usage(&self, user: &str) -> Usage {
+        if let Some(usage) = self.usage.lock().unwrap().get(user) {
+            return *usage;
+        }
+        // Counting reads the workspaces and storage, so it happens without holding the cache
+        let counted = self.recount(user);
+        *self.usage.lock().unwrap().entry(user.to_string()).or_insert(counted)
+    }
+
+    /// Counts what `user` owns from scratch, without touching the cached usage
+    pub fn recount(&self, user: &str) -> Usage {
+        let docs = self.workspaces.docs_owned_by(user);
+        let attachments = self.attachments.sizes_by_document();
+        let storage_bytes = docs.iter().map(|doc_id| self.content_bytes(doc_id) + attachments.get(doc_id).copied().unwrap_or(0)).sum();
+        Usage { documents: docs.len() as u64, storage_bytes }
+    }
+
+    /// The bytes `doc_id` takes: its content and its attachments
+    pub fn document_bytes(&self, doc_id: &str) -> u64 {
+        self.content_bytes(doc_id) + self.attachments.sizes_by_document().get(doc_id).copied().unwrap_or(0)
+    }
+
+    /// The user `doc_id` counts against, if it is in a workspace
+    pub fn owner_of(&self, doc_id: &str) -> Option<String> {
+        self.workspaces.owner_of(doc_id)
+    }
+
+    /// Refuses one more document for `user` if they have as many as they may
+    pub fn check_document(&self, user: &str) -> Result<(), QuotaExceeded> {
+        let (usage, limit) = (self.usage(user).documents, self.limits_for(user).max_documents);
+        if usage + 1 > limit {
+            return Err(QuotaExceeded { resource: QuotaResource::Documents, usage, requested: 1, limit });
+        }
+        Ok(())
+    }
+
+    /// Refuses `bytes` more storage for `user` if they would go over their limit
+    pub fn check_bytes(&self, user: &str, bytes: u64) -> Result<(), QuotaExceeded> {
+        let (usage, limit) = (self.usage(user).storage_bytes, self.limits_for(user).max_storage_bytes);
+        if usage.saturating_add(bytes) > limit {
+            return Err(QuotaExceeded { resource: QuotaResource::StorageBytes, usage, requested: bytes, limit });
+        }
+        Ok(())
+    }
+
+    /// Refuses an attachment of `bytes` for `user` if it is larger than they may upload, or
+    /// would take them over their storage
+    pub fn check_attachment(&self, user: &str, bytes: u64) -> Result<(), QuotaExceeded> {
+        let limit = self.limits_for(user).max_attachment_bytes;
+        if bytes > limit {
+            return Err(QuotaExceeded { resource: QuotaResource::AttachmentBytes, usage: 0, requested: bytes, limit });
+        }
+        self.check_bytes(user, bytes)
+    }
+
+    /// Adjusts the usage of `user` after a change: `documents` and `bytes` more, or fewer when
+    /// negative. Users not counted yet are left alone; their first count includes the change.
+    pub fn record(&self, user: &str, documents: i64, bytes: i64) {
+        if let Some(usage) = self.usage.lock().unwrap().get_mut(user) {
+            usage.documents = usage.documents.saturating_add_signed(documents);
+            usage.storage_bytes = usage.storage_bytes.saturating_add_signed(bytes);
+        }
+    }
+
+    /// Recounts every user counted so far and corrects the ones whose usage drifted, returning them
+    pub fn reconcile(&self) -> Vec<String> {
+        let users: Vec<String> = self.usage.lock().unwrap().keys().cloned().collect();
+        let mut corrected = Vec::new();
+        for user in users {
+            let counted = self.recount(&user);
+            let mut usage = self.usage.lock().unwrap();
+            match usage.get(&user) {
+                Some(cached) if *cached != counted => {
+                    eprintln!("Corrected the usage of {} from {:?} to {:?}", user, cached, counted);
+                    usage.insert(user.clone(), counted);
+                    corrected.push(user);
+                }
+                _ => {}
+            }
+        }
+        corrected
+    }
+
+    /// What `user` uses of their limits
+    pub fn report(&self, user: &str) -> UsageReport {
+        let (usage, limits) = (self.usage(user), self.limits_for(user));
+        UsageReport {
+            user: user.to_string(),
+            documents: Allowance { used: usage.documents, limit: limits.max_documents },
+            storage_bytes: Allowance { used: usage.storage_bytes, limit: limits.max_storage_bytes },
+            max_attachment_bytes: limits.max_attachment_bytes,
+            overridden: self.overrides.lock().unwrap().contains_key(user),
+        }
+    }
+
+    fn content_bytes(&self, doc_id: &str) -> u64 {
+        self.documents.load(doc_id).map(|content| content.len() as u64).unwrap_or(0)
+    }
+}
+
+/// Runs `reconcile` every hour
+pub fn spawn_reconciler(quotas: QuotaManager) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            let quotas = quotas.clone();
+            // Recounting reads every document of the users counted, so keep it off the async workers
+            let corrected = tokio::task::spawn_blocking(move || quotas.reconcile()).await.unwrap_or_default();
+            if !corrected.is_empty() {
+                println!("Corrected the usage of {} users", corrected.len());
+            }
+        }
+    })
+}
+
+fn error(status: StatusCode, message: &str) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "type": "error", "message": message })), status).into_response()
+}
+
+/// `GET /api/user/usage` tells the signed-in user what they use of their limits.
+/// `GET /api/admin/quotas` lists the per-user overrides, `PUT /api/admin/quotas/:user` sets one
+/// and `DELETE /api/admin/quotas/:user` removes it, all with the admin key as bearer token.
+pub fn quota_routes(quotas: QuotaManager, admin_key: Option<String>) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let admin = warp::header::optional::<String>("authorization").and_then(move |authorization: Option<String>| {
+        let admin = is_admin(admin_key.as_deref(), authorization.as_deref());
+        async move { if admin { Ok(()) } else { Err(warp::reject::custom(NotAdmin)) } }
+    });
+    let with_quotas = warp::any().map(move || quotas.clone());
+
+    let usage = warp::path!("api" / "user" / "usage")
+        .and(warp::get())
+        .and(authenticated_user())
+        .and(with_quotas.clone())
+        .map(|user: Option<String>, quotas: QuotaManager| match user {
+            Some(user) => warp::reply::json(&quotas.report(&user)).into_response(),
+            None => sign_in_required(),
+        });
+    let list = warp::path!("api" / "admin" / "quotas")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(with_quotas.clone())
+        .map(|(), quotas: QuotaManager| warp::reply::json(&quotas.overrides()).into_response());
+    let set = warp::path!("api" / "admin" / "quotas" / String)
+        .and(warp::put())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(with_quotas.clone())
+        .map(|user: String, (), exception: QuotaOverride, quotas: QuotaManager| {
+            quotas.set_override(&user, Some(exception));
+            warp::reply::json(&quotas.report(&user)).into_response()
+        });
+    let clear = warp::path!("api" / "admin" / "quotas" / String)
+        .and(warp::delete())
+        .and(admin)
+        .and(with_quotas)
+        .map(|user: String, (), quotas: QuotaManager| {
+            quotas.set_override(&user, None);
+            StatusCode::NO_CONTENT.into_response()
+        });
+
+    usage.or(list).unify().or(set).unify().or(clear).unify().recover(|rejection: warp::Rejection| async move {
+        if rejection.find::<NotAdmin>().is_some() {
+            Ok(error(StatusCode::FORBIDDEN, "Managing quotas needs the admin key"))
+        } else {
+            Err(rejection)
+        }
+    })
+    .unify()
+}
+
+#[derive(Debug)]
+struct NotAdmin;
+
+impl warp::reject::Reject for NotAdmin {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth::bearer_for;
+    use crate::storage::MemoryStorage;
+    use crate::editor::diff_engine::DiffOperation;
+    use crate::networking::chat_sync::ChatSyncManager;
+    use crate::networking::protocol::DeltaMessage;
+    use crate::networking::room_host::{MemoryLimits, PastePolicy, RoomHost};
+    use crate::networking::revision_log::Receipt;
+    use crate::storage::activity::ActivityFeeds;
+    use crate::storage::attachments::attachment_routes;
+    use crate::storage::history::HistoryManager;
+    use crate::storage::trash::DocumentTrash;
+    use crate::storage::workspace::workspace_routes;
+    use chrono::{Duration, Utc};
+    use std::time::Instant;
+
+    /// Olga's "Team" workspace, where ed is an editor, and ed's own "Side" workspace, all held
+    /// to one set of quotas
+    struct Server {
+        quotas: QuotaManager,
+        workspaces: Workspaces,
+        attachments: AttachmentStore,
+        documents: Arc<MemoryStorage>,
+        team: String,
+        side: String,
+    }
+
+    fn server(limits: QuotaLimits) -> Server {
+        let documents = Arc::new(MemoryStorage::default());
+        let attachments = AttachmentStore::new(Arc::new(MemoryStorage::default()));
+        let workspaces = Workspaces::new();
+        let quotas = QuotaManager::new(workspaces.clone(), documents.clone(), attachments.clone(), limits);
+        let workspaces = workspaces.with_quotas(quotas.clone());
+        let attachments = attachments.with_quotas(quotas.clone());
+        let team = workspaces.create("olga", "Team").unwrap().id;
+        workspaces.set_member(&team, "olga", "ed", None).unwrap();
+        let side = workspaces.create("ed", "Side").unwrap().id;
+        Server { quotas, workspaces, attachments, documents, team, side }
+    }
+
+    fn paste(seq: u64, text: &str) -> DeltaMessage {
+        DeltaMessage { client_id: String::new(), seq, base_revision: 0, paste: true, bulk: None, operations: vec![DiffOperation::Insert(0, text.to_string())] }
+    }
+
+    #[tokio::test]
+    async fn test_limits_are_enforced_at_each_entry_point() {
+        let server = server(QuotaLimits { max_documents: 2, max_storage_bytes: 100, max_attachment_bytes: 40 });
+        server.documents.save("a.md", &"a".repeat(60)).unwrap();
+
+        // Creating a document past the limit
+        let workspace_api = workspace_routes(server.workspaces.clone(), ActivityFeeds::new(10));
+        let add = |doc: &str| {
+            warp::test::request().method("POST").path(&format!("/api/workspaces/{}/docs", server.team)).header("authorization", bearer_for("ed")).json(&serde_json::json!({ "doc": doc }))
+        };
+        assert_eq!(add("a.md").reply(&workspace_api).await.status(), 200);
+        assert_eq!(add("b.md").reply(&workspace_api).await.status(), 200);
+        let refused = add("c.md").reply(&workspace_api).await;
+        assert_eq!(refused.status(), 403);
+        let frame: serde_json::Value = serde_json::from_slice(refused.body()).unwrap();
+        assert_eq!((frame["code"].as_str(), frame["resource"].as_str()), (Some(QUOTA_EXCEEDED), Some("documents")));
+        assert_eq!((frame["usage"].as_u64(), frame["limit"].as_u64()), (Some(2), Some(2)));
+        assert_eq!(frame["message_key"], "error.quota_exceeded.documents");
+        let error = server.workspaces.add_doc(&server.team, "ed", "c.md").unwrap_err();
+        assert_eq!(error, "You have 2 of your 2 documents; delete one to create another");
+
+        // Uploading an attachment larger than allowed, then one the storage has no room for
+        let attachment_api = attachment_routes(server.attachments.clone());
+        let upload = |bytes: usize| {
+            warp::test::request().method("POST").path("/api/docs/a.md/attachments?name=notes.txt").header("content-type", "text/plain").body("n".repeat(bytes))
+        };
+        let refused = upload(41).reply(&attachment_api).await;
+        assert_eq!(refused.status(), 403);
+        let frame: serde_json::Value = serde_json::from_slice(refused.body()).unwrap();
+        assert_eq!((frame["resource"].as_str(), frame["requested"].as_u64(), frame["limit"].as_u64()), (Some("attachment_bytes"), Some(41), Some(40)));
+        assert_eq!(upload(30).reply(&attachment_api).await.status(), 201);
+        let refused = upload(20).reply(&attachment_api).await;
+        let frame: serde_json::Value = serde_json::from_slice(refused.body()).unwrap();
+        let exceeded = QuotaExceeded { resource: QuotaResource::StorageBytes, usage: 90, requested: 20, limit: 100 };
+        assert_eq!(frame, exceeded.frame());
+
+        // Pasting a large block into a document of the full account
+        let rooms = RoomHost::new(Arc::new(MemoryStorage::default()), MemoryLimits::new())
+            .with_paste_policy(PastePolicy { confirm_above: 10, ..PastePolicy::new() })
+            .with_quotas(server.quotas.clone());
+        let now = Instant::now();
+        rooms.join("b.md", "ed-1", now).unwrap();
+        let reject = rooms.receive("b.md", "ed-1", &paste(0, &"p".repeat(15)), now).unwrap_err();
+        assert_eq!(reject.quota, Some(QuotaExceeded { requested: 15, ..exceeded }));
+        assert_eq!(reject.reason, "This needs 15 more bytes, and 90 of your 100 bytes are used");
+        assert!(matches!(rooms.receive("b.md", "ed-1", &paste(1, "small"), now), Ok(Receipt::Applied(..))));
+    }
+
+    #[test]
+    fn test_incremental_usage_matches_a_recount() {
+        let server = server(QuotaLimits::new());
+        let history_dir = std::env::temp_dir().join(format!("rustpad-quota-{}", std::process::id()));
+        let history = Arc::new(Mutex::new(HistoryManager::new(&history_dir.to_string_lossy(), 10)));
+        let rooms = RoomHost::new(Arc::new(MemoryStorage::default()), MemoryLimits::new());
+        let trash = DocumentTrash::new(server.documents.clone(), history, ChatSyncManager::new(), server.attachments.clone(), server.workspaces.clone(), rooms);
+        let both = |server: &Server| ["olga", "ed"].map(|user| (server.quotas.usage(user), server.quotas.recount(user)));
+        assert_eq!(both(&server), [(Usage::default(), Usage::default()); 2]);
+
+        // Creates, uploads, a move to another owner and a purge, counted as they happen
+        let now = Utc::now();
+        server.documents.save("a.md", "alpha").unwrap();
+        server.workspaces.add_doc(&server.team, "olga", "a.md").unwrap();
+        server.documents.save("b.md", "bravo!!").unwrap();
+        server.workspaces.add_doc(&server.team, "ed", "b.md").unwrap();
+        server.attachments.upload("a.md", "a.txt", "text/plain", b"twelve bytes", now).unwrap();
+        server.attachments.upload("b.md", "b.txt", "text/plain", b"seven b", now).unwrap();
+        server.attachments.upload("b.md", "b.txt", "text/plain", b"seven b", now).unwrap(); // Same content, counted once
+        server.workspaces.add_doc(&server.team, "ed", "c.md").unwrap();
+        server.workspaces.add_doc(&server.side, "ed", "b.md").unwrap();
+        for (usage, recount) in both(&server) {
+            assert_eq!(usage, recount);
+        }
+        assert_eq!(server.quotas.usage("olga"), Usage { documents: 2, storage_bytes: 5 + 12 });
+
+        trash.delete("a.md", "olga", now).unwrap();
+        assert_eq!(server.quotas.usage("olga").documents, 2); // Trashed documents count until purged
+        assert_eq!(trash.purge_expired(now + Duration::days(31)), vec!["a.md".to_string()]);
+        for (usage, recount) in both(&server) {
+            assert_eq!(usage, recount);
+        }
+        assert_eq!(server.quotas.usage("olga"), Usage { documents: 1, storage_bytes: 0 });
+        assert_eq!(server.quotas.usage("ed"), Usage { documents: 1, storage_bytes: 7 + 7 });
+        let _ = std::fs::remove_dir_all(&history_dir);
+    }
+
+    #[test]
+    fn test_overrides_take_precedence_over_global_limits() {
+        let server = server(QuotaLimits { max_documents: 1, ..QuotaLimits::new() });
+        let storage = Arc::new(MemoryStorage::default());
+        let quotas = server.quotas.clone().with_storage(storage.clone());
+        quotas.set_override("ed", Some(QuotaOverride { max_documents: Some(3), ..QuotaOverride::default() }));
+
+        // Only the limits the override sets change, and only for its user
+        assert_eq!(quotas.limits_for("ed"), QuotaLimits { max_documents: 3, ..QuotaLimits::new() });
+        assert_eq!(quotas.limits_for("olga").max_documents, 1);
+        for doc in ["a.md", "b.md", "c.md"] {
+            server.workspaces.add_doc(&server.side, "ed", doc).unwrap();
+        }
+        assert!(server.workspaces.add_doc(&server.side, "ed", "d.md").is_err());
+        server.workspaces.add_doc(&server.team, "olga", "e.md").unwrap();
+        assert!(server.workspaces.add_doc(&server.team, "olga", "f.md").is_err());
+
+        // Overrides are kept across restarts, until removed
+        let restarted = QuotaManager::new(server.workspaces.clone(), server.documents.clone(), server.attachments.clone(), QuotaLimits::new()).with_storage(storage.clone());
+        assert_eq!(restarted.limits_for("ed").max_documents, 3);
+        quotas.set_override("ed", None);
+        assert_eq!(quotas.limits_for("ed").max_documents, 1);
+        assert!(QuotaManager::new(server.workspaces.clone(), server.documents.clone(), server.attachments.clone(), QuotaLimits::new()).with_storage(storage).overrides().is_empty());
+    }
+
+    #[test]
+    fn test_reconciliation_corrects_drifted_usage() {
+        let server = server(QuotaLimits::new());
+        server.documents.save("a.md", "alpha").unwrap();
+        server.workspaces.add_doc(&server.team, "olga", "a.md").unwrap();
+        assert_eq!(server.quotas.usage("olga"), Usage { documents: 1, storage_bytes: 5 });
+        assert!(server.quotas.reconcile().is_empty());
+
+        // A corrupted counter, and edits that grew the document behind the quotas' back
+        server.quotas.record("olga", 5, 1000);
+        server.documents.save("a.md", "alpha, beta").unwrap();
+        assert_eq!(server.quotas.usage("olga"), Usage { documents: 6, storage_bytes: 1005 });
+        assert_eq!(server.quotas.reconcile(), vec!["olga".to_string()]);
+        assert_eq!(server.quotas.usage("olga"), Usage { documents: 1, storage_bytes: 11 });
+        assert!(server.quotas.reconcile().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_usage_endpoint_and_admin_overrides() {
+        let server = server(QuotaLimits { max_documents: 10, max_storage_bytes: 1000, max_attachment_bytes: 100 });
+        server.documents.save("a.md", "alpha").unwrap();
+        server.workspaces.add_doc(&server.team, "olga", "a.md").unwrap();
+        let routes = quota_routes(server.quotas.clone(), Some("admin".to_string()));
+
+        let response = warp::test::request().path("/api/user/usage").header("authorization", bearer_for("olga")).reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        let usage: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            usage,
+            serde_json::json!({
+                "user": "olga",
+                "documents": { "used": 1, "limit": 10 },
+                "storage_bytes": { "used": 5, "limit": 1000 },
+                "max_attachment_bytes": 100,
+                "overridden": false,
+            })
+        );
+        assert_eq!(warp::test::request().path("/api/user/usage?user=olga").reply(&routes).await.status(), 401);
+
+        // Overrides need the admin key
+        let set = || warp::test::request().method("PUT").path("/api/admin/quotas/olga").json(&serde_json::json!({ "max_documents": 20 }));
+        assert_eq!(set().reply(&routes).await.status(), 403);
+        let response = set().header("authorization", "Bearer admin").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        let report: UsageReport = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!((report.documents, report.overridden), (Allowance { used: 1, limit: 20 }, true));
+        let response = warp::test::request().path("/api/admin/quotas").header("authorization", "Bearer admin").reply(&routes).await;
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(), serde_json::json!({ "olga": { "max_documents": 20 } }));
+
+        let clear = warp::test::request().method("DELETE").path("/api/admin/quotas/olga").header("authorization", "Bearer admin");
+        assert_eq!(clear.reply(&routes).await.status(), 204);
+        assert_eq!(server.quotas.report("olga").documents.limit, 10);
+    }
+}
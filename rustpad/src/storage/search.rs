@@ -0,0 +1,458 @@
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use crate::auth::auth::{authenticated_user, sign_in_required};
+use crate::editor::find::{FindQuery, Finder};
+use crate::networking::room_host::RoomHost;
+use crate::storage::workspace::Workspaces;
+
+/// Results a search returns without a `limit`
+pub const DEFAULT_LIMIT: usize = 100;
+
+/// Most results one search returns, whatever its `limit`
+pub const MAX_RESULTS: usize = 1000;
+
+/// Characters of the line kept on each side of a match in its snippet
+const SNIPPET_CONTEXT: usize = 40;
+
+/// How much a workspace search may scan
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchLimits {
+    pub max_doc_bytes: usize, // Larger documents are skipped with a note
+    pub max_scan: Duration,   // Per document; matches found later are skipped with a note
+    pub concurrency: usize,   // Documents read and scanned at once
+}
+
+impl SearchLimits {
+    /// Documents up to 2MB, a quarter second on each, four at a time
+    pub fn new() -> Self {
+        Self { max_doc_bytes: 2 * 1024 * 1024, max_scan: Duration::from_millis(250), concurrency: 4 }
+    }
+}
+
+impl Default for SearchLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A search of a workspace, as asked for with `?q=<pattern>&regex=true&case=true&limit=<n>&prefix=<doc id prefix>`
+#[derive(Debug, Clone)]
+pub struct SearchRequest {
+    pub finder: Finder,
+    pub limit: usize,           // At most `MAX_RESULTS`
+    pub prefix: Option<String>, // Only documents whose id starts with it
+}
+
+impl SearchRequest {
+    /// Reads a search from its query string, refusing invalid and oversized patterns. Patterns
+    /// are literal and ignore case unless `regex` and `case` say otherwise.
+    pub fn parse(query: &HashMap<String, String>) -> Result<Self, String> {
+        let flag = |name: &str| match query.get(name).map(String::as_str) {
+            None | Some("false") => Ok(false),
+            Some("true") => Ok(true),
+            Some(other) => Err(format!("{} must be true or false, not {:?}", name, other)),
+        };
+        let find = FindQuery { pattern: query.get("q").cloned().unwrap_or_default(), regex: flag("regex")?, case_sensitive: flag("case")? };
+        let limit = match query.get("limit") {
+            Some(limit) => limit.parse::<usize>().map_err(|_| format!("Invalid limit {:?}", limit))?,
+            None => DEFAULT_LIMIT,
+        };
+        Ok(Self {
+            finder: Finder::new(&find)?,
+            limit: limit.clamp(1, MAX_RESULTS),
+            prefix: query.get("prefix").filter(|prefix| !prefix.is_empty()).cloned(),
+        })
+    }
+}
+
+/// A match, with the line it is on for context
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub doc_id: String,
+    pub title: String, // The room's title, or its id without one
+    pub line: usize,   // From 1
+    pub snippet: String, // The line, cut to some context around the match
+    pub span: (usize, usize), // The match, as a byte range of `snippet`; cut at the end of the line
+}
+
+/// What a search streams, one per line of its response
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SearchEvent {
+    Hit(SearchHit),
+    Skipped { doc_id: String, reason: String }, // A document not searched, or only partly
+    Done { results: usize, documents: usize, truncated: bool }, // Always last; `truncated` when the limit stopped the search
+}
+
+/// Searches the live content of a workspace's documents: what is in memory for loaded rooms,
+/// and what was saved for the others, read from storage a few at a time as the search goes
+#[derive(Clone)]
+pub struct WorkspaceSearch {
+    host: RoomHost,
+    workspaces: Workspaces,
+    limits: SearchLimits,
+}
+
+impl WorkspaceSearch {
+    /// Searches the documents of `workspaces`, as `host` has them
+    pub fn new(host: RoomHost, workspaces: Workspaces) -> Self {
+        Self { host, workspaces, limits: SearchLimits::new() }
+    }
+
+    /// Scans within `limits` instead
+    pub fn with_limits(self, limits: SearchLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    /// The documents of workspace `id` that `user` may read, in the workspace's order. Deleted
+    /// documents are left out; users with access to none of them, and no role in the
+    /// workspace, are refused.
+    pub fn readable(&self, id: &str, user: &str, prefix: Option<&str>) -> Result<Vec<String>, String> {
+        let workspace = self.workspaces.get(id).ok_or_else(|| format!("Unknown workspace {}", id))?;
+        let readable: Vec<String> = workspace
+            .docs
+            .iter()
+            .filter(|doc| !workspace.trashed.contains_key(*doc) && workspace.doc_role(doc, user).is_some())
+            .cloned()
+            .collect();
+        if readable.is_empty() && !workspace.members.contains_key(user) {
+            return Err(format!("{} has no access to {}", user, id));
+        }
+        Ok(readable.into_iter().filter(|doc| prefix.is_none_or(|prefix| doc.starts_with(prefix))).collect())
+    }
+
+    /// Starts searching the documents of workspace `id` that `user` may read, returning the
+    /// results as they are found. Documents are scanned `concurrency` at a time, off the async
+    /// workers, and their results come in the workspace's order; the search ends with `Done`,
+    /// and stops early once the receiver is dropped.
+    pub fn search(&self, id: &str, user: &str, request: &SearchRequest) -> Result<mpsc::Receiver<SearchEvent>, String> {
+        let docs = self.readable(id, user, request.prefix.as_deref())?;
+        let (sender, receiver) = mpsc::channel(64);
+        let (search, request) = (self.clone(), request.clone());
+        tokio::spawn(async move {
+            let total = docs.len();
+            let scans = stream::iter(docs)
+                .map(|doc| {
+                    let (search, finder, limit) = (search.clone(), request.finder.clone(), request.limit);
+                    async move {
+                        let scanned = doc.clone();
+                        tokio::task::spawn_blocking(move || search.scan_document(&scanned, &finder, limit)).await.unwrap_or_else(|e| {
+                            vec![SearchEvent::Skipped { doc_id: doc, reason: format!("Search failed: {}", e) }]
+                        })
+                    }
+                })
+                .buffered(search.limits.concurrency.max(1));
+            futures_util::pin_mut!(scans);
+
+            let (mut results, mut documents, mut truncated) = (0, 0, false);
+            while let Some(events) = scans.next().await {
+                documents += 1;
+                for event in events {
+                    if matches!(event, SearchEvent::Hit(_)) {
+                        if results == request.limit {
+                            truncated = true;
+                            break;
+                        }
+                        results += 1;
+                    }
+                    if sender.send(event).await.is_err() {
+                        return; // Nobody is listening anymore
+                    }
+                }
+                if results == request.limit && documents < total {
+                    truncated = true;
+                }
+                if truncated {
+                    break;
+                }
+            }
+            let _ = sender.send(SearchEvent::Done { results, documents, truncated }).await;
+        });
+        Ok(receiver)
+    }
+
+    /// Reads `doc_id` and finds up to `limit` matches in it
+    fn scan_document(&self, doc_id: &str, finder: &Finder, limit: usize) -> Vec<SearchEvent> {
+        match self.host.content(doc_id) {
+            Ok((text, title)) => scan(doc_id, title.as_deref().unwrap_or(doc_id), &text, finder, limit, self.limits),
+            Err(e) => vec![SearchEvent::Skipped { doc_id: doc_id.to_string(), reason: e }],
+        }
+    }
+}
+
+/// Finds up to `limit` matches in `text`, the document `doc_id` titled `title`, within
+/// `limits`. What the limits cut off is noted as `Skipped`.
+fn scan(doc_id: &str, title: &str, text: &str, finder: &Finder, limit: usize, limits: SearchLimits) -> Vec<SearchEvent> {
+    let skipped = |reason: String| SearchEvent::Skipped { doc_id: doc_id.to_string(), reason };
+    if text.len() > limits.max_doc_bytes {
+        return vec![skipped(format!("Larger than the {} bytes searched", limits.max_doc_bytes))];
+    }
+    let started = Instant::now();
+    let mut events = Vec::new();
+    let (mut line, mut line_start, mut counted) = (1, 0, 0); // Lines are counted up to `counted`
+    for (start, end) in finder.find_iter(text) {
+        if events.len() == limit {
+            break;
+        }
+        if started.elapsed() >= limits.max_scan {
+            events.push(skipped(format!("Stopped after {} ms; later matches weren't searched", limits.max_scan.as_millis())));
+            break;
+        }
+        for (offset, _) in text[counted..start].match_indices('\n') {
+            line += 1;
+            line_start = counted + offset + 1;
+        }
+        counted = start;
+        let line_end = text[start..].find('\n').map_or(text.len(), |offset| start + offset);
+        let (snippet, span) = snippet(&text[line_start..line_end], start - line_start, end.min(line_end) - line_start);
+        events.push(SearchEvent::Hit(SearchHit { doc_id: doc_id.to_string(), title: title.to_string(), line, snippet, span }));
+    }
+    events
+}
+
+/// `line` cut to `SNIPPET_CONTEXT` characters on each side of the match at `start..end`, and
+/// where the match is in it
+fn snippet(line: &str, start: usize, end: usize) -> (String, (usize, usize)) {
+    let from = line[..start].char_indices().rev().nth(SNIPPET_CONTEXT - 1).map_or(0, |(index, _)| index);
+    let to = line[end..].char_indices().nth(SNIPPET_CONTEXT).map_or(line.len(), |(index, _)| end + index);
+    (line[from..to].to_string(), (start - from, end - from))
+}
+
+/// `GET /api/workspaces/:id/search?q=<pattern>`, searching the documents of a workspace the
+/// signed-in user may read; see `SearchRequest` for the other parameters. Results stream
+/// as newline-delimited JSON `SearchEvent`s while the documents are scanned.
+pub fn search_routes(search: WorkspaceSearch) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("api" / "workspaces" / String / "search")
+        .and(warp::get())
+        .and(authenticated_user())
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |id: String, user: Option<String>, query: HashMap<String, String>| {
+            let Some(user) = user else { return sign_in_required() };
+            let error = |message: String, status: StatusCode| {
+                warp::reply::with_status(warp::reply::json(&serde_json::json!({ "type": "error", "message": message })), status).into_response()
+            };
+            if search.workspaces.get(&id).is_none() {
+                return error(format!("Unknown workspace {}", id), StatusCode::NOT_FOUND);
+            }
+            let request = match SearchRequest::parse(&query) {
+                Ok(request) => request,
+                Err(e) => return error(e, StatusCode::BAD_REQUEST),
+            };
+            let events = match search.search(&id, &user, &request) {
+                Ok(events) => events,
+                Err(e) => return error(e, StatusCode::FORBIDDEN),
+            };
+            let lines = ReceiverStream::new(events).map(|event| Ok::<_, Infallible>(format!("{}\n", serde_json::to_string(&event).unwrap())));
+            let mut response = warp::reply::Response::new(warp::hyper::Body::wrap_stream(lines));
+            response.headers_mut().insert("content-type", warp::http::HeaderValue::from_static("application/x-ndjson"));
+            response
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth::bearer_for;
+    use crate::storage::MemoryStorage;
+    use crate::editor::diff_engine::DiffOperation;
+    use crate::networking::protocol::DeltaMessage;
+    use crate::networking::room_host::{MemoryLimits, TITLE_KEY};
+    use crate::storage::workspace::WorkspaceRole;
+    use crate::storage::Storage;
+    use chrono::Utc;
+    use std::error::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Storage keeping everything in memory, taking a while over each load and counting how
+    /// many are under way at once
+    #[derive(Default)]
+    struct SlowStorage {
+        inner: MemoryStorage,
+        loading: AtomicUsize,
+        most_loading: AtomicUsize,
+    }
+
+    impl Storage for SlowStorage {
+        fn save(&self, identifier: &str, content: &str) -> Result<(), Box<dyn Error>> {
+            self.inner.save(identifier, content)
+        }
+
+        fn load(&self, identifier: &str) -> Result<String, Box<dyn Error>> {
+            let loading = self.loading.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_loading.fetch_max(loading, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(5));
+            self.loading.fetch_sub(1, Ordering::SeqCst);
+            self.inner.load(identifier)
+        }
+
+        fn delete(&self, identifier: &str) -> Result<(), Box<dyn Error>> {
+            self.inner.delete(identifier)
+        }
+    }
+
+    /// A workspace "Team" owned by "ana" holding `docs` as rooms of `host` with their text,
+    /// each unloaded to storage unless it is `loaded`
+    fn workspace(host: &RoomHost, docs: &[(&str, &str)], loaded: &[&str]) -> (Workspaces, String) {
+        let workspaces = Workspaces::new();
+        let id = workspaces.create("ana", "Team").unwrap().id;
+        let now = std::time::Instant::now();
+        for (doc, text) in docs {
+            workspaces.add_doc(&id, "ana", doc).unwrap();
+            host.join(doc, "ana1", now).unwrap();
+            let delta = DeltaMessage { client_id: String::new(), seq: 0, base_revision: 0, paste: false, bulk: None, operations: vec![DiffOperation::Insert(0, text.to_string())] };
+            host.receive(doc, "ana1", &delta, now).unwrap();
+            if !loaded.contains(doc) {
+                host.close_room(doc).unwrap();
+            }
+        }
+        (workspaces, id)
+    }
+
+    fn request(pairs: &[(&str, &str)]) -> SearchRequest {
+        SearchRequest::parse(&pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()).unwrap()
+    }
+
+    async fn collect(mut events: mpsc::Receiver<SearchEvent>) -> Vec<SearchEvent> {
+        let mut collected = Vec::new();
+        while let Some(event) = events.recv().await {
+            collected.push(event);
+        }
+        collected
+    }
+
+    fn hits(events: &[SearchEvent]) -> Vec<(&str, usize, &str)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                SearchEvent::Hit(hit) => Some((hit.doc_id.as_str(), hit.line, &hit.snippet[hit.span.0..hit.span.1])),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_snippets_mark_the_match_on_its_line() {
+        let text = format!("intro\n{}TODO: fix é{}\nTODO", "é".repeat(50), " tail".repeat(20));
+        let finder = Finder::new(&FindQuery::literal("todo")).unwrap();
+        let events = scan("notes.md", "Notes", &text, &finder, 10, SearchLimits::new());
+        let [SearchEvent::Hit(first), SearchEvent::Hit(second)] = &events[..] else { panic!("{:?}", events) };
+
+        // Forty characters of context each side, cut on character boundaries
+        assert_eq!((first.line, first.title.as_str()), (2, "Notes"));
+        assert_eq!(first.snippet, format!("{}TODO: fix é tail tail tail tail tail tail ta", "é".repeat(40)));
+        assert_eq!(&first.snippet[first.span.0..first.span.1], "TODO");
+        assert_eq!(first.span.0, 80);
+        assert_eq!((second.line, second.snippet.as_str(), second.span), (3, "TODO", (0, 4)));
+
+        // Matches across lines are cut at the end of theirs
+        let finder = Finder::new(&FindQuery::regex(r"tail\nto")).unwrap();
+        let [SearchEvent::Hit(hit)] = &scan("notes.md", "Notes", &text, &finder, 10, SearchLimits::new())[..] else { panic!("no hit") };
+        assert_eq!((hit.line, &hit.snippet[hit.span.0..hit.span.1]), (2, "tail"));
+    }
+
+    #[tokio::test]
+    async fn test_search_streams_readable_documents_in_order() {
+        let host = RoomHost::new(Arc::new(SlowStorage::default()), MemoryLimits::new());
+        let docs = [
+            ("src-main.rs", "fn main() {\n    // TODO: args\n}"),
+            ("notes.md", "todo list\n- todo one"),
+            ("src-lib.rs", "pub fn todo() {}"),
+            ("old.md", "TODO: gone"),
+        ];
+        let (workspaces, id) = workspace(&host, &docs, &["notes.md"]);
+        host.set_metadata("notes.md", TITLE_KEY, "Notes").unwrap();
+        workspaces.set_doc_role(&id, "ana", "notes.md", "guest", Some(WorkspaceRole::Viewer)).unwrap();
+        workspaces.trash_doc("old.md", "ana", Utc::now(), Utc::now() + chrono::Duration::days(30)).unwrap();
+        let search = WorkspaceSearch::new(host.clone(), workspaces.clone());
+
+        // Loaded rooms are searched as they are in memory, in the workspace's order
+        let events = collect(search.search(&id, "ana", &request(&[("q", "todo")])).unwrap()).await;
+        assert_eq!(hits(&events), vec![("src-main.rs", 2, "TODO"), ("notes.md", 1, "todo"), ("notes.md", 2, "todo"), ("src-lib.rs", 1, "todo")]);
+        assert_eq!(events.last(), Some(&SearchEvent::Done { results: 4, documents: 3, truncated: false }));
+        let SearchEvent::Hit(notes) = &events[1] else { panic!("not a hit") };
+        assert_eq!(notes.title, "Notes");
+
+        let events = collect(search.search(&id, "ana", &request(&[("q", "TODO"), ("case", "true")])).unwrap()).await;
+        assert_eq!(hits(&events), vec![("src-main.rs", 2, "TODO")]);
+        let events = collect(search.search(&id, "ana", &request(&[("q", r"fn \w+\(\)"), ("regex", "true"), ("prefix", "src-")])).unwrap()).await;
+        assert_eq!(hits(&events), vec![("src-main.rs", 1, "fn main()"), ("src-lib.rs", 1, "fn todo()")]);
+        let events = collect(search.search(&id, "ana", &request(&[("q", "fn todo()")])).unwrap()).await;
+        assert_eq!(hits(&events), vec![("src-lib.rs", 1, "fn todo()")]);
+
+        // Only what the user may read
+        let events = collect(search.search(&id, "guest", &request(&[("q", "todo")])).unwrap()).await;
+        assert_eq!(hits(&events), vec![("notes.md", 1, "todo"), ("notes.md", 2, "todo")]);
+        assert!(search.search(&id, "mallory", &request(&[("q", "todo")])).is_err());
+
+        // Over HTTP, one event per line
+        let routes = search_routes(search);
+        let get = |user: &str, path: String| warp::test::request().path(&path).header("authorization", bearer_for(user));
+        let response = get("guest", format!("/api/workspaces/{}/search?q=todo", id)).reply(&routes).await;
+        assert_eq!((response.status(), response.headers()["content-type"].to_str().unwrap()), (StatusCode::OK, "application/x-ndjson"));
+        let lines: Vec<SearchEvent> = String::from_utf8_lossy(response.body()).lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(serde_json::to_value(&lines[0]).unwrap()["type"], "hit");
+        assert_eq!(get("mallory", format!("/api/workspaces/{}/search?q=todo", id)).reply(&routes).await.status(), 403);
+        assert_eq!(get("ana", format!("/api/workspaces/{}/search?q=(todo&regex=true", id)).reply(&routes).await.status(), 400);
+        assert_eq!(warp::test::request().path(&format!("/api/workspaces/{}/search?user=ana&q=todo", id)).reply(&routes).await.status(), 401);
+        assert_eq!(get("ana", "/api/workspaces/nope/search?q=todo".to_string()).reply(&routes).await.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_limits_cut_searches_short_with_a_note() {
+        let host = RoomHost::new(Arc::new(SlowStorage::default()), MemoryLimits::new());
+        let docs = [("a.md", "x x x"), ("b.md", "x"), ("big.md", "x big document"), ("c.md", "no match")];
+        let (workspaces, id) = workspace(&host, &docs, &[]);
+        let limits = SearchLimits { max_doc_bytes: 10, ..SearchLimits::new() };
+        let search = WorkspaceSearch::new(host.clone(), workspaces.clone()).with_limits(limits);
+
+        let events = collect(search.search(&id, "ana", &request(&[("q", "x")])).unwrap()).await;
+        assert_eq!(hits(&events).len(), 4);
+        assert_eq!(events[4], SearchEvent::Skipped { doc_id: "big.md".to_string(), reason: "Larger than the 10 bytes searched".to_string() });
+        assert_eq!(events.last(), Some(&SearchEvent::Done { results: 4, documents: 4, truncated: false }));
+
+        // The result cap stops the search
+        let events = collect(search.search(&id, "ana", &request(&[("q", "x"), ("limit", "2")])).unwrap()).await;
+        assert_eq!(hits(&events), vec![("a.md", 1, "x"), ("a.md", 1, "x")]);
+        assert_eq!(events.last(), Some(&SearchEvent::Done { results: 2, documents: 1, truncated: true }));
+        let events = collect(search.search(&id, "ana", &request(&[("q", "x"), ("limit", "4")])).unwrap()).await;
+        assert_eq!(events.last(), Some(&SearchEvent::Done { results: 4, documents: 2, truncated: true }));
+
+        // Out of time, each document notes it was cut short
+        let search = search.with_limits(SearchLimits { max_scan: Duration::ZERO, ..limits });
+        let events = collect(search.search(&id, "ana", &request(&[("q", "x")])).unwrap()).await;
+        let skipped: Vec<&SearchEvent> = events.iter().filter(|event| matches!(event, SearchEvent::Skipped { .. })).collect();
+        assert!(hits(&events).is_empty());
+        assert_eq!(skipped.len(), 3);
+        assert!(matches!(skipped[0], SearchEvent::Skipped { doc_id, reason } if doc_id == "a.md" && reason.starts_with("Stopped after 0 ms")));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_unloaded_documents_are_read_a_few_at_a_time() {
+        let storage = Arc::new(SlowStorage::default());
+        let host = RoomHost::new(storage.clone(), MemoryLimits::new());
+        let names: Vec<String> = (0..40).map(|n| format!("doc-{:02}.md", n)).collect();
+        let texts: Vec<String> = (0..40).map(|n| format!("line\nneedle {}", n)).collect();
+        let docs: Vec<(&str, &str)> = names.iter().map(String::as_str).zip(texts.iter().map(String::as_str)).collect();
+        let (workspaces, id) = workspace(&host, &docs, &[]);
+        storage.most_loading.store(0, Ordering::SeqCst);
+
+        let search = WorkspaceSearch::new(host, workspaces).with_limits(SearchLimits { concurrency: 3, ..SearchLimits::new() });
+        let events = collect(search.search(&id, "ana", &request(&[("q", "needle \\d+"), ("regex", "true")])).unwrap()).await;
+        let found: Vec<String> = hits(&events).into_iter().map(|(_, line, text)| format!("{} {}", line, text)).collect();
+        let expected: Vec<String> = (0..40).map(|n| format!("2 needle {}", n)).collect();
+        assert_eq!(found, expected);
+        assert_eq!(events.last(), Some(&SearchEvent::Done { results: 40, documents: 40, truncated: false }));
+        let most = storage.most_loading.load(Ordering::SeqCst);
+        assert!((2..=3).contains(&most), "{} documents were read at once", most);
+    }
+}
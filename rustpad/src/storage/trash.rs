@@ -0,0 +1,418 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
+use warp::ws::Message;
+use warp::{Filter, Reply};
+
+use crate::auth::auth::{authenticated_user, sign_in_required};
+use crate::networking::chat_sync::{Annotation, ChatMessage, ChatSyncManager};
+use crate::networking::room_host::RoomHost;
+use crate::storage::attachments::AttachmentStore;
+use crate::storage::history::HistoryManager;
+use crate::storage::workspace::{deleted_frame, PermissionCache, TrashedDoc, WorkspaceRole, Workspaces};
+use crate::storage::Storage;
+
+/// How long a deleted document can be restored before it is purged
+pub fn default_retention() -> Duration {
+    Duration::days(30)
+}
+
+/// Close reason for connections to a deleted document
+pub const DOCUMENT_DELETED: &str = "document_deleted";
+
+/// Close code sent with `DOCUMENT_DELETED`, from the range left to applications
+pub const DOCUMENT_DELETED_CODE: u16 = 4410;
+
+/// The close frame for connections to a deleted document
+pub fn deleted_close() -> Message {
+    Message::close_with(DOCUMENT_DELETED_CODE, DOCUMENT_DELETED)
+}
+
+/// What deleting a document did
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeletedDoc {
+    pub doc_id: String,
+    #[serde(flatten)]
+    pub trashed: TrashedDoc,
+    pub disconnected: Vec<String>, // Clients that were in the document's room, to close with `deleted_close`
+}
+
+/// The chat of a trashed document, held back so it is hidden until the document is restored
+struct TrashedChat {
+    messages: Vec<ChatMessage>,
+    annotations: HashMap<usize, Vec<Annotation>>,
+}
+
+/// Deletes documents into a trash their owner can restore them from, and purges them for good
+/// once `retention` has passed: the content, the version history, the chat and annotations,
+/// the attachments, the room and the workspace entry.
+#[derive(Clone)]
+pub struct DocumentTrash {
+    documents: Arc<dyn Storage + Send + Sync>, // Document content, by doc id
+    history: Arc<Mutex<HistoryManager>>,       // Versions, keyed by doc id
+    chat: ChatSyncManager,                     // Chat and annotations, with the doc id as room
+    attachments: AttachmentStore,
+    workspaces: Workspaces, // Who owns what, and which documents are trashed
+    rooms: RoomHost,
+    retention: Duration,
+    chat_in_trash: Arc<Mutex<HashMap<String, TrashedChat>>>, // By doc id
+}
+
+impl DocumentTrash {
+    /// Creates a trash for the documents in `documents` and what the other stores hold for them,
+    /// keeping deleted documents for `default_retention()`
+    pub fn new(
+        documents: Arc<dyn Storage + Send + Sync>,
+        history: Arc<Mutex<HistoryManager>>,
+        chat: ChatSyncManager,
+        attachments: AttachmentStore,
+        workspaces: Workspaces,
+        rooms: RoomHost,
+    ) -> Self {
+        Self {
+            documents,
+            history,
+            chat,
+            attachments,
+            workspaces,
+            rooms,
+            retention: default_retention(),
+            chat_in_trash: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Keeps deleted documents restorable for `retention` instead of `default_retention()`
+    pub fn with_retention(self, retention: Duration) -> Self {
+        Self { retention, ..self }
+    }
+
+    /// Whether `user` may delete and restore `doc_id`; only its owner may
+    pub fn is_owner(&self, doc_id: &str, user: &str) -> bool {
+        self.workspaces.doc_role(doc_id, user) == Some(WorkspaceRole::Owner)
+    }
+
+    /// Moves `doc_id` to the trash on behalf of its owner `user`. Its room is saved and closed,
+    /// and the clients that were in it are returned; other connections to the document get the
+    /// `document_deleted` frame from `Workspaces::sync`. It is left out of listings, its chat is
+    /// put aside and its share links are suspended until it is restored.
+    pub fn delete(&self, doc_id: &str, user: &str, now: DateTime<Utc>) -> Result<DeletedDoc, String> {
+        let trashed = self.workspaces.trash_doc(doc_id, user, now, now + self.retention)?;
+        let disconnected = match self.rooms.close_room(doc_id) {
+            Ok(disconnected) => disconnected,
+            Err(e) => {
+                let _ = self.workspaces.restore_doc(doc_id, user, now);
+                return Err(e);
+            }
+        };
+        let (messages, annotations) = self.chat.take_room(doc_id);
+        self.chat_in_trash.lock().unwrap().insert(doc_id.to_string(), TrashedChat { messages, annotations });
+        Ok(DeletedDoc { doc_id: doc_id.to_string(), trashed, disconnected })
+    }
+
+    /// Takes `doc_id` back out of the trash on behalf of its owner `user`, with its chat, and
+    /// its share links working again. The room, content and history were never touched.
+    pub fn restore(&self, doc_id: &str, user: &str, now: DateTime<Utc>) -> Result<(), String> {
+        self.workspaces.restore_doc(doc_id, user, now)?;
+        if let Some(chat) = self.chat_in_trash.lock().unwrap().remove(doc_id) {
+            self.chat.restore_room(doc_id, chat.messages, chat.annotations)?;
+        }
+        Ok(())
+    }
+
+    /// `Workspaces::open`, for a connection to `doc_id`. A trashed document is refused with the
+    /// `document_deleted` frame saying until when it can be restored; anything else refused
+    /// gets an error frame.
+    pub fn open(&self, doc_id: &str, user: &str, token: Option<&str>) -> Result<PermissionCache, serde_json::Value> {
+        if let Some(trashed) = self.workspaces.trashed(doc_id) {
+            return Err(deleted_frame(doc_id, &trashed));
+        }
+        self.workspaces.open(doc_id, user, token).map_err(|e| serde_json::json!({ "type": "error", "message": e }))
+    }
+
+    /// Hashes of every attachment referenced by chat or annotations, trashed or not, for the
+    /// attachment garbage collector to keep
+    pub fn referenced_attachments(&self) -> HashSet<String> {
+        self.referenced_except(None)
+    }
+
+    /// Purges the documents whose retention ran out by `now`, returning their ids. A document
+    /// that fails to purge stays in the trash and is tried again on the next pass.
+    pub fn purge_expired(&self, now: DateTime<Utc>) -> Vec<String> {
+        let mut purged = Vec::new();
+        for doc_id in self.workspaces.expired_trash(now) {
+            match self.purge(&doc_id) {
+                Ok(()) => purged.push(doc_id),
+                Err(e) => eprintln!("Failed to purge {}, keeping it in the trash: {}", doc_id, e),
+            }
+        }
+        purged
+    }
+
+    /// Deletes everything kept for `doc_id`, and gives its owner the quota back. The workspace
+    /// entry goes last, so the document is only forgotten once nothing else is left.
+    fn purge(&self, doc_id: &str) -> Result<(), String> {
+        // Measured first, while the content and attachments are still there
+        let freed = self.workspaces.quotas().and_then(|quotas| Some((quotas, quotas.owner_of(doc_id)?, quotas.document_bytes(doc_id))));
+        self.rooms.purge(doc_id)?;
+        self.history.lock().unwrap().delete_history(doc_id).map_err(|e| format!("Failed to delete the history of {}: {}", doc_id, e))?;
+        if self.documents.load(doc_id).is_ok() {
+            self.documents.delete(doc_id).map_err(|e| format!("Failed to delete {}: {}", doc_id, e))?;
+        }
+        self.attachments.delete_document(doc_id, &self.referenced_except(Some(doc_id)))?;
+        self.chat_in_trash.lock().unwrap().remove(doc_id);
+        self.workspaces.forget_doc(doc_id);
+        if let Some((quotas, owner, bytes)) = freed {
+            quotas.record(&owner, -1, -(bytes as i64));
+        }
+        Ok(())
+    }
+
+    /// `referenced_attachments`, leaving out the trashed chat of `except`
+    fn referenced_except(&self, except: Option<&str>) -> HashSet<String> {
+        let mut referenced = self.chat.referenced_attachments();
+        let chat_in_trash = self.chat_in_trash.lock().unwrap();
+        let trashed = chat_in_trash.iter().filter(|(doc_id, _)| Some(doc_id.as_str()) != except).map(|(_, chat)| chat);
+        for chat in trashed {
+            let from_chat = chat.messages.iter().flat_map(|message| &message.attachments);
+            let from_annotations = chat.annotations.values().flatten().flat_map(|annotation| &annotation.attachments);
+            referenced.extend(from_chat.chain(from_annotations).map(|attachment| attachment.hash.clone()));
+        }
+        referenced
+    }
+}
+
+/// Purges expired documents every hour
+pub fn spawn_purger(trash: DocumentTrash) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            let trash = trash.clone();
+            // Purging blocks on storage and the file system, so keep it off the async workers
+            let purged = tokio::task::spawn_blocking(move || trash.purge_expired(Utc::now())).await.unwrap_or_default();
+            if !purged.is_empty() {
+                println!("Purged {} deleted documents", purged.len());
+            }
+        }
+    })
+}
+
+/// Replies with `result`, or with the error and a status telling an unknown document (404) and
+/// a user who doesn't own it (403) apart from a refused request (400)
+fn respond<T: Serialize>(trash: &DocumentTrash, doc_id: &str, user: &str, result: impl FnOnce() -> Result<T, String>) -> warp::reply::Response {
+    let error = |message: String, status: StatusCode| {
+        warp::reply::with_status(warp::reply::json(&serde_json::json!({ "type": "error", "message": message })), status).into_response()
+    };
+    if trash.workspaces.workspace_of(doc_id).is_none() {
+        return error(format!("Unknown document {}", doc_id), StatusCode::NOT_FOUND);
+    }
+    if !trash.is_owner(doc_id, user) {
+        return error("Only the document's owner can delete or restore it".to_string(), StatusCode::FORBIDDEN);
+    }
+    match result() {
+        Ok(body) => warp::reply::json(&body).into_response(),
+        Err(e) => error(e, StatusCode::BAD_REQUEST),
+    }
+}
+
+/// `DELETE /api/docs/:id`, moving a document to the trash, and `POST /api/docs/:id/restore`,
+/// taking it back out; both for its owner only, signed in
+pub fn trash_routes(trash: DocumentTrash) -> impl Filter<Extract = (warp::reply::Response,), Error = warp::Rejection> + Clone {
+    let restore_trash = trash.clone();
+    let delete = warp::path!("api" / "docs" / String)
+        .and(warp::delete())
+        .and(authenticated_user())
+        .map(move |doc_id: String, user: Option<String>| {
+            let Some(user) = user else { return sign_in_required() };
+            respond(&trash, &doc_id, &user, || trash.delete(&doc_id, &user, Utc::now()))
+        });
+    let restore = warp::path!("api" / "docs" / String / "restore")
+        .and(warp::post())
+        .and(authenticated_user())
+        .map(move |doc_id: String, user: Option<String>| {
+            let Some(user) = user else { return sign_in_required() };
+            respond(&restore_trash, &doc_id, &user, || restore_trash.restore(&doc_id, &user, Utc::now()))
+        });
+    delete.or(restore).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth::bearer_for;
+    use crate::storage::MemoryStorage;
+    use crate::networking::room_host::MemoryLimits;
+    use crate::storage::activity::ActivityFeeds;
+    use chrono::TimeZone;
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    /// A server with one document, "pad.md", owned by olga and edited by ed, with history, chat
+    /// with an attachment, and an open room
+    struct Server {
+        trash: DocumentTrash,
+        documents: Arc<MemoryStorage>,
+        room_storage: Arc<MemoryStorage>,
+        history: Arc<Mutex<HistoryManager>>,
+        chat: ChatSyncManager,
+        attachments: AttachmentStore,
+        workspaces: Workspaces,
+        rooms: RoomHost,
+        workspace: String,
+        history_dir: PathBuf,
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.history_dir);
+        }
+    }
+
+    fn at(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, day, 9, 0, 0).unwrap()
+    }
+
+    fn server(name: &str) -> Server {
+        let documents = Arc::new(MemoryStorage::default());
+        documents.save("pad.md", "line one\nline two").unwrap();
+        let history_dir = std::env::temp_dir().join(format!("rustpad-trash-{}-{}", name, std::process::id()));
+        let history = Arc::new(Mutex::new(HistoryManager::new(&history_dir.to_string_lossy(), 10)));
+        history.lock().unwrap().add_version("pad.md", "line one", "first").unwrap();
+        let chat = ChatSyncManager::new();
+        let attachments = AttachmentStore::new(Arc::new(MemoryStorage::default()));
+        let notes = attachments.upload("pad.md", "notes.txt", "text/plain", b"see line two", Utc::now()).unwrap();
+        let messages: Vec<ChatMessage> = serde_json::from_value(serde_json::json!([
+            { "id": 1, "user": "olga", "message": "notes attached", "timestamp": "2024-03-01T09:00:00Z", "attachments": [notes] },
+        ]))
+        .unwrap();
+        chat.restore_room("pad.md", messages, HashMap::new()).unwrap();
+
+        let workspaces = Workspaces::new();
+        let workspace = workspaces.create("olga", "Team").unwrap().id;
+        workspaces.set_member(&workspace, "olga", "ed", None).unwrap();
+        workspaces.add_doc(&workspace, "olga", "pad.md").unwrap();
+        let room_storage = Arc::new(MemoryStorage::default());
+        let rooms = RoomHost::new(room_storage.clone(), MemoryLimits::new());
+        rooms.join("pad.md", "olga-1", Instant::now()).unwrap();
+        rooms.join("pad.md", "ed-1", Instant::now()).unwrap();
+        rooms.post_chat("pad.md", serde_json::from_value(serde_json::json!({ "user": "ed", "message": "hi", "timestamp": "" })).unwrap(), Instant::now()).unwrap();
+
+        let trash = DocumentTrash::new(documents.clone(), history.clone(), chat.clone(), attachments.clone(), workspaces.clone(), rooms.clone());
+        Server { trash, documents, room_storage, history, chat, attachments, workspaces, rooms, workspace, history_dir }
+    }
+
+    fn listed(server: &Server, user: &str, include_trashed: bool) -> Vec<String> {
+        let docs = server.workspaces.docs_with_badges(&server.workspace, user, &ActivityFeeds::new(10), include_trashed).unwrap();
+        docs.into_iter().map(|badge| badge.doc).collect()
+    }
+
+    #[test]
+    fn test_delete_hides_and_disconnects() {
+        let server = server("delete");
+        let mut invalidations = server.workspaces.subscribe();
+        let mut ed = server.workspaces.open("pad.md", "ed", None).unwrap();
+
+        let deleted = server.trash.delete("pad.md", "olga", at(1)).unwrap();
+        assert_eq!(deleted.disconnected, vec!["ed-1".to_string(), "olga-1".to_string()]);
+        assert_eq!(deleted.trashed.purge_at, at(31));
+        assert!(server.rooms.document("pad.md").is_none());
+
+        // Open connections are told, and new ones are refused with the restore deadline
+        let frame = server.workspaces.sync(&mut ed, &mut invalidations).unwrap();
+        assert_eq!((frame["type"].as_str(), frame["restore_until"].as_str()), (Some(DOCUMENT_DELETED), Some("2024-03-31T09:00:00Z")));
+        assert!(ed.check_edit().is_err());
+        let refused = server.trash.open("pad.md", "olga", None).unwrap_err();
+        assert_eq!(refused, frame);
+
+        // Gone from listings and chat, except for the owner asking for the trash
+        assert!(listed(&server, "ed", false).is_empty());
+        assert!(listed(&server, "ed", true).is_empty());
+        assert!(listed(&server, "olga", false).is_empty());
+        assert_eq!(listed(&server, "olga", true), vec!["pad.md".to_string()]);
+        assert!(server.chat.room_history("pad.md").is_empty());
+        assert!(!server.chat.unread_counts("ed").contains_key("pad.md"));
+        assert!(server.trash.delete("pad.md", "olga", at(2)).is_err());
+    }
+
+    #[test]
+    fn test_restore_round_trip() {
+        let server = server("restore");
+        let token = server.workspaces.create_share_token(&server.workspace, "olga", "pad.md", WorkspaceRole::Viewer).unwrap();
+        let chat_before: Vec<serde_json::Value> = server.chat.room_history("pad.md").iter().map(|message| serde_json::to_value(message).unwrap()).collect();
+
+        server.trash.delete("pad.md", "olga", at(1)).unwrap();
+        assert!(server.workspaces.open("pad.md", "guest", Some(&token)).is_err()); // Suspended...
+        assert!(server.workspaces.get(&server.workspace).unwrap().share_tokens.contains_key(&token)); // ...not revoked
+        assert!(server.rooms.join("pad.md", "x", Instant::now()).is_ok()); // The room was saved, not dropped
+        server.rooms.leave("pad.md", "x", Instant::now());
+
+        server.trash.restore("pad.md", "olga", at(20)).unwrap();
+        assert!(server.workspaces.open("pad.md", "guest", Some(&token)).unwrap().role().is_some());
+        assert_eq!(listed(&server, "ed", false), vec!["pad.md".to_string()]);
+        let chat_after: Vec<serde_json::Value> = server.chat.room_history("pad.md").iter().map(|message| serde_json::to_value(message).unwrap()).collect();
+        assert_eq!(chat_after, chat_before);
+        let history = server.history.lock().unwrap().list_versions("pad.md");
+        assert_eq!(history.iter().map(|version| version.content.as_str()).collect::<Vec<_>>(), vec!["line one"]);
+        assert_eq!(server.rooms.recent_chat("pad.md").unwrap()[0].message.as_str(), "hi");
+        assert!(server.trash.restore("pad.md", "olga", at(20)).is_err()); // Not deleted any more
+
+        // Past the deadline there is nothing to restore
+        server.trash.delete("pad.md", "olga", at(1)).unwrap();
+        assert!(server.trash.restore("pad.md", "olga", at(31)).is_err());
+    }
+
+    #[test]
+    fn test_purge_after_retention_removes_everything() {
+        let server = server("purge");
+        let trash = server.trash.clone().with_retention(Duration::days(7));
+        let hash = server.chat.room_history("pad.md")[0].attachments[0].hash.clone();
+        server.workspaces.create_share_token(&server.workspace, "olga", "pad.md", WorkspaceRole::Editor).unwrap();
+        trash.delete("pad.md", "olga", at(1)).unwrap();
+
+        // Kept, attachments included, while it can still be restored
+        assert!(trash.purge_expired(at(7)).is_empty());
+        assert!(trash.referenced_attachments().contains(&hash));
+        assert!(server.attachments.collect_garbage(&trash.referenced_attachments(), at(7)).is_empty());
+
+        assert_eq!(trash.purge_expired(at(8)), vec!["pad.md".to_string()]);
+        assert!(server.documents.load("pad.md").is_err());
+        let mut history = server.history.lock().unwrap();
+        history.load_history("pad.md").unwrap();
+        assert!(history.list_versions("pad.md").is_empty());
+        drop(history);
+        assert!(server.attachments.get(&hash).is_none());
+        assert!(server.room_storage.files.lock().unwrap().is_empty());
+        let workspace = server.workspaces.get(&server.workspace).unwrap();
+        assert!(workspace.docs.is_empty() && workspace.share_tokens.is_empty() && workspace.trashed.is_empty());
+        assert!(trash.restore("pad.md", "olga", at(8)).is_err());
+        assert!(trash.referenced_attachments().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_only_the_owner_deletes() {
+        let server = server("owner");
+        assert!(server.trash.delete("pad.md", "ed", at(1)).is_err());
+        assert!(server.workspaces.trashed("pad.md").is_none());
+
+        let routes = trash_routes(server.trash.clone());
+        let response = warp::test::request().method("DELETE").path("/api/docs/pad.md?user=olga").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = warp::test::request().method("DELETE").path("/api/docs/pad.md").header("authorization", bearer_for("ed")).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = warp::test::request().method("DELETE").path("/api/docs/nope.md").header("authorization", bearer_for("olga")).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(server.chat.room_history("pad.md").len(), 1);
+
+        let response = warp::test::request().method("DELETE").path("/api/docs/pad.md").header("authorization", bearer_for("olga")).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let deleted: DeletedDoc = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!((deleted.doc_id.as_str(), deleted.trashed.trashed_by.as_str()), ("pad.md", "olga"));
+        let response = warp::test::request().method("POST").path("/api/docs/pad.md/restore").header("authorization", bearer_for("ed")).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = warp::test::request().method("POST").path("/api/docs/pad.md/restore").header("authorization", bearer_for("olga")).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(server.workspaces.trashed("pad.md").is_none());
+    }
+}
@@ -0,0 +1,1107 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use crate::auth::auth::{recover_signed_out, signed_in_user};
+use crate::editor::config::EditorConfig;
+use crate::i18n::{timestamp, Locale, LocalizedMessage};
+use crate::editor::save_hooks::SaveHooksConfig;
+use crate::storage::activity::ActivityFeeds;
+use crate::storage::quota::{QuotaExceeded, QuotaManager};
+use crate::storage::Storage;
+use crate::ui::file_manager::{default_ignore, validate_ignore, FileManager};
+
+/// Storage identifier under which all workspaces are persisted
+const WORKSPACES_ID: &str = "workspaces.json";
+
+/// Longest workspace name accepted
+pub const MAX_NAME_LENGTH: usize = 100;
+
+/// What a user may do in a workspace or one of its documents. Roles are ordered, so
+/// `role >= WorkspaceRole::Editor` means "may edit".
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceRole {
+    Viewer,
+    Editor,
+    Moderator, // Edits, and can redact chat and annotations, mute users and turn on slow mode
+    Owner,     // Manages members, settings and roles; exactly one per workspace
+}
+
+/// Theme and editor settings every document of a workspace starts from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct WorkspaceSettings {
+    #[serde(default)]
+    pub theme: Option<String>,
+    #[serde(default)]
+    pub editor: Option<EditorConfig>,
+    #[serde(default)]
+    pub save_hooks: Option<SaveHooksConfig>, // For documents without their own
+    #[serde(default)]
+    pub file_ignore: Option<Vec<String>>, // Patterns left out of the file tree; `DEFAULT_IGNORE` when unset
+}
+
+/// A named group of documents with its own members
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Workspace {
+    pub id: String,
+    pub name: String,
+    pub owner: String,
+    pub members: HashMap<String, WorkspaceRole>, // Including the owner
+    pub default_role: WorkspaceRole,             // Given to members invited without a role
+    #[serde(default)]
+    pub settings: WorkspaceSettings,
+    #[serde(default)]
+    pub docs: Vec<String>,
+    #[serde(default)]
+    pub doc_roles: HashMap<String, HashMap<String, WorkspaceRole>>, // Per-document overrides, by doc then user
+    #[serde(default)]
+    pub share_tokens: HashMap<String, ShareToken>, // Links into one document, by token; only the owner sees them
+    #[serde(default)]
+    pub trashed: HashMap<String, TrashedDoc>, // Deleted documents until they are purged, by doc; nobody can open them
+}
+
+/// Access to one document through a share link, for whoever holds the token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShareToken {
+    pub doc: String,
+    pub role: WorkspaceRole,
+}
+
+/// A deleted document, which its owner can restore until it is purged
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrashedDoc {
+    pub trashed_by: String,
+    pub trashed_at: DateTime<Utc>,
+    pub purge_at: DateTime<Utc>, // Restorable until then
+}
+
+impl Workspace {
+    /// The role of `user` in `doc_id`: the owner is always owner, then a document override,
+    /// then the user's workspace role
+    pub fn doc_role(&self, doc_id: &str, user: &str) -> Option<WorkspaceRole> {
+        if user == self.owner {
+            return Some(WorkspaceRole::Owner);
+        }
+        let overridden = self.doc_roles.get(doc_id).and_then(|roles| roles.get(user));
+        overridden.or_else(|| self.members.get(user)).copied()
+    }
+
+    /// The role of a connection to `doc_id`: the better of the user's own role and the role
+    /// the share token grants, if it is still valid for that document. Nobody connects to a
+    /// trashed document, so its share links are suspended rather than revoked.
+    fn connection_role(&self, doc_id: &str, user: &str, token: Option<&str>) -> Option<WorkspaceRole> {
+        if self.trashed.contains_key(doc_id) {
+            return None;
+        }
+        let shared = token.and_then(|token| self.share_tokens.get(token)).filter(|share| share.doc == doc_id).map(|share| share.role);
+        self.doc_role(doc_id, user).max(shared)
+    }
+}
+
+/// A document in the workspace sidebar, with what changed since the user last looked
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DocBadge {
+    pub doc: String,
+    pub role: WorkspaceRole,
+    pub unread: usize, // Changes by other people since the user's last visit
+    pub last_activity: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trashed: Option<TrashedDoc>, // Only listed for the owner, when asked for
+}
+
+/// Sent on `Workspaces::subscribe` whenever the permissions of a document change
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invalidation {
+    pub doc_id: String,
+    pub generation: u64, // The document's permission generation after the change
+}
+
+/// A connection's role in its document, resolved at handshake and cached so checking each
+/// message is an in-memory comparison. It only goes back to the workspaces when an
+/// invalidation for its document arrives, through `Workspaces::sync` or `Workspaces::revalidate`.
+#[derive(Debug, Clone)]
+pub struct PermissionCache {
+    doc_id: String,
+    user: String,
+    token: Option<String>,       // Share token the connection was opened with
+    role: Option<WorkspaceRole>, // `None` once access was revoked
+    generation: u64,             // The document's permission generation the role was resolved at
+}
+
+impl PermissionCache {
+    /// The document the role is for
+    pub fn doc_id(&self) -> &str {
+        &self.doc_id
+    }
+
+    /// The user the role is of
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// The cached role; `None` once access was revoked
+    pub fn role(&self) -> Option<WorkspaceRole> {
+        self.role
+    }
+
+    /// The document's permission generation the role was resolved at
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Refuses an edit unless the cached role may edit
+    pub fn check_edit(&self) -> Result<(), String> {
+        if self.role >= Some(WorkspaceRole::Editor) {
+            Ok(())
+        } else {
+            Err(format!("{} may not edit {}", self.user, self.doc_id))
+        }
+    }
+
+    /// Whether `invalidation` changes permissions this cache was resolved before
+    pub fn is_stale(&self, invalidation: &Invalidation) -> bool {
+        invalidation.doc_id == self.doc_id && invalidation.generation > self.generation
+    }
+}
+
+/// Every workspace, persisted through a `Storage` backend. Changes to members, roles and share
+/// tokens bump the permission generation of the documents they affect and are announced on
+/// `subscribe`, so open connections re-resolve their `PermissionCache` before their next message.
+#[derive(Clone)]
+pub struct Workspaces {
+    workspaces: Arc<Mutex<HashMap<String, Workspace>>>, // Keyed by workspace id
+    generations: Arc<Mutex<HashMap<String, u64>>>,      // Permission generation of each document, by doc id
+    invalidations: broadcast::Sender<Invalidation>,
+    storage: Option<Arc<dyn Storage + Send + Sync>>,
+    files_root: Option<PathBuf>, // Each workspace's files live in a directory of its own below this
+    file_managers: Arc<Mutex<HashMap<String, FileManager>>>, // Shared by every sidebar of a workspace, by id
+    quotas: Option<Arc<QuotaManager>>, // Limits on the documents each owner holds
+}
+
+impl Workspaces {
+    /// Creates an in-memory set of workspaces without files
+    pub fn new() -> Self {
+        Self {
+            workspaces: Arc::new(Mutex::new(HashMap::new())),
+            generations: Arc::new(Mutex::new(HashMap::new())),
+            invalidations: broadcast::channel(256).0,
+            storage: None,
+            files_root: None,
+            file_managers: Arc::new(Mutex::new(HashMap::new())),
+            quotas: None,
+        }
+    }
+
+    /// Creates workspaces backed by `storage`, loading any previously saved ones
+    pub fn with_storage(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        let workspaces = Self { storage: Some(storage.clone()), ..Self::new() };
+        if let Ok(saved) = storage.load(WORKSPACES_ID) {
+            let saved: Vec<Workspace> = serde_json::from_str(&saved).unwrap_or_default();
+            workspaces.workspaces.lock().unwrap().extend(saved.into_iter().map(|workspace| (workspace.id.clone(), workspace)));
+        }
+        workspaces
+    }
+
+    /// Keeps each workspace's files in `root/<workspace id>`
+    pub fn with_files_root(mut self, root: &Path) -> Self {
+        self.files_root = Some(root.to_path_buf());
+        self
+    }
+
+    /// Holds workspace owners to `quotas` when documents are added to their workspaces. Clone
+    /// the workspaces after this, so every clone enforces them.
+    pub fn with_quotas(mut self, quotas: QuotaManager) -> Self {
+        self.quotas = Some(Arc::new(quotas));
+        self
+    }
+
+    /// The quotas documents are added under, if any
+    pub fn quotas(&self) -> Option<&QuotaManager> {
+        self.quotas.as_deref()
+    }
+
+    /// Permission changes, for open connections to re-resolve their roles. Subscribe before
+    /// `open`, so no change between the two goes unnoticed.
+    pub fn subscribe(&self) -> broadcast::Receiver<Invalidation> {
+        self.invalidations.subscribe()
+    }
+
+    /// Creates a workspace owned by `owner`
+    pub fn create(&self, owner: &str, name: &str) -> Result<Workspace, String> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err(format!("Workspace names must be 1 to {} characters", MAX_NAME_LENGTH));
+        }
+        let workspace = Workspace {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            owner: owner.to_string(),
+            members: HashMap::from([(owner.to_string(), WorkspaceRole::Owner)]),
+            default_role: WorkspaceRole::Editor,
+            settings: WorkspaceSettings::default(),
+            docs: Vec::new(),
+            doc_roles: HashMap::new(),
+            share_tokens: HashMap::new(),
+            trashed: HashMap::new(),
+        };
+        let mut workspaces = self.workspaces.lock().unwrap();
+        workspaces.insert(workspace.id.clone(), workspace.clone());
+        self.save(&workspaces);
+        Ok(workspace)
+    }
+
+    /// The workspace `id`, if it exists
+    pub fn get(&self, id: &str) -> Option<Workspace> {
+        self.workspaces.lock().unwrap().get(id).cloned()
+    }
+
+    /// The workspace role of `user` in `id`, if they are a member
+    pub fn role_in(&self, id: &str, user: &str) -> Option<WorkspaceRole> {
+        self.workspaces.lock().unwrap().get(id)?.members.get(user).copied()
+    }
+
+    /// The workspace `doc_id` belongs to
+    pub fn workspace_of(&self, doc_id: &str) -> Option<String> {
+        let workspaces = self.workspaces.lock().unwrap();
+        workspaces.values().find(|workspace| workspace.docs.iter().any(|doc| doc == doc_id)).map(|workspace| workspace.id.clone())
+    }
+
+    /// The owner of the workspace `doc_id` belongs to, whom it counts against
+    pub fn owner_of(&self, doc_id: &str) -> Option<String> {
+        let workspaces = self.workspaces.lock().unwrap();
+        find_doc(&workspaces, doc_id).map(|workspace| workspace.owner.clone())
+    }
+
+    /// The documents, trashed ones included, in the workspaces `user` owns
+    pub fn docs_owned_by(&self, user: &str) -> Vec<String> {
+        let workspaces = self.workspaces.lock().unwrap();
+        workspaces.values().filter(|workspace| workspace.owner == user).flat_map(|workspace| workspace.docs.iter().cloned()).collect()
+    }
+
+    /// Refuses adding `doc_id` to workspace `id` if that would take its owner over their quota:
+    /// one more document, and the bytes it already has. Moves between workspaces of the same
+    /// owner are always fine.
+    pub fn check_quota(&self, id: &str, doc_id: &str) -> Result<(), QuotaExceeded> {
+        let (Some(quotas), Some(workspace)) = (self.quotas(), self.get(id)) else { return Ok(()) };
+        if self.owner_of(doc_id).as_ref() == Some(&workspace.owner) {
+            return Ok(());
+        }
+        quotas.check_document(&workspace.owner)?;
+        quotas.check_bytes(&workspace.owner, quotas.document_bytes(doc_id))
+    }
+
+    /// The save hooks of the workspace `doc_id` belongs to, for documents without their own
+    pub fn save_hook_defaults(&self, doc_id: &str) -> SaveHooksConfig {
+        let workspaces = self.workspaces.lock().unwrap();
+        let workspace = workspaces.values().find(|workspace| workspace.docs.iter().any(|doc| doc == doc_id));
+        workspace.and_then(|workspace| workspace.settings.save_hooks.clone()).unwrap_or_default()
+    }
+
+    /// The role of `user` in `doc_id`, through the workspace it belongs to
+    pub fn doc_role(&self, doc_id: &str, user: &str) -> Option<WorkspaceRole> {
+        let workspaces = self.workspaces.lock().unwrap();
+        find_doc(&workspaces, doc_id)?.doc_role(doc_id, user)
+    }
+
+    /// Adds `user` to the workspace, or changes their role; only the owner may. Without a
+    /// role, new members get the workspace's default role.
+    pub fn set_member(&self, id: &str, actor: &str, user: &str, role: Option<WorkspaceRole>) -> Result<WorkspaceRole, String> {
+        self.change(id, actor, |workspace| {
+            if user == workspace.owner {
+                return Err("Transfer ownership to change the owner's role".to_string());
+            }
+            let role = role.or_else(|| workspace.members.get(user).copied()).unwrap_or(workspace.default_role);
+            if role == WorkspaceRole::Owner {
+                return Err("Transfer ownership to make someone the owner".to_string());
+            }
+            workspace.members.insert(user.to_string(), role);
+            Ok(role)
+        })
+    }
+
+    /// Removes `user` from the workspace and its documents' overrides. The owner may remove
+    /// anyone but themselves; members may remove themselves.
+    pub fn remove_member(&self, id: &str, actor: &str, user: &str) -> Result<(), String> {
+        let mut workspaces = self.workspaces.lock().unwrap();
+        let workspace = workspaces.get_mut(id).ok_or_else(|| format!("Unknown workspace {}", id))?;
+        if actor != workspace.owner && actor != user {
+            return Err("Only the owner can remove other members".to_string());
+        }
+        if user == workspace.owner {
+            return Err("Transfer ownership before leaving the workspace".to_string());
+        }
+        if workspace.members.remove(user).is_none() {
+            return Err(format!("{} is not a member", user));
+        }
+        for roles in workspace.doc_roles.values_mut() {
+            roles.remove(user);
+        }
+        let docs = workspace.docs.clone();
+        self.save(&workspaces);
+        self.invalidate(&docs);
+        Ok(())
+    }
+
+    /// Hands the workspace to `user`, who must be a member; the previous owner stays on as editor
+    pub fn transfer_ownership(&self, id: &str, actor: &str, user: &str) -> Result<(), String> {
+        self.change(id, actor, |workspace| {
+            if !workspace.members.contains_key(user) {
+                return Err(format!("{} is not a member", user));
+            }
+            workspace.members.insert(workspace.owner.clone(), WorkspaceRole::Editor);
+            workspace.members.insert(user.to_string(), WorkspaceRole::Owner);
+            workspace.owner = user.to_string();
+            Ok(())
+        })
+    }
+
+    /// Replaces the shared settings and the default role of new members; only the owner may
+    pub fn configure(&self, id: &str, actor: &str, settings: WorkspaceSettings, default_role: Option<WorkspaceRole>) -> Result<(), String> {
+        self.change(id, actor, |workspace| {
+            if default_role == Some(WorkspaceRole::Owner) {
+                return Err("The default role can't be owner".to_string());
+            }
+            if let Some(save_hooks) = &settings.save_hooks {
+                save_hooks.validate()?;
+            }
+            if let Some(file_ignore) = &settings.file_ignore {
+                validate_ignore(file_ignore)?;
+            }
+            workspace.settings = settings;
+            workspace.default_role = default_role.unwrap_or(workspace.default_role);
+            Ok(())
+        })?;
+        if let Some(manager) = self.file_managers.lock().unwrap().get(id) {
+            manager.set_ignore(&self.get(id).and_then(|workspace| workspace.settings.file_ignore).unwrap_or_else(default_ignore))?;
+        }
+        Ok(())
+    }
+
+    /// Overrides the role of `user` in `doc_id`, or with `None` returns them to their
+    /// workspace role; only the owner may
+    pub fn set_doc_role(&self, id: &str, actor: &str, doc_id: &str, user: &str, role: Option<WorkspaceRole>) -> Result<(), String> {
+        self.change(id, actor, |workspace| {
+            if !workspace.docs.iter().any(|doc| doc == doc_id) {
+                return Err(format!("{} is not in this workspace", doc_id));
+            }
+            if user == workspace.owner || role == Some(WorkspaceRole::Owner) {
+                return Err("The owner's role can't be overridden".to_string());
+            }
+            let roles = workspace.doc_roles.entry(doc_id.to_string()).or_default();
+            match role {
+                Some(role) => roles.insert(user.to_string(), role),
+                None => roles.remove(user),
+            };
+            Ok(())
+        })
+    }
+
+    /// Moves `doc_id` into workspace `id`, out of the one it was in. `actor` must be an editor
+    /// of both. The document's role overrides stay behind, and its file moves with it.
+    pub fn add_doc(&self, id: &str, actor: &str, doc_id: &str) -> Result<(), String> {
+        if !is_relative_path(doc_id) {
+            return Err(format!("Invalid document id {:?}", doc_id));
+        }
+        // Quotas are counted from the workspaces, so before taking the lock
+        if self.role_in(id, actor) >= Some(WorkspaceRole::Editor) {
+            self.check_quota(id, doc_id).map_err(|e| e.to_string())?;
+        }
+        let previous_owner = self.owner_of(doc_id);
+        let mut workspaces = self.workspaces.lock().unwrap();
+        if workspaces.get(id).ok_or_else(|| format!("Unknown workspace {}", id))?.members.get(actor) < Some(&WorkspaceRole::Editor) {
+            return Err("Only editors can add documents".to_string());
+        }
+        let from = find_doc(&workspaces, doc_id).map(|workspace| (workspace.id.clone(), workspace.doc_role(doc_id, actor)));
+        if let Some((from, role)) = &from {
+            if from == id {
+                return Ok(());
+            }
+            if *role < Some(WorkspaceRole::Editor) {
+                return Err("Only editors of its workspace can move a document".to_string());
+            }
+        }
+
+        if let (Some(root), Some((from, _))) = (&self.files_root, &from) {
+            let (source, target) = (root.join(from).join(doc_id), root.join(id).join(doc_id));
+            if source.exists() {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).map_err(|e| format!("Failed to move {}: {}", doc_id, e))?;
+                }
+                fs::rename(&source, &target).map_err(|e| format!("Failed to move {}: {}", doc_id, e))?;
+                for manager in [from.as_str(), id].iter().filter_map(|workspace| self.file_managers.lock().unwrap().get(*workspace).cloned()) {
+                    manager.refresh(); // Both trees changed behind their managers' backs
+                }
+            }
+        }
+        if let Some((from, _)) = from {
+            let source = workspaces.get_mut(&from).unwrap();
+            source.docs.retain(|doc| doc != doc_id);
+            source.doc_roles.remove(doc_id);
+            source.share_tokens.retain(|_, share| share.doc != doc_id);
+        }
+        let workspace = workspaces.get_mut(id).unwrap();
+        workspace.docs.push(doc_id.to_string());
+        let owner = workspace.owner.clone();
+        self.save(&workspaces);
+        self.invalidate(&[doc_id.to_string()]);
+        drop(workspaces);
+
+        // The document now counts against the new workspace's owner instead of the old one's
+        if let Some(quotas) = self.quotas().filter(|_| previous_owner.as_ref() != Some(&owner)) {
+            let bytes = quotas.document_bytes(doc_id) as i64;
+            if let Some(previous_owner) = previous_owner {
+                quotas.record(&previous_owner, -1, -bytes);
+            }
+            quotas.record(&owner, 1, bytes);
+        }
+        Ok(())
+    }
+
+    /// The documents of workspace `id` that `user` can open, with unread and activity badges.
+    /// Trashed documents are left out, unless the owner asks for them with `include_trashed`.
+    pub fn docs_with_badges(&self, id: &str, user: &str, activity: &ActivityFeeds, include_trashed: bool) -> Result<Vec<DocBadge>, String> {
+        let workspace = self.get(id).ok_or_else(|| format!("Unknown workspace {}", id))?;
+        Ok(workspace
+            .docs
+            .iter()
+            .filter_map(|doc| {
+                let role = workspace.doc_role(doc, user)?;
+                let trashed = workspace.trashed.get(doc).cloned();
+                if trashed.is_some() && !(include_trashed && role == WorkspaceRole::Owner) {
+                    return None;
+                }
+                let (unread, last_activity) = activity.with_feed(doc, |feed| {
+                    (feed.summary_for(user).changes, feed.events().iter().map(|event| event.updated_at).max())
+                });
+                Some(DocBadge { doc: doc.clone(), role, unread, last_activity, trashed })
+            })
+            .collect())
+    }
+
+    /// Moves `doc_id` to the trash until `purge_at`; only its owner may. Open connections hear
+    /// of it on their next `sync`, and its share links stop working until it is restored.
+    pub fn trash_doc(&self, doc_id: &str, actor: &str, now: DateTime<Utc>, purge_at: DateTime<Utc>) -> Result<TrashedDoc, String> {
+        let id = self.workspace_of(doc_id).ok_or_else(|| format!("Unknown document {}", doc_id))?;
+        self.change(&id, actor, |workspace| {
+            if workspace.trashed.contains_key(doc_id) {
+                return Err(format!("{} is already deleted", doc_id));
+            }
+            let trashed = TrashedDoc { trashed_by: actor.to_string(), trashed_at: now, purge_at };
+            workspace.trashed.insert(doc_id.to_string(), trashed.clone());
+            Ok(trashed)
+        })
+    }
+
+    /// Takes `doc_id` back out of the trash, with its roles and share links, if it isn't due
+    /// for purging by `now`; only its owner may
+    pub fn restore_doc(&self, doc_id: &str, actor: &str, now: DateTime<Utc>) -> Result<(), String> {
+        let id = self.workspace_of(doc_id).ok_or_else(|| format!("Unknown document {}", doc_id))?;
+        self.change(&id, actor, |workspace| match workspace.trashed.get(doc_id) {
+            None => Err(format!("{} is not deleted", doc_id)),
+            Some(trashed) if now >= trashed.purge_at => Err(format!("{} was due for purging at {}", doc_id, timestamp(trashed.purge_at))),
+            Some(_) => {
+                workspace.trashed.remove(doc_id);
+                Ok(())
+            }
+        })
+    }
+
+    /// Whether `doc_id` is in the trash, and until when
+    pub fn trashed(&self, doc_id: &str) -> Option<TrashedDoc> {
+        let workspaces = self.workspaces.lock().unwrap();
+        find_doc(&workspaces, doc_id)?.trashed.get(doc_id).cloned()
+    }
+
+    /// Trashed documents due for purging by `now`
+    pub fn expired_trash(&self, now: DateTime<Utc>) -> Vec<String> {
+        let workspaces = self.workspaces.lock().unwrap();
+        workspaces.values().flat_map(|workspace| workspace.trashed.iter()).filter(|(_, trashed)| now >= trashed.purge_at).map(|(doc, _)| doc.clone()).collect()
+    }
+
+    /// Removes every trace of `doc_id` from its workspace: the listing, the role overrides, the
+    /// share links and the trash entry. Used once the document itself is purged, which also
+    /// gives its owner the quota back.
+    pub fn forget_doc(&self, doc_id: &str) {
+        let mut workspaces = self.workspaces.lock().unwrap();
+        let Some(workspace) = workspaces.values_mut().find(|workspace| workspace.docs.iter().any(|doc| doc == doc_id)) else { return };
+        workspace.docs.retain(|doc| doc != doc_id);
+        workspace.doc_roles.remove(doc_id);
+        workspace.share_tokens.retain(|_, share| share.doc != doc_id);
+        workspace.trashed.remove(doc_id);
+        self.save(&workspaces);
+        self.invalidate(&[doc_id.to_string()]);
+    }
+
+    /// The file tree of workspace `id`, which only sees that workspace's files. Its sidebars
+    /// all share one manager, so they hear of each other's changes, and its custom file order
+    /// is kept with the workspaces. It leaves out the files the workspace settings ignore.
+    pub fn file_manager(&self, id: &str) -> Option<FileManager> {
+        let root = self.files_root.as_ref()?;
+        let file_ignore = self.get(id)?.settings.file_ignore;
+        let mut managers = self.file_managers.lock().unwrap();
+        let manager = managers.entry(id.to_string()).or_insert_with(|| {
+            let manager = FileManager::new(&root.join(id).to_string_lossy()).with_ignore(file_ignore.unwrap_or_else(default_ignore));
+            match &self.storage {
+                Some(storage) => manager.with_storage(storage.clone(), &format!("file_order_{}.json", id)),
+                None => manager,
+            }
+        });
+        Some(manager.clone())
+    }
+
+    /// Creates a share link granting `role` in `doc_id` to whoever holds the returned token;
+    /// only the owner may
+    pub fn create_share_token(&self, id: &str, actor: &str, doc_id: &str, role: WorkspaceRole) -> Result<String, String> {
+        self.change(id, actor, |workspace| {
+            if !workspace.docs.iter().any(|doc| doc == doc_id) {
+                return Err(format!("{} is not in this workspace", doc_id));
+            }
+            if role >= WorkspaceRole::Moderator {
+                return Err("Share links can't grant moderation or ownership".to_string());
+            }
+            let token = Uuid::new_v4().to_string();
+            workspace.share_tokens.insert(token.clone(), ShareToken { doc: doc_id.to_string(), role });
+            Ok(token)
+        })
+    }
+
+    /// Revokes a share link; connections opened with it lose what it granted. Only the owner may.
+    pub fn revoke_share_token(&self, id: &str, actor: &str, token: &str) -> Result<(), String> {
+        self.change(id, actor, |workspace| {
+            workspace.share_tokens.remove(token).map(|_| ()).ok_or_else(|| "Unknown share link".to_string())
+        })
+    }
+
+    /// Resolves the role of a connection of `user` to `doc_id`, opened with an optional share
+    /// token, for it to cache. Connections without access to the document are refused.
+    pub fn open(&self, doc_id: &str, user: &str, token: Option<&str>) -> Result<PermissionCache, String> {
+        let mut cache = PermissionCache {
+            doc_id: doc_id.to_string(),
+            user: user.to_string(),
+            token: token.map(str::to_string),
+            role: None,
+            generation: 0,
+        };
+        self.revalidate(&mut cache);
+        cache.role.map(|_| cache.clone()).ok_or_else(|| format!("{} has no access to {}", user, doc_id))
+    }
+
+    /// Resolves the role of `cache` afresh. When it changed, returns the
+    /// `{"type":"permission_changed","role":..}` frame to send to the connection, or the
+    /// `{"type":"document_deleted",..}` frame when the document went to the trash, after which
+    /// the connection is closed.
+    pub fn revalidate(&self, cache: &mut PermissionCache) -> Option<serde_json::Value> {
+        // The role and the generation are read under the same lock changes are made under,
+        // so a change during the handshake is either seen here or announced afterwards
+        let workspaces = self.workspaces.lock().unwrap();
+        let workspace = find_doc(&workspaces, &cache.doc_id);
+        let role = workspace.and_then(|workspace| workspace.connection_role(&cache.doc_id, &cache.user, cache.token.as_deref()));
+        cache.generation = self.generations.lock().unwrap().get(&cache.doc_id).copied().unwrap_or(0);
+        if role == cache.role {
+            return None;
+        }
+        cache.role = role;
+        match workspace.and_then(|workspace| workspace.trashed.get(&cache.doc_id)) {
+            Some(trashed) => Some(deleted_frame(&cache.doc_id, trashed)),
+            None => Some(serde_json::json!({ "type": "permission_changed", "role": role })),
+        }
+    }
+
+    /// Applies the invalidations waiting on `invalidations` to `cache`, re-resolving it if any
+    /// is newer than the cached role. Meant to run before each message is checked: when
+    /// nothing changed it costs a channel poll and no storage or workspace access.
+    pub fn sync(&self, cache: &mut PermissionCache, invalidations: &mut broadcast::Receiver<Invalidation>) -> Option<serde_json::Value> {
+        let mut stale = false;
+        loop {
+            match invalidations.try_recv() {
+                Ok(invalidation) => stale |= cache.is_stale(&invalidation),
+                Err(broadcast::error::TryRecvError::Lagged(_)) => stale = true, // Missed some; assume ours was among them
+                Err(_) => break,
+            }
+        }
+        if stale {
+            self.revalidate(cache)
+        } else {
+            None
+        }
+    }
+
+    /// Runs an owner-only `change` on workspace `id`, then saves and updates open connections
+    fn change<T>(&self, id: &str, actor: &str, change: impl FnOnce(&mut Workspace) -> Result<T, String>) -> Result<T, String> {
+        let mut workspaces = self.workspaces.lock().unwrap();
+        let workspace = workspaces.get_mut(id).ok_or_else(|| format!("Unknown workspace {}", id))?;
+        if workspace.owner != actor {
+            return Err("Only the workspace owner can do that".to_string());
+        }
+        let result = change(workspace)?;
+        let docs = workspace.docs.clone();
+        self.save(&workspaces);
+        self.invalidate(&docs);
+        Ok(result)
+    }
+
+    /// Bumps the permission generation of `docs` and announces it. Called with the workspaces
+    /// lock held, so `revalidate` never pairs an old role with a new generation.
+    fn invalidate(&self, docs: &[String]) {
+        let mut generations = self.generations.lock().unwrap();
+        for doc_id in docs {
+            let generation = generations.entry(doc_id.clone()).or_insert(0);
+            *generation += 1;
+            let invalidation = Invalidation { doc_id: doc_id.clone(), generation: *generation };
+            let _ = self.invalidations.send(invalidation); // Fine if nobody listens
+        }
+    }
+
+    /// Persists every workspace if there is a storage backend
+    fn save(&self, workspaces: &HashMap<String, Workspace>) {
+        if let Some(storage) = &self.storage {
+            let saved: Vec<&Workspace> = workspaces.values().collect();
+            match serde_json::to_string(&saved) {
+                Ok(json) => {
+                    if let Err(e) = storage.save(WORKSPACES_ID, &json) {
+                        eprintln!("Failed to persist workspaces: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Failed to serialize workspaces: {}", e),
+            }
+        }
+    }
+}
+
+impl Default for Workspaces {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The frame telling a connection its document was deleted, and until when the owner can
+/// restore it. The message is in English, with its key and parameters for the client to
+/// write it in the user's language.
+pub fn deleted_frame(doc_id: &str, trashed: &TrashedDoc) -> serde_json::Value {
+    let params = [("doc_id", doc_id.to_string()), ("restore_until", timestamp(trashed.purge_at))];
+    let message = LocalizedMessage::new(Locale::En, "notice.document_deleted", &params);
+    serde_json::json!({
+        "type": "document_deleted",
+        "doc_id": doc_id,
+        "message_key": message.message_key,
+        "params": message.params,
+        "message": message.message,
+        "restore_until": trashed.purge_at,
+    })
+}
+
+fn find_doc<'a>(workspaces: &'a HashMap<String, Workspace>, doc_id: &str) -> Option<&'a Workspace> {
+    workspaces.values().find(|workspace| workspace.docs.iter().any(|doc| doc == doc_id))
+}
+
+/// Whether `path` stays inside the directory it is joined to
+fn is_relative_path(path: &str) -> bool {
+    !path.is_empty() && Path::new(path).components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[derive(Deserialize)]
+struct CreateRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct MemberRequest {
+    user: String,
+    #[serde(default)]
+    role: Option<WorkspaceRole>,
+}
+
+#[derive(Deserialize)]
+struct DocRequest {
+    doc: String,
+}
+
+#[derive(Deserialize)]
+struct ShareRequest {
+    role: WorkspaceRole,
+}
+
+#[derive(Deserialize)]
+struct RoleRequest {
+    role: Option<WorkspaceRole>, // `null` removes a document override
+}
+
+/// Replies with `body`, or with the error and a status telling an unknown workspace (404)
+/// and missing rights (403) apart from a refused request (400)
+fn respond<T: Serialize>(workspaces: &Workspaces, id: &str, actor: &str, required: WorkspaceRole, result: impl FnOnce() -> Result<T, String>) -> warp::reply::Response {
+    let error = |message: String, status: StatusCode| {
+        warp::reply::with_status(warp::reply::json(&serde_json::json!({ "type": "error", "message": message })), status).into_response()
+    };
+    match workspaces.get(id) {
+        None => return error(format!("Unknown workspace {}", id), StatusCode::NOT_FOUND),
+        Some(workspace) if workspace.members.get(actor) < Some(&required) => {
+            return error("Not allowed in this workspace".to_string(), StatusCode::FORBIDDEN);
+        }
+        Some(_) => {}
+    }
+    match result() {
+        Ok(body) => warp::reply::json(&body).into_response(),
+        Err(e) => error(e, StatusCode::BAD_REQUEST),
+    }
+}
+
+/// Routes for workspaces, all acting for the signed-in user:
+/// `POST /api/workspaces`, `GET /api/workspaces/:id`, `PUT /api/workspaces/:id/settings`,
+/// `POST /api/workspaces/:id/members`, `DELETE /api/workspaces/:id/members/:user`,
+/// `POST /api/workspaces/:id/owner`, `POST` and `GET /api/workspaces/:id/docs` (the sidebar,
+/// with badges; `&include_trashed=true` adds the owner's deleted documents), `PUT /api/workspaces/:id/docs/:doc/roles/:user`, `POST /api/workspaces/:id/docs/:doc/share`
+/// and `DELETE /api/workspaces/:id/share/:token`
+pub fn workspace_routes(workspaces: Workspaces, activity: ActivityFeeds) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+    let with_workspaces = warp::any().map(move || workspaces.clone());
+
+    let create = warp::path!("api" / "workspaces")
+        .and(warp::post())
+        .and(signed_in_user())
+        .and(warp::body::json())
+        .and(with_workspaces.clone())
+        .map(|actor: String, request: CreateRequest, workspaces: Workspaces| {
+            match workspaces.create(&actor, &request.name) {
+                Ok(workspace) => warp::reply::with_status(warp::reply::json(&workspace), StatusCode::CREATED).into_response(),
+                Err(e) => warp::reply::with_status(warp::reply::json(&serde_json::json!({ "type": "error", "message": e })), StatusCode::BAD_REQUEST).into_response(),
+            }
+        });
+    let get = warp::path!("api" / "workspaces" / String)
+        .and(warp::get())
+        .and(signed_in_user())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_workspaces.clone())
+        .map(|id: String, actor: String, query: HashMap<String, String>, workspaces: Workspaces| {
+            respond(&workspaces, &id, &actor, WorkspaceRole::Viewer, || {
+                let workspace = workspaces.get(&id).ok_or_else(String::new)?;
+                // Share links are as good as access, so members other than the owner don't see them
+                let share_tokens = if workspace.owner == actor { workspace.share_tokens.clone() } else { HashMap::new() };
+                // Deleted documents are listed for the owner only, when asked for
+                let include_trashed = workspace.owner == actor && query.get("include_trashed").is_some_and(|include| include == "true");
+                let trashed = if include_trashed { workspace.trashed.clone() } else { HashMap::new() };
+                let docs = workspace.docs.iter().filter(|doc| include_trashed || !workspace.trashed.contains_key(*doc)).cloned().collect();
+                Ok(Workspace { share_tokens, docs, trashed, ..workspace })
+            })
+        });
+    let settings = warp::path!("api" / "workspaces" / String / "settings")
+        .and(warp::put())
+        .and(signed_in_user())
+        .and(warp::body::json())
+        .and(with_workspaces.clone())
+        .map(|id: String, actor: String, settings: WorkspaceSettings, workspaces: Workspaces| {
+            respond(&workspaces, &id, &actor, WorkspaceRole::Owner, || workspaces.configure(&id, &actor, settings, None))
+        });
+    let invite = warp::path!("api" / "workspaces" / String / "members")
+        .and(warp::post())
+        .and(signed_in_user())
+        .and(warp::body::json())
+        .and(with_workspaces.clone())
+        .map(|id: String, actor: String, request: MemberRequest, workspaces: Workspaces| {
+            respond(&workspaces, &id, &actor, WorkspaceRole::Owner, || workspaces.set_member(&id, &actor, &request.user, request.role))
+        });
+    let remove = warp::path!("api" / "workspaces" / String / "members" / String)
+        .and(warp::delete())
+        .and(signed_in_user())
+        .and(with_workspaces.clone())
+        .map(|id: String, user: String, actor: String, workspaces: Workspaces| {
+            respond(&workspaces, &id, &actor, WorkspaceRole::Viewer, || workspaces.remove_member(&id, &actor, &user))
+        });
+    let transfer = warp::path!("api" / "workspaces" / String / "owner")
+        .and(warp::post())
+        .and(signed_in_user())
+        .and(warp::body::json())
+        .and(with_workspaces.clone())
+        .map(|id: String, actor: String, request: MemberRequest, workspaces: Workspaces| {
+            respond(&workspaces, &id, &actor, WorkspaceRole::Owner, || workspaces.transfer_ownership(&id, &actor, &request.user))
+        });
+    let add_doc = warp::path!("api" / "workspaces" / String / "docs")
+        .and(warp::post())
+        .and(signed_in_user())
+        .and(warp::body::json())
+        .and(with_workspaces.clone())
+        .map(|id: String, actor: String, request: DocRequest, workspaces: Workspaces| {
+            // Editors going over the owner's quota are told which limit, and by how much
+            let editor = workspaces.role_in(&id, &actor) >= Some(WorkspaceRole::Editor);
+            match workspaces.check_quota(&id, &request.doc) {
+                Err(exceeded) if editor => exceeded.reply(),
+                _ => respond(&workspaces, &id, &actor, WorkspaceRole::Editor, || workspaces.add_doc(&id, &actor, &request.doc)),
+            }
+        });
+    let list_docs = warp::path!("api" / "workspaces" / String / "docs")
+        .and(warp::get())
+        .and(signed_in_user())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_workspaces.clone())
+        .and(warp::any().map(move || activity.clone()))
+        .map(|id: String, actor: String, query: HashMap<String, String>, workspaces: Workspaces, activity: ActivityFeeds| {
+            let include_trashed = query.get("include_trashed").is_some_and(|include| include == "true");
+            respond(&workspaces, &id, &actor, WorkspaceRole::Viewer, || workspaces.docs_with_badges(&id, &actor, &activity, include_trashed))
+        });
+    let share = warp::path!("api" / "workspaces" / String / "docs" / String / "share")
+        .and(warp::post())
+        .and(signed_in_user())
+        .and(warp::body::json())
+        .and(with_workspaces.clone())
+        .map(|id: String, doc: String, actor: String, request: ShareRequest, workspaces: Workspaces| {
+            respond(&workspaces, &id, &actor, WorkspaceRole::Owner, || {
+                workspaces.create_share_token(&id, &actor, &doc, request.role).map(|token| serde_json::json!({ "token": token }))
+            })
+        });
+    let revoke = warp::path!("api" / "workspaces" / String / "share" / String)
+        .and(warp::delete())
+        .and(signed_in_user())
+        .and(with_workspaces.clone())
+        .map(|id: String, token: String, actor: String, workspaces: Workspaces| {
+            respond(&workspaces, &id, &actor, WorkspaceRole::Owner, || workspaces.revoke_share_token(&id, &actor, &token))
+        });
+    let doc_role = warp::path!("api" / "workspaces" / String / "docs" / String / "roles" / String)
+        .and(warp::put())
+        .and(signed_in_user())
+        .and(warp::body::json())
+        .and(with_workspaces)
+        .map(|id: String, doc: String, user: String, actor: String, request: RoleRequest, workspaces: Workspaces| {
+            respond(&workspaces, &id, &actor, WorkspaceRole::Owner, || workspaces.set_doc_role(&id, &actor, &doc, &user, request.role))
+        });
+
+    create
+        .or(get)
+        .or(settings)
+        .or(invite)
+        .or(remove)
+        .or(transfer)
+        .or(add_doc)
+        .or(list_docs)
+        .or(doc_role)
+        .or(share)
+        .or(revoke)
+        .recover(recover_signed_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::auth::bearer_for;
+    use chrono::TimeZone;
+
+    fn workspace_with_team(workspaces: &Workspaces) -> String {
+        let id = workspaces.create("olga", "Platform").unwrap().id;
+        workspaces.set_member(&id, "olga", "ed", None).unwrap();
+        workspaces.set_member(&id, "olga", "vic", Some(WorkspaceRole::Viewer)).unwrap();
+        workspaces.add_doc(&id, "olga", "design.md").unwrap();
+        workspaces.add_doc(&id, "ed", "notes.md").unwrap();
+        id
+    }
+
+    #[test]
+    fn test_doc_roles_inherit_and_override() {
+        let workspaces = Workspaces::new();
+        let id = workspace_with_team(&workspaces);
+        assert_eq!(workspaces.doc_role("design.md", "ed"), Some(WorkspaceRole::Editor)); // The default role
+        assert_eq!(workspaces.doc_role("design.md", "vic"), Some(WorkspaceRole::Viewer));
+        assert_eq!(workspaces.doc_role("design.md", "stranger"), None);
+
+        // Overrides win over the workspace role, both ways, for that document only
+        workspaces.set_doc_role(&id, "olga", "design.md", "vic", Some(WorkspaceRole::Editor)).unwrap();
+        workspaces.set_doc_role(&id, "olga", "design.md", "ed", Some(WorkspaceRole::Viewer)).unwrap();
+        workspaces.set_doc_role(&id, "olga", "notes.md", "guest", Some(WorkspaceRole::Viewer)).unwrap();
+        assert_eq!(workspaces.doc_role("design.md", "vic"), Some(WorkspaceRole::Editor));
+        assert_eq!(workspaces.doc_role("design.md", "ed"), Some(WorkspaceRole::Viewer));
+        assert_eq!(workspaces.doc_role("notes.md", "ed"), Some(WorkspaceRole::Editor));
+        assert_eq!(workspaces.doc_role("notes.md", "guest"), Some(WorkspaceRole::Viewer));
+
+        // Nobody overrides the owner, and only the owner manages roles
+        assert!(workspaces.set_doc_role(&id, "olga", "design.md", "olga", Some(WorkspaceRole::Viewer)).is_err());
+        assert!(workspaces.set_doc_role(&id, "ed", "notes.md", "vic", Some(WorkspaceRole::Editor)).is_err());
+        workspaces.set_doc_role(&id, "olga", "design.md", "ed", None).unwrap();
+        assert_eq!(workspaces.doc_role("design.md", "ed"), Some(WorkspaceRole::Editor));
+    }
+
+    #[test]
+    fn test_revocation_applies_before_next_edit() {
+        let workspaces = Workspaces::new();
+        let id = workspace_with_team(&workspaces);
+        let mut ed_invalidations = workspaces.subscribe();
+        let mut ed = workspaces.open("design.md", "ed", None).unwrap();
+        let mut vic_invalidations = workspaces.subscribe();
+        let mut vic = workspaces.open("notes.md", "vic", None).unwrap();
+        assert!(workspaces.open("design.md", "stranger", None).is_err());
+        assert!(ed.check_edit().is_ok());
+
+        workspaces.set_member(&id, "olga", "ed", Some(WorkspaceRole::Viewer)).unwrap();
+        let frame = workspaces.sync(&mut ed, &mut ed_invalidations).unwrap();
+        assert_eq!(frame, serde_json::json!({ "type": "permission_changed", "role": "viewer" }));
+        assert!(ed.check_edit().is_err());
+        assert_eq!(workspaces.sync(&mut vic, &mut vic_invalidations), None); // vic's role didn't change
+
+        workspaces.remove_member(&id, "olga", "vic").unwrap();
+        assert_eq!(workspaces.sync(&mut vic, &mut vic_invalidations).unwrap()["role"], serde_json::Value::Null);
+        assert_eq!(vic.role(), None);
+
+        // The owner hands over, and stays on as an editor
+        workspaces.transfer_ownership(&id, "olga", "ed").unwrap();
+        workspaces.sync(&mut ed, &mut ed_invalidations).unwrap();
+        assert_eq!(ed.role(), Some(WorkspaceRole::Owner));
+        assert_eq!(workspaces.role_in(&id, "olga"), Some(WorkspaceRole::Editor));
+        assert!(workspaces.set_member(&id, "olga", "vic", None).is_err());
+    }
+
+    #[test]
+    fn test_promotion_and_share_links_apply_live() {
+        let workspaces = Workspaces::new();
+        let id = workspace_with_team(&workspaces);
+        let mut invalidations = workspaces.subscribe();
+        let mut vic = workspaces.open("design.md", "vic", None).unwrap();
+        assert!(vic.check_edit().is_err());
+
+        workspaces.set_doc_role(&id, "olga", "design.md", "vic", Some(WorkspaceRole::Editor)).unwrap();
+        assert_eq!(workspaces.sync(&mut vic, &mut invalidations).unwrap()["role"], "editor");
+        assert!(vic.check_edit().is_ok());
+
+        // A share link opens the document to outsiders until it is revoked
+        let token = workspaces.create_share_token(&id, "olga", "notes.md", WorkspaceRole::Editor).unwrap();
+        assert!(workspaces.create_share_token(&id, "ed", "notes.md", WorkspaceRole::Editor).is_err());
+        assert!(workspaces.open("design.md", "guest", Some(&token)).is_err()); // Only for the linked document
+        let mut guest_invalidations = workspaces.subscribe();
+        let mut guest = workspaces.open("notes.md", "guest", Some(&token)).unwrap();
+        assert!(guest.check_edit().is_ok());
+
+        workspaces.revoke_share_token(&id, "olga", &token).unwrap();
+        assert_eq!(workspaces.sync(&mut guest, &mut guest_invalidations).unwrap()["role"], serde_json::Value::Null);
+        assert!(guest.check_edit().is_err());
+        assert!(workspaces.open("notes.md", "guest", Some(&token)).is_err());
+    }
+
+    #[test]
+    fn test_change_during_handshake_resolves_to_newer_state() {
+        let workspaces = Workspaces::new();
+        let id = workspace_with_team(&workspaces);
+
+        // The change lands between subscribing and resolving: the cache already has the new
+        // role, and the queued invalidation isn't newer than it
+        let mut invalidations = workspaces.subscribe();
+        workspaces.set_member(&id, "olga", "ed", Some(WorkspaceRole::Viewer)).unwrap();
+        let mut ed = workspaces.open("design.md", "ed", None).unwrap();
+        assert_eq!(ed.role(), Some(WorkspaceRole::Viewer));
+        assert_eq!(workspaces.sync(&mut ed, &mut invalidations), None);
+
+        // The change lands after resolving: the invalidation is newer and wins
+        workspaces.set_member(&id, "olga", "ed", Some(WorkspaceRole::Editor)).unwrap();
+        let generation = ed.generation();
+        assert_eq!(workspaces.sync(&mut ed, &mut invalidations).unwrap()["role"], "editor");
+        assert!(ed.generation() > generation);
+    }
+
+    #[test]
+    fn test_per_message_check_reads_no_storage() {
+        /// Storage counting how often it is read
+        #[derive(Default)]
+        struct CountingStorage {
+            saved: Mutex<Option<String>>,
+            loads: std::sync::atomic::AtomicUsize,
+        }
+        impl Storage for CountingStorage {
+            fn save(&self, _: &str, content: &str) -> Result<(), Box<dyn std::error::Error>> {
+                *self.saved.lock().unwrap() = Some(content.to_string());
+                Ok(())
+            }
+            fn load(&self, _: &str) -> Result<String, Box<dyn std::error::Error>> {
+                self.loads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                self.saved.lock().unwrap().clone().ok_or_else(|| "Not found".into())
+            }
+            fn delete(&self, _: &str) -> Result<(), Box<dyn std::error::Error>> {
+                Ok(())
+            }
+        }
+
+        let storage = Arc::new(CountingStorage::default());
+        let workspaces = Workspaces::with_storage(storage.clone());
+        workspace_with_team(&workspaces);
+        let mut invalidations = workspaces.subscribe();
+        let mut ed = workspaces.open("design.md", "ed", None).unwrap();
+        let loads = storage.loads.load(std::sync::atomic::Ordering::SeqCst);
+
+        const MESSAGES: u32 = 100_000;
+        let start = std::time::Instant::now();
+        for _ in 0..MESSAGES {
+            assert!(workspaces.sync(&mut ed, &mut invalidations).is_none());
+            ed.check_edit().unwrap();
+        }
+        let elapsed = start.elapsed();
+        assert_eq!(storage.loads.load(std::sync::atomic::Ordering::SeqCst), loads);
+        assert!(elapsed < std::time::Duration::from_secs(2), "{:?} for {} checks", elapsed, MESSAGES);
+    }
+
+    #[test]
+    fn test_moving_doc_moves_its_files() {
+        let root = std::env::temp_dir().join(format!("rustpad-workspaces-{}", std::process::id()));
+        let workspaces = Workspaces::new().with_files_root(&root);
+        let platform = workspace_with_team(&workspaces);
+        let web = workspaces.create("ed", "Web").unwrap().id;
+        workspaces.file_manager(&platform).unwrap().create_file("design.md", "# Design").unwrap();
+        let names = |id: &str| -> Vec<String> { workspaces.file_manager(id).unwrap().list_files().unwrap().into_iter().map(|node| node.name).collect() };
+        fs::create_dir_all(root.join(&web)).unwrap();
+        assert!(names(&web).is_empty());
+
+        assert!(workspaces.add_doc(&web, "vic", "design.md").is_err()); // Not a member of Web
+        assert!(workspaces.add_doc(&web, "ed", "../escape.md").is_err());
+        workspaces.add_doc(&web, "ed", "design.md").unwrap();
+        assert_eq!(workspaces.workspace_of("design.md"), Some(web.clone()));
+        assert_eq!(workspaces.doc_role("design.md", "vic"), None); // Platform's members don't come along
+
+        assert_eq!(names(&web), vec!["design.md".to_string()]); // Both trees see the move at once
+        assert!(names(&platform).is_empty());
+        assert_eq!(fs::read_to_string(root.join(&web).join("design.md")).unwrap(), "# Design");
+
+        // The workspace's ignore patterns apply to its tree
+        let settings = WorkspaceSettings { file_ignore: Some(vec!["*.md".to_string()]), ..WorkspaceSettings::default() };
+        assert!(workspaces.configure(&web, "ed", WorkspaceSettings { file_ignore: Some(vec!["../*".to_string()]), ..settings.clone() }, None).is_err());
+        workspaces.configure(&web, "ed", settings, None).unwrap();
+        assert!(names(&web).is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sidebar_lists_docs_with_badges() {
+        let workspaces = Workspaces::new();
+        let id = workspace_with_team(&workspaces);
+        workspaces.set_doc_role(&id, "olga", "notes.md", "vic", Some(WorkspaceRole::Viewer)).unwrap();
+        workspaces.remove_member(&id, "vic", "vic").unwrap(); // vic keeps only the notes override
+        let activity = ActivityFeeds::new(50);
+        let at = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        activity.with_feed("design.md", |feed| {
+            feed.record_edit("olga", 3, at);
+            feed.record_edit("ed", 1, at);
+        });
+        let route = workspace_routes(workspaces, activity);
+
+        let response = warp::test::request().path(&format!("/api/workspaces/{}/docs", id)).header("authorization", bearer_for("ed")).reply(&route).await;
+        assert_eq!(response.status(), 200);
+        let docs: Vec<DocBadge> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(docs.iter().map(|doc| (doc.doc.as_str(), doc.unread)).collect::<Vec<_>>(), vec![("design.md", 1), ("notes.md", 0)]);
+        assert_eq!(docs[0].last_activity, Some(at));
+        assert_eq!(docs[1].last_activity, None);
+
+        let response = warp::test::request().path(&format!("/api/workspaces/{}/docs", id)).header("authorization", bearer_for("vic")).reply(&route).await;
+        assert_eq!(response.status(), 403); // Document overrides don't open the workspace sidebar
+        let response = warp::test::request().path(&format!("/api/workspaces/{}/docs?user=ed", id)).reply(&route).await;
+        assert_eq!(response.status(), 401);
+        let response = warp::test::request().path("/api/workspaces/nope/docs").header("authorization", bearer_for("ed")).reply(&route).await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/api/workspaces/{}/members", id))
+            .header("authorization", bearer_for("ed"))
+            .json(&serde_json::json!({ "user": "mia" }))
+            .reply(&route)
+            .await;
+        assert_eq!(response.status(), 403);
+    }
+}
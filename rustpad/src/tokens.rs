@@ -0,0 +1,214 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+/// What an API token lets a bot or integration do.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    #[serde(rename = "doc:read")]
+    DocRead,
+    #[serde(rename = "doc:write")]
+    DocWrite,
+    #[serde(rename = "chat:write")]
+    ChatWrite,
+}
+
+/// An API token as listed and returned on creation; the secret it is used with is kept apart.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ApiToken {
+    /// Whether the token was given `scope`.
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// Whether connections with this token watch without editing: hidden from presence, sending
+    /// no cursors and counted against the subscriber cap instead of the editor slots.
+    pub fn is_read_only(&self) -> bool {
+        !self.allows(Scope::DocWrite)
+    }
+}
+
+struct Entry {
+    token: ApiToken,
+    revoked: watch::Sender<bool>, // Set on revocation, ending every connection made with the token
+}
+
+/// API tokens for machine access, keyed by their secret.
+#[derive(Clone, Default)]
+pub struct TokenStore {
+    tokens: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl TokenStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        TokenStore::default()
+    }
+
+    /// Creates a token with `scopes`, returning its secret with it. Reading the document is
+    /// the least a token may do.
+    pub fn create(&self, name: &str, scopes: Vec<Scope>) -> Result<(String, ApiToken), String> {
+        if !scopes.iter().any(|scope| matches!(scope, Scope::DocRead | Scope::DocWrite)) {
+            return Err("A token needs the doc:read or doc:write scope".to_string());
+        }
+        let token = ApiToken { id: Uuid::new_v4().to_string(), name: name.to_string(), scopes, created_at: Utc::now() };
+        let secret = format!("rp_{}", Uuid::new_v4().simple());
+        let (revoked, _) = watch::channel(false);
+        self.tokens.lock().unwrap().insert(secret.clone(), Entry { token: token.clone(), revoked });
+        Ok((secret, token))
+    }
+
+    /// The token `secret` belongs to, with a receiver that changes when it is revoked.
+    pub fn authorize(&self, secret: &str) -> Option<(ApiToken, watch::Receiver<bool>)> {
+        let tokens = self.tokens.lock().unwrap();
+        tokens.get(secret).map(|entry| (entry.token.clone(), entry.revoked.subscribe()))
+    }
+
+    /// Every token, oldest first.
+    pub fn list(&self) -> Vec<ApiToken> {
+        let mut tokens: Vec<ApiToken> = self.tokens.lock().unwrap().values().map(|entry| entry.token.clone()).collect();
+        tokens.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        tokens
+    }
+
+    /// Revokes the token with `id`, disconnecting everything connected with it.
+    pub fn revoke(&self, id: &str) -> Result<(), String> {
+        let mut tokens = self.tokens.lock().unwrap();
+        let secret = tokens
+            .iter()
+            .find(|(_, entry)| entry.token.id == id)
+            .map(|(secret, _)| secret.clone())
+            .ok_or_else(|| format!("Token {} does not exist", id))?;
+        let entry = tokens.remove(&secret).unwrap();
+        entry.revoked.send_replace(true);
+        Ok(())
+    }
+}
+
+/// Resolves once `revoked` reports a revocation, or never without a token.
+pub async fn revoked(revoked: Option<watch::Receiver<bool>>) {
+    match revoked {
+        Some(mut revoked) => {
+            // A dropped sender also means the token is gone
+            let _ = revoked.wait_for(|revoked| *revoked).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// The secret of an `Authorization: Bearer` header.
+pub fn bearer(authorization: Option<&str>) -> Option<&str> {
+    authorization?.strip_prefix("Bearer ").map(str::trim)
+}
+
+/// Whether `authorization` carries the server's admin key. Without a configured key nobody is admin.
+pub fn is_admin(admin_key: Option<&str>, authorization: Option<&str>) -> bool {
+    admin_key.is_some_and(|key| bearer(authorization) == Some(key))
+}
+
+#[derive(Deserialize)]
+struct NewToken {
+    #[serde(default)]
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+fn error(status: StatusCode, message: &str) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "type": "error", "message": message })), status).into_response()
+}
+
+/// `POST /api/tokens` creates a token, `GET /api/tokens` lists them and `DELETE /api/tokens/:id`
+/// revokes one, all with the admin key as bearer token.
+pub fn routes(store: TokenStore, admin_key: Option<String>) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+    let admin = warp::header::optional::<String>("authorization").and_then(move |authorization: Option<String>| {
+        let admin = is_admin(admin_key.as_deref(), authorization.as_deref());
+        async move { if admin { Ok(()) } else { Err(warp::reject::custom(NotAdmin)) } }
+    });
+    let with_store = warp::any().map(move || store.clone());
+
+    let create = warp::path!("api" / "tokens")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(with_store.clone())
+        .map(|(), new: NewToken, store: TokenStore| match store.create(&new.name, new.scopes) {
+            Ok((secret, token)) => {
+                let reply = warp::reply::json(&serde_json::json!({ "secret": secret, "token": token }));
+                warp::reply::with_status(reply, StatusCode::CREATED).into_response()
+            }
+            Err(e) => error(StatusCode::BAD_REQUEST, &e),
+        });
+    let list = warp::path!("api" / "tokens")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(with_store.clone())
+        .map(|(), store: TokenStore| warp::reply::json(&store.list()).into_response());
+    let revoke = warp::path!("api" / "tokens" / String)
+        .and(warp::delete())
+        .and(admin)
+        .and(with_store)
+        .map(|id: String, (), store: TokenStore| match store.revoke(&id) {
+            Ok(()) => StatusCode::NO_CONTENT.into_response(),
+            Err(e) => error(StatusCode::NOT_FOUND, &e),
+        });
+
+    create.or(list).unify().or(revoke).unify().recover(|rejection: warp::Rejection| async move {
+        if rejection.find::<NotAdmin>().is_some() {
+            Ok(error(StatusCode::FORBIDDEN, "Managing tokens needs the admin key"))
+        } else {
+            Err(rejection)
+        }
+    })
+}
+
+#[derive(Debug)]
+struct NotAdmin;
+
+impl warp::reject::Reject for NotAdmin {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tokens_need_the_admin_key_and_a_read_scope() {
+        let store = TokenStore::new();
+        let route = routes(store.clone(), Some("admin".to_string()));
+        let create = |authorization: &str, scopes: serde_json::Value| {
+            warp::test::request()
+                .method("POST")
+                .path("/api/tokens")
+                .header("authorization", authorization)
+                .json(&serde_json::json!({ "name": "ci", "scopes": scopes }))
+        };
+
+        assert_eq!(create("Bearer wrong", serde_json::json!(["doc:read"])).reply(&route).await.status(), 403);
+        assert_eq!(create("Bearer admin", serde_json::json!(["chat:write"])).reply(&route).await.status(), 400);
+        let response = create("Bearer admin", serde_json::json!(["doc:read", "chat:write"])).reply(&route).await;
+        assert_eq!(response.status(), 201);
+        let created: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let (token, mut revoked) = store.authorize(created["secret"].as_str().unwrap()).unwrap();
+        assert!(token.is_read_only() && token.allows(Scope::ChatWrite));
+
+        let revoke = warp::test::request().method("DELETE").path(&format!("/api/tokens/{}", token.id)).header("authorization", "Bearer admin");
+        assert_eq!(revoke.reply(&route).await.status(), 204);
+        assert!(*revoked.borrow_and_update());
+        assert!(store.authorize(created["secret"].as_str().unwrap()).is_none());
+
+        // Without an admin key configured, nobody manages tokens
+        let route = routes(store, None);
+        assert_eq!(create("Bearer ", serde_json::json!(["doc:read"])).reply(&route).await.status(), 403);
+    }
+}
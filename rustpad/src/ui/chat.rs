@@ -1,72 +1,125 @@
 use warp::ws::{Message, WebSocket};
 use warp::{Filter, Reply};
-use futures_util::{StreamExt, SinkExt};
+use futures_util::StreamExt;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use crate::client::{self, Client, Clients};
+use crate::rate_limit::RateLimiter;
+use crate::validation::{ChatBody, Username};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Recent messages kept to show clients as they join; the oldest are dropped beyond this
+pub const MAX_CHAT_HISTORY: usize = 500;
+/// Messages each user may send per `CHAT_RATE_WINDOW`
+pub const CHAT_RATE_LIMIT: usize = 10;
+pub const CHAT_RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Chat message; `ChatBody` caps its length at `MAX_CHAT_BODY_LEN`
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChatMessage {
-    pub user: String,
-    pub message: String,
+    pub user: Username,
+    pub message: ChatBody,
 }
 
-type ChatClients = Arc<Mutex<Vec<warp::ws::WebSocket>>>;
-
 /// Manages the chat participants and broadcast functionality
+#[derive(Clone)]
 pub struct ChatManager {
-    clients: ChatClients,
+    clients: Clients, // Each client's messages are forwarded to its socket by a task of its own
+    history: Arc<Mutex<VecDeque<ChatMessage>>>, // Most recent messages, oldest first
+    rate_limiter: RateLimiter,
+    max_history: usize,
 }
 
 impl ChatManager {
     pub fn new() -> Self {
         Self {
-            clients: Arc::new(Mutex::new(Vec::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            history: Arc::new(Mutex::new(VecDeque::new())),
+            rate_limiter: RateLimiter::new(CHAT_RATE_LIMIT, CHAT_RATE_WINDOW),
+            max_history: MAX_CHAT_HISTORY,
+        }
+    }
+
+    /// Keeps at most `max_history` recent messages instead of `MAX_CHAT_HISTORY`
+    pub fn with_max_history(self, max_history: usize) -> Self {
+        Self { max_history, ..self }
+    }
+
+    /// Throttles each user with `rate_limiter` instead of the default limit
+    pub fn with_rate_limiter(self, rate_limiter: RateLimiter) -> Self {
+        Self { rate_limiter, ..self }
+    }
+
+    /// Accepts a message from its sender, refusing it when the sender is over the rate limit.
+    /// Accepted messages join the recent history, dropping the oldest beyond `max_history`.
+    pub fn receive(&self, chat_message: ChatMessage, now: Instant) -> Result<ChatMessage, String> {
+        self.rate_limiter.check(chat_message.user.as_str(), now)?;
+        let mut history = self.history.lock().unwrap();
+        history.push_back(chat_message.clone());
+        while history.len() > self.max_history {
+            history.pop_front();
         }
+        Ok(chat_message)
+    }
+
+    /// The recent messages, oldest first
+    pub fn recent_messages(&self) -> Vec<ChatMessage> {
+        self.history.lock().unwrap().iter().cloned().collect()
     }
 
     /// Registers a new WebSocket client for receiving chat messages
-    pub async fn register_client(&self, socket: WebSocket) {
-        let (mut ws_tx, mut ws_rx) = socket.split();
-        
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.push(ws_tx);
+    pub async fn register_client(self, socket: WebSocket) {
+        let (ws_tx, ws_rx) = socket.split();
+        let (sender, send_task) = client::spawn_writer(ws_tx);
+        let writer = send_task.abort_handle();
+
+        // Catch the new client up on the recent messages before it joins the broadcasts
+        for chat_message in self.recent_messages() {
+            let _ = sender.send(Message::text(serde_json::to_string(&chat_message).unwrap())).await;
         }
+        let client_id = Uuid::new_v4().to_string();
+        client::add_client(self.clients.clone(), client_id.clone(), Client::new(&client_id, "", sender).with_writer(writer.clone()));
+
+        let mut ws_rx = ws_rx.take_until(send_task);
 
         // Wait for incoming chat messages from the client
         while let Some(result) = ws_rx.next().await {
             if let Ok(message) = result {
                 if message.is_text() {
-                    // Broadcast the received message to all clients
-                    let chat_message: ChatMessage = serde_json::from_str(message.to_str().unwrap()).unwrap();
-                    self.broadcast_message(chat_message).await;
+                    // Broadcast the received message to all clients, dropping invalid ones
+                    let received = serde_json::from_str::<ChatMessage>(message.to_str().unwrap())
+                        .map_err(|e| e.to_string())
+                        .and_then(|chat_message| self.receive(chat_message, Instant::now()));
+                    match received {
+                        Ok(chat_message) => self.broadcast_message(chat_message),
+                        Err(e) => println!("Rejected chat message: {}", e),
+                    }
                 }
             }
         }
 
         // Remove the WebSocket client when it disconnects
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.retain(|client| !client.is_closed());
-        }
+        client::remove_client(self.clients.clone(), &client_id);
+        writer.abort();
     }
 
     /// Broadcasts a chat message to all connected clients
-    pub async fn broadcast_message(&self, chat_message: ChatMessage) {
-        let message = serde_json::to_string(&chat_message).unwrap();
-        let clients = self.clients.lock().unwrap();
+    pub fn broadcast_message(&self, chat_message: ChatMessage) {
+        client::broadcast_message(self.clients.clone(), &serde_json::to_string(&chat_message).unwrap());
+    }
+}
 
-        for client in clients.iter() {
-            if client.send(Message::text(message.clone())).await.is_err() {
-                println!("Failed to send message to client");
-            }
-        }
+impl Default for ChatManager {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 /// WebSocket handler for the chat WebSocket route
-pub async fn chat_ws_handler(ws: warp::ws::Ws, manager: ChatManager) -> impl Reply {
-    ws.on_upgrade(move |socket| manager.register_client(socket))
+pub async fn chat_ws_handler(ws: warp::ws::Ws, manager: ChatManager) -> Result<impl Reply, warp::Rejection> {
+    Ok(ws.on_upgrade(move |socket| manager.register_client(socket)))
 }
 
 /// Route for sending chat messages via WebSocket
@@ -82,14 +135,34 @@ fn with_manager(manager: ChatManager) -> impl Filter<Extract = (ChatManager,), E
     warp::any().map(move || manager.clone())
 }
 
-#[tokio::main]
-async fn main() {
-    let chat_manager = ChatManager::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // WebSocket route for the chat system
-    let chat_ws_route = chat_route(chat_manager.clone());
+    fn chat(user: &str, message: &str) -> ChatMessage {
+        ChatMessage { user: Username::try_from(user).unwrap(), message: ChatBody::try_from(message).unwrap() }
+    }
+
+    #[test]
+    fn test_flood_is_throttled() {
+        let manager = ChatManager::new();
+        let now = Instant::now();
+        for _ in 0..CHAT_RATE_LIMIT {
+            manager.receive(chat("spammer", "spam"), now).unwrap();
+        }
+        assert!(manager.receive(chat("spammer", "spam"), now).is_err());
+        assert!(manager.receive(chat("alice", "hello"), now).is_ok());
+        assert!(manager.receive(chat("spammer", "back again"), now + CHAT_RATE_WINDOW).is_ok());
+    }
 
-    // Combine the routes
-    println!("Chat server running at ws://localhost:3030/chat_ws");
-    warp::serve(chat_ws_route).run(([127, 0, 0, 1], 3030)).await;
+    #[test]
+    fn test_history_stays_bounded() {
+        let manager = ChatManager::new().with_max_history(3).with_rate_limiter(RateLimiter::new(100, CHAT_RATE_WINDOW));
+        let now = Instant::now();
+        for n in 0..10 {
+            manager.receive(chat("alice", &n.to_string()), now).unwrap();
+        }
+        let messages: Vec<String> = manager.recent_messages().into_iter().map(|m| m.message.as_str().to_string()).collect();
+        assert_eq!(messages, vec!["7", "8", "9"]);
+    }
 }
@@ -0,0 +1,54 @@
+use crate::ui::input_handler::InputEvent;
+
+/// A command listed in the command palette.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaletteEntry {
+    pub id: &'static str,    // Stable name, e.g. for keybinding config
+    pub title: &'static str, // What the palette shows and searches
+    pub event: InputEvent,   // What running the command sends to the input handler
+}
+
+/// The editing commands offered by the command palette. Comment commands use the comment
+/// syntax of `language`, the current document's language or file extension.
+pub fn entries(language: &str) -> Vec<PaletteEntry> {
+    let entry = |id, title, event| PaletteEntry { id, title, event };
+    vec![
+        entry("edit.undo", "Undo", InputEvent::Undo),
+        entry("edit.redo", "Redo", InputEvent::Redo),
+        entry("edit.copy", "Copy", InputEvent::Copy),
+        entry("edit.cut", "Cut", InputEvent::Cut),
+        entry("edit.indent", "Indent Lines", InputEvent::Tab),
+        entry("edit.dedent", "Dedent Lines", InputEvent::Dedent),
+        entry("edit.duplicateLines", "Duplicate Lines", InputEvent::DuplicateLines),
+        entry("edit.moveLinesUp", "Move Lines Up", InputEvent::MoveLinesUp),
+        entry("edit.moveLinesDown", "Move Lines Down", InputEvent::MoveLinesDown),
+        entry("edit.toggleLineComment", "Toggle Line Comment", InputEvent::ToggleLineComment(language.to_string())),
+        entry("edit.toggleBlockComment", "Toggle Block Comment", InputEvent::ToggleBlockComment(language.to_string())),
+    ]
+}
+
+/// Palette entries whose title contains every word of `query`, ignoring case.
+pub fn search(query: &str, language: &str) -> Vec<PaletteEntry> {
+    let query = query.to_lowercase();
+    entries(language)
+        .into_iter()
+        .filter(|entry| {
+            let title = entry.title.to_lowercase();
+            query.split_whitespace().all(|word| title.contains(word))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_matches_title_words() {
+        let found = search("comment LINE", "py");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].event, InputEvent::ToggleLineComment("py".to_string()));
+        assert_eq!(search("move lines", "rs").len(), 2);
+        assert_eq!(search("", "rs").len(), entries("rs").len());
+    }
+}
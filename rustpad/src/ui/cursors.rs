@@ -1,20 +1,37 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use futures_util::{StreamExt, SinkExt};
 use warp::ws::{Message, WebSocket};
+use crate::display::{DisplayPreferences, Palette, PALETTE_SIZE};
+use crate::networking::cursors::CursorCoalescer;
+use crate::validation::{ColorHex, Username};
 
 /// Represents a collaborator's cursor position
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Cursor {
-    pub user: String,        // The user's name or identifier
+    pub user: Username,      // The user's name or identifier
     pub position: usize,     // The cursor's position (character index) in the document
-    pub color: String,       // The color of the cursor to distinguish users
+    pub color: ColorHex,     // The color of the cursor to distinguish users, in the recipient's palette
+    #[serde(default)]
+    pub user_color_index: usize, // The user's color in any palette, the same for every recipient
+}
+
+/// Frames a client sends on the cursor socket
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum CursorFrame {
+    Display { display: DisplayPreferences }, // How this client shows the others
+    Cursor(Cursor),
 }
 
 /// Manages tracking and displaying of user cursors in the collaborative editor
 pub struct CursorManager {
     cursors: Arc<Mutex<HashMap<String, Cursor>>>,  // Map of user ID to cursor positions
+    coalescer: Mutex<CursorCoalescer>,             // Limits how often each user's moves are broadcast
+    color_indexes: Mutex<HashMap<String, usize>>,  // Palette index of every user seen, kept when they leave
+    displays: Mutex<HashMap<String, DisplayPreferences>>, // Display preferences of recipients, by user
 }
 
 impl CursorManager {
@@ -22,22 +39,56 @@ impl CursorManager {
     pub fn new() -> Self {
         Self {
             cursors: Arc::new(Mutex::new(HashMap::new())),
+            coalescer: Mutex::new(CursorCoalescer::default()),
+            color_indexes: Mutex::new(HashMap::new()),
+            displays: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The palette index of `user`'s color. A new user gets the index used least among the
+    /// users present, so colors repeat only once the palette runs out; a user who returns keeps
+    /// theirs.
+    pub fn assign_color(&self, user: &str) -> usize {
+        let present: Vec<String> = self.cursors.lock().unwrap().keys().cloned().collect();
+        let mut color_indexes = self.color_indexes.lock().unwrap();
+        if let Some(index) = color_indexes.get(user) {
+            return *index;
+        }
+        let mut used = [0usize; PALETTE_SIZE];
+        for index in present.iter().filter_map(|user| color_indexes.get(user)) {
+            used[*index] += 1;
         }
+        let index = (0..PALETTE_SIZE).min_by_key(|index| used[*index]).unwrap_or(0);
+        color_indexes.insert(user.to_string(), index);
+        index
     }
 
-    /// Registers a new cursor for a user
-    pub fn register_cursor(&self, user: String, initial_position: usize, color: String) {
+    /// Registers a new cursor for a user, in the color `assign_color` picks for them
+    pub fn register_cursor(&self, user: Username, initial_position: usize) {
+        let user_color_index = self.assign_color(user.as_str());
+        let color = ColorHex::try_from(Palette::Default.color(user_color_index)).unwrap();
         let mut cursors = self.cursors.lock().unwrap();
         cursors.insert(
-            user.clone(),
+            user.to_string(),
             Cursor {
                 user,
                 position: initial_position,
                 color,
+                user_color_index,
             },
         );
     }
 
+    /// Sets how `user` shows the others; their cursors are sent to them in `display.palette`
+    pub fn set_display(&self, user: &str, display: DisplayPreferences) {
+        self.displays.lock().unwrap().insert(user.to_string(), display);
+    }
+
+    /// How `user` shows the others
+    pub fn display(&self, user: &str) -> DisplayPreferences {
+        self.displays.lock().unwrap().get(user).copied().unwrap_or_default()
+    }
+
     /// Updates the cursor position of a user
     pub fn update_cursor(&self, user: String, new_position: usize) {
         let mut cursors = self.cursors.lock().unwrap();
@@ -46,10 +97,28 @@ impl CursorManager {
         }
     }
 
+    /// Updates the cursor position of a user at `now`, returning whether to broadcast now. Moves
+    /// within the coalescing interval of the last broadcast wait for `due_at`.
+    pub fn move_cursor(&self, user: &str, new_position: usize, now: Instant) -> bool {
+        self.update_cursor(user.to_string(), new_position);
+        self.coalescer.lock().unwrap().moved(user, new_position, now).is_some()
+    }
+
+    /// When held back moves are due, if any
+    pub fn due_at(&self) -> Option<Instant> {
+        self.coalescer.lock().unwrap().next_due()
+    }
+
+    /// Whether held back moves are due by `now`, and so a broadcast
+    pub fn take_due(&self, now: Instant) -> bool {
+        !self.coalescer.lock().unwrap().due(now).is_empty()
+    }
+
     /// Removes a cursor when a user disconnects
     pub fn remove_cursor(&self, user: &str) {
         let mut cursors = self.cursors.lock().unwrap();
         cursors.remove(user);
+        self.coalescer.lock().unwrap().remove(user);
     }
 
     /// Retrieves the current cursor positions for all users
@@ -58,9 +127,19 @@ impl CursorManager {
         cursors.values().cloned().collect()
     }
 
-    /// Broadcasts cursor positions to all clients
-    pub async fn broadcast_cursors(&self, socket: WebSocket) {
-        let cursors = self.get_cursors();
+    /// The current cursors as sent to `recipient`, colored from their palette
+    pub fn cursors_for(&self, recipient: &str) -> Vec<Cursor> {
+        let palette = self.display(recipient).palette;
+        let mut cursors = self.get_cursors();
+        for cursor in cursors.iter_mut() {
+            cursor.color = ColorHex::try_from(palette.color(cursor.user_color_index)).unwrap();
+        }
+        cursors
+    }
+
+    /// Broadcasts cursor positions to a client, in the palette of `recipient`
+    pub async fn broadcast_cursors(&self, socket: WebSocket, recipient: &str) {
+        let cursors = self.cursors_for(recipient);
         let serialized_cursors = serde_json::to_string(&cursors).unwrap();
         let (mut ws_tx, _) = socket.split();
         let _ = ws_tx.send(Message::text(serialized_cursors)).await;
@@ -73,14 +152,59 @@ pub async fn cursor_ws_handler(ws: warp::ws::Ws, manager: Arc<CursorManager>) ->
 }
 
 async fn manage_cursors(mut socket: WebSocket, manager: Arc<CursorManager>) {
-    while let Some(result) = socket.next().await {
+    let mut user: Option<String> = None; // Known from the first cursor the client sends
+    loop {
+        // Broadcast held back moves once they are due, unless a message comes first
+        let due_at = manager.due_at();
+        let wait_for_due = async {
+            match due_at {
+                Some(due_at) => tokio::time::sleep_until(due_at.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        let result = tokio::select! {
+            result = socket.next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            _ = wait_for_due => {
+                if manager.take_due(Instant::now()) {
+                    manager.broadcast_cursors(socket.clone(), user.as_deref().unwrap_or_default()).await;
+                }
+                continue;
+            }
+        };
         if let Ok(message) = result {
             if let Ok(text) = message.to_str() {
-                let cursor: Cursor = serde_json::from_str(text).unwrap();
-                manager.update_cursor(cursor.user.clone(), cursor.position);
-                
-                // Broadcast updated cursor positions to all clients
-                manager.broadcast_cursors(socket.clone()).await;
+                let cursor = match serde_json::from_str(text) {
+                    Ok(CursorFrame::Cursor(cursor)) => cursor,
+                    Ok(CursorFrame::Display { display }) => match &user {
+                        Some(user) => {
+                            // Resend everything in the new palette
+                            manager.set_display(user, display);
+                            manager.broadcast_cursors(socket.clone(), user).await;
+                            continue;
+                        }
+                        None => {
+                            let error = serde_json::json!({ "type": "error", "message": "Send a cursor before display preferences" });
+                            let _ = socket.send(Message::text(error.to_string())).await;
+                            continue;
+                        }
+                    },
+                    Err(e) => {
+                        let error = serde_json::json!({ "type": "error", "message": e.to_string() });
+                        let _ = socket.send(Message::text(error.to_string())).await;
+                        continue;
+                    }
+                };
+                if user.is_none() {
+                    manager.register_cursor(cursor.user.clone(), cursor.position);
+                    user = Some(cursor.user.to_string());
+                }
+                // Broadcast updated cursor positions to all clients, at most once per interval
+                if manager.move_cursor(cursor.user.as_str(), cursor.position, Instant::now()) {
+                    manager.broadcast_cursors(socket.clone(), user.as_deref().unwrap_or_default()).await;
+                }
             }
         }
     }
@@ -111,3 +235,42 @@ async fn main() {
     println!("Cursor synchronization server running on ws://localhost:3030/cursors");
     warp::serve(cursors_route).run(([127, 0, 0, 1], 3030)).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::LabelMode;
+
+    #[test]
+    fn test_recipients_get_colors_from_their_own_palette() {
+        let manager = CursorManager::new();
+        manager.register_cursor(Username::try_from("ana").unwrap(), 0);
+        manager.register_cursor(Username::try_from("ben").unwrap(), 4);
+        manager.set_display("ana", DisplayPreferences { palette: Palette::Deuteranopia, labels: LabelMode::Never, dim_away_users: true });
+        manager.set_display("ben", DisplayPreferences { palette: Palette::HighContrast, ..DisplayPreferences::default() });
+
+        let seen_by = |recipient: &str| -> HashMap<String, (usize, String)> {
+            manager
+                .cursors_for(recipient)
+                .into_iter()
+                .map(|cursor| (cursor.user.to_string(), (cursor.user_color_index, cursor.color.as_str().to_string())))
+                .collect()
+        };
+        let (by_ana, by_ben) = (seen_by("ana"), seen_by("ben"));
+        assert_ne!(by_ana["ana"].0, by_ana["ben"].0);
+        for user in ["ana", "ben"] {
+            let index = by_ana[user].0;
+            assert_eq!(by_ben[user].0, index);
+            assert_eq!(by_ana[user].1, Palette::Deuteranopia.color(index));
+            assert_eq!(by_ben[user].1, Palette::HighContrast.color(index));
+            assert_ne!(by_ana[user].1, by_ben[user].1);
+        }
+        // Recipients without preferences get the default palette
+        assert_eq!(seen_by("cy")["ben"].1, Palette::Default.color(by_ana["ben"].0));
+
+        // A user who leaves and comes back keeps their color
+        manager.remove_cursor("ben");
+        manager.register_cursor(Username::try_from("ben").unwrap(), 0);
+        assert_eq!(seen_by("ana")["ben"].0, by_ana["ben"].0);
+    }
+}
@@ -1,14 +1,17 @@
+use tauri::api::dialog::blocking::FileDialogBuilder;
 use tauri::{CustomMenuItem, Menu, MenuItem, Submenu, Window};
 use crate::editor::state::EditorState;
 use crate::editor::syntax_highlighting::SyntaxHighlighter;
 use crate::networking::peer_sync::PeerSync;
 use crate::networking::websocket::WebSocketClient;
+use crate::ui::local_files::LocalFiles;
 
 pub struct DesktopUI {
     state: EditorState,
     syntax_highlighter: SyntaxHighlighter,
     peer_sync: PeerSync,
     websocket_client: Option<WebSocketClient>,
+    local_files: LocalFiles, // The file on disk backing the editor
 }
 
 impl DesktopUI {
@@ -19,9 +22,35 @@ impl DesktopUI {
             syntax_highlighter: SyntaxHighlighter::new(),
             peer_sync: PeerSync::new(),
             websocket_client: Some(WebSocketClient::new("ws://localhost:8080")),
+            local_files: LocalFiles::new(".rustpad/history"),
         }
     }
 
+    /// Builds the application menu with the File actions handled in `run`.
+    pub fn menu() -> Menu {
+        let file_menu = Menu::new()
+            .add_item(CustomMenuItem::new("open", "Open...").accelerator("CmdOrCtrl+O"))
+            .add_item(CustomMenuItem::new("save", "Save").accelerator("CmdOrCtrl+S"))
+            .add_item(CustomMenuItem::new("save_as", "Save As...").accelerator("CmdOrCtrl+Shift+S"))
+            .add_native_item(MenuItem::Separator)
+            .add_native_item(MenuItem::Quit);
+        Menu::new().add_submenu(Submenu::new("File", file_menu))
+    }
+
+    /// Loads `path` into the editor and highlights it by its extension. Files the
+    /// highlighter doesn't know are shown as plain text.
+    pub fn open_file(&mut self, path: &str) -> Result<(), String> {
+        let language = self.local_files.open(path, &mut self.state)?;
+        self.syntax_highlighter.set_language(language.as_deref().unwrap_or(""));
+        self.syntax_highlighter.highlight(&mut self.state);
+        Ok(())
+    }
+
+    /// Writes the editor's text to `path` and records the save in the file's history.
+    pub fn save_file(&mut self, path: &str) -> Result<(), String> {
+        self.local_files.save(path, &self.state)
+    }
+
     /// Runs the main event loop for the desktop application, handling input, synchronization, and rendering.
     pub fn run(&mut self, window: Window) {
         let cloned_window = window.clone();
@@ -34,6 +63,26 @@ impl DesktopUI {
             }
         });
 
+        // Handle the File menu
+        let menu_window = window.clone();
+        window.on_menu_event(move |event| {
+            let result = match event.menu_item_id() {
+                "open" => match FileDialogBuilder::new().pick_file() {
+                    Some(path) => self.open_file(&path.to_string_lossy()).map(|_| self.render(&menu_window)),
+                    None => Ok(()),
+                },
+                "save" => match self.local_files.path().map(|path| path.to_string_lossy().to_string()) {
+                    Some(path) => self.save_file(&path),
+                    None => self.save_file_as(),
+                },
+                "save_as" => self.save_file_as(),
+                _ => Ok(()),
+            };
+            if let Err(error) = result {
+                menu_window.emit("file_error", error).expect("Failed to report file error");
+            }
+        });
+
         // Continuously listen for WebSocket messages and apply them to the editor state.
         self.listen_for_websocket_messages(&window);
     }
@@ -42,9 +91,14 @@ impl DesktopUI {
     fn handle_input(&mut self, input: &str) {
         self.state.insert_text(input);
         self.syntax_highlighter.highlight(&mut self.state);
+        self.peer_sync.broadcast_change(&self.state);
+    }
 
-        if let Some(ref mut ws_client) = self.websocket_client {
-            let _ = self.peer_sync.broadcast_change(&self.state, ws_client);
+    /// Asks for a path and saves there; cancelling the dialog saves nothing.
+    fn save_file_as(&mut self) -> Result<(), String> {
+        match FileDialogBuilder::new().save_file() {
+            Some(path) => self.save_file(&path.to_string_lossy()),
+            None => Ok(()),
         }
     }
 
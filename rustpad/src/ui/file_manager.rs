@@ -1,8 +1,34 @@
 use serde::{Deserialize, Serialize};
-use std::fs::{self, DirEntry};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::broadcast;
+use uuid::Uuid;
 use warp::{Filter, Reply};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use crate::editor::snippets::FileTemplateStore;
+use crate::storage::Storage;
+
+/// Patterns of files and directories left out of every tree unless configured otherwise
+pub const DEFAULT_IGNORE: &[&str] = &["node_modules/", ".git/"];
+
+/// Children sent per directory expansion, unless configured otherwise
+pub const DEFAULT_MAX_ENTRIES: usize = 500;
+
+/// Levels below the base directory the full tree goes, unless configured otherwise
+pub const DEFAULT_MAX_DEPTH: usize = 8;
+
+/// Longest ignore pattern accepted, and most patterns
+const MAX_PATTERN_LEN: usize = 200;
+const MAX_PATTERNS: usize = 100;
+
+/// Origin of the changes `refresh` finds, for sidebars to tell from their own
+pub const WATCHER_ORIGIN: &str = "watcher";
 
 // Represents a file or folder in the file tree
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -10,12 +36,78 @@ pub struct FileNode {
     pub name: String,
     pub path: String,
     pub is_directory: bool,
-    pub children: Option<Vec<FileNode>>,
+    pub children: Option<Vec<FileNode>>, // `None` for files, and directories not expanded yet
+    #[serde(default)]
+    pub has_children: bool,
+    #[serde(default)]
+    pub truncated: bool, // Only some children were sent; expand again with `continuation` for more
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
+}
+
+/// A command from the sidebar, tagged by its `command` field
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum FileCommand {
+    Create {
+        file_path: String,
+        #[serde(default)]
+        content: String, // Empty starts from the template for the file's extension
+    },
+    Delete { file_path: String },
+    Rename { old_path: String, new_name: String },
+    Reorder { parent: String, order: Vec<String> }, // `parent` is relative to the base directory; "" for it
+    Expand {
+        path: String, // Relative to the base directory
+        #[serde(default)]
+        continuation: Option<String>, // From a truncated expansion, for the children after it
+    },
+    Tree {
+        #[serde(default)]
+        sort_mode: SortMode, // Kept for the connection's later trees
+        #[serde(default)]
+        depth: Option<usize>, // The full tree this many levels deep, instead of the top level
+    },
+}
+
+/// How the children of each directory are ordered in a client's file tree
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortMode {
+    Name,
+    Modified, // Most recently modified first
+    #[default]
+    Custom,   // As reordered by the team; entries never placed come after, by name
+}
+
+/// Custom orders of every directory, by path relative to the base directory
+type Orders = HashMap<String, Vec<String>>;
+
+/// The entries of a directory as last read, unsorted and without ignored ones
+#[derive(Debug, Clone)]
+struct Listing {
+    modified: SystemTime, // Of the directory, to notice it changed
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    is_directory: bool,
 }
 
 /// Manages the file tree UI and sidebar
+#[derive(Clone)]
 pub struct FileManager {
     base_dir: PathBuf,
+    templates: FileTemplateStore, // Starter content for new files
+    orders: Arc<Mutex<Orders>>,
+    storage: Option<(Arc<dyn Storage + Send + Sync>, String)>, // Where `orders` are saved, and under which id
+    changes: broadcast::Sender<String>, // Id of the connection behind each change to the tree
+    ignore: Arc<Mutex<Vec<String>>>,    // Patterns left out of trees, gitignore style
+    listings: Arc<Mutex<HashMap<String, Listing>>>, // By directory relative to the base directory
+    max_entries: usize,
+    max_depth: usize,
 }
 
 impl FileManager {
@@ -23,114 +115,653 @@ impl FileManager {
     pub fn new(base_dir: &str) -> Self {
         Self {
             base_dir: PathBuf::from(base_dir),
+            templates: FileTemplateStore::new(),
+            orders: Arc::new(Mutex::new(HashMap::new())),
+            storage: None,
+            changes: broadcast::channel(64).0,
+            ignore: Arc::new(Mutex::new(default_ignore())),
+            listings: Arc::new(Mutex::new(HashMap::new())),
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
 
-    /// Generates a file tree structure from the base directory
+    /// Sends at most `max_entries` children per expansion
+    pub fn with_max_entries(self, max_entries: usize) -> Self {
+        Self { max_entries: max_entries.max(1), ..self }
+    }
+
+    /// Limits the full tree to `max_depth` levels below the base directory
+    pub fn with_max_depth(self, max_depth: usize) -> Self {
+        Self { max_depth, ..self }
+    }
+
+    /// Leaves files and directories matching `patterns` out of trees, instead of `DEFAULT_IGNORE`;
+    /// patterns are checked by `set_ignore` and `validate_ignore`, which workspaces use
+    pub fn with_ignore(self, patterns: Vec<String>) -> Self {
+        Self { ignore: Arc::new(Mutex::new(patterns)), ..self }
+    }
+
+    /// Leaves files and directories matching `patterns` out of trees, instead of `DEFAULT_IGNORE`.
+    /// Patterns are gitignore style: `*`, `?` and `**` wildcards, a trailing `/` for directories
+    /// only, and a `/` anywhere else to match the path from the base directory instead of names.
+    /// Every clone of the manager, and so every sidebar, switches at once.
+    pub fn set_ignore(&self, patterns: &[String]) -> Result<(), String> {
+        validate_ignore(patterns)?;
+        *self.ignore.lock().unwrap() = patterns.to_vec();
+        self.listings.lock().unwrap().clear();
+        let _ = self.changes.send(WATCHER_ORIGIN.to_string());
+        Ok(())
+    }
+
+    /// Keeps the custom order of directories in `storage` under `id`, loading any saved one
+    pub fn with_storage(self, storage: Arc<dyn Storage + Send + Sync>, id: &str) -> Self {
+        let orders: Orders = storage.load(id).ok().and_then(|saved| serde_json::from_str(&saved).ok()).unwrap_or_default();
+        Self { orders: Arc::new(Mutex::new(orders)), storage: Some((storage, id.to_string())), ..self }
+    }
+
+    /// Changes to the tree, by the id of the connection that made them, so every sidebar can
+    /// send its client the new tree
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.changes.subscribe()
+    }
+
+    /// The templates new files start from, for registering user templates
+    pub fn templates(&self) -> &FileTemplateStore {
+        &self.templates
+    }
+
+    /// Creates a file in the base directory, failing if it already exists. A file created
+    /// without content starts from the template for its extension, if there is one.
+    pub fn create_file(&self, file_path: &str, content: &str) -> io::Result<FileNode> {
+        let file_path = checked_entry(file_path)?;
+        let path = self.base_dir.join(&file_path);
+        let content = match content {
+            "" => self.templates.new_file_content(&Path::new(&file_path).extension().unwrap_or_default().to_string_lossy()),
+            content => content.to_string(),
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::OpenOptions::new().write(true).create_new(true).open(&path)?.write_all(content.as_bytes())?;
+        self.invalidate(&self.relative(&path));
+        self.build_file_tree(&self.relative(&path), SortMode::default(), 0)
+    }
+
+    /// Generates a file tree structure from the base directory, with the children of each
+    /// directory in the custom order, down to the depth limit
     pub fn generate_file_tree(&self) -> io::Result<FileNode> {
-        let base_dir = self.base_dir.clone();
-        let root = self.build_file_tree(base_dir)?;
-        Ok(root)
+        self.generate_sorted_tree(SortMode::default())
     }
 
-    /// Builds the file tree recursively
-    fn build_file_tree(&self, path: PathBuf) -> io::Result<FileNode> {
-        let metadata = fs::metadata(&path)?;
+    /// Generates the file tree with the children of each directory ordered by `sort_mode`, down
+    /// to the depth limit
+    pub fn generate_sorted_tree(&self, sort_mode: SortMode) -> io::Result<FileNode> {
+        self.build_file_tree("", sort_mode, self.max_depth)
+    }
 
+    /// The directory `path`, relative to the base directory, with one page of its children
+    /// ordered by `sort_mode`: the first, or the one after `continuation` from an earlier page.
+    /// Subdirectories come without their children, saying whether they have any.
+    pub fn expand(&self, path: &str, sort_mode: SortMode, continuation: Option<&str>) -> io::Result<FileNode> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let relative = checked_relative(path)?;
+        let offset = match continuation {
+            Some(continuation) => continuation.parse::<usize>().map_err(|_| invalid(format!("Invalid continuation {:?}", continuation)))?,
+            None => 0,
+        };
+        let ancestors = self.ancestors(&relative)?;
+        let entries = self.sorted(&relative, self.listing(&relative)?.entries, sort_mode);
+
+        let end = offset.saturating_add(self.max_entries).min(entries.len());
+        let children = entries
+            .get(offset.min(end)..end)
+            .unwrap_or_default()
+            .iter()
+            .map(|entry| self.child(&relative, entry, &ancestors))
+            .collect();
+        let path = self.base_dir.join(&relative);
+        Ok(FileNode {
+            name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+            path: path.to_string_lossy().to_string(),
+            is_directory: true,
+            children: Some(children),
+            has_children: !entries.is_empty(),
+            truncated: end < entries.len(),
+            continuation: (end < entries.len()).then(|| end.to_string()),
+        })
+    }
+
+    /// Builds the tree of `relative`, a path relative to the base directory, `depth` levels deep
+    fn build_file_tree(&self, relative: &str, sort_mode: SortMode, depth: usize) -> io::Result<FileNode> {
+        let mut ancestors = self.ancestors(relative)?;
+        ancestors.pop(); // Its own, which `subtree` adds back
+        self.subtree(relative, sort_mode, depth, &mut ancestors)
+    }
+
+    /// Builds the tree of `relative` recursively. A directory that is one of its `ancestors`,
+    /// through a symlink, is left without children instead of being followed round the cycle.
+    fn subtree(&self, relative: &str, sort_mode: SortMode, depth: usize, ancestors: &mut Vec<PathBuf>) -> io::Result<FileNode> {
+        let path = self.base_dir.join(relative);
+        let metadata = fs::metadata(&path)?;
         let mut node = FileNode {
-            name: path.file_name().unwrap().to_string_lossy().to_string(),
+            name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
             path: path.to_string_lossy().to_string(),
             is_directory: metadata.is_dir(),
             children: None,
+            has_children: false,
+            truncated: false,
+            continuation: None,
         };
 
-        if metadata.is_dir() {
-            let entries = fs::read_dir(&path)?
-                .filter_map(|entry| entry.ok())
-                .filter_map(|entry| self.build_file_tree(entry.path()).ok())
-                .collect::<Vec<FileNode>>();
-            node.children = Some(entries);
+        let canonical = fs::canonicalize(&path)?;
+        if metadata.is_dir() && !ancestors.contains(&canonical) {
+            let entries = self.sorted(relative, self.listing(relative)?.entries, sort_mode);
+            node.has_children = !entries.is_empty();
+            if depth > 0 {
+                ancestors.push(canonical);
+                let children = entries.iter().filter_map(|entry| self.subtree(&join(relative, &entry.name), sort_mode, depth - 1, ancestors).ok()).collect();
+                ancestors.pop();
+                node.children = Some(children);
+            }
         }
 
         Ok(node)
     }
 
+    /// The node of `entry` in the directory `parent`, without children
+    fn child(&self, parent: &str, entry: &Entry, ancestors: &[PathBuf]) -> FileNode {
+        let relative = join(parent, &entry.name);
+        let path = self.base_dir.join(&relative);
+        let has_children = entry.is_directory
+            && fs::canonicalize(&path).is_ok_and(|canonical| !ancestors.contains(&canonical))
+            && self.listing(&relative).is_ok_and(|listing| !listing.entries.is_empty());
+        FileNode {
+            name: entry.name.clone(),
+            path: path.to_string_lossy().to_string(),
+            is_directory: entry.is_directory,
+            children: None,
+            has_children,
+            truncated: false,
+            continuation: None,
+        }
+    }
+
+    /// The canonical paths of the base directory and of every directory down to `relative`.
+    /// Fails when `relative` goes through a symlink back to one of them.
+    fn ancestors(&self, relative: &str) -> io::Result<Vec<PathBuf>> {
+        let mut ancestors = vec![fs::canonicalize(&self.base_dir)?];
+        let mut path = self.base_dir.clone();
+        for name in relative.split('/').filter(|name| !name.is_empty()) {
+            path.push(name);
+            let canonical = fs::canonicalize(&path)?;
+            if ancestors.contains(&canonical) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is a symlink cycle", relative)));
+            }
+            ancestors.push(canonical);
+        }
+        Ok(ancestors)
+    }
+
+    /// The entries of the directory `relative`, from the cache when it has them
+    fn listing(&self, relative: &str) -> io::Result<Listing> {
+        if let Some(listing) = self.listings.lock().unwrap().get(relative) {
+            return Ok(listing.clone());
+        }
+        let path = self.base_dir.join(relative);
+        let modified = fs::metadata(&path)?.modified()?;
+        let ignore = self.ignore.lock().unwrap().clone();
+        let entries = fs::read_dir(&path)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let is_directory = fs::metadata(entry.path()).ok()?.is_dir(); // Follows symlinks, leaving out broken ones
+                (!is_ignored(&ignore, &join(relative, &name), is_directory)).then_some(Entry { name, is_directory })
+            })
+            .collect();
+        let listing = Listing { modified, entries };
+        self.listings.lock().unwrap().insert(relative.to_string(), listing.clone());
+        Ok(listing)
+    }
+
+    /// `entries` of the directory `relative`, ordered by `sort_mode`
+    fn sorted(&self, relative: &str, mut entries: Vec<Entry>, sort_mode: SortMode) -> Vec<Entry> {
+        match sort_mode {
+            SortMode::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+            SortMode::Modified => {
+                // Read afresh, as editing a file leaves its directory's listing as it was
+                let path = self.base_dir.join(relative);
+                let modified = |entry: &Entry| fs::metadata(path.join(&entry.name)).and_then(|metadata| metadata.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+                entries.sort_by_cached_key(|entry| (std::cmp::Reverse(modified(entry)), entry.name.clone()));
+            }
+            SortMode::Custom => {
+                let orders = self.orders.lock().unwrap();
+                let order = orders.get(relative).map(Vec::as_slice).unwrap_or_default();
+                let position = |name: &str| order.iter().position(|placed| placed == name).unwrap_or(usize::MAX);
+                entries.sort_by(|a, b| position(&a.name).cmp(&position(&b.name)).then_with(|| a.name.cmp(&b.name)));
+            }
+        }
+        entries
+    }
+
+    /// Forgets the cached listings `relative` could have changed: its own and those below it,
+    /// and those of the directories above it
+    fn invalidate(&self, relative: &str) {
+        let below = format!("{}/", relative);
+        self.listings.lock().unwrap().retain(|dir, _| {
+            let above = dir.is_empty() || relative.starts_with(&format!("{}/", dir));
+            !above && dir != relative && !dir.starts_with(&below)
+        });
+    }
+
+    /// Checks the cached listings against the disk, as the file watcher does every few seconds,
+    /// forgetting those of directories changed since they were read. Tells every sidebar when
+    /// any were, and returns them.
+    pub fn refresh(&self) -> Vec<String> {
+        let cached: Vec<(String, SystemTime)> = self.listings.lock().unwrap().iter().map(|(dir, listing)| (dir.clone(), listing.modified)).collect();
+        let stale: Vec<String> = cached
+            .into_iter()
+            .filter(|(dir, modified)| fs::metadata(self.base_dir.join(dir)).and_then(|metadata| metadata.modified()).map_or(true, |now| now != *modified))
+            .map(|(dir, _)| dir)
+            .collect();
+        if !stale.is_empty() {
+            let mut listings = self.listings.lock().unwrap();
+            stale.iter().for_each(|dir| {
+                listings.remove(dir);
+            });
+            let _ = self.changes.send(WATCHER_ORIGIN.to_string());
+        }
+        stale
+    }
+
     /// Lists all files and directories in the base directory as a tree structure
     pub fn list_files(&self) -> io::Result<Vec<FileNode>> {
         let file_tree = self.generate_file_tree()?;
         Ok(file_tree.children.unwrap_or_default())
     }
 
-    /// Deletes a file or directory in the base directory
+    /// Zips the base directory, leaving out ignored files and directories and symlinks, to back
+    /// up or move the project
+    pub fn export_archive(&self) -> io::Result<Vec<u8>> {
+        Ok(self.export_archive_to(Cursor::new(Vec::new()))?.into_inner())
+    }
+
+    /// Zips the base directory into `out`, as `export_archive` does. Files are copied into the
+    /// archive a block at a time, so large ones are never held in memory whole.
+    pub fn export_archive_to<W: Write + Seek>(&self, out: W) -> io::Result<W> {
+        let mut zip = ZipWriter::new(out);
+        let ignore = self.ignore.lock().unwrap().clone();
+        let mut directories = vec![String::new()];
+        while let Some(relative) = directories.pop() {
+            let mut entries: Vec<fs::DirEntry> = fs::read_dir(self.base_dir.join(&relative))?.collect::<io::Result<_>>()?;
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let file_type = entry.file_type()?;
+                let path = join(&relative, &entry.file_name().to_string_lossy());
+                if file_type.is_symlink() || is_ignored(&ignore, &path, file_type.is_dir()) {
+                    continue; // Symlinks could lead out of the project, or round in a cycle
+                }
+                let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+                if file_type.is_dir() {
+                    zip.add_directory(path.as_str(), options)?;
+                    directories.push(path);
+                } else {
+                    zip.start_file(path.as_str(), options)?;
+                    io::copy(&mut fs::File::open(entry.path())?, &mut zip)?;
+                }
+            }
+        }
+        Ok(zip.finish()?)
+    }
+
+    /// Extracts an archive made by `export_archive` into the base directory, and returns the
+    /// paths of the files it holds. Every entry is checked before anything is written: entries
+    /// leaving the base directory, passing through a symlink or replacing an existing file fail
+    /// the whole import.
+    pub fn import_archive(&self, bytes: &[u8]) -> io::Result<Vec<String>> {
+        self.import_archive_from(Cursor::new(bytes))
+    }
+
+    /// Extracts the archive read from `archive`, as `import_archive` does, a block at a time
+    pub fn import_archive_from<R: Read + Seek>(&self, archive: R) -> io::Result<Vec<String>> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let mut archive = ZipArchive::new(archive)?;
+        let mut entries = Vec::new(); // (index, path, is_directory)
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index)?;
+            let name = entry.name();
+            if name.contains('\\') || name.starts_with('/') {
+                return Err(invalid(format!("Archive entry {:?} leaves the project", name)));
+            }
+            let path = checked_relative(name).map_err(|_| invalid(format!("Archive entry {:?} leaves the project", name)))?;
+            if path.is_empty() {
+                continue;
+            }
+            entries.push((index, path, entry.is_dir()));
+        }
+        for (_, path, is_directory) in &entries {
+            let mut prefix = self.base_dir.clone();
+            for part in path.split('/') {
+                prefix.push(part);
+                if fs::symlink_metadata(&prefix).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+                    return Err(invalid(format!("Archive entry {:?} goes through a symlink", path)));
+                }
+            }
+            if !is_directory && prefix.exists() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path)));
+            }
+        }
+
+        let mut files = Vec::new();
+        for (index, path, is_directory) in entries {
+            let target = self.base_dir.join(&path);
+            if is_directory {
+                fs::create_dir_all(&target)?;
+                continue;
+            }
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&target)?;
+            io::copy(&mut archive.by_index(index)?, &mut file)?;
+            files.push(path);
+        }
+        self.listings.lock().unwrap().clear();
+        Ok(files)
+    }
+
+    /// Deletes a file or directory in the base directory, and forgets its place in the custom
+    /// order along with the order of anything below it
     pub fn delete_file(&self, file_path: &str) -> io::Result<()> {
-        let path = self.base_dir.join(file_path);
+        let path = self.base_dir.join(checked_entry(file_path)?);
         if path.is_dir() {
-            fs::remove_dir_all(path)?;
+            fs::remove_dir_all(&path)?;
         } else {
-            fs::remove_file(path)?;
+            fs::remove_file(&path)?;
+        }
+        self.invalidate(&self.relative(&path));
+        self.update_orders(&path, None);
+        Ok(())
+    }
+
+    /// Places the children of `parent`, a directory relative to the base directory, in `order`.
+    /// Every entry must be one of its children, once; children left out keep coming after the
+    /// ordered ones, by name.
+    pub fn reorder(&self, parent: &str, order: &[String]) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let parent = checked_relative(parent)?;
+        let children: HashSet<String> = fs::read_dir(self.base_dir.join(&parent))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        let mut seen = HashSet::new();
+        for name in order {
+            if !children.contains(name) {
+                return Err(invalid(format!("{} is not in {:?}", name, parent)));
+            }
+            if !seen.insert(name) {
+                return Err(invalid(format!("{} is listed twice", name)));
+            }
         }
+
+        let mut orders = self.orders.lock().unwrap();
+        orders.insert(parent, order.to_vec());
+        self.save_orders(&orders);
         Ok(())
     }
 
-    /// Renames a file or directory in the base directory
+    /// Renames a file or directory in the base directory, keeping it in the same directory.
+    /// Never replaces an existing file or directory.
     pub fn rename_file(&self, old_path: &str, new_name: &str) -> io::Result<FileNode> {
-        let old_full_path = self.base_dir.join(old_path);
+        if new_name.is_empty() || new_name == "." || new_name == ".." || new_name.contains(['/', '\\']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid name {:?}", new_name)));
+        }
+        let old_full_path = self.base_dir.join(checked_entry(old_path)?);
         let new_full_path = old_full_path.with_file_name(new_name);
+        if !old_full_path.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", old_path)));
+        }
+        if new_full_path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", new_name)));
+        }
         fs::rename(&old_full_path, &new_full_path)?;
+        self.invalidate(&self.relative(&old_full_path));
+        self.invalidate(&self.relative(&new_full_path));
+        self.update_orders(&old_full_path, Some(new_name));
 
         // Return the updated node
-        self.build_file_tree(new_full_path)
+        self.build_file_tree(&self.relative(&new_full_path), SortMode::default(), self.max_depth)
+    }
+
+    /// Keeps the custom order in step with `path` being renamed to `new_name`, or deleted when
+    /// that is `None`: its place in its directory's order, and the orders of directories below it
+    fn update_orders(&self, path: &Path, new_name: Option<&str>) {
+        let parent = path.parent().map(|parent| self.relative(parent)).unwrap_or_default();
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let old = self.relative(path);
+        let new = new_name.map(|new_name| match parent.as_str() {
+            "" => new_name.to_string(),
+            parent => format!("{}/{}", parent, new_name),
+        });
+
+        let mut orders = self.orders.lock().unwrap();
+        if let Some(order) = orders.get_mut(&parent) {
+            match new_name {
+                Some(new_name) => order.iter_mut().filter(|placed| **placed == name).for_each(|placed| *placed = new_name.to_string()),
+                None => order.retain(|placed| *placed != name),
+            }
+        }
+        let below: Vec<String> = orders.keys().filter(|dir| **dir == old || dir.starts_with(&format!("{}/", old))).cloned().collect();
+        for dir in below {
+            let order = orders.remove(&dir).unwrap();
+            if let Some(new) = &new {
+                orders.insert(format!("{}{}", new, &dir[old.len()..]), order);
+            }
+        }
+        self.save_orders(&orders);
+    }
+
+    fn save_orders(&self, orders: &Orders) {
+        if let Some((storage, id)) = &self.storage {
+            if let Err(e) = storage.save(id, &serde_json::to_string(orders).unwrap()) {
+                eprintln!("Failed to save the file order: {}", e);
+            }
+        }
+    }
+
+    /// `path` relative to the base directory, with `/` separators; "" for the base directory
+    fn relative(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.base_dir).unwrap_or(path);
+        relative.components().map(|component| component.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+    }
+
+    /// Runs a command sent by the sidebar of connection `client_id`, and returns the updated top
+    /// level of the file tree sorted by the connection's `sort_mode`; `expand` returns the
+    /// directory asked for, and `tree` with a depth the full tree that deep. Other connections are told of changes through
+    /// `subscribe`. Malformed commands and failed file operations are errors to report back, not
+    /// reasons to disconnect.
+    pub fn handle_command(&self, client_id: &str, sort_mode: &mut SortMode, text: &str) -> Result<FileNode, String> {
+        let command = serde_json::from_str::<FileCommand>(text).map_err(|e| format!("Invalid command: {}", e))?;
+        let failed = |e: io::Error| format!("Failed to run {:?}: {}", command, e);
+        let result = match &command {
+            FileCommand::Create { file_path, content } => self.create_file(file_path, content).map(|_| ()),
+            FileCommand::Delete { file_path } => self.delete_file(file_path),
+            FileCommand::Rename { old_path, new_name } => self.rename_file(old_path, new_name).map(|_| ()),
+            FileCommand::Reorder { parent, order } => self.reorder(parent, order),
+            FileCommand::Expand { path, continuation } => return self.expand(path, *sort_mode, continuation.as_deref()).map_err(failed),
+            FileCommand::Tree { sort_mode: requested, depth } => {
+                *sort_mode = *requested;
+                if let Some(depth) = depth {
+                    return self.build_file_tree("", *sort_mode, (*depth).min(self.max_depth)).map_err(failed);
+                }
+                Ok(())
+            }
+        };
+        result.map_err(failed)?;
+        if !matches!(command, FileCommand::Tree { .. }) {
+            let _ = self.changes.send(client_id.to_string()); // Fails only when nobody is listening
+        }
+        self.tree(*sort_mode)
+    }
+
+    /// The top level of the file tree sorted by `sort_mode`, or the error to report
+    fn tree(&self, sort_mode: SortMode) -> Result<FileNode, String> {
+        self.expand("", sort_mode, None).map_err(|e| format!("Failed to read the file tree: {}", e))
+    }
+}
+
+/// `DEFAULT_IGNORE`, as the patterns `set_ignore` takes
+pub fn default_ignore() -> Vec<String> {
+    DEFAULT_IGNORE.iter().map(|pattern| pattern.to_string()).collect()
+}
+
+/// Checks the ignore patterns a workspace configures
+pub fn validate_ignore(patterns: &[String]) -> Result<(), String> {
+    if patterns.len() > MAX_PATTERNS {
+        return Err(format!("At most {} ignore patterns are allowed", MAX_PATTERNS));
     }
+    for pattern in patterns {
+        if pattern.trim().is_empty() || pattern.len() > MAX_PATTERN_LEN {
+            return Err(format!("Invalid ignore pattern {:?}", pattern));
+        }
+        if pattern.split('/').any(|part| part == "..") {
+            return Err(format!("Ignore pattern {:?} leaves the workspace", pattern));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `relative`, a path relative to the base directory, matches one of the `patterns`
+fn is_ignored(patterns: &[String], relative: &str, is_directory: bool) -> bool {
+    let name = relative.rsplit('/').next().unwrap_or(relative);
+    patterns.iter().any(|pattern| {
+        let (pattern, directories_only) = match pattern.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern.as_str(), false),
+        };
+        if directories_only && !is_directory {
+            return false;
+        }
+        match pattern.strip_prefix('/') {
+            Some(anchored) => glob_match(anchored, relative),
+            None if pattern.contains('/') => glob_match(pattern, relative),
+            None => glob_match(pattern, name),
+        }
+    })
+}
+
+/// Whether `text` matches the glob `pattern`: `*` and `?` stand for any characters but `/`,
+/// `**` for any at all
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let splits = || text.char_indices().map(|(at, _)| at).chain([text.len()]);
+    if let Some(rest) = pattern.strip_prefix("**") {
+        if rest.strip_prefix('/').is_some_and(|after| glob_match(after, text)) {
+            return true; // No directories at all
+        }
+        return splits().any(|at| glob_match(rest, &text[at..]));
+    }
+    let mut chars = pattern.chars();
+    match chars.next() {
+        None => text.is_empty(),
+        Some('*') => splits().take_while(|at| !text[..*at].contains('/')).any(|at| glob_match(chars.as_str(), &text[at..])),
+        Some('?') => text.chars().next().is_some_and(|c| c != '/' && glob_match(chars.as_str(), &text[c.len_utf8()..])),
+        Some(c) => text.starts_with(c) && glob_match(chars.as_str(), &text[c.len_utf8()..]),
+    }
+}
+
+/// `name` in the directory `parent`, both relative to the base directory
+fn join(parent: &str, name: &str) -> String {
+    match parent {
+        "" => name.to_string(),
+        parent => format!("{}/{}", parent, name),
+    }
+}
+
+/// `path` relative to the base directory without surrounding slashes, refusing ones leaving it
+fn checked_relative(path: &str) -> io::Result<String> {
+    let path = path.trim_matches('/');
+    if Path::new(path).components().any(|component| !matches!(component, std::path::Component::Normal(_))) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid directory {:?}", path)));
+    }
+    Ok(path.to_string())
+}
+
+/// `path` of a file or directory below the base directory, as `checked_relative` but also
+/// refusing absolute paths and the base directory itself
+fn checked_entry(path: &str) -> io::Result<String> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid path {:?}", path));
+    if path.starts_with(['/', '\\']) {
+        return Err(invalid());
+    }
+    match checked_relative(path)? {
+        relative if relative.is_empty() => Err(invalid()),
+        relative => Ok(relative),
+    }
+}
+
+/// Runs `manager.refresh` every `interval`, so sidebars see files created outside the editor
+pub fn spawn_watcher(manager: FileManager, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            let manager = manager.clone();
+            let _ = tokio::task::spawn_blocking(move || manager.refresh()).await;
+        }
+    })
 }
 
 /// WebSocket handler for file tree updates
-pub async fn file_manager_ws_handler(ws: warp::ws::Ws, manager: FileManager) -> impl warp::Reply {
-    ws.on_upgrade(move |socket| handle_file_manager_socket(socket, manager))
+pub async fn file_manager_ws_handler(ws: warp::ws::Ws, manager: FileManager) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(ws.on_upgrade(move |socket| handle_file_manager_socket(socket, manager)))
 }
 
 async fn handle_file_manager_socket(socket: warp::ws::WebSocket, manager: FileManager) {
     let (mut ws_tx, mut ws_rx) = socket.split();
+    let client_id = Uuid::new_v4().to_string();
+    let mut sort_mode = SortMode::default();
+    let mut changes = manager.subscribe();
 
     // Send the initial file tree structure to the connected client
-    let file_tree = manager.generate_file_tree().unwrap();
-    let serialized_tree = serde_json::to_string(&file_tree).unwrap();
-    if ws_tx.send(warp::ws::Message::text(serialized_tree)).await.is_err() {
+    if ws_tx.send(reply(manager.tree(sort_mode))).await.is_err() {
         return; // Handle error in sending the file tree
     }
 
-    // Listen for file management commands (like renaming, deleting)
-    while let Some(Ok(message)) = ws_rx.next().await {
-        if let Ok(text) = message.to_str() {
-            let cmd: serde_json::Value = serde_json::from_str(text).unwrap();
-
-            if let Some(command) = cmd.get("command") {
-                match command.as_str().unwrap() {
-                    "delete" => {
-                        if let Some(file_path) = cmd.get("file_path") {
-                            manager.delete_file(file_path.as_str().unwrap()).unwrap();
-                        }
+    // Listen for file management commands (like renaming, deleting), answering each with the
+    // updated file tree or an error, and send the tree again whenever another connection changes it
+    loop {
+        let message = tokio::select! {
+            message = ws_rx.next() => match message {
+                Some(Ok(message)) => message,
+                _ => return,
+            },
+            change = changes.recv() => match change {
+                Ok(origin) if origin == client_id => continue,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    if ws_tx.send(reply(manager.tree(sort_mode))).await.is_err() {
+                        return;
                     }
-                    "rename" => {
-                        if let Some(old_path) = cmd.get("old_path") {
-                            if let Some(new_name) = cmd.get("new_name") {
-                                manager.rename_file(old_path.as_str().unwrap(), new_name.as_str().unwrap()).unwrap();
-                            }
-                        }
-                    }
-                    _ => {}
+                    continue;
                 }
-            }
-
-            // After executing a command, send the updated file tree
-            let updated_tree = manager.generate_file_tree().unwrap();
-            let updated_serialized_tree = serde_json::to_string(&updated_tree).unwrap();
-            if ws_tx.send(warp::ws::Message::text(updated_serialized_tree)).await.is_err() {
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+        };
+        if let Ok(text) = message.to_str() {
+            if ws_tx.send(reply(manager.handle_command(&client_id, &mut sort_mode, text))).await.is_err() {
                 return; // Handle error in sending the updated file tree
             }
         }
     }
 }
 
+/// The message for a file tree, or for an error
+fn reply(tree: Result<FileNode, String>) -> warp::ws::Message {
+    match tree {
+        Ok(tree) => warp::ws::Message::text(serde_json::to_string(&tree).unwrap()),
+        Err(e) => warp::ws::Message::text(serde_json::json!({ "type": "error", "message": e }).to_string()),
+    }
+}
+
 /// Route for file tree management WebSocket
 pub fn file_manager_route(manager: FileManager) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
     warp::path("file_manager_ws")
@@ -144,15 +775,397 @@ fn with_manager(manager: FileManager) -> impl Filter<Extract = (FileManager,), E
     warp::any().map(move || manager.clone())
 }
 
-/// Example main function for setting up the file manager WebSocket server
-#[tokio::main]
-async fn main() {
-    let file_manager = FileManager::new("project_files");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    /// Names of the children of `path` in `tree`, a path of names below the root
+    fn names(tree: &FileNode, path: &[&str]) -> Vec<String> {
+        let node = path.iter().fold(tree, |node, name| node.children.as_ref().unwrap().iter().find(|child| child.name == *name).unwrap());
+        node.children.as_ref().unwrap().iter().map(|child| child.name.clone()).collect()
+    }
+
+    /// A project with `a.txt`, `b.txt`, `c.txt`, `src/lib.rs` and `src/main.rs`
+    fn project(name: &str) -> (PathBuf, FileManager) {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-{}-{}", name, std::process::id()));
+        let manager = FileManager::new(&dir.to_string_lossy());
+        for file in ["a.txt", "b.txt", "c.txt", "src/lib.rs", "src/main.rs"] {
+            manager.create_file(file, "x").unwrap();
+        }
+        (dir, manager)
+    }
+
+    #[test]
+    fn test_create_file_starts_from_template() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-manager-{}", std::process::id()));
+        let manager = FileManager::new(&dir.to_string_lossy());
+
+        let node = manager.create_file("src/lib.rs", "").unwrap();
+        assert_eq!((node.name.as_str(), node.is_directory), ("lib.rs", false));
+        assert_eq!(fs::read_to_string(dir.join("src/lib.rs")).unwrap(), manager.templates().new_file_content("rs"));
+        assert!(manager.create_file("src/lib.rs", "").is_err()); // Never overwrites
 
-    // WebSocket route for file manager
-    let file_manager_ws_route = file_manager_route(file_manager.clone());
+        manager.create_file("main.rs", "fn main() {}").unwrap();
+        manager.create_file("notes.txt", "").unwrap();
+        assert_eq!(fs::read_to_string(dir.join("main.rs")).unwrap(), "fn main() {}");
+        assert_eq!(fs::read_to_string(dir.join("notes.txt")).unwrap(), "");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_create_refuses_paths_leaving_the_project() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-create-outside-{}", std::process::id()));
+        let manager = FileManager::new(&dir.to_string_lossy());
+        let outside = format!("rustpad-escaped-{}.txt", std::process::id());
+
+        for path in [format!("../{}", outside), format!("src/../../{}", outside), std::env::temp_dir().join(&outside).to_string_lossy().to_string(), String::new()] {
+            let command = serde_json::json!({ "command": "create", "file_path": path, "content": "x" }).to_string();
+            assert!(manager.handle_command("sidebar", &mut SortMode::default(), &command).is_err(), "{}", path);
+        }
+        assert!(!std::env::temp_dir().join(&outside).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_deletes_and_renames_stay_in_the_project() {
+        let (dir, manager) = project("outside");
+        let outside = std::env::temp_dir().join(format!("rustpad-kept-{}", std::process::id()));
+        fs::create_dir_all(&outside).unwrap();
+        let escape = format!("../{}", outside.file_name().unwrap().to_string_lossy());
 
-    // Start the server
-    println!("File Manager server running on ws://localhost:3030/file_manager_ws");
-    warp::serve(file_manager_ws_route).run(([127, 0, 0, 1], 3030)).await;
+        for path in [escape.as_str(), "src/../..", "", "/", outside.to_str().unwrap()] {
+            assert!(manager.delete_file(path).is_err(), "{:?}", path);
+            assert!(manager.rename_file(path, "moved").is_err(), "{:?}", path);
+        }
+        assert!(outside.exists() && dir.join("a.txt").exists());
+        fs::remove_dir_all(&outside).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_commands_are_reported() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-commands-{}", std::process::id()));
+        let manager = FileManager::new(&dir.to_string_lossy());
+        manager.create_file("notes.txt", "notes").unwrap();
+
+        for text in ["not json", r#"{"command":"rename","old_path":"notes.txt"}"#, r#"{"command":"chmod"}"#, r#"{"command":7}"#] {
+            let error = manager.handle_command("sidebar", &mut SortMode::default(), text).unwrap_err();
+            assert!(error.starts_with("Invalid command"), "{}", error);
+        }
+        let error = manager.handle_command("sidebar", &mut SortMode::default(), r#"{"command":"delete","file_path":"missing.txt"}"#).unwrap_err();
+        assert!(error.starts_with("Failed to run"), "{}", error);
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rename_returns_updated_tree() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-rename-{}", std::process::id()));
+        let manager = FileManager::new(&dir.to_string_lossy());
+        manager.create_file("draft.md", "# Draft").unwrap();
+
+        let tree = manager.handle_command("sidebar", &mut SortMode::default(), r#"{"command":"rename","old_path":"draft.md","new_name":"final.md"}"#).unwrap();
+        let names: Vec<&str> = tree.children.as_ref().unwrap().iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, vec!["final.md"]);
+        assert_eq!(fs::read_to_string(dir.join("final.md")).unwrap(), "# Draft");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_operations_reply_with_errors() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-errors-{}", std::process::id()));
+        let manager = FileManager::new(&dir.to_string_lossy());
+        manager.create_file("a.txt", "a").unwrap();
+        manager.create_file("b.txt", "b").unwrap();
+        let send = |text: &str| -> serde_json::Value {
+            let message = reply(manager.handle_command("sidebar", &mut SortMode::default(), text));
+            serde_json::from_str(message.to_str().unwrap()).unwrap()
+        };
+
+        let deleted = send(r#"{"command":"delete","file_path":"missing.txt"}"#);
+        assert_eq!(deleted["type"], "error");
+        assert!(deleted["message"].as_str().unwrap().contains("missing.txt"));
+
+        let renamed = send(r#"{"command":"rename","old_path":"a.txt","new_name":"b.txt"}"#);
+        assert_eq!(renamed["type"], "error");
+        assert!(renamed["message"].as_str().unwrap().contains("b.txt already exists"), "{}", renamed);
+        assert_eq!(send(r#"{"command":"rename","old_path":"a.txt","new_name":"../a.txt"}"#)["type"], "error");
+        assert_eq!((fs::read_to_string(dir.join("a.txt")).unwrap(), fs::read_to_string(dir.join("b.txt")).unwrap()), ("a".to_string(), "b".to_string()));
+
+        // The same connection carries on with the next command
+        let tree = send(r#"{"command":"rename","old_path":"a.txt","new_name":"c.txt"}"#);
+        assert_eq!(tree["children"].as_array().unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reorder_round_trip_per_directory() {
+        let (dir, manager) = project("reorder");
+        let mut changes = manager.subscribe();
+        let mut sort_mode = SortMode::default();
+        let mut send = |text: &str| manager.handle_command("sidebar", &mut sort_mode, text);
+
+        let tree = send(r#"{"command":"reorder","parent":"","order":["c.txt","src"]}"#).unwrap();
+        assert_eq!(names(&tree, &[]), vec!["c.txt", "src", "a.txt", "b.txt"]);
+        assert!(tree.children.as_ref().unwrap()[1].children.is_none()); // Expanded on request
+        assert_eq!(names(&send(r#"{"command":"expand","path":"src"}"#).unwrap(), &[]), vec!["lib.rs", "main.rs"]);
+        assert_eq!(changes.try_recv().unwrap(), "sidebar");
+
+        // Each directory has its own order, and later trees keep both
+        send(r#"{"command":"reorder","parent":"src","order":["main.rs"]}"#).unwrap();
+        manager.create_file("0.txt", "").unwrap();
+        let tree = manager.generate_file_tree().unwrap();
+        assert_eq!(names(&tree, &[]), vec!["c.txt", "src", "0.txt", "a.txt", "b.txt"]);
+        assert_eq!(names(&tree, &["src"]), vec!["main.rs", "lib.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sort_modes() {
+        let (dir, manager) = project("sort");
+        manager.reorder("", &["c.txt".to_string()]).unwrap();
+        let touch = |name: &str, secs: u64| {
+            let file = fs::File::open(dir.join(name)).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs)).unwrap();
+        };
+        for (name, secs) in [("a.txt", 300), ("b.txt", 100), ("c.txt", 200), ("src", 50)] {
+            touch(name, secs);
+        }
+
+        // The mode asked for in a tree request sticks to the connection
+        let mut sort_mode = SortMode::default();
+        let tree = manager.handle_command("sidebar", &mut sort_mode, r#"{"command":"tree","sort_mode":"name"}"#).unwrap();
+        assert_eq!(names(&tree, &[]), vec!["a.txt", "b.txt", "c.txt", "src"]);
+        let tree = manager.handle_command("sidebar", &mut sort_mode, r#"{"command":"create","file_path":"d.txt"}"#).unwrap();
+        assert_eq!(names(&tree, &[]), vec!["a.txt", "b.txt", "c.txt", "d.txt", "src"]);
+        touch("d.txt", 10);
+
+        let tree = manager.handle_command("sidebar", &mut sort_mode, r#"{"command":"tree","sort_mode":"modified"}"#).unwrap();
+        assert_eq!(names(&tree, &[]), vec!["a.txt", "c.txt", "b.txt", "src", "d.txt"]);
+        let tree = manager.handle_command("sidebar", &mut sort_mode, r#"{"command":"tree"}"#).unwrap();
+        assert_eq!(names(&tree, &[]), vec!["c.txt", "a.txt", "b.txt", "d.txt", "src"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reorder_rejects_entries_not_in_directory() {
+        let (dir, manager) = project("reorder-invalid");
+        let mut sort_mode = SortMode::default();
+        for text in [
+            r#"{"command":"reorder","parent":"","order":["a.txt","lib.rs"]}"#,
+            r#"{"command":"reorder","parent":"","order":["a.txt","a.txt"]}"#,
+            r#"{"command":"reorder","parent":"../","order":[]}"#,
+            r#"{"command":"reorder","parent":"missing","order":[]}"#,
+        ] {
+            assert!(manager.handle_command("sidebar", &mut sort_mode, text).unwrap_err().starts_with("Failed to run"), "{}", text);
+        }
+        assert!(manager.orders.lock().unwrap().is_empty());
+        assert_eq!(names(&manager.generate_file_tree().unwrap(), &[]), vec!["a.txt", "b.txt", "c.txt", "src"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_renames_and_deletes_keep_order_clean() {
+        let (dir, manager) = project("reorder-stale");
+        let storage = Arc::new(MemoryStorage::default());
+        let manager = manager.with_storage(storage.clone(), "order.json");
+        manager.reorder("", &["b.txt".to_string(), "src".to_string(), "a.txt".to_string()]).unwrap();
+        manager.reorder("src", &["main.rs".to_string(), "lib.rs".to_string()]).unwrap();
+
+        manager.rename_file("src", "code").unwrap();
+        manager.rename_file("code/main.rs", "app.rs").unwrap();
+        let tree = manager.generate_file_tree().unwrap();
+        assert_eq!(names(&tree, &[]), vec!["b.txt", "code", "a.txt", "c.txt"]);
+        assert_eq!(names(&tree, &["code"]), vec!["app.rs", "lib.rs"]);
+
+        manager.delete_file("b.txt").unwrap();
+        let expected: Orders = HashMap::from([
+            ("".to_string(), vec!["code".to_string(), "a.txt".to_string()]),
+            ("code".to_string(), vec!["app.rs".to_string(), "lib.rs".to_string()]),
+        ]);
+        assert_eq!(*manager.orders.lock().unwrap(), expected);
+        manager.delete_file("code").unwrap();
+        assert_eq!(manager.orders.lock().unwrap().keys().collect::<Vec<_>>(), vec![""]);
+
+        // The order is saved, so a restarted server sorts the same way
+        let restarted = FileManager::new(&dir.to_string_lossy()).with_storage(storage, "order.json");
+        assert_eq!(names(&restarted.generate_file_tree().unwrap(), &[]), vec!["a.txt", "c.txt"]);
+        assert_eq!(restarted.orders.lock().unwrap()[""], vec!["a.txt".to_string()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lazy_expansion() {
+        let (dir, manager) = project("lazy");
+        manager.create_file("src/components/button.rs", "").unwrap();
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        let mut sort_mode = SortMode::default();
+
+        let tree = manager.handle_command("sidebar", &mut sort_mode, r#"{"command":"tree","sort_mode":"name"}"#).unwrap();
+        let top: Vec<(&str, bool, bool)> = tree.children.as_ref().unwrap().iter().map(|node| (node.name.as_str(), node.has_children, node.children.is_some())).collect();
+        assert_eq!(top, vec![("a.txt", false, false), ("b.txt", false, false), ("c.txt", false, false), ("empty", false, false), ("src", true, false)]);
+
+        let src = manager.handle_command("sidebar", &mut sort_mode, r#"{"command":"expand","path":"src"}"#).unwrap();
+        assert_eq!(names(&src, &[]), vec!["components", "lib.rs", "main.rs"]);
+        assert!(src.children.as_ref().unwrap()[0].has_children && !src.truncated);
+        let components = manager.handle_command("sidebar", &mut sort_mode, r#"{"command":"expand","path":"src/components"}"#).unwrap();
+        assert_eq!(names(&components, &[]), vec!["button.rs"]);
+
+        for path in ["../", "src/../..", "a.txt", "missing"] {
+            let text = serde_json::json!({ "command": "expand", "path": path }).to_string();
+            assert!(manager.handle_command("sidebar", &mut sort_mode, &text).is_err(), "{}", path);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncation_and_continuation() {
+        let dir = std::env::temp_dir().join(format!("rustpad-file-pages-{}", std::process::id()));
+        let manager = FileManager::new(&dir.to_string_lossy()).with_max_entries(2);
+        for name in ["1.txt", "2.txt", "3.txt", "4.txt", "5.txt"] {
+            manager.create_file(name, "").unwrap();
+        }
+
+        let mut pages = Vec::new();
+        let mut continuation = None;
+        loop {
+            let page = manager.expand("", SortMode::Name, continuation.as_deref()).unwrap();
+            pages.push(names(&page, &[]));
+            assert_eq!(page.truncated, page.continuation.is_some());
+            match page.continuation {
+                Some(next) => continuation = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(pages, vec![vec!["1.txt", "2.txt"], vec!["3.txt", "4.txt"], vec!["5.txt"]]);
+        assert!(manager.expand("", SortMode::Name, Some("later")).is_err());
+        assert!(names(&manager.expand("", SortMode::Name, Some("9")).unwrap(), &[]).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ignore_globs() {
+        let (dir, manager) = project("ignore");
+        for file in [".git/HEAD", "node_modules/left-pad/index.js", "build/out.o", "src/gen/build.rs", "debug.log", "src/node_modules"] {
+            manager.create_file(file, "").unwrap();
+        }
+        let tree = manager.generate_sorted_tree(SortMode::Name).unwrap();
+        assert_eq!(names(&tree, &[]), vec!["a.txt", "b.txt", "build", "c.txt", "debug.log", "src"]);
+        assert_eq!(names(&tree, &["src"]), vec!["gen", "lib.rs", "main.rs", "node_modules"]); // A file, not a directory
+
+        let patterns: Vec<String> = ["*.log", "/build/", "src/**/build.rs", "node_modules"].iter().map(|pattern| pattern.to_string()).collect();
+        manager.set_ignore(&patterns).unwrap();
+        let tree = manager.generate_sorted_tree(SortMode::Name).unwrap();
+        assert_eq!(names(&tree, &[]), vec![".git", "a.txt", "b.txt", "c.txt", "src"]);
+        assert_eq!(names(&tree, &["src"]), vec!["gen", "lib.rs", "main.rs"]);
+        assert!(names(&tree, &["src", "gen"]).is_empty());
+
+        assert!(manager.set_ignore(&["../secrets".to_string()]).is_err());
+        assert!(manager.set_ignore(&[" ".to_string()]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_cycles_are_not_followed() {
+        let (dir, manager) = project("cycle");
+        std::os::unix::fs::symlink(&dir, dir.join("src/root")).unwrap();
+
+        let tree = manager.generate_sorted_tree(SortMode::Name).unwrap();
+        let root = tree.children.as_ref().unwrap()[3].children.as_ref().unwrap().iter().find(|node| node.name == "root").unwrap();
+        assert!(root.is_directory && root.children.is_none() && !root.has_children);
+
+        let src = manager.expand("src", SortMode::Name, None).unwrap();
+        assert_eq!(names(&src, &[]), vec!["lib.rs", "main.rs", "root"]);
+        assert!(!src.children.as_ref().unwrap()[2].has_children);
+        assert!(manager.expand("src/root", SortMode::Name, None).is_err());
+        assert!(manager.expand("src/root/src", SortMode::Name, None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_invalidated_after_external_creation() {
+        let (dir, manager) = project("watcher");
+        let mut changes = manager.subscribe();
+        assert_eq!(names(&manager.expand("src", SortMode::Name, None).unwrap(), &[]), vec!["lib.rs", "main.rs"]);
+        assert!(manager.refresh().is_empty());
+
+        // Created behind the manager's back: served from the cache until the watcher looks
+        fs::write(dir.join("src/extra.rs"), "").unwrap();
+        assert_eq!(names(&manager.expand("src", SortMode::Name, None).unwrap(), &[]), vec!["lib.rs", "main.rs"]);
+        assert_eq!(manager.refresh(), vec!["src".to_string()]);
+        assert_eq!(changes.try_recv().unwrap(), WATCHER_ORIGIN);
+        assert_eq!(names(&manager.expand("src", SortMode::Name, None).unwrap(), &[]), vec!["extra.rs", "lib.rs", "main.rs"]);
+
+        // The manager's own changes show at once
+        manager.delete_file("src/extra.rs").unwrap();
+        assert_eq!(names(&manager.expand("src", SortMode::Name, None).unwrap(), &[]), vec!["lib.rs", "main.rs"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let (dir, manager) = project("export");
+        manager.create_file("src/nested/deep.rs", "fn deep() {}").unwrap();
+        manager.create_file(".git/HEAD", "ref: main").unwrap();
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        let archive = manager.export_archive().unwrap();
+
+        let copy = std::env::temp_dir().join(format!("rustpad-file-import-{}", std::process::id()));
+        fs::create_dir_all(&copy).unwrap();
+        let imported = FileManager::new(&copy.to_string_lossy());
+        let mut files = imported.import_archive(&archive).unwrap();
+        files.sort();
+        assert_eq!(files, vec!["a.txt", "b.txt", "c.txt", "src/lib.rs", "src/main.rs", "src/nested/deep.rs"]);
+        let tree = imported.generate_sorted_tree(SortMode::Name).unwrap();
+        assert_eq!(names(&tree, &[]), vec!["a.txt", "b.txt", "c.txt", "empty", "src"]);
+        assert_eq!(names(&tree, &["src"]), vec!["lib.rs", "main.rs", "nested"]);
+        assert_eq!(fs::read_to_string(copy.join("src/nested/deep.rs")).unwrap(), "fn deep() {}");
+        assert!(!copy.join(".git").exists()); // Ignored
+
+        // Importing again would overwrite files, so nothing is written
+        fs::remove_file(copy.join("src/nested/deep.rs")).unwrap();
+        assert_eq!(imported.import_archive(&archive).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(!copy.join("src/nested/deep.rs").exists());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&copy).unwrap();
+    }
+
+    #[test]
+    fn test_archive_entries_leaving_the_project_are_refused() {
+        let (dir, manager) = project("traversal");
+        for name in ["../escaped.txt", "src/../../escaped.txt", "/etc/escaped.txt", "..\\escaped.txt"] {
+            let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+            zip.start_file("fine.txt", FileOptions::default()).unwrap();
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(b"gotcha").unwrap();
+            let archive = zip.finish().unwrap().into_inner();
+            assert_eq!(manager.import_archive(&archive).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", name);
+            assert!(!dir.join("fine.txt").exists());
+        }
+        assert!(!dir.parent().unwrap().join("escaped.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_full_tree_is_depth_limited() {
+        let (dir, manager) = project("depth");
+        manager.create_file("src/a/b/c/deep.rs", "").unwrap();
+        let manager = manager.with_max_depth(3);
+
+        let tree = manager.generate_sorted_tree(SortMode::Name).unwrap();
+        assert_eq!(names(&tree, &["src", "a"]), vec!["b"]);
+        let b = &tree.children.as_ref().unwrap()[3].children.as_ref().unwrap()[0].children.as_ref().unwrap()[0];
+        assert!(b.children.is_none() && b.has_children);
+
+        let mut sort_mode = SortMode::default();
+        let tree = manager.handle_command("sidebar", &mut sort_mode, r#"{"command":"tree","sort_mode":"name","depth":2}"#).unwrap();
+        assert_eq!(names(&tree, &["src"]), vec!["a", "lib.rs", "main.rs"]);
+        assert!(tree.children.as_ref().unwrap()[3].children.as_ref().unwrap()[0].children.is_none());
+        let tree = manager.handle_command("sidebar", &mut sort_mode, r#"{"command":"tree","depth":99}"#).unwrap();
+        assert_eq!(names(&tree, &["src", "a"]), vec!["b"]); // No deeper than the limit
+        assert!(tree.children.as_ref().unwrap()[3].children.as_ref().unwrap()[0].children.as_ref().unwrap()[0].children.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -14,7 +14,11 @@ impl InputHandler {
     pub fn handle_input(&self, input_event: InputEvent, state: &mut EditorState) {
         match input_event {
             InputEvent::CharacterInput(character) => {
-                state.insert_text(&character);
+                let mut chars = character.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(ch), None) => state.insert_with_autopair(ch),
+                    _ => state.insert_text(&character),
+                }
             }
             InputEvent::Backspace => {
                 state.delete_character_before_cursor();
@@ -38,7 +42,10 @@ impl InputHandler {
                 state.insert_newline();
             }
             InputEvent::Tab => {
-                state.insert_text("\t");
+                state.indent();
+            }
+            InputEvent::Dedent => {
+                state.dedent();
             }
             InputEvent::Copy => {
                 state.copy_selected_text();
@@ -47,13 +54,50 @@ impl InputHandler {
                 state.cut_selected_text();
             }
             InputEvent::Paste(pasted_text) => {
-                state.insert_text(&pasted_text);
+                state.paste_text(&pasted_text);
+            }
+            InputEvent::MoveCursorTo(position) => {
+                state.move_cursor(position);
+            }
+            InputEvent::Select(start, end) => {
+                state.set_selection(start, end);
+            }
+            InputEvent::ClearSelection => {
+                state.clear_selection();
+            }
+            InputEvent::DeleteRange(start, end) => {
+                state.delete_text(start, end);
+            }
+            InputEvent::DuplicateLines => {
+                state.duplicate_lines();
+            }
+            InputEvent::MoveLinesUp => {
+                state.move_lines_up();
+            }
+            InputEvent::MoveLinesDown => {
+                state.move_lines_down();
+            }
+            InputEvent::ToggleLineComment(language) => {
+                state.toggle_line_comment(&language);
+            }
+            InputEvent::ToggleBlockComment(language) => {
+                state.toggle_block_comment(&language);
+            }
+            InputEvent::Undo | InputEvent::Redo => {
+                // History lives in the editor's VersionControl, which handles these
             }
         }
     }
 }
 
+impl Default for InputHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Enum representing various types of input events that the editor can handle.
+#[derive(Debug, Clone, PartialEq)]
 pub enum InputEvent {
     /// A single character input by the user (e.g., typing 'a', 'b', etc.).
     CharacterInput(String),
@@ -79,9 +123,12 @@ pub enum InputEvent {
     /// Enter key pressed (insert a new line).
     Enter,
 
-    /// Tab key pressed (insert a tab character).
+    /// Tab key pressed (indent to the next tab stop, or indent the selected lines).
     Tab,
 
+    /// Shift+Tab pressed (remove one level of indentation from the current lines).
+    Dedent,
+
     /// Copy the selected text.
     Copy,
 
@@ -90,4 +137,37 @@ pub enum InputEvent {
 
     /// Paste text from the clipboard.
     Paste(String),
+
+    /// Move the cursor to a specific position.
+    MoveCursorTo(usize),
+
+    /// Select the text between the start and end positions.
+    Select(usize, usize),
+
+    /// Clear the current selection.
+    ClearSelection,
+
+    /// Delete the text between the start and end positions.
+    DeleteRange(usize, usize),
+
+    /// Duplicate the current line, or the lines the selection touches.
+    DuplicateLines,
+
+    /// Swap the current lines with the line above.
+    MoveLinesUp,
+
+    /// Swap the current lines with the line below.
+    MoveLinesDown,
+
+    /// Toggle line comments on the current lines, for the given language.
+    ToggleLineComment(String),
+
+    /// Toggle a block comment around the selection, for the given language.
+    ToggleBlockComment(String),
+
+    /// Undo the last change.
+    Undo,
+
+    /// Redo the last undone change.
+    Redo,
 }
@@ -0,0 +1,1126 @@
+use serde::{Deserialize, Serialize};
+
+use crate::editor::config::EditorConfig;
+use crate::editor::state::EditorState;
+use crate::ui::input_handler::InputEvent;
+
+/// Maximum number of entries kept in the Emacs kill ring.
+pub const KILL_RING_SIZE: usize = 30;
+
+/// Keybinding scheme used to translate key presses into editor input events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KeymapMode {
+    #[default]
+    Default,
+    Vim,
+    Emacs,
+}
+
+/// The modes of Vim's modal editing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VimMode {
+    Normal,
+    Insert,
+    Visual,
+}
+
+/// A physical key, without modifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Char(char),
+    Esc,
+    Enter,
+    Backspace,
+    Delete,
+    Tab,
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+/// A raw key press as delivered by the UI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub ctrl: bool,
+    pub alt: bool, // Meta in Emacs terms
+}
+
+impl KeyEvent {
+    /// A key pressed without modifiers.
+    pub fn key(code: KeyCode) -> Self {
+        Self { code, ctrl: false, alt: false }
+    }
+
+    /// A plain character key.
+    pub fn char(c: char) -> Self {
+        Self::key(KeyCode::Char(c))
+    }
+
+    /// A character key pressed with Ctrl.
+    pub fn ctrl(c: char) -> Self {
+        Self { ctrl: true, ..Self::char(c) }
+    }
+
+    /// A character key pressed with Alt/Meta.
+    pub fn alt(c: char) -> Self {
+        Self { alt: true, ..Self::char(c) }
+    }
+}
+
+/// Vim motions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    Left,
+    Right,
+    Down,
+    Up,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    LineStart,
+    LineEnd,
+    FirstLine, // gg, or line N with a count
+    LastLine,  // G, or line N with a count
+}
+
+/// How much text an operator covers for a given motion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MotionKind {
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+
+/// Vim operators that act on the text covered by a motion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Delete,
+    Yank,
+    Change,
+}
+
+/// What an operator applies to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Motion(Motion),
+    Line,                  // dd, yy, cc
+    Word { around: bool }, // iw / aw
+}
+
+/// A fully parsed Vim normal or visual mode command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VimCommand {
+    Move { count: usize, explicit_count: bool, motion: Motion },
+    Operate { operator: Operator, count: usize, target: Target },
+    Simple { count: usize, key: char },
+}
+
+/// Result of parsing the keys typed so far in normal or visual mode.
+enum Parse {
+    Incomplete,
+    Invalid,
+    Done(VimCommand),
+}
+
+/// What the previous Emacs command was, for appending kills and yank-pop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LastCommand {
+    Other,
+    Kill,
+    Yank { start: usize, end: usize, index: usize },
+}
+
+/// Translates raw key presses into `InputEvent`s according to the active keymap.
+/// It is a pure state machine: it reads the current `EditorState` to resolve motions
+/// but never mutates it, so the returned events are applied by the `InputHandler`.
+pub struct KeymapEngine {
+    mode: KeymapMode,
+    vim_mode: VimMode,
+    pending: String,          // Vim keys typed towards an incomplete command
+    visual_anchor: usize,     // Where the Vim visual selection started
+    register: String,         // Vim unnamed register
+    register_linewise: bool,  // Whether the register holds whole lines
+    kill_ring: Vec<String>,   // Emacs kill ring, most recent last
+    mark: Option<usize>,      // Emacs mark
+    ctrl_x_prefix: bool,      // Emacs C-x was pressed
+    last_command: LastCommand,
+}
+
+impl KeymapEngine {
+    /// Creates an engine using the given keymap.
+    pub fn new(mode: KeymapMode) -> Self {
+        Self {
+            mode,
+            vim_mode: VimMode::Normal,
+            pending: String::new(),
+            visual_anchor: 0,
+            register: String::new(),
+            register_linewise: false,
+            kill_ring: Vec::new(),
+            mark: None,
+            ctrl_x_prefix: false,
+            last_command: LastCommand::Other,
+        }
+    }
+
+    /// Creates an engine using the keymap of a user's editor configuration.
+    pub fn from_config(config: &EditorConfig) -> Self {
+        Self::new(config.keymap)
+    }
+
+    /// Switches keymap while editing. Any half-typed command is discarded and Vim starts in normal mode.
+    pub fn set_mode(&mut self, mode: KeymapMode) {
+        self.mode = mode;
+        self.vim_mode = VimMode::Normal;
+        self.pending.clear();
+        self.mark = None;
+        self.ctrl_x_prefix = false;
+        self.last_command = LastCommand::Other;
+    }
+
+    /// Applies a changed editor configuration.
+    pub fn apply_config(&mut self, config: &EditorConfig) {
+        if config.keymap != self.mode {
+            self.set_mode(config.keymap);
+        }
+    }
+
+    /// Returns the active keymap.
+    pub fn mode(&self) -> KeymapMode {
+        self.mode
+    }
+
+    /// Returns the current Vim mode.
+    pub fn vim_mode(&self) -> VimMode {
+        self.vim_mode
+    }
+
+    /// Returns the text held in the Vim register.
+    pub fn register(&self) -> &str {
+        &self.register
+    }
+
+    /// Returns the Emacs kill ring, most recent entry last.
+    pub fn kill_ring(&self) -> &[String] {
+        &self.kill_ring
+    }
+
+    /// Translates a key press into the input events it stands for. Unknown keys and
+    /// sequences produce no events.
+    pub fn handle_key(&mut self, key: KeyEvent, state: &EditorState) -> Vec<InputEvent> {
+        match self.mode {
+            KeymapMode::Default => default_key(key),
+            KeymapMode::Vim => self.vim_key(key, state),
+            KeymapMode::Emacs => self.emacs_key(key, state),
+        }
+    }
+
+    fn vim_key(&mut self, key: KeyEvent, state: &EditorState) -> Vec<InputEvent> {
+        let text = &state.get_text()[..];
+        let pos = state.get_cursor_position();
+
+        if self.vim_mode == VimMode::Insert {
+            if key.code == KeyCode::Esc {
+                self.vim_mode = VimMode::Normal;
+                // Like Vim, leaving insert mode steps back onto the last inserted character
+                return if pos > line_start(text, pos) { vec![InputEvent::MoveCursorTo(prev(text, pos))] } else { Vec::new() };
+            }
+            return default_key(key);
+        }
+
+        if key.code == KeyCode::Esc {
+            self.pending.clear();
+            if self.vim_mode == VimMode::Visual {
+                self.vim_mode = VimMode::Normal;
+                return vec![InputEvent::ClearSelection];
+            }
+            return Vec::new();
+        }
+        if key.ctrl && key.code == KeyCode::Char('r') && self.pending.is_empty() {
+            return vec![InputEvent::Redo];
+        }
+        let c = match key.code {
+            KeyCode::Char(c) if !key.ctrl && !key.alt => c,
+            _ => {
+                self.pending.clear(); // Anything else aborts the pending command
+                return Vec::new();
+            }
+        };
+
+        self.pending.push(c);
+        match parse_vim(&self.pending, self.vim_mode == VimMode::Visual) {
+            Parse::Incomplete => Vec::new(),
+            Parse::Invalid => {
+                self.pending.clear();
+                Vec::new()
+            }
+            Parse::Done(command) => {
+                self.pending.clear();
+                match self.vim_mode {
+                    VimMode::Visual => self.vim_visual(command, text, pos),
+                    _ => self.vim_normal(command, text, pos),
+                }
+            }
+        }
+    }
+
+    fn vim_normal(&mut self, command: VimCommand, text: &str, pos: usize) -> Vec<InputEvent> {
+        match command {
+            VimCommand::Move { count, explicit_count, motion } => {
+                vec![InputEvent::MoveCursorTo(cursor_target(text, pos, motion, count, explicit_count))]
+            }
+            VimCommand::Operate { operator, count, target } => self.operate(operator, count, target, text, pos),
+            VimCommand::Simple { count, key } => self.simple(key, count, text, pos),
+        }
+    }
+
+    fn operate(&mut self, operator: Operator, count: usize, target: Target, text: &str, pos: usize) -> Vec<InputEvent> {
+        let (start, end, linewise) = match target {
+            Target::Line => {
+                let last = nth_line_start(text, line_index(text, pos) + count - 1);
+                if operator == Operator::Change {
+                    // cc keeps the line itself and replaces its content
+                    (line_start(text, pos), line_end(text, last), false)
+                } else {
+                    let (start, end) = linewise_range(text, pos, last);
+                    (start, end, true)
+                }
+            }
+            Target::Word { around } => {
+                let (start, end) = word_object(text, pos, count, around);
+                (start, end, false)
+            }
+            Target::Motion(motion) => {
+                // cw on a word behaves like ce, except that it starts from the current word
+                let change_word = operator == Operator::Change
+                    && motion == Motion::WordForward
+                    && char_at(text, pos).is_some_and(|c| !c.is_whitespace());
+                let (motion, target) = if change_word {
+                    let current_end = prev(text, skip_while(text, pos, |c| Some(char_class(c)) == char_at(text, pos).map(char_class)));
+                    (Motion::WordEnd, (1..count).fold(current_end, |at, _| word_end(text, at)))
+                } else {
+                    (motion, operator_target(text, pos, motion, count))
+                };
+                match motion_kind(motion) {
+                    MotionKind::Linewise => {
+                        let (start, end) = linewise_range(text, pos.min(target), pos.max(target));
+                        (start, end, true)
+                    }
+                    MotionKind::Inclusive => (pos.min(target), next(text, pos.max(target)), false),
+                    MotionKind::Exclusive => {
+                        let (start, mut end) = (pos.min(target), pos.max(target));
+                        // A word motion that runs onto the next line stops at the end of the line
+                        if motion == Motion::WordForward {
+                            if let Some(newline) = text[start..end].find('\n').filter(|i| *i > 0) {
+                                end = start + newline;
+                            }
+                        }
+                        (start, end, false)
+                    }
+                }
+            }
+        };
+        if start >= end {
+            return Vec::new();
+        }
+
+        self.register = text[start..end].to_string();
+        self.register_linewise = linewise;
+        match operator {
+            Operator::Delete => {
+                let cursor = if linewise && end == text.len() && start > 0 { line_start(text, start - 1) } else { start };
+                vec![InputEvent::DeleteRange(start, end), InputEvent::MoveCursorTo(cursor)]
+            }
+            Operator::Yank => vec![
+                InputEvent::Select(start, end),
+                InputEvent::Copy,
+                InputEvent::ClearSelection,
+                InputEvent::MoveCursorTo(start),
+            ],
+            Operator::Change => {
+                self.vim_mode = VimMode::Insert;
+                vec![InputEvent::DeleteRange(start, end), InputEvent::MoveCursorTo(start)]
+            }
+        }
+    }
+
+    fn simple(&mut self, key: char, count: usize, text: &str, pos: usize) -> Vec<InputEvent> {
+        let start = line_start(text, pos);
+        let end = line_end(text, pos);
+        match key {
+            'x' => {
+                let delete_end = (0..count).fold(pos, |at, _| if at < end { next(text, at) } else { at });
+                if delete_end == pos {
+                    return Vec::new();
+                }
+                self.register = text[pos..delete_end].to_string();
+                self.register_linewise = false;
+                vec![InputEvent::DeleteRange(pos, delete_end)]
+            }
+            'p' | 'P' => self.put(key == 'p', count, text, pos),
+            'u' => vec![InputEvent::Undo; count],
+            'i' => self.enter_insert(Vec::new()),
+            'a' => self.enter_insert(vec![InputEvent::MoveCursorTo(if pos < end { next(text, pos) } else { pos })]),
+            'A' => self.enter_insert(vec![InputEvent::MoveCursorTo(end)]),
+            'I' => self.enter_insert(vec![InputEvent::MoveCursorTo(first_non_blank(text, pos))]),
+            'o' => self.enter_insert(vec![InputEvent::MoveCursorTo(end), InputEvent::Enter]),
+            'O' => self.enter_insert(vec![InputEvent::MoveCursorTo(start), InputEvent::Enter, InputEvent::MoveCursorTo(start)]),
+            'v' => {
+                self.vim_mode = VimMode::Visual;
+                self.visual_anchor = pos;
+                vec![InputEvent::Select(pos, next(text, pos))]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn enter_insert(&mut self, events: Vec<InputEvent>) -> Vec<InputEvent> {
+        self.vim_mode = VimMode::Insert;
+        events
+    }
+
+    /// p / P: puts the register after or before the cursor, or below/above the line if it holds lines.
+    fn put(&mut self, after: bool, count: usize, text: &str, pos: usize) -> Vec<InputEvent> {
+        if self.register.is_empty() {
+            return Vec::new();
+        }
+        let content = self.register.repeat(count);
+        if !self.register_linewise {
+            let at = if after && pos < line_end(text, pos) { next(text, pos) } else { pos };
+            let cursor = at + content.len() - content.chars().last().map_or(0, char::len_utf8);
+            return vec![InputEvent::MoveCursorTo(at), InputEvent::Paste(content), InputEvent::MoveCursorTo(cursor)];
+        }
+
+        let end = line_end(text, pos);
+        if !after {
+            let at = line_start(text, pos);
+            return vec![InputEvent::MoveCursorTo(at), InputEvent::Paste(content), InputEvent::MoveCursorTo(at)];
+        }
+        if end < text.len() {
+            vec![InputEvent::MoveCursorTo(end + 1), InputEvent::Paste(content), InputEvent::MoveCursorTo(end + 1)]
+        } else {
+            // Below the last line: the lines go after a new line break instead of before one
+            let lines = content.strip_suffix('\n').unwrap_or(&content);
+            vec![InputEvent::MoveCursorTo(end), InputEvent::Paste(format!("\n{}", lines)), InputEvent::MoveCursorTo(end + 1)]
+        }
+    }
+
+    fn vim_visual(&mut self, command: VimCommand, text: &str, pos: usize) -> Vec<InputEvent> {
+        let anchor = self.visual_anchor;
+        match command {
+            VimCommand::Move { count, explicit_count, motion } => {
+                let target = cursor_target(text, pos, motion, count, explicit_count);
+                vec![InputEvent::MoveCursorTo(target), InputEvent::Select(anchor.min(target), next(text, anchor.max(target)))]
+            }
+            VimCommand::Simple { key: 'v', .. } => {
+                self.vim_mode = VimMode::Normal;
+                vec![InputEvent::ClearSelection]
+            }
+            VimCommand::Simple { key, .. } if "dxyc".contains(key) => {
+                let (start, end) = (anchor.min(pos), next(text, anchor.max(pos)));
+                self.register = text[start..end].to_string();
+                self.register_linewise = false;
+                self.vim_mode = if key == 'c' { VimMode::Insert } else { VimMode::Normal };
+                if key == 'y' {
+                    vec![InputEvent::Select(start, end), InputEvent::Copy, InputEvent::ClearSelection, InputEvent::MoveCursorTo(start)]
+                } else {
+                    vec![InputEvent::Select(start, end), InputEvent::Cut, InputEvent::MoveCursorTo(start)]
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn emacs_key(&mut self, key: KeyEvent, state: &EditorState) -> Vec<InputEvent> {
+        let text = &state.get_text()[..];
+        let pos = state.get_cursor_position();
+        let last_command = std::mem::replace(&mut self.last_command, LastCommand::Other);
+
+        if std::mem::replace(&mut self.ctrl_x_prefix, false) {
+            return match key.code {
+                KeyCode::Char('u') => vec![InputEvent::Undo],
+                _ => Vec::new(),
+            };
+        }
+
+        let c = match key.code {
+            KeyCode::Char(c) => c,
+            _ if key.ctrl || key.alt => return Vec::new(),
+            _ => return default_key(key),
+        };
+
+        if key.ctrl {
+            match c {
+                'f' => vec![InputEvent::MoveCursorTo(next(text, pos))],
+                'b' => vec![InputEvent::MoveCursorTo(prev(text, pos))],
+                'n' => vec![InputEvent::MoveCursorTo(vertical(text, pos, 1))],
+                'p' => vec![InputEvent::MoveCursorTo(vertical(text, pos, -1))],
+                'a' => vec![InputEvent::MoveCursorTo(line_start(text, pos))],
+                'e' => vec![InputEvent::MoveCursorTo(line_end(text, pos))],
+                'd' if pos < text.len() => vec![InputEvent::DeleteRange(pos, next(text, pos))],
+                'k' => {
+                    // Kill to the end of the line, or the line break itself at the end of a line
+                    let end = line_end(text, pos);
+                    let end = if end == pos && pos < text.len() { pos + 1 } else { end };
+                    if end == pos {
+                        return Vec::new();
+                    }
+                    self.kill(&text[pos..end], last_command == LastCommand::Kill);
+                    self.last_command = LastCommand::Kill;
+                    vec![InputEvent::DeleteRange(pos, end)]
+                }
+                ' ' | '@' => {
+                    self.mark = Some(pos);
+                    Vec::new()
+                }
+                'g' => {
+                    self.mark = None;
+                    vec![InputEvent::ClearSelection]
+                }
+                'w' => match self.mark.take() {
+                    Some(mark) if mark != pos => {
+                        let (start, end) = (mark.min(pos), mark.max(pos));
+                        self.kill(&text[start..end], last_command == LastCommand::Kill);
+                        self.last_command = LastCommand::Kill;
+                        vec![InputEvent::Select(start, end), InputEvent::Cut, InputEvent::MoveCursorTo(start)]
+                    }
+                    _ => Vec::new(),
+                },
+                'y' => match self.kill_ring.last() {
+                    Some(entry) => {
+                        self.last_command = LastCommand::Yank { start: pos, end: pos + entry.len(), index: self.kill_ring.len() - 1 };
+                        vec![InputEvent::Paste(entry.clone())]
+                    }
+                    None => Vec::new(),
+                },
+                '/' | '_' => vec![InputEvent::Undo],
+                'x' => {
+                    self.ctrl_x_prefix = true;
+                    Vec::new()
+                }
+                _ => Vec::new(),
+            }
+        } else if key.alt {
+            match c {
+                'f' => vec![InputEvent::MoveCursorTo(emacs_word_forward(text, pos))],
+                'b' => vec![InputEvent::MoveCursorTo(word_backward(text, pos))],
+                '<' => vec![InputEvent::MoveCursorTo(0)],
+                '>' => vec![InputEvent::MoveCursorTo(text.len())],
+                'w' => match self.mark.take() {
+                    Some(mark) if mark != pos => {
+                        let (start, end) = (mark.min(pos), mark.max(pos));
+                        self.kill(&text[start..end], false);
+                        vec![InputEvent::Select(start, end), InputEvent::Copy, InputEvent::ClearSelection]
+                    }
+                    _ => Vec::new(),
+                },
+                'y' => match last_command {
+                    // Replace the text just yanked with the previous kill ring entry
+                    LastCommand::Yank { start, end, index } => {
+                        let index = if index == 0 { self.kill_ring.len() - 1 } else { index - 1 };
+                        let entry = self.kill_ring[index].clone();
+                        self.last_command = LastCommand::Yank { start, end: start + entry.len(), index };
+                        vec![InputEvent::DeleteRange(start, end), InputEvent::Paste(entry)]
+                    }
+                    _ => Vec::new(),
+                },
+                _ => Vec::new(),
+            }
+        } else {
+            default_key(key)
+        }
+    }
+
+    /// Adds killed text to the ring, appending to the latest entry for consecutive kills.
+    fn kill(&mut self, killed: &str, append: bool) {
+        match self.kill_ring.last_mut() {
+            Some(last) if append => last.push_str(killed),
+            _ => {
+                self.kill_ring.push(killed.to_string());
+                if self.kill_ring.len() > KILL_RING_SIZE {
+                    self.kill_ring.remove(0);
+                }
+            }
+        }
+    }
+}
+
+/// Translation shared by the default keymap and Vim insert mode.
+fn default_key(key: KeyEvent) -> Vec<InputEvent> {
+    let event = match (key.code, key.ctrl, key.alt) {
+        (KeyCode::Char('z'), true, false) => InputEvent::Undo,
+        (KeyCode::Char('y'), true, false) => InputEvent::Redo,
+        (KeyCode::Char('c'), true, false) => InputEvent::Copy,
+        (KeyCode::Char('x'), true, false) => InputEvent::Cut,
+        (KeyCode::Char(c), false, false) => InputEvent::CharacterInput(c.to_string()),
+        (KeyCode::Enter, _, _) => InputEvent::Enter,
+        (KeyCode::Backspace, _, _) => InputEvent::Backspace,
+        (KeyCode::Delete, _, _) => InputEvent::Delete,
+        (KeyCode::Tab, _, _) => InputEvent::Tab,
+        (KeyCode::Left, _, _) => InputEvent::CursorLeft,
+        (KeyCode::Right, _, _) => InputEvent::CursorRight,
+        (KeyCode::Up, _, _) => InputEvent::CursorUp,
+        (KeyCode::Down, _, _) => InputEvent::CursorDown,
+        _ => return Vec::new(),
+    };
+    vec![event]
+}
+
+/// Parses `[count] command`, `[count] operator [count] motion`, text objects and `gg`.
+fn parse_vim(keys: &str, visual: bool) -> Parse {
+    let mut chars = keys.chars().peekable();
+    let read_count = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        let mut digits = String::new();
+        while let Some(c) = chars.peek().copied() {
+            // A leading 0 is the line-start motion, not a count
+            if c.is_ascii_digit() && !(c == '0' && digits.is_empty()) {
+                digits.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        digits.parse::<usize>().ok()
+    };
+
+    let first_count = read_count(&mut chars);
+    let c = match chars.next() {
+        Some(c) => c,
+        None => return Parse::Incomplete,
+    };
+    let count = first_count.unwrap_or(1);
+
+    if visual && "vdxyc".contains(c) {
+        return Parse::Done(VimCommand::Simple { count, key: c });
+    }
+    let operator = match c {
+        'd' => Some(Operator::Delete),
+        'y' => Some(Operator::Yank),
+        'c' => Some(Operator::Change),
+        _ => None,
+    };
+
+    if let Some(operator) = operator {
+        let second_count = read_count(&mut chars);
+        let count = count * second_count.unwrap_or(1);
+        let target = match chars.next() {
+            None => return Parse::Incomplete,
+            Some(m) if m == c => Target::Line,
+            Some(object @ ('i' | 'a')) => match chars.next() {
+                None => return Parse::Incomplete,
+                Some('w') => Target::Word { around: object == 'a' },
+                Some(_) => return Parse::Invalid,
+            },
+            Some('g') => match chars.next() {
+                None => return Parse::Incomplete,
+                Some('g') => Target::Motion(Motion::FirstLine),
+                Some(_) => return Parse::Invalid,
+            },
+            Some(m) => match motion_for(m) {
+                Some(motion) => Target::Motion(motion),
+                None => return Parse::Invalid,
+            },
+        };
+        return Parse::Done(VimCommand::Operate { operator, count, target });
+    }
+
+    let explicit_count = first_count.is_some();
+    if c == 'g' {
+        return match chars.next() {
+            None => Parse::Incomplete,
+            Some('g') => Parse::Done(VimCommand::Move { count, explicit_count, motion: Motion::FirstLine }),
+            Some(_) => Parse::Invalid,
+        };
+    }
+    if let Some(motion) = motion_for(c) {
+        return Parse::Done(VimCommand::Move { count, explicit_count, motion });
+    }
+    if "xpPuiaAIoOv".contains(c) {
+        return Parse::Done(VimCommand::Simple { count, key: c });
+    }
+    Parse::Invalid
+}
+
+fn motion_for(c: char) -> Option<Motion> {
+    Some(match c {
+        'h' => Motion::Left,
+        'l' => Motion::Right,
+        'j' => Motion::Down,
+        'k' => Motion::Up,
+        'w' => Motion::WordForward,
+        'b' => Motion::WordBackward,
+        'e' => Motion::WordEnd,
+        '0' => Motion::LineStart,
+        '$' => Motion::LineEnd,
+        'G' => Motion::LastLine,
+        _ => return None,
+    })
+}
+
+fn motion_kind(motion: Motion) -> MotionKind {
+    match motion {
+        Motion::WordEnd | Motion::LineEnd => MotionKind::Inclusive,
+        Motion::Down | Motion::Up | Motion::FirstLine | Motion::LastLine => MotionKind::Linewise,
+        _ => MotionKind::Exclusive,
+    }
+}
+
+/// Where a motion leaves the cursor in normal or visual mode.
+fn cursor_target(text: &str, pos: usize, motion: Motion, count: usize, explicit_count: bool) -> usize {
+    match motion {
+        Motion::Right => (0..count).fold(pos, |at, _| next(text, at)).min(last_char(text, pos)),
+        Motion::Down | Motion::Up => {
+            let target = vertical(text, pos, if motion == Motion::Down { count as isize } else { -(count as isize) });
+            target.min(last_char(text, target))
+        }
+        Motion::LineEnd => last_char(text, pos),
+        Motion::FirstLine | Motion::LastLine => {
+            let line = match (motion, explicit_count) {
+                (_, true) => count - 1,
+                (Motion::FirstLine, false) => 0,
+                _ => text.matches('\n').count(),
+            };
+            first_non_blank(text, nth_line_start(text, line))
+        }
+        _ => operator_target(text, pos, motion, count),
+    }
+}
+
+/// Where a motion ends when used after an operator.
+fn operator_target(text: &str, pos: usize, motion: Motion, count: usize) -> usize {
+    let repeat = |step: &dyn Fn(usize) -> usize| (0..count).fold(pos, |at, _| step(at));
+    match motion {
+        Motion::Left => repeat(&|at| if at > line_start(text, at) { prev(text, at) } else { at }),
+        Motion::Right => repeat(&|at| if at < line_end(text, at) { next(text, at) } else { at }),
+        Motion::Down => vertical(text, pos, count as isize),
+        Motion::Up => vertical(text, pos, -(count as isize)),
+        Motion::WordForward => repeat(&|at| word_forward(text, at)),
+        Motion::WordBackward => repeat(&|at| word_backward(text, at)),
+        Motion::WordEnd => repeat(&|at| word_end(text, at)),
+        Motion::LineStart => line_start(text, pos),
+        Motion::LineEnd => last_char(text, pos),
+        Motion::FirstLine => 0,
+        Motion::LastLine => text.len(),
+    }
+}
+
+/// The whole lines spanning `from` to `to`, including one adjacent line break.
+fn linewise_range(text: &str, from: usize, to: usize) -> (usize, usize) {
+    let start = line_start(text, from);
+    let end = line_end(text, to);
+    if end < text.len() {
+        (start, end + 1)
+    } else if start > 0 {
+        (start - 1, end)
+    } else {
+        (start, end)
+    }
+}
+
+/// The `count` words under the cursor (iw), plus the whitespace after them (aw).
+fn word_object(text: &str, pos: usize, count: usize, around: bool) -> (usize, usize) {
+    let class = match char_at(text, pos) {
+        Some(c) => char_class(c),
+        None => return (pos, pos),
+    };
+    let mut start = pos;
+    while start > 0 && char_before(text, start).map(char_class) == Some(class) {
+        start = prev(text, start);
+    }
+
+    let mut end = pos;
+    for i in 0..count {
+        if i > 0 {
+            end = skip_while(text, end, |c| c.is_whitespace() && c != '\n');
+        }
+        let class = match char_at(text, end) {
+            Some(c) => char_class(c),
+            None => break,
+        };
+        end = skip_while(text, end, |c| char_class(c) == class);
+    }
+    if around {
+        end = skip_while(text, end, |c| c.is_whitespace() && c != '\n');
+    }
+    (start, end)
+}
+
+/// 0 for whitespace, 1 for word characters, 2 for punctuation, as Vim's `w` distinguishes them.
+fn char_class(c: char) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+fn word_forward(text: &str, pos: usize) -> usize {
+    let at = match char_at(text, pos) {
+        Some(c) if !c.is_whitespace() => skip_while(text, pos, |next| char_class(next) == char_class(c)),
+        _ => pos,
+    };
+    skip_while(text, at, char::is_whitespace)
+}
+
+fn word_backward(text: &str, pos: usize) -> usize {
+    let mut at = pos;
+    while at > 0 && char_before(text, at).is_some_and(char::is_whitespace) {
+        at = prev(text, at);
+    }
+    if let Some(class) = char_before(text, at).map(char_class) {
+        while at > 0 && char_before(text, at).map(char_class) == Some(class) {
+            at = prev(text, at);
+        }
+    }
+    at
+}
+
+/// The last character of the current or next word.
+fn word_end(text: &str, pos: usize) -> usize {
+    let at = skip_while(text, next(text, pos), char::is_whitespace);
+    match char_at(text, at) {
+        Some(c) => prev(text, skip_while(text, at, |next| char_class(next) == char_class(c))),
+        None => pos,
+    }
+}
+
+/// Emacs `forward-word`: to the end of the next word.
+fn emacs_word_forward(text: &str, pos: usize) -> usize {
+    let at = skip_while(text, pos, |c| char_class(c) != 1);
+    skip_while(text, at, |c| char_class(c) == 1)
+}
+
+fn skip_while(text: &str, pos: usize, predicate: impl Fn(char) -> bool) -> usize {
+    let mut at = pos;
+    while let Some(c) = char_at(text, at) {
+        if !predicate(c) {
+            break;
+        }
+        at += c.len_utf8();
+    }
+    at
+}
+
+/// Moves `lines` lines down (or up if negative), keeping the column where possible.
+fn vertical(text: &str, pos: usize, lines: isize) -> usize {
+    let column = text[line_start(text, pos)..pos].chars().count();
+    let line = (line_index(text, pos) as isize + lines).clamp(0, text.matches('\n').count() as isize) as usize;
+    let start = nth_line_start(text, line);
+    let end = line_end(text, start);
+    text[start..end].char_indices().nth(column).map_or(end, |(i, _)| start + i)
+}
+
+fn char_at(text: &str, pos: usize) -> Option<char> {
+    text.get(pos..).and_then(|rest| rest.chars().next())
+}
+
+fn char_before(text: &str, pos: usize) -> Option<char> {
+    text[..pos].chars().next_back()
+}
+
+fn next(text: &str, pos: usize) -> usize {
+    char_at(text, pos).map_or(pos, |c| pos + c.len_utf8())
+}
+
+fn prev(text: &str, pos: usize) -> usize {
+    char_before(text, pos).map_or(pos, |c| pos - c.len_utf8())
+}
+
+fn line_start(text: &str, pos: usize) -> usize {
+    text[..pos].rfind('\n').map_or(0, |i| i + 1)
+}
+
+fn line_end(text: &str, pos: usize) -> usize {
+    text[pos..].find('\n').map_or(text.len(), |i| pos + i)
+}
+
+/// The last character of the line, where normal mode keeps the cursor.
+fn last_char(text: &str, pos: usize) -> usize {
+    let (start, end) = (line_start(text, pos), line_end(text, pos));
+    if end > start { prev(text, end) } else { start }
+}
+
+fn first_non_blank(text: &str, pos: usize) -> usize {
+    skip_while(text, line_start(text, pos), |c| c == ' ' || c == '\t').min(last_char(text, pos))
+}
+
+fn line_index(text: &str, pos: usize) -> usize {
+    text[..pos].matches('\n').count()
+}
+
+fn nth_line_start(text: &str, line: usize) -> usize {
+    if line == 0 {
+        return 0;
+    }
+    text.match_indices('\n').nth(line - 1).map_or_else(|| line_start(text, text.len()), |(i, _)| i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::version_control::VersionControl;
+
+    /// Applies engine output to a bare `EditorState`, standing in for the UI.
+    struct Harness {
+        engine: KeymapEngine,
+        state: EditorState,
+        history: VersionControl,
+        clipboard: String,
+    }
+
+    impl Harness {
+        fn new(mode: KeymapMode, text: &str, cursor: usize) -> Self {
+            let mut state = EditorState::new();
+            state.insert_text(text);
+            state.move_cursor(cursor);
+            Self { engine: KeymapEngine::new(mode), state, history: VersionControl::new(), clipboard: String::new() }
+        }
+
+        fn press(&mut self, key: KeyEvent) {
+            for event in self.engine.handle_key(key, &self.state) {
+                self.apply(event);
+            }
+        }
+
+        /// Types plain characters; `<esc>` stands for Escape.
+        fn keys(&mut self, keys: &str) {
+            for part in keys.split("<esc>").enumerate().flat_map(|(i, part)| {
+                let esc = if i > 0 { Some(KeyEvent::key(KeyCode::Esc)) } else { None };
+                esc.into_iter().chain(part.chars().map(KeyEvent::char)).collect::<Vec<_>>()
+            }) {
+                self.press(part);
+            }
+        }
+
+        fn apply(&mut self, event: InputEvent) {
+            let cursor = self.state.get_cursor_position();
+            match event {
+                InputEvent::CharacterInput(text) | InputEvent::Paste(text) => {
+                    self.history.track_change(&self.state);
+                    self.state.insert_text(&text);
+                }
+                InputEvent::Enter => {
+                    self.history.track_change(&self.state);
+                    self.state.insert_text("\n");
+                }
+                InputEvent::Backspace if cursor > 0 => {
+                    self.history.track_change(&self.state);
+                    let start = prev(&self.state.get_text(), cursor);
+                    self.state.delete_text(start, cursor);
+                }
+                InputEvent::MoveCursorTo(position) => self.state.move_cursor(position),
+                InputEvent::Select(start, end) => self.state.set_selection(start, end),
+                InputEvent::ClearSelection => self.state.clear_selection(),
+                InputEvent::DeleteRange(start, end) => {
+                    self.history.track_change(&self.state);
+                    self.state.delete_text(start, end);
+                }
+                InputEvent::Copy => {
+                    let (start, end) = self.state.get_selection_range().unwrap();
+                    self.clipboard = self.state.get_text()[start..end].to_string();
+                }
+                InputEvent::Cut => {
+                    let (start, end) = self.state.get_selection_range().unwrap();
+                    self.clipboard = self.state.get_text()[start..end].to_string();
+                    self.history.track_change(&self.state);
+                    self.state.delete_text(start, end);
+                    self.state.clear_selection();
+                }
+                InputEvent::Undo => {
+                    if let Some(state) = self.history.undo(&self.state) {
+                        self.state = state;
+                    }
+                }
+                InputEvent::Redo => {
+                    if let Some(state) = self.history.redo(&self.state) {
+                        self.state = state;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        fn text(&self) -> String {
+            self.state.get_text().into_owned()
+        }
+
+        fn cursor(&self) -> usize {
+            self.state.get_cursor_position()
+        }
+    }
+
+    #[test]
+    fn test_vim_delete_with_count_and_motion() {
+        let mut vim = Harness::new(KeymapMode::Vim, "one two three four", 0);
+        vim.keys("d2w");
+        assert_eq!(vim.text(), "three four");
+        assert_eq!(vim.cursor(), 0);
+
+        vim.keys("2dw");
+        assert_eq!(vim.text(), "");
+    }
+
+    #[test]
+    fn test_vim_change_inner_word() {
+        let mut vim = Harness::new(KeymapMode::Vim, "hello world foo", 8);
+        vim.keys("ciwthere<esc>");
+        assert_eq!(vim.text(), "hello there foo");
+        assert_eq!(vim.cursor(), 10);
+        assert_eq!(vim.engine.vim_mode(), VimMode::Normal);
+
+        // On the last letter of a word, cw only changes that letter
+        vim.keys("cwbar<esc>");
+        assert_eq!(vim.text(), "hello therbar foo");
+
+        vim.keys("0c2wbye<esc>");
+        assert_eq!(vim.text(), "bye foo");
+
+        vim.keys("0daw");
+        assert_eq!(vim.text(), "foo");
+    }
+
+    #[test]
+    fn test_vim_motions_and_counts() {
+        let mut vim = Harness::new(KeymapMode::Vim, "first line\nsecond\nthird line here", 0);
+        vim.keys("3l");
+        assert_eq!(vim.cursor(), 3);
+        vim.keys("j");
+        assert_eq!(vim.cursor(), 14);
+        vim.keys("j$");
+        assert_eq!(vim.cursor(), vim.text().len() - 1);
+        vim.keys("0w");
+        assert_eq!(vim.cursor(), 24);
+        vim.keys("e");
+        assert_eq!(vim.cursor(), 27);
+        vim.keys("b");
+        assert_eq!(vim.cursor(), 24);
+        vim.keys("gg");
+        assert_eq!(vim.cursor(), 0);
+        vim.keys("G");
+        assert_eq!(vim.cursor(), 18);
+        vim.keys("2G");
+        assert_eq!(vim.cursor(), 11);
+
+        vim.keys("2dd");
+        assert_eq!(vim.text(), "first line");
+        vim.keys("3x");
+        assert_eq!(vim.text(), "st line");
+    }
+
+    #[test]
+    fn test_vim_undo_redo_and_put() {
+        let mut vim = Harness::new(KeymapMode::Vim, "abc\ndef", 0);
+        vim.keys("x");
+        assert_eq!(vim.text(), "bc\ndef");
+        vim.keys("u");
+        assert_eq!(vim.text(), "abc\ndef");
+        vim.press(KeyEvent::ctrl('r'));
+        assert_eq!(vim.text(), "bc\ndef");
+
+        vim.keys("yyjp");
+        assert_eq!(vim.text(), "bc\ndef\nbc");
+        assert_eq!(vim.cursor(), 7);
+    }
+
+    #[test]
+    fn test_vim_visual_selection_feeds_copy_and_cut() {
+        let mut vim = Harness::new(KeymapMode::Vim, "one two three", 0);
+        vim.keys("vey");
+        assert_eq!(vim.clipboard, "one");
+        assert_eq!(vim.engine.register(), "one");
+        assert_eq!(vim.state.get_selection_range(), None);
+        assert_eq!(vim.engine.vim_mode(), VimMode::Normal);
+
+        vim.keys("wvld");
+        assert_eq!(vim.clipboard, "tw");
+        assert_eq!(vim.text(), "one o three");
+        assert_eq!(vim.cursor(), 4);
+
+        vim.keys("v<esc>");
+        assert_eq!(vim.engine.vim_mode(), VimMode::Normal);
+        assert_eq!(vim.state.get_selection_range(), None);
+    }
+
+    #[test]
+    fn test_emacs_navigation_and_kill_ring() {
+        let mut emacs = Harness::new(KeymapMode::Emacs, "alpha beta\ngamma", 0);
+        emacs.press(KeyEvent::alt('f'));
+        assert_eq!(emacs.cursor(), 5);
+        emacs.press(KeyEvent::ctrl('e'));
+        assert_eq!(emacs.cursor(), 10);
+        emacs.press(KeyEvent::ctrl('n'));
+        assert_eq!(emacs.cursor(), 16);
+        emacs.press(KeyEvent::ctrl('a'));
+
+        // Consecutive kills append to one kill ring entry
+        emacs.press(KeyEvent::alt('<'));
+        emacs.press(KeyEvent::ctrl('k'));
+        emacs.press(KeyEvent::ctrl('k'));
+        assert_eq!(emacs.text(), "gamma");
+        assert_eq!(emacs.engine.kill_ring(), ["alpha beta\n"]);
+
+        emacs.press(KeyEvent::ctrl(' '));
+        emacs.press(KeyEvent::alt('f'));
+        emacs.press(KeyEvent::ctrl('w'));
+        assert_eq!(emacs.text(), "");
+        assert_eq!(emacs.clipboard, "gamma");
+
+        emacs.press(KeyEvent::ctrl('y'));
+        assert_eq!(emacs.text(), "gamma");
+        emacs.press(KeyEvent::alt('y'));
+        assert_eq!(emacs.text(), "alpha beta\n");
+
+        // Yank-pop is undone in two steps: the paste, then the removal of the previous yank
+        emacs.press(KeyEvent::ctrl('/'));
+        assert_eq!(emacs.text(), "");
+        emacs.press(KeyEvent::ctrl('x'));
+        emacs.press(KeyEvent::char('u'));
+        assert_eq!(emacs.text(), "gamma");
+    }
+
+    #[test]
+    fn test_mode_switching_mid_session() {
+        let mut harness = Harness::new(KeymapMode::Default, "", 0);
+        harness.keys("hi");
+        assert_eq!(harness.text(), "hi");
+
+        let mut config = EditorConfig::new();
+        config.set_keymap(KeymapMode::Vim);
+        harness.engine.apply_config(&config);
+        harness.keys("0ix<esc>");
+        assert_eq!(harness.text(), "xhi");
+
+        // Vim insert mode is left behind when switching to Emacs
+        harness.keys("a");
+        config.set_keymap(KeymapMode::Emacs);
+        harness.engine.apply_config(&config);
+        harness.press(KeyEvent::ctrl('e'));
+        harness.keys("!");
+        assert_eq!(harness.text(), "xhi!");
+        assert_eq!(harness.engine.mode(), KeymapMode::Emacs);
+    }
+
+    #[test]
+    fn test_unknown_sequences_fall_through() {
+        let mut vim = Harness::new(KeymapMode::Vim, "keep this", 0);
+        vim.keys("zq");
+        vim.keys("dz");
+        vim.keys("ciq");
+        vim.press(KeyEvent::alt('x'));
+        assert_eq!(vim.text(), "keep this");
+        assert_eq!(vim.cursor(), 0);
+        vim.keys("dw");
+        assert_eq!(vim.text(), "this");
+
+        let mut emacs = Harness::new(KeymapMode::Emacs, "text", 0);
+        emacs.press(KeyEvent::ctrl('q'));
+        emacs.press(KeyEvent::alt('y')); // Yank-pop without a preceding yank
+        emacs.press(KeyEvent::ctrl('w')); // No mark set
+        assert_eq!(emacs.text(), "text");
+    }
+}
@@ -0,0 +1,128 @@
+use std::path::{Path, PathBuf};
+use crate::editor::language::language_for_path;
+use crate::editor::state::EditorState;
+use crate::storage::file_storage::FileStorage;
+use crate::storage::history::{FileVersion, HistoryManager};
+
+/// Versions kept per open file
+pub const MAX_FILE_VERSIONS: usize = 50;
+
+/// Opens and saves the desktop editor's document on the local disk, keeping a version
+/// history of each save. Independent of the Tauri window so it can be tested on its own.
+pub struct LocalFiles {
+    storage: FileStorage,     // Resolves paths as given; absolute paths are used as-is
+    history: HistoryManager,  // Versions of every file saved, keyed by its path
+    path: Option<PathBuf>,    // The file currently open, if any
+}
+
+impl LocalFiles {
+    /// Creates a LocalFiles with no file open, writing versions under `history_dir`
+    pub fn new(history_dir: &str) -> Self {
+        Self {
+            storage: FileStorage::new(""),
+            history: HistoryManager::new(history_dir, MAX_FILE_VERSIONS),
+            path: None,
+        }
+    }
+
+    /// Loads `path` into `state`, with the cursor at the start. Returns the highlighter
+    /// language for the file, `None` when it has no extension.
+    pub fn open(&mut self, path: &str, state: &mut EditorState) -> Result<Option<String>, String> {
+        let text = self.storage.load_file(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        state.replace_text(text);
+        state.move_cursor(0);
+
+        let path = PathBuf::from(path);
+        let language = language_for_path(&path);
+        self.history.load_history(&path.to_string_lossy()).map_err(|e| format!("Failed to load the versions of {}: {}", path.display(), e))?;
+        self.path = Some(path);
+        Ok(language)
+    }
+
+    /// Writes the text of `state` to `path` and records it as a new version. Saving to a
+    /// different path than the open file makes it the open file.
+    pub fn save(&mut self, path: &str, state: &EditorState) -> Result<(), String> {
+        let info = self.storage.save_file(path, &state.get_text()).map_err(|e| format!("Failed to save {}: {}", path, e))?;
+
+        self.path = Some(PathBuf::from(path));
+        self.history
+            .add_version(path, &state.get_text(), &format!("Saved at {}", info.last_modified))
+            .map(|_| ())
+            .map_err(|e| format!("Failed to record a version of {}: {}", path, e))
+    }
+
+    /// The file currently open
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The saved versions of every file, keyed by path
+    pub fn history(&self) -> &HistoryManager {
+        &self.history
+    }
+
+    /// The saved versions of the file currently open, oldest first
+    pub fn versions(&self) -> Vec<FileVersion> {
+        self.path.as_ref().map(|path| self.history.list_versions(&path.to_string_lossy())).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_open_and_save_records_versions() {
+        let dir = std::env::temp_dir().join(format!("rustpad-local-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.RS");
+        fs::write(&path, "fn main() {}").unwrap();
+        let path = path.to_string_lossy().to_string();
+
+        let mut files = LocalFiles::new(&dir.join("history").to_string_lossy());
+        let mut state = EditorState::new();
+        assert_eq!(files.open(&path, &mut state).unwrap().as_deref(), Some("rs"));
+        assert_eq!((state.get_text().as_ref(), state.get_cursor_position()), ("fn main() {}", 0));
+
+        state.insert_text("// ");
+        files.save(&path, &state).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "// fn main() {}");
+        let versions = files.versions();
+        assert_eq!((versions.len(), versions[0].content.as_str()), (1, "// fn main() {}"));
+
+        // A file of the same name elsewhere has a history of its own
+        let other = dir.join("other");
+        fs::create_dir_all(&other).unwrap();
+        let other_path = other.join("main.RS").to_string_lossy().to_string();
+        files.save(&other_path, &state).unwrap();
+        assert_eq!(files.history().list_versions(&other_path).len(), 1);
+
+        // Reopening picks the file's versions up again, including in a new LocalFiles
+        files.open(&path, &mut state).unwrap();
+        assert_eq!(files.versions().len(), 1);
+        let mut reopened = LocalFiles::new(&dir.join("history").to_string_lossy());
+        reopened.open(&path, &mut state).unwrap();
+        assert_eq!(reopened.versions()[0].content, "// fn main() {}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_extension_opens_as_plain_text() {
+        let dir = std::env::temp_dir().join(format!("rustpad-local-plain-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("notes.unknownext"), "remember").unwrap();
+        fs::write(dir.join("README"), "read me").unwrap();
+
+        let mut files = LocalFiles::new(&dir.join("history").to_string_lossy());
+        let mut state = EditorState::new();
+        let language = files.open(&dir.join("notes.unknownext").to_string_lossy(), &mut state).unwrap();
+        assert_eq!((language.as_deref(), state.get_text().as_ref()), (Some("unknownext"), "remember"));
+        assert_eq!(files.open(&dir.join("README").to_string_lossy(), &mut state).unwrap(), None);
+        assert_eq!(files.path(), Some(dir.join("README").as_path()));
+
+        assert!(files.open(&dir.join("missing.rs").to_string_lossy(), &mut state).is_err());
+        assert_eq!(state.get_text(), "read me");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,33 +1,52 @@
 pub mod renderer;
 pub mod input_handler;
+pub mod keymap;
+pub mod command_palette;
+pub mod local_files;
+pub mod theme;
+pub mod file_manager;
+pub mod chat;
+pub mod preview;
 
 use crate::editor::state::EditorState;
 use crate::editor::syntax_highlighting::SyntaxHighlighter;
 use crate::ui::renderer::Renderer;
-use crate::ui::input_handler::InputHandler;
+use crate::ui::input_handler::{InputEvent, InputHandler};
+use crate::ui::keymap::{KeyEvent, KeymapEngine, KeymapMode};
+use crate::ui::theme::{initialize_themes, set_editor_theme, Theme, ThemeMessage, Themes};
 
 /// `UI` is the central module for handling the rendering and user interactions in the editor.
 pub struct UI {
     renderer: Renderer,
     input_handler: InputHandler,
+    keymap: KeymapEngine, // Translates key presses for the input handler
     syntax_highlighter: SyntaxHighlighter,
+    themes: Themes,
+    theme: Theme, // Editor colors, following the highlight theme
 }
 
 impl UI {
     /// Creates a new `UI` instance with the required components.
     pub fn new() -> Self {
+        let syntax_highlighter = SyntaxHighlighter::new();
+        let theme = Theme { name: syntax_highlighter.theme_name().to_string(), colors: syntax_highlighter.ui_colors() };
         Self {
             renderer: Renderer::new(),
             input_handler: InputHandler::new(),
-            syntax_highlighter: SyntaxHighlighter::new(),
+            keymap: KeymapEngine::new(KeymapMode::Default),
+            syntax_highlighter,
+            themes: initialize_themes(),
+            theme,
         }
     }
 
-    /// Runs the main loop for handling input and rendering the editor UI.
-    pub fn run(&mut self, editor_state: &mut EditorState) {
-        loop {
-            // Handle user input and update the editor state
-            self.input_handler.handle_input(editor_state);
+    /// Runs the main loop for handling key presses and rendering the editor UI, until `keys`
+    /// runs out.
+    pub fn run(&mut self, editor_state: &mut EditorState, keys: impl IntoIterator<Item = KeyEvent>) {
+        for key in keys {
+            // Handle user input and update the editor state; undo and redo need the editor's
+            // history, which only `handle_key`'s caller has
+            self.handle_key(key, editor_state);
 
             // Apply syntax highlighting to the document
             self.syntax_highlighter.highlight(editor_state);
@@ -36,4 +55,46 @@ impl UI {
             self.renderer.render(editor_state);
         }
     }
+
+    /// Switches the keybinding scheme while editing.
+    pub fn set_keymap_mode(&mut self, mode: KeymapMode) {
+        self.keymap.set_mode(mode);
+    }
+
+    /// Switches the highlight theme and the editor colors together, re-highlighting the
+    /// document. When `shared`, returns the message to broadcast to collaborators.
+    pub fn set_editor_theme(&mut self, name: &str, shared: bool, editor_state: &mut EditorState) -> Result<Option<ThemeMessage>, String> {
+        self.theme = set_editor_theme(&mut self.syntax_highlighter, self.themes.clone(), name)?;
+        self.syntax_highlighter.highlight(editor_state);
+        Ok(shared.then(|| ThemeMessage { name: name.to_string() }))
+    }
+
+    /// Applies a theme a collaborator shared. Themes this client doesn't have are ignored.
+    pub fn apply_theme_message(&mut self, message: &ThemeMessage, editor_state: &mut EditorState) {
+        let _ = self.set_editor_theme(&message.name, false, editor_state);
+    }
+
+    /// The editor colors in use
+    pub fn theme(&self) -> &Theme {
+        &self.theme
+    }
+
+    /// Runs a key press through the keymap and applies the resulting events. Undo and redo
+    /// are returned for the editor to apply against its history.
+    pub fn handle_key(&mut self, key: KeyEvent, editor_state: &mut EditorState) -> Vec<InputEvent> {
+        let mut history_events = Vec::new();
+        for event in self.keymap.handle_key(key, editor_state) {
+            match event {
+                InputEvent::Undo | InputEvent::Redo => history_events.push(event),
+                event => self.input_handler.handle_input(event, editor_state),
+            }
+        }
+        history_events
+    }
+}
+
+impl Default for UI {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -1,38 +1,42 @@
-use warp::ws::{Message, WebSocket};
+use warp::ws::WebSocket;
 use warp::{Filter, Reply};
-use futures_util::{StreamExt, SinkExt};
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use crate::client::{self, Client, Clients};
 
+/// Rendered preview sent to every client
 #[derive(Serialize, Deserialize, Debug)]
-struct PreviewUpdate {
-    html: String,
-    css: String,
-    js: String,
+pub struct PreviewUpdate {
+    pub html: String,
+    pub css: String,
+    pub js: String,
 }
 
-type PreviewClients = Arc<Mutex<Vec<warp::ws::WebSocket>>>;
-
 /// Manages the live preview updates and WebSocket connections
+#[derive(Clone)]
 pub struct PreviewManager {
-    clients: PreviewClients,
+    clients: Clients, // Each client's messages are forwarded to its socket by a task of its own
 }
 
 impl PreviewManager {
     pub fn new() -> Self {
         Self {
-            clients: Arc::new(Mutex::new(Vec::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
     /// Registers a new WebSocket client for receiving preview updates
-    pub async fn register_client(&self, socket: WebSocket) {
-        let (mut ws_tx, mut ws_rx) = socket.split();
-        
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.push(ws_tx);
-        }
+    pub async fn register_client(self, socket: WebSocket) {
+        let (ws_tx, ws_rx) = socket.split();
+        let (sender, send_task) = client::spawn_writer(ws_tx);
+        let writer = send_task.abort_handle();
+        let client_id = Uuid::new_v4().to_string();
+        client::add_client(self.clients.clone(), client_id.clone(), Client::new(&client_id, "", sender).with_writer(writer.clone()));
+
+        let mut ws_rx = ws_rx.take_until(send_task);
 
         // Wait for incoming messages (this can be commands for the preview, e.g., reload)
         while let Some(result) = ws_rx.next().await {
@@ -45,29 +49,25 @@ impl PreviewManager {
         }
 
         // Remove the WebSocket client when it disconnects
-        {
-            let mut clients = self.clients.lock().unwrap();
-            clients.retain(|client| !client.is_closed());
-        }
+        client::remove_client(self.clients.clone(), &client_id);
+        writer.abort();
     }
 
     /// Broadcasts the updated HTML, CSS, and JS to all connected clients
-    pub async fn broadcast_update(&self, update: PreviewUpdate) {
-        let message = serde_json::to_string(&update).unwrap();
-        let clients = self.clients.lock().unwrap();
+    pub fn broadcast_update(&self, update: PreviewUpdate) {
+        client::broadcast_message(self.clients.clone(), &serde_json::to_string(&update).unwrap());
+    }
+}
 
-        for client in clients.iter() {
-            if client.send(Message::text(message.clone())).await.is_err() {
-                // If sending the message fails, the client has probably disconnected
-                println!("Failed to send message to client");
-            }
-        }
+impl Default for PreviewManager {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 /// WebSocket handler for the preview WebSocket route
-pub async fn preview_ws_handler(ws: warp::ws::Ws, manager: PreviewManager) -> impl Reply {
-    ws.on_upgrade(move |socket| manager.register_client(socket))
+pub async fn preview_ws_handler(ws: warp::ws::Ws, manager: PreviewManager) -> Result<impl Reply, warp::Rejection> {
+    Ok(ws.on_upgrade(move |socket| manager.register_client(socket)))
 }
 
 /// Route for sending updates to the preview pane
@@ -76,9 +76,7 @@ pub fn send_preview_update_route(manager: PreviewManager) -> impl Filter<Extract
         .and(warp::body::json())
         .and(with_manager(manager))
         .map(|update: PreviewUpdate, manager: PreviewManager| {
-            tokio::spawn(async move {
-                manager.broadcast_update(update).await;
-            });
+            manager.broadcast_update(update);
             warp::reply::json(&"Preview updated")
         })
 }
@@ -87,24 +85,3 @@ pub fn send_preview_update_route(manager: PreviewManager) -> impl Filter<Extract
 fn with_manager(manager: PreviewManager) -> impl Filter<Extract = (PreviewManager,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || manager.clone())
 }
-
-/// Example of how to create the server with WebSocket and preview update routes
-#[tokio::main]
-async fn main() {
-    let preview_manager = PreviewManager::new();
-
-    // WebSocket route for live preview
-    let preview_ws_route = warp::path("preview_ws")
-        .and(warp::ws())
-        .and(with_manager(preview_manager.clone()))
-        .and_then(preview_ws_handler);
-
-    // Route for sending updates to the preview pane
-    let update_route = send_preview_update_route(preview_manager.clone());
-
-    // Serve both routes
-    let routes = preview_ws_route.or(update_route);
-
-    println!("Server running on ws://localhost:3030/preview_ws and http://localhost:3030/update_preview");
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-}
@@ -16,9 +16,12 @@ impl Renderer {
         let mut rendered_lines = Vec::new();
 
         // Iterate through each line in the document, applying syntax highlighting
-        for (line_index, line) in state.get_text().lines().enumerate() {
+        for (line_index, line) in state.lines().enumerate() {
+            if state.is_line_hidden(line_index) {
+                continue; // Inside a folded region
+            }
             let highlighted_regions = state.get_highlighted_regions_for_line(line_index);
-            let rendered_line = self.render_line(line, highlighted_regions);
+            let rendered_line = self.render_line(&line, highlighted_regions);
 
             rendered_lines.push(rendered_line);
         }
@@ -64,6 +67,12 @@ impl Renderer {
     }
 }
 
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Represents a line of rendered text, consisting of segments with optional styles.
 pub struct RenderedLine {
     segments: Vec<RenderedSegment>,
@@ -88,6 +97,12 @@ impl RenderedLine {
     }
 }
 
+impl Default for RenderedLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Represents a segment of rendered text with an optional style (for syntax highlighting).
 #[derive(Clone)]
 pub struct RenderedSegment {
@@ -1,5 +1,9 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use crate::display::{contrast_warnings, ContrastWarning};
+use crate::editor::syntax_highlighting::SyntaxHighlighter;
+use crate::validation::ColorHex;
 
 /// Represents a theme, which includes a name and a set of colors.
 #[derive(Clone, Debug)]
@@ -44,6 +48,34 @@ pub fn set_theme(themes: Themes, new_theme: Theme) -> Result<(), &'static str> {
     Ok(())
 }
 
+/// Adds or replaces a theme a user uploaded. Every color has to be a hex color; the theme is
+/// stored even when colors are hard to tell apart, and the pairs below the WCAG contrast
+/// minimums are returned as warnings for the uploader.
+pub fn add_custom_theme(themes: Themes, mut theme: Theme) -> Result<Vec<ContrastWarning>, String> {
+    for color in theme.colors.values_mut() {
+        *color = ColorHex::try_from(color.as_str()).map_err(|e| e.to_string())?.into();
+    }
+    let warnings = contrast_warnings(&theme.colors);
+    set_theme(themes, theme)?;
+    Ok(warnings)
+}
+
+/// Sent to collaborators when a user shares their editor theme
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename = "theme")]
+pub struct ThemeMessage {
+    pub name: String, // A highlight theme name
+}
+
+/// Switches `highlighter` to the highlight theme `name` and stores the matching UI colors
+/// under the same name, so the editor background and text follow the highlight theme.
+pub fn set_editor_theme(highlighter: &mut SyntaxHighlighter, themes: Themes, name: &str) -> Result<Theme, String> {
+    highlighter.set_theme(name)?;
+    let theme = Theme { name: name.to_string(), colors: highlighter.ui_colors() };
+    set_theme(themes, theme.clone())?;
+    Ok(theme)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,4 +106,33 @@ mod tests {
         assert!(theme.is_some());
         assert_eq!(theme.unwrap().name, "light");
     }
+
+    #[test]
+    fn test_editor_theme_sets_ui_colors() {
+        let themes = initialize_themes();
+        let mut highlighter = SyntaxHighlighter::new();
+
+        let theme = set_editor_theme(&mut highlighter, themes.clone(), "Solarized (light)").unwrap();
+        assert_eq!(theme.colors["background"], "#fdf6e3");
+        assert_eq!(get_theme(themes.clone(), "Solarized (light)").unwrap().colors, theme.colors);
+
+        assert!(set_editor_theme(&mut highlighter, themes, "Missing").is_err());
+        assert_eq!(highlighter.theme_name(), "Solarized (light)");
+    }
+
+    #[test]
+    fn test_uploaded_theme_gets_contrast_warnings() {
+        let themes = initialize_themes();
+        let theme = |text: &str| Theme {
+            name: "pale".to_string(),
+            colors: [("background", "#FFFFFF"), ("text", text)].into_iter().map(|(name, color)| (name.to_string(), color.to_string())).collect(),
+        };
+
+        let warnings = add_custom_theme(themes.clone(), theme("#cccccc")).unwrap();
+        assert_eq!(warnings.iter().map(|warning| (warning.foreground.as_str(), warning.background.as_str())).collect::<Vec<_>>(), vec![("text", "background")]);
+        assert_eq!(get_theme(themes.clone(), "pale").unwrap().colors["background"], "#ffffff");
+
+        assert!(add_custom_theme(themes.clone(), theme("#1a1a1a")).unwrap().is_empty());
+        assert!(add_custom_theme(themes, theme("grey")).is_err());
+    }
 }
@@ -1,21 +1,33 @@
+use yew::format::Text;
 use yew::prelude::*;
+use yew::services::timeout::{TimeoutService, TimeoutTask};
+use yew::services::websocket::{WebSocketService, WebSocketStatus, WebSocketTask};
+use web_sys::HtmlTextAreaElement;
+use crate::editor::diff_engine::DiffOperation;
 use crate::editor::state::EditorState;
 use crate::editor::syntax_highlighting::SyntaxHighlighter;
-use crate::networking::peer_sync::PeerSync;
-use crate::networking::websocket::WebSocketClient;
+use crate::networking::supervisor::{ConnectionState, ConnectionSupervisor, SupervisorAction};
+use crate::version::PROTOCOL_VERSION;
 
 pub struct WebUI {
     link: ComponentLink<Self>,
     state: EditorState,
     syntax_highlighter: SyntaxHighlighter,
-    peer_sync: PeerSync,
-    websocket_client: Option<WebSocketClient>,
+    supervisor: ConnectionSupervisor,
+    connection: ConnectionState,
+    socket: Option<WebSocketTask>, // The open socket, if any
+    retry: Option<TimeoutTask>,    // The pending reconnection attempt
+    textarea: NodeRef,
 }
 
 pub enum Msg {
     InputChanged(String),
     ReceiveWebSocketMessage(String),
     ApplyChanges,
+    ConnectionState(ConnectionState),
+    SocketOpened,
+    SocketClosed,
+    Retry,
 }
 
 impl Component for WebUI {
@@ -23,32 +35,35 @@ impl Component for WebUI {
     type Properties = ();
 
     fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
-        let websocket_client = WebSocketClient::new("ws://localhost:8080");
-        
+        let url = format!("ws://localhost:8080/ws?protocol={}", PROTOCOL_VERSION);
+        let user = format!("guest-{}", uuid::Uuid::new_v4().simple());
+
         Self {
             link,
             state: EditorState::new(),
             syntax_highlighter: SyntaxHighlighter::new(),
-            peer_sync: PeerSync::new(),
-            websocket_client: Some(websocket_client),
+            supervisor: ConnectionSupervisor::new(&url, &user),
+            connection: ConnectionState::Connecting,
+            socket: None,
+            retry: None,
+            textarea: NodeRef::default(),
         }
     }
 
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
             Msg::InputChanged(input) => {
-                self.state.insert_text(&input);
+                let cursor = self.caret(&input);
+                let actions = self.supervisor.local_edit(&input, cursor);
+                self.perform(actions);
+                self.state.replace_text(self.supervisor.local_text().to_string());
                 self.syntax_highlighter.highlight(&mut self.state);
-
-                if let Some(ref mut ws_client) = self.websocket_client {
-                    let changes = self.state.get_text();
-                    let _ = self.peer_sync.broadcast_change(&self.state, ws_client);
-                }
-
                 true
             }
             Msg::ReceiveWebSocketMessage(message) => {
-                self.peer_sync.handle_incoming_message(message, &mut self.state);
+                let cursor = self.caret(self.supervisor.local_text());
+                let actions = self.supervisor.receive(&message, cursor);
+                self.perform(actions);
                 true
             }
             Msg::ApplyChanges => {
@@ -56,15 +71,40 @@ impl Component for WebUI {
                 self.syntax_highlighter.highlight(&mut self.state);
                 true
             }
+            Msg::ConnectionState(connection) => {
+                self.connection = connection;
+                true
+            }
+            Msg::SocketOpened => {
+                let actions = self.supervisor.opened();
+                self.perform(actions);
+                false
+            }
+            Msg::SocketClosed => {
+                // A failing socket reports both an error and the close; only the first counts
+                if self.socket.take().is_some() {
+                    self.connection_lost();
+                }
+                false
+            }
+            Msg::Retry => {
+                self.retry = None;
+                let actions = self.supervisor.retry();
+                self.perform(actions);
+                false
+            }
         }
     }
 
     fn view(&self) -> Html {
         html! {
             <div class="editor-container">
+                { self.render_connection_state() }
                 <textarea
+                    ref=self.textarea.clone()
                     class="editor"
-                    value={self.state.get_text().clone()}
+                    readonly=self.supervisor.locked()
+                    value={self.state.get_text().into_owned()}
                     oninput=self.link.callback(|e: InputData| Msg::InputChanged(e.value))
                 />
                 <div class="highlighted-code">
@@ -75,20 +115,96 @@ impl Component for WebUI {
     }
 
     fn mounted(&mut self) -> ShouldRender {
-        if let Some(ref mut ws_client) = self.websocket_client {
-            let link = self.link.clone();
-            // Start listening for WebSocket messages
-            wasm_bindgen_futures::spawn_local(async move {
-                while let Some(message) = ws_client.receive_message().await {
-                    link.send_message(Msg::ReceiveWebSocketMessage(message));
-                }
-            });
-        }
+        let actions = self.supervisor.start();
+        self.perform(actions);
         false
     }
 }
 
 impl WebUI {
+    /// Carries out what the connection supervisor asked for
+    fn perform(&mut self, actions: Vec<SupervisorAction>) {
+        for action in actions {
+            match action {
+                SupervisorAction::Connect(url) => {
+                    let received = self.link.callback(|data: Text| match data {
+                        Ok(message) => Msg::ReceiveWebSocketMessage(message),
+                        Err(_) => Msg::ApplyChanges,
+                    });
+                    let notification = self.link.callback(|status| match status {
+                        WebSocketStatus::Opened => Msg::SocketOpened,
+                        WebSocketStatus::Closed | WebSocketStatus::Error => Msg::SocketClosed,
+                    });
+                    match WebSocketService::connect_text(&url, received, notification) {
+                        Ok(socket) => self.socket = Some(socket),
+                        Err(_) => self.connection_lost(),
+                    }
+                }
+                SupervisorAction::RetryIn(delay) => {
+                    self.retry = Some(TimeoutService::spawn(delay, self.link.callback(|_| Msg::Retry)));
+                }
+                SupervisorAction::Send(frame) => {
+                    if let Some(socket) = self.socket.as_mut() {
+                        socket.send(Ok(frame));
+                    }
+                }
+                SupervisorAction::Apply { operations, cursor } => self.apply_to_textarea(&operations, cursor),
+                SupervisorAction::State(connection) => self.link.send_message(Msg::ConnectionState(connection)),
+            }
+        }
+    }
+
+    /// Schedules reconnecting after the socket closed or couldn't be opened
+    fn connection_lost(&mut self) {
+        let actions = self.supervisor.closed(js_sys::Math::random());
+        self.perform(actions);
+    }
+
+    /// Changes the textarea in place by `operations`, so only the changed text is touched, then
+    /// puts the caret at `cursor`. Operations are on bytes; the textarea counts UTF-16 units.
+    fn apply_to_textarea(&mut self, operations: &[DiffOperation], cursor: usize) {
+        if let Some(textarea) = self.textarea.cast::<HtmlTextAreaElement>() {
+            let mut text = textarea.value();
+            for operation in operations {
+                let (start, end, replacement) = match operation {
+                    DiffOperation::Insert(position, inserted) => (*position, *position, inserted.as_str()),
+                    DiffOperation::Delete(start, end) => (*start, *end, ""),
+                    DiffOperation::Replace(start, end, replaced) => (*start, *end, replaced.as_str()),
+                };
+                let _ = textarea.set_range_text_with_start_and_end(replacement, utf16_offset(&text, start), utf16_offset(&text, end));
+                text.replace_range(start.min(text.len())..end.min(text.len()), replacement);
+            }
+            let caret = utf16_offset(&text, cursor);
+            let _ = textarea.set_selection_range(caret, caret);
+        }
+        self.state.replace_text(self.supervisor.local_text().to_string());
+        self.syntax_highlighter.highlight(&mut self.state);
+    }
+
+    /// The caret in `text` as it is in the textarea, as a byte offset
+    fn caret(&self, text: &str) -> usize {
+        let caret = self.textarea.cast::<HtmlTextAreaElement>().and_then(|textarea| textarea.selection_start().ok().flatten());
+        byte_offset(text, caret.unwrap_or(0))
+    }
+
+    /// Tells the user when edits aren't reaching the others
+    fn render_connection_state(&self) -> Html {
+        let status = match &self.connection {
+            ConnectionState::Connected => return html! {},
+            ConnectionState::Connecting => "Connecting…".to_string(),
+            ConnectionState::Resuming => "Reconnected, catching up…".to_string(),
+            ConnectionState::Reconnecting { retry_in, .. } => {
+                format!("Connection lost; retrying in {}s. Your edits will be sent once it is back.", retry_in.as_secs().max(1))
+            }
+            ConnectionState::Unsynced { buffered } => {
+                format!("{} edits are not synced yet; editing is paused until the connection is back.", buffered)
+            }
+        };
+        html! {
+            <div class="connection-state">{ status }</div>
+        }
+    }
+
     /// Renders the highlighted code into HTML
     fn render_highlighted_code(&self) -> Html {
         let highlighted_lines = self.state.get_highlighted_lines();
@@ -100,3 +216,20 @@ impl WebUI {
         }
     }
 }
+
+/// The UTF-16 offset of byte `offset` in `text`, as the textarea counts positions
+fn utf16_offset(text: &str, offset: usize) -> u32 {
+    text[..offset.min(text.len())].encode_utf16().count() as u32
+}
+
+/// The byte offset in `text` of UTF-16 offset `offset`
+fn byte_offset(text: &str, offset: u32) -> usize {
+    let mut units = 0;
+    for (index, ch) in text.char_indices() {
+        if units >= offset as usize {
+            return index;
+        }
+        units += ch.len_utf16();
+    }
+    text.len()
+}
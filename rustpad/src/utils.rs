@@ -1,3 +1,6 @@
+#[cfg(test)]
+pub(crate) mod test_config;
+
 use uuid::Uuid;
 use serde_json::{json, Value};
 use warp::ws::Message;
@@ -0,0 +1,7 @@
+use proptest::test_runner::Config as ProptestConfig;
+
+/// Proptest settings honoring `PROPTEST_CASES`, with a smaller default for CI.
+pub fn config(default_cases: u32) -> ProptestConfig {
+    let cases = std::env::var("PROPTEST_CASES").ok().and_then(|cases| cases.parse().ok()).unwrap_or(default_cases);
+    ProptestConfig { cases, ..ProptestConfig::default() }
+}
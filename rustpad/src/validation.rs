@@ -0,0 +1,344 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+
+/// Maximum length of a username, in characters.
+pub const MAX_USERNAME_LEN: usize = 64;
+/// Maximum length of a chat message or annotation, in characters.
+pub const MAX_CHAT_BODY_LEN: usize = 4000;
+/// Maximum length of a document title or theme name, in characters.
+pub const MAX_TITLE_LEN: usize = 200;
+
+/// Error returned when a user-supplied string fails validation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub field: &'static str,
+    pub reason: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid {}: {}", self.field, self.reason)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl ValidationError {
+    fn new(field: &'static str, reason: impl Into<String>) -> Self {
+        ValidationError { field, reason: reason.into() }
+    }
+}
+
+/// Returns true for the invisible characters that change text direction (bidi overrides).
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200E}' | '\u{200F}' | '\u{061C}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Normalizes to NFC, strips bidi controls, rejects other control characters (except newlines
+/// and tabs when `multiline`), trims, and enforces a non-empty length cap.
+fn clean(field: &'static str, input: &str, max_len: usize, multiline: bool) -> Result<String, ValidationError> {
+    let normalized: String = input.nfc().filter(|c| !is_bidi_control(*c)).collect();
+
+    if let Some(c) = normalized
+        .chars()
+        .find(|c| c.is_control() && !(multiline && (*c == '\n' || *c == '\t')))
+    {
+        return Err(ValidationError::new(field, format!("contains control character U+{:04X}", c as u32)));
+    }
+
+    let trimmed = normalized.trim();
+    if trimmed.is_empty() {
+        return Err(ValidationError::new(field, "must not be empty"));
+    }
+    let len = trimmed.chars().count();
+    if len > max_len {
+        return Err(ValidationError::new(field, format!("is {} characters long, the limit is {}", len, max_len)));
+    }
+    Ok(trimmed.to_string())
+}
+
+/// Defines the boilerplate shared by the validated string newtypes.
+macro_rules! validated_string {
+    ($name:ident) => {
+        impl $name {
+            /// Returns the validated value as a string slice.
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = ValidationError;
+
+            fn try_from(value: &str) -> Result<Self, Self::Error> {
+                $name::try_from(value.to_string())
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(value: $name) -> String {
+                value.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+    };
+}
+
+/// A display name or user id: letters, digits, spaces and `_ - . @`, at most 64 characters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct Username(String);
+
+impl TryFrom<String> for Username {
+    type Error = ValidationError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let cleaned = clean("username", &value, MAX_USERNAME_LEN, false)?;
+        if let Some(c) = cleaned
+            .chars()
+            .find(|c| !(c.is_alphanumeric() || matches!(c, ' ' | '_' | '-' | '.' | '@')))
+        {
+            return Err(ValidationError::new("username", format!("contains disallowed character '{}'", c)));
+        }
+        Ok(Username(cleaned))
+    }
+}
+
+impl Username {
+    /// The name used for sessions that have not logged in.
+    pub fn guest() -> Self {
+        Username("guest".to_string())
+    }
+}
+
+validated_string!(Username);
+
+/// The body of a chat message or annotation; may span multiple lines.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct ChatBody(String);
+
+impl TryFrom<String> for ChatBody {
+    type Error = ValidationError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Ok(ChatBody(clean("message", &value, MAX_CHAT_BODY_LEN, true)?))
+    }
+}
+
+validated_string!(ChatBody);
+
+/// A single-line document title or theme name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct Title(String);
+
+impl TryFrom<String> for Title {
+    type Error = ValidationError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Ok(Title(clean("title", &value, MAX_TITLE_LEN, false)?))
+    }
+}
+
+validated_string!(Title);
+
+/// A CSS hex color (`#rgb` or `#rrggbb`), stored in lowercase.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct ColorHex(String);
+
+impl TryFrom<String> for ColorHex {
+    type Error = ValidationError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let value = value.trim().to_ascii_lowercase();
+        let digits = value
+            .strip_prefix('#')
+            .ok_or_else(|| ValidationError::new("color", "must start with '#'"))?;
+        if !(digits.len() == 3 || digits.len() == 6) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ValidationError::new("color", "must be #rgb or #rrggbb"));
+        }
+        Ok(ColorHex(value))
+    }
+}
+
+validated_string!(ColorHex);
+
+/// Tags kept by `sanitize_html`; everything else is escaped.
+const ALLOWED_TAGS: &[&str] = &[
+    "a", "b", "blockquote", "br", "code", "em", "h1", "h2", "h3", "h4", "h5", "h6", "i", "li", "ol", "p", "pre",
+    "strong", "ul",
+];
+
+/// Escapes text for safe inclusion in HTML.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Extracts a safe `href` (http, https or mailto) from the attributes of an `<a>` tag.
+fn safe_href(attributes: &str) -> Option<String> {
+    let start = attributes.to_ascii_lowercase().find("href=")? + "href=".len();
+    let rest = &attributes[start..];
+    let value = match rest.chars().next()? {
+        quote @ ('"' | '\'') => rest[1..].split(quote).next()?,
+        _ => rest.split_whitespace().next()?,
+    };
+    let lower = value.trim().to_ascii_lowercase();
+    if lower.starts_with("http://") || lower.starts_with("https://") || lower.starts_with("mailto:") {
+        Some(escape_html(value.trim()))
+    } else {
+        None
+    }
+}
+
+/// Allow-list HTML sanitizer for content rendered in previews. Allowed tags are kept without
+/// attributes (except a safe `href` on links); any other markup is escaped as text.
+pub fn sanitize_html(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(open) = rest.find('<') {
+        output.push_str(&escape_html(&rest[..open]));
+        let after = &rest[open + 1..];
+        let close = match after.find('>') {
+            Some(close) => close,
+            None => {
+                rest = &rest[open..];
+                break;
+            }
+        };
+
+        let tag = &after[..close];
+        let (closing, body) = match tag.strip_prefix('/') {
+            Some(body) => (true, body),
+            None => (false, tag.trim_end_matches('/')),
+        };
+        let name_end = body.find(|c: char| c.is_whitespace()).unwrap_or(body.len());
+        let name = body[..name_end].to_ascii_lowercase();
+
+        if ALLOWED_TAGS.contains(&name.as_str()) {
+            match (closing, name.as_str()) {
+                (true, _) => output.push_str(&format!("</{}>", name)),
+                (false, "a") => match safe_href(&body[name_end..]) {
+                    Some(href) => output.push_str(&format!("<a href=\"{}\" rel=\"noopener noreferrer\">", href)),
+                    None => output.push_str("<a>"),
+                },
+                (false, _) => output.push_str(&format!("<{}>", name)),
+            }
+        } else {
+            output.push_str(&escape_html(&rest[open..open + close + 2]));
+        }
+        rest = &after[close + 1..];
+    }
+    output.push_str(&escape_html(rest));
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_username_accept_reject() {
+        assert_eq!(Username::try_from("alice").unwrap().as_str(), "alice");
+        assert_eq!(Username::try_from("  Jane Doe ").unwrap().as_str(), "Jane Doe");
+        assert!(Username::try_from("jane.doe@example.com").is_ok());
+        assert!(Username::try_from("Zoë").is_ok());
+        assert!(Username::try_from("").is_err());
+        assert!(Username::try_from("   ").is_err());
+        assert!(Username::try_from("bad\u{0007}name").is_err());
+        assert!(Username::try_from("<script>").is_err());
+        assert!(Username::try_from("a".repeat(MAX_USERNAME_LEN + 1)).is_err());
+        assert!(Username::try_from("a".repeat(MAX_USERNAME_LEN)).is_ok());
+    }
+
+    #[test]
+    fn test_username_strips_bidi_and_normalizes() {
+        let username = Username::try_from("admin\u{202E}txt.exe").unwrap();
+        assert_eq!(username.as_str(), "admintxt.exe");
+
+        // "e" followed by a combining acute accent is composed to a single "é"
+        let username = Username::try_from("Rene\u{0301}").unwrap();
+        assert_eq!(username.as_str(), "Ren\u{00E9}");
+    }
+
+    #[test]
+    fn test_chat_body_accept_reject() {
+        assert_eq!(ChatBody::try_from("line one\nline two").unwrap().as_str(), "line one\nline two");
+        assert!(ChatBody::try_from("tab\tseparated").is_ok());
+        assert!(ChatBody::try_from("").is_err());
+        assert!(ChatBody::try_from("null\u{0000}byte").is_err());
+        assert!(ChatBody::try_from("x".repeat(MAX_CHAT_BODY_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_title_accept_reject() {
+        assert!(Title::try_from("Meeting notes").is_ok());
+        assert!(Title::try_from("two\nlines").is_err());
+        assert!(Title::try_from("t".repeat(MAX_TITLE_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_color_hex_accept_reject() {
+        assert_eq!(ColorHex::try_from("#FFAA00").unwrap().as_str(), "#ffaa00");
+        assert!(ColorHex::try_from("#abc").is_ok());
+        assert!(ColorHex::try_from("ffaa00").is_err());
+        assert!(ColorHex::try_from("#ffaa0").is_err());
+        assert!(ColorHex::try_from("#gggggg").is_err());
+        assert!(ColorHex::try_from("red").is_err());
+    }
+
+    #[test]
+    fn test_serde_round_trip_and_rejection() {
+        let username = Username::try_from("alice").unwrap();
+        let json = serde_json::to_string(&username).unwrap();
+        assert_eq!(json, "\"alice\"");
+        assert_eq!(serde_json::from_str::<Username>(&json).unwrap(), username);
+
+        let color: ColorHex = serde_json::from_str("\"#00FF00\"").unwrap();
+        assert_eq!(serde_json::to_string(&color).unwrap(), "\"#00ff00\"");
+
+        assert!(serde_json::from_str::<Username>("\"bad\\u0001\"").is_err());
+        assert!(serde_json::from_str::<ChatBody>("\"\"").is_err());
+    }
+
+    #[test]
+    fn test_sanitize_html() {
+        assert_eq!(sanitize_html("<b>bold</b> & <i>it</i>"), "<b>bold</b> &amp; <i>it</i>");
+        assert_eq!(
+            sanitize_html("<script>alert(1)</script>"),
+            "&lt;script&gt;alert(1)&lt;/script&gt;"
+        );
+        assert_eq!(sanitize_html("<p onclick=\"x()\">hi</p>"), "<p>hi</p>");
+        assert_eq!(
+            sanitize_html("<a href=\"https://example.com\">ok</a>"),
+            "<a href=\"https://example.com\" rel=\"noopener noreferrer\">ok</a>"
+        );
+        assert_eq!(sanitize_html("<a href=\"javascript:alert(1)\">x</a>"), "<a>x</a>");
+        assert_eq!(sanitize_html("1 < 2"), "1 &lt; 2");
+    }
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+/// Version of the wire protocol spoken over the WebSocket, as `MAJOR.MINOR`.
+/// Clients with a different major version can't talk to this server.
+pub const PROTOCOL_VERSION: &str = "1.0";
+
+/// Body of `GET /api/version`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VersionInfo {
+    pub version: String,  // Crate version of the running server
+    pub protocol: String, // Wire protocol version, see `PROTOCOL_VERSION`
+}
+
+impl VersionInfo {
+    /// Versions of the running server.
+    pub fn current() -> Self {
+        VersionInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: PROTOCOL_VERSION.to_string(),
+        }
+    }
+}
+
+/// Structured error returned instead of a WebSocket upgrade when the client speaks an incompatible protocol.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpgradeRequired {
+    #[serde(rename = "type")]
+    pub kind: String, // Always "upgrade_required"
+    pub client_protocol: Option<String>,
+    pub server_protocol: String,
+    pub message: String,
+}
+
+/// Checks a client's protocol version against ours; only the major version has to match.
+pub fn negotiate(client_protocol: Option<&str>) -> Result<(), UpgradeRequired> {
+    let compatible = client_protocol.and_then(major).is_some_and(|client| Some(client) == major(PROTOCOL_VERSION));
+    if compatible {
+        return Ok(());
+    }
+
+    let message = match client_protocol {
+        Some(version) => format!("Protocol {} is not supported, this server speaks {}", version, PROTOCOL_VERSION),
+        None => format!("No protocol version given, this server speaks {}", PROTOCOL_VERSION),
+    };
+    Err(UpgradeRequired {
+        kind: "upgrade_required".to_string(),
+        client_protocol: client_protocol.map(str::to_string),
+        server_protocol: PROTOCOL_VERSION.to_string(),
+        message,
+    })
+}
+
+fn major(version: &str) -> Option<u64> {
+    version.split('.').next()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_compares_major_versions() {
+        assert!(negotiate(Some("1.0")).is_ok());
+        assert!(negotiate(Some("1.7")).is_ok());
+        assert!(negotiate(Some("1")).is_ok());
+
+        let rejected = negotiate(Some("2.0")).unwrap_err();
+        assert_eq!(rejected.kind, "upgrade_required");
+        assert_eq!(rejected.client_protocol.as_deref(), Some("2.0"));
+        assert_eq!(rejected.server_protocol, PROTOCOL_VERSION);
+        assert!(negotiate(Some("banana")).is_err());
+        assert!(negotiate(None).is_err());
+    }
+}
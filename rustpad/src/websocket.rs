@@ -1,12 +1,20 @@
-use futures_util::{StreamExt, SinkExt};
+use futures_util::StreamExt;
 use serde_json;
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::broadcast;
 use warp::ws::{Message, WebSocket};
-use crate::client::{Clients, Client, add_client, remove_client};
+use crate::client::{self, Clients, Client, add_client, remove_client, spawn_writer};
 use crate::document::DocumentUpdate;
 use crate::utils::{ws_message_to_string, generate_uuid};
-use std::sync::{Arc, Mutex};
-use crate::sessions::{verify_session, Sessions};  // Ensure the sessions module is properly linkeduse warp::reject::Reject;
+use crate::sessions::{verify_session, Sessions};  // Ensure the sessions module is properly linked
+use crate::validation::Username;
+use serde::Deserialize;
+
+/// A document update as sent by a client; fields are validated on deserialization.
+#[derive(Deserialize)]
+struct IncomingUpdate {
+    content: String,
+    user: Username,
+}
 
 /// Custom reject for invalid sessions.
 #[derive(Debug)]
@@ -25,7 +33,7 @@ pub async fn handle_websocket(
     let client_id = generate_uuid(); // Generate a unique ID for the client
 
     // Split WebSocket into sender and receiver
-    let (mut client_ws_tx, mut client_ws_rx) = socket.split();
+    let (client_ws_tx, mut client_ws_rx) = socket.split();
 
     // Verify session and retrieve user information (e.g., client_id or username)
     if !verify_session(&sessions, &client_id).await {
@@ -33,49 +41,56 @@ pub async fn handle_websocket(
         return Err(warp::reject::custom(InvalidSession)); // Reject the connection
     }
 
-    // Channel to send messages to the client asynchronously
-    let (sender, mut receiver) = mpsc::unbounded_channel();
+    // Queue messages for the client, written to the WebSocket by a task of its own
+    let (sender, mut send_task) = spawn_writer(client_ws_tx);
 
     // Add the client to the list of connected clients
-    let client = Client::new(&client_id, "username", sender.clone()); // Use appropriate username
+    let client = Client::new(&client_id, "username", sender.clone()).with_writer(send_task.abort_handle()); // Use appropriate username
     add_client(clients.clone(), client_id.clone(), client);
 
-    // Task to send messages to the WebSocket from the mpsc channel
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = receiver.recv().await {
-            if client_ws_tx.send(msg).await.is_err() {
-                break; // Client disconnected, break the sending task
-            }
-        }
-    });
-
     // Task to receive messages from the WebSocket
+    let reader_id = client_id.clone();
     let recv_task = tokio::spawn(async move {
         while let Some(result) = client_ws_rx.next().await {
             if let Ok(message) = result {
+                if message.is_close() {
+                    break; // The client is leaving; remove it now
+                }
+                if message.is_ping() {
+                    let _ = sender.try_send(Message::pong(message.into_bytes()));
+                    continue;
+                }
+                if message.is_binary() {
+                    eprintln!("Ignoring a binary frame from client: {}", reader_id);
+                    continue;
+                }
                 if let Ok(text) = ws_message_to_string(message) {
-                    if let Ok(parsed_json) = serde_json::from_str::<serde_json::Value>(&text) {
-                        // Parse the document update and broadcast it
-                        if let (Some(content), Some(user)) = (
-                            parsed_json.get("content").and_then(|v| v.as_str()), 
-                            parsed_json.get("user").and_then(|v| v.as_str())
-                        ) {
-                            let update = DocumentUpdate::new(content, user);
+                    // Parse the document update and broadcast it; invalid input gets an error frame
+                    match serde_json::from_str::<IncomingUpdate>(&text) {
+                        Ok(incoming) => {
+                            let update = DocumentUpdate::new(&incoming.content, incoming.user);
                             if tx.send(update.clone()).is_err() {
                                 break; // Broadcast to clients failed, break the task
                             }
                         }
+                        Err(e) => {
+                            let error = serde_json::json!({ "type": "error", "message": e.to_string() });
+                            let _ = sender.try_send(Message::text(error.to_string()));
+                        }
                     }
                 }
             }
         }
     });
 
-    // Wait for either task to complete
+    // Wait for either task to complete; the writer also stops when the client falls behind
+    let mut recv_task = recv_task;
     tokio::select! {
-        _ = send_task => (),
-        _ = recv_task => (),
+        _ = &mut send_task => (),
+        _ = &mut recv_task => (),
     }
+    send_task.abort();
+    recv_task.abort();
 
     // Remove the client when the connection is closed
     remove_client(clients.clone(), &client_id);
@@ -83,16 +98,8 @@ pub async fn handle_websocket(
     Ok(()) // Ensure this returns ()
 }
 
-/// Broadcasts a document update to all connected clients asynchronously.
+/// Broadcasts a document update to all connected clients, dropping those too far behind.
 pub async fn broadcast_update(clients: Clients, update: DocumentUpdate) {
     let message = serde_json::to_string(&update).unwrap();
-    let clients_lock = clients.lock().unwrap();
-    
-    for (_client_id, client) in clients_lock.iter() {
-        if let Some(sender) = &client.sender {
-            if let Err(e) = sender.send(Message::text(message.clone())) {
-                eprintln!("Failed to send message to client: {}", e);
-            }
-        }
-    }
+    client::broadcast_message(clients, &message);
 }